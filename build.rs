@@ -3,5 +3,7 @@ fn main() {
     {
         println!("cargo:rerun-if-changed=src/arch/aarch64/kernel.ld");
         println!("cargo:rerun-if-changed=src/arch/aarch64/boot.S");
+        println!("cargo:rerun-if-changed=src/arch/aarch64/exception.S");
+        println!("cargo:rerun-if-changed=src/arch/aarch64/boot/chainload.S");
     }
 }
@@ -24,22 +24,60 @@ pub mod virt {
     /// When mapped to kernel virtual space, this becomes 0xffffff8009000000.
     pub const UART_BASE: u64 = 0x0900_0000;
 
+    /// PL011 UART register window size.
+    pub const UART_SIZE: u64 = 0x1000;
+
+    /// PL011 UART interrupt: SPI 1, GIC INTID 33.
+    pub const UART_IRQ: u32 = 33;
+
+    /// PL031 RTC base address.
+    pub const RTC_BASE: u64 = 0x0901_0000;
+
+    /// PL031 RTC register window size.
+    pub const RTC_SIZE: u64 = 0x1000;
+
     /// GIC (Generic Interrupt Controller) base address.
     #[allow(dead_code)]
     pub const GIC_BASE: u64 = 0x0800_0000;
 
+    /// GIC register window size: the GICv2 distributor and CPU interface,
+    /// 64KB each.
+    pub const GIC_SIZE: u64 = 0x2_0000;
+
+    /// GICv2 CPU interface base, the second 64KB of the GIC window.
+    pub const GICC_BASE: u64 = GIC_BASE + 0x1_0000;
+
+    /// GICv3 redistributor base, one 128KB frame per CPU.
+    pub const GICR_BASE: u64 = 0x080a_0000;
+
+    /// GICv3 redistributor window size, frames for 123 CPUs.
+    pub const GICR_SIZE: u64 = 0xf6_0000;
+
     /// PCI Express ECAM (Enhanced Configuration Access Mechanism) base.
+    ///
+    /// This is QEMU's low ECAM window, just below RAM. The high ECAM window
+    /// at `0x40_1000_0000` is only used with `highmem` and not mapped here.
     #[allow(dead_code)]
-    pub const PCIE_ECAM_BASE: u64 = 0x4010_0000;
+    pub const PCIE_ECAM_BASE: u64 = 0x3f00_0000;
+
+    /// PCI Express ECAM size, 1MB of configuration space per bus for 16
+    /// buses.
+    pub const PCIE_ECAM_SIZE: u64 = 0x100_0000;
 
     /// PCI Express MMIO base.
     #[allow(dead_code)]
-    pub const PCIE_MMIO_BASE: u64 = 0x4020_0000;
+    pub const PCIE_MMIO_BASE: u64 = 0x1000_0000;
+
+    /// PCI Express MMIO window size, up to the PIO window.
+    pub const PCIE_MMIO_SIZE: u64 = PCIE_PIO_BASE - PCIE_MMIO_BASE;
 
     /// PCI Express PIO (Programmed I/O) base.
     #[allow(dead_code)]
     pub const PCIE_PIO_BASE: u64 = 0x3eff_0000;
 
+    /// PCI Express PIO window size.
+    pub const PCIE_PIO_SIZE: u64 = 0x1_0000;
+
     /// Flash memory base address.
     #[allow(dead_code)]
     pub const FLASH_BASE: u64 = 0x0000_0000;
@@ -53,17 +91,29 @@ pub mod virt {
     pub const DEVICE_BASE: u64 = 0x0800_0000;
 
     /// Device memory region size.
+    ///
+    /// Covers everything from the GIC up to the start of RAM.
     #[allow(dead_code)]
-    pub const DEVICE_SIZE: u64 = 0x7800_0000;
+    pub const DEVICE_SIZE: u64 = RAM_BASE - DEVICE_BASE;
 }
 
 /// Kernel virtual address space layout.
 pub mod kernel {
+    /// Number of virtual address bits translated through TTBR0/TTBR1.
+    ///
+    /// 39 bits (3-level tables) by default, 48 bits (4-level tables) with
+    /// the `va48` feature. Every window below is derived from this.
+    pub const VA_BITS: u32 = if cfg!(feature = "va48") { 48 } else { 39 };
+
+    /// Size of the kernel virtual address space in bytes.
+    pub const VA_SPACE_SIZE: u64 = 1 << VA_BITS;
+
     /// Kernel virtual base address (high-half).
     ///
-    /// This is the base of the kernel's virtual address space, using the
-    /// standard Linux high-half layout for 39-bit VA space.
-    pub const VIRTUAL_BASE: u64 = 0xffffff8000000000;
+    /// This is the base of the TTBR1 range, using the standard Linux
+    /// high-half layout: 0xffffff8000000000 for 39-bit VA and
+    /// 0xffff000000000000 for 48-bit VA.
+    pub const VIRTUAL_BASE: u64 = 0u64.wrapping_sub(VA_SPACE_SIZE);
 
     /// Kernel load offset within virtual address space.
     ///
@@ -85,6 +135,40 @@ pub mod kernel {
     /// Default kernel stack size (64KB).
     #[allow(dead_code)]
     pub const STACK_SIZE: u64 = 0x10000;
+
+    /// Size of the linear map window at `VIRTUAL_BASE`.
+    ///
+    /// 1/256 of the VA space: 2GB with 39-bit VA, 1TB with 48-bit VA. The
+    /// boot code only populates the part covering RAM.
+    pub const LINEAR_MAP_SIZE: u64 = VA_SPACE_SIZE >> 8;
+
+    /// Start of the ioremap window for device mappings.
+    ///
+    /// Sits directly above the linear map.
+    pub const IOREMAP_START: u64 = VIRTUAL_BASE + LINEAR_MAP_SIZE;
+
+    /// Size of the ioremap window (1GB with 39-bit VA, 512GB with 48-bit VA).
+    pub const IOREMAP_SIZE: u64 = VA_SPACE_SIZE >> 9;
+
+    /// End of the ioremap window (exclusive).
+    #[allow(dead_code)]
+    pub const IOREMAP_END: u64 = IOREMAP_START + IOREMAP_SIZE;
+
+    /// Start of the vmalloc window, directly above the ioremap window.
+    #[allow(dead_code)]
+    pub const VMALLOC_START: u64 = IOREMAP_END;
+
+    /// Size of the vmalloc window (a quarter of the VA space).
+    #[allow(dead_code)]
+    pub const VMALLOC_SIZE: u64 = VA_SPACE_SIZE >> 2;
+
+    /// Start of the fixmap window for fixed-address mappings.
+    ///
+    /// Sits 32MB below the top of the address space.
+    pub const FIXMAP_START: u64 = 0xffff_ffff_fe00_0000;
+
+    /// Size of the fixmap window (2MB).
+    pub const FIXMAP_SIZE: u64 = 0x20_0000;
 }
 
 /// Memory type attributes for MAIR_EL1.
@@ -93,6 +177,10 @@ pub mod mair {
     #[allow(dead_code)]
     pub const MT_NORMAL: u64 = 0xFF;
 
+    /// Normal memory with allocation tags, Inner/Outer Write-Back.
+    #[allow(dead_code)]
+    pub const MT_NORMAL_TAGGED: u64 = 0xF0;
+
     /// Normal memory, Non-Cacheable.
     #[allow(dead_code)]
     pub const MT_NORMAL_NC: u64 = 0x44;
@@ -104,6 +192,158 @@ pub mod mair {
     /// Device memory, nGnRE (no Gathering, no Reordering, Early Write Ack).
     #[allow(dead_code)]
     pub const MT_DEVICE_NGNRE: u64 = 0x04;
+
+    /// Memory types the kernel maps with, one MAIR_EL1 attribute each.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MemType {
+        /// `MT_NORMAL`
+        Normal,
+        /// `MT_NORMAL_TAGGED`
+        NormalTagged,
+        /// `MT_NORMAL_NC`
+        NormalNC,
+        /// `MT_DEVICE_NGNRNE`
+        DeviceNGnRnE,
+        /// `MT_DEVICE_NGNRE`
+        DeviceNGnRE,
+    }
+
+    impl MemType {
+        /// Returns the MAIR_EL1 attribute byte.
+        pub const fn attr(self) -> u64 {
+            match self {
+                Self::Normal => MT_NORMAL,
+                Self::NormalTagged => MT_NORMAL_TAGGED,
+                Self::NormalNC => MT_NORMAL_NC,
+                Self::DeviceNGnRnE => MT_DEVICE_NGNRNE,
+                Self::DeviceNGnRE => MT_DEVICE_NGNRE,
+            }
+        }
+
+        /// Returns true for the Normal memory types, which may only
+        /// describe RAM.
+        pub const fn is_normal(self) -> bool {
+            matches!(self, Self::Normal | Self::NormalTagged | Self::NormalNC)
+        }
+
+        /// Returns the type name.
+        #[allow(dead_code)]
+        pub const fn as_str(self) -> &'static str {
+            match self {
+                Self::Normal => "Normal",
+                Self::NormalTagged => "Normal-Tagged",
+                Self::NormalNC => "Normal-NC",
+                Self::DeviceNGnRnE => "Device-nGnRnE",
+                Self::DeviceNGnRE => "Device-nGnRE",
+            }
+        }
+    }
+
+    /// Number of MAIR_EL1 attribute slots.
+    pub const SLOTS: usize = 8;
+
+    /// Which memory type each MAIR_EL1 slot holds.
+    ///
+    /// Descriptor `AttrIndx` fields select a slot, so builders look the
+    /// index up here by type instead of hard-coding it.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MairConfig {
+        slots: [Option<MemType>; SLOTS],
+        len: usize,
+    }
+
+    impl MairConfig {
+        /// Starts with every slot unused.
+        pub const fn new() -> Self {
+            Self {
+                slots: [None; SLOTS],
+                len: 0,
+            }
+        }
+
+        /// Put `ty` in the next unused slot.
+        ///
+        /// Panics if all slots are used or `ty` already has one, which
+        /// fails the build for a constant configuration.
+        pub const fn with(mut self, ty: MemType) -> Self {
+            assert!(self.len < SLOTS, "all MAIR slots used");
+            assert!(self.index_of(ty).is_none(), "memory type already in MAIR");
+            self.slots[self.len] = Some(ty);
+            self.len += 1;
+            self
+        }
+
+        /// Returns the `AttrIndx` of `ty`, if it has a slot.
+        pub const fn index_of(&self, ty: MemType) -> Option<u64> {
+            let mut i = 0;
+            while i < self.len {
+                if let Some(slot) = self.slots[i]
+                    && slot as u8 == ty as u8
+                {
+                    return Some(i as u64);
+                }
+                i += 1;
+            }
+            None
+        }
+
+        /// Returns the `AttrIndx` of `ty`, panicking if it has no slot.
+        pub const fn index(&self, ty: MemType) -> u64 {
+            match self.index_of(ty) {
+                Some(index) => index,
+                None => panic!("memory type not in MAIR"),
+            }
+        }
+
+        /// Returns the memory type in slot `index`, `None` if unused.
+        #[allow(dead_code)]
+        pub const fn type_at(&self, index: u64) -> Option<MemType> {
+            if index < SLOTS as u64 {
+                self.slots[index as usize]
+            } else {
+                None
+            }
+        }
+
+        /// Returns the MAIR_EL1 value; unused slots are Device-nGnRnE.
+        pub const fn value(&self) -> u64 {
+            let mut value = 0;
+            let mut i = 0;
+            while i < self.len {
+                if let Some(ty) = self.slots[i] {
+                    value |= ty.attr() << (i * 8);
+                }
+                i += 1;
+            }
+            value
+        }
+    }
+
+    /// MAIR_EL1 layout programmed by boot.S.
+    pub const BOOT: MairConfig = MairConfig::new()
+        .with(MemType::Normal)
+        .with(MemType::NormalTagged)
+        .with(MemType::NormalNC)
+        .with(MemType::DeviceNGnRnE)
+        .with(MemType::DeviceNGnRE);
+
+    /// MAIR_EL1 index of `MT_NORMAL`, as programmed by boot.S.
+    #[allow(dead_code)]
+    pub const IDX_NORMAL: u64 = BOOT.index(MemType::Normal);
+
+    /// MAIR_EL1 index of `MT_NORMAL_TAGGED`, as programmed by boot.S.
+    #[allow(dead_code)]
+    pub const IDX_NORMAL_TAGGED: u64 = BOOT.index(MemType::NormalTagged);
+
+    /// MAIR_EL1 index of `MT_NORMAL_NC`, as programmed by boot.S.
+    #[allow(dead_code)]
+    pub const IDX_NORMAL_NC: u64 = BOOT.index(MemType::NormalNC);
+
+    /// MAIR_EL1 index of `MT_DEVICE_NGNRNE`, as programmed by boot.S.
+    pub const IDX_DEVICE_NGNRNE: u64 = BOOT.index(MemType::DeviceNGnRnE);
+
+    /// MAIR_EL1 index of `MT_DEVICE_NGNRE`, as programmed by boot.S.
+    pub const IDX_DEVICE_NGNRE: u64 = BOOT.index(MemType::DeviceNGnRE);
 }
 
 /// Helper functions for address translation.
@@ -118,7 +358,7 @@ pub mod translation {
     /// # Returns
     /// Kernel virtual address
     #[allow(dead_code)]
-    pub fn phys_to_virt(phys: u64) -> u64 {
+    pub const fn phys_to_virt(phys: u64) -> u64 {
         phys + kernel::VIRTUAL_BASE
     }
 
@@ -129,10 +369,23 @@ pub mod translation {
     ///
     /// # Returns
     /// Physical address
-    pub fn virt_to_phys(virt: u64) -> u64 {
+    pub const fn virt_to_phys(virt: u64) -> u64 {
         virt - kernel::VIRTUAL_BASE
     }
 
+    /// Convert a kernel virtual address to a physical address, checking
+    /// that it lies in the linear map.
+    ///
+    /// # Arguments
+    /// * `virt` - Kernel virtual address to convert
+    ///
+    /// # Returns
+    /// Physical address, or `None` if `virt` is outside the linear map
+    pub fn try_virt_to_phys(virt: u64) -> Option<u64> {
+        virt.checked_sub(kernel::VIRTUAL_BASE)
+            .filter(|&phys| phys < kernel::LINEAR_MAP_SIZE)
+    }
+
     /// Get UART virtual address for kernel use.
     ///
     /// # Returns
@@ -181,4 +434,165 @@ pub mod regions {
     pub fn flash() -> (u64, u64) {
         (virt::FLASH_BASE, virt::FLASH_SIZE)
     }
+
+    /// Get the device register windows for QEMU Virt platform, by name.
+    ///
+    /// # Returns
+    /// Slice of (name, base, size) tuples, sorted by base
+    pub fn mmio() -> &'static [(&'static str, u64, u64)] {
+        &[
+            ("gic", virt::GIC_BASE, virt::GIC_SIZE),
+            ("gic-redist", virt::GICR_BASE, virt::GICR_SIZE),
+            ("uart", virt::UART_BASE, virt::UART_SIZE),
+            ("rtc", virt::RTC_BASE, virt::RTC_SIZE),
+            ("pcie-mmio", virt::PCIE_MMIO_BASE, virt::PCIE_MMIO_SIZE),
+            ("pcie-pio", virt::PCIE_PIO_BASE, virt::PCIE_PIO_SIZE),
+            ("pcie-ecam", virt::PCIE_ECAM_BASE, virt::PCIE_ECAM_SIZE),
+        ]
+    }
+
+    /// Get the name of the device register window containing `phys`.
+    ///
+    /// # Returns
+    /// The name from [`mmio`], or `None` outside all windows
+    #[allow(dead_code)]
+    pub fn mmio_name(phys: u64) -> Option<&'static str> {
+        mmio()
+            .iter()
+            .find(|&&(_, base, size)| phys >= base && phys - base < size)
+            .map(|&(name, _, _)| name)
+    }
+}
+
+/// Layout sanity checks for the constants above.
+///
+/// Everything that can be evaluated at compile time is checked with `const`
+/// assertions, so a bad edit to this file fails the build. Checks that depend
+/// on where the kernel image actually ended up are done at boot by
+/// `BootInfo::from_virtual`.
+pub mod layout_checks {
+    use super::{kernel, virt};
+
+    /// Upper bound on a sane kernel image size (64MB).
+    pub const MAX_KERNEL_SIZE: u64 = 0x0400_0000;
+
+    /// Returns true if `[base, base + size)` overlaps the RAM region.
+    const fn overlaps_ram(base: u64, size: u64) -> bool {
+        base < virt::RAM_END && virt::RAM_BASE < base + size
+    }
+
+    const _: () = assert!(
+        kernel::VIRTUAL_START == kernel::VIRTUAL_BASE + kernel::LOAD_OFFSET,
+        "VIRTUAL_START must equal VIRTUAL_BASE + LOAD_OFFSET"
+    );
+    const _: () = assert!(
+        kernel::PAGE_SIZE.is_power_of_two(),
+        "PAGE_SIZE must be a power of two"
+    );
+    const _: () = assert!(
+        kernel::STACK_SIZE.is_multiple_of(kernel::PAGE_SIZE),
+        "STACK_SIZE must be a multiple of PAGE_SIZE"
+    );
+    const _: () = assert!(
+        kernel::LOAD_OFFSET.is_multiple_of(kernel::PAGE_SIZE),
+        "LOAD_OFFSET must be page aligned"
+    );
+    const _: () = assert!(
+        virt::RAM_BASE.checked_add(virt::RAM_SIZE).is_some(),
+        "RAM_END must not overflow"
+    );
+    const _: () = assert!(
+        virt::RAM_BASE + kernel::LOAD_OFFSET + MAX_KERNEL_SIZE <= virt::RAM_END,
+        "kernel image must fit below RAM_END at LOAD_OFFSET"
+    );
+    const _: () = assert!(
+        kernel::IOREMAP_START >= kernel::VIRTUAL_BASE + virt::RAM_END,
+        "ioremap window must not overlap the linear map"
+    );
+    const _: () = assert!(
+        kernel::IOREMAP_START.is_multiple_of(kernel::PAGE_SIZE),
+        "ioremap window must be page aligned"
+    );
+    const _: () = assert!(
+        !overlaps_ram(virt::DEVICE_BASE, virt::DEVICE_SIZE),
+        "device region must not overlap RAM"
+    );
+    const _: () = assert!(
+        !overlaps_ram(virt::FLASH_BASE, virt::FLASH_SIZE),
+        "flash region must not overlap RAM"
+    );
+    const _: () = assert!(
+        !overlaps_ram(virt::UART_BASE, virt::UART_SIZE)
+            && !overlaps_ram(virt::RTC_BASE, virt::RTC_SIZE)
+            && !overlaps_ram(virt::GIC_BASE, virt::GIC_SIZE)
+            && !overlaps_ram(virt::GICR_BASE, virt::GICR_SIZE)
+            && !overlaps_ram(virt::PCIE_ECAM_BASE, virt::PCIE_ECAM_SIZE)
+            && !overlaps_ram(virt::PCIE_MMIO_BASE, virt::PCIE_MMIO_SIZE)
+            && !overlaps_ram(virt::PCIE_PIO_BASE, virt::PCIE_PIO_SIZE),
+        "MMIO windows must not overlap RAM"
+    );
+    const _: () = assert!(
+        virt::PCIE_MMIO_BASE + virt::PCIE_MMIO_SIZE <= virt::PCIE_PIO_BASE
+            && virt::PCIE_PIO_BASE + virt::PCIE_PIO_SIZE <= virt::PCIE_ECAM_BASE
+            && virt::PCIE_ECAM_BASE + virt::PCIE_ECAM_SIZE <= virt::RAM_BASE,
+        "PCIe windows must not overlap each other"
+    );
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::kernel;
+    use super::mair::{self, MairConfig, MemType};
+
+    #[test]
+    fn test_mair_config_value() {
+        assert_eq!(MairConfig::new().value(), 0);
+        // One attribute byte per slot, slot 0 lowest
+        assert_eq!(mair::BOOT.value(), 0x0000_0004_0044_f0ff);
+        let config = MairConfig::new()
+            .with(MemType::DeviceNGnRE)
+            .with(MemType::NormalNC)
+            .with(MemType::Normal);
+        assert_eq!(config.value(), 0x00ff_4404);
+    }
+
+    #[test]
+    fn test_mair_config_lookup() {
+        let config = MairConfig::new()
+            .with(MemType::DeviceNGnRE)
+            .with(MemType::Normal);
+        assert_eq!(config.index_of(MemType::DeviceNGnRE), Some(0));
+        assert_eq!(config.index_of(MemType::Normal), Some(1));
+        assert_eq!(config.index_of(MemType::NormalNC), None);
+        assert_eq!(config.type_at(1), Some(MemType::Normal));
+        assert_eq!(config.type_at(2), None);
+        assert_eq!(config.type_at(8), None);
+
+        for index in 0..5 {
+            let ty = mair::BOOT.type_at(index).unwrap();
+            assert_eq!(mair::BOOT.index(ty), index);
+        }
+        assert_eq!(mair::IDX_DEVICE_NGNRE, 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "memory type already in MAIR")]
+    fn test_mair_config_duplicate() {
+        MairConfig::new()
+            .with(MemType::Normal)
+            .with(MemType::Normal);
+    }
+
+    #[test]
+    fn test_va_bits_layout() {
+        let base = if cfg!(feature = "va48") {
+            0xffff_0000_0000_0000
+        } else {
+            0xffff_ff80_0000_0000
+        };
+        assert_eq!(kernel::VIRTUAL_BASE, base);
+        assert_eq!(kernel::VIRTUAL_BASE.wrapping_add(kernel::VA_SPACE_SIZE), 0);
+        assert_eq!(kernel::IOREMAP_START, base + kernel::LINEAR_MAP_SIZE);
+        assert_eq!(kernel::VMALLOC_START, kernel::IOREMAP_END);
+    }
 }
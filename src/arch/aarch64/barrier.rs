@@ -0,0 +1,139 @@
+//! Memory and instruction barriers.
+//!
+//! `dsb`, `dmb` and `isb` wrap the instructions of the same name. Host
+//! builds have no device or translation tables to order, so there they
+//! only stop the compiler from moving memory accesses across the call,
+//! which lets code using them build and run under host tests unchanged.
+
+/// Shareability domain and access types a DSB or DMB waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Scope {
+    /// Full system, all accesses.
+    Sy,
+    /// Inner Shareable domain, all accesses.
+    Ish,
+    /// Inner Shareable domain, stores only.
+    IshSt,
+    /// Non-shareable, this CPU only.
+    Nsh,
+    /// Full system, stores only.
+    St,
+    /// Full system, loads only.
+    Ld,
+}
+
+impl Scope {
+    /// Returns the barrier option as written in assembly.
+    #[allow(dead_code)]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sy => "sy",
+            Self::Ish => "ish",
+            Self::IshSt => "ishst",
+            Self::Nsh => "nsh",
+            Self::St => "st",
+            Self::Ld => "ld",
+        }
+    }
+}
+
+/// Data synchronization barrier: waits until earlier accesses in `scope`
+/// have completed.
+#[inline(always)]
+pub fn dsb(scope: Scope) {
+    #[cfg(target_os = "none")]
+    unsafe {
+        // Safety: barriers only order and wait for memory accesses
+        match scope {
+            Scope::Sy => core::arch::asm!("dsb sy", options(nostack, preserves_flags)),
+            Scope::Ish => core::arch::asm!("dsb ish", options(nostack, preserves_flags)),
+            Scope::IshSt => core::arch::asm!("dsb ishst", options(nostack, preserves_flags)),
+            Scope::Nsh => core::arch::asm!("dsb nsh", options(nostack, preserves_flags)),
+            Scope::St => core::arch::asm!("dsb st", options(nostack, preserves_flags)),
+            Scope::Ld => core::arch::asm!("dsb ld", options(nostack, preserves_flags)),
+        }
+    }
+    #[cfg(not(target_os = "none"))]
+    {
+        let _ = scope;
+        compiler_fence();
+    }
+}
+
+/// Data memory barrier: orders earlier accesses in `scope` before later
+/// ones without waiting for them to complete.
+#[inline(always)]
+#[allow(dead_code)]
+pub fn dmb(scope: Scope) {
+    #[cfg(target_os = "none")]
+    unsafe {
+        // Safety: barriers only order memory accesses
+        match scope {
+            Scope::Sy => core::arch::asm!("dmb sy", options(nostack, preserves_flags)),
+            Scope::Ish => core::arch::asm!("dmb ish", options(nostack, preserves_flags)),
+            Scope::IshSt => core::arch::asm!("dmb ishst", options(nostack, preserves_flags)),
+            Scope::Nsh => core::arch::asm!("dmb nsh", options(nostack, preserves_flags)),
+            Scope::St => core::arch::asm!("dmb st", options(nostack, preserves_flags)),
+            Scope::Ld => core::arch::asm!("dmb ld", options(nostack, preserves_flags)),
+        }
+    }
+    #[cfg(not(target_os = "none"))]
+    {
+        let _ = scope;
+        compiler_fence();
+    }
+}
+
+/// Instruction synchronization barrier: later instructions are fetched
+/// after earlier system register changes have taken effect.
+#[inline(always)]
+#[allow(dead_code)]
+pub fn isb() {
+    #[cfg(target_os = "none")]
+    unsafe {
+        // Safety: flushing the pipeline has no other effect
+        core::arch::asm!("isb", options(nostack, preserves_flags));
+    }
+    #[cfg(not(target_os = "none"))]
+    compiler_fence();
+}
+
+/// Keep the compiler from moving memory accesses across a host barrier.
+#[cfg(not(target_os = "none"))]
+fn compiler_fence() {
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const ALL: [Scope; 6] = [
+        Scope::Sy,
+        Scope::Ish,
+        Scope::IshSt,
+        Scope::Nsh,
+        Scope::St,
+        Scope::Ld,
+    ];
+
+    #[test]
+    fn test_host_shims() {
+        // Every barrier builds and returns on the host
+        let mut value = 0;
+        for scope in ALL {
+            value += 1;
+            dsb(scope);
+            dmb(scope);
+            isb();
+        }
+        assert_eq!(value, ALL.len());
+    }
+
+    #[test]
+    fn test_scope_operands() {
+        let names: Vec<_> = ALL.iter().map(Scope::as_str).collect();
+        assert_eq!(names, ["sy", "ish", "ishst", "nsh", "st", "ld"]);
+    }
+}
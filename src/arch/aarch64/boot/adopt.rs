@@ -0,0 +1,599 @@
+//! Boot with the MMU left on by a previous loader.
+//!
+//! Normally boot.S is entered with the MMU off, resets SCTLR_EL1 and builds
+//! its own tables. A loader may instead jump in with its translation still
+//! enabled (e.g. `chainload` without `disable_mmu`). boot.S then records
+//! the loader's registers in an [`EntryState`] and asks [`check`] whether
+//! the loader's tables already map what the kernel touches before it can
+//! switch to its own:
+//!
+//! - the image and boot stack at their link addresses, as normal
+//!   write-back memory that is writable and executable
+//! - the UART at its early virtual address, as device memory
+//! - the boot code at its physical address through TTBR0, for the switch
+//!
+//! If they do, boot continues on the loader's tables and
+//! [`replace_tables`] later swaps in the kernel's with a break-before-make
+//! sequence. Otherwise boot.S turns the MMU off from its identity-mapped
+//! code and takes the normal path.
+//!
+//! The checks only read descriptors through a callback, so they are tested
+//! on fabricated tables.
+
+#[cfg(target_os = "none")]
+use crate::arch::address;
+use crate::arch::cpu::{self, sctlr};
+use crate::arch::pagetable::{self, Translation, desc, is_device, is_normal_write_back};
+use core::fmt;
+
+/// TCR_EL1 field positions.
+mod tcr {
+    /// T0SZ, bits[5:0].
+    pub const T0SZ_SHIFT: u64 = 0;
+    /// TG0, bits[15:14].
+    pub const TG0_SHIFT: u64 = 14;
+    /// T1SZ, bits[21:16].
+    pub const T1SZ_SHIFT: u64 = 16;
+    /// TG1, bits[31:30].
+    pub const TG1_SHIFT: u64 = 30;
+    /// TG0 encoding of the 4KB granule.
+    pub const TG0_4K: u64 = 0b00;
+    /// TG1 encoding of the 4KB granule.
+    pub const TG1_4K: u64 = 0b10;
+}
+
+/// Translation registers found at entry, recorded by boot.S.
+///
+/// `#[repr(C)]` because boot.S fills in the register fields by offset.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EntryState {
+    /// SCTLR_EL1 at entry.
+    pub sctlr: u64,
+    /// TTBR0_EL1 at entry.
+    pub ttbr0: u64,
+    /// TTBR1_EL1 at entry.
+    pub ttbr1: u64,
+    /// TCR_EL1 at entry.
+    pub tcr: u64,
+    /// MAIR_EL1 at entry.
+    pub mair: u64,
+    /// Decision taken, see [`EntryState::outcome`].
+    pub verdict: u64,
+}
+
+const _: () = assert!(
+    core::mem::offset_of!(EntryState, mair) == 32,
+    "boot.S stores the entry registers at fixed offsets"
+);
+
+/// `verdict` of a boot that was entered with the MMU off.
+const VERDICT_MMU_OFF: u64 = 0;
+/// `verdict` of a boot that kept the loader's tables.
+const VERDICT_ADOPTED: u64 = 1;
+/// `verdict` of a refused adoption is this plus the error's index.
+const VERDICT_REFUSED: u64 = 2;
+
+/// Reasons the loader's translation setup cannot be kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdoptError {
+    /// SCTLR_EL1 selects big-endian, WXN or alignment checking.
+    Sctlr,
+    /// TCR_EL1 uses another granule or VA size than the kernel.
+    Tcr,
+    /// Part of the image or boot stack is not mapped.
+    ImageUnmapped,
+    /// The image is mapped, but not to where it was loaded.
+    ImageMisplaced,
+    /// The image is not writable, executable, write-back memory.
+    ImageAttributes,
+    /// The UART is not mapped at its early virtual address.
+    UartUnmapped,
+    /// The UART is not mapped as device memory.
+    UartAttributes,
+    /// The boot code is not identity mapped through TTBR0.
+    StubUnmapped,
+    /// The boot code would change memory type under the kernel's MAIR.
+    StubAttributes,
+}
+
+impl AdoptError {
+    /// Every error, indexed by its verdict code.
+    const ALL: [AdoptError; 9] = [
+        AdoptError::Sctlr,
+        AdoptError::Tcr,
+        AdoptError::ImageUnmapped,
+        AdoptError::ImageMisplaced,
+        AdoptError::ImageAttributes,
+        AdoptError::UartUnmapped,
+        AdoptError::UartAttributes,
+        AdoptError::StubUnmapped,
+        AdoptError::StubAttributes,
+    ];
+
+    /// Returns a short human-readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdoptError::Sctlr => "unsupported SCTLR_EL1 bits",
+            AdoptError::Tcr => "unsupported granule or VA size",
+            AdoptError::ImageUnmapped => "image not mapped",
+            AdoptError::ImageMisplaced => "image mapped to another address",
+            AdoptError::ImageAttributes => "image mapped with wrong attributes",
+            AdoptError::UartUnmapped => "UART not mapped",
+            AdoptError::UartAttributes => "UART not mapped as device memory",
+            AdoptError::StubUnmapped => "boot code not identity mapped",
+            AdoptError::StubAttributes => "boot code mapped with wrong attributes",
+        }
+    }
+
+    /// Returns the value stored in [`EntryState::verdict`] for the error.
+    const fn verdict(self) -> u64 {
+        VERDICT_REFUSED + self as u64
+    }
+}
+
+/// How boot dealt with the MMU state it was entered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Entered with the MMU off, the usual case.
+    MmuOff,
+    /// The loader's tables were kept until the kernel's replaced them.
+    Adopted,
+    /// The loader's tables were refused and the MMU turned off.
+    Disabled(AdoptError),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::MmuOff => f.write_str("MMU: off at entry"),
+            Outcome::Adopted => f.write_str("MMU: on at entry, loader tables adopted"),
+            Outcome::Disabled(e) => write!(f, "MMU: on at entry, turned off ({})", e.as_str()),
+        }
+    }
+}
+
+impl EntryState {
+    /// Decode the recorded verdict.
+    ///
+    /// Unknown values are reported as entry with the MMU off, which is what
+    /// boot.S falls back to.
+    pub fn outcome(&self) -> Outcome {
+        if !entered_with_mmu(self) {
+            return Outcome::MmuOff;
+        }
+        match self.verdict {
+            VERDICT_MMU_OFF => Outcome::MmuOff,
+            VERDICT_ADOPTED => Outcome::Adopted,
+            v => AdoptError::ALL
+                .get((v - VERDICT_REFUSED) as usize)
+                .map_or(Outcome::MmuOff, |&e| Outcome::Disabled(e)),
+        }
+    }
+}
+
+/// Where the kernel must find itself in the loader's tables.
+#[derive(Debug, Clone, Copy)]
+pub struct Expected {
+    /// Link address of the image start.
+    pub image_virt: u64,
+    /// Link address just past the boot stack.
+    pub image_end: u64,
+    /// Address the image was loaded at.
+    pub image_phys: u64,
+    /// Virtual address early serial output uses.
+    pub uart_virt: u64,
+    /// Physical address of the UART.
+    pub uart_phys: u64,
+    /// Physical start of the boot code that switches tables.
+    pub stub_phys: u64,
+    /// Physical end of that boot code.
+    pub stub_end: u64,
+}
+
+/// Returns true if a leaf maps memory the kernel can run from and write.
+fn kernel_memory(leaf: &Translation, mair: u64) -> bool {
+    leaf.desc & desc::AF != 0
+        && leaf.desc & (desc::PXN | desc::AP_RO) == 0
+        && is_normal_write_back(pagetable::mair_attr(mair, pagetable::attr_index(leaf.desc)))
+}
+
+/// Check whether the loader's tables can be kept until the kernel's own
+/// are installed.
+///
+/// # Arguments
+/// * `state` - Loader registers recorded at entry
+/// * `expected` - Addresses the kernel needs mapped
+/// * `va_bits` - Kernel VA size, which the loader's TCR_EL1 must match
+/// * `read` - Returns the descriptor stored at a physical address
+pub fn check(
+    state: &EntryState,
+    expected: &Expected,
+    va_bits: u32,
+    read: impl Fn(u64) -> u64,
+) -> Result<(), AdoptError> {
+    if state.sctlr & (sctlr::EE | sctlr::WXN | sctlr::A) != 0 {
+        return Err(AdoptError::Sctlr);
+    }
+
+    // The switch reprograms TCR_EL1 while running through the loader's
+    // TTBR0 tables, so both halves must already be walked the same way
+    let tsz = pagetable::tcr_tsz(va_bits);
+    let field = |shift: u64, width: u64| (state.tcr >> shift) & ((1 << width) - 1);
+    if field(tcr::T0SZ_SHIFT, 6) != tsz
+        || field(tcr::T1SZ_SHIFT, 6) != tsz
+        || field(tcr::TG0_SHIFT, 2) != tcr::TG0_4K
+        || field(tcr::TG1_SHIFT, 2) != tcr::TG1_4K
+    {
+        return Err(AdoptError::Tcr);
+    }
+
+    let mut va = expected.image_virt;
+    while va < expected.image_end {
+        let leaf = pagetable::translate(state.ttbr1, va, va_bits, &read)
+            .ok_or(AdoptError::ImageUnmapped)?;
+        if leaf.phys != expected.image_phys + (va - expected.image_virt) {
+            return Err(AdoptError::ImageMisplaced);
+        }
+        if !kernel_memory(&leaf, state.mair) {
+            return Err(AdoptError::ImageAttributes);
+        }
+        va = leaf.next_va(va);
+        if va == 0 {
+            break;
+        }
+    }
+
+    let uart = pagetable::translate(state.ttbr1, expected.uart_virt, va_bits, &read)
+        .filter(|leaf| leaf.phys == expected.uart_phys)
+        .ok_or(AdoptError::UartUnmapped)?;
+    let attr = pagetable::mair_attr(state.mair, pagetable::attr_index(uart.desc));
+    if uart.desc & desc::AF == 0 || !is_device(attr) {
+        return Err(AdoptError::UartAttributes);
+    }
+
+    // MAIR_EL1 changes while the boot code is still fetched through the
+    // loader's TTBR0, so its attribute index must mean write-back in both
+    let mut pa = expected.stub_phys;
+    while pa < expected.stub_end {
+        let leaf = pagetable::translate(state.ttbr0, pa, va_bits, &read)
+            .filter(|leaf| leaf.phys == pa)
+            .ok_or(AdoptError::StubUnmapped)?;
+        if !kernel_memory(&leaf, state.mair) || !kernel_memory(&leaf, pagetable::BOOT_MAIR_EL1) {
+            return Err(AdoptError::StubAttributes);
+        }
+        pa = leaf.next_va(pa);
+    }
+
+    Ok(())
+}
+
+/// Returns true if `state` was recorded with the MMU on.
+pub fn entered_with_mmu(state: &EntryState) -> bool {
+    cpu::sctlr_mmu_enabled(state.sctlr)
+}
+
+#[cfg(target_os = "none")]
+unsafe extern "C" {
+    /// Entry registers and verdict, filled in by boot.S and
+    /// `boot_check_loader_tables`.
+    static __boot_entry_state: EntryState;
+
+    /// Identity-mapped table switch (see boot.S).
+    fn __boot_replace_tables();
+}
+
+/// Decide whether to keep the loader's tables; called from boot.S.
+///
+/// Runs at the load address before the kernel mapping exists, with the
+/// boot stack reached through the loader's identity map. It must not touch
+/// statics holding absolute addresses, which are only valid once running
+/// at the link address.
+///
+/// # Returns
+/// 1 to keep the loader's tables, 0 to turn the MMU off.
+///
+/// # Safety
+/// The loader's tables must be identity mapped through TTBR0 and `state`
+/// must point to the recorded [`EntryState`].
+#[cfg(target_os = "none")]
+#[unsafe(no_mangle)]
+unsafe extern "C" fn boot_check_loader_tables(
+    state: *mut EntryState,
+    image_virt: u64,
+    image_end: u64,
+    image_phys: u64,
+    stub_phys: u64,
+    stub_end: u64,
+) -> u64 {
+    let expected = Expected {
+        image_virt,
+        image_end,
+        image_phys,
+        uart_virt: address::kernel::VIRTUAL_BASE + address::virt::UART_BASE,
+        uart_phys: address::virt::UART_BASE,
+        stub_phys,
+        stub_end,
+    };
+    // Safety: the loader's tables are identity mapped, see above
+    let read = |phys: u64| unsafe { core::ptr::read_volatile(phys as *const u64) };
+
+    // Safety: boot.S passes its own record, nothing else runs yet
+    let state = unsafe { &mut *state };
+    let result = check(state, &expected, address::kernel::VA_BITS, read);
+    state.verdict = match result {
+        Ok(()) => VERDICT_ADOPTED,
+        Err(e) => e.verdict(),
+    };
+    result.is_ok() as u64
+}
+
+/// Returns the entry state recorded by boot.S.
+#[cfg(target_os = "none")]
+pub fn entry_state() -> EntryState {
+    // Safety: written once by boot.S before any Rust code ran
+    unsafe { core::ptr::read_volatile(&raw const __boot_entry_state) }
+}
+
+/// Install the kernel's own tables if boot kept the loader's.
+///
+/// Must run before anything relies on mappings the loader may lack, i.e.
+/// first thing in `early_init`. Does nothing if boot.S built the kernel's
+/// tables itself.
+///
+/// # Returns
+/// How boot handled the MMU state it was entered in.
+#[cfg(target_os = "none")]
+pub fn replace_tables() -> Outcome {
+    let outcome = entry_state().outcome();
+    if outcome != Outcome::Adopted {
+        return outcome;
+    }
+    kassert!(cpu::mmu_enabled());
+
+    let stub = address::translation::virt_to_phys(__boot_replace_tables as *const () as u64);
+    // Safety: `check` verified the stub is identity mapped through TTBR0,
+    // which keeps mapping it across the switch; the stub returns to the
+    // kernel mapping, which the new TTBR1 tables provide.
+    unsafe {
+        let stub: unsafe extern "C" fn() = core::mem::transmute(stub as usize);
+        stub();
+    }
+    outcome
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const VA_BITS: u32 = 39;
+    const BASE: u64 = 0xffff_ff80_0000_0000;
+    const IMAGE_PHYS: u64 = 0x4008_0000;
+    const UART_PHYS: u64 = 0x0900_0000;
+    /// MAIR of the loader: write-back at 0, device nGnRE at 1.
+    const MAIR: u64 = 0x04ff;
+
+    /// Fabricated tables, keyed by physical address.
+    struct Tables(HashMap<u64, [u64; 512]>);
+
+    impl Tables {
+        fn new() -> Self {
+            Self(HashMap::new())
+        }
+
+        fn set(&mut self, table: u64, index: usize, entry: u64) {
+            self.0.entry(table).or_insert([0; 512])[index] = entry;
+        }
+
+        fn read(&self, phys: u64) -> u64 {
+            self.0
+                .get(&(phys & !0xfff))
+                .map_or(0, |t| t[(phys & 0xfff) as usize / 8])
+        }
+    }
+
+    /// 1GB block at `phys` with MAIR `index`, AF set and writable.
+    fn block(phys: u64, index: u64) -> u64 {
+        phys | (index << desc::ATTR_INDX_SHIFT) | desc::AF | desc::SH_INNER | desc::BLOCK
+    }
+
+    /// Loader tables mapping the kernel like boot.S: TTBR0 identity maps
+    /// RAM and TTBR1 linear maps the device GB and RAM.
+    fn loader() -> (Tables, EntryState) {
+        let mut tables = Tables::new();
+        // TTBR0 root at 0x1000, TTBR1 root at 0x2000
+        tables.set(0x1000, 1, block(0x4000_0000, 0));
+        tables.set(0x2000, 0, block(0, 1) | desc::PXN);
+        tables.set(0x2000, 1, block(0x4000_0000, 0));
+        let state = EntryState {
+            sctlr: 0x30c5_0830 | sctlr::M | sctlr::C | sctlr::I,
+            ttbr0: 0x1000,
+            ttbr1: 0x2000,
+            tcr: pagetable::tcr_el1(VA_BITS, 0),
+            mair: MAIR,
+            verdict: 0,
+        };
+        (tables, state)
+    }
+
+    fn expected() -> Expected {
+        Expected {
+            image_virt: BASE + IMAGE_PHYS,
+            image_end: BASE + IMAGE_PHYS + 0x30_0000,
+            image_phys: IMAGE_PHYS,
+            uart_virt: BASE + UART_PHYS,
+            uart_phys: UART_PHYS,
+            stub_phys: IMAGE_PHYS,
+            stub_end: IMAGE_PHYS + 0x800,
+        }
+    }
+
+    fn run(tables: &Tables, state: &EntryState) -> Result<(), AdoptError> {
+        check(state, &expected(), VA_BITS, |pa| tables.read(pa))
+    }
+
+    #[test]
+    fn test_translate_blocks_and_pages() {
+        let mut tables = Tables::new();
+        // L1[1] -> L2 at 0x3000, L2[0] 2MB block, L2[1] -> L3 at 0x4000
+        tables.set(0x2000, 1, 0x3000 | desc::VALID | desc::TABLE);
+        tables.set(0x3000, 0, 0x8000_0000 | desc::AF | desc::BLOCK);
+        tables.set(0x3000, 1, 0x4000 | desc::VALID | desc::TABLE);
+        tables.set(0x4000, 2, pagetable::page_entry(0x9000_0000, 4));
+        let read = |pa| tables.read(pa);
+
+        let leaf = pagetable::translate(0x2000, BASE + 0x4012_3456, VA_BITS, read).unwrap();
+        assert_eq!((leaf.phys, leaf.level), (0x8012_3456, 2));
+        assert_eq!(leaf.next_va(BASE + 0x4012_3456), BASE + 0x4020_0000);
+
+        let leaf = pagetable::translate(0x2000, BASE + 0x4020_2010, VA_BITS, read).unwrap();
+        assert_eq!((leaf.phys, leaf.level), (0x9000_0010, 3));
+        assert_eq!(pagetable::attr_index(leaf.desc), 4);
+
+        // Empty L1 slot, empty L3 slot
+        assert_eq!(pagetable::translate(0x2000, BASE, VA_BITS, read), None);
+        assert_eq!(
+            pagetable::translate(0x2000, BASE + 0x4020_0000, VA_BITS, read),
+            None
+        );
+    }
+
+    #[test]
+    fn test_translate_va48_root() {
+        let mut tables = Tables::new();
+        tables.set(0x1000, 0, 0x2000 | desc::VALID | desc::TABLE);
+        tables.set(0x2000, 1, block(0x4000_0000, 0));
+        let read = |pa| tables.read(pa);
+
+        let va = 0xffff_0000_4008_0000;
+        let leaf = pagetable::translate(0x1000, va, 48, read).unwrap();
+        assert_eq!((leaf.phys, leaf.level), (0x4008_0000, 1));
+
+        // A block descriptor at L0 is not valid with a 4KB granule
+        tables.set(0x1000, 0, block(0, 0));
+        let read = |pa| tables.read(pa);
+        assert_eq!(pagetable::translate(0x1000, va, 48, read), None);
+    }
+
+    #[test]
+    fn test_mair_attributes() {
+        assert!(is_normal_write_back(0xff));
+        assert!(is_normal_write_back(0x77));
+        assert!(!is_normal_write_back(0x44));
+        assert!(!is_normal_write_back(0xf0));
+        assert!(!is_normal_write_back(0x00));
+        assert!(is_device(0x00) && is_device(0x04) && is_device(0x0c));
+        assert!(!is_device(0xff) && !is_device(0x44));
+        assert_eq!(pagetable::mair_attr(pagetable::BOOT_MAIR_EL1, 3), 0x00);
+        assert_eq!(pagetable::mair_attr(pagetable::BOOT_MAIR_EL1, 4), 0x04);
+    }
+
+    #[test]
+    fn test_adopt_compatible_tables() {
+        let (tables, state) = loader();
+        assert!(entered_with_mmu(&state));
+        assert_eq!(run(&tables, &state), Ok(()));
+    }
+
+    #[test]
+    fn test_adopt_refuses_registers() {
+        let (tables, state) = loader();
+        for bit in [sctlr::EE, sctlr::WXN, sctlr::A] {
+            let state = EntryState {
+                sctlr: state.sctlr | bit,
+                ..state
+            };
+            assert_eq!(run(&tables, &state), Err(AdoptError::Sctlr));
+        }
+
+        // 48-bit VA, then a 64KB TTBR1 granule
+        let mut tcr = state;
+        tcr.tcr = tcr.tcr & !0x3f_003f | 16 << 16 | 16;
+        assert_eq!(run(&tables, &tcr), Err(AdoptError::Tcr));
+        let mut tcr = state;
+        tcr.tcr = tcr.tcr & !(0b11 << 30) | 0b11 << 30;
+        assert_eq!(run(&tables, &tcr), Err(AdoptError::Tcr));
+    }
+
+    #[test]
+    fn test_adopt_refuses_image_mapping() {
+        let (mut tables, state) = loader();
+        // Image linked at the RAM base GB but mapped to the next one
+        tables.set(0x2000, 1, block(0x8000_0000, 0));
+        assert_eq!(run(&tables, &state), Err(AdoptError::ImageMisplaced));
+
+        // Split the RAM GB into 2MB blocks and drop the one past the image
+        let (mut tables, state) = loader();
+        tables.set(0x2000, 1, 0x3000 | desc::VALID | desc::TABLE);
+        for i in 0..512 {
+            let pa = 0x4000_0000 + i as u64 * 0x20_0000;
+            tables.set(0x3000, i, block(pa, 0));
+        }
+        assert_eq!(run(&tables, &state), Ok(()));
+        tables.set(0x3000, 1, 0);
+        assert_eq!(run(&tables, &state), Err(AdoptError::ImageUnmapped));
+
+        // Read-only, execute-never and non-cacheable mappings
+        for bad in [
+            block(0x4000_0000, 0) | desc::AP_RO,
+            block(0x4000_0000, 0) | desc::PXN,
+            block(0x4000_0000, 0) & !desc::AF,
+            block(0x4000_0000, 1),
+        ] {
+            let (mut tables, state) = loader();
+            tables.set(0x2000, 1, bad);
+            assert_eq!(run(&tables, &state), Err(AdoptError::ImageAttributes));
+        }
+    }
+
+    #[test]
+    fn test_adopt_refuses_uart_mapping() {
+        let (mut tables, state) = loader();
+        tables.set(0x2000, 0, 0);
+        assert_eq!(run(&tables, &state), Err(AdoptError::UartUnmapped));
+
+        // Mapped as cacheable memory
+        tables.set(0x2000, 0, block(0, 0));
+        assert_eq!(run(&tables, &state), Err(AdoptError::UartAttributes));
+    }
+
+    #[test]
+    fn test_adopt_refuses_stub_mapping() {
+        let (mut tables, state) = loader();
+        // TTBR0 maps RAM somewhere else than its own address
+        tables.set(0x1000, 1, block(0x8000_0000, 0));
+        assert_eq!(run(&tables, &state), Err(AdoptError::StubUnmapped));
+
+        // Loader index 2 is write-back, but the kernel's index 2 is not
+        let (mut tables, mut state) = loader();
+        state.mair |= 0xff << 16;
+        tables.set(0x1000, 1, block(0x4000_0000, 2));
+        assert_eq!(run(&tables, &state), Err(AdoptError::StubAttributes));
+    }
+
+    #[test]
+    fn test_outcome_from_verdict() {
+        let mut state = EntryState::default();
+        assert!(!entered_with_mmu(&state));
+        assert_eq!(state.outcome(), Outcome::MmuOff);
+
+        // A verdict without the MMU on at entry is stale
+        state.verdict = VERDICT_ADOPTED;
+        assert_eq!(state.outcome(), Outcome::MmuOff);
+        state.sctlr = sctlr::M;
+        state.verdict = VERDICT_ADOPTED;
+        assert_eq!(state.outcome(), Outcome::Adopted);
+        for e in AdoptError::ALL {
+            state.verdict = e.verdict();
+            assert_eq!(state.outcome(), Outcome::Disabled(e));
+        }
+        state.verdict = 99;
+        assert_eq!(state.outcome(), Outcome::MmuOff);
+
+        assert_eq!(
+            Outcome::Disabled(AdoptError::UartUnmapped).to_string(),
+            "MMU: on at entry, turned off (UART not mapped)"
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Jump into a freshly loaded kernel image without a reset.
+//!
+//! Meant for rapid development iteration: load a new image (e.g. with
+//! `xmodem`) and hand over to it with the DTB in x0, as a bootloader would.
+
+use crate::mm::memblock::{Memblock, ReservationOwner};
+
+#[cfg(target_os = "none")]
+core::arch::global_asm!(include_str!("chainload.S"));
+
+#[cfg(target_os = "none")]
+unsafe extern "C" {
+    /// Identity-mapped hand-over code (see `chainload.S`).
+    fn __chainload_trampoline(entry: u64, dtb: u64, disable_mmu: u64) -> !;
+}
+
+/// Reasons an image is refused before jumping to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainloadError {
+    /// The image has zero length.
+    EmptyImage,
+    /// The entry point is not instruction aligned.
+    UnalignedEntry,
+    /// The image is not entirely inside one memory region.
+    ImageOutsideMemory,
+    /// The image overlaps the running kernel or its stacks.
+    OverlapsRunningKernel,
+    /// The DTB is not inside memory.
+    DtbOutsideMemory,
+    /// The DTB lies inside the image.
+    DtbOverlapsImage,
+}
+
+impl ChainloadError {
+    /// Returns a short human-readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChainloadError::EmptyImage => "image is empty",
+            ChainloadError::UnalignedEntry => "entry point is not 4-byte aligned",
+            ChainloadError::ImageOutsideMemory => "image is outside memory",
+            ChainloadError::OverlapsRunningKernel => "image overlaps the running kernel",
+            ChainloadError::DtbOutsideMemory => "DTB is outside memory",
+            ChainloadError::DtbOverlapsImage => "DTB overlaps the image",
+        }
+    }
+}
+
+/// Check that an image at `[base, base + size)` can be loaded and run.
+///
+/// # Arguments
+/// * `mb` - Memblock state describing memory and the running kernel
+/// * `base` - Physical address of the image, which is also its entry point
+/// * `size` - Image size in bytes
+/// * `dtb_phys` - Physical address of the DTB to pass, zero for none
+pub fn validate(mb: &Memblock, base: u64, size: u64, dtb_phys: u64) -> Result<(), ChainloadError> {
+    if size == 0 {
+        return Err(ChainloadError::EmptyImage);
+    }
+    if !base.is_multiple_of(4) {
+        return Err(ChainloadError::UnalignedEntry);
+    }
+    if !mb.is_memory(base, size) {
+        return Err(ChainloadError::ImageOutsideMemory);
+    }
+
+    // Writing the image must not clobber the code or stack doing the load
+    let running = [ReservationOwner::KernelImage, ReservationOwner::Stack];
+    if running
+        .iter()
+        .any(|&owner| mb.overlaps_owner(base, size, owner))
+    {
+        return Err(ChainloadError::OverlapsRunningKernel);
+    }
+
+    if dtb_phys != 0 {
+        if !mb.is_memory(dtb_phys, 1) {
+            return Err(ChainloadError::DtbOutsideMemory);
+        }
+        if dtb_phys >= base && dtb_phys - base < size {
+            return Err(ChainloadError::DtbOverlapsImage);
+        }
+    }
+
+    Ok(())
+}
+
+/// Jump to a kernel image already placed in memory.
+///
+/// Validates the image against memblock, makes it visible to instruction
+/// fetch, cleans the image and DTB to the point of coherency so a kernel
+/// starting with caches off sees them, masks interrupts and branches to `entry_phys` with `dtb_phys`
+/// in x0. Halts with a message if validation fails.
+///
+/// # Arguments
+/// * `entry_phys` - Physical address of the image and its entry point
+/// * `image_size` - Image size in bytes
+/// * `dtb_phys` - Physical address of the DTB to pass, zero for none
+/// * `disable_mmu` - Turn the MMU and caches off before jumping, as the
+///   arm64 boot protocol expects
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn chainload(entry_phys: u64, image_size: u64, dtb_phys: u64, disable_mmu: bool) -> ! {
+    use crate::arch::{address, cache, irq, serial};
+    use core::fmt::Write;
+
+    let checked = validate(
+        &crate::mm::memblock::lock(),
+        entry_phys,
+        image_size,
+        dtb_phys,
+    );
+    if let Err(e) = checked {
+        super::fail(super::FailStage::Chainload, "Chainload refused", e.as_str());
+    }
+
+    let _ = writeln!(
+        serial::Writer,
+        "Chainloading {:#x} ({} bytes), DTB {:#x}",
+        entry_phys,
+        image_size,
+        dtb_phys
+    );
+
+    // The image was written, and the DTB possibly relocated, through the
+    // cacheable linear map
+    cache::clean_dcache_range(address::translation::phys_to_virt(entry_phys), image_size);
+    if dtb_phys != 0 {
+        let dtb_virt = address::translation::phys_to_virt(dtb_phys);
+        // Safety: validated to be in RAM, which the boot linear map covers
+        let header =
+            unsafe { core::slice::from_raw_parts(dtb_virt as *const u8, crate::fdt::HEADER_SIZE) };
+        let dtb_size = crate::fdt::header_total_size(header).unwrap_or(crate::fdt::HEADER_SIZE);
+        cache::clean_dcache_range(dtb_virt, dtb_size as u64);
+    }
+    cache::invalidate_icache_all();
+
+    // Never restored: nothing runs here after the jump
+    let _ = irq::disable_save();
+
+    let trampoline = address::translation::virt_to_phys(__chainload_trampoline as *const () as u64);
+    // Safety: the trampoline is position independent and reached through
+    // the TTBR0 identity map; it never returns.
+    unsafe {
+        let trampoline: unsafe extern "C" fn(u64, u64, u64) -> ! =
+            core::mem::transmute(trampoline as usize);
+        trampoline(entry_phys, dtb_phys, disable_mmu as u64)
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn memblock() -> Memblock {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x1000_0000).unwrap();
+        mb.add(0x6000_0000, 0x1000_0000).unwrap();
+        mb.reserve_tagged(0x4008_0000, 0x20_0000, ReservationOwner::KernelImage)
+            .unwrap();
+        mb.reserve_tagged(0x4100_0000, 0x1_0000, ReservationOwner::Stack)
+            .unwrap();
+        mb.reserve_tagged(0x4000_0000, 0x1_0000, ReservationOwner::Dtb)
+            .unwrap();
+        mb
+    }
+
+    #[test]
+    fn test_chainload_validate_ok() {
+        let mb = memblock();
+        assert_eq!(validate(&mb, 0x4400_0000, 0x100_0000, 0x4000_0000), Ok(()));
+        // No DTB, and an image ending exactly at the end of memory
+        assert_eq!(validate(&mb, 0x6f00_0000, 0x100_0000, 0), Ok(()));
+    }
+
+    #[test]
+    fn test_chainload_validate_image() {
+        let mb = memblock();
+        assert_eq!(
+            validate(&mb, 0x4400_0000, 0, 0),
+            Err(ChainloadError::EmptyImage)
+        );
+        assert_eq!(
+            validate(&mb, 0x4400_0002, 0x1000, 0),
+            Err(ChainloadError::UnalignedEntry)
+        );
+        // Below RAM, across the hole between regions, and past the end
+        for base in [0x0800_0000, 0x4fff_f000, 0x6fff_f000] {
+            assert_eq!(
+                validate(&mb, base, 0x2000, 0),
+                Err(ChainloadError::ImageOutsideMemory)
+            );
+        }
+        // Over the running image and over the boot stack
+        for base in [0x4000_0000, 0x4100_f000] {
+            assert_eq!(
+                validate(&mb, base, 0x10_0000, 0),
+                Err(ChainloadError::OverlapsRunningKernel)
+            );
+        }
+    }
+
+    #[test]
+    fn test_chainload_validate_dtb() {
+        let mb = memblock();
+        assert_eq!(
+            validate(&mb, 0x4400_0000, 0x1000, 0x0900_0000),
+            Err(ChainloadError::DtbOutsideMemory)
+        );
+        assert_eq!(
+            validate(&mb, 0x4400_0000, 0x10_0000, 0x4408_0000),
+            Err(ChainloadError::DtbOverlapsImage)
+        );
+        // Right after the image is fine
+        assert_eq!(validate(&mb, 0x4400_0000, 0x10_0000, 0x4410_0000), Ok(()));
+    }
+}
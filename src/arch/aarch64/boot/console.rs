@@ -0,0 +1,194 @@
+//! Interactive early debug console.
+//!
+//! Once boot has finished, reads single-letter commands from the serial
+//! port for poking at the kernel during bring-up. Enabled by passing
+//! `debugcon` on the kernel command line.
+
+/// Command line flag that enables the console.
+pub const CMDLINE_FLAG: &str = "debugcon";
+
+/// Longest accepted input line; further characters are dropped.
+pub const LINE_MAX: usize = 32;
+
+/// Help text printed by the `h` command.
+pub const HELP: &str = "  m  dump memblock\n  s  print stats\n  r  reboot\n  h  this help\n";
+
+/// A console command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Dump the memblock state.
+    Memblock,
+    /// Print the event counters.
+    Stats,
+    /// Reset the system through PSCI.
+    Reboot,
+    /// List the commands.
+    Help,
+    /// An empty line.
+    Empty,
+    /// Anything else.
+    Unknown,
+}
+
+impl Command {
+    /// Parses one input line.
+    pub fn parse(line: &str) -> Self {
+        match line.trim() {
+            "" => Command::Empty,
+            "m" => Command::Memblock,
+            "s" => Command::Stats,
+            "r" => Command::Reboot,
+            "h" | "?" => Command::Help,
+            _ => Command::Unknown,
+        }
+    }
+}
+
+/// Returns true if `cmdline` enables the debug console.
+pub fn enabled(cmdline: &str) -> bool {
+    cmdline
+        .split_ascii_whitespace()
+        .any(|arg| arg == CMDLINE_FLAG)
+}
+
+/// Collects input bytes into lines, echoing them back.
+pub struct LineEditor {
+    buf: [u8; LINE_MAX],
+    len: usize,
+    /// The previous byte ended a line with `\r`.
+    after_cr: bool,
+}
+
+impl LineEditor {
+    /// Create an editor with an empty line.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; LINE_MAX],
+            len: 0,
+            after_cr: false,
+        }
+    }
+
+    /// Feed one received byte.
+    ///
+    /// # Arguments
+    /// * `byte` - Byte read from the serial port
+    /// * `echo` - Sink for bytes to echo back to the terminal
+    ///
+    /// # Returns
+    /// The parsed command once a line is complete
+    pub fn feed(&mut self, byte: u8, echo: &mut impl FnMut(u8)) -> Option<Command> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+
+        match byte {
+            // Terminals send "\r", "\n" or "\r\n"; count the pair once
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => {
+                echo(b'\r');
+                echo(b'\n');
+                let line = &self.buf[..self.len];
+                self.len = 0;
+                Some(core::str::from_utf8(line).map_or(Command::Unknown, Command::parse))
+            }
+            // Backspace and delete
+            0x08 | 0x7f => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    b"\x08 \x08".iter().for_each(|&b| echo(b));
+                }
+                None
+            }
+            0x20..=0x7e => {
+                if self.len < LINE_MAX {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                    echo(byte);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    /// Feed `input` through a fresh editor, returning the commands and echo.
+    fn run(input: &[u8]) -> (Vec<Command>, Vec<u8>) {
+        let mut editor = LineEditor::new();
+        let mut echo = Vec::new();
+        let commands = input
+            .iter()
+            .filter_map(|&b| editor.feed(b, &mut |e| echo.push(e)))
+            .collect();
+        (commands, echo)
+    }
+
+    #[test]
+    fn test_console_commands() {
+        let (commands, echo) = run(b"m\rs\rr\rh\r");
+        assert_eq!(
+            commands,
+            [
+                Command::Memblock,
+                Command::Stats,
+                Command::Reboot,
+                Command::Help
+            ]
+        );
+        assert_eq!(echo, b"m\r\ns\r\nr\r\nh\r\n");
+
+        let (commands, _) = run(b"x\r\r  s  \rmm\r");
+        assert_eq!(
+            commands,
+            [
+                Command::Unknown,
+                Command::Empty,
+                Command::Stats,
+                Command::Unknown
+            ]
+        );
+    }
+
+    #[test]
+    fn test_console_line_endings() {
+        // "\r\n" is one line ending, "\n" on its own is another
+        let (commands, _) = run(b"m\r\ns\n\nr\r\n");
+        assert_eq!(
+            commands,
+            [
+                Command::Memblock,
+                Command::Stats,
+                Command::Empty,
+                Command::Reboot
+            ]
+        );
+    }
+
+    #[test]
+    fn test_console_editing() {
+        let (commands, echo) = run(b"x\x7fm\r\x08\x08\r");
+        assert_eq!(commands, [Command::Memblock, Command::Empty]);
+        // Backspace on an empty line echoes nothing
+        assert_eq!(echo, b"x\x08 \x08m\r\n\r\n");
+
+        // Control characters are ignored, overlong input is truncated
+        let (commands, _) = run(b"\x1bs\r");
+        assert_eq!(commands, [Command::Stats]);
+        let mut long = vec![b'a'; LINE_MAX + 8];
+        long.push(b'\r');
+        let (commands, echo) = run(&long);
+        assert_eq!(commands, [Command::Unknown]);
+        assert_eq!(echo.len(), LINE_MAX + 2);
+    }
+
+    #[test]
+    fn test_console_enabled() {
+        assert!(enabled("debugcon"));
+        assert!(enabled("console=ttyAMA0 debugcon quiet"));
+        assert!(!enabled(""));
+        assert!(!enabled("debugcon=0"));
+    }
+}
@@ -0,0 +1,293 @@
+//! Bump allocator for pages needed before memblock is up.
+//!
+//! Pages are carved in order from [`EARLY_PAGES`] page aligned pages in the
+//! kernel's BSS, so they are zeroed by boot and mapped along with the
+//! kernel image. The pool cannot be handed back page by page; once memblock
+//! is initialized [`reclaim`] retags the pages handed out as page tables,
+//! frees the rest of the pool and shuts the allocator for good.
+
+use crate::arch::address::kernel::PAGE_SIZE;
+use crate::mm::memblock::{Memblock, ReservationOwner};
+use core::fmt;
+
+#[cfg(target_os = "none")]
+use crate::arch::{address, sync::IrqSafeMutex};
+
+/// Pages in the early pool.
+pub const EARLY_PAGES: usize = 8;
+
+/// Hands out the pages of a fixed range in ascending order.
+pub struct BumpAlloc {
+    base: u64,
+    end: u64,
+    /// Next page to hand out.
+    next: u64,
+    /// The range has been given to memblock.
+    reclaimed: bool,
+}
+
+/// What [`BumpAlloc::reclaim`] did with the pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reclaimed {
+    /// Bytes handed out, now reserved as page tables.
+    pub used: u64,
+    /// Bytes returned to memblock.
+    pub returned: u64,
+}
+
+impl fmt::Display for Reclaimed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "early_alloc: {} pages used, {} returned to memblock",
+            self.used / PAGE_SIZE,
+            self.returned / PAGE_SIZE
+        )
+    }
+}
+
+impl BumpAlloc {
+    /// Creates an allocator over the page aligned range at `base`.
+    ///
+    /// # Arguments
+    /// * `base` - Physical address of the first page
+    /// * `size` - Size of the range, a multiple of the page size
+    pub const fn new(base: u64, size: u64) -> Self {
+        Self {
+            base,
+            end: base + size,
+            next: base,
+            reclaimed: false,
+        }
+    }
+
+    /// Allocate the next page.
+    ///
+    /// # Returns
+    /// The physical address of the page
+    pub fn alloc_page(&mut self) -> Result<u64, &'static str> {
+        if self.reclaimed {
+            return Err("early allocator already reclaimed");
+        }
+        if self.remaining() < PAGE_SIZE {
+            return Err("early page pool exhausted");
+        }
+        let page = self.next;
+        self.next += PAGE_SIZE;
+        Ok(page)
+    }
+
+    /// Returns the bytes handed out so far.
+    pub fn used(&self) -> u64 {
+        self.next - self.base
+    }
+
+    /// Returns the bytes left to hand out.
+    pub fn remaining(&self) -> u64 {
+        self.end - self.next
+    }
+
+    /// Returns true once the pool belongs to memblock.
+    pub fn is_reclaimed(&self) -> bool {
+        self.reclaimed
+    }
+
+    /// Hand the pool over to `mb`.
+    ///
+    /// The pool lies inside the kernel image reservation. Its pages are cut
+    /// out of it, the used ones reserved again as page tables and the
+    /// unused ones left free. Either all of this happens or none of it.
+    ///
+    /// # Arguments
+    /// * `mb` - Memblock with the kernel image reserved
+    pub fn reclaim(&mut self, mb: &mut Memblock) -> Result<Reclaimed, &'static str> {
+        if self.reclaimed {
+            return Err("early allocator already reclaimed");
+        }
+        let reclaimed = Reclaimed {
+            used: self.used(),
+            returned: self.remaining(),
+        };
+        mb.transaction(|mb| {
+            mb.free(self.base, self.end - self.base)?;
+            mb.reserve_tagged(self.base, reclaimed.used, ReservationOwner::PageTable)
+        })
+        .map_err(|e| e.as_str())?;
+        self.reclaimed = true;
+        Ok(reclaimed)
+    }
+}
+
+/// Backing storage for the early pool.
+#[cfg(target_os = "none")]
+#[repr(C, align(4096))]
+struct EarlyPool([u8; EARLY_PAGES * PAGE_SIZE as usize]);
+
+#[cfg(target_os = "none")]
+static mut EARLY_POOL: EarlyPool = EarlyPool([0; EARLY_PAGES * PAGE_SIZE as usize]);
+
+/// The early allocator, set up over the pool on first use.
+#[cfg(target_os = "none")]
+static EARLY: IrqSafeMutex<Option<BumpAlloc>> = IrqSafeMutex::new("early_alloc", None);
+
+/// Run `f` on the early allocator.
+#[cfg(target_os = "none")]
+fn with_early<T>(f: impl FnOnce(&mut BumpAlloc) -> T) -> T {
+    let mut early = EARLY.lock();
+    let alloc = early.get_or_insert_with(|| {
+        let base = address::translation::virt_to_phys((&raw const EARLY_POOL) as u64);
+        BumpAlloc::new(base, size_of::<EarlyPool>() as u64)
+    });
+    f(alloc)
+}
+
+/// Allocate a zeroed page from the early pool.
+///
+/// # Returns
+/// The physical address of the page
+#[cfg(target_os = "none")]
+pub fn alloc_page() -> Result<u64, &'static str> {
+    with_early(BumpAlloc::alloc_page)
+}
+
+/// Returns true while pages still come from the early pool, i.e. before
+/// [`reclaim`].
+#[cfg(target_os = "none")]
+pub fn active() -> bool {
+    with_early(|alloc| !alloc.is_reclaimed())
+}
+
+/// Hand the early pool over to the global memblock.
+///
+/// Called once memblock knows about RAM and the kernel image.
+#[cfg(target_os = "none")]
+pub fn reclaim() -> Result<Reclaimed, &'static str> {
+    with_early(|alloc| alloc.reclaim(&mut crate::mm::memblock::lock()))
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const RAM_BASE: u64 = 0x4000_0000;
+    const RAM_SIZE: u64 = 0x40_0000;
+    const KERNEL_BASE: u64 = 0x4008_0000;
+    const KERNEL_SIZE: u64 = 0x4_0000;
+    /// The pool at the end of the kernel's BSS.
+    const POOL_BASE: u64 = KERNEL_BASE + KERNEL_SIZE - POOL_SIZE;
+    const POOL_SIZE: u64 = EARLY_PAGES as u64 * PAGE_SIZE;
+
+    fn memblock() -> Memblock {
+        let mut mb = Memblock::new();
+        mb.add(RAM_BASE, RAM_SIZE).unwrap();
+        mb.reserve_tagged(KERNEL_BASE, KERNEL_SIZE, ReservationOwner::KernelImage)
+            .unwrap();
+        mb
+    }
+
+    #[test]
+    fn test_bump_alloc_in_order() {
+        let mut early = BumpAlloc::new(POOL_BASE, POOL_SIZE);
+        assert_eq!((early.used(), early.remaining()), (0, POOL_SIZE));
+        for i in 0..EARLY_PAGES as u64 {
+            assert_eq!(early.alloc_page(), Ok(POOL_BASE + i * PAGE_SIZE));
+        }
+        assert_eq!(early.alloc_page(), Err("early page pool exhausted"));
+        assert_eq!((early.used(), early.remaining()), (POOL_SIZE, 0));
+    }
+
+    #[test]
+    fn test_reclaim_accounting() {
+        let mut mb = memblock();
+        let free = mb.stats().free_memory;
+        let mut early = BumpAlloc::new(POOL_BASE, POOL_SIZE);
+        let tables: Vec<u64> = (0..3).map(|_| early.alloc_page().unwrap()).collect();
+
+        let reclaimed = early.reclaim(&mut mb).unwrap();
+        assert_eq!(
+            reclaimed,
+            Reclaimed {
+                used: 3 * PAGE_SIZE,
+                returned: 5 * PAGE_SIZE,
+            }
+        );
+        assert_eq!(
+            reclaimed.to_string(),
+            "early_alloc: 3 pages used, 5 returned to memblock"
+        );
+
+        // Used pages move from the kernel image to the page tables, the
+        // rest becomes free
+        let stats = mb.stats();
+        assert_eq!(stats.free_memory, free + reclaimed.returned);
+        assert_eq!(
+            stats.reserved_by_owner[ReservationOwner::KernelImage as usize],
+            KERNEL_SIZE - POOL_SIZE
+        );
+        assert_eq!(
+            stats.reserved_by_owner[ReservationOwner::PageTable as usize],
+            reclaimed.used
+        );
+        for &table in &tables {
+            assert!(mb.overlaps_owner(table, PAGE_SIZE, ReservationOwner::PageTable));
+        }
+        assert!(!mb.overlaps_owner(
+            POOL_BASE + reclaimed.used,
+            reclaimed.returned,
+            ReservationOwner::KernelImage
+        ));
+
+        // The pool is memblock's now
+        assert_eq!(early.alloc_page(), Err("early allocator already reclaimed"));
+        assert!(early.is_reclaimed());
+        assert_eq!(
+            early.reclaim(&mut mb),
+            Err("early allocator already reclaimed")
+        );
+        assert_eq!(mb.stats().free_memory, free + reclaimed.returned);
+    }
+
+    #[test]
+    fn test_reclaim_unused_and_full_pool() {
+        // Nothing handed out: the whole pool goes back
+        let mut mb = memblock();
+        let free = mb.stats().free_memory;
+        let mut early = BumpAlloc::new(POOL_BASE, POOL_SIZE);
+        assert_eq!(
+            early.reclaim(&mut mb),
+            Ok(Reclaimed {
+                used: 0,
+                returned: POOL_SIZE,
+            })
+        );
+        assert_eq!(mb.stats().free_memory, free + POOL_SIZE);
+
+        // Everything handed out: nothing comes back
+        let mut mb = memblock();
+        let mut early = BumpAlloc::new(POOL_BASE, POOL_SIZE);
+        while early.alloc_page().is_ok() {}
+        assert_eq!(early.reclaim(&mut mb).unwrap().returned, 0);
+        assert_eq!(mb.stats().free_memory, free);
+        assert_eq!(
+            mb.stats().reserved_by_owner[ReservationOwner::PageTable as usize],
+            POOL_SIZE
+        );
+    }
+
+    #[test]
+    fn test_failed_reclaim_changes_nothing() {
+        let mut mb = memblock();
+        let mut early = BumpAlloc::new(POOL_BASE, POOL_SIZE);
+        early.alloc_page().unwrap();
+        // Something allocated inside the pool range cannot be freed
+        // piecewise, so the hand-over fails and is rolled back
+        mb.free(KERNEL_BASE, KERNEL_SIZE).unwrap();
+        mb.alloc_at(POOL_BASE, 2 * PAGE_SIZE).unwrap();
+        let before = mb.stats();
+
+        assert!(early.reclaim(&mut mb).is_err());
+        assert_eq!(mb.stats(), before);
+        assert!(!early.is_reclaimed());
+        assert_eq!(early.used(), PAGE_SIZE);
+    }
+}
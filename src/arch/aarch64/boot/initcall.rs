@@ -0,0 +1,353 @@
+//! Ordered subsystem initialization.
+//!
+//! Subsystems register their init function with [`initcall!`] instead of
+//! being called one by one from `kernel_init`. On the target each
+//! registration is a static [`InitcallEntry`] placed in the `.initcalls`
+//! linker section, and [`run_initcalls`] runs the entries of one
+//! [`InitLevel`] in link order, logging each call and its duration:
+//!
+//! ```text
+//! initcall serial ... ok took 3 us
+//! initcall selftest ... FAILED (out of memory) took 41 us
+//! ```
+//!
+//! A failing mandatory call stops boot through `boot::fail`; a failing
+//! optional call is only logged. The runner itself works on any entry
+//! slice, which is how the host tests drive it.
+
+use core::fmt;
+
+/// Boot levels, run in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(dead_code)]
+pub enum InitLevel {
+    /// Before memory management, with only the boot mappings.
+    Early,
+    /// Memblock and the regions carved from it.
+    MemorySetup,
+    /// Once the kernel's own mappings can be changed.
+    PostMmu,
+    /// Device drivers.
+    Device,
+    /// Everything else, once the core subsystems are up.
+    Late,
+}
+
+impl InitLevel {
+    /// Returns the level name.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Early => "early",
+            Self::MemorySetup => "memory_setup",
+            Self::PostMmu => "post_mmu",
+            Self::Device => "device",
+            Self::Late => "late",
+        }
+    }
+}
+
+/// A registered init function.
+#[derive(Debug)]
+pub struct InitcallEntry {
+    /// Level the call runs at.
+    pub level: InitLevel,
+    /// Name used in the log.
+    pub name: &'static str,
+    /// The init function.
+    pub func: fn() -> Result<(), &'static str>,
+    /// Whether boot continues if the call fails.
+    pub optional: bool,
+}
+
+/// Outcome of one init call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallRecord {
+    /// Name of the call.
+    pub name: &'static str,
+    /// What the call returned.
+    pub result: Result<(), &'static str>,
+    /// Whether boot continues if the call fails.
+    pub optional: bool,
+    /// Duration in microseconds.
+    pub took_us: u64,
+}
+
+impl fmt::Display for CallRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "initcall {} ... ", self.name)?;
+        match self.result {
+            Ok(()) => f.write_str("ok")?,
+            Err(e) => write!(f, "FAILED ({})", e)?,
+        }
+        write!(f, " took {} us", self.took_us)
+    }
+}
+
+/// Summary of running one level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelReport {
+    /// Level that was run.
+    pub level: InitLevel,
+    /// Calls made, including a failing mandatory one.
+    pub ran: usize,
+    /// Optional calls that failed.
+    pub failed_optional: usize,
+    /// The mandatory call that failed and stopped the level.
+    pub fatal: Option<CallRecord>,
+    /// Total duration of the calls in microseconds.
+    pub took_us: u64,
+}
+
+/// Run the entries of `level` in slice order.
+///
+/// Stops at the first mandatory call that fails.
+///
+/// # Arguments
+/// * `entries` - All registered entries, of any level
+/// * `level` - Level to run
+/// * `now_us` - Returns a monotonic time in microseconds
+/// * `log` - Called with the outcome of each call
+pub fn run_level(
+    entries: &[InitcallEntry],
+    level: InitLevel,
+    now_us: &mut dyn FnMut() -> u64,
+    log: &mut dyn FnMut(&CallRecord),
+) -> LevelReport {
+    let mut report = LevelReport {
+        level,
+        ran: 0,
+        failed_optional: 0,
+        fatal: None,
+        took_us: 0,
+    };
+
+    for entry in entries.iter().filter(|entry| entry.level == level) {
+        let start = now_us();
+        let result = (entry.func)();
+        let record = CallRecord {
+            name: entry.name,
+            result,
+            optional: entry.optional,
+            took_us: now_us().saturating_sub(start),
+        };
+        log(&record);
+
+        report.ran += 1;
+        report.took_us += record.took_us;
+        if result.is_err() {
+            if !entry.optional {
+                report.fatal = Some(record);
+                break;
+            }
+            report.failed_optional += 1;
+        }
+    }
+    report
+}
+
+#[cfg(target_os = "none")]
+unsafe extern "C" {
+    /// Start of the `.initcalls` section (from linker script).
+    static __initcalls_start: u8;
+    /// End of the `.initcalls` section (from linker script).
+    static __initcalls_end: u8;
+}
+
+/// Returns the entries registered with [`initcall!`], in link order.
+#[cfg(target_os = "none")]
+fn entries() -> &'static [InitcallEntry] {
+    let start = &raw const __initcalls_start as usize;
+    let end = &raw const __initcalls_end as usize;
+    let len = (end - start) / core::mem::size_of::<InitcallEntry>();
+    // Safety: the linker script places only `InitcallEntry` statics
+    // between the two symbols, aligned for the type
+    unsafe { core::slice::from_raw_parts(start as *const InitcallEntry, len) }
+}
+
+/// Run the init calls registered for `level`.
+///
+/// A mandatory call that fails stops boot with `boot::fail`.
+#[cfg(target_os = "none")]
+pub fn run_initcalls(level: InitLevel) {
+    use crate::arch::{serial, timer};
+    use core::fmt::Write;
+
+    let freq = timer::frequency();
+    let report = run_level(
+        entries(),
+        level,
+        &mut || timer::ticks_to_us(timer::ticks(), freq),
+        &mut |record| {
+            let _ = writeln!(serial::Writer, "{}", record);
+        },
+    );
+    let _ = writeln!(
+        serial::Writer,
+        "initcalls {}: {} run, {} optional failed, took {} us",
+        level.as_str(),
+        report.ran,
+        report.failed_optional,
+        report.took_us
+    );
+    if let Some(CallRecord {
+        name,
+        result: Err(e),
+        ..
+    }) = report.fatal
+    {
+        super::fail(super::FailStage::Initcall, name, e);
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    std::thread_local! {
+        /// Names of the calls made, in order.
+        static CALLS: Cell<Vec<&'static str>> = const { Cell::new(Vec::new()) };
+    }
+
+    fn called(name: &'static str) {
+        CALLS.with(|calls| {
+            let mut list = calls.take();
+            list.push(name);
+            calls.set(list);
+        });
+    }
+
+    fn serial() -> Result<(), &'static str> {
+        called("serial");
+        Ok(())
+    }
+
+    fn timer() -> Result<(), &'static str> {
+        called("timer");
+        Ok(())
+    }
+
+    fn memblock() -> Result<(), &'static str> {
+        called("memblock");
+        Ok(())
+    }
+
+    fn probe() -> Result<(), &'static str> {
+        called("probe");
+        Err("no device")
+    }
+
+    fn rtc() -> Result<(), &'static str> {
+        called("rtc");
+        Ok(())
+    }
+
+    fn heap() -> Result<(), &'static str> {
+        called("heap");
+        Err("out of memory")
+    }
+
+    fn never() -> Result<(), &'static str> {
+        called("never");
+        Ok(())
+    }
+
+    const fn entry(
+        level: InitLevel,
+        name: &'static str,
+        func: fn() -> Result<(), &'static str>,
+        optional: bool,
+    ) -> InitcallEntry {
+        InitcallEntry {
+            level,
+            name,
+            func,
+            optional,
+        }
+    }
+
+    /// Levels interleaved as the linker may place them.
+    static TABLE: [InitcallEntry; 7] = [
+        entry(InitLevel::Early, "serial", serial, false),
+        entry(InitLevel::Device, "probe", probe, true),
+        entry(InitLevel::MemorySetup, "memblock", memblock, false),
+        entry(InitLevel::Early, "timer", timer, true),
+        entry(InitLevel::Device, "rtc", rtc, false),
+        entry(InitLevel::Device, "heap", heap, false),
+        entry(InitLevel::Device, "never", never, false),
+    ];
+
+    /// Run `level` of `TABLE` with a clock advancing 5us per reading.
+    fn run(level: InitLevel) -> (LevelReport, Vec<String>, Vec<&'static str>) {
+        CALLS.with(|calls| calls.take());
+        let mut clock = 0;
+        let mut log = Vec::new();
+        let report = run_level(
+            &TABLE,
+            level,
+            &mut || {
+                clock += 5;
+                clock
+            },
+            &mut |record| log.push(record.to_string()),
+        );
+        (report, log, CALLS.with(|calls| calls.take()))
+    }
+
+    #[test]
+    fn test_level_filter_and_order() {
+        let (report, log, calls) = run(InitLevel::Early);
+        assert_eq!(calls, ["serial", "timer"]);
+        assert_eq!(
+            log,
+            [
+                "initcall serial ... ok took 5 us",
+                "initcall timer ... ok took 5 us"
+            ]
+        );
+        assert_eq!(report.ran, 2);
+        assert_eq!(report.took_us, 10);
+        assert_eq!(report.fatal, None);
+
+        let (report, _, calls) = run(InitLevel::Late);
+        assert!(calls.is_empty());
+        assert_eq!(report.ran, 0);
+    }
+
+    #[test]
+    fn test_failures() {
+        let (report, log, calls) = run(InitLevel::Device);
+
+        // The optional failure is logged and skipped, the mandatory one
+        // stops the level before "never"
+        assert_eq!(calls, ["probe", "rtc", "heap"]);
+        assert_eq!(
+            log,
+            [
+                "initcall probe ... FAILED (no device) took 5 us",
+                "initcall rtc ... ok took 5 us",
+                "initcall heap ... FAILED (out of memory) took 5 us",
+            ]
+        );
+        assert_eq!(report.ran, 3);
+        assert_eq!(report.failed_optional, 1);
+        assert_eq!(report.took_us, 15);
+        assert_eq!(
+            report.fatal,
+            Some(CallRecord {
+                name: "heap",
+                result: Err("out of memory"),
+                optional: false,
+                took_us: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn test_level_order() {
+        assert!(InitLevel::Early < InitLevel::MemorySetup);
+        assert!(InitLevel::PostMmu < InitLevel::Device);
+        assert!(InitLevel::Device < InitLevel::Late);
+        assert_eq!(InitLevel::MemorySetup.as_str(), "memory_setup");
+    }
+}
@@ -0,0 +1,418 @@
+//! Placement audit of the blobs the loader put in RAM.
+//!
+//! QEMU's `-kernel`, `-dtb` and `-initrd` loader picks where each blob
+//! goes, and nothing stops it from placing one over another or over the
+//! kernel's BSS, which only exists once the kernel runs. [`audit`] checks
+//! the ranges before memblock hands out anything: every blob must lie in
+//! RAM, and no two may overlap.
+//!
+//! An overlap is resolved by moving the smaller blob that can move. Only
+//! the DTB can be relocated today; it is copied into a memblock
+//! allocation by `init_memory`. Moving the initrd is not supported yet,
+//! so an overlap that would need it is fatal, as is one between blobs
+//! that cannot move at all.
+
+use crate::fdt::Fdt;
+use crate::mm::memblock::Region;
+use core::fmt;
+
+/// A blob placed by the loader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blob {
+    /// The kernel image, BSS included.
+    Kernel,
+    /// The flattened device tree.
+    Dtb,
+    /// The initial ramdisk.
+    Initrd,
+}
+
+impl Blob {
+    /// Returns the blob's name as used in reports.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Kernel => "kernel",
+            Self::Dtb => "dtb",
+            Self::Initrd => "initrd",
+        }
+    }
+
+    /// Returns true if the blob is position independent data that could
+    /// be moved. The kernel runs where it was loaded.
+    pub fn movable(&self) -> bool {
+        matches!(self, Self::Dtb | Self::Initrd)
+    }
+
+    /// Returns true if moving the blob is implemented.
+    pub fn relocatable(&self) -> bool {
+        matches!(self, Self::Dtb)
+    }
+}
+
+impl fmt::Display for Blob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// A blob and the physical range it occupies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placed {
+    pub blob: Blob,
+    pub range: Region,
+}
+
+impl Placed {
+    pub const fn new(blob: Blob, range: Region) -> Self {
+        Self { blob, range }
+    }
+}
+
+/// Bytes shared by two blobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overlap {
+    pub a: Blob,
+    pub b: Blob,
+    /// First shared byte.
+    pub base: u64,
+    /// Number of shared bytes.
+    pub size: u64,
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} overlaps {} by {} bytes at {:#x}",
+            self.a, self.b, self.size, self.base
+        )
+    }
+}
+
+/// Compute the bytes `a` and `b` have in common.
+///
+/// # Returns
+/// The overlap, or `None` if the ranges are disjoint or only touch
+pub fn overlap(a: &Placed, b: &Placed) -> Option<Overlap> {
+    let base = a.range.base.max(b.range.base);
+    let end = a.range.end_wide().min(b.range.end_wide());
+    if end <= base as u128 {
+        return None;
+    }
+    Some(Overlap {
+        a: a.blob,
+        b: b.blob,
+        base,
+        size: (end - base as u128) as u64,
+    })
+}
+
+/// Returns true if `range` lies wholly inside `ram`.
+fn in_ram(range: &Region, ram: &Region) -> bool {
+    range.base >= ram.base && range.end_wide() <= ram.end_wide()
+}
+
+/// What to do about an overlap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Copy this blob somewhere else.
+    Relocate(Blob),
+    /// The overlap cannot be resolved.
+    Fail(Problem),
+}
+
+/// Pick how to resolve the overlap between `a` and `b`.
+///
+/// The smaller of the two movable blobs is moved, `a` on a tie. If it
+/// cannot be relocated yet, or neither blob can move, the overlap is
+/// fatal.
+///
+/// # Arguments
+/// * `a` - First blob of the pair
+/// * `b` - Second blob of the pair
+/// * `overlap` - The bytes they share
+pub fn resolve(a: &Placed, b: &Placed, overlap: Overlap) -> Resolution {
+    let victim = match (a.blob.movable(), b.blob.movable()) {
+        (true, true) if b.range.size < a.range.size => b.blob,
+        (true, _) => a.blob,
+        (false, true) => b.blob,
+        (false, false) => return Resolution::Fail(Problem::Overlap(overlap)),
+    };
+    if victim.relocatable() {
+        Resolution::Relocate(victim)
+    } else {
+        Resolution::Fail(Problem::CannotRelocate(overlap, victim))
+    }
+}
+
+/// A placement the kernel cannot boot with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// The blob is not wholly inside RAM.
+    OutsideRam(Placed),
+    /// Two blobs overlap and neither can move.
+    Overlap(Overlap),
+    /// Two blobs overlap and the one to move cannot be relocated yet.
+    CannotRelocate(Overlap, Blob),
+}
+
+impl Problem {
+    /// Returns a short description for the boot failure report.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::OutsideRam(_) => "blob outside RAM",
+            Self::Overlap(_) => "blobs overlap",
+            Self::CannotRelocate(..) => "overlapping blob cannot be relocated",
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutsideRam(placed) => {
+                write!(f, "{} {} is outside RAM", placed.blob, placed.range)
+            }
+            Self::Overlap(overlap) => write!(f, "{}", overlap),
+            Self::CannotRelocate(overlap, blob) => {
+                write!(f, "{}, and {} relocation is not supported", overlap, blob)
+            }
+        }
+    }
+}
+
+/// Check the blob placement against RAM and each other.
+///
+/// # Arguments
+/// * `blobs` - The blobs the loader placed
+/// * `ram` - Physical RAM
+///
+/// # Returns
+/// The blob to relocate, if one must move, or the first problem found
+pub fn audit(blobs: &[Placed], ram: Region) -> Result<Option<Blob>, Problem> {
+    if let Some(outside) = blobs.iter().find(|p| !in_ram(&p.range, &ram)) {
+        return Err(Problem::OutsideRam(*outside));
+    }
+
+    let mut relocate = None;
+    for (i, a) in blobs.iter().enumerate() {
+        for b in &blobs[i + 1..] {
+            let Some(overlap) = overlap(a, b) else {
+                continue;
+            };
+            match resolve(a, b, overlap) {
+                Resolution::Relocate(blob) => relocate = Some(blob),
+                Resolution::Fail(problem) => return Err(problem),
+            }
+        }
+    }
+    Ok(relocate)
+}
+
+/// Write one line per blob with its range and what is wrong with it.
+///
+/// # Arguments
+/// * `out` - Sink for the table
+/// * `blobs` - The blobs the loader placed
+/// * `ram` - Physical RAM
+pub fn write_table(out: &mut impl fmt::Write, blobs: &[Placed], ram: Region) -> fmt::Result {
+    writeln!(out, "Boot blobs, RAM {}:", ram)?;
+    for placed in blobs {
+        write!(out, "  {:<6} {}", placed.blob, placed.range)?;
+        let mut clean = true;
+        if !in_ram(&placed.range, &ram) {
+            write!(out, " outside RAM")?;
+            clean = false;
+        }
+        for other in blobs.iter().filter(|o| o.blob != placed.blob) {
+            if let Some(overlap) = overlap(placed, other) {
+                write!(
+                    out,
+                    " overlaps {} by {:#x} at {:#x}",
+                    other.blob, overlap.size, overlap.base
+                )?;
+                clean = false;
+            }
+        }
+        writeln!(out, "{}", if clean { " ok" } else { "" })?;
+    }
+    Ok(())
+}
+
+/// Read the initrd range from `/chosen`.
+///
+/// `linux,initrd-start` and `linux,initrd-end` may each be one or two
+/// cells.
+///
+/// # Returns
+/// The initrd's physical range, or `None` if there is none or the
+/// properties are incomplete
+pub fn initrd_range(fdt: &Fdt) -> Option<Region> {
+    let chosen = fdt.find_node("/chosen")?;
+    let cell = |name| {
+        let prop = chosen.property(name)?;
+        prop.as_u64().or_else(|| prop.as_u32().map(u64::from))
+    };
+    let start = cell("linux,initrd-start")?;
+    let end = cell("linux,initrd-end")?;
+    (end > start).then(|| Region::new(start, end - start))
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const RAM: Region = Region::new(0x4000_0000, 0x800_0000);
+
+    fn kernel() -> Placed {
+        Placed::new(Blob::Kernel, Region::new(0x4008_0000, 0x20_0000))
+    }
+
+    fn dtb(base: u64) -> Placed {
+        Placed::new(Blob::Dtb, Region::new(base, 0x1_0000))
+    }
+
+    fn initrd(base: u64, size: u64) -> Placed {
+        Placed::new(Blob::Initrd, Region::new(base, size))
+    }
+
+    #[test]
+    fn test_overlap_pairs() {
+        // Straddling the kernel's end
+        let found = overlap(&kernel(), &dtb(0x4027_8000)).unwrap();
+        assert_eq!(found.base, 0x4027_8000);
+        assert_eq!(found.size, 0x8000);
+        assert_eq!(
+            found.to_string(),
+            "kernel overlaps dtb by 32768 bytes at 0x40278000"
+        );
+
+        // Nested wholly inside, either way round
+        let nested = overlap(&dtb(0x4010_0000), &kernel()).unwrap();
+        assert_eq!((nested.base, nested.size), (0x4010_0000, 0x1_0000));
+        let nested = overlap(&kernel(), &dtb(0x4010_0000)).unwrap();
+        assert_eq!((nested.base, nested.size), (0x4010_0000, 0x1_0000));
+
+        // Adjacent on either side is not an overlap
+        assert_eq!(overlap(&kernel(), &dtb(0x4028_0000)), None);
+        assert_eq!(overlap(&kernel(), &dtb(0x4007_0000)), None);
+        assert_eq!(overlap(&kernel(), &dtb(0x4800_0000)), None);
+    }
+
+    #[test]
+    fn test_resolve_moves_smaller_movable_blob() {
+        let kernel = kernel();
+        let bss = dtb(0x4027_8000);
+        let over = overlap(&kernel, &bss).unwrap();
+        assert_eq!(
+            resolve(&kernel, &bss, over),
+            Resolution::Relocate(Blob::Dtb)
+        );
+        assert_eq!(
+            resolve(&bss, &kernel, over),
+            Resolution::Relocate(Blob::Dtb)
+        );
+
+        // The DTB inside a larger initrd moves
+        let big = initrd(0x4100_0000, 0x100_0000);
+        let inside = dtb(0x4180_0000);
+        let over = overlap(&big, &inside).unwrap();
+        assert_eq!(
+            resolve(&big, &inside, over),
+            Resolution::Relocate(Blob::Dtb)
+        );
+
+        // A smaller initrd would have to move, which is not supported
+        let small = initrd(0x4180_8000, 0x1000);
+        let over = overlap(&inside, &small).unwrap();
+        assert_eq!(
+            resolve(&inside, &small, over),
+            Resolution::Fail(Problem::CannotRelocate(over, Blob::Initrd))
+        );
+
+        // So would an initrd over the kernel
+        let small = initrd(0x4010_0000, 0x1000);
+        let over = overlap(&kernel, &small).unwrap();
+        assert_eq!(
+            resolve(&kernel, &small, over),
+            Resolution::Fail(Problem::CannotRelocate(over, Blob::Initrd))
+        );
+    }
+
+    #[test]
+    fn test_resolve_unmovable() {
+        let a = kernel();
+        let b = Placed::new(Blob::Kernel, Region::new(0x4010_0000, 0x1000));
+        let over = overlap(&a, &b).unwrap();
+        assert_eq!(
+            resolve(&a, &b, over),
+            Resolution::Fail(Problem::Overlap(over))
+        );
+    }
+
+    #[test]
+    fn test_audit() {
+        // QEMU's usual layout: kernel low, initrd and DTB high
+        let clean = [
+            kernel(),
+            initrd(0x4400_0000, 0x10_0000),
+            dtb(0x4800_0000 - 0x1_0000),
+        ];
+        assert_eq!(audit(&clean, RAM), Ok(None));
+
+        // DTB dropped on the kernel's BSS
+        let clobbered = [kernel(), dtb(0x4027_8000)];
+        assert_eq!(audit(&clobbered, RAM), Ok(Some(Blob::Dtb)));
+
+        // Initrd on the kernel
+        let fatal = [kernel(), initrd(0x4020_0000, 0x10_0000)];
+        let err = audit(&fatal, RAM).unwrap_err();
+        assert_eq!(err.as_str(), "overlapping blob cannot be relocated");
+        assert_eq!(
+            err.to_string(),
+            "kernel overlaps initrd by 524288 bytes at 0x40200000, and initrd relocation is not supported"
+        );
+    }
+
+    #[test]
+    fn test_audit_outside_ram() {
+        // Running off the end of RAM
+        let past = [kernel(), dtb(0x47ff_8000)];
+        let err = audit(&past, RAM).unwrap_err();
+        assert_eq!(err, Problem::OutsideRam(past[1]));
+        assert_eq!(
+            err.to_string(),
+            "dtb [0x0000000047ff8000 - 0x0000000048008000) (0x10000 bytes) is outside RAM"
+        );
+
+        // Ending exactly at the top is fine
+        assert_eq!(audit(&[dtb(0x47ff_0000)], RAM), Ok(None));
+        // Below RAM
+        assert!(audit(&[dtb(0x3fff_0000)], RAM).is_err());
+    }
+
+    #[test]
+    fn test_write_table() {
+        let blobs = [kernel(), dtb(0x4027_8000), initrd(0x47ff_f000, 0x2000)];
+        let mut out = String::new();
+        write_table(&mut out, &blobs, RAM).unwrap();
+        assert_eq!(
+            out,
+            "Boot blobs, RAM [0x0000000040000000 - 0x0000000048000000) (0x8000000 bytes):\n\
+             \x20 kernel [0x0000000040080000 - 0x0000000040280000) (0x200000 bytes) overlaps dtb by 0x8000 at 0x40278000\n\
+             \x20 dtb    [0x0000000040278000 - 0x0000000040288000) (0x10000 bytes) overlaps kernel by 0x8000 at 0x40278000\n\
+             \x20 initrd [0x0000000047fff000 - 0x0000000048001000) (0x2000 bytes) outside RAM\n"
+        );
+
+        let mut out = String::new();
+        write_table(&mut out, &[kernel()], RAM).unwrap();
+        assert!(out.ends_with("(0x200000 bytes) ok\n"));
+    }
+
+    #[test]
+    fn test_initrd_range() {
+        // The test blob has a start but no end
+        let fdt = Fdt::new(include_bytes!("../../../fdt/test.dtb")).unwrap();
+        assert_eq!(initrd_range(&fdt), None);
+    }
+}
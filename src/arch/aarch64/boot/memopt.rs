@@ -0,0 +1,253 @@
+//! Memory layout overrides from the kernel command line.
+//!
+//! These make the kernel boot as if it had less memory, for testing
+//! degraded configurations without changing the machine:
+//!
+//! - `mem=<size>`: keep only the lowest `size` bytes of RAM
+//! - `memmap=<size>$<base>`: punch a hole at `base`, up to [`MAX_HOLES`]
+//! - `memtest=<passes>`: test free RAM and drop chunks that fail
+//!
+//! Sizes take the `K`, `M` and `G` suffixes of [`parse_size`]. The
+//! overrides are applied once the RAM regions are added and the kernel
+//! and DTB reserved, before anything is allocated. Ranges overlapping a
+//! reservation are left alone, since removing them would pull memory
+//! from under the kernel.
+
+use crate::mm::cma::parse_size;
+use crate::mm::memblock::{Memblock, Region, RegionVec};
+use core::fmt;
+
+/// Maximum number of `memmap=` holes.
+pub const MAX_HOLES: usize = 8;
+
+/// Memory overrides parsed from the command line.
+#[derive(Clone, Copy)]
+pub struct MemOptions {
+    /// `mem=` limit in bytes.
+    pub limit: Option<u64>,
+    /// `memmap=` holes, in command line order.
+    holes: RegionVec<MAX_HOLES>,
+    /// `memmap=` options dropped because the hole list was full.
+    pub dropped: usize,
+    /// `memtest=` passes, 0 if off.
+    pub memtest_passes: u32,
+}
+
+impl MemOptions {
+    /// Parse the overrides in `cmdline`.
+    ///
+    /// The last `mem=` and `memtest=` options win, `memmap=` options add
+    /// up. Malformed values are ignored.
+    pub fn parse(cmdline: &str) -> Self {
+        let mut opts = Self {
+            limit: None,
+            holes: RegionVec::new(),
+            dropped: 0,
+            memtest_passes: 0,
+        };
+
+        for arg in cmdline.split_ascii_whitespace() {
+            if let Some(value) = arg.strip_prefix("mem=") {
+                if let Some(limit) = parse_size(value) {
+                    opts.limit = Some(limit);
+                }
+            } else if let Some(value) = arg.strip_prefix("memmap=") {
+                if let Some(hole) = parse_memmap(value)
+                    && opts.holes.push(hole).is_err()
+                {
+                    opts.dropped += 1;
+                }
+            } else if let Some(value) = arg.strip_prefix("memtest=")
+                && let Ok(passes) = value.parse()
+            {
+                opts.memtest_passes = passes;
+            }
+        }
+        opts
+    }
+
+    /// Returns the `memmap=` holes.
+    pub fn holes(&self) -> &[Region] {
+        self.holes.as_slice()
+    }
+
+    /// Apply `mem=` and the holes to `mb`, logging each change to `out`.
+    ///
+    /// # Returns
+    /// The number of bytes of RAM removed
+    pub fn apply(&self, mb: &mut Memblock, out: &mut dyn fmt::Write) -> Result<u64, &'static str> {
+        let mut removed = 0;
+
+        if let Some(limit) = self.limit
+            && let Some(range) = limit_range(mb, limit)
+        {
+            removed += remove_logged(mb, "mem=", range, out)?;
+        }
+        for &hole in self.holes() {
+            removed += remove_logged(mb, "memmap=", hole, out)?;
+        }
+        if self.dropped != 0 {
+            let _ = writeln!(
+                out,
+                "memmap=: {} options over the limit of {} ignored",
+                self.dropped, MAX_HOLES
+            );
+        }
+
+        Ok(removed)
+    }
+}
+
+/// Parse a `memmap=` value, `<size>$<base>`.
+pub fn parse_memmap(value: &str) -> Option<Region> {
+    let (size, base) = value.split_once('$')?;
+    let hole = Region::new(parse_size(base)?, parse_size(size)?);
+    (hole.size != 0 && hole.base.checked_add(hole.size).is_some()).then_some(hole)
+}
+
+/// Returns the RAM above the lowest `limit` bytes, `None` if there is
+/// no more than `limit` bytes of RAM.
+pub fn limit_range(mb: &Memblock, limit: u64) -> Option<Region> {
+    let mut left = limit;
+    let top = mb.memory_regions().last()?.end_wide();
+    for region in mb.memory_regions() {
+        if region.size > left {
+            let cut = region.base + left;
+            return Some(Region::new(cut, (top - cut as u128) as u64));
+        }
+        left -= region.size;
+    }
+    None
+}
+
+/// Remove `range` from `mb` unless it overlaps a reservation.
+///
+/// # Returns
+/// The number of bytes of RAM removed
+fn remove_logged(
+    mb: &mut Memblock,
+    option: &str,
+    range: Region,
+    out: &mut dyn fmt::Write,
+) -> Result<u64, &'static str> {
+    if mb.reserved_regions().iter().any(|r| r.overlaps(&range)) {
+        let _ = writeln!(out, "{} {} overlaps a reservation, skipped", option, range);
+        return Ok(0);
+    }
+
+    let before = mb.total_memory();
+    let report = mb.remove(range.base, range.size).map_err(|e| e.as_str())?;
+    let _ = writeln!(
+        out,
+        "{} removed {}, memory {:#x} -> {:#x}",
+        option,
+        range,
+        before,
+        mb.total_memory()
+    );
+    Ok(report.memory_removed)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const RAM: u64 = 0x4000_0000;
+
+    fn memblock() -> Memblock {
+        let mut mb = Memblock::new();
+        mb.add(RAM, 0x1000_0000).unwrap();
+        mb.reserve(RAM + 0x8_0000, 0x20_0000).unwrap();
+        mb
+    }
+
+    #[test]
+    fn test_parse_memmap() {
+        assert_eq!(
+            parse_memmap("64M$0x48000000"),
+            Some(Region::new(0x4800_0000, 0x400_0000))
+        );
+        assert_eq!(
+            parse_memmap("0x1000$1G"),
+            Some(Region::new(0x4000_0000, 0x1000))
+        );
+        assert_eq!(parse_memmap("64M"), None);
+        assert_eq!(parse_memmap("0$0x1000"), None);
+        assert_eq!(parse_memmap("64M$"), None);
+        assert_eq!(parse_memmap("1G$0xffffffffffff0000"), None);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let opts = MemOptions::parse(
+            "console mem=512M memmap=1M$0x48000000 mem=256M memtest=2 memmap=4K$0x49000000 mem=x",
+        );
+        assert_eq!(opts.limit, Some(256 << 20));
+        assert_eq!(opts.memtest_passes, 2);
+        assert_eq!(
+            opts.holes(),
+            [
+                Region::new(0x4800_0000, 1 << 20),
+                Region::new(0x4900_0000, 0x1000)
+            ]
+        );
+        assert_eq!(opts.dropped, 0);
+
+        let opts = MemOptions::parse("");
+        assert_eq!(opts.limit, None);
+        assert!(opts.holes().is_empty());
+        assert_eq!(opts.memtest_passes, 0);
+
+        let many = "memmap=4K$0x48000000 ".repeat(MAX_HOLES + 2);
+        let opts = MemOptions::parse(&many);
+        assert_eq!(opts.holes().len(), MAX_HOLES);
+        assert_eq!(opts.dropped, 2);
+    }
+
+    #[test]
+    fn test_limit_range() {
+        let mut mb = memblock();
+        assert_eq!(
+            limit_range(&mb, 0x800_0000),
+            Some(Region::new(0x4800_0000, 0x800_0000))
+        );
+        assert_eq!(limit_range(&mb, 0x1000_0000), None);
+
+        // The limit counts RAM, not address space
+        mb.remove(RAM + 0x100_0000, 0x100_0000).unwrap();
+        assert_eq!(
+            limit_range(&mb, 0x800_0000),
+            Some(Region::new(0x4900_0000, 0x700_0000))
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut mb = memblock();
+        let opts = MemOptions::parse(
+            "mem=128M memmap=1M$0x44000000 memmap=1M$0x40000000 memmap=1M$0x4c000000",
+        );
+        let mut log = String::new();
+
+        // The limit and the first hole go, the hole over the kernel stays
+        assert_eq!(opts.apply(&mut mb, &mut log), Ok(0x810_0000));
+        assert_eq!(mb.total_memory(), 0x7f0_0000);
+        assert_eq!(
+            mb.memory_regions(),
+            [
+                Region::new(RAM, 0x400_0000),
+                Region::new(0x4410_0000, 0x3f0_0000)
+            ]
+        );
+        assert_eq!(mb.reserved_regions().len(), 1);
+
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("mem= removed"));
+        assert!(lines[0].ends_with("memory 0x10000000 -> 0x8000000"));
+        assert!(lines[1].ends_with("memory 0x8000000 -> 0x7f00000"));
+        assert!(lines[2].ends_with("overlaps a reservation, skipped"));
+        // Already gone with mem=, nothing left to remove
+        assert!(lines[3].ends_with("memory 0x7f00000 -> 0x7f00000"));
+    }
+}
@@ -3,10 +3,46 @@
 //! This module handles kernel boot process, memory initialization, and
 //! early system setup.
 
-use crate::arch::aarch64::address;
+use crate::arch::{address, psci};
+use crate::fdt::Fdt;
 use crate::mm::memblock;
+use core::fmt;
+#[cfg(target_os = "none")]
+use core::sync::atomic::{AtomicU32, Ordering};
+
+pub mod adopt;
+pub mod chainload;
+#[cfg(any(not(feature = "debug_shell"), test))]
+pub mod console;
+pub mod early_alloc;
+pub mod initcall;
+pub mod layout_audit;
+pub mod memopt;
+#[cfg(all(target_os = "none", feature = "payload"))]
+pub mod payload;
+pub mod report;
+#[cfg(any(feature = "debug_shell", test))]
+pub mod shell;
+pub mod timeline;
+pub mod watchdog;
+pub mod xmodem;
+
+#[cfg(target_os = "none")]
+#[allow(unused_imports)]
+pub use chainload::chainload;
+#[cfg(target_os = "none")]
+pub use initcall::{InitLevel, run_initcalls};
+#[cfg(target_os = "none")]
+pub use timeline::phase;
+
+#[cfg(target_os = "none")]
+unsafe extern "C" {
+    /// Top of the boot stack (from linker script).
+    static __boot_stack_top: u8;
+}
 
 /// Kernel boot information.
+#[derive(Clone, Copy)]
 pub struct BootInfo {
     /// Physical address of kernel image start.
     pub kernel_phys_start: u64,
@@ -15,124 +51,1409 @@ pub struct BootInfo {
     pub kernel_phys_end: u64,
     /// Size of kernel image in bytes.
     pub kernel_size: u64,
+    /// Physical address of the unpacked payload.
+    #[allow(dead_code)]
+    pub payload_phys: u64,
+    /// Size of the unpacked payload in bytes, zero if there is none.
+    #[allow(dead_code)]
+    pub payload_size: u64,
+}
+
+/// Kernel image bounds that cannot be right, the one error type of the
+/// boot time layout checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    /// The end symbol lies below the start symbol.
+    Inverted,
+    /// Start and end symbols are equal.
+    Empty,
+    /// The image is larger than [`MAX_KERNEL_SIZE`].
+    ///
+    /// [`MAX_KERNEL_SIZE`]: address::layout_checks::MAX_KERNEL_SIZE
+    TooLarge {
+        /// Bytes between the symbols.
+        size: u64,
+    },
+    /// A bound is not a linear map address.
+    NotLinearMapped {
+        /// The offending virtual address.
+        virt: u64,
+    },
+    /// A bound translates to a physical address outside RAM.
+    OutsideRam {
+        /// The offending physical address.
+        phys: u64,
+    },
+    /// A bound is not page aligned.
+    Unaligned {
+        /// The offending virtual address.
+        virt: u64,
+    },
+}
+
+impl BootInfoError {
+    /// Returns a short description of the violated check.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Inverted => "image end is below its start",
+            Self::Empty => "image is empty",
+            Self::TooLarge { .. } => "image exceeds sanity size limit",
+            Self::NotLinearMapped { .. } => "image bound is outside the linear map",
+            Self::OutsideRam { .. } => "image bound is outside RAM",
+            Self::Unaligned { .. } => "image bound is not page aligned",
+        }
+    }
+}
+
+impl fmt::Display for BootInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())?;
+        match *self {
+            Self::Inverted | Self::Empty => Ok(()),
+            Self::TooLarge { size } => write!(
+                f,
+                " ({:#x} > {:#x})",
+                size,
+                address::layout_checks::MAX_KERNEL_SIZE
+            ),
+            Self::NotLinearMapped { virt } | Self::Unaligned { virt } => {
+                write!(f, " ({:#x})", virt)
+            }
+            Self::OutsideRam { phys } => write!(f, " ({:#x})", phys),
+        }
+    }
 }
 
 impl BootInfo {
     /// Create boot info from kernel virtual addresses.
     ///
+    /// The addresses come straight from linker symbols, so a regressed
+    /// linker script shows up here rather than as a wrapped `kernel_size`
+    /// handed to memblock. This is where the load address dependent
+    /// layout checks live; the rest are `const` asserts in
+    /// `address::layout_checks`.
+    ///
     /// # Arguments
     /// * `kernel_virt_start` - Virtual start address of kernel
     /// * `kernel_virt_end` - Virtual end address of kernel
-    pub fn from_virtual(kernel_virt_start: u64, kernel_virt_end: u64) -> Self {
-        let kernel_phys_start = address::translation::virt_to_phys(kernel_virt_start);
-        let kernel_phys_end = address::translation::virt_to_phys(kernel_virt_end);
-        let kernel_size = kernel_phys_end - kernel_phys_start;
+    ///
+    /// # Returns
+    /// The boot info, or the first check the bounds fail
+    pub fn from_virtual(
+        kernel_virt_start: u64,
+        kernel_virt_end: u64,
+    ) -> Result<Self, BootInfoError> {
+        use address::layout_checks::MAX_KERNEL_SIZE;
+        use address::{kernel, translation, virt};
+
+        if kernel_virt_end < kernel_virt_start {
+            return Err(BootInfoError::Inverted);
+        }
+        if kernel_virt_end == kernel_virt_start {
+            return Err(BootInfoError::Empty);
+        }
+        let kernel_size = kernel_virt_end - kernel_virt_start;
+        if kernel_size > MAX_KERNEL_SIZE {
+            return Err(BootInfoError::TooLarge { size: kernel_size });
+        }
+        if let Some(virt) = [kernel_virt_start, kernel_virt_end]
+            .into_iter()
+            .find(|v| !v.is_multiple_of(kernel::PAGE_SIZE))
+        {
+            return Err(BootInfoError::Unaligned { virt });
+        }
 
-        Self {
+        // The end is exclusive, its last byte is what must be mapped
+        let translate = |virt| {
+            translation::try_virt_to_phys(virt).ok_or(BootInfoError::NotLinearMapped { virt })
+        };
+        let kernel_phys_start = translate(kernel_virt_start)?;
+        let kernel_phys_end = translate(kernel_virt_end - 1)? + 1;
+        if kernel_phys_start < virt::RAM_BASE {
+            return Err(BootInfoError::OutsideRam {
+                phys: kernel_phys_start,
+            });
+        }
+        if kernel_phys_end > virt::RAM_END {
+            return Err(BootInfoError::OutsideRam {
+                phys: kernel_phys_end,
+            });
+        }
+
+        Ok(Self {
             kernel_phys_start,
             kernel_phys_end,
             kernel_size,
-        }
+            payload_phys: 0,
+            payload_size: 0,
+        })
     }
 }
 
+/// Blobs the loader placed next to the kernel, as found by the layout
+/// audit.
+#[derive(Clone, Copy)]
+pub struct BootBlobs {
+    /// Physical address of the DTB, zero if none was passed.
+    pub dtb_phys: u64,
+    /// Initrd range named in `/chosen`, if there is one.
+    pub initrd: Option<memblock::Region>,
+    /// Range of a DTB that overlaps another blob and has to be copied
+    /// before it is reserved.
+    pub relocate_dtb: Option<memblock::Region>,
+}
+
+/// What init calls need to know about this boot, recorded by
+/// `kernel_init` before the first level that reads it.
+#[cfg(target_os = "none")]
+#[derive(Clone, Copy)]
+struct BootParams {
+    /// Kernel image placement.
+    info: BootInfo,
+    /// Loader placed blobs; `init_memory` updates the DTB address if it
+    /// relocates the blob.
+    blobs: BootBlobs,
+    /// Kernel command line.
+    cmdline: &'static str,
+}
+
+/// Boot parameters for init calls.
+#[cfg(target_os = "none")]
+static BOOT_PARAMS: crate::arch::sync::IrqSafeMutex<Option<BootParams>> =
+    crate::arch::sync::IrqSafeMutex::new("boot_params", None);
+
+/// Memblock state right after `init_memory`, diffed against the final
+/// state in the boot report.
+#[cfg(target_os = "none")]
+static INIT_SNAPSHOT: crate::arch::sync::IrqSafeMutex<Option<memblock::MemblockSnapshot>> =
+    crate::arch::sync::IrqSafeMutex::new("init_snapshot", None);
+
 /// Initialize memory management subsystem.
 ///
+/// The kernel image, the initrd, the device tree and the device register
+/// windows are reserved before anything can be allocated, and the
+/// `mem=`, `memmap=` and `memtest=` overrides are applied. Then the CMA
+/// pool is carved while memory is still unfragmented.
+///
+/// A DTB the layout audit found overlapping another blob is first copied
+/// into a memblock allocation; `blobs` is updated with the new address
+/// and `cmdline` re-read from the copy, since the old one may be reused.
+///
 /// # Arguments
 /// * `boot_info` - Kernel boot information
+/// * `blobs` - Loader placed blobs
+/// * `cmdline` - Kernel command line
 ///
 /// # Returns
 /// Result indicating success or error
-pub fn init_memory(boot_info: &BootInfo) -> Result<(), &'static str> {
+pub fn init_memory(
+    boot_info: &BootInfo,
+    blobs: &mut BootBlobs,
+    cmdline: &mut &'static str,
+) -> Result<(), &'static str> {
     // Get RAM region for QEMU Virt platform
     let (ram_base, ram_size) = address::regions::ram();
 
     // Initialize memblock with available RAM
     memblock::init(ram_base, ram_size)?;
+    #[cfg(target_os = "none")]
+    classify_memory(blobs.dtb_phys)?;
 
     // Reserve kernel image memory
-    memblock::reserve(boot_info.kernel_phys_start, boot_info.kernel_size)?;
+    memblock::reserve_tagged(
+        boot_info.kernel_phys_start,
+        boot_info.kernel_size,
+        memblock::ReservationOwner::KernelImage,
+    )
+    .map_err(|e| e.as_str())?;
+    #[cfg(target_os = "none")]
+    {
+        use crate::arch::serial;
+        use core::fmt::Write;
+
+        let reclaimed = early_alloc::reclaim()?;
+        let _ = writeln!(serial::Writer, "{reclaimed}");
+    }
+    reserve_mmio_regions(&mut memblock::lock())?;
+    if let Some(initrd) = blobs.initrd {
+        memblock::reserve_tagged(initrd.base, initrd.size, memblock::ReservationOwner::Initrd)
+            .map_err(|e| e.as_str())?;
+    }
+
+    if let Some(old) = blobs.relocate_dtb.take() {
+        blobs.dtb_phys = relocate_dtb(old)?;
+        #[cfg(target_os = "none")]
+        {
+            use crate::arch::serial;
+            use core::fmt::Write;
+
+            let _ = writeln!(
+                serial::Writer,
+                "DTB relocated from {:#x} to {:#x}",
+                old.base,
+                blobs.dtb_phys
+            );
+            *cmdline = device_tree(blobs.dtb_phys).map_or("", |fdt| bootargs(&fdt));
+        }
+    } else if let Some(dtb_ptr) = dtb_pointer(blobs.dtb_phys) {
+        // Safety: the pointer is inside the boot linear map of RAM
+        unsafe { reserve_dtb(dtb_ptr) }?;
+    }
+
+    #[cfg(target_os = "none")]
+    apply_mem_options(cmdline)?;
+
+    crate::mm::cma::init(cmdline)?;
 
     Ok(())
 }
 
+/// Unpack the embedded payload and record where it went.
+///
+/// A payload that fails to unpack is reported and left out; boot goes on
+/// without it.
+#[cfg(all(target_os = "none", feature = "payload"))]
+fn unpack_payload(boot_info: &mut BootInfo) {
+    use crate::arch::serial;
+    use core::fmt::Write;
+
+    match payload::unpack() {
+        Ok((phys, size)) => {
+            boot_info.payload_phys = phys;
+            boot_info.payload_size = size;
+            if let Some(params) = BOOT_PARAMS.lock().as_mut() {
+                params.info = *boot_info;
+            }
+            let _ = writeln!(serial::Writer, "Payload: {:#x} bytes at {:#x}", size, phys);
+        }
+        Err(e) => {
+            let _ = writeln!(serial::Writer, "Failed to unpack payload: {}", e);
+        }
+    }
+}
+
+/// Mark the firmware and device carve-outs the device tree lists in
+/// memblock, so only normal memory gets allocated.
+#[cfg(target_os = "none")]
+fn classify_memory(dtb_phys: u64) -> Result<(), &'static str> {
+    let Some(fdt) = device_tree(dtb_phys) else {
+        return Ok(());
+    };
+    let mut mb = memblock::lock();
+    for bank in memory_banks(&fdt).filter(|b| b.kind != memblock::RegionKind::Normal) {
+        mb.set_kind(bank.base, bank.size, bank.kind)
+            .map_err(|e| e.as_str())?;
+    }
+    Ok(())
+}
+
+/// Apply the memory overrides in `cmdline`, then run `memtest=`.
+///
+/// Chunks failing the memory test are removed from memblock.
+#[cfg(target_os = "none")]
+fn apply_mem_options(cmdline: &str) -> Result<(), &'static str> {
+    use crate::arch::serial;
+    use crate::mm::memtest;
+    use core::fmt::Write;
+
+    let opts = memopt::MemOptions::parse(cmdline);
+    let mut mb = memblock::lock();
+    opts.apply(&mut mb, &mut serial::Writer)?;
+    if opts.memtest_passes == 0 {
+        return Ok(());
+    }
+
+    let mut free = memblock::RegionVec::<32>::new();
+    let mut untested = 0;
+    mb.for_each_free(|range| {
+        if free.push(range).is_err() {
+            untested += range.size;
+        }
+    });
+
+    let mut result = Ok(());
+    let failed = memtest::run(
+        &mut memtest::LinearMap,
+        free.as_slice(),
+        opts.memtest_passes,
+        |chunk| {
+            let _ = writeln!(serial::Writer, "memtest: bad memory {}, removed", chunk);
+            if let Err(e) = mb.remove(chunk.base, chunk.size) {
+                result = Err(e.as_str());
+            }
+        },
+    );
+    let _ = writeln!(
+        serial::Writer,
+        "memtest: {} passes over {:#x} bytes, {} bad chunks, {:#x} bytes untested",
+        opts.memtest_passes,
+        free.total_size(),
+        failed,
+        untested
+    );
+    result
+}
+
+/// Init call setting up memblock from the recorded boot parameters.
+///
+/// The parameters are written back, as the DTB may have moved.
+#[cfg(target_os = "none")]
+fn memory_setup() -> Result<(), &'static str> {
+    let mut params = (*BOOT_PARAMS.lock()).ok_or("boot parameters not recorded")?;
+    init_memory(&params.info, &mut params.blobs, &mut params.cmdline)?;
+    *INIT_SNAPSHOT.lock() = Some(memblock::lock().snapshot());
+    if let Some(recorded) = BOOT_PARAMS.lock().as_mut() {
+        recorded.blobs = params.blobs;
+        recorded.cmdline = params.cmdline;
+    }
+    Ok(())
+}
+
+initcall!(MemorySetup, "memblock", memory_setup);
+
+/// Print the reservations made and released since `init_memory`.
+#[cfg(target_os = "none")]
+fn print_init_memory_diff() {
+    use crate::arch::serial;
+    use core::fmt::Write;
+
+    let Some(before) = INIT_SNAPSHOT.lock().take() else {
+        return;
+    };
+    let after = memblock::lock().snapshot();
+    let _ = writeln!(serial::Writer, "Memory consumed during init:");
+    let _ = memblock::diff(&before, &after, &mut serial::Writer);
+}
+
+/// Compute the physical range covered by a device tree blob.
+///
+/// # Arguments
+/// * `dtb_phys` - Physical address of the blob
+/// * `header` - The blob's header bytes
+///
+/// # Returns
+/// `[dtb_phys, dtb_phys + totalsize)`, or why the header is unusable
+pub fn dtb_range(dtb_phys: u64, header: &[u8]) -> Result<memblock::Region, &'static str> {
+    let size = crate::fdt::header_total_size(header).map_err(|e| e.as_str())? as u64;
+    dtb_phys.checked_add(size).ok_or("DTB range wraps")?;
+    Ok(memblock::Region::new(dtb_phys, size))
+}
+
+/// Copy the device tree blob at `old` into a new "dtb" allocation.
+///
+/// The allocation may reuse part of `old`, which is not reserved, so the
+/// copy handles overlap.
+///
+/// # Returns
+/// The physical address of the copy
+fn relocate_dtb(old: memblock::Region) -> Result<u64, &'static str> {
+    let new = memblock::alloc_tagged(old.size, 8, memblock::ReservationOwner::Dtb)
+        .map_err(|e| e.as_str())?;
+    let src = address::translation::phys_to_virt(old.base) as *const u8;
+    let dst = address::translation::phys_to_virt(new) as *mut u8;
+    // Safety: both ranges are RAM, which the boot linear map covers, and
+    // nothing else uses the blob this early
+    unsafe { core::ptr::copy(src, dst, old.size as usize) };
+    Ok(new)
+}
+
+/// Reserve the device tree blob at `dtb_ptr` as "dtb".
+///
+/// # Arguments
+/// * `dtb_ptr` - Linear map address of the blob
+///
+/// # Returns
+/// The reserved physical range, or `None` if no valid blob header is
+/// there and nothing was reserved
+///
+/// # Safety
+/// `dtb_ptr` must point to at least `fdt::HEADER_SIZE` readable bytes in
+/// the linear map.
+pub unsafe fn reserve_dtb(dtb_ptr: *const u8) -> Result<Option<memblock::Region>, &'static str> {
+    // Safety: guaranteed by the caller
+    let header = unsafe { core::slice::from_raw_parts(dtb_ptr, crate::fdt::HEADER_SIZE) };
+    let dtb_phys = address::translation::virt_to_phys(dtb_ptr as u64);
+    let Ok(range) = dtb_range(dtb_phys, header) else {
+        return Ok(None);
+    };
+
+    memblock::reserve_tagged(range.base, range.size, memblock::ReservationOwner::Dtb)
+        .map_err(|e| e.as_str())?;
+    Ok(Some(range))
+}
+
+/// Reserve the platform's device register windows.
+///
+/// They lie outside RAM, so nothing could be allocated there anyway, but
+/// with them reserved as [`memblock::FLAG_MMIO`] the memblock dumps show
+/// the whole physical map and device mappings can be enumerated from
+/// memblock. The window names are in [`address::regions::mmio`].
+///
+/// # Arguments
+/// * `mb` - Memblock to reserve the windows in
+pub fn reserve_mmio_regions(mb: &mut memblock::Memblock) -> Result<(), &'static str> {
+    for &(_, base, size) in address::regions::mmio() {
+        mb.reserve_with_flags(
+            base,
+            size,
+            memblock::FLAG_MMIO,
+            memblock::ReservationOwner::Mmio,
+        )
+        .map_err(|e| e.as_str())?;
+    }
+    Ok(())
+}
+
+/// Returns the linear map address of the DTB, if `dtb_phys` is in RAM.
+fn dtb_pointer(dtb_phys: u64) -> Option<*const u8> {
+    let (ram_base, ram_size) = address::regions::ram();
+    if dtb_phys < ram_base || dtb_phys >= ram_base + ram_size {
+        return None;
+    }
+    Some(address::translation::phys_to_virt(dtb_phys) as *const u8)
+}
+
+/// Compute the guard page address for a stack.
+///
+/// # Arguments
+/// * `stack_top` - Highest address of the stack (exclusive)
+/// * `stack_size` - Size of the stack in bytes
+///
+/// # Returns
+/// Address of the page immediately below the lowest stack page
+pub fn stack_guard_page(stack_top: u64, stack_size: u64) -> u64 {
+    let page_mask = !(address::kernel::PAGE_SIZE - 1);
+    let stack_bottom = (stack_top - stack_size) & page_mask;
+    stack_bottom - address::kernel::PAGE_SIZE
+}
+
+/// Unmap the page below the boot stack so overflows fault.
+///
+/// The guard page is also reserved in memblock with `FLAG_NOMAP` so it is
+/// never handed out or mapped again.
+///
+/// # Returns
+/// Virtual address of the guard page or error
+#[cfg(target_os = "none")]
+pub fn setup_stack_guard() -> Result<u64, &'static str> {
+    use crate::arch::pagetable;
+    use crate::mm::kstack;
+
+    // Safety: only the address of the linker symbol is taken
+    let stack_top = unsafe { &__boot_stack_top as *const u8 as u64 };
+    let guard = stack_guard_page(stack_top, address::kernel::STACK_SIZE);
+
+    memblock::reserve_with_flags(
+        address::translation::virt_to_phys(guard),
+        address::kernel::PAGE_SIZE,
+        memblock::FLAG_NOMAP,
+        memblock::ReservationOwner::Stack,
+    )
+    .map_err(|e| e.as_str())?;
+    pagetable::unmap_page(guard)?;
+
+    // Let the exception handler report overflows of the boot stack
+    let layout = kstack::StackLayout::new(guard, address::kernel::STACK_SIZE);
+    kstack::register(layout).map_err(|e| e.as_str())?;
+
+    Ok(guard)
+}
+
 /// Test memory allocation functionality.
 ///
 /// # Returns
 /// Result with allocated address or error
+#[cfg(target_os = "none")]
 pub fn test_memory_allocation() -> Result<u64, &'static str> {
-    // Test allocation of a 4KB page with 4KB alignment
-    memblock::alloc(address::kernel::PAGE_SIZE, address::kernel::PAGE_SIZE)
+    // Through the page allocator façade, as the buddy allocator may
+    // already have taken over from memblock
+    crate::mm::pmm::alloc_page()
+}
+
+/// Number of self tests that failed during boot.
+#[cfg(target_os = "none")]
+static SELFTEST_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// Init call running the self tests and reporting their results.
+#[cfg(target_os = "none")]
+fn selftest() -> Result<(), &'static str> {
+    use crate::arch::serial;
+    use core::fmt::Write;
+
+    serial::write_str("Testing serial loopback... ");
+    let loopback = serial::selftest();
+    serial::frame::send_test_result("serial_loopback", loopback.is_ok());
+    match loopback {
+        Ok(report) => {
+            let _ = writeln!(serial::Writer, "ok ({} stale bytes)", report.drained);
+        }
+        Err(e) => {
+            SELFTEST_FAILURES.fetch_add(1, Ordering::Relaxed);
+            let _ = writeln!(serial::Writer, "FAILED ({})", e);
+        }
+    }
+
+    serial::write_str("Testing memory allocation...\n");
+    let allocated = test_memory_allocation();
+    serial::frame::send_test_result("memory_allocation", allocated.is_ok());
+    if allocated.is_err() {
+        SELFTEST_FAILURES.fetch_add(1, Ordering::Relaxed);
+    }
+    let addr = allocated?;
+
+    serial::write_str("Allocated page at ");
+    // Simple hex output
+    let hex_digits = b"0123456789ABCDEF";
+    for shift in (0..16).rev() {
+        let nibble = (addr >> (shift * 4)) & 0xF;
+        serial::write_byte(hex_digits[nibble as usize]);
+    }
+    serial::write_str("\n");
+    Ok(())
 }
 
+initcall!(Late, "selftest", selftest, optional);
+
 /// Print kernel memory information.
 ///
 /// # Arguments
 /// * `boot_info` - Kernel boot information
 pub fn print_memory_info(_boot_info: &BootInfo) {
-    use crate::arch::aarch64::serial;
+    use crate::arch::serial;
 
     serial::write_str("Kernel physical memory: [");
     // TODO: Implement proper hex formatting
     serial::write_str("]\n");
+
+    let total = memblock::lock().total_memory();
+    serial::write_str("Total RAM: ");
+    serial::write_dec_u64(total / (1024 * 1024));
+    serial::write_str(" MiB\n");
+}
+
+/// First exit status used for a failed boot stage.
+///
+/// Statuses below it count failed self tests, 0 is a clean boot.
+pub const FAIL_EXIT_BASE: u32 = 0x40;
+
+/// Boot stage whose failure stops boot, see [`fail`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum FailStage {
+    /// Kernel layout validation.
+    Layout = 1,
+    /// Fixmap setup.
+    Fixmap,
+    /// A mandatory init call.
+    Initcall,
+    /// Chainload target validation.
+    Chainload,
+    /// Running at an exception level other than EL1.
+    ExceptionLevel,
+}
+
+impl FailStage {
+    /// Returns the exit status reported for a failure in this stage.
+    pub const fn exit_code(self) -> u32 {
+        FAIL_EXIT_BASE + self as u32
+    }
+}
+
+/// Returns the exit status reported when `failed` self tests failed.
+///
+/// Clamped below [`FAIL_EXIT_BASE`], so it is never mistaken for a
+/// failed stage.
+#[allow(dead_code)]
+pub const fn selftest_exit_code(failed: u32) -> u32 {
+    if failed < FAIL_EXIT_BASE {
+        failed
+    } else {
+        FAIL_EXIT_BASE - 1
+    }
+}
+
+/// Check that the kernel runs at EL1.
+///
+/// boot.S drops from EL2 with `HCR_EL2`/`SPSR_EL2` from
+/// [`crate::arch::sysregs::BOOT_HCR_EL2`] and
+/// [`crate::arch::sysregs::BOOT_SPSR_EL2`] before the MMU is enabled; by the
+/// time Rust runs there is no way back up to redo it. This catches an
+/// entry path that skipped the drop, or firmware that entered at EL3.
+pub fn ensure_el1() -> Result<(), &'static str> {
+    match crate::arch::reg::current_el() {
+        1 => Ok(()),
+        0 => Err("running at EL0"),
+        2 => Err("still at EL2, boot.S did not drop to EL1"),
+        _ => Err("running at EL3"),
+    }
+}
+
+/// Kernel command line flag asking to exit once boot is complete.
+pub const EXIT_AFTER_BOOT_FLAG: &str = "exit_after_boot";
+
+/// Returns true if `cmdline` asks to exit once boot is complete.
+#[allow(dead_code)]
+pub fn exit_after_boot(cmdline: &str) -> bool {
+    cmdline
+        .split_ascii_whitespace()
+        .any(|arg| arg == EXIT_AFTER_BOOT_FLAG)
+}
+
+/// Report an unrecoverable boot failure and stop.
+///
+/// Prints the failing step, the error and the current memblock state,
+/// then exits with the status of `stage` (see [`semihosting::exit`]).
+///
+/// [`semihosting::exit`]: crate::arch::semihosting::exit
+///
+/// # Arguments
+/// * `stage` - Stage that failed
+/// * `what` - Description of the step that failed
+/// * `err` - Error message
+#[cfg(target_os = "none")]
+pub fn fail(stage: FailStage, what: &str, err: &str) -> ! {
+    use crate::arch::serial::{self, color, frame};
+    use core::fmt::Write;
+
+    let _ = color::write_prefix(
+        &mut serial::Writer,
+        color::Level::Error,
+        color::color_enabled(),
+        module_path!(),
+    );
+    let _ = writeln!(serial::Writer, "{}: {}", what, err);
+    let mut report = frame::FrameBuf::new();
+    let _ = write!(report, "{}: {}", what, err);
+    let _ = frame::send_frame(frame::FrameKind::PanicReport, report.as_bytes());
+    let _ = memblock::try_dump_to(&mut serial::Writer);
+    crate::arch::semihosting::exit(stage.exit_code())
+}
+
+/// Wrap the device tree passed by the bootloader.
+///
+/// # Arguments
+/// * `dtb_phys` - Physical address of the DTB, zero if none was passed
+///
+/// # Returns
+/// The parsed blob, or `None` if it is missing, outside RAM or invalid
+#[cfg(target_os = "none")]
+fn device_tree(dtb_phys: u64) -> Option<Fdt<'static>> {
+    let ptr = dtb_pointer(dtb_phys)?;
+    // Safety: the boot linear map covers all of RAM, and `init_memory`
+    // reserves the blob before anything is allocated.
+    unsafe { Fdt::from_ptr(ptr) }.ok()
+}
+
+/// Audit where the loader put the kernel, DTB and initrd.
+///
+/// Prints the placement table, and stops the boot if the blobs cannot be
+/// kept apart (see [`layout_audit`]).
+///
+/// # Arguments
+/// * `boot_info` - Kernel image placement
+/// * `dtb_phys` - Physical address of the DTB, zero if none was passed
+/// * `fdt` - The parsed DTB, if it is usable
+///
+/// # Returns
+/// The blobs for `init_memory` to reserve
+#[cfg(target_os = "none")]
+fn audit_layout(boot_info: &BootInfo, dtb_phys: u64, fdt: Option<&Fdt>) -> BootBlobs {
+    use crate::arch::serial;
+    use core::fmt::Write;
+    use layout_audit::{Blob, Placed};
+
+    let (ram_base, ram_size) = address::regions::ram();
+    let ram = memblock::Region::new(ram_base, ram_size);
+    let kernel = memblock::Region::new(boot_info.kernel_phys_start, boot_info.kernel_size);
+    let dtb = fdt.map(|fdt| memblock::Region::new(dtb_phys, fdt.total_size() as u64));
+    let initrd = fdt.and_then(layout_audit::initrd_range);
+
+    let mut placed = [Placed::new(Blob::Kernel, kernel); 3];
+    let mut count = 1;
+    for (blob, range) in [(Blob::Dtb, dtb), (Blob::Initrd, initrd)] {
+        if let Some(range) = range {
+            placed[count] = Placed::new(blob, range);
+            count += 1;
+        }
+    }
+    let placed = &placed[..count];
+
+    let _ = layout_audit::write_table(&mut serial::Writer, placed, ram);
+    match layout_audit::audit(placed, ram) {
+        Ok(relocate) => BootBlobs {
+            dtb_phys,
+            initrd,
+            relocate_dtb: dtb.filter(|_| relocate == Some(Blob::Dtb)),
+        },
+        Err(problem) => {
+            let _ = writeln!(serial::Writer, "{}", problem);
+            fail(FailStage::Layout, "Boot blob layout", problem.as_str())
+        }
+    }
+}
+
+/// Iterates over the RAM banks the device tree describes, classified.
+///
+/// `/memory` nodes are [`Normal`] memory. Children of `/reserved-memory`
+/// are carved out of it: a `shared-dma-pool` is [`Device`] memory,
+/// anything else [`Firmware`] memory. Disabled nodes are skipped.
+///
+/// [`Normal`]: memblock::RegionKind::Normal
+/// [`Device`]: memblock::RegionKind::Device
+/// [`Firmware`]: memblock::RegionKind::Firmware
+fn memory_banks<'a>(fdt: &Fdt<'a>) -> impl Iterator<Item = memblock::Region> + 'a {
+    use memblock::RegionKind;
+
+    let mut in_reserved = false;
+    fdt.nodes().flat_map(move |node| {
+        if node.depth() <= 1 {
+            in_reserved = node.depth() == 1 && node.name() == "reserved-memory";
+        }
+        let device_type = node.property("device_type").and_then(|p| p.as_str());
+        let kind = match node.depth() {
+            1 if device_type == Some("memory") => Some(RegionKind::Normal),
+            2 if in_reserved && node.compatible_contains("shared-dma-pool") => {
+                Some(RegionKind::Device)
+            }
+            2 if in_reserved => Some(RegionKind::Firmware),
+            _ => None,
+        }
+        .filter(|_| node.is_enabled());
+        kind.into_iter().flat_map(move |kind| {
+            node.reg_entries()
+                .map(move |reg| memblock::Region::new(reg.address, reg.size).with_kind(kind))
+        })
+    })
+}
+
+/// Returns the device tree recorded for init calls, if it is usable.
+#[cfg(target_os = "none")]
+pub fn boot_fdt() -> Option<Fdt<'static>> {
+    let params = (*BOOT_PARAMS.lock())?;
+    device_tree(params.blobs.dtb_phys)
+}
+
+/// Find the physical base of the first enabled PL011 in the device tree.
+fn console_base(fdt: &Fdt) -> Option<u64> {
+    let uart = fdt.find_compatible("arm,pl011").find(|n| n.is_enabled())?;
+    uart.reg_entries().next().map(|reg| reg.address)
+}
+
+/// Find the PSCI conduit named by the `/psci` node.
+fn psci_conduit(fdt: &Fdt) -> Option<psci::Conduit> {
+    let method = fdt.find_node("/psci")?.property("method")?;
+    psci::Conduit::from_method(method.as_str()?)
+}
+
+/// Returns the kernel command line from `/chosen/bootargs`, or "".
+fn bootargs<'a>(fdt: &Fdt<'a>) -> &'a str {
+    fdt.find_node("/chosen")
+        .and_then(|chosen| chosen.property("bootargs"))
+        .and_then(|args| args.as_str())
+        .unwrap_or("")
+}
+
+/// Run the interactive debug console on the serial port.
+///
+/// Reads commands (see `console::HELP`) until the system is reset.
+#[cfg(all(target_os = "none", not(feature = "debug_shell")))]
+pub fn debug_console() -> ! {
+    use crate::arch::serial;
+    use console::Command;
+    use core::fmt::Write;
+
+    if let Err(e) = serial::enable_irq_rx() {
+        let _ = writeln!(serial::Writer, "Polling for input: {}", e);
+    }
+    serial::write_str("Debug console, 'h' for help\n> ");
+    let mut editor = console::LineEditor::new();
+    loop {
+        let byte = serial::read_byte();
+        let Some(command) = editor.feed(byte, &mut serial::write_byte) else {
+            continue;
+        };
+
+        match command {
+            Command::Memblock => {
+                let _ = writeln!(serial::Writer, "{}", *memblock::lock());
+            }
+            Command::Stats => {
+                crate::stats::dump();
+                let _ = serial::write_error_stats(&mut serial::Writer);
+            }
+            Command::Reboot => {
+                if let Err(e) = psci::system_reset() {
+                    let _ = writeln!(serial::Writer, "Reboot failed: {}", e);
+                }
+            }
+            Command::Help => serial::write_str(console::HELP),
+            Command::Unknown => serial::write_str("Unknown command, 'h' for help\n"),
+            Command::Empty => {}
+        }
+        serial::write_str("> ");
+    }
+}
+
+/// Inter-byte timeout while receiving an XMODEM image.
+pub const XMODEM_TIMEOUT_MS: u64 = 3000;
+
+/// Receive an image over the serial line with XMODEM.
+///
+/// The destination is checked like a chainload target and must be free
+/// memory; it is claimed from memblock for the transfer, so nothing in use
+/// such as page tables, the heap or the DTB can be overwritten. The claim
+/// is kept for the received image and dropped if the transfer fails.
+/// Nothing else may use the serial port during the transfer.
+///
+/// # Arguments
+/// * `dest_phys` - Physical address to store the image at
+/// * `max_len` - Size of the destination buffer in bytes
+///
+/// # Returns
+/// The received length, a multiple of `xmodem::BLOCK_SIZE`
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn load_image_xmodem(dest_phys: u64, max_len: usize) -> Result<usize, &'static str> {
+    {
+        let mut mb = memblock::lock();
+        chainload::validate(&mb, dest_phys, max_len as u64, 0).map_err(|e| e.as_str())?;
+        mb.alloc_at(dest_phys, max_len as u64)
+            .map_err(|e| e.as_str())?;
+    }
+
+    let dest_virt = address::translation::phys_to_virt(dest_phys);
+    // Safety: free RAM claimed above, reachable through the boot linear map
+    let dest = unsafe { core::slice::from_raw_parts_mut(dest_virt as *mut u8, max_len) };
+
+    let received = receive_xmodem(dest);
+    if received.is_err() {
+        memblock::free(dest_phys, max_len as u64).map_err(|e| e.as_str())?;
+    }
+    received
+}
+
+/// Run the XMODEM receiver until the transfer ends.
+///
+/// # Arguments
+/// * `dest` - Buffer the image is stored in
+///
+/// # Returns
+/// The received length
+#[cfg(target_os = "none")]
+fn receive_xmodem(dest: &mut [u8]) -> Result<usize, &'static str> {
+    use crate::arch::{serial, timer};
+    use xmodem::Action;
+
+    let timeout = timer::ms_to_ticks(XMODEM_TIMEOUT_MS, timer::frequency());
+    let mut receiver = xmodem::Receiver::new();
+    // Tell the sender we are ready
+    let mut action = Action::Reply(xmodem::NAK);
+
+    loop {
+        match action {
+            Action::Wait => {}
+            Action::Reply(byte) => serial::write_byte(byte),
+            Action::Finished(len) => {
+                serial::write_byte(xmodem::ACK);
+                return Ok(len);
+            }
+            Action::Abort(e) => {
+                serial::write_bytes(&[xmodem::CAN, xmodem::CAN]);
+                return Err(e.as_str());
+            }
+        }
+
+        let deadline = timer::ticks() + timeout;
+        action = loop {
+            if let Some(byte) = serial::try_read_byte() {
+                break receiver.feed(byte, dest);
+            }
+            if timer::ticks() > deadline {
+                break receiver.timeout();
+            }
+            core::hint::spin_loop();
+        };
+    }
 }
 
 /// Early kernel initialization.
 ///
 /// This function performs essential initialization steps that must happen
 /// before any other kernel functionality.
-pub fn early_init() {
-    use crate::arch::aarch64::serial;
+///
+/// # Arguments
+/// * `dtb_phys` - Physical address of the DTB, zero if none was passed
+#[cfg(target_os = "none")]
+pub fn early_init(dtb_phys: u64) {
+    use crate::arch::{earlycon, serial};
+    use core::fmt::Write;
+
+    // Loader tables kept by boot.S may lack the linear map, switch first
+    let mmu = adopt::replace_tables();
+
+    // Install exception vectors so faults are reported
+    crate::arch::exception::init();
 
-    // Initialize serial output
-    serial::init();
-    serial::write_str("Phoenix kernel booting...\n");
+    // Prefer the UART described by the device tree over the QEMU default
+    let fdt = device_tree(dtb_phys);
+    if let Some(base) = fdt.as_ref().and_then(console_base) {
+        let _ = serial::set_phys_base(base);
+    }
+    if let Some(backend) = fdt
+        .as_ref()
+        .map(bootargs)
+        .and_then(earlycon::Backend::from_cmdline)
+    {
+        earlycon::select(backend);
+    }
+
+    // Initialize serial output and the other early subsystems
+    run_initcalls(InitLevel::Early);
+    earlycon::write_str("Phoenix kernel booting...\n");
+    let _ = write!(earlycon::Writer, "Wall clock: ");
+    let _ = crate::arch::rtc::write_now(&mut earlycon::Writer);
+    let _ = writeln!(earlycon::Writer);
+    let _ = writeln!(earlycon::Writer, "{}", mmu);
+
+    phase("cpu");
+    if let Err(e) = ensure_el1() {
+        fail(FailStage::ExceptionLevel, "Wrong exception level", e);
+    }
+    let cpu = crate::arch::cpu::get();
+    let _ = writeln!(earlycon::Writer, "CPU: {}", cpu);
+    if !cpu.granule_4k_supported() {
+        earlycon::write_str("CPU does not report 4KB granule support\n");
+    }
+    if !crate::arch::sysregs::check_boot(cpu.tcr_ips(), &mut earlycon::Writer) {
+        earlycon::write_str("MMU control registers differ from what boot.S programs\n");
+    }
 }
 
 /// Main kernel initialization.
 ///
-/// This function performs all kernel initialization after early setup.
+/// This function performs all kernel initialization after early setup,
+/// then hands the CPU to the idle loop.
 ///
 /// # Arguments
 /// * `kernel_virt_start` - Virtual start address of kernel
 /// * `kernel_virt_end` - Virtual end address of kernel
-pub fn kernel_init(kernel_virt_start: u64, kernel_virt_end: u64) {
-    use crate::arch::aarch64::serial;
+/// * `dtb_phys` - Physical address of the DTB, zero if none was passed
+#[cfg(target_os = "none")]
+pub fn kernel_init(kernel_virt_start: u64, kernel_virt_end: u64, dtb_phys: u64) -> ! {
+    use crate::arch::serial;
+    use core::fmt::Write;
+
+    // Sanity check the kernel layout before handing it to memblock
+    watchdog::begin(&watchdog::stages::LAYOUT);
+    #[cfg_attr(not(feature = "payload"), allow(unused_mut))]
+    let mut boot_info = match BootInfo::from_virtual(kernel_virt_start, kernel_virt_end) {
+        Ok(info) => info,
+        Err(e) => {
+            let _ = writeln!(
+                serial::Writer,
+                "__kernel_virtual_start={:#x} __kernel_virtual_end={:#x}: {}",
+                kernel_virt_start,
+                kernel_virt_end,
+                e
+            );
+            fail(FailStage::Layout, "Invalid kernel image bounds", e.as_str())
+        }
+    };
+    if let Err(e) = crate::mm::fixmap::init() {
+        fail(FailStage::Fixmap, "Failed to set up fixmap", e.as_str());
+    }
 
-    let boot_info = BootInfo::from_virtual(kernel_virt_start, kernel_virt_end);
+    // Parsing the device tree only reads it, so it can precede memblock
+    let mut fdt = device_tree(dtb_phys);
+    let mut cmdline = fdt.as_ref().map_or("", bootargs);
+    serial::color::set_color(serial::color::color_from_cmdline(cmdline));
+    watchdog::set_policy(watchdog::Policy::from_cmdline(cmdline));
+
+    // Check the loader kept the blobs apart before memblock trusts them
+    let blobs = audit_layout(&boot_info, dtb_phys, fdt.as_ref());
 
     // Initialize memory management
-    serial::write_str("Initializing memory management...\n");
-    if let Err(e) = init_memory(&boot_info) {
-        serial::write_str("Failed to initialize memory: ");
+    crate::arch::earlycon::write_str("Initializing memory management...\n");
+    watchdog::begin(&watchdog::stages::MEMORY);
+    *BOOT_PARAMS.lock() = Some(BootParams {
+        info: boot_info,
+        blobs,
+        cmdline,
+    });
+    run_initcalls(InitLevel::MemorySetup);
+    // The DTB, and the command line in it, may have moved
+    let params = *BOOT_PARAMS.lock();
+    if let Some(params) = params
+        && params.blobs.dtb_phys != dtb_phys
+    {
+        fdt = device_tree(params.blobs.dtb_phys);
+        cmdline = params.cmdline;
+    }
+    run_initcalls(InitLevel::PostMmu);
+    #[cfg(feature = "payload")]
+    unpack_payload(&mut boot_info);
+    if let Some(cma) = crate::mm::cma::info() {
+        let _ = writeln!(serial::Writer, "{}", cma);
+    }
+
+    phase("psci");
+    if let Some(conduit) = fdt.as_ref().and_then(psci_conduit) {
+        psci::set_conduit(conduit);
+        serial::write_str("PSCI conduit: ");
+        serial::write_str(conduit.as_str());
+        serial::write_str("\n");
+    }
+
+    // Switch serial output to its own device mapping
+    watchdog::begin(&watchdog::stages::SERIAL_REMAP);
+    if let Err(e) = serial::remap() {
+        serial::write_str("Failed to remap serial: ");
+        serial::write_str(e.as_str());
+        serial::write_str("\n");
+    }
+
+    // Protect the boot stack against overflow
+    watchdog::begin(&watchdog::stages::STACK_GUARD);
+    if let Err(e) = setup_stack_guard() {
+        serial::write_str("Failed to set up stack guard: ");
         serial::write_bytes(e.as_bytes());
         serial::write_str("\n");
-        loop {}
     }
 
-    // Test memory allocation
-    serial::write_str("Testing memory allocation...\n");
-    match test_memory_allocation() {
-        Ok(addr) => {
-            serial::write_str("Allocated page at ");
-            // Simple hex output
-            let hex_digits = b"0123456789ABCDEF";
-            for shift in (0..16).rev() {
-                let nibble = (addr >> (shift * 4)) & 0xF;
-                serial::write_byte(hex_digits[nibble as usize]);
-            }
+    // Carve the heap from the largest free range left
+    watchdog::begin(&watchdog::stages::HEAP);
+    match crate::mm::heap::init_from_memblock() {
+        Ok(heap) => {
+            let _ = writeln!(serial::Writer, "Heap: {}", heap);
+        }
+        Err(e) => {
+            serial::write_str("Failed to set up heap: ");
+            serial::write_str(e);
             serial::write_str("\n");
         }
+    }
+
+    // Give the boot CPU its per-CPU block and check it reads back
+    watchdog::begin(&watchdog::stages::PERCPU);
+    match crate::arch::percpu::init_cpu(0) {
+        Ok(()) => {
+            let cpu_id = crate::arch::percpu::current().cpu_id;
+            kassert!(cpu_id == 0, "boot CPU block reads back CPU {}", cpu_id);
+            let _ = writeln!(serial::Writer, "Per-CPU: CPU {} online", cpu_id);
+        }
         Err(e) => {
-            serial::write_str("Allocation failed: ");
-            serial::write_bytes(e.as_bytes());
+            serial::write_str("Failed to set up per-CPU data: ");
+            serial::write_str(e);
             serial::write_str("\n");
         }
     }
 
+    // Drivers, then the rest, including the allocation self test and the
+    // hand-over from memblock to the buddy allocator
+    run_initcalls(InitLevel::Device);
+    watchdog::begin(&watchdog::stages::SELFTEST);
+    run_initcalls(InitLevel::Late);
+
+    watchdog::end();
+
+    let failed = SELFTEST_FAILURES.load(Ordering::Relaxed);
+    if failed != 0 {
+        let _ = writeln!(serial::Writer, "{} self tests failed", failed);
+        crate::arch::semihosting::exit(selftest_exit_code(failed));
+    }
+
     // Print memory information
+    phase("report");
     print_memory_info(&boot_info);
+    crate::stats::dump();
+
+    let status = serial::status();
+    if status.degraded {
+        serial::write_str("Console degraded, dropped waits: ");
+        serial::write_dec_u64(status.dropped_waits);
+        serial::write_str("\n");
+    }
+    let _ = serial::write_error_stats(&mut serial::Writer);
+    let _ = crate::mm::page_alloc::write_zone_stats(&mut serial::Writer);
+    let _ = crate::mm::accounting::report(&mut serial::Writer);
+
+    report::emit_memory_map(&boot_info);
+    print_init_memory_diff();
+
+    crate::arch::earlycon::write_str("Kernel initialization complete!\n");
+    phase("running");
+
+    // Only interrupts a driver enabled reach the CPU, see gic::dispatch
+    if crate::arch::gic::driver().is_some() {
+        crate::arch::irq::enable();
+    }
+
+    if exit_after_boot(cmdline) {
+        crate::arch::semihosting::exit(0);
+    }
+
+    #[cfg(feature = "debug_shell")]
+    shell::run();
+
+    #[cfg(not(feature = "debug_shell"))]
+    {
+        serial::write_str("Hello, world!\n");
+
+        if console::enabled(cmdline) {
+            debug_console();
+        }
+    }
 
-    serial::write_str("Kernel initialization complete!\n");
-    serial::write_str("Hello, world!\n");
+    phase("idle");
+    crate::arch::idle::idle_loop(cmdline)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    static TEST_DTB: &[u8] = include_bytes!("../../../fdt/test.dtb");
+
+    #[test]
+    fn test_device_tree_probes() {
+        let fdt = Fdt::new(TEST_DTB).unwrap();
+
+        // The disabled PL011 under /soc is skipped
+        assert_eq!(console_base(&fdt), Some(address::virt::UART_BASE));
+        assert_eq!(psci_conduit(&fdt), Some(psci::Conduit::Hvc));
+        assert!(console::enabled(bootargs(&fdt)));
+    }
+
+    #[test]
+    fn test_memory_banks() {
+        use memblock::RegionKind;
+
+        let fdt = Fdt::new(TEST_DTB).unwrap();
+        let banks: Vec<_> = memory_banks(&fdt)
+            .map(|b| (b.base, b.size, b.kind))
+            .collect();
+        // The disabled carve-out is skipped
+        assert_eq!(
+            banks,
+            [
+                (0x4000_0000, 0x4000_0000, RegionKind::Normal),
+                (0x7f00_0000, 0x10_0000, RegionKind::Firmware),
+                (0x7e00_0000, 0x100_0000, RegionKind::Device),
+            ]
+        );
+
+        // Applied to memblock, only normal memory is left to allocate
+        let mut mb = memblock::Memblock::new();
+        mb.add(banks[0].0, banks[0].1).unwrap();
+        for &(base, size, kind) in &banks[1..] {
+            mb.set_kind(base, size, kind).unwrap();
+        }
+        let normal: Vec<_> = mb.normal_memory_iter().map(|r| (r.base, r.size)).collect();
+        assert_eq!(
+            normal,
+            [(0x4000_0000, 0x3e00_0000), (0x7f10_0000, 0xf0_0000)]
+        );
+    }
+
+    #[test]
+    fn test_boot_info_from_virtual() {
+        let start = address::translation::phys_to_virt(0x4008_0000);
+        let info = BootInfo::from_virtual(start, start + 0x20_0000).unwrap();
+        assert_eq!(info.kernel_phys_start, 0x4008_0000);
+        assert_eq!(info.kernel_phys_end, 0x4028_0000);
+        assert_eq!(info.kernel_size, 0x20_0000);
+        assert_eq!(info.payload_size, 0);
+        // Same as plain translation for a good image
+        assert_eq!(
+            info.kernel_phys_end,
+            address::translation::virt_to_phys(start + 0x20_0000)
+        );
+    }
+
+    #[test]
+    fn test_boot_info_from_bad_symbols() {
+        use address::kernel::LINEAR_MAP_SIZE;
+        use address::layout_checks::MAX_KERNEL_SIZE;
+        use address::translation::phys_to_virt;
+        use address::virt::{RAM_BASE, RAM_END};
+
+        let start = phys_to_virt(0x4008_0000);
+        assert_eq!(
+            BootInfo::from_virtual(start, start).err(),
+            Some(BootInfoError::Empty)
+        );
+        // Swapped symbols must not underflow into a huge size
+        assert_eq!(
+            BootInfo::from_virtual(start + 0x20_0000, start).err(),
+            Some(BootInfoError::Inverted)
+        );
+        assert_eq!(
+            BootInfo::from_virtual(start, start + MAX_KERNEL_SIZE + 0x1000).err(),
+            Some(BootInfoError::TooLarge {
+                size: MAX_KERNEL_SIZE + 0x1000
+            })
+        );
+        assert_eq!(
+            BootInfo::from_virtual(start + 0x10, start + 0x2010).err(),
+            Some(BootInfoError::Unaligned { virt: start + 0x10 })
+        );
+        assert_eq!(
+            BootInfo::from_virtual(start, start + 0x2010).err(),
+            Some(BootInfoError::Unaligned {
+                virt: start + 0x2010
+            })
+        );
+        // Not linear map addresses at all, e.g. unrelocated physical ones
+        assert_eq!(
+            BootInfo::from_virtual(0x4008_0000, 0x4028_0000).err(),
+            Some(BootInfoError::NotLinearMapped { virt: 0x4008_0000 })
+        );
+        // Linear map, but below RAM
+        assert_eq!(
+            BootInfo::from_virtual(
+                phys_to_virt(RAM_BASE - 0x1000),
+                phys_to_virt(RAM_BASE + 0x1000)
+            )
+            .err(),
+            Some(BootInfoError::OutsideRam {
+                phys: RAM_BASE - 0x1000
+            })
+        );
+        assert_eq!(
+            BootInfo::from_virtual(
+                phys_to_virt(RAM_END - 0x1000),
+                phys_to_virt(RAM_END + 0x1000)
+            )
+            .err(),
+            if RAM_END < LINEAR_MAP_SIZE {
+                Some(BootInfoError::OutsideRam {
+                    phys: RAM_END + 0x1000,
+                })
+            } else {
+                // RAM fills the linear map, so past its end is not mapped
+                Some(BootInfoError::NotLinearMapped {
+                    virt: phys_to_virt(RAM_END + 0xfff),
+                })
+            }
+        );
+        // Ending exactly at the end of RAM is fine
+        assert!(
+            BootInfo::from_virtual(phys_to_virt(RAM_END - 0x1000), phys_to_virt(RAM_END)).is_ok()
+        );
+
+        assert_eq!(
+            BootInfoError::TooLarge { size: 0x800_0000 }.to_string(),
+            "image exceeds sanity size limit (0x8000000 > 0x4000000)"
+        );
+    }
+
+    #[test]
+    fn test_ensure_el1() {
+        use crate::arch::reg::mock;
+        assert_eq!(ensure_el1(), Ok(()));
+        mock::set("currentel", 2 << 2);
+        assert!(ensure_el1().unwrap_err().contains("EL2"));
+        mock::set("currentel", 3 << 2);
+        assert!(ensure_el1().is_err());
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(FailStage::Layout.exit_code(), 0x41);
+        assert_eq!(FailStage::Chainload.exit_code(), 0x44);
+        // All stage codes survive the host's 8-bit truncation
+        assert!(FailStage::Chainload.exit_code() <= 0xff);
+        assert_eq!(FailStage::ExceptionLevel.exit_code(), 0x45);
+
+        assert_eq!(selftest_exit_code(1), 1);
+        assert_eq!(selftest_exit_code(1000), FAIL_EXIT_BASE - 1);
+
+        assert!(exit_after_boot("console exit_after_boot"));
+        assert!(!exit_after_boot("exit_after_boot=0"));
+        assert!(!exit_after_boot(""));
+    }
+
+    #[test]
+    fn test_dtb_range() {
+        let header = &TEST_DTB[..crate::fdt::HEADER_SIZE];
+        assert_eq!(
+            dtb_range(0x4800_0000, header),
+            Ok(memblock::Region::new(0x4800_0000, TEST_DTB.len() as u64))
+        );
+        assert_eq!(dtb_range(u64::MAX - 0x10, header), Err("DTB range wraps"));
+
+        let mut bad = header.to_vec();
+        bad[0] = 0;
+        assert_eq!(dtb_range(0x4800_0000, &bad), Err("bad FDT magic"));
+    }
+
+    #[test]
+    fn test_dtb_reserved_range_in_memblock() {
+        let mut mb = memblock::Memblock::new();
+        mb.add(0x4000_0000, 0x100_0000).unwrap();
+        let range = dtb_range(0x4000_0800, TEST_DTB).unwrap();
+        mb.reserve_tagged(range.base, range.size, memblock::ReservationOwner::Dtb)
+            .unwrap();
+
+        // Bottom-up allocations start right at the blob but never land on it
+        for _ in 0..16 {
+            let page = mb.alloc(0x1000, 0x1000).unwrap();
+            assert!(page + 0x1000 <= range.base || page >= range.end());
+        }
+        assert_eq!(
+            mb.stats().reserved(memblock::ReservationOwner::Dtb),
+            TEST_DTB.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_reserve_mmio_regions() {
+        use memblock::{FLAG_MMIO, ReservationOwner};
+
+        let mut mb = memblock::Memblock::new();
+        let (ram_base, ram_size) = address::regions::ram();
+        mb.add(ram_base, ram_size).unwrap();
+        reserve_mmio_regions(&mut mb).unwrap();
+
+        for &(name, base, size) in address::regions::mmio() {
+            assert!(mb.overlaps_flags(base, size, FLAG_MMIO), "{}", name);
+            assert!(!mb.is_memory(base, size), "{}", name);
+            let covering = mb
+                .reserved_regions()
+                .iter()
+                .find(|r| r.base <= base && base + size <= r.end())
+                .unwrap();
+            assert_eq!(covering.owner, ReservationOwner::Mmio, "{}", name);
+            assert_eq!(address::regions::mmio_name(base), Some(name));
+            assert_eq!(address::regions::mmio_name(base + size - 1), Some(name));
+        }
+        assert_eq!(
+            address::regions::mmio_name(address::virt::UART_BASE),
+            Some("uart")
+        );
+        assert_eq!(address::regions::mmio_name(ram_base), None);
+
+        // RAM is untouched and the accounting ignores the windows
+        let stats = mb.stats();
+        assert_eq!(stats.free(), ram_size);
+        let windows: u64 = address::regions::mmio().iter().map(|w| w.2).sum();
+        assert_eq!(stats.reserved(ReservationOwner::Mmio), windows);
+        assert_eq!(
+            crate::mm::accounting::Category::of_owner(ReservationOwner::Mmio),
+            None
+        );
+
+        // Twice is an overlap
+        assert!(reserve_mmio_regions(&mut mb).is_err());
+    }
+
+    #[test]
+    fn test_stack_guard_page() {
+        let top = address::kernel::VIRTUAL_BASE + 0x20_0000;
+        let size = address::kernel::STACK_SIZE;
+        assert_eq!(stack_guard_page(top, size), top - size - 0x1000);
+
+        // Unaligned stack bottom rounds down before stepping below it
+        assert_eq!(stack_guard_page(top - 0x10, size), top - size - 0x2000);
+    }
 }
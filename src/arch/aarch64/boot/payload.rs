@@ -0,0 +1,42 @@
+//! Compressed payload embedded in the kernel image.
+//!
+//! With the `payload` feature, the file named by the `PHOENIX_PAYLOAD`
+//! environment variable at build time is placed in `.payload` inside
+//! `.rodata`. It must already be in the format [`crate::compress`]
+//! describes.
+//!
+//! [`unpack`] runs once the MMU is on and memblock is up. It reserves pages
+//! for the decompressed data under [`ReservationOwner::Payload`] and
+//! decompresses into them through the linear map.
+
+use crate::arch::address::{kernel, translation::phys_to_virt};
+use crate::compress::{self, Header};
+use crate::mm::memblock::{self, ReservationOwner};
+
+/// The payload as built, header first.
+#[used]
+#[unsafe(link_section = ".payload")]
+static PAYLOAD: [u8; include_bytes!(env!("PHOENIX_PAYLOAD")).len()] =
+    *include_bytes!(env!("PHOENIX_PAYLOAD"));
+
+/// Decompress the payload into memory reserved from memblock.
+///
+/// # Returns
+/// The physical address and size of the decompressed data
+pub fn unpack() -> Result<(u64, u64), &'static str> {
+    let (header, _) = Header::parse(&PAYLOAD).map_err(|e| e.as_str())?;
+    let size = header.decompressed_len as u64;
+    let reserved = size.max(1).next_multiple_of(kernel::PAGE_SIZE);
+    let phys = memblock::alloc_tagged(reserved, kernel::PAGE_SIZE, ReservationOwner::Payload)
+        .map_err(|e| e.as_str())?;
+
+    // Safety: memblock just reserved `reserved` bytes at `phys` for us alone,
+    // and all RAM is reachable through the linear map.
+    let dst =
+        unsafe { core::slice::from_raw_parts_mut(phys_to_virt(phys) as *mut u8, size as usize) };
+    if let Err(e) = compress::unpack(&PAYLOAD, dst) {
+        let _ = memblock::free(phys, reserved);
+        return Err(e.as_str());
+    }
+    Ok((phys, size))
+}
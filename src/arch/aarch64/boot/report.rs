@@ -0,0 +1,283 @@
+//! Machine-readable memory map report.
+//!
+//! At the end of boot the memory layout is printed in a stable,
+//! line-oriented `key=value` format so tooling can diff it between kernel
+//! versions. The report sits between sentinel lines and looks like
+//!
+//! ```text
+//! ---MEMMAP-BEGIN---
+//! version=3
+//! kernel base=0x40080000 end=0x40200000 size=0x180000
+//! memory base=0x40000000 size=0x40000000
+//! reserved base=0x40080000 size=0x180000 flags=0x0 owner=kernel
+//! reserved base=0x40200000 size=0x1000000 flags=0x0 owner=cma
+//! cma base=0x40200000 total=0x1000000 used=0x0 largest_free=0x1000000
+//! ...
+//! zone name=normal base=0x40000000 end=0x80000000 pages=0x40000 free=0x3ee80
+//! order zone=normal order=0 free=0x0
+//! ...
+//! order zone=normal order=10 free=0xfb
+//! total memory=0x40000000 reserved=0x1180000 free=0x3ee80000
+//! ---MEMMAP-END---
+//! ```
+//!
+//! Every line starts with a record type followed by space separated
+//! `key=value` fields in a fixed order. Values are hex numbers, decimal
+//! block orders or names, which are restricted to `[a-z_]`, so nothing
+//! needs escaping. Every zone lists all orders, free blocks or not.
+
+use super::BootInfo;
+use crate::mm::cma::CmaInfo;
+use crate::mm::memblock::Memblock;
+use crate::mm::page_alloc::{ZoneId, ZoneStats};
+use core::fmt;
+
+/// First line of the report.
+pub const BEGIN: &str = "---MEMMAP-BEGIN---";
+
+/// Last line of the report.
+pub const END: &str = "---MEMMAP-END---";
+
+/// Format version, bumped whenever records or fields change.
+pub const VERSION: u32 = 3;
+
+/// Returns the report name of a zone.
+fn zone_name(zone: ZoneId) -> &'static str {
+    match zone {
+        ZoneId::Dma => "dma",
+        ZoneId::Normal => "normal",
+    }
+}
+
+/// Write the memory map report for `boot_info` and `mb` to `out`.
+///
+/// # Arguments
+/// * `out` - Sink for the report
+/// * `boot_info` - Kernel image placement
+/// * `mb` - Memblock state to describe
+/// * `cma` - CMA pool usage, if there is a pool
+/// * `zones` - Page allocator zones, empty before it takes over
+pub fn write_memory_map(
+    out: &mut impl fmt::Write,
+    boot_info: &BootInfo,
+    mb: &Memblock,
+    cma: Option<&CmaInfo>,
+    zones: &[ZoneStats],
+) -> fmt::Result {
+    writeln!(out, "{}", BEGIN)?;
+    writeln!(out, "version={}", VERSION)?;
+    writeln!(
+        out,
+        "kernel base={:#x} end={:#x} size={:#x}",
+        boot_info.kernel_phys_start, boot_info.kernel_phys_end, boot_info.kernel_size
+    )?;
+    for region in mb.memory_regions() {
+        writeln!(
+            out,
+            "memory base={:#x} size={:#x}",
+            region.base, region.size
+        )?;
+    }
+    for region in mb.reserved_regions() {
+        writeln!(
+            out,
+            "reserved base={:#x} size={:#x} flags={:#x} owner={}",
+            region.base,
+            region.size,
+            region.flags,
+            region.owner.as_str()
+        )?;
+    }
+    if let Some(cma) = cma {
+        writeln!(
+            out,
+            "cma base={:#x} total={:#x} used={:#x} largest_free={:#x}",
+            cma.base, cma.total, cma.used, cma.largest_free
+        )?;
+    }
+    for zone in zones {
+        let name = zone_name(zone.zone);
+        writeln!(
+            out,
+            "zone name={} base={:#x} end={:#x} pages={:#x} free={:#x}",
+            name, zone.start, zone.end, zone.total_pages, zone.free_pages
+        )?;
+        for (order, free) in zone.free_blocks.iter().enumerate() {
+            writeln!(out, "order zone={} order={} free={:#x}", name, order, free)?;
+        }
+    }
+
+    let stats = mb.stats();
+    writeln!(
+        out,
+        "total memory={:#x} reserved={:#x} free={:#x}",
+        stats.total_memory,
+        stats.total_reserved,
+        stats.free()
+    )?;
+    writeln!(out, "{}", END)
+}
+
+/// Print the memory map report over the console.
+///
+/// # Arguments
+/// * `boot_info` - Kernel image placement
+#[cfg(target_os = "none")]
+pub fn emit_memory_map(boot_info: &BootInfo) {
+    use crate::arch::serial;
+    use crate::mm::{cma, memblock, page_alloc};
+
+    let cma = cma::info();
+    let zones = page_alloc::zone_stats();
+    let mb = memblock::lock();
+    let _ = write_memory_map(
+        &mut serial::Writer,
+        boot_info,
+        &mb,
+        cma.as_ref(),
+        zones.as_ref().map_or(&[], |z| &z[..]),
+    );
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::mm::memblock::{FLAG_NOMAP, ReservationOwner};
+    use crate::mm::page_alloc::MAX_ORDER;
+
+    fn report(
+        boot_info: &BootInfo,
+        mb: &Memblock,
+        cma: Option<&CmaInfo>,
+        zones: &[ZoneStats],
+    ) -> String {
+        let mut out = String::new();
+        write_memory_map(&mut out, boot_info, mb, cma, zones).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_memory_map_snapshot() {
+        let boot_info = BootInfo {
+            kernel_phys_start: 0x4008_0000,
+            kernel_phys_end: 0x4020_0000,
+            kernel_size: 0x18_0000,
+            payload_phys: 0,
+            payload_size: 0,
+        };
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x2000_0000).unwrap();
+        mb.add(0x8000_0000, 0x1000_0000).unwrap();
+        mb.reserve_tagged(0x4008_0000, 0x18_0000, ReservationOwner::KernelImage)
+            .unwrap();
+        mb.reserve_with_flags(0x4020_0000, 0x1000, FLAG_NOMAP, ReservationOwner::Stack)
+            .unwrap();
+        mb.reserve_tagged(0x4800_0000, 0x10_0000, ReservationOwner::Dtb)
+            .unwrap();
+        let cma = CmaInfo {
+            base: 0x4040_0000,
+            total: 0x100_0000,
+            used: 0x3000,
+            largest_free: 0xff_c000,
+        };
+        let mut free_blocks = [0; MAX_ORDER + 1];
+        free_blocks[0] = 1;
+        free_blocks[3] = 2;
+        free_blocks[MAX_ORDER] = 0x7c;
+        let zones = [
+            ZoneStats {
+                zone: ZoneId::Dma,
+                start: 0x4000_0000,
+                end: 0x8000_0000,
+                total_pages: 0x4_0000,
+                free_pages: 0x1_f011,
+                free_blocks,
+            },
+            ZoneStats {
+                zone: ZoneId::Normal,
+                start: 0x8000_0000,
+                end: 0x9000_0000,
+                total_pages: 0x1_0000,
+                free_pages: 0,
+                free_blocks: [0; MAX_ORDER + 1],
+            },
+        ];
+
+        assert_eq!(
+            report(&boot_info, &mb, Some(&cma), &zones),
+            "---MEMMAP-BEGIN---\n\
+             version=3\n\
+             kernel base=0x40080000 end=0x40200000 size=0x180000\n\
+             memory base=0x40000000 size=0x20000000\n\
+             memory base=0x80000000 size=0x10000000\n\
+             reserved base=0x40080000 size=0x180000 flags=0x0 owner=kernel\n\
+             reserved base=0x40200000 size=0x1000 flags=0x1 owner=stack\n\
+             reserved base=0x48000000 size=0x100000 flags=0x0 owner=dtb\n\
+             cma base=0x40400000 total=0x1000000 used=0x3000 largest_free=0xffc000\n\
+             zone name=dma base=0x40000000 end=0x80000000 pages=0x40000 free=0x1f011\n\
+             order zone=dma order=0 free=0x1\n\
+             order zone=dma order=1 free=0x0\n\
+             order zone=dma order=2 free=0x0\n\
+             order zone=dma order=3 free=0x2\n\
+             order zone=dma order=4 free=0x0\n\
+             order zone=dma order=5 free=0x0\n\
+             order zone=dma order=6 free=0x0\n\
+             order zone=dma order=7 free=0x0\n\
+             order zone=dma order=8 free=0x0\n\
+             order zone=dma order=9 free=0x0\n\
+             order zone=dma order=10 free=0x7c\n\
+             zone name=normal base=0x80000000 end=0x90000000 pages=0x10000 free=0x0\n\
+             order zone=normal order=0 free=0x0\n\
+             order zone=normal order=1 free=0x0\n\
+             order zone=normal order=2 free=0x0\n\
+             order zone=normal order=3 free=0x0\n\
+             order zone=normal order=4 free=0x0\n\
+             order zone=normal order=5 free=0x0\n\
+             order zone=normal order=6 free=0x0\n\
+             order zone=normal order=7 free=0x0\n\
+             order zone=normal order=8 free=0x0\n\
+             order zone=normal order=9 free=0x0\n\
+             order zone=normal order=10 free=0x0\n\
+             total memory=0x30000000 reserved=0x281000 free=0x2fd7f000\n\
+             ---MEMMAP-END---\n"
+        );
+    }
+
+    #[test]
+    fn test_memory_map_empty() {
+        let boot_info = BootInfo {
+            kernel_phys_start: 0,
+            kernel_phys_end: 0,
+            kernel_size: 0,
+            payload_phys: 0,
+            payload_size: 0,
+        };
+        let out = report(&boot_info, &Memblock::new(), None, &[]);
+
+        // Sentinels frame the report even with nothing to describe
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.first(), Some(&BEGIN));
+        assert_eq!(lines.last(), Some(&END));
+        assert_eq!(
+            lines[1..lines.len() - 1],
+            [
+                "version=3",
+                "kernel base=0x0 end=0x0 size=0x0",
+                "total memory=0x0 reserved=0x0 free=0x0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_owner_names_need_no_escaping() {
+        for owner in ReservationOwner::ALL {
+            let name = owner.as_str();
+            assert!(!name.is_empty());
+            assert!(
+                name.bytes().all(|b| b.is_ascii_lowercase() || b == b'_'),
+                "owner name {:?} is not [a-z_]",
+                name
+            );
+        }
+    }
+}
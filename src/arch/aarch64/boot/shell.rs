@@ -0,0 +1,557 @@
+//! Early debug shell.
+//!
+//! With the `debug_shell` feature, `kernel_init` ends in [`run`], which
+//! reads command lines from the serial port and dispatches them through
+//! the [`COMMANDS`] table. Commands can dump and change memblock state,
+//! check the heap redzones, peek and poke memory, and reset or power off
+//! the machine.
+//!
+//! Addresses given to `rd` and `wr` are kernel virtual addresses and are
+//! not checked; a bad one ends in the fatal exception report.
+
+use core::fmt;
+
+/// Prompt printed before each command.
+pub const PROMPT: &str = "phoenix> ";
+
+/// Most arguments any command takes.
+pub const MAX_ARGS: usize = 2;
+
+/// Longest range `rd` dumps.
+pub const RD_MAX: u64 = 0x1000;
+
+/// Bytes shown per hexdump line.
+pub const HEXDUMP_WIDTH: usize = 16;
+
+/// A parsed shell command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Dump the memblock state.
+    Mem,
+    /// Print the event counters.
+    Stats,
+    /// Allocate from memblock.
+    Alloc { size: u64, align: u64 },
+    /// Free a range back to memblock.
+    Free { addr: u64, size: u64 },
+    /// Check the redzones of every live heap allocation.
+    HeapCheck,
+    /// Hexdump memory.
+    Read { addr: u64, len: u64 },
+    /// Store a 32-bit word.
+    Write { addr: u64, value: u32 },
+    /// Reset through PSCI.
+    Reset,
+    /// Power off through PSCI.
+    Poweroff,
+    /// List the commands.
+    Help,
+}
+
+/// A command table entry.
+pub struct Spec {
+    /// Name typed to run the command.
+    pub name: &'static str,
+    /// Argument names for the usage message.
+    pub args: &'static str,
+    /// One-line description.
+    pub help: &'static str,
+    /// Number of numeric arguments.
+    pub argc: usize,
+    /// Build the command from its arguments, or say what is wrong with them.
+    build: fn(&[u64]) -> Result<Command, &'static str>,
+}
+
+// Entries are identified by name
+impl PartialEq for Spec {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Spec {}
+
+impl fmt::Debug for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+/// All shell commands, in help order.
+pub static COMMANDS: [Spec; 10] = [
+    Spec {
+        name: "mem",
+        args: "",
+        help: "dump memblock",
+        argc: 0,
+        build: |_| Ok(Command::Mem),
+    },
+    Spec {
+        name: "stats",
+        args: "",
+        help: "print event counters",
+        argc: 0,
+        build: |_| Ok(Command::Stats),
+    },
+    Spec {
+        name: "alloc",
+        args: "<size> <align>",
+        help: "allocate from memblock",
+        argc: 2,
+        build: |a| {
+            if a[0] == 0 {
+                return Err("size must not be zero");
+            }
+            if !a[1].is_power_of_two() {
+                return Err("align must be a power of two");
+            }
+            Ok(Command::Alloc {
+                size: a[0],
+                align: a[1],
+            })
+        },
+    },
+    Spec {
+        name: "free",
+        args: "<addr> <size>",
+        help: "free a memblock range",
+        argc: 2,
+        build: |a| {
+            Ok(Command::Free {
+                addr: a[0],
+                size: a[1],
+            })
+        },
+    },
+    Spec {
+        name: "heapcheck",
+        args: "",
+        help: "check heap redzones",
+        argc: 0,
+        build: |_| Ok(Command::HeapCheck),
+    },
+    Spec {
+        name: "rd",
+        args: "<addr> <len>",
+        help: "hexdump memory",
+        argc: 2,
+        build: |a| {
+            if a[1] == 0 || a[1] > RD_MAX {
+                return Err("len must be between 1 and 0x1000");
+            }
+            if a[0].checked_add(a[1]).is_none() {
+                return Err("range wraps the address space");
+            }
+            Ok(Command::Read {
+                addr: a[0],
+                len: a[1],
+            })
+        },
+    },
+    Spec {
+        name: "wr",
+        args: "<addr> <u32>",
+        help: "write a 32-bit word",
+        argc: 2,
+        build: |a| {
+            if !a[0].is_multiple_of(4) {
+                return Err("addr must be 4-byte aligned");
+            }
+            let value = u32::try_from(a[1]).map_err(|_| "value does not fit in 32 bits")?;
+            Ok(Command::Write { addr: a[0], value })
+        },
+    },
+    Spec {
+        name: "reset",
+        args: "",
+        help: "reset the system",
+        argc: 0,
+        build: |_| Ok(Command::Reset),
+    },
+    Spec {
+        name: "poweroff",
+        args: "",
+        help: "power the system off",
+        argc: 0,
+        build: |_| Ok(Command::Poweroff),
+    },
+    Spec {
+        name: "help",
+        args: "",
+        help: "this help",
+        argc: 0,
+        build: |_| Ok(Command::Help),
+    },
+];
+
+/// Why a line could not be turned into a command.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError<'a> {
+    /// No command has this name.
+    Unknown(&'a str),
+    /// Wrong number of arguments.
+    Usage(&'static Spec),
+    /// An argument is not a number.
+    BadNumber(&'a str),
+    /// The arguments are numbers but not acceptable ones.
+    Invalid(&'static Spec, &'static str),
+}
+
+impl fmt::Display for ParseError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(name) => {
+                write!(f, "unknown command '{}', available:", name)?;
+                for spec in &COMMANDS {
+                    write!(f, " {}", spec.name)?;
+                }
+                Ok(())
+            }
+            Self::Usage(spec) if spec.args.is_empty() => write!(f, "usage: {}", spec.name),
+            Self::Usage(spec) => write!(f, "usage: {} {}", spec.name, spec.args),
+            Self::BadNumber(arg) => write!(f, "not a number: '{}'", arg),
+            Self::Invalid(spec, why) => write!(f, "{}: {}", spec.name, why),
+        }
+    }
+}
+
+/// Parse a number, hexadecimal with a `0x` prefix and decimal otherwise.
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parse one input line.
+///
+/// # Returns
+/// The command, `None` for a blank line, or why the line was rejected
+pub fn parse(line: &str) -> Result<Option<Command>, ParseError<'_>> {
+    let mut tokens = line.split_ascii_whitespace();
+    let Some(name) = tokens.next() else {
+        return Ok(None);
+    };
+    let spec = COMMANDS
+        .iter()
+        .find(|spec| spec.name == name)
+        .ok_or(ParseError::Unknown(name))?;
+
+    let mut args = [0u64; MAX_ARGS];
+    let mut argc = 0;
+    for token in tokens {
+        if argc == spec.argc {
+            return Err(ParseError::Usage(spec));
+        }
+        args[argc] = parse_number(token).ok_or(ParseError::BadNumber(token))?;
+        argc += 1;
+    }
+    if argc != spec.argc {
+        return Err(ParseError::Usage(spec));
+    }
+
+    (spec.build)(&args[..argc])
+        .map(Some)
+        .map_err(|why| ParseError::Invalid(spec, why))
+}
+
+/// Write the command list.
+pub fn write_help(out: &mut impl fmt::Write) -> fmt::Result {
+    for spec in &COMMANDS {
+        let usage_len = spec.name.len() + 1 + spec.args.len();
+        write!(out, "  {} {}", spec.name, spec.args)?;
+        writeln!(
+            out,
+            "{:pad$}{}",
+            "",
+            spec.help,
+            pad = 24usize.saturating_sub(usage_len)
+        )?;
+    }
+    Ok(())
+}
+
+/// Write one hexdump line for `bytes` read from `addr`.
+///
+/// Short lines are padded so the ASCII column stays aligned.
+pub fn write_hexdump_line(out: &mut impl fmt::Write, addr: u64, bytes: &[u8]) -> fmt::Result {
+    write!(out, "{:016x}:", addr)?;
+    for i in 0..HEXDUMP_WIDTH {
+        match bytes.get(i) {
+            Some(b) => write!(out, " {:02x}", b)?,
+            None => out.write_str("   ")?,
+        }
+    }
+    out.write_str("  |")?;
+    for &b in bytes {
+        let c = if b.is_ascii_graphic() || b == b' ' {
+            b as char
+        } else {
+            '.'
+        };
+        out.write_char(c)?;
+    }
+    out.write_str("|\n")
+}
+
+/// Run the shell on the serial port until the machine is reset or a
+/// power off is requested.
+#[cfg(all(target_os = "none", feature = "debug_shell"))]
+pub fn run() {
+    use crate::arch::serial::{self, editor::LineEditor};
+    use core::fmt::Write;
+
+    serial::write_str("Debug shell, 'help' for commands\n");
+    if let Err(e) = serial::enable_irq_rx() {
+        let _ = writeln!(serial::Writer, "Polling for input: {}", e);
+    }
+    serial::write_str(PROMPT);
+    let mut editor = LineEditor::new();
+    loop {
+        let byte = serial::read_byte();
+        let Some(line) = editor.feed(byte, &mut serial::write_byte) else {
+            continue;
+        };
+
+        match parse(line) {
+            Ok(Some(command)) => execute(command),
+            Ok(None) => {}
+            Err(e) => {
+                let _ = writeln!(serial::Writer, "{}", e);
+            }
+        }
+        // The idle loop carries out the power off
+        if crate::arch::idle::shutdown_requested() {
+            return;
+        }
+        serial::write_str(PROMPT);
+    }
+}
+
+/// Carry out one command.
+#[cfg(all(target_os = "none", feature = "debug_shell"))]
+fn execute(command: Command) {
+    use crate::arch::{psci, serial};
+    use crate::mm::memblock;
+    use core::fmt::Write;
+
+    let out = &mut serial::Writer;
+    match command {
+        Command::Mem => {
+            let _ = writeln!(out, "{}", *memblock::lock());
+        }
+        Command::Stats => {
+            crate::stats::dump();
+            let _ = serial::write_error_stats(out);
+        }
+        Command::Alloc { size, align } => match memblock::alloc(size, align) {
+            Ok(addr) => {
+                let _ = writeln!(out, "allocated {:#x}", addr);
+            }
+            Err(e) => {
+                let _ = writeln!(out, "alloc failed: {}", e.as_str());
+            }
+        },
+        Command::Free { addr, size } => {
+            if let Err(e) = memblock::free(addr, size) {
+                let _ = writeln!(out, "free failed: {}", e.as_str());
+            }
+        }
+        Command::HeapCheck => {
+            #[cfg(feature = "heap_debug")]
+            match crate::mm::heap::check_all() {
+                Ok(checked) => {
+                    let _ = writeln!(
+                        out,
+                        "heap: {} allocations intact, {} untracked",
+                        checked,
+                        crate::mm::heap::untracked()
+                    );
+                }
+                Err((base, corruption)) => {
+                    // Safety: check_all only reports live blocks
+                    let report = unsafe { crate::mm::redzone::Report::live(base, corruption) };
+                    let _ = write!(out, "{}", report);
+                }
+            }
+            #[cfg(not(feature = "heap_debug"))]
+            let _ = writeln!(out, "heapcheck: built without heap_debug");
+        }
+        Command::Read { addr, len } => {
+            let mut line = addr;
+            while line < addr + len {
+                let count = (addr + len - line).min(HEXDUMP_WIDTH as u64) as usize;
+                let mut bytes = [0u8; HEXDUMP_WIDTH];
+                for (i, b) in bytes[..count].iter_mut().enumerate() {
+                    // Safety: the shell trusts the user with the address
+                    *b = unsafe { core::ptr::read_volatile((line + i as u64) as *const u8) };
+                }
+                let _ = write_hexdump_line(out, line, &bytes[..count]);
+                line += count as u64;
+            }
+        }
+        Command::Write { addr, value } => {
+            // Safety: the shell trusts the user with the address
+            unsafe { core::ptr::write_volatile(addr as *mut u32, value) };
+        }
+        Command::Reset => {
+            if let Err(e) = psci::system_reset() {
+                let _ = writeln!(out, "reset failed: {}", e);
+            }
+        }
+        Command::Poweroff => {
+            crate::arch::idle::request_shutdown();
+        }
+        Command::Help => {
+            let _ = write_help(out);
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn spec(name: &str) -> &'static Spec {
+        COMMANDS.iter().find(|s| s.name == name).unwrap()
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("0"), Some(0));
+        assert_eq!(parse_number("4096"), Some(4096));
+        assert_eq!(parse_number("0x1000"), Some(0x1000));
+        assert_eq!(parse_number("0XfFfF"), Some(0xffff));
+        assert_eq!(parse_number("0xffffffffffffffff"), Some(u64::MAX));
+        assert_eq!(parse_number("0x"), None);
+        assert_eq!(parse_number("0x1g"), None);
+        assert_eq!(parse_number("12a"), None);
+        assert_eq!(parse_number("-1"), None);
+        assert_eq!(parse_number("0x10000000000000000"), None);
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(parse(""), Ok(None));
+        assert_eq!(parse("   "), Ok(None));
+        assert_eq!(parse("mem"), Ok(Some(Command::Mem)));
+        assert_eq!(parse("  stats  "), Ok(Some(Command::Stats)));
+        assert_eq!(
+            parse("alloc 0x2000 4096"),
+            Ok(Some(Command::Alloc {
+                size: 0x2000,
+                align: 0x1000
+            }))
+        );
+        assert_eq!(
+            parse("free 0x40000000\t0x1000"),
+            Ok(Some(Command::Free {
+                addr: 0x4000_0000,
+                size: 0x1000
+            }))
+        );
+        assert_eq!(
+            parse("rd 0xffffffc000000000 64"),
+            Ok(Some(Command::Read {
+                addr: 0xffff_ffc0_0000_0000,
+                len: 64
+            }))
+        );
+        assert_eq!(
+            parse("wr 0x9000000 0xdeadbeef"),
+            Ok(Some(Command::Write {
+                addr: 0x900_0000,
+                value: 0xdead_beef
+            }))
+        );
+        assert_eq!(parse("reset"), Ok(Some(Command::Reset)));
+        assert_eq!(parse("poweroff"), Ok(Some(Command::Poweroff)));
+        assert_eq!(parse("help"), Ok(Some(Command::Help)));
+        assert_eq!(parse("heapcheck"), Ok(Some(Command::HeapCheck)));
+    }
+
+    #[test]
+    fn test_parse_argument_count() {
+        assert_eq!(parse("alloc 0x1000"), Err(ParseError::Usage(spec("alloc"))));
+        assert_eq!(parse("alloc 1 2 3"), Err(ParseError::Usage(spec("alloc"))));
+        assert_eq!(parse("mem 1"), Err(ParseError::Usage(spec("mem"))));
+        assert_eq!(parse("mem 1").unwrap_err().to_string(), "usage: mem");
+        assert_eq!(
+            parse("rd 0x1000").unwrap_err().to_string(),
+            "usage: rd <addr> <len>"
+        );
+        assert_eq!(
+            parse("free zz 1").unwrap_err().to_string(),
+            "not a number: 'zz'"
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_arguments() {
+        let cases = [
+            ("alloc 0 0x1000", "alloc: size must not be zero"),
+            ("alloc 0x1000 3", "alloc: align must be a power of two"),
+            ("rd 0x1000 0", "rd: len must be between 1 and 0x1000"),
+            ("rd 0x1000 0x1001", "rd: len must be between 1 and 0x1000"),
+            (
+                "rd 0xffffffffffffffff 2",
+                "rd: range wraps the address space",
+            ),
+            ("wr 0x1002 1", "wr: addr must be 4-byte aligned"),
+            ("wr 0x1000 0x100000000", "wr: value does not fit in 32 bits"),
+        ];
+        for (line, message) in cases {
+            assert_eq!(parse(line).unwrap_err().to_string(), message, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_parse_unknown_lists_commands() {
+        let e = parse("peek 1").unwrap_err();
+        assert_eq!(e, ParseError::Unknown("peek"));
+        assert_eq!(
+            e.to_string(),
+            "unknown command 'peek', available: mem stats alloc free heapcheck rd wr reset poweroff help"
+        );
+    }
+
+    #[test]
+    fn test_command_table() {
+        for (i, spec) in COMMANDS.iter().enumerate() {
+            assert!(
+                spec.argc <= MAX_ARGS,
+                "{} takes too many arguments",
+                spec.name
+            );
+            assert_eq!(spec.args.split_whitespace().count(), spec.argc);
+            assert!(COMMANDS[..i].iter().all(|s| s.name != spec.name));
+        }
+    }
+
+    #[test]
+    fn test_help_lists_every_command() {
+        let mut out = String::new();
+        write_help(&mut out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), COMMANDS.len());
+        assert_eq!(lines[2], "  alloc <size> <align>    allocate from memblock");
+        assert_eq!(lines[0], "  mem                     dump memblock");
+    }
+
+    #[test]
+    fn test_hexdump_line() {
+        let mut out = String::new();
+        write_hexdump_line(&mut out, 0x4000_1000, b"Hello, world!\0\x01\x7f").unwrap();
+        assert_eq!(
+            out,
+            "0000000040001000: 48 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 00 01 7f  |Hello, world!...|\n"
+        );
+
+        let mut out = String::new();
+        write_hexdump_line(&mut out, 0x10, b"ab").unwrap();
+        assert_eq!(
+            out,
+            format!("0000000000000010: 61 62{}  |ab|\n", " ".repeat(3 * 14))
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Boot timeline.
+//!
+//! [`phase`] marks how far boot has got: it prints `[phase] <name>` with
+//! the counter time in milliseconds and remembers the phase, so the panic
+//! handler can report the last one reached when boot hangs or dies. Only
+//! the most recent phase is kept.
+
+use crate::arch::sync::IrqSafeMutex;
+use core::fmt;
+
+/// A reached boot phase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Phase {
+    /// Phase name.
+    pub name: &'static str,
+    /// Counter time in milliseconds when the phase began, if known.
+    pub at_ms: Option<u64>,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[phase] {}", self.name)?;
+        if let Some(ms) = self.at_ms {
+            write!(f, " at {} ms", ms)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records the latest boot phase.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    /// Most recent phase, `None` before the first one.
+    latest: Option<Phase>,
+}
+
+impl Tracker {
+    /// Create a tracker with no phase reached.
+    pub const fn new() -> Self {
+        Self { latest: None }
+    }
+
+    /// Record that `name` began, replacing the previous phase.
+    ///
+    /// # Arguments
+    /// * `name` - Phase name
+    /// * `at_ms` - Counter time in milliseconds, `None` without a timer
+    ///
+    /// # Returns
+    /// The recorded phase
+    pub fn enter(&mut self, name: &'static str, at_ms: Option<u64>) -> Phase {
+        let phase = Phase { name, at_ms };
+        self.latest = Some(phase);
+        phase
+    }
+
+    /// Returns the most recent phase.
+    pub fn latest(&self) -> Option<Phase> {
+        self.latest
+    }
+}
+
+/// Boot timeline of CPU0.
+static TIMELINE: IrqSafeMutex<Tracker> = IrqSafeMutex::new("timeline", Tracker::new());
+
+/// Returns the counter time in milliseconds, `None` if the counter
+/// frequency is not set up.
+#[cfg(target_os = "none")]
+fn now_ms() -> Option<u64> {
+    use crate::arch::timer;
+
+    let freq = timer::frequency();
+    if freq == 0 {
+        return None;
+    }
+    Some(timer::ticks_to_ms(timer::ticks(), freq))
+}
+
+/// Mark the start of boot phase `name` and print it.
+///
+/// # Arguments
+/// * `name` - Phase name
+#[cfg(target_os = "none")]
+pub fn phase(name: &'static str) {
+    use crate::arch::earlycon;
+    use core::fmt::Write;
+
+    let phase = TIMELINE.lock().enter(name, now_ms());
+    let _ = writeln!(earlycon::Writer, "{}", phase);
+}
+
+/// Returns the last boot phase reached.
+///
+/// Never spins, so the panic handler may call it even if the panic hit
+/// while the timeline was being updated; it then returns `None`.
+pub fn last() -> Option<Phase> {
+    TIMELINE.try_lock().and_then(|timeline| timeline.latest())
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracker_keeps_latest() {
+        let mut tracker = Tracker::new();
+        assert_eq!(tracker.latest(), None);
+
+        tracker.enter("early", Some(3));
+        let memory = tracker.enter("memory", Some(7));
+        assert_eq!(memory.name, "memory");
+        assert_eq!(tracker.latest(), Some(memory));
+
+        // A phase without a timestamp still replaces the previous one
+        tracker.enter("heap", None);
+        assert_eq!(
+            tracker.latest(),
+            Some(Phase {
+                name: "heap",
+                at_ms: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_phase_display() {
+        let phase = Phase {
+            name: "memory",
+            at_ms: Some(42),
+        };
+        assert_eq!(format!("{}", phase), "[phase] memory at 42 ms");
+        let phase = Phase {
+            name: "early",
+            at_ms: None,
+        };
+        assert_eq!(format!("{}", phase), "[phase] early");
+    }
+
+    #[test]
+    fn test_last_reports_global_phase() {
+        assert_eq!(last(), None);
+        TIMELINE.lock().enter("layout", Some(1));
+        assert_eq!(last().map(|p| p.name), Some("layout"));
+
+        // Held while the panic handler looks: nothing rather than a hang
+        let guard = TIMELINE.lock();
+        assert_eq!(last(), None);
+        drop(guard);
+        assert_eq!(last().map(|p| p.name), Some("layout"));
+    }
+}
@@ -0,0 +1,283 @@
+//! Boot progress watchdog.
+//!
+//! Each boot stage arms a deadline when it begins and disarms it when it
+//! completes. The timer tick handler calls `tick`, which escalates once if
+//! the current stage overruns its deadline. Arming and checking only touch
+//! atomics, so supervision is cheap enough to leave on for every boot.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+/// Default per-stage timeout in milliseconds.
+pub const DEFAULT_TIMEOUT_MS: u64 = 2000;
+
+/// A supervised boot stage.
+#[derive(Debug)]
+pub struct Stage {
+    /// Stage name used in watchdog reports.
+    pub name: &'static str,
+    /// Time the stage may take before the watchdog fires.
+    pub timeout_ms: u64,
+}
+
+impl Stage {
+    /// Creates a stage with the default timeout.
+    pub const fn new(name: &'static str) -> Self {
+        Self::with_timeout(name, DEFAULT_TIMEOUT_MS)
+    }
+
+    /// Creates a stage with an explicit timeout.
+    pub const fn with_timeout(name: &'static str, timeout_ms: u64) -> Self {
+        Self { name, timeout_ms }
+    }
+}
+
+/// Boot stage table.
+pub mod stages {
+    use super::Stage;
+
+    /// Kernel layout validation.
+    pub static LAYOUT: Stage = Stage::new("layout");
+    /// Memblock initialization.
+    pub static MEMORY: Stage = Stage::new("memory");
+    /// Serial remap onto ioremap.
+    pub static SERIAL_REMAP: Stage = Stage::new("serial_remap");
+    /// Boot stack guard page setup.
+    pub static STACK_GUARD: Stage = Stage::new("stack_guard");
+    /// Heap arena placement.
+    pub static HEAP: Stage = Stage::new("heap");
+    /// Boot CPU per-CPU block setup.
+    pub static PERCPU: Stage = Stage::new("percpu");
+    /// Boot-time allocation self test.
+    pub static SELFTEST: Stage = Stage::with_timeout("selftest", 5000);
+}
+
+/// What to do once a stage overruns its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Report and keep waiting for the stage to finish.
+    Warn,
+    /// Report and panic.
+    Panic,
+}
+
+impl Policy {
+    /// Parse the `bootwd=panic|warn` option from a kernel command line.
+    ///
+    /// Defaults to `Warn` if the option is absent or unrecognized.
+    pub fn from_cmdline(cmdline: &str) -> Self {
+        let mut policy = Self::Warn;
+        for arg in cmdline.split_ascii_whitespace() {
+            match arg {
+                "bootwd=panic" => policy = Self::Panic,
+                "bootwd=warn" => policy = Self::Warn,
+                _ => {}
+            }
+        }
+        policy
+    }
+}
+
+/// Deadline bookkeeping for the current boot stage.
+pub struct Watchdog {
+    /// Current stage, or null if disarmed.
+    stage: AtomicPtr<Stage>,
+    /// Counter value after which the current stage has overrun.
+    deadline: AtomicU64,
+    /// Whether the current stage has already been reported.
+    fired: AtomicBool,
+}
+
+impl Watchdog {
+    /// Creates a disarmed watchdog.
+    pub const fn new() -> Self {
+        Self {
+            stage: AtomicPtr::new(ptr::null_mut()),
+            deadline: AtomicU64::new(0),
+            fired: AtomicBool::new(false),
+        }
+    }
+
+    /// Arm the watchdog for `stage` starting at counter value `now`.
+    ///
+    /// # Arguments
+    /// * `stage` - Stage that is beginning
+    /// * `now` - Current counter value
+    /// * `freq` - Counter frequency in Hz
+    pub fn arm(&self, stage: &'static Stage, now: u64, freq: u64) {
+        let timeout = crate::arch::timer::ms_to_ticks(stage.timeout_ms, freq);
+        self.deadline
+            .store(now.saturating_add(timeout), Ordering::Relaxed);
+        self.fired.store(false, Ordering::Relaxed);
+        // Publish the stage last so `tick` never sees it with a stale deadline
+        self.stage
+            .store(stage as *const Stage as *mut Stage, Ordering::Release);
+    }
+
+    /// Disarm the watchdog after a stage completes.
+    pub fn disarm(&self) {
+        self.stage.store(ptr::null_mut(), Ordering::Release);
+    }
+
+    /// Check the deadline at counter value `now`.
+    ///
+    /// A stage overruns once `now` is strictly past its deadline, so a stage
+    /// finishing on the deadline tick itself is not reported.
+    ///
+    /// # Returns
+    /// The overrunning stage the first time its deadline is exceeded
+    pub fn tick(&self, now: u64) -> Option<&'static Stage> {
+        let stage = self.stage.load(Ordering::Acquire);
+        if stage.is_null() || now <= self.deadline.load(Ordering::Relaxed) {
+            return None;
+        }
+        if self.fired.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        // Safety: only `&'static Stage` references are ever stored
+        Some(unsafe { &*stage })
+    }
+}
+
+/// Global boot watchdog.
+#[allow(dead_code)]
+static WATCHDOG: Watchdog = Watchdog::new();
+
+/// Whether an overrun panics instead of only warning.
+static PANIC_ON_EXPIRY: AtomicBool = AtomicBool::new(false);
+
+/// Set the escalation policy, e.g. from `Policy::from_cmdline`.
+pub fn set_policy(policy: Policy) {
+    PANIC_ON_EXPIRY.store(policy == Policy::Panic, Ordering::Relaxed);
+}
+
+/// Arm the watchdog as `stage` begins.
+///
+/// The stage is also marked as a phase on the boot timeline.
+#[cfg(target_os = "none")]
+pub fn begin(stage: &'static Stage) {
+    use crate::arch::{serial::frame, timer};
+
+    super::phase(stage.name);
+    let _ = frame::send_frame(frame::FrameKind::BootStage, stage.name.as_bytes());
+    WATCHDOG.arm(stage, timer::ticks(), timer::frequency());
+}
+
+/// Disarm the watchdog as the current stage completes.
+#[cfg(target_os = "none")]
+pub fn end() {
+    WATCHDOG.disarm();
+}
+
+/// Timer tick hook: escalate if the current stage has overrun.
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn tick() {
+    use crate::arch::timer;
+
+    if let Some(stage) = WATCHDOG.tick(timer::ticks()) {
+        escalate(stage);
+    }
+}
+
+/// Report an overrunning stage and apply the escalation policy.
+///
+/// The report has the boot timeline's last phase, memblock (its summary
+/// if the hung stage holds the lock) and the event counters.
+#[cfg(target_os = "none")]
+fn escalate(stage: &Stage) {
+    use crate::arch::serial;
+    use crate::mm::memblock;
+    use core::fmt::Write;
+
+    let _ = writeln!(
+        serial::Writer,
+        "BOOT WATCHDOG: stage {} exceeded {} ms",
+        stage.name,
+        stage.timeout_ms
+    );
+    match super::timeline::last() {
+        Some(phase) => {
+            let _ = writeln!(serial::Writer, "Last boot phase: {}", phase);
+        }
+        None => serial::write_str("Last boot phase: unknown\n"),
+    }
+    let _ = memblock::try_dump_to(&mut serial::Writer);
+    crate::stats::dump();
+
+    if PANIC_ON_EXPIRY.load(Ordering::Relaxed) {
+        panic!("boot stage {} hung", stage.name);
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    /// 1kHz counter so ticks equal milliseconds.
+    const FREQ: u64 = 1000;
+
+    static FAST: Stage = Stage::with_timeout("fast", 10);
+    static SLOW: Stage = Stage::new("slow");
+
+    #[test]
+    fn test_disarmed_never_fires() {
+        let wd = Watchdog::new();
+        assert!(wd.tick(0).is_none());
+        assert!(wd.tick(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_fires_once_after_deadline() {
+        let wd = Watchdog::new();
+        wd.arm(&FAST, 100, FREQ);
+
+        for now in 100..=110 {
+            assert!(wd.tick(now).is_none());
+        }
+        assert_eq!(wd.tick(111).map(|s| s.name), Some("fast"));
+        // Only reported once, even while still overrunning
+        assert!(wd.tick(112).is_none());
+        assert!(wd.tick(500).is_none());
+    }
+
+    #[test]
+    fn test_completes_exactly_at_deadline() {
+        let wd = Watchdog::new();
+        wd.arm(&FAST, 0, FREQ);
+        assert!(wd.tick(10).is_none());
+        wd.disarm();
+        assert!(wd.tick(11).is_none());
+    }
+
+    #[test]
+    fn test_rearm_per_stage() {
+        let wd = Watchdog::new();
+        wd.arm(&FAST, 0, FREQ);
+        assert!(wd.tick(20).is_some());
+
+        // Next stage gets a fresh deadline and can fire again
+        wd.arm(&SLOW, 20, FREQ);
+        assert!(wd.tick(2020).is_none());
+        assert_eq!(wd.tick(2021).map(|s| s.name), Some("slow"));
+    }
+
+    #[test]
+    fn test_deadline_saturates() {
+        let wd = Watchdog::new();
+        wd.arm(&SLOW, u64::MAX - 5, FREQ);
+        assert!(wd.tick(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_policy_from_cmdline() {
+        assert_eq!(Policy::from_cmdline(""), Policy::Warn);
+        assert_eq!(Policy::from_cmdline("bootwd=panic"), Policy::Panic);
+        assert_eq!(Policy::from_cmdline("quiet bootwd=warn"), Policy::Warn);
+        assert_eq!(
+            Policy::from_cmdline("bootwd=panic bootwd=warn"),
+            Policy::Warn
+        );
+        assert_eq!(Policy::from_cmdline("bootwd=reboot"), Policy::Warn);
+    }
+}
@@ -0,0 +1,284 @@
+//! Minimal XMODEM (checksum variant) receiver.
+//!
+//! The protocol state machine is I/O free: the caller feeds it received
+//! bytes and timeouts and sends back whatever reply it asks for. Images
+//! arrive in 128-byte blocks, so the received length is rounded up to a
+//! block and the tail is padded by the sender (usually with 0x1a).
+
+/// Start of a 128-byte block.
+pub const SOH: u8 = 0x01;
+/// End of transmission.
+pub const EOT: u8 = 0x04;
+/// Block accepted.
+pub const ACK: u8 = 0x06;
+/// Block rejected, or "ready to receive" before the first block.
+pub const NAK: u8 = 0x15;
+/// Cancel the transfer.
+pub const CAN: u8 = 0x18;
+
+/// Payload bytes per block.
+pub const BLOCK_SIZE: usize = 128;
+
+/// Consecutive bad blocks or timeouts tolerated before giving up.
+pub const MAX_ERRORS: u32 = 10;
+
+/// Block number, its complement, payload and checksum.
+const PACKET_SIZE: usize = 2 + BLOCK_SIZE + 1;
+
+/// Reasons a transfer is aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmodemError {
+    /// The image does not fit in the destination.
+    TooLarge,
+    /// The sender cancelled the transfer.
+    Cancelled,
+    /// A block arrived out of sequence.
+    Sequence,
+    /// Too many consecutive bad blocks or timeouts.
+    TooManyErrors,
+}
+
+impl XmodemError {
+    /// Returns a short human-readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            XmodemError::TooLarge => "image too large",
+            XmodemError::Cancelled => "cancelled by sender",
+            XmodemError::Sequence => "block out of sequence",
+            XmodemError::TooManyErrors => "too many errors",
+        }
+    }
+}
+
+/// What the caller should do after feeding the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Keep reading.
+    Wait,
+    /// Send this byte to the sender and keep reading.
+    Reply(u8),
+    /// Send `ACK`; the transfer is complete with this many bytes.
+    Finished(usize),
+    /// Send `CAN` and give up.
+    Abort(XmodemError),
+}
+
+/// XMODEM receiver state.
+pub struct Receiver {
+    packet: [u8; PACKET_SIZE],
+    /// Bytes of `packet` filled, `None` while waiting for a header.
+    filled: Option<usize>,
+    /// Number of the next block to store.
+    expected: u8,
+    received: usize,
+    errors: u32,
+}
+
+impl Receiver {
+    /// Create a receiver expecting block 1.
+    pub const fn new() -> Self {
+        Self {
+            packet: [0; PACKET_SIZE],
+            filled: None,
+            expected: 1,
+            received: 0,
+            errors: 0,
+        }
+    }
+
+    /// Returns the number of bytes stored so far.
+    #[allow(dead_code)]
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Feed one received byte.
+    ///
+    /// # Arguments
+    /// * `byte` - Byte read from the line
+    /// * `dest` - Image buffer; accepted blocks are copied in order
+    pub fn feed(&mut self, byte: u8, dest: &mut [u8]) -> Action {
+        let Some(filled) = self.filled else {
+            return match byte {
+                SOH => {
+                    self.filled = Some(0);
+                    Action::Wait
+                }
+                EOT => Action::Finished(self.received),
+                CAN => Action::Abort(XmodemError::Cancelled),
+                // Line noise between blocks
+                _ => Action::Wait,
+            };
+        };
+
+        self.packet[filled] = byte;
+        if filled + 1 < PACKET_SIZE {
+            self.filled = Some(filled + 1);
+            return Action::Wait;
+        }
+
+        self.filled = None;
+        self.finish_packet(dest)
+    }
+
+    /// Handle a read timeout: drop any partial block and ask again.
+    pub fn timeout(&mut self) -> Action {
+        self.filled = None;
+        self.reject()
+    }
+
+    /// Validate and store a complete packet.
+    fn finish_packet(&mut self, dest: &mut [u8]) -> Action {
+        let block = self.packet[0];
+        let payload = &self.packet[2..2 + BLOCK_SIZE];
+        let checksum = payload.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+        if block ^ self.packet[1] != 0xff || checksum != self.packet[PACKET_SIZE - 1] {
+            return self.reject();
+        }
+
+        if block == self.expected.wrapping_sub(1) {
+            // Our ACK was lost and the sender repeated the block
+            return Action::Reply(ACK);
+        }
+        if block != self.expected {
+            return Action::Abort(XmodemError::Sequence);
+        }
+
+        let end = self.received + BLOCK_SIZE;
+        let Some(slot) = dest.get_mut(self.received..end) else {
+            return Action::Abort(XmodemError::TooLarge);
+        };
+        slot.copy_from_slice(payload);
+
+        self.received = end;
+        self.expected = self.expected.wrapping_add(1);
+        self.errors = 0;
+        Action::Reply(ACK)
+    }
+
+    /// Count an error and ask for a retransmission.
+    fn reject(&mut self) -> Action {
+        self.errors += 1;
+        if self.errors > MAX_ERRORS {
+            return Action::Abort(XmodemError::TooManyErrors);
+        }
+        Action::Reply(NAK)
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn packet(block: u8, fill: u8) -> Vec<u8> {
+        let payload = [fill; BLOCK_SIZE];
+        let sum = payload.iter().fold(0u8, |s, &b| s.wrapping_add(b));
+        let mut p = vec![SOH, block, !block];
+        p.extend_from_slice(&payload);
+        p.push(sum);
+        p
+    }
+
+    /// Feed `input`, returning every non-`Wait` action.
+    fn run(rx: &mut Receiver, input: &[u8], dest: &mut [u8]) -> Vec<Action> {
+        input
+            .iter()
+            .map(|&b| rx.feed(b, dest))
+            .filter(|a| *a != Action::Wait)
+            .collect()
+    }
+
+    #[test]
+    fn test_xmodem_transfer() {
+        let mut rx = Receiver::new();
+        let mut dest = [0u8; 512];
+
+        let mut input = packet(1, 0xaa);
+        input.extend(packet(2, 0xbb));
+        input.push(EOT);
+
+        assert_eq!(
+            run(&mut rx, &input, &mut dest),
+            [
+                Action::Reply(ACK),
+                Action::Reply(ACK),
+                Action::Finished(256)
+            ]
+        );
+        assert!(dest[..128].iter().all(|&b| b == 0xaa));
+        assert!(dest[128..256].iter().all(|&b| b == 0xbb));
+        assert!(dest[256..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_xmodem_bad_block_retried() {
+        let mut rx = Receiver::new();
+        let mut dest = [0u8; 256];
+
+        // Corrupt checksum, then bad complement, then the good block
+        let mut bad_sum = packet(1, 0x11);
+        *bad_sum.last_mut().unwrap() ^= 1;
+        let mut bad_num = packet(1, 0x11);
+        bad_num[2] = 0;
+
+        let mut input = b"\xff\x00".to_vec();
+        input.extend(bad_sum);
+        input.extend(bad_num);
+        input.extend(packet(1, 0x11));
+        assert_eq!(
+            run(&mut rx, &input, &mut dest),
+            [Action::Reply(NAK), Action::Reply(NAK), Action::Reply(ACK)]
+        );
+        assert_eq!(rx.received(), 128);
+
+        // A repeated block is acknowledged but not stored twice
+        let input = packet(1, 0x22);
+        assert_eq!(run(&mut rx, &input, &mut dest), [Action::Reply(ACK)]);
+        assert_eq!(rx.received(), 128);
+        assert_eq!(dest[0], 0x11);
+    }
+
+    #[test]
+    fn test_xmodem_aborts() {
+        let mut dest = [0u8; 128];
+
+        let mut rx = Receiver::new();
+        let mut input = packet(1, 0);
+        input.extend(packet(2, 0));
+        assert_eq!(
+            run(&mut rx, &input, &mut dest),
+            [Action::Reply(ACK), Action::Abort(XmodemError::TooLarge)]
+        );
+
+        let mut rx = Receiver::new();
+        assert_eq!(
+            run(&mut rx, &packet(3, 0), &mut dest),
+            [Action::Abort(XmodemError::Sequence)]
+        );
+
+        let mut rx = Receiver::new();
+        assert_eq!(
+            run(&mut rx, &[CAN], &mut dest),
+            [Action::Abort(XmodemError::Cancelled)]
+        );
+    }
+
+    #[test]
+    fn test_xmodem_timeouts() {
+        let mut rx = Receiver::new();
+        let mut dest = [0u8; 256];
+
+        // A timeout drops the partial block
+        let good = packet(1, 0x33);
+        run(&mut rx, &good[..50], &mut dest);
+        assert_eq!(rx.timeout(), Action::Reply(NAK));
+        assert_eq!(run(&mut rx, &good, &mut dest), [Action::Reply(ACK)]);
+
+        // The error count starts over after a good block
+        for _ in 0..MAX_ERRORS {
+            assert_eq!(rx.timeout(), Action::Reply(NAK));
+        }
+        assert_eq!(rx.timeout(), Action::Abort(XmodemError::TooManyErrors));
+    }
+}
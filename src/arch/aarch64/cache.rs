@@ -0,0 +1,89 @@
+//! Cache maintenance by virtual address.
+//!
+//! Line sizes come from CTR_EL0, so the helpers work on any core without
+//! hardcoding 64-byte lines.
+
+/// Smallest data cache line size encoded in CTR_EL0, in bytes.
+///
+/// `DminLine` (bits [19:16]) is log2 of the line size in 4-byte words.
+pub const fn dcache_line_size(ctr: u64) -> u64 {
+    4 << ((ctr >> 16) & 0xf)
+}
+
+/// Smallest instruction cache line size encoded in CTR_EL0, in bytes.
+///
+/// `IminLine` (bits [3:0]) is log2 of the line size in 4-byte words.
+#[allow(dead_code)]
+pub const fn icache_line_size(ctr: u64) -> u64 {
+    4 << (ctr & 0xf)
+}
+
+/// Returns the line-aligned `[start, end)` covering `[addr, addr + size)`.
+pub const fn line_span(addr: u64, size: u64, line: u64) -> (u64, u64) {
+    let start = addr & !(line - 1);
+    let end = (addr + size + line - 1) & !(line - 1);
+    (start, end)
+}
+
+/// Read the cache type register (CTR_EL0).
+#[cfg(target_os = "none")]
+fn ctr() -> u64 {
+    let ctr: u64;
+    unsafe {
+        // Safety: reading CTR_EL0 has no side effects
+        core::arch::asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack));
+    }
+    ctr
+}
+
+/// Clean the data cache to the point of coherency over a range.
+///
+/// Afterwards the range's contents are visible to non-cacheable accesses,
+/// such as instruction fetches or data accesses with the MMU off.
+///
+/// # Arguments
+/// * `va` - Virtual start address
+/// * `size` - Length in bytes
+#[cfg(target_os = "none")]
+pub fn clean_dcache_range(va: u64, size: u64) {
+    let line = dcache_line_size(ctr());
+    let (start, end) = line_span(va, size, line);
+
+    let mut addr = start;
+    while addr < end {
+        // Safety: cleaning a mapped line only writes back dirty data
+        unsafe { core::arch::asm!("dc cvac, {}", in(reg) addr, options(nostack)) };
+        addr += line;
+    }
+    crate::arch::barrier::dsb(crate::arch::barrier::Scope::Sy);
+}
+
+/// Invalidate all instruction caches to the point of unification.
+#[cfg(target_os = "none")]
+pub fn invalidate_icache_all() {
+    // Safety: instruction cache invalidation has no architectural side
+    // effects beyond refetching
+    unsafe { core::arch::asm!("ic iallu", "dsb nsh", "isb", options(nostack)) };
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_sizes() {
+        // Cortex-A57 style CTR_EL0: 64-byte D and I lines
+        let ctr = 0x8444_c004;
+        assert_eq!(dcache_line_size(ctr), 64);
+        assert_eq!(icache_line_size(ctr), 64);
+        assert_eq!(dcache_line_size(0), 4);
+    }
+
+    #[test]
+    fn test_line_span() {
+        assert_eq!(line_span(0x1000, 0x40, 64), (0x1000, 0x1040));
+        assert_eq!(line_span(0x1010, 0x40, 64), (0x1000, 0x1080));
+        assert_eq!(line_span(0x103f, 1, 64), (0x1000, 0x1040));
+        assert_eq!(line_span(0x1000, 0, 64), (0x1000, 0x1000));
+    }
+}
@@ -0,0 +1,509 @@
+//! CPU feature detection from the ID registers.
+//!
+//! Decodes `ID_AA64MMFR0_EL1` (physical address size and translation
+//! granules) and `ID_AA64PFR0_EL1` (FP/SIMD) into [`CpuFeatures`], and
+//! adds `MIDR_EL1` (implementer, part and revision) and `ID_AA64ISAR0_EL1`
+//! (CRC32) in [`CpuInfo`]. The decoding is pure so it can be tested on the
+//! host with register values captured from QEMU CPU models.
+//!
+//! The boot CPU's [`CpuInfo`] is read once and then available from [`get`].
+
+use core::fmt;
+
+/// ID_AA64MMFR0_EL1 field positions.
+mod mmfr0 {
+    /// Physical address range, bits[3:0].
+    pub const PARANGE_SHIFT: u64 = 0;
+    /// 16KB granule support, bits[23:20].
+    pub const TGRAN16_SHIFT: u64 = 20;
+    /// 64KB granule support, bits[27:24].
+    pub const TGRAN64_SHIFT: u64 = 24;
+    /// 4KB granule support, bits[31:28].
+    pub const TGRAN4_SHIFT: u64 = 28;
+}
+
+/// MIDR_EL1 field positions.
+mod midr {
+    /// Revision, bits[3:0].
+    pub const REVISION_SHIFT: u64 = 0;
+    /// Primary part number, bits[15:4].
+    pub const PARTNUM_SHIFT: u64 = 4;
+    /// Variant, bits[23:20].
+    pub const VARIANT_SHIFT: u64 = 20;
+    /// Implementer, bits[31:24].
+    pub const IMPLEMENTER_SHIFT: u64 = 24;
+}
+
+/// ID_AA64ISAR0_EL1 field positions.
+mod isar0 {
+    /// CRC32 instructions, bits[19:16].
+    pub const CRC32_SHIFT: u64 = 16;
+}
+
+/// ID_AA64PFR0_EL1 field positions.
+mod pfr0 {
+    /// Floating point, bits[19:16].
+    pub const FP_SHIFT: u64 = 16;
+    /// Advanced SIMD, bits[23:20].
+    pub const ADVSIMD_SHIFT: u64 = 20;
+}
+
+/// SCTLR_EL1 bit positions.
+pub mod sctlr {
+    /// MMU enable.
+    pub const M: u64 = 1 << 0;
+    /// Alignment check enable.
+    pub const A: u64 = 1 << 1;
+    /// Data cache enable.
+    pub const C: u64 = 1 << 2;
+    /// Instruction cache enable.
+    pub const I: u64 = 1 << 12;
+    /// Writable regions are execute-never.
+    pub const WXN: u64 = 1 << 19;
+    /// Big-endian data accesses at EL1.
+    pub const EE: u64 = 1 << 25;
+}
+
+/// Value of a 4-bit ID field meaning "not implemented" for signed fields.
+const NOT_IMPLEMENTED: u64 = 0xf;
+
+/// Largest PARange encoding the page tables can express (48 bits).
+///
+/// Output addresses are limited to bits[47:12] without FEAT_LPA.
+pub const MAX_PARANGE: u64 = 5;
+
+/// Extract the 4-bit ID register field at `shift`.
+const fn field(reg: u64, shift: u64) -> u64 {
+    (reg >> shift) & 0xf
+}
+
+/// Returns the physical address size in bits for a PARange encoding.
+///
+/// Reserved encodings are reported as 0.
+pub const fn pa_bits(parange: u64) -> u32 {
+    match parange {
+        0 => 32,
+        1 => 36,
+        2 => 40,
+        3 => 42,
+        4 => 44,
+        5 => 48,
+        6 => 52,
+        _ => 0,
+    }
+}
+
+/// Features decoded from the ID registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFeatures {
+    /// Raw PARange field of ID_AA64MMFR0_EL1.
+    pub parange: u64,
+    /// Physical address size in bits, 0 if PARange is reserved.
+    pub pa_bits: u32,
+    /// 4KB translation granule supported.
+    pub granule_4k: bool,
+    /// 16KB translation granule supported.
+    pub granule_16k: bool,
+    /// 64KB translation granule supported.
+    pub granule_64k: bool,
+    /// Floating point implemented.
+    pub fp: bool,
+    /// Advanced SIMD implemented.
+    pub asimd: bool,
+}
+
+impl CpuFeatures {
+    /// Decode raw ID register values.
+    ///
+    /// # Arguments
+    /// * `mmfr0` - Value of ID_AA64MMFR0_EL1
+    /// * `pfr0` - Value of ID_AA64PFR0_EL1
+    pub const fn decode(mmfr0: u64, pfr0: u64) -> Self {
+        let parange = field(mmfr0, mmfr0::PARANGE_SHIFT);
+        Self {
+            parange,
+            pa_bits: pa_bits(parange),
+            // TGran4 and TGran64 are signed: 0xf means unsupported
+            granule_4k: field(mmfr0, mmfr0::TGRAN4_SHIFT) != NOT_IMPLEMENTED,
+            // TGran16 is unsigned: 0 means unsupported
+            granule_16k: field(mmfr0, mmfr0::TGRAN16_SHIFT) != 0,
+            granule_64k: field(mmfr0, mmfr0::TGRAN64_SHIFT) != NOT_IMPLEMENTED,
+            fp: field(pfr0, pfr0::FP_SHIFT) != NOT_IMPLEMENTED,
+            asimd: field(pfr0, pfr0::ADVSIMD_SHIFT) != NOT_IMPLEMENTED,
+        }
+    }
+
+    /// Returns the TCR_EL1.IPS value to program for this CPU.
+    ///
+    /// The CPU's PARange, capped at [`MAX_PARANGE`].
+    pub const fn tcr_ips(&self) -> u64 {
+        if self.parange > MAX_PARANGE {
+            MAX_PARANGE
+        } else {
+            self.parange
+        }
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PA {} bits, granules:", self.pa_bits)?;
+        let granules = [
+            (self.granule_4k, " 4K"),
+            (self.granule_16k, " 16K"),
+            (self.granule_64k, " 64K"),
+        ];
+        if granules.iter().all(|&(supported, _)| !supported) {
+            f.write_str(" none")?;
+        }
+        for (supported, name) in granules {
+            if supported {
+                f.write_str(name)?;
+            }
+        }
+        write!(
+            f,
+            ", FP {}, SIMD {}",
+            if self.fp { "yes" } else { "no" },
+            if self.asimd { "yes" } else { "no" }
+        )
+    }
+}
+
+/// MIDR_EL1 implementer codes.
+pub mod implementer {
+    /// Reserved for software use; QEMU's `max` CPU reports it.
+    pub const SOFTWARE: u8 = 0x00;
+    /// Arm Limited.
+    pub const ARM: u8 = 0x41;
+    /// Broadcom.
+    pub const BROADCOM: u8 = 0x42;
+    /// Cavium.
+    pub const CAVIUM: u8 = 0x43;
+    /// Fujitsu.
+    pub const FUJITSU: u8 = 0x46;
+    /// Qualcomm.
+    pub const QUALCOMM: u8 = 0x51;
+    /// Apple.
+    pub const APPLE: u8 = 0x61;
+}
+
+/// Returns the name of a MIDR_EL1 implementer code, if known.
+pub const fn implementer_name(implementer: u8) -> Option<&'static str> {
+    match implementer {
+        implementer::SOFTWARE => Some("Software"),
+        implementer::ARM => Some("ARM"),
+        implementer::BROADCOM => Some("Broadcom"),
+        implementer::CAVIUM => Some("Cavium"),
+        implementer::FUJITSU => Some("Fujitsu"),
+        implementer::QUALCOMM => Some("Qualcomm"),
+        implementer::APPLE => Some("Apple"),
+        _ => None,
+    }
+}
+
+/// Returns the name of a part from `implementer`, if known.
+pub const fn part_name(implementer: u8, part: u16) -> Option<&'static str> {
+    match (implementer, part) {
+        (implementer::SOFTWARE, 0x051) => Some("QEMU max"),
+        (implementer::ARM, 0xd03) => Some("Cortex-A53"),
+        (implementer::ARM, 0xd04) => Some("Cortex-A35"),
+        (implementer::ARM, 0xd05) => Some("Cortex-A55"),
+        (implementer::ARM, 0xd07) => Some("Cortex-A57"),
+        (implementer::ARM, 0xd08) => Some("Cortex-A72"),
+        (implementer::ARM, 0xd09) => Some("Cortex-A73"),
+        (implementer::ARM, 0xd0b) => Some("Cortex-A76"),
+        (implementer::ARM, 0xd0c) => Some("Neoverse-N1"),
+        (implementer::ARM, 0xd40) => Some("Neoverse-V1"),
+        (implementer::ARM, 0xd41) => Some("Cortex-A78"),
+        (implementer::ARM, 0xd46) => Some("Cortex-A510"),
+        (implementer::ARM, 0xd47) => Some("Cortex-A710"),
+        (implementer::ARM, 0xd49) => Some("Neoverse-N2"),
+        (implementer::BROADCOM, 0x516) => Some("ThunderX2"),
+        (implementer::CAVIUM, 0x0a1) => Some("ThunderX"),
+        (implementer::FUJITSU, 0x001) => Some("A64FX"),
+        _ => None,
+    }
+}
+
+/// Identity and features of a CPU, decoded from its ID registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuInfo {
+    /// Raw MIDR_EL1.
+    pub midr: u64,
+    /// Raw ID_AA64ISAR0_EL1.
+    pub isar0: u64,
+    /// Features decoded from ID_AA64MMFR0_EL1 and ID_AA64PFR0_EL1.
+    pub features: CpuFeatures,
+}
+
+impl CpuInfo {
+    /// Decode raw ID register values.
+    ///
+    /// # Arguments
+    /// * `midr` - Value of MIDR_EL1
+    /// * `mmfr0` - Value of ID_AA64MMFR0_EL1
+    /// * `isar0` - Value of ID_AA64ISAR0_EL1
+    /// * `pfr0` - Value of ID_AA64PFR0_EL1
+    pub const fn decode(midr: u64, mmfr0: u64, isar0: u64, pfr0: u64) -> Self {
+        Self {
+            midr,
+            isar0,
+            features: CpuFeatures::decode(mmfr0, pfr0),
+        }
+    }
+
+    /// Returns the MIDR_EL1 implementer code.
+    pub const fn implementer(&self) -> u8 {
+        (self.midr >> midr::IMPLEMENTER_SHIFT) as u8
+    }
+
+    /// Returns the MIDR_EL1 primary part number.
+    pub const fn part(&self) -> u16 {
+        ((self.midr >> midr::PARTNUM_SHIFT) & 0xfff) as u16
+    }
+
+    /// Returns the major revision (the `r` in `rNpM`).
+    pub const fn variant(&self) -> u8 {
+        field(self.midr, midr::VARIANT_SHIFT) as u8
+    }
+
+    /// Returns the minor revision (the `p` in `rNpM`).
+    pub const fn revision(&self) -> u8 {
+        field(self.midr, midr::REVISION_SHIFT) as u8
+    }
+
+    /// Returns the implementer's name, if known.
+    pub const fn implementer_name(&self) -> Option<&'static str> {
+        implementer_name(self.implementer())
+    }
+
+    /// Returns the part's name, if known.
+    pub const fn part_name(&self) -> Option<&'static str> {
+        part_name(self.implementer(), self.part())
+    }
+
+    /// Returns true if the CRC32 instructions are implemented.
+    pub const fn supports_crc32(&self) -> bool {
+        field(self.isar0, isar0::CRC32_SHIFT) != 0
+    }
+
+    /// Returns true if the 4KB translation granule is supported.
+    pub const fn granule_4k_supported(&self) -> bool {
+        self.features.granule_4k
+    }
+
+    /// Returns the TCR_EL1.IPS value to program for this CPU.
+    pub const fn tcr_ips(&self) -> u64 {
+        self.features.tcr_ips()
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.part_name(), self.implementer_name()) {
+            (Some(part), _) => f.write_str(part)?,
+            (None, Some(implementer)) => write!(f, "{} part {:#05x}", implementer, self.part())?,
+            (None, None) => write!(
+                f,
+                "implementer {:#04x} part {:#05x}",
+                self.implementer(),
+                self.part()
+            )?,
+        }
+        write!(
+            f,
+            " r{}p{}, {}, CRC32{}",
+            self.variant(),
+            self.revision(),
+            self.features,
+            if self.supports_crc32() { "+" } else { "-" }
+        )
+    }
+}
+
+/// Returns true if `sctlr_el1` has stage 1 translation enabled.
+pub const fn sctlr_mmu_enabled(sctlr_el1: u64) -> bool {
+    sctlr_el1 & sctlr::M != 0
+}
+
+/// Returns true if the running CPU has the EL1 MMU enabled.
+#[cfg(target_os = "none")]
+pub fn mmu_enabled() -> bool {
+    sctlr_mmu_enabled(crate::arch::reg::sctlr_el1())
+}
+
+/// Boot CPU information, read on first use.
+#[cfg(target_os = "none")]
+static CPU_INFO: crate::arch::sync::IrqSafeMutex<Option<CpuInfo>> =
+    crate::arch::sync::IrqSafeMutex::new("cpu_info", None);
+
+/// Read the ID registers of the running CPU and decode them.
+#[cfg(target_os = "none")]
+fn read_info() -> CpuInfo {
+    let midr: u64;
+    let mmfr0: u64;
+    let isar0: u64;
+    let pfr0: u64;
+    unsafe {
+        // Safety: reading ID registers has no side effects
+        core::arch::asm!(
+            "mrs {}, midr_el1",
+            "mrs {}, id_aa64mmfr0_el1",
+            "mrs {}, id_aa64isar0_el1",
+            "mrs {}, id_aa64pfr0_el1",
+            out(reg) midr,
+            out(reg) mmfr0,
+            out(reg) isar0,
+            out(reg) pfr0,
+        );
+    }
+    CpuInfo::decode(midr, mmfr0, isar0, pfr0)
+}
+
+/// Returns the boot CPU's information.
+///
+/// The ID registers are read on the first call; later calls return the
+/// same values.
+#[cfg(target_os = "none")]
+pub fn get() -> CpuInfo {
+    *CPU_INFO.lock().get_or_insert_with(read_info)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_cortex_a57() {
+        // QEMU -cpu cortex-a57: 44-bit PA, 4K and 64K granules only
+        let cpu = CpuFeatures::decode(0x0000_0000_0000_1124, 0x0000_0000_0000_2222);
+        assert_eq!(cpu.parange, 4);
+        assert_eq!(cpu.pa_bits, 44);
+        assert!(cpu.granule_4k && !cpu.granule_16k && cpu.granule_64k);
+        assert!(cpu.fp && cpu.asimd);
+        assert_eq!(cpu.tcr_ips(), 4);
+    }
+
+    #[test]
+    fn test_decode_cortex_a72() {
+        // 48-bit PA, all three granules
+        let cpu = CpuFeatures::decode(0x0000_0000_0010_1125, 0x0000_0000_0000_2222);
+        assert_eq!(cpu.pa_bits, 48);
+        assert!(cpu.granule_4k && cpu.granule_16k && cpu.granule_64k);
+        assert_eq!(cpu.tcr_ips(), 5);
+    }
+
+    #[test]
+    fn test_decode_qemu_max() {
+        // 52-bit PA with 52-bit capable granules, capped to 48 bits for TCR
+        let cpu = CpuFeatures::decode(0x1000_0000_1020_0026, 0x0000_0000_0011_0011);
+        assert_eq!(cpu.pa_bits, 52);
+        assert!(cpu.granule_4k && cpu.granule_16k && cpu.granule_64k);
+        assert!(cpu.fp && cpu.asimd);
+        assert_eq!(cpu.tcr_ips(), MAX_PARANGE);
+    }
+
+    #[test]
+    fn test_decode_missing_features() {
+        // No 4K or 64K granule, no FP/SIMD, reserved PARange
+        let cpu = CpuFeatures::decode(0xff00_0007, 0x00ff_0000);
+        assert_eq!(cpu.pa_bits, 0);
+        assert!(!cpu.granule_4k && !cpu.granule_16k && !cpu.granule_64k);
+        assert!(!cpu.fp && !cpu.asimd);
+    }
+
+    #[test]
+    fn test_cpu_features_display() {
+        let cpu = CpuFeatures::decode(0x1124, 0x2222);
+        assert_eq!(
+            cpu.to_string(),
+            "PA 44 bits, granules: 4K 64K, FP yes, SIMD yes"
+        );
+        let cpu = CpuFeatures::decode(0xff00_0000, 0x00ff_0000);
+        assert_eq!(
+            cpu.to_string(),
+            "PA 32 bits, granules: none, FP no, SIMD no"
+        );
+    }
+
+    // MIDR, MMFR0, ISAR0 and PFR0 as QEMU reports them
+    const CORTEX_A53: CpuInfo = CpuInfo::decode(
+        0x0000_0000_410f_d034,
+        0x0000_0000_0000_1122,
+        0x0000_0000_0001_1120,
+        0x0000_0000_0000_2222,
+    );
+    const QEMU_MAX: CpuInfo = CpuInfo::decode(
+        0x0000_0000_000f_0510,
+        0x1000_0000_1020_0026,
+        0x0221_1001_1021_2120,
+        0x1201_0011_1111_2222,
+    );
+
+    #[test]
+    fn test_cpu_info_cortex_a53() {
+        let cpu = CORTEX_A53;
+        assert_eq!(cpu.implementer(), implementer::ARM);
+        assert_eq!(cpu.part(), 0xd03);
+        assert_eq!((cpu.variant(), cpu.revision()), (0, 4));
+        assert_eq!(cpu.implementer_name(), Some("ARM"));
+        assert_eq!(cpu.part_name(), Some("Cortex-A53"));
+        assert!(cpu.supports_crc32());
+        assert_eq!(cpu.features.pa_bits, 40);
+        assert!(cpu.granule_4k_supported());
+        assert_eq!(cpu.tcr_ips(), 2);
+    }
+
+    #[test]
+    fn test_cpu_info_qemu_max() {
+        let cpu = QEMU_MAX;
+        assert_eq!(cpu.implementer(), implementer::SOFTWARE);
+        assert_eq!(cpu.part(), 0x051);
+        assert_eq!(cpu.part_name(), Some("QEMU max"));
+        assert!(cpu.supports_crc32());
+        assert_eq!(cpu.features.pa_bits, 52);
+        // The page tables cannot use more than 48 bits of PA
+        assert_eq!(cpu.tcr_ips(), MAX_PARANGE);
+    }
+
+    #[test]
+    fn test_cpu_info_missing_features() {
+        // Unknown implementer, no CRC32
+        let cpu = CpuInfo::decode(0x7f2f_1231, 0x1124, 0, 0x0011);
+        assert_eq!((cpu.variant(), cpu.revision()), (2, 1));
+        assert_eq!(cpu.implementer_name(), None);
+        assert_eq!(cpu.part_name(), None);
+        assert!(!cpu.supports_crc32());
+        // Known implementer, unknown part
+        assert_eq!(part_name(implementer::ARM, 0xfff), None);
+    }
+
+    #[test]
+    fn test_cpu_info_display() {
+        assert_eq!(
+            CORTEX_A53.to_string(),
+            "Cortex-A53 r0p4, PA 40 bits, granules: 4K 64K, FP yes, SIMD yes, CRC32+"
+        );
+        let cpu = CpuInfo::decode(0x411f_dfff, 0x1124, 0, 0x2222);
+        assert_eq!(
+            cpu.to_string(),
+            "ARM part 0xdff r1p15, PA 44 bits, granules: 4K 64K, FP yes, SIMD yes, CRC32-"
+        );
+        let cpu = CpuInfo::decode(0x7f00_0010, 0x1124, 0, 0x2222);
+        assert!(
+            cpu.to_string()
+                .starts_with("implementer 0x7f part 0x001 r0p0")
+        );
+    }
+
+    #[test]
+    fn test_sctlr_mmu_enabled() {
+        // Reset value written by boot.S, then with M, C and I set
+        assert!(!sctlr_mmu_enabled(0x30c5_0830));
+        assert!(sctlr_mmu_enabled(
+            0x30c5_0830 | sctlr::M | sctlr::C | sctlr::I
+        ));
+        assert!(!sctlr_mmu_enabled(sctlr::C | sctlr::I));
+    }
+}
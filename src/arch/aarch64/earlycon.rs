@@ -0,0 +1,139 @@
+//! Boot log console.
+//!
+//! Boot progress goes through [`write_str`] and [`Writer`] rather than
+//! straight to the PL011, so it can reach boards and QEMU setups without
+//! that UART. The backend is a plain enum picked once at boot with
+//! `earlycon=` on the kernel command line:
+//!
+//! - `earlycon=serial` (default): the PL011 driver in [`serial`]
+//! - `earlycon=semihosting`: the host console through semihosting
+//!   `SYS_WRITE0`, which needs QEMU's `-semihosting-config enable=on`
+//!
+//! Driver-specific output, such as the debug console and framed reports,
+//! keeps using [`serial`] directly.
+//!
+//! [`serial`]: crate::arch::serial
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Kernel command line option selecting the backend.
+pub const CMDLINE_OPTION: &str = "earlycon=";
+
+/// Where boot log output goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// The PL011 UART.
+    Serial = 0,
+    /// The host console through semihosting.
+    Semihosting = 1,
+}
+
+impl Backend {
+    /// Returns the name used on the command line.
+    #[cfg(test)]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Serial => "serial",
+            Self::Semihosting => "semihosting",
+        }
+    }
+
+    /// Parse the backend name used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "serial" => Some(Self::Serial),
+            "semihosting" => Some(Self::Semihosting),
+            _ => None,
+        }
+    }
+
+    /// Returns the backend named by the last `earlycon=` option in
+    /// `cmdline`, `None` if there is none or it names no backend.
+    pub fn from_cmdline(cmdline: &str) -> Option<Self> {
+        cmdline
+            .split_ascii_whitespace()
+            .filter_map(|arg| arg.strip_prefix(CMDLINE_OPTION))
+            .next_back()
+            .and_then(Self::from_name)
+    }
+
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Semihosting,
+            _ => Self::Serial,
+        }
+    }
+}
+
+/// Selected backend, as `Backend as u8`.
+static BACKEND: AtomicU8 = AtomicU8::new(Backend::Serial as u8);
+
+/// Send boot log output to `backend` from now on.
+pub fn select(backend: Backend) {
+    BACKEND.store(backend as u8, Ordering::Relaxed);
+}
+
+/// Returns the selected backend.
+pub fn selected() -> Backend {
+    Backend::from_u8(BACKEND.load(Ordering::Relaxed))
+}
+
+/// Write `s` to the selected backend.
+#[cfg(target_os = "none")]
+pub fn write_str(s: &str) {
+    use crate::arch::{semihosting, serial};
+
+    match selected() {
+        Backend::Serial => {
+            let _ = core::fmt::Write::write_str(&mut serial::Writer, s);
+        }
+        Backend::Semihosting => semihosting::write_str(s),
+    }
+}
+
+/// `core::fmt::Write` adapter for the boot log.
+#[cfg(target_os = "none")]
+pub struct Writer;
+
+#[cfg(target_os = "none")]
+impl core::fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_str(s);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_from_cmdline() {
+        assert_eq!(Backend::from_cmdline(""), None);
+        assert_eq!(
+            Backend::from_cmdline("console earlycon=semihosting"),
+            Some(Backend::Semihosting)
+        );
+        // The last option wins, unknown names select nothing
+        assert_eq!(
+            Backend::from_cmdline("earlycon=semihosting earlycon=serial"),
+            Some(Backend::Serial)
+        );
+        assert_eq!(Backend::from_cmdline("earlycon=pl011"), None);
+        assert_eq!(Backend::from_cmdline("earlycon"), None);
+    }
+
+    #[test]
+    fn test_backend_select() {
+        assert_eq!(selected(), Backend::Serial);
+        select(Backend::Semihosting);
+        assert_eq!(selected(), Backend::Semihosting);
+        select(Backend::Serial);
+        assert_eq!(selected(), Backend::Serial);
+
+        for backend in [Backend::Serial, Backend::Semihosting] {
+            assert_eq!(Backend::from_name(backend.as_str()), Some(backend));
+            assert_eq!(Backend::from_u8(backend as u8), backend);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Exception vector setup, IRQ entry and fatal exception reporting.
+//!
+//! IRQs taken at EL1 on the kernel stack go to [`irq_handler`], which
+//! hands them to the interrupt controller driver. All other exceptions
+//! are treated as fatal: the handler prints the syndrome and halts. Fatal
+//! handlers run on a dedicated emergency stack so a kernel stack overflow
+//! into a guard page can still be reported.
+
+#[cfg(target_os = "none")]
+use core::arch::global_asm;
+
+#[cfg(target_os = "none")]
+global_asm!(include_str!("exception.S"));
+
+/// Size of the emergency exception stack (16KB).
+#[allow(dead_code)]
+const EMERGENCY_STACK_SIZE: usize = 0x4000;
+
+/// ESR_EL1 exception class field.
+mod esr {
+    /// Shift of the EC field bits[31:26].
+    pub const EC_SHIFT: u64 = 26;
+    /// Mask of the EC field after shifting.
+    pub const EC_MASK: u64 = 0x3f;
+    /// Instruction abort from a lower exception level.
+    pub const EC_IABT_LOW: u64 = 0x20;
+    /// Instruction abort taken without a change in exception level.
+    pub const EC_IABT_CUR: u64 = 0x21;
+    /// Data abort from a lower exception level.
+    pub const EC_DABT_LOW: u64 = 0x24;
+    /// Data abort taken without a change in exception level.
+    pub const EC_DABT_CUR: u64 = 0x25;
+}
+
+/// Returns the exception class of an ESR_EL1 value.
+pub const fn exception_class(esr: u64) -> u64 {
+    (esr >> esr::EC_SHIFT) & esr::EC_MASK
+}
+
+/// Returns true if `esr` describes a data abort taken from EL1.
+pub const fn is_kernel_data_abort(esr: u64) -> bool {
+    exception_class(esr) == esr::EC_DABT_CUR
+}
+
+/// Returns true if `esr` describes an instruction or data abort, for which
+/// FAR holds the faulting address.
+pub const fn is_abort(esr: u64) -> bool {
+    matches!(
+        exception_class(esr),
+        esr::EC_IABT_LOW | esr::EC_IABT_CUR | esr::EC_DABT_LOW | esr::EC_DABT_CUR
+    )
+}
+
+/// Backing storage for the emergency exception stack.
+#[repr(C, align(16))]
+#[allow(dead_code)]
+struct EmergencyStack([u8; EMERGENCY_STACK_SIZE]);
+
+#[allow(dead_code)]
+static mut EMERGENCY_STACK: EmergencyStack = EmergencyStack([0; EMERGENCY_STACK_SIZE]);
+
+#[cfg(target_os = "none")]
+unsafe extern "C" {
+    /// Exception vector table (from exception.S).
+    static __exception_vectors: u8;
+}
+
+/// Install the exception vectors and the emergency stack.
+///
+/// The kernel runs on SP_EL1, so SP_EL0 is free to hold the emergency
+/// stack the vector entries switch to.
+#[cfg(target_os = "none")]
+pub fn init() {
+    let stack_top = (&raw const EMERGENCY_STACK) as u64 + EMERGENCY_STACK_SIZE as u64;
+
+    unsafe {
+        // Safety: the vector table is 2KB aligned by exception.S and the
+        // emergency stack is only used by the fatal exception path
+        core::arch::asm!(
+            "msr sp_el0, {stack}",
+            "msr vbar_el1, {vectors}",
+            "isb",
+            stack = in(reg) stack_top,
+            vectors = in(reg) &__exception_vectors as *const u8 as u64,
+        );
+    }
+}
+
+/// Returns the affinity level 0 (core) number of the current CPU.
+#[cfg(target_os = "none")]
+pub fn cpu_id() -> u64 {
+    crate::arch::reg::mpidr_el1() & 0xff
+}
+
+/// Serve the pending IRQs.
+///
+/// Called from exception.S with IRQs masked, on the interrupted kernel
+/// stack. Must not wait on anything the interrupted code may hold.
+#[cfg(target_os = "none")]
+#[unsafe(no_mangle)]
+extern "C" fn irq_handler() {
+    use crate::arch::{address, gic, serial};
+
+    // Nothing unmasks IRQs before a driver is active
+    let Some(gic) = gic::driver() else {
+        return;
+    };
+    gic::dispatch(gic, |intid| match intid {
+        address::virt::UART_IRQ => {
+            serial::handle_irq();
+            true
+        }
+        _ => false,
+    });
+}
+
+/// Report a fatal exception and halt.
+///
+/// Called from exception.S on the emergency stack.
+///
+/// # Arguments
+/// * `index` - Vector table entry that was taken
+/// * `esr` - Exception syndrome
+/// * `elr` - Faulting PC
+/// * `far` - Faulting address
+#[cfg(target_os = "none")]
+#[unsafe(no_mangle)]
+extern "C" fn exception_fatal(index: u64, esr: u64, elr: u64, far: u64) -> ! {
+    use crate::arch::serial;
+    use crate::mm::kstack;
+    use core::fmt::Write;
+
+    if is_kernel_data_abort(esr)
+        && let Some(stack) = kstack::classify_fault(far)
+    {
+        let _ = writeln!(
+            serial::Writer,
+            "\nkernel stack overflow on CPU {} (stack {})",
+            cpu_id(),
+            stack
+        );
+    }
+
+    let _ = writeln!(
+        serial::Writer,
+        "\nUnhandled exception {}: ESR={:#x} ELR={:#x} FAR={:#x}",
+        index,
+        esr,
+        elr,
+        far
+    );
+
+    // Say what the faulting address maps to, or where it falls out of the
+    // tables
+    if is_abort(esr) {
+        use crate::arch::{address, pagetable};
+
+        let ttbr = pagetable::ttbr_for(far, address::kernel::VA_BITS)
+            .map_or("no TTBR", |ttbr| ttbr.as_str());
+        match pagetable::lookup(far) {
+            Ok(leaf) => {
+                let _ = write!(
+                    serial::Writer,
+                    "FAR maps to {:#x} through {} at level {}: ",
+                    leaf.phys,
+                    ttbr,
+                    leaf.level
+                );
+                let _ = pagetable::write_attrs(
+                    &leaf.attrs(),
+                    pagetable::current_mair(),
+                    &mut serial::Writer,
+                );
+                let _ = writeln!(serial::Writer, " (descriptor {:#x})", leaf.desc);
+            }
+            Err(unmapped) => {
+                let _ = writeln!(serial::Writer, "FAR: {} ({})", unmapped, ttbr);
+            }
+        }
+    }
+
+    crate::arch::idle::park_forever()
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exception_class() {
+        // Data abort from EL1, translation fault level 3
+        let esr = 0x9600_0007;
+        assert_eq!(exception_class(esr), 0x25);
+        assert!(is_kernel_data_abort(esr));
+
+        // Instruction abort from EL1
+        assert!(!is_kernel_data_abort(0x8600_0007));
+
+        assert!(is_abort(esr));
+        assert!(is_abort(0x8600_0007));
+        assert!(is_abort(0x9200_0007));
+        // SVC and BRK carry no faulting address
+        assert!(!is_abort(0x5600_0000));
+        assert!(!is_abort(0xf200_0000));
+    }
+}
@@ -0,0 +1,482 @@
+//! ARM Generic Interrupt Controller.
+//!
+//! QEMU virt has a GICv2 or a GICv3 depending on `-machine gic-version=`.
+//! A GICv2 CPU interface is a memory mapped page, a GICv3 one is the
+//! `ICC_*` system registers, so a driver for one version gets no
+//! interrupts on the other. Both drivers implement [`GicDriver`], and
+//! [`init`] picks one at boot from the device tree `compatible`, or from
+//! the distributor's `GICD_PIDR2.ArchRev` when there is no device tree.
+//!
+//! Only the boot CPU's interface is set up, and every SPI is routed to
+//! it. The IRQ exception vector hands each interrupt to [`dispatch`].
+
+use crate::fdt::Fdt;
+use core::fmt;
+#[cfg(target_os = "none")]
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub mod v2;
+pub mod v3;
+
+/// First shared peripheral interrupt; INTIDs 0 to 15 are software
+/// generated, 16 to 31 per-CPU PPIs.
+pub const SPI_BASE: u32 = 32;
+
+/// INTID read from the acknowledge register when nothing is pending.
+pub const INTID_SPURIOUS: u32 = 1023;
+
+/// First of the special INTIDs 1020 to 1023, which are never real
+/// interrupts.
+pub const INTID_SPECIAL: u32 = 1020;
+
+/// Priority given to every interrupt by `init`, in the middle of the range
+/// so individual ones can be raised or lowered.
+pub const DEFAULT_PRIORITY: u8 = 0xa0;
+
+/// Priority mask letting every priority through.
+pub const PRIORITY_MASK_ALL: u8 = 0xff;
+
+/// Distributor register offsets common to GICv2 and GICv3.
+pub mod gicd {
+    /// Distributor control register.
+    pub const CTLR: usize = 0x0000;
+    /// Interrupt controller type register.
+    pub const TYPER: usize = 0x0004;
+    /// Interrupt group registers, one bit per INTID.
+    pub const IGROUPR: usize = 0x0080;
+    /// Set-enable registers, one bit per INTID.
+    pub const ISENABLER: usize = 0x0100;
+    /// Clear-enable registers, one bit per INTID.
+    pub const ICENABLER: usize = 0x0180;
+    /// Priority registers, one byte per INTID.
+    pub const IPRIORITYR: usize = 0x0400;
+
+    /// `TYPER.ITLinesNumber`: supported INTIDs are `32 * (N + 1)`.
+    pub const TYPER_ITLINES_MASK: u32 = 0x1f;
+}
+
+/// Register and bit for `intid` in a bank of one bit per INTID registers,
+/// such as `GICD_ISENABLER<n>`.
+///
+/// # Arguments
+/// * `bank` - Offset of the first register of the bank
+/// * `intid` - Interrupt ID
+///
+/// # Returns
+/// `(offset, mask)` of the register and bit
+pub const fn bit_reg(bank: usize, intid: u32) -> (usize, u32) {
+    (bank + (intid / 32) as usize * 4, 1 << (intid % 32))
+}
+
+/// Offset of the priority byte of `intid` in a `IPRIORITYR` bank.
+pub const fn priority_reg(bank: usize, intid: u32) -> usize {
+    bank + intid as usize
+}
+
+/// Number of INTIDs a distributor supports, from its `GICD_TYPER`.
+///
+/// Capped at 1020, above which the INTIDs are special.
+pub const fn lines(typer: u32) -> u32 {
+    let lines = 32 * ((typer & gicd::TYPER_ITLINES_MASK) + 1);
+    if lines > INTID_SPECIAL {
+        INTID_SPECIAL
+    } else {
+        lines
+    }
+}
+
+/// Returns the INTID acknowledged by an `IAR` value, `None` for the
+/// special INTIDs, which mean there was nothing to take.
+///
+/// # Arguments
+/// * `iar` - Value read from the acknowledge register
+/// * `mask` - Width of the INTID field
+pub const fn acked_intid(iar: u32, mask: u32) -> Option<u32> {
+    let intid = iar & mask;
+    if intid >= INTID_SPECIAL && intid <= INTID_SPURIOUS {
+        None
+    } else {
+        Some(intid)
+    }
+}
+
+/// GIC architecture version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V2,
+    V3,
+    /// GICv3 with direct virtual LPI injection, driven as a GICv3.
+    V4,
+}
+
+impl Version {
+    /// Returns the version's name.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::V2 => "GICv2",
+            Self::V3 => "GICv3",
+            Self::V4 => "GICv4",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Offset of `GICD_PIDR2` in a GICv2 distributor.
+pub const GICD_PIDR2_V2: usize = 0x0fe8;
+
+/// Offset of `GICD_PIDR2` in a GICv3 distributor.
+pub const GICD_PIDR2_V3: usize = 0xffe8;
+
+/// Decode the architecture version from a `PIDR2` value.
+///
+/// `ArchRev` is bits[7:4]; the rest of the register is implementation
+/// defined.
+pub const fn version_from_pidr2(pidr2: u32) -> Option<Version> {
+    match (pidr2 >> 4) & 0xf {
+        2 => Some(Version::V2),
+        3 => Some(Version::V3),
+        4 => Some(Version::V4),
+        _ => None,
+    }
+}
+
+/// Device tree `compatible` strings and the version they name.
+const COMPATIBLE: &[(&str, Version)] = &[
+    ("arm,gic-v3", Version::V3),
+    ("arm,gic-400", Version::V2),
+    ("arm,cortex-a15-gic", Version::V2),
+    ("arm,cortex-a9-gic", Version::V2),
+];
+
+/// Find the version of the first enabled interrupt controller in the
+/// device tree.
+pub fn version_from_fdt(fdt: &Fdt) -> Option<Version> {
+    COMPATIBLE.iter().find_map(|&(compatible, version)| {
+        fdt.find_compatible(compatible)
+            .any(|n| n.is_enabled())
+            .then_some(version)
+    })
+}
+
+/// Probe the distributor for its version.
+///
+/// The GICv2 ID register is read first: it lies inside the 4KB GICv2
+/// distributor and inside the 64KB GICv3 one, while the GICv3 offset is
+/// past the end of a GICv2 distributor, where a read may abort.
+///
+/// # Arguments
+/// * `read` - Reads the distributor register at an offset
+pub fn probe_version(read: impl Fn(usize) -> u32) -> Option<Version> {
+    match version_from_pidr2(read(GICD_PIDR2_V2)) {
+        Some(Version::V2) => Some(Version::V2),
+        _ => version_from_pidr2(read(GICD_PIDR2_V3)).filter(|v| *v != Version::V2),
+    }
+}
+
+/// A window of device registers in the kernel's device mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Regs(u64);
+
+impl Regs {
+    /// Wrap the registers at virtual address `base`.
+    ///
+    /// # Safety
+    /// `base` must map the device's registers for as long as the window
+    /// is used; every access through it is a volatile access there.
+    pub const unsafe fn new(base: u64) -> Self {
+        Self(base)
+    }
+
+    /// Returns the window `offset` bytes further on.
+    pub const fn offset(&self, offset: u64) -> Self {
+        Self(self.0 + offset)
+    }
+
+    /// Read the 32-bit register at `offset`.
+    pub fn read(&self, offset: usize) -> u32 {
+        // Safety: guaranteed by the caller of `new`
+        unsafe { core::ptr::read_volatile((self.0 as usize + offset) as *const u32) }
+    }
+
+    /// Write the 32-bit register at `offset`.
+    pub fn write(&self, offset: usize, value: u32) {
+        // Safety: as for `read`
+        unsafe { core::ptr::write_volatile((self.0 as usize + offset) as *mut u32, value) }
+    }
+
+    /// Read the 64-bit register at `offset`.
+    pub fn read64(&self, offset: usize) -> u64 {
+        // Safety: as for `read`
+        unsafe { core::ptr::read_volatile((self.0 as usize + offset) as *const u64) }
+    }
+
+    /// Write the 64-bit register at `offset`.
+    pub fn write64(&self, offset: usize, value: u64) {
+        // Safety: as for `read`
+        unsafe { core::ptr::write_volatile((self.0 as usize + offset) as *mut u64, value) }
+    }
+
+    /// Write the byte register at `offset`, such as an interrupt priority.
+    pub fn write8(&self, offset: usize, value: u8) {
+        // Safety: as for `read`
+        unsafe { core::ptr::write_volatile((self.0 as usize + offset) as *mut u8, value) }
+    }
+}
+
+/// An interrupt controller driver.
+pub trait GicDriver: Sync {
+    /// Set up the distributor and the running CPU's interface, with every
+    /// interrupt disabled at [`DEFAULT_PRIORITY`].
+    fn init(&self) -> Result<(), &'static str>;
+
+    /// Let `intid` be signalled to the CPU.
+    fn enable_irq(&self, intid: u32);
+
+    /// Stop `intid` from being signalled.
+    fn disable_irq(&self, intid: u32);
+
+    /// Set the priority of `intid`; lower values are more urgent.
+    fn set_priority(&self, intid: u32, priority: u8);
+
+    /// Acknowledge the highest priority pending interrupt.
+    ///
+    /// # Returns
+    /// Its INTID, or `None` if nothing was pending
+    fn ack(&self) -> Option<u32>;
+
+    /// Signal the end of handling `intid`, as returned by [`ack`].
+    ///
+    /// [`ack`]: GicDriver::ack
+    fn eoi(&self, intid: u32);
+}
+
+/// Take and handle every pending interrupt.
+///
+/// Each acknowledged INTID goes to `handle` and is then ended. An INTID
+/// `handle` does not know is disabled, so it cannot keep firing.
+///
+/// # Arguments
+/// * `gic` - The active driver
+/// * `handle` - Serves an interrupt, false if nothing handles the INTID
+///
+/// # Returns
+/// The number of interrupts taken
+pub fn dispatch(gic: &dyn GicDriver, mut handle: impl FnMut(u32) -> bool) -> usize {
+    let mut taken = 0;
+    while let Some(intid) = gic.ack() {
+        if !handle(intid) {
+            gic.disable_irq(intid);
+        }
+        gic.eoi(intid);
+        taken += 1;
+    }
+    taken
+}
+
+/// Driver chosen by [`init`], 0 before.
+#[cfg(target_os = "none")]
+static ACTIVE: AtomicU8 = AtomicU8::new(0);
+
+/// Returns the interrupt controller driver, once [`init`] has chosen one.
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn driver() -> Option<&'static dyn GicDriver> {
+    match ACTIVE.load(Ordering::Acquire) {
+        2 => Some(&v2::GIC),
+        3 => Some(&v3::GIC),
+        _ => None,
+    }
+}
+
+/// Pick the driver for the GIC at hand and set it up.
+#[cfg(target_os = "none")]
+fn init() -> Result<(), &'static str> {
+    use crate::arch::{address, serial};
+    use core::fmt::Write;
+
+    let found = crate::arch::boot::boot_fdt()
+        .as_ref()
+        .and_then(version_from_fdt);
+    let (version, source) = match found {
+        Some(version) => (version, "device tree"),
+        None => {
+            // Safety: the boot device mapping covers the GIC
+            let gicd =
+                unsafe { Regs::new(address::translation::phys_to_virt(address::virt::GIC_BASE)) };
+            let version = probe_version(|offset| gicd.read(offset)).ok_or("no GIC found")?;
+            (version, "GICD_PIDR2")
+        }
+    };
+
+    let (driver, id, interface): (&'static dyn GicDriver, u8, &str) = match version {
+        Version::V2 => (&v2::GIC, 2, "memory mapped"),
+        Version::V3 | Version::V4 => (&v3::GIC, 3, "system register"),
+    };
+    driver.init()?;
+    ACTIVE.store(id, Ordering::Release);
+    let _ = writeln!(
+        serial::Writer,
+        "GIC: {} from {}, {} CPU interface",
+        version,
+        source,
+        interface
+    );
+    Ok(())
+}
+
+initcall!(Device, "gic", init, optional);
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_distributor_offsets() {
+        // From the GICv2 and GICv3 architecture specifications
+        assert_eq!(gicd::CTLR, 0x000);
+        assert_eq!(gicd::TYPER, 0x004);
+        assert_eq!(gicd::IGROUPR, 0x080);
+        assert_eq!(gicd::ISENABLER, 0x100);
+        assert_eq!(gicd::ICENABLER, 0x180);
+        assert_eq!(gicd::IPRIORITYR, 0x400);
+        assert_eq!(GICD_PIDR2_V2, 0xfe8);
+        assert_eq!(GICD_PIDR2_V3, 0xffe8);
+    }
+
+    #[test]
+    fn test_bit_reg() {
+        assert_eq!(bit_reg(gicd::ISENABLER, 0), (0x100, 1));
+        assert_eq!(bit_reg(gicd::ISENABLER, 31), (0x100, 1 << 31));
+        // The UART's SPI 1
+        assert_eq!(bit_reg(gicd::ISENABLER, 33), (0x104, 1 << 1));
+        assert_eq!(bit_reg(gicd::ICENABLER, 1019), (0x1fc, 1 << 27));
+        assert_eq!(priority_reg(gicd::IPRIORITYR, 27), 0x41b);
+    }
+
+    #[test]
+    fn test_lines() {
+        // QEMU virt: 288 INTIDs
+        assert_eq!(lines(8), 288);
+        assert_eq!(lines(0), 32);
+        assert_eq!(lines(0x1f), INTID_SPECIAL);
+        // Only the low five bits count
+        assert_eq!(lines(0xffff_ffe0), 32);
+    }
+
+    #[test]
+    fn test_acked_intid() {
+        assert_eq!(acked_intid(27, 0x3ff), Some(27));
+        assert_eq!(acked_intid(1023, 0x3ff), None);
+        assert_eq!(acked_intid(1020, 0xff_ffff), None);
+        assert_eq!(acked_intid(1019, 0xff_ffff), Some(1019));
+        // GICv2 puts the source CPU of an SGI above the INTID
+        assert_eq!(acked_intid(0x1c01, 0x3ff), Some(1));
+        // LPIs lie above the special range
+        assert_eq!(acked_intid(8192, 0xff_ffff), Some(8192));
+    }
+
+    #[test]
+    fn test_version_from_pidr2() {
+        // QEMU's GICv2, GICv3 and GICv4 distributors
+        assert_eq!(version_from_pidr2(0x2b), Some(Version::V2));
+        assert_eq!(version_from_pidr2(0x3b), Some(Version::V3));
+        assert_eq!(version_from_pidr2(0x4b), Some(Version::V4));
+        // Nothing there, or a GICv1
+        assert_eq!(version_from_pidr2(0), None);
+        assert_eq!(version_from_pidr2(0x1b), None);
+        // Implementation defined bits are ignored
+        assert_eq!(version_from_pidr2(0xffff_ff3f), Some(Version::V3));
+    }
+
+    #[test]
+    fn test_probe_version() {
+        let gicv2 = |offset| match offset {
+            GICD_PIDR2_V2 => 0x2b,
+            _ => panic!("read past a GICv2 distributor"),
+        };
+        assert_eq!(probe_version(gicv2), Some(Version::V2));
+
+        let gicv3 = |offset| match offset {
+            GICD_PIDR2_V3 => 0x3b,
+            _ => 0,
+        };
+        assert_eq!(probe_version(gicv3), Some(Version::V3));
+
+        // A GICv1, and a GICv2 ID at the GICv3 offset, are not believed
+        assert_eq!(probe_version(|_| 0x1b), None);
+        let misplaced = |offset| if offset == GICD_PIDR2_V3 { 0x2b } else { 0 };
+        assert_eq!(probe_version(misplaced), None);
+    }
+
+    /// A GIC with a queue of pending INTIDs.
+    #[derive(Default)]
+    struct MockGic {
+        pending: RefCell<VecDeque<u32>>,
+        enabled: RefCell<Vec<u32>>,
+        ended: RefCell<Vec<u32>>,
+    }
+
+    impl GicDriver for MockGic {
+        fn init(&self) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn enable_irq(&self, intid: u32) {
+            self.enabled.borrow_mut().push(intid);
+        }
+
+        fn disable_irq(&self, intid: u32) {
+            self.enabled.borrow_mut().retain(|&i| i != intid);
+        }
+
+        fn set_priority(&self, _intid: u32, _priority: u8) {}
+
+        fn ack(&self) -> Option<u32> {
+            let intid = self.pending.borrow_mut().pop_front();
+            intid.filter(|i| self.enabled.borrow().contains(i))
+        }
+
+        fn eoi(&self, intid: u32) {
+            self.ended.borrow_mut().push(intid);
+        }
+    }
+
+    // Safety: only ever used on the test's thread
+    unsafe impl Sync for MockGic {}
+
+    #[test]
+    fn test_dispatch() {
+        let gic = MockGic::default();
+        gic.enable_irq(33);
+        gic.enable_irq(40);
+        gic.pending.borrow_mut().extend([33, 40, 33]);
+
+        let mut served = Vec::new();
+        let taken = dispatch(&gic, |intid| {
+            served.push(intid);
+            intid == 33
+        });
+        assert_eq!(taken, 3);
+        assert_eq!(served, [33, 40, 33]);
+        // Every acknowledged interrupt is ended, the unhandled one disabled
+        assert_eq!(*gic.ended.borrow(), [33, 40, 33]);
+        assert_eq!(*gic.enabled.borrow(), [33]);
+
+        // Nothing pending, nothing taken
+        assert_eq!(dispatch(&gic, |_| true), 0);
+    }
+
+    #[test]
+    fn test_version_from_fdt() {
+        let fdt = Fdt::new(include_bytes!("../../../fdt/test.dtb")).unwrap();
+        assert_eq!(version_from_fdt(&fdt), Some(Version::V2));
+        assert_eq!(Version::V2.to_string(), "GICv2");
+    }
+}
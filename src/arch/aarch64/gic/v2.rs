@@ -0,0 +1,122 @@
+//! GICv2: memory mapped distributor and CPU interface.
+
+use super::{DEFAULT_PRIORITY, GicDriver, PRIORITY_MASK_ALL, Regs, bit_reg, gicd};
+use crate::arch::address::{translation::phys_to_virt, virt};
+
+/// GICv2 distributor registers not shared with GICv3.
+pub mod gicd_v2 {
+    /// Interrupt processor targets, one byte per INTID.
+    pub const ITARGETSR: usize = 0x0800;
+    /// `CTLR.Enable`: forward interrupts to the CPU interfaces.
+    pub const CTLR_ENABLE: u32 = 1 << 0;
+}
+
+/// GICv2 CPU interface register offsets.
+pub mod gicc {
+    /// CPU interface control register.
+    pub const CTLR: usize = 0x0000;
+    /// Priority mask register.
+    pub const PMR: usize = 0x0004;
+    /// Binary point register.
+    pub const BPR: usize = 0x0008;
+    /// Interrupt acknowledge register.
+    pub const IAR: usize = 0x000c;
+    /// End of interrupt register.
+    pub const EOIR: usize = 0x0010;
+    /// `CTLR.EnableGrp0`: signal interrupts to the CPU.
+    pub const CTLR_ENABLE: u32 = 1 << 0;
+    /// Width of the INTID field of `IAR`; bits[12:10] hold the source CPU
+    /// of an SGI.
+    pub const IAR_INTID_MASK: u32 = 0x3ff;
+}
+
+/// `ITARGETSR` byte routing an SPI to CPU interface 0.
+pub const TARGET_CPU0: u8 = 1 << 0;
+
+/// The GICv2 on QEMU virt.
+// Safety: the boot device mapping covers the GIC window
+pub static GIC: GicV2 = unsafe {
+    GicV2::new(
+        Regs::new(phys_to_virt(virt::GIC_BASE)),
+        Regs::new(phys_to_virt(virt::GICC_BASE)),
+    )
+};
+
+/// GICv2 driver.
+pub struct GicV2 {
+    gicd: Regs,
+    gicc: Regs,
+}
+
+impl GicV2 {
+    /// Creates a driver for the distributor and CPU interface at the given
+    /// windows.
+    pub const fn new(gicd: Regs, gicc: Regs) -> Self {
+        Self { gicd, gicc }
+    }
+}
+
+impl GicDriver for GicV2 {
+    fn init(&self) -> Result<(), &'static str> {
+        self.gicd.write(gicd::CTLR, 0);
+        let lines = super::lines(self.gicd.read(gicd::TYPER));
+        for intid in (0..lines).step_by(32) {
+            let (offset, _) = bit_reg(gicd::ICENABLER, intid);
+            self.gicd.write(offset, u32::MAX);
+        }
+        for intid in 0..lines {
+            self.set_priority(intid, DEFAULT_PRIORITY);
+            // Banked, read only, for SGIs and PPIs
+            if intid >= super::SPI_BASE {
+                self.gicd
+                    .write8(gicd_v2::ITARGETSR + intid as usize, TARGET_CPU0);
+            }
+        }
+        self.gicd.write(gicd::CTLR, gicd_v2::CTLR_ENABLE);
+
+        self.gicc.write(gicc::PMR, PRIORITY_MASK_ALL as u32);
+        self.gicc.write(gicc::BPR, 0);
+        self.gicc.write(gicc::CTLR, gicc::CTLR_ENABLE);
+        Ok(())
+    }
+
+    fn enable_irq(&self, intid: u32) {
+        let (offset, mask) = bit_reg(gicd::ISENABLER, intid);
+        self.gicd.write(offset, mask);
+    }
+
+    fn disable_irq(&self, intid: u32) {
+        let (offset, mask) = bit_reg(gicd::ICENABLER, intid);
+        self.gicd.write(offset, mask);
+    }
+
+    fn set_priority(&self, intid: u32, priority: u8) {
+        self.gicd
+            .write8(super::priority_reg(gicd::IPRIORITYR, intid), priority);
+    }
+
+    fn ack(&self) -> Option<u32> {
+        super::acked_intid(self.gicc.read(gicc::IAR), gicc::IAR_INTID_MASK)
+    }
+
+    fn eoi(&self, intid: u32) {
+        self.gicc.write(gicc::EOIR, intid);
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_offsets() {
+        assert_eq!(gicd_v2::ITARGETSR, 0x800);
+        assert_eq!(gicc::CTLR, 0x00);
+        assert_eq!(gicc::PMR, 0x04);
+        assert_eq!(gicc::BPR, 0x08);
+        assert_eq!(gicc::IAR, 0x0c);
+        assert_eq!(gicc::EOIR, 0x10);
+        // QEMU virt puts the CPU interface right after the distributor
+        assert_eq!(virt::GICC_BASE, 0x0801_0000);
+    }
+}
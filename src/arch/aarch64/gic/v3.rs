@@ -0,0 +1,421 @@
+//! GICv3: memory mapped distributor and redistributors, system register
+//! CPU interface.
+//!
+//! Each CPU has its own redistributor frame, found by walking the
+//! redistributor window until a frame's `GICR_TYPER` affinity matches the
+//! CPU's MPIDR. SGIs and PPIs are configured there instead of in the
+//! distributor.
+
+use super::{DEFAULT_PRIORITY, GicDriver, PRIORITY_MASK_ALL, Regs, SPI_BASE, bit_reg, gicd};
+use crate::arch::address::{translation::phys_to_virt, virt};
+use crate::arch::reg::{read_sysreg, write_sysreg};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// GICv3 distributor registers and bits not shared with GICv2.
+pub mod gicd_v3 {
+    /// Interrupt routing registers, 64 bits per SPI.
+    pub const IROUTER: usize = 0x6000;
+    /// `CTLR.EnableGrp1NS`: forward non-secure group 1 interrupts.
+    pub const CTLR_ENABLE_GRP1_NS: u32 = 1 << 1;
+    /// `CTLR.ARE_NS`: affinity routing for the non-secure state.
+    pub const CTLR_ARE_NS: u32 = 1 << 4;
+    /// `CTLR.RWP`: a register write is still taking effect.
+    pub const CTLR_RWP: u32 = 1 << 31;
+}
+
+/// Redistributor register offsets and bits.
+pub mod gicr {
+    /// Redistributor type register (64 bits), in the RD frame.
+    pub const TYPER: usize = 0x0008;
+    /// Power management register, in the RD frame.
+    pub const WAKER: usize = 0x0014;
+    /// Offset of the SGI frame from the RD frame.
+    pub const SGI_FRAME: u64 = 0x1_0000;
+    /// Interrupt group register for SGIs and PPIs, in the SGI frame.
+    pub const IGROUPR0: usize = 0x0080;
+    /// Set-enable register for SGIs and PPIs, in the SGI frame.
+    pub const ISENABLER0: usize = 0x0100;
+    /// Clear-enable register for SGIs and PPIs, in the SGI frame.
+    pub const ICENABLER0: usize = 0x0180;
+    /// Priority registers for SGIs and PPIs, in the SGI frame.
+    pub const IPRIORITYR: usize = 0x0400;
+
+    /// `TYPER.VLPIS`: the redistributor has the two extra GICv4 frames.
+    pub const TYPER_VLPIS: u64 = 1 << 1;
+    /// `TYPER.Last`: this is the last redistributor in the window.
+    pub const TYPER_LAST: u64 = 1 << 4;
+    /// Shift of `TYPER.Affinity_Value`, bits[63:32].
+    pub const TYPER_AFFINITY_SHIFT: u32 = 32;
+    /// `WAKER.ProcessorSleep`: the CPU interface is asleep.
+    pub const WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+    /// `WAKER.ChildrenAsleep`: the redistributor has not woken up yet.
+    pub const WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+    /// Size of a GICv3 redistributor: RD and SGI frames, 64KB each.
+    pub const FRAME_SIZE_V3: u64 = 0x2_0000;
+    /// Size of a GICv4 redistributor: two more frames for virtual LPIs.
+    pub const FRAME_SIZE_V4: u64 = 0x4_0000;
+}
+
+/// `ICC_SRE_EL1.SRE`: use the system register CPU interface.
+pub const ICC_SRE_SRE: u64 = 1 << 0;
+
+/// `ICC_IGRPEN1_EL1.Enable`: signal group 1 interrupts.
+pub const ICC_IGRPEN1_ENABLE: u64 = 1 << 0;
+
+/// Width of the INTID field of `ICC_IAR1_EL1`.
+pub const IAR_INTID_MASK: u32 = 0xff_ffff;
+
+/// Polls of a busy bit before giving up on the GIC.
+const POLL_LIMIT: u32 = 1_000_000;
+
+/// Pack the affinity fields of an MPIDR the way `GICR_TYPER` reports
+/// them: Aff3.Aff2.Aff1.Aff0.
+pub const fn typer_affinity(mpidr: u64) -> u32 {
+    (((mpidr >> 8) & 0xff00_0000) | (mpidr & 0xff_ffff)) as u32
+}
+
+/// Pack the affinity fields of an MPIDR the way `GICD_IROUTER` takes
+/// them: Aff3 in bits[39:32], Aff2 to Aff0 in bits[23:0], routing mode 0.
+pub const fn irouter_affinity(mpidr: u64) -> u64 {
+    mpidr & 0xff_00ff_ffff
+}
+
+/// Size of the redistributor whose `GICR_TYPER` is `typer`.
+pub const fn frame_stride(typer: u64) -> u64 {
+    if typer & gicr::TYPER_VLPIS != 0 {
+        gicr::FRAME_SIZE_V4
+    } else {
+        gicr::FRAME_SIZE_V3
+    }
+}
+
+/// Find the redistributor of the CPU with affinity `mpidr`.
+///
+/// Walks the frames from `base`, stopping after the one marked last or at
+/// the end of the window.
+///
+/// # Arguments
+/// * `base` - Start of the redistributor window
+/// * `size` - Size of the window
+/// * `mpidr` - MPIDR of the CPU
+/// * `read_typer` - Reads `GICR_TYPER` of the frame at an address
+///
+/// # Returns
+/// The address of the CPU's RD frame
+pub fn find_redistributor(
+    base: u64,
+    size: u64,
+    mpidr: u64,
+    read_typer: impl Fn(u64) -> u64,
+) -> Option<u64> {
+    let want = typer_affinity(mpidr);
+    let end = base.checked_add(size)?;
+    let mut frame = base;
+    while frame < end {
+        let typer = read_typer(frame);
+        if (typer >> gicr::TYPER_AFFINITY_SHIFT) as u32 == want {
+            return Some(frame);
+        }
+        if typer & gicr::TYPER_LAST != 0 {
+            break;
+        }
+        frame += frame_stride(typer);
+    }
+    None
+}
+
+/// The GICv3 on QEMU virt.
+// Safety: the boot device mapping covers the GIC windows
+pub static GIC: GicV3 = unsafe {
+    GicV3::new(
+        Regs::new(phys_to_virt(virt::GIC_BASE)),
+        phys_to_virt(virt::GICR_BASE),
+        virt::GICR_SIZE,
+    )
+};
+
+/// GICv3 driver.
+pub struct GicV3 {
+    gicd: Regs,
+    /// Virtual base of the redistributor window.
+    gicr_base: u64,
+    /// Size of the redistributor window.
+    gicr_size: u64,
+    /// Virtual address of the boot CPU's RD frame, 0 before `init`.
+    rd: AtomicU64,
+}
+
+impl GicV3 {
+    /// Creates a driver for the distributor and the redistributor window
+    /// at the given virtual addresses.
+    ///
+    /// # Safety
+    /// `gicr_base` must map `gicr_size` bytes of redistributor frames.
+    pub const unsafe fn new(gicd: Regs, gicr_base: u64, gicr_size: u64) -> Self {
+        Self {
+            gicd,
+            gicr_base,
+            gicr_size,
+            rd: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the boot CPU's SGI frame, once `init` has found it.
+    fn sgi_frame(&self) -> Option<Regs> {
+        match self.rd.load(Ordering::Acquire) {
+            0 => None,
+            // Safety: `init` found the frame inside the window
+            rd => Some(unsafe { Regs::new(rd) }.offset(gicr::SGI_FRAME)),
+        }
+    }
+
+    /// Wait for a distributor write to take effect.
+    fn wait_rwp(&self) -> Result<(), &'static str> {
+        poll(|| self.gicd.read(gicd::CTLR) & gicd_v3::CTLR_RWP == 0)
+            .ok_or("GIC distributor write did not complete")
+    }
+
+    /// Set up the distributor with every SPI routed to `mpidr`.
+    fn init_distributor(&self, mpidr: u64) -> Result<(), &'static str> {
+        self.gicd.write(gicd::CTLR, 0);
+        self.wait_rwp()?;
+
+        let lines = super::lines(self.gicd.read(gicd::TYPER));
+        for intid in (SPI_BASE..lines).step_by(32) {
+            let (offset, _) = bit_reg(gicd::ICENABLER, intid);
+            self.gicd.write(offset, u32::MAX);
+            let (offset, _) = bit_reg(gicd::IGROUPR, intid);
+            self.gicd.write(offset, u32::MAX);
+        }
+        for intid in SPI_BASE..lines {
+            self.set_priority(intid, DEFAULT_PRIORITY);
+            self.gicd.write64(
+                gicd_v3::IROUTER + 8 * intid as usize,
+                irouter_affinity(mpidr),
+            );
+        }
+
+        self.gicd.write(
+            gicd::CTLR,
+            gicd_v3::CTLR_ARE_NS | gicd_v3::CTLR_ENABLE_GRP1_NS,
+        );
+        self.wait_rwp()
+    }
+
+    /// Wake the redistributor of the running CPU and set up its SGIs and
+    /// PPIs.
+    fn init_redistributor(&self, mpidr: u64) -> Result<(), &'static str> {
+        let rd = find_redistributor(self.gicr_base, self.gicr_size, mpidr, |frame| {
+            // Safety: the frame lies inside the redistributor window
+            unsafe { Regs::new(frame) }.read64(gicr::TYPER)
+        })
+        .ok_or("no GIC redistributor for this CPU")?;
+        // Safety: as above
+        let rd_frame = unsafe { Regs::new(rd) };
+
+        let waker = rd_frame.read(gicr::WAKER);
+        rd_frame.write(gicr::WAKER, waker & !gicr::WAKER_PROCESSOR_SLEEP);
+        poll(|| rd_frame.read(gicr::WAKER) & gicr::WAKER_CHILDREN_ASLEEP == 0)
+            .ok_or("GIC redistributor did not wake up")?;
+        self.rd.store(rd, Ordering::Release);
+
+        let sgi = rd_frame.offset(gicr::SGI_FRAME);
+        sgi.write(gicr::ICENABLER0, u32::MAX);
+        sgi.write(gicr::IGROUPR0, u32::MAX);
+        for intid in 0..SPI_BASE {
+            self.set_priority(intid, DEFAULT_PRIORITY);
+        }
+        Ok(())
+    }
+
+    /// Switch the running CPU to the system register interface and let
+    /// group 1 interrupts of any priority through.
+    fn init_cpu_interface(&self) -> Result<(), &'static str> {
+        let sre = read_sysreg!(icc_sre_el1);
+        // Safety: enabling the system register interface only changes how
+        // this CPU talks to the GIC
+        unsafe { write_sysreg!(icc_sre_el1, sre | ICC_SRE_SRE) };
+        crate::arch::barrier::isb();
+        if read_sysreg!(icc_sre_el1) & ICC_SRE_SRE == 0 {
+            return Err("GIC system register interface is disabled");
+        }
+
+        // Safety: interrupts stay masked in DAIF until the kernel unmasks
+        // them
+        unsafe {
+            write_sysreg!(icc_pmr_el1, PRIORITY_MASK_ALL);
+            write_sysreg!(icc_bpr1_el1, 0);
+            write_sysreg!(icc_igrpen1_el1, ICC_IGRPEN1_ENABLE);
+        }
+        crate::arch::barrier::isb();
+        Ok(())
+    }
+}
+
+/// Poll `done` up to [`POLL_LIMIT`] times.
+fn poll(mut done: impl FnMut() -> bool) -> Option<()> {
+    (0..POLL_LIMIT).any(|_| done()).then_some(())
+}
+
+/// Read `ICC_IAR1_EL1`, acknowledging the interrupt it returns.
+///
+/// Not [`read_sysreg!`]: the read has a side effect and must stay ordered
+/// with the handler's memory accesses.
+fn read_iar1() -> u64 {
+    #[cfg(target_os = "none")]
+    {
+        let iar: u64;
+        // Safety: acknowledging an interrupt is what the caller asked for
+        unsafe { core::arch::asm!("mrs {}, icc_iar1_el1", out(reg) iar, options(nostack)) };
+        iar
+    }
+    #[cfg(not(target_os = "none"))]
+    crate::arch::reg::mock::read("icc_iar1_el1")
+}
+
+impl GicDriver for GicV3 {
+    fn init(&self) -> Result<(), &'static str> {
+        let mpidr = crate::arch::reg::mpidr_el1();
+        self.init_distributor(mpidr)?;
+        self.init_redistributor(mpidr)?;
+        self.init_cpu_interface()
+    }
+
+    fn enable_irq(&self, intid: u32) {
+        if intid >= SPI_BASE {
+            let (offset, mask) = bit_reg(gicd::ISENABLER, intid);
+            self.gicd.write(offset, mask);
+        } else if let Some(sgi) = self.sgi_frame() {
+            sgi.write(gicr::ISENABLER0, 1 << intid);
+        }
+    }
+
+    fn disable_irq(&self, intid: u32) {
+        if intid >= SPI_BASE {
+            let (offset, mask) = bit_reg(gicd::ICENABLER, intid);
+            self.gicd.write(offset, mask);
+        } else if let Some(sgi) = self.sgi_frame() {
+            sgi.write(gicr::ICENABLER0, 1 << intid);
+        }
+    }
+
+    fn set_priority(&self, intid: u32, priority: u8) {
+        if intid >= SPI_BASE {
+            self.gicd
+                .write8(super::priority_reg(gicd::IPRIORITYR, intid), priority);
+        } else if let Some(sgi) = self.sgi_frame() {
+            sgi.write8(super::priority_reg(gicr::IPRIORITYR, intid), priority);
+        }
+    }
+
+    fn ack(&self) -> Option<u32> {
+        super::acked_intid(read_iar1() as u32, IAR_INTID_MASK)
+    }
+
+    fn eoi(&self, intid: u32) {
+        // Safety: ends the interrupt the caller acknowledged
+        unsafe { write_sysreg!(icc_eoir1_el1, intid) };
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::arch::reg::mock;
+
+    #[test]
+    fn test_register_offsets() {
+        assert_eq!(gicd_v3::IROUTER, 0x6000);
+        assert_eq!(gicr::TYPER, 0x08);
+        assert_eq!(gicr::WAKER, 0x14);
+        assert_eq!(gicr::SGI_FRAME, 0x1_0000);
+        assert_eq!(gicr::IGROUPR0, 0x080);
+        assert_eq!(gicr::ISENABLER0, 0x100);
+        assert_eq!(gicr::ICENABLER0, 0x180);
+        assert_eq!(gicr::IPRIORITYR, 0x400);
+        // QEMU virt's redistributor window, 123 GICv3 frames
+        assert_eq!(virt::GICR_BASE, 0x080a_0000);
+        assert_eq!(virt::GICR_SIZE / gicr::FRAME_SIZE_V3, 123);
+    }
+
+    #[test]
+    fn test_affinity_packing() {
+        // Aff3 = 0x12, Aff2 = 0x34, Aff1 = 0x56, Aff0 = 0x78, with the
+        // U and MT bits set
+        let mpidr = 0x12_c134_5678;
+        assert_eq!(typer_affinity(mpidr), 0x1234_5678);
+        assert_eq!(irouter_affinity(mpidr), 0x12_0034_5678);
+        // QEMU's CPU 0 reports MPIDR 0x8000_0000
+        assert_eq!(typer_affinity(0x8000_0000), 0);
+        assert_eq!(irouter_affinity(0x8000_0000), 0);
+    }
+
+    /// `GICR_TYPER` of a frame for the CPU with affinity `aff`.
+    fn typer(aff: u64, flags: u64) -> u64 {
+        aff << gicr::TYPER_AFFINITY_SHIFT | flags
+    }
+
+    #[test]
+    fn test_find_redistributor() {
+        let base = 0x080a_0000;
+        // Four CPUs, Aff0 0 to 3, the last frame marked
+        let frames = |frame: u64| {
+            let index = (frame - base) / gicr::FRAME_SIZE_V3;
+            typer(index, if index == 3 { gicr::TYPER_LAST } else { 0 })
+        };
+        let size = virt::GICR_SIZE;
+        assert_eq!(
+            find_redistributor(base, size, 0x8000_0000, frames),
+            Some(base)
+        );
+        assert_eq!(
+            find_redistributor(base, size, 0x8000_0002, frames),
+            Some(base + 2 * gicr::FRAME_SIZE_V3)
+        );
+        // Past the last frame nothing is read
+        assert_eq!(find_redistributor(base, size, 0x8000_0004, frames), None);
+
+        // A cluster in Aff1 is matched on all fields
+        let cluster = |frame: u64| typer(0x100 | ((frame - base) / gicr::FRAME_SIZE_V3), 0);
+        assert_eq!(
+            find_redistributor(base, 4 * gicr::FRAME_SIZE_V3, 0x101, cluster),
+            Some(base + gicr::FRAME_SIZE_V3)
+        );
+        // The walk stops at the end of the window without a last frame
+        assert_eq!(
+            find_redistributor(base, 4 * gicr::FRAME_SIZE_V3, 0x104, cluster),
+            None
+        );
+    }
+
+    #[test]
+    fn test_find_redistributor_gicv4_stride() {
+        let base = 0x080a_0000;
+        let frames = |frame: u64| {
+            assert_eq!((frame - base) % gicr::FRAME_SIZE_V4, 0, "misaligned walk");
+            typer((frame - base) / gicr::FRAME_SIZE_V4, gicr::TYPER_VLPIS)
+        };
+        assert_eq!(
+            find_redistributor(base, virt::GICR_SIZE, 3, frames),
+            Some(base + 3 * gicr::FRAME_SIZE_V4)
+        );
+        assert_eq!(frame_stride(gicr::TYPER_VLPIS), 0x4_0000);
+        assert_eq!(frame_stride(gicr::TYPER_LAST), 0x2_0000);
+    }
+
+    #[test]
+    fn test_cpu_interface_ack_eoi() {
+        // Safety: the CPU interface calls only touch the mock registers
+        let gic = unsafe { GicV3::new(Regs::new(0), 0, 0) };
+
+        // The timer's PPI
+        mock::set("icc_iar1_el1", 30);
+        assert_eq!(gic.ack(), Some(30));
+        gic.eoi(30);
+        assert_eq!(mock::read("icc_eoir1_el1"), 30);
+
+        mock::set("icc_iar1_el1", 1023);
+        assert_eq!(gic.ack(), None);
+    }
+}
@@ -0,0 +1,373 @@
+//! Idling and parking the CPU.
+//!
+//! Once boot is done, `kernel_init` hands CPU0 to [`idle_loop`], which
+//! sleeps between timer ticks instead of spinning, prints an optional
+//! heartbeat and powers off when a shutdown is requested. Fatal paths use
+//! [`park_forever`] instead, which never wakes up to do anything.
+//!
+//! The timer interrupt is not routed through the GIC, so the tick that
+//! wakes the idle loop is the generic timer's event stream, which wakes
+//! WFE without an interrupt. [`wait_for_interrupt`] is the WFI
+//! counterpart for waiting on routed interrupts.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Command line option setting the heartbeat period in seconds.
+pub const HEARTBEAT_OPTION: &str = "heartbeat=";
+
+/// Rate the idle loop aims to wake up at.
+pub const TICK_HZ: u64 = 1000;
+
+/// Largest CNTKCTL_EL1.EVNTI value, a 4-bit field.
+const EVNTI_MAX: u64 = 15;
+
+/// Returns the heartbeat period requested by `cmdline`, in seconds.
+///
+/// The last `heartbeat=` option wins; the heartbeat is off by default and
+/// with `heartbeat=0`.
+pub fn heartbeat_period(cmdline: &str) -> Option<u64> {
+    cmdline
+        .split_ascii_whitespace()
+        .filter_map(|arg| arg.strip_prefix(HEARTBEAT_OPTION))
+        .filter_map(|secs| secs.parse::<u64>().ok())
+        .next_back()
+        .filter(|&secs| secs != 0)
+}
+
+/// Returns the CNTKCTL_EL1.EVNTI value for an event stream of about `hz`.
+///
+/// An event fires each time counter bit EVNTI flips from 0 to 1, that is
+/// every `2^(EVNTI + 1)` counter ticks. The longest period not above the
+/// requested one is picked, capped by the field width.
+///
+/// # Arguments
+/// * `freq` - Counter frequency in Hz
+/// * `hz` - Requested event rate
+pub const fn event_stream_index(freq: u64, hz: u64) -> u64 {
+    let period = match freq.checked_div(hz) {
+        Some(period) => period,
+        None => u64::MAX,
+    };
+    if period < 4 {
+        return 0;
+    }
+    let index = period.ilog2() as u64 - 1;
+    if index > EVNTI_MAX { EVNTI_MAX } else { index }
+}
+
+/// Decides when the next heartbeat line is due.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// Counter ticks between beats.
+    period: u64,
+    /// Counter value of the last beat, or of the start.
+    last: u64,
+}
+
+impl Heartbeat {
+    /// Schedule a beat every `secs` seconds, starting from `now`.
+    ///
+    /// # Arguments
+    /// * `secs` - Seconds between beats
+    /// * `hz` - Counter frequency in Hz
+    /// * `now` - Current counter value
+    ///
+    /// # Returns
+    /// `None` if the period works out to zero ticks.
+    pub const fn new(secs: u64, hz: u64, now: u64) -> Option<Self> {
+        let period = secs.saturating_mul(hz);
+        if period == 0 {
+            return None;
+        }
+        Some(Self { period, last: now })
+    }
+
+    /// Returns true if a beat is due at counter value `now`.
+    ///
+    /// Beats stay on the original schedule: a late poll does not push the
+    /// next beat back, and beats missed entirely are dropped rather than
+    /// printed in a burst.
+    pub fn poll(&mut self, now: u64) -> bool {
+        let elapsed = now.wrapping_sub(self.last);
+        if elapsed < self.period {
+            return false;
+        }
+        self.last = self.last.wrapping_add(elapsed - elapsed % self.period);
+        true
+    }
+}
+
+/// A one-shot shutdown request.
+///
+/// Any context may ask for a shutdown; the idle loop takes the request
+/// exactly once and carries it out.
+pub struct ShutdownFlag(AtomicU8);
+
+impl ShutdownFlag {
+    /// Nothing requested.
+    const IDLE: u8 = 0;
+    /// Requested, not yet acted on.
+    const REQUESTED: u8 = 1;
+    /// Taken by the idle loop.
+    const TAKEN: u8 = 2;
+
+    /// Create a flag with no request pending.
+    pub const fn new() -> Self {
+        Self(AtomicU8::new(Self::IDLE))
+    }
+
+    /// Ask for a shutdown.
+    ///
+    /// # Returns
+    /// True if this call made the request, false if one was already made.
+    pub fn request(&self) -> bool {
+        self.0
+            .compare_exchange(
+                Self::IDLE,
+                Self::REQUESTED,
+                Ordering::Release,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    /// Returns true once a shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::Acquire) != Self::IDLE
+    }
+
+    /// Claim a pending request.
+    ///
+    /// # Returns
+    /// True for exactly one caller after a request was made.
+    pub fn take(&self) -> bool {
+        self.0
+            .compare_exchange(
+                Self::REQUESTED,
+                Self::TAKEN,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+}
+
+/// Heartbeat bytes lost to a full TX FIFO.
+#[cfg(target_os = "none")]
+static HEARTBEAT_DROPS: crate::arch::serial::DropCounter = crate::arch::serial::DropCounter::new();
+
+/// Shutdown request handled by [`idle_loop`].
+static SHUTDOWN: ShutdownFlag = ShutdownFlag::new();
+
+/// Ask the idle loop to power the system off.
+///
+/// # Returns
+/// True if this call made the request, false if one was already made.
+#[allow(dead_code)]
+pub fn request_shutdown() -> bool {
+    SHUTDOWN.request()
+}
+
+/// Returns true once a shutdown has been requested.
+#[allow(dead_code)]
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.is_requested()
+}
+
+/// Sleep until an interrupt is pending.
+///
+/// IRQs are masked around the WFI so one arriving just before it still
+/// ends the wait instead of being taken and leaving the CPU asleep; the
+/// caller's IRQ state is then restored and a pending IRQ is taken if it
+/// was unmasked.
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn wait_for_interrupt() {
+    let flags = crate::arch::irq::disable_save();
+    unsafe {
+        // Safety: completes outstanding accesses, then sleeps until a
+        // wakeup event; no state is changed
+        core::arch::asm!("dsb sy", "wfi", options(nostack));
+    }
+    crate::arch::irq::restore(flags);
+}
+
+/// Stop this CPU for good.
+///
+/// IRQs are masked so nothing runs on it again; WFE keeps it from burning
+/// host CPU time under QEMU.
+pub fn park_forever() -> ! {
+    // Never restored
+    let _ = crate::arch::irq::disable_save();
+    loop {
+        #[cfg(target_os = "none")]
+        unsafe {
+            // Safety: waiting for an event has no side effects
+            core::arch::asm!("wfe", options(nomem, nostack));
+        }
+        #[cfg(not(target_os = "none"))]
+        core::hint::spin_loop();
+    }
+}
+
+/// Turn on the generic timer event stream at about [`TICK_HZ`].
+#[cfg(target_os = "none")]
+fn enable_tick_events() {
+    use crate::arch::timer;
+
+    // EVNTEN - BIT[2], EVNTDIR - BIT[3] clear (0 to 1), EVNTI - BIT[7:4]
+    let evnti = event_stream_index(timer::frequency(), TICK_HZ);
+    unsafe {
+        // Safety: only changes event generation; EL0 access bits are kept
+        core::arch::asm!(
+            "mrs {tmp}, cntkctl_el1",
+            "bic {tmp}, {tmp}, #0xfc",
+            "orr {tmp}, {tmp}, {val}",
+            "msr cntkctl_el1, {tmp}",
+            "isb",
+            tmp = out(reg) _,
+            val = in(reg) (evnti << 4) | (1 << 2),
+            options(nostack),
+        );
+    }
+}
+
+/// Idle CPU0 once boot is complete.
+///
+/// Wakes on every tick to print the heartbeat, if `heartbeat=` asked for
+/// one, and to power off through PSCI once [`request_shutdown`] was
+/// called. If power off fails the CPU is parked.
+///
+/// # Arguments
+/// * `cmdline` - Kernel command line, for `heartbeat=`
+#[cfg(target_os = "none")]
+pub fn idle_loop(cmdline: &str) -> ! {
+    use crate::arch::{psci, serial, timer};
+    use core::fmt::Write;
+
+    let hz = timer::frequency();
+    let start = timer::ticks();
+    let mut heartbeat = heartbeat_period(cmdline).and_then(|secs| Heartbeat::new(secs, hz, start));
+    enable_tick_events();
+
+    loop {
+        if SHUTDOWN.take() {
+            serial::write_str("Powering off\n");
+            if let Err(e) = psci::system_off() {
+                let _ = writeln!(serial::Writer, "Power off failed: {}", e);
+            }
+            park_forever();
+        }
+
+        let now = timer::ticks();
+        if heartbeat.as_mut().is_some_and(|beat| beat.poll(now)) {
+            // Skipped rather than waited for if the UART is busy
+            let uptime = timer::ticks_to_ms(now - start, hz) / 1000;
+            let _ = writeln!(
+                serial::TryWriter(&HEARTBEAT_DROPS),
+                "heartbeat: {}s",
+                uptime
+            );
+        }
+
+        unsafe {
+            // Safety: sleeps until the next event stream tick or other
+            // wakeup event; no state is changed
+            core::arch::asm!("wfe", options(nomem, nostack));
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    /// QEMU virt counter frequency.
+    const FREQ: u64 = 62_500_000;
+
+    #[test]
+    fn test_heartbeat_period_option() {
+        assert_eq!(heartbeat_period(""), None);
+        assert_eq!(heartbeat_period("console heartbeat=5"), Some(5));
+        assert_eq!(heartbeat_period("heartbeat=5 heartbeat=10"), Some(10));
+        assert_eq!(heartbeat_period("heartbeat=5 heartbeat=0"), None);
+        assert_eq!(heartbeat_period("heartbeat=x heartbeat="), None);
+    }
+
+    #[test]
+    fn test_heartbeat_schedule() {
+        let start = 1_000;
+        let mut beat = Heartbeat::new(2, FREQ, start).unwrap();
+
+        // Not due until two seconds of ticks have passed
+        assert!(!beat.poll(start));
+        assert!(!beat.poll(start + 2 * FREQ - 1));
+        assert!(beat.poll(start + 2 * FREQ));
+        assert!(!beat.poll(start + 2 * FREQ + 1));
+
+        // A late poll keeps the original schedule
+        assert!(beat.poll(start + 4 * FREQ + FREQ / 2));
+        assert!(beat.poll(start + 6 * FREQ));
+
+        // Missed beats are dropped, not replayed
+        assert!(beat.poll(start + 15 * FREQ));
+        assert!(!beat.poll(start + 15 * FREQ + 1));
+        assert!(beat.poll(start + 16 * FREQ));
+
+        assert_eq!(Heartbeat::new(0, FREQ, 0), None);
+        assert_eq!(Heartbeat::new(1, 0, 0), None);
+    }
+
+    #[test]
+    fn test_heartbeat_counter_wrap() {
+        let start = u64::MAX - FREQ / 2;
+        let mut beat = Heartbeat::new(1, FREQ, start).unwrap();
+        assert!(!beat.poll(FREQ / 2 - 2));
+        assert!(beat.poll(FREQ / 2));
+    }
+
+    #[test]
+    fn test_event_stream_index() {
+        // 62.5MHz wants 62500 ticks per event: 2^16 is over, 2^15 fits
+        assert_eq!(event_stream_index(FREQ, TICK_HZ), 14);
+        assert_eq!(event_stream_index(1_000_000, 1000), 8);
+        // Slow rates are capped by the 4-bit field
+        assert_eq!(event_stream_index(FREQ, 1), EVNTI_MAX);
+        assert_eq!(event_stream_index(FREQ, 0), EVNTI_MAX);
+        // Faster than the counter allows
+        assert_eq!(event_stream_index(1000, 1000), 0);
+    }
+
+    #[test]
+    fn test_shutdown_handshake() {
+        let flag = ShutdownFlag::new();
+        assert!(!flag.is_requested());
+        assert!(!flag.take());
+
+        assert!(flag.request());
+        assert!(flag.is_requested());
+        // A second request is reported as a duplicate
+        assert!(!flag.request());
+
+        // Taken exactly once, and stays requested afterwards
+        assert!(flag.take());
+        assert!(!flag.take());
+        assert!(flag.is_requested());
+        assert!(!flag.request());
+    }
+
+    #[test]
+    fn test_shutdown_taken_once_across_threads() {
+        let flag = ShutdownFlag::new();
+        assert!(flag.request());
+        let taken = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4).map(|_| s.spawn(|| flag.take())).collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .filter(|&t| t)
+                .count()
+        });
+        assert_eq!(taken, 1);
+    }
+}
@@ -0,0 +1,214 @@
+//! Local interrupt masking.
+//!
+//! IRQs are masked through the `I` bit of DAIF. Host test builds swap the
+//! system register for a per-thread mock so the save/restore logic can be
+//! exercised without hardware.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// DAIF `I` bit: IRQs masked when set.
+pub const DAIF_I: u64 = 1 << 7;
+
+/// DAIF state saved by [`disable_save`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[must_use = "the saved state must be passed to `restore`"]
+pub struct IrqFlags(u64);
+
+impl IrqFlags {
+    /// Returns true if IRQs were unmasked when the state was saved.
+    #[allow(dead_code)]
+    pub fn irqs_enabled(&self) -> bool {
+        self.0 & DAIF_I == 0
+    }
+}
+
+#[cfg(target_os = "none")]
+mod daif {
+    use core::arch::asm;
+
+    pub fn read() -> u64 {
+        let daif: u64;
+        // Safety: reading DAIF has no side effects
+        unsafe { asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack)) };
+        daif
+    }
+
+    pub fn mask_irq() {
+        // Safety: only masks IRQs on this CPU. Not `nomem`, so memory
+        // accesses are not moved out of the masked region.
+        unsafe { asm!("msr daifset, #2", options(nostack)) };
+    }
+
+    pub fn unmask_irq() {
+        // Safety: the IRQ vector is installed; not `nomem`, as for
+        // `mask_irq`
+        unsafe { asm!("msr daifclr, #2", options(nostack)) };
+    }
+
+    pub fn write(daif: u64) {
+        // Safety: restores a value previously read from DAIF
+        unsafe { asm!("msr daif, {}", in(reg) daif, options(nostack)) };
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+mod daif {
+    use core::cell::Cell;
+
+    std::thread_local! {
+        /// Mock DAIF, IRQs unmasked at start.
+        static DAIF: Cell<u64> = const { Cell::new(0) };
+    }
+
+    pub fn read() -> u64 {
+        DAIF.with(|d| d.get())
+    }
+
+    pub fn mask_irq() {
+        DAIF.with(|d| d.set(d.get() | super::DAIF_I));
+    }
+
+    pub fn write(daif: u64) {
+        DAIF.with(|d| d.set(daif));
+    }
+}
+
+/// Mask IRQs on this CPU.
+///
+/// # Returns
+/// The previous state, to be handed back to [`restore`]
+pub fn disable_save() -> IrqFlags {
+    let flags = IrqFlags(daif::read());
+    daif::mask_irq();
+    flags
+}
+
+/// Restore the IRQ state saved by [`disable_save`].
+///
+/// Nested save/restore pairs must be released in reverse order; IRQs are
+/// only unmasked again by the outermost restore.
+pub fn restore(flags: IrqFlags) {
+    daif::write(flags.0);
+}
+
+/// IRQs masked for the lifetime of the guard.
+///
+/// Created by [`IrqGuard::new`]; dropping it restores the state saved
+/// when it was created.
+#[must_use = "IRQs are unmasked again as soon as the guard is dropped"]
+pub struct IrqGuard(IrqFlags);
+
+impl IrqGuard {
+    /// Mask IRQs on this CPU until the guard is dropped.
+    pub fn new() -> Self {
+        Self(disable_save())
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        restore(self.0);
+    }
+}
+
+/// Set once boot is over and IRQs may be unmasked.
+///
+/// Before that nothing can interrupt a lock holder, so boot paths may
+/// skip masking; see [`runtime_phase`].
+static IRQS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Record that boot is over and IRQs may be unmasked from now on.
+///
+/// Must be called before IRQs are first unmasked.
+#[allow(dead_code)]
+pub fn enter_runtime_phase() {
+    IRQS_ENABLED.store(true, Ordering::Release);
+}
+
+/// Leave boot and unmask IRQs on this CPU.
+///
+/// Interrupts are taken from here on, so the IRQ vector and interrupt
+/// controller must be set up.
+#[cfg(target_os = "none")]
+pub fn enable() {
+    enter_runtime_phase();
+    daif::unmask_irq();
+}
+
+/// Returns true once [`enter_runtime_phase`] was called.
+#[allow(dead_code)]
+pub fn runtime_phase() -> bool {
+    IRQS_ENABLED.load(Ordering::Acquire)
+}
+
+/// Returns true if IRQs are currently unmasked on this CPU.
+#[allow(dead_code)]
+pub fn irqs_enabled() -> bool {
+    daif::read() & DAIF_I == 0
+}
+
+/// Returns true if this CPU may not block: in an interrupt handler or
+/// anywhere else IRQs are masked.
+///
+/// Read from DAIF, so host builds see the mock.
+pub fn in_irq_context() -> bool {
+    !irqs_enabled()
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irq_disable_restore() {
+        assert!(irqs_enabled());
+
+        let flags = disable_save();
+        assert!(flags.irqs_enabled());
+        assert!(!irqs_enabled());
+        assert!(in_irq_context());
+
+        restore(flags);
+        assert!(irqs_enabled());
+        assert!(!in_irq_context());
+    }
+
+    #[test]
+    fn test_irq_guard() {
+        {
+            let _outer = IrqGuard::new();
+            assert!(!irqs_enabled());
+            {
+                let _inner = IrqGuard::new();
+            }
+            // Only the outer guard unmasks again
+            assert!(!irqs_enabled());
+        }
+        assert!(irqs_enabled());
+    }
+
+    #[test]
+    fn test_irq_nested_restore() {
+        let outer = disable_save();
+        let inner = disable_save();
+        assert!(!inner.irqs_enabled());
+
+        // The inner restore keeps IRQs masked
+        restore(inner);
+        assert!(!irqs_enabled());
+        restore(outer);
+        assert!(irqs_enabled());
+    }
+
+    #[test]
+    fn test_irq_restore_keeps_other_bits() {
+        // FIQ masked by someone else stays masked across the pair
+        let fiq = 1 << 6;
+        daif::write(fiq);
+        let flags = disable_save();
+        assert_eq!(daif::read(), fiq | DAIF_I);
+        restore(flags);
+        assert_eq!(daif::read(), fiq);
+        daif::write(0);
+    }
+}
@@ -1,17 +1,88 @@
+#[cfg(target_os = "none")]
 use core::arch::global_asm;
+#[cfg(target_os = "none")]
 use core::panic::PanicInfo;
 
-global_asm!(include_str!("boot.S"));
+#[cfg(target_os = "none")]
+global_asm!(
+    include_str!("boot.S"),
+    VA_BITS = const address::kernel::VA_BITS,
+    ROOT_LEVEL = const pagetable::ROOT_LEVEL,
+    BOOT_TCR_EL1 = const pagetable::BOOT_TCR_EL1,
+    BOOT_MAIR_EL1 = const pagetable::BOOT_MAIR_EL1,
+    SCTLR_EL1_RESET = const sysregs::SCTLR_EL1_RESET,
+    SCTLR_MMU_ON = const sysregs::SCTLR_MMU_ON,
+    BOOT_HCR_EL2 = const sysregs::BOOT_HCR_EL2,
+    BOOT_SPSR_EL2 = const sysregs::BOOT_SPSR_EL2,
+    MAX_PARANGE = const cpu::MAX_PARANGE,
+);
 
 pub mod address;
+pub mod barrier;
 pub mod boot;
+pub mod cache;
+pub mod cpu;
+pub mod earlycon;
+pub mod exception;
+pub mod gic;
+pub mod idle;
+pub mod irq;
+pub mod pagetable;
+pub mod percpu;
+pub mod psci;
+pub mod reg;
+pub mod rtc;
+pub mod semihosting;
 pub mod serial;
+pub mod sync;
+pub mod sysregs;
+pub mod timer;
 
+#[cfg(target_os = "none")]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {
-        unsafe {
-            core::arch::asm!("wfe");
-        }
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    // Nothing may be left waiting for a TX interrupt that will not come
+    serial::disable_irq_tx();
+
+    // Red even with color off, a panic must stand out
+    let _ = writeln!(serial::Writer);
+    let _ = serial::color::write_prefix(&mut serial::Writer, serial::color::Level::Error, true, "");
+    let _ = writeln!(serial::Writer, "Kernel panic: {}", info);
+    let _ = write!(serial::Writer, "Time: ");
+    let _ = rtc::write_now(&mut serial::Writer);
+    let _ = writeln!(serial::Writer);
+    let status = serial::status();
+    if status.degraded {
+        let _ = writeln!(
+            serial::Writer,
+            "Console degraded: {} dropped waits{}",
+            status.dropped_waits,
+            if status.device_absent {
+                ", device absent"
+            } else {
+                ""
+            }
+        );
+    }
+    if status.dropped_bytes != 0 {
+        let _ = writeln!(
+            serial::Writer,
+            "Console dropped {} bytes of non-blocking output",
+            status.dropped_bytes
+        );
+    }
+    if let Some(phase) = boot::timeline::last() {
+        let _ = writeln!(serial::Writer, "Last boot phase: {}", phase.name);
     }
+    // The panic may have happened with memblock locked, don't spin on it
+    let _ = crate::mm::memblock::try_dump_to(&mut serial::Writer);
+
+    #[cfg(feature = "panic-poweroff")]
+    if let Err(e) = psci::system_off() {
+        let _ = writeln!(serial::Writer, "Power off failed: {}", e);
+    }
+
+    idle::park_forever()
 }
@@ -0,0 +1,1441 @@
+//! Kernel page table manipulation.
+//!
+//! The boot code maps the kernel with 1GB L1 block descriptors (4KB
+//! granule). With the default 39-bit VA the walk has 3 levels starting at
+//! L1; with the `va48` feature it has 4 levels and the root is an L0 table
+//! pointing at the same L1 table. This module splits those blocks into
+//! finer-grained tables on demand so individual pages can be remapped or
+//! unmapped after the MMU is enabled.
+//!
+//! A block is replaced by its table with break-before-make. The block
+//! usually maps the code doing the split, so the break runs from a small
+//! stub entered through a temporary TTBR0 identity map (see
+//! `pagetable.S`).
+//!
+//! For debugging, [`walk`] and [`dump_tables`] read any set of tables
+//! through a [`TableReader`], translating one address or listing every
+//! mapping.
+
+use crate::arch::address;
+#[cfg(target_os = "none")]
+use crate::arch::barrier::{self, Scope};
+#[cfg(target_os = "none")]
+use crate::arch::sync::IrqSafeMutex;
+use crate::arch::sysregs;
+#[cfg(target_os = "none")]
+use crate::mm::{layout, memblock};
+use core::fmt;
+
+#[cfg(target_os = "none")]
+core::arch::global_asm!(include_str!("pagetable.S"));
+
+#[cfg(target_os = "none")]
+unsafe extern "C" {
+    /// Break-before-make of one live descriptor (see pagetable.S).
+    fn __pt_break_before_make(entry: u64, desc: u64);
+}
+
+/// Number of descriptors in a translation table.
+pub const ENTRIES: usize = 512;
+
+/// Descriptor bit definitions.
+pub mod desc {
+    /// Descriptor is valid.
+    pub const VALID: u64 = 1 << 0;
+    /// Table descriptor at L1/L2, page descriptor at L3.
+    pub const TABLE: u64 = 1 << 1;
+    /// Block descriptor type at L1/L2 (bits[1:0] = 0b01).
+    pub const BLOCK: u64 = VALID;
+    /// Page descriptor type at L3 (bits[1:0] = 0b11).
+    pub const PAGE: u64 = VALID | TABLE;
+    /// Mask for the descriptor type bits[1:0].
+    pub const TYPE_MASK: u64 = 0b11;
+    /// Output address bits[47:12].
+    pub const ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+    /// Lower attributes bits[11:2].
+    pub const LOWER_ATTRS: u64 = 0x0000_0000_0000_0ffc;
+    /// Upper attributes bits[63:52].
+    pub const UPPER_ATTRS: u64 = 0xfff0_0000_0000_0000;
+    /// Shift of the MAIR attribute index field bits[4:2].
+    pub const ATTR_INDX_SHIFT: u64 = 2;
+    /// AP[1], accessible from EL0.
+    pub const AP_EL0: u64 = 1 << 6;
+    /// AP[2], read-only.
+    pub const AP_RO: u64 = 1 << 7;
+    /// Inner Shareable, bits[9:8] = 0b11.
+    pub const SH_INNER: u64 = 0b11 << 8;
+    /// Access flag.
+    pub const AF: u64 = 1 << 10;
+    /// Privileged execute-never.
+    pub const PXN: u64 = 1 << 53;
+    /// Unprivileged execute-never.
+    pub const UXN: u64 = 1 << 54;
+}
+
+/// A single 4KB translation table.
+#[repr(C, align(4096))]
+pub struct PageTable {
+    pub entries: [u64; ENTRIES],
+}
+
+/// Returns the number of translation levels needed for `va_bits` of VA.
+pub const fn levels(va_bits: u32) -> usize {
+    // Each level resolves 9 bits above the 12-bit page offset
+    (va_bits as usize - 12).div_ceil(9)
+}
+
+/// Returns the level of the root table for `va_bits` of VA.
+pub const fn root_level(va_bits: u32) -> usize {
+    4 - levels(va_bits)
+}
+
+/// Number of translation levels used by the kernel tables.
+#[allow(dead_code)]
+pub const LEVELS: usize = levels(address::kernel::VA_BITS);
+
+/// Level of the kernel root table: L1 with 39-bit VA, L0 with 48-bit VA.
+pub const ROOT_LEVEL: usize = root_level(address::kernel::VA_BITS);
+
+const _: () = assert!(
+    (address::kernel::VA_BITS - 12).is_multiple_of(9),
+    "VA_BITS must fill the root table so table_index needs no extra mask"
+);
+
+/// Returns the TCR_EL1.TxSZ value for `va_bits` of VA.
+pub const fn tcr_tsz(va_bits: u32) -> u64 {
+    64 - va_bits as u64
+}
+
+/// Returns the TCR_EL1 value for `va_bits` of VA in both halves.
+///
+/// # Arguments
+/// * `va_bits` - Virtual address size
+/// * `ips` - Intermediate physical address size, as a PARange encoding
+///   (see `cpu::CpuFeatures::tcr_ips`)
+pub const fn tcr_el1(va_bits: u32, ips: u64) -> u64 {
+    sysregs::TcrValue::new(va_bits).ips(ips).build()
+}
+
+/// TCR_EL1 value loaded by boot.S, which then fills in IPS from the CPU's
+/// PARange.
+#[allow(dead_code)]
+pub const BOOT_TCR_EL1: u64 = tcr_el1(address::kernel::VA_BITS, 0);
+
+/// MAIR_EL1 value loaded by boot.S, indexed as in `address::mair`.
+pub const BOOT_MAIR_EL1: u64 = sysregs::BOOT_MAIR.build();
+
+/// Returns the address shift for a translation level (0 to 3).
+pub const fn level_shift(level: usize) -> u64 {
+    // L3 maps 4KB, each level above covers 512 times more
+    12 + 9 * (3 - level as u64)
+}
+
+/// Returns the size of the region mapped by one descriptor at `level`.
+pub const fn level_size(level: usize) -> u64 {
+    1 << level_shift(level)
+}
+
+/// Returns the table index for `va` at translation `level`.
+pub const fn table_index(va: u64, level: usize) -> usize {
+    ((va >> level_shift(level)) as usize) & (ENTRIES - 1)
+}
+
+/// Returns the table index a walk over `va_bits` of VA uses at each level.
+///
+/// Entry `n` is the index into the level `n` table, or `None` for levels
+/// above the root.
+#[cfg(test)]
+pub const fn walk_indices(va: u64, va_bits: u32) -> [Option<usize>; 4] {
+    let mut indices = [None; 4];
+    let mut level = root_level(va_bits);
+    while level <= 3 {
+        indices[level] = Some(table_index(va, level));
+        level += 1;
+    }
+    indices
+}
+
+/// Returns true if `desc` is a block descriptor at L1/L2.
+pub const fn is_block(desc: u64) -> bool {
+    desc & desc::TYPE_MASK == desc::BLOCK
+}
+
+/// Returns true if `desc` points to a next-level table (L1/L2).
+pub const fn is_table(desc: u64) -> bool {
+    desc & desc::TYPE_MASK == desc::VALID | desc::TABLE
+}
+
+/// Compute descriptor `index` of the table that replaces block `block`.
+///
+/// The block at `level` is split into a table at `level + 1` whose entries
+/// map the same output range with the same attributes.
+///
+/// # Arguments
+/// * `block` - Block descriptor being split
+/// * `level` - Level of the block descriptor (1 or 2)
+/// * `index` - Entry index within the new table
+pub const fn split_entry(block: u64, level: usize, index: usize) -> u64 {
+    let attrs = block & (desc::LOWER_ATTRS | desc::UPPER_ATTRS);
+    let base = block & desc::ADDR_MASK & !(level_size(level) - 1);
+    let addr = base + index as u64 * level_size(level + 1);
+    let kind = if level + 1 == 3 {
+        desc::PAGE
+    } else {
+        desc::BLOCK
+    };
+    addr | attrs | kind
+}
+
+/// Build an L3 page descriptor for a kernel-only, non-executable mapping.
+///
+/// # Arguments
+/// * `phys` - Page aligned physical address
+/// * `attr_index` - MAIR_EL1 attribute index
+pub const fn page_entry(phys: u64, attr_index: u64) -> u64 {
+    (phys & desc::ADDR_MASK)
+        | (attr_index << desc::ATTR_INDX_SHIFT)
+        | desc::SH_INNER
+        | desc::AF
+        | desc::PXN
+        | desc::UXN
+        | desc::PAGE
+}
+
+/// Build an L1 block descriptor identity mapping the 1GB around `phys`.
+///
+/// Normal memory, executable at EL1, for the temporary identity map used
+/// to replace live descriptors.
+pub const fn idmap_block(phys: u64) -> u64 {
+    (phys & desc::ADDR_MASK & !(level_size(1) - 1))
+        | (address::mair::IDX_NORMAL << desc::ATTR_INDX_SHIFT)
+        | desc::SH_INNER
+        | desc::AF
+        | desc::UXN
+        | desc::BLOCK
+}
+
+/// Returns the MAIR_EL1 attribute index of a block or page descriptor.
+pub const fn attr_index(desc: u64) -> u64 {
+    (desc >> desc::ATTR_INDX_SHIFT) & 0b111
+}
+
+/// Returns the MAIR_EL1 attribute byte selected by `index`.
+pub const fn mair_attr(mair: u64, index: u64) -> u8 {
+    (mair >> (index * 8)) as u8
+}
+
+/// Returns true if a 4-bit MAIR cacheability field is write-back.
+const fn write_back(field: u8) -> bool {
+    // 0b11RW non-transient, 0b01RW transient with RW != 0
+    field & 0b1100 == 0b1100 || (field & 0b1100 == 0b0100 && field & 0b11 != 0)
+}
+
+/// Returns true if a MAIR attribute byte is inner and outer write-back
+/// normal memory.
+pub const fn is_normal_write_back(attr: u8) -> bool {
+    attr >> 4 != 0 && write_back(attr >> 4) && write_back(attr & 0xf)
+}
+
+/// Returns true if a MAIR attribute byte is any kind of device memory.
+pub const fn is_device(attr: u8) -> bool {
+    attr & 0xf3 == 0
+}
+
+/// Returns a short name for the memory type of a MAIR attribute byte.
+pub const fn memory_type(attr: u8) -> &'static str {
+    match attr {
+        0x00 => "Device-nGnRnE",
+        0x04 => "Device-nGnRE",
+        0x44 => "Normal-NC",
+        _ if is_normal_write_back(attr) => "Normal",
+        _ if is_device(attr) => "Device",
+        _ => "Normal-other",
+    }
+}
+
+/// Source of descriptors for a table walk.
+///
+/// The live tables are read through the linear map; host tests supply
+/// tables held in memory. Any `Fn(u64) -> u64` is a reader.
+pub trait TableReader {
+    /// Returns the descriptor stored at physical address `phys`.
+    fn read(&self, phys: u64) -> u64;
+}
+
+impl<F: Fn(u64) -> u64> TableReader for F {
+    fn read(&self, phys: u64) -> u64 {
+        self(phys)
+    }
+}
+
+/// Access and execute permissions of a block or page descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attrs {
+    /// MAIR_EL1 attribute index.
+    pub attr_index: u64,
+    /// AP[2]: writes are not permitted.
+    pub read_only: bool,
+    /// AP[1]: EL0 may access the mapping.
+    pub el0: bool,
+    /// PXN: the kernel may not execute from the mapping.
+    pub execute_never: bool,
+    /// Access flag set.
+    pub accessed: bool,
+}
+
+impl Attrs {
+    /// Decode the attributes of leaf descriptor `desc`.
+    pub const fn from_desc(desc: u64) -> Self {
+        Self {
+            attr_index: attr_index(desc),
+            read_only: desc & desc::AP_RO != 0,
+            el0: desc & desc::AP_EL0 != 0,
+            execute_never: desc & desc::PXN != 0,
+            accessed: desc & desc::AF != 0,
+        }
+    }
+}
+
+/// Outcome of walking a set of tables for one address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    /// Output address for the looked up VA.
+    pub phys: u64,
+    /// Level of the leaf descriptor.
+    pub level: usize,
+    /// The leaf block or page descriptor.
+    pub desc: u64,
+}
+
+impl Translation {
+    /// Returns the first VA after the region the leaf descriptor maps.
+    pub const fn next_va(&self, va: u64) -> u64 {
+        (va | (level_size(self.level) - 1)).wrapping_add(1)
+    }
+
+    /// Returns the decoded attributes of the leaf descriptor.
+    pub const fn attrs(&self) -> Attrs {
+        Attrs::from_desc(self.desc)
+    }
+}
+
+/// A walk that ended on an invalid descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unmapped {
+    /// Level of the invalid descriptor.
+    pub level: usize,
+}
+
+impl fmt::Display for Unmapped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VA not mapped at level {}", self.level)
+    }
+}
+
+/// Walk the tables rooted at `root` for `va`.
+///
+/// Like [`translate`], but reports the level the walk stopped at, so a
+/// fault can be annotated with where its address falls out of the tables.
+///
+/// # Arguments
+/// * `root` - Physical address of the root table
+/// * `va` - Virtual address to translate
+/// * `va_bits` - VA size the tables were built for (TCR_EL1.TxSZ)
+/// * `reader` - Reads descriptors by physical address
+pub fn walk(
+    root: u64,
+    va: u64,
+    va_bits: u32,
+    reader: impl TableReader,
+) -> Result<Translation, Unmapped> {
+    let mut table = root & desc::ADDR_MASK;
+
+    for level in root_level(va_bits)..=3 {
+        let entry = reader.read(table + table_index(va, level) as u64 * 8);
+        let leaf = if level == 3 {
+            entry & desc::TYPE_MASK == desc::PAGE
+        } else {
+            // L0 cannot hold blocks with a 4KB granule
+            level > 0 && is_block(entry)
+        };
+
+        if leaf {
+            let offset = va & (level_size(level) - 1);
+            let base = entry & desc::ADDR_MASK & !(level_size(level) - 1);
+            return Ok(Translation {
+                phys: base + offset,
+                level,
+                desc: entry,
+            });
+        }
+        if level == 3 || !is_table(entry) {
+            return Err(Unmapped { level });
+        }
+        table = entry & desc::ADDR_MASK;
+    }
+    Err(Unmapped { level: 3 })
+}
+
+/// Translate `va` through the tables rooted at `root`.
+///
+/// Tables are read through `read`, so the walk works on tables that are
+/// not the live kernel ones, or not in memory at all.
+///
+/// # Arguments
+/// * `root` - Physical address of the root table
+/// * `va` - Virtual address to translate
+/// * `va_bits` - VA size the tables were built for (TCR_EL1.TxSZ)
+/// * `read` - Reads descriptors by physical address
+///
+/// # Returns
+/// The leaf mapping `va`, or `None` if the walk hits an invalid descriptor.
+pub fn translate(root: u64, va: u64, va_bits: u32, read: impl TableReader) -> Option<Translation> {
+    walk(root, va, va_bits, read).ok()
+}
+
+/// Returns the first VA translated through TTBR1 with `va_bits` of VA.
+pub const fn ttbr1_base(va_bits: u32) -> u64 {
+    !((1 << va_bits) - 1)
+}
+
+/// Translation table base register a VA is walked through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ttbr {
+    /// Lower half, the boot identity map.
+    Ttbr0,
+    /// Upper half, the kernel tables.
+    Ttbr1,
+}
+
+impl Ttbr {
+    /// Returns the register name.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ttbr0 => "TTBR0",
+            Self::Ttbr1 => "TTBR1",
+        }
+    }
+}
+
+/// Returns the TTBR that translates `va` when both halves use `va_bits`
+/// of VA, or `None` for the hole between the two halves.
+pub const fn ttbr_for(va: u64, va_bits: u32) -> Option<Ttbr> {
+    if va >> va_bits == 0 {
+        Some(Ttbr::Ttbr0)
+    } else if va >= ttbr1_base(va_bits) {
+        Some(Ttbr::Ttbr1)
+    } else {
+        None
+    }
+}
+
+/// Walk the tables of whichever half translates `va`.
+///
+/// # Arguments
+/// * `va` - Virtual address to translate
+/// * `va_bits` - VA size of both halves
+/// * `roots` - Root table addresses from TTBR0 and TTBR1, in that order
+/// * `reader` - Reads descriptors by physical address
+///
+/// # Returns
+/// The leaf mapping `va`, or where the walk stopped. Addresses in the
+/// hole between the halves fault at the root level.
+pub fn walk_split(
+    va: u64,
+    va_bits: u32,
+    roots: [u64; 2],
+    reader: impl TableReader,
+) -> Result<Translation, Unmapped> {
+    let root = match ttbr_for(va, va_bits) {
+        Some(Ttbr::Ttbr0) => roots[0],
+        Some(Ttbr::Ttbr1) => roots[1],
+        None => {
+            return Err(Unmapped {
+                level: root_level(va_bits),
+            });
+        }
+    };
+    walk(root, va, va_bits, reader)
+}
+
+/// Print `attrs` as the memory type, permissions and execute-never bit,
+/// followed by ` EL0` and ` AF=0` where they apply.
+///
+/// # Arguments
+/// * `attrs` - Decoded leaf attributes
+/// * `mair` - MAIR_EL1 value the attribute index selects from
+/// * `out` - Destination
+pub fn write_attrs(attrs: &Attrs, mair: u64, out: &mut dyn fmt::Write) -> fmt::Result {
+    write!(
+        out,
+        "{} {} XN={}",
+        memory_type(mair_attr(mair, attrs.attr_index)),
+        if attrs.read_only { "RO" } else { "RW" },
+        attrs.execute_never as u8,
+    )?;
+    if attrs.el0 {
+        out.write_str(" EL0")?;
+    }
+    if !attrs.accessed {
+        out.write_str(" AF=0")?;
+    }
+    Ok(())
+}
+
+/// Returns the name of the mapping size a leaf at `level` uses.
+const fn granule_name(level: usize) -> &'static str {
+    match level {
+        1 => "1GB blocks",
+        2 => "2MB blocks",
+        _ => "4KB pages",
+    }
+}
+
+/// Consecutive leaves with contiguous output and identical attributes.
+struct Run {
+    /// First VA of the run.
+    va: u64,
+    /// Output address of `va`.
+    phys: u64,
+    /// Length in bytes; a run may reach the top of the address space.
+    len: u128,
+    /// Level of the leaves.
+    level: usize,
+    /// Attribute bits shared by the leaves.
+    attrs: u64,
+}
+
+impl Run {
+    /// Returns true if a leaf at `va` extends this run.
+    fn extends(&self, va: u64, leaf: &Translation) -> bool {
+        let offset = self.len;
+        self.level == leaf.level
+            && self.attrs == leaf.desc & (desc::LOWER_ATTRS | desc::UPPER_ATTRS)
+            && self.va as u128 + offset == va as u128
+            && self.phys as u128 + offset == leaf.phys as u128
+    }
+
+    /// Print the run as one line.
+    fn print(&self, mair: u64, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(
+            out,
+            "{:#x}..{:#x} -> {:#x} [{}] ",
+            self.va,
+            self.va as u128 + self.len,
+            self.phys,
+            granule_name(self.level),
+        )?;
+        write_attrs(&Attrs::from_desc(self.attrs), mair, out)?;
+        out.write_str("\n")
+    }
+}
+
+/// Visit every leaf of the table at `table`, in VA order.
+fn visit_leaves(
+    reader: &impl TableReader,
+    table: u64,
+    level: usize,
+    va_base: u64,
+    entries: usize,
+    leaf: &mut dyn FnMut(u64, Translation) -> fmt::Result,
+) -> fmt::Result {
+    for index in 0..entries {
+        let entry = reader.read(table + index as u64 * 8);
+        let va = va_base | ((index as u64) << level_shift(level));
+        let is_leaf = if level == 3 {
+            entry & desc::TYPE_MASK == desc::PAGE
+        } else {
+            level > 0 && is_block(entry)
+        };
+
+        if is_leaf {
+            let phys = entry & desc::ADDR_MASK & !(level_size(level) - 1);
+            leaf(
+                va,
+                Translation {
+                    phys,
+                    level,
+                    desc: entry,
+                },
+            )?;
+        } else if level < 3 && is_table(entry) {
+            let next = entry & desc::ADDR_MASK;
+            visit_leaves(reader, next, level + 1, va, ENTRIES, leaf)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print every mapping in the tables rooted at `root`.
+///
+/// Consecutive leaves of the same size whose output is contiguous and
+/// whose attributes match are merged into one line, e.g.
+/// `0xffffff8040000000..0xffffff8040200000 -> 0x40000000 [2MB blocks]
+/// Normal RW XN=0`. `XN` is the privileged execute-never bit; `EL0` and
+/// `AF=0` are added for mappings EL0 can reach or that lack the access
+/// flag.
+///
+/// # Arguments
+/// * `root` - Physical address of the root table
+/// * `va_base` - First VA the root maps, see [`ttbr1_base`]
+/// * `va_bits` - VA size the tables were built for
+/// * `mair` - MAIR_EL1 value to name memory types with
+/// * `reader` - Reads descriptors by physical address
+/// * `out` - Destination of the listing
+pub fn dump_tables(
+    root: u64,
+    va_base: u64,
+    va_bits: u32,
+    mair: u64,
+    reader: impl TableReader,
+    out: &mut dyn fmt::Write,
+) -> fmt::Result {
+    let level = root_level(va_bits);
+    let entries = 1 << (va_bits as u64 - level_shift(level));
+    let mut run: Option<Run> = None;
+
+    visit_leaves(
+        &reader,
+        root & desc::ADDR_MASK,
+        level,
+        va_base,
+        entries,
+        &mut |va, leaf| {
+            if let Some(current) = run.as_mut()
+                && current.extends(va, &leaf)
+            {
+                current.len += level_size(leaf.level) as u128;
+                return Ok(());
+            }
+            if let Some(done) = run.take() {
+                done.print(mair, out)?;
+            }
+            run = Some(Run {
+                va,
+                phys: leaf.phys,
+                len: level_size(leaf.level) as u128,
+                level: leaf.level,
+                attrs: leaf.desc & (desc::LOWER_ATTRS | desc::UPPER_ATTRS),
+            });
+            Ok(())
+        },
+    )?;
+
+    match run {
+        Some(done) => done.print(mair, out),
+        None => Ok(()),
+    }
+}
+
+/// Read the physical address of the kernel (TTBR1) root table.
+#[cfg(target_os = "none")]
+fn kernel_root() -> u64 {
+    let ttbr: u64;
+    unsafe {
+        // Safety: reading TTBR1_EL1 has no side effects
+        core::arch::asm!("mrs {}, ttbr1_el1", out(reg) ttbr);
+    }
+    ttbr & desc::ADDR_MASK
+}
+
+/// Read the physical address of the boot identity map (TTBR0) root table.
+#[cfg(target_os = "none")]
+fn user_root() -> u64 {
+    let ttbr: u64;
+    unsafe {
+        // Safety: reading TTBR0_EL1 has no side effects
+        core::arch::asm!("mrs {}, ttbr0_el1", out(reg) ttbr);
+    }
+    ttbr & desc::ADDR_MASK
+}
+
+/// Read MAIR_EL1.
+#[cfg(target_os = "none")]
+pub fn current_mair() -> u64 {
+    let mair: u64;
+    unsafe {
+        // Safety: reading MAIR_EL1 has no side effects
+        core::arch::asm!("mrs {}, mair_el1", out(reg) mair);
+    }
+    mair
+}
+
+/// Read a descriptor of the live tables through the linear map.
+#[cfg(target_os = "none")]
+fn read_live(phys: u64) -> u64 {
+    // Safety: only called with addresses of descriptors in tables reached
+    // from a TTBR, which are in RAM covered by the linear map
+    unsafe { core::ptr::read_volatile(address::translation::phys_to_virt(phys) as *const u64) }
+}
+
+/// Print the kernel half mappings of the tables rooted at `root_phys`.
+///
+/// # Arguments
+/// * `root_phys` - Physical address of a TTBR1 root table
+/// * `out` - Destination of the listing
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn dump(root_phys: u64, out: &mut dyn fmt::Write) -> fmt::Result {
+    let va_bits = address::kernel::VA_BITS;
+    dump_tables(
+        root_phys,
+        ttbr1_base(va_bits),
+        va_bits,
+        current_mair(),
+        read_live,
+        out,
+    )
+}
+
+/// Print the mappings of the live kernel (TTBR1) tables.
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn dump_kernel(out: &mut dyn fmt::Write) -> fmt::Result {
+    dump(kernel_root(), out)
+}
+
+/// Walk the live tables for `va`, through TTBR0 or TTBR1 as the MMU
+/// would.
+///
+/// # Returns
+/// The leaf mapping `va`, or where the walk stopped.
+#[cfg(target_os = "none")]
+pub fn lookup(va: u64) -> Result<Translation, Unmapped> {
+    walk_split(
+        va,
+        address::kernel::VA_BITS,
+        [user_root(), kernel_root()],
+        read_live,
+    )
+}
+
+/// Translate `va` through the live tables.
+///
+/// # Returns
+/// The physical address and attributes `va` maps to, or `None` if it is
+/// not mapped.
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn translate_active(va: u64) -> Option<(u64, Attrs)> {
+    lookup(va).ok().map(|leaf| (leaf.phys, leaf.attrs()))
+}
+
+/// Get a mutable reference to the table at physical address `phys`.
+///
+/// # Safety
+/// `phys` must be the address of a translation table reachable through the
+/// kernel linear map.
+#[cfg(target_os = "none")]
+unsafe fn table_at(phys: u64) -> &'static mut PageTable {
+    unsafe { &mut *(address::translation::phys_to_virt(phys) as *mut PageTable) }
+}
+
+/// Allocate a physical page for a translation table.
+///
+/// Until memblock takes over, pages come from the early boot pool, and
+/// once the buddy allocator has taken over from memblock, from it.
+#[cfg(target_os = "none")]
+fn alloc_table_page() -> Result<u64, &'static str> {
+    use crate::mm::pmm;
+
+    if crate::arch::boot::early_alloc::active() {
+        return crate::arch::boot::early_alloc::alloc_page();
+    }
+    if pmm::phase() == pmm::Phase::Buddy {
+        return pmm::alloc_page();
+    }
+    memblock::alloc_tagged(
+        address::kernel::PAGE_SIZE,
+        address::kernel::PAGE_SIZE,
+        memblock::ReservationOwner::PageTable,
+    )
+    .map_err(|e| e.as_str())
+}
+
+/// Temporary TTBR0 tables for [`replace_live`].
+#[cfg(target_os = "none")]
+struct Idmap {
+    /// Root with 4-level tables, unused with 3.
+    l0: PageTable,
+    l1: PageTable,
+}
+
+#[cfg(target_os = "none")]
+static IDMAP: IrqSafeMutex<Idmap> = IrqSafeMutex::new(
+    "idmap",
+    Idmap {
+        l0: PageTable {
+            entries: [0; ENTRIES],
+        },
+        l1: PageTable {
+            entries: [0; ENTRIES],
+        },
+    },
+);
+
+/// Replace the live descriptor at `entry` with `new` by break-before-make.
+///
+/// The descriptor may map this code, its stack and the table holding it,
+/// so the break runs in `__pt_break_before_make`, entered through an
+/// identity map of only the kernel and the table, with all exceptions
+/// masked. The kernel's TTBR0 is restored afterwards.
+#[cfg(target_os = "none")]
+fn replace_live(entry: &mut u64, new: u64) {
+    use address::translation::virt_to_phys;
+
+    let mut idmap = IDMAP.lock();
+    let entry_phys = virt_to_phys(entry as *mut u64 as u64);
+    let stub = virt_to_phys(__pt_break_before_make as *const () as u64);
+
+    idmap.l1.entries.fill(0);
+    for phys in [stub, entry_phys] {
+        idmap.l1.entries[table_index(phys, 1)] = idmap_block(phys);
+    }
+    let mut root = virt_to_phys(&idmap.l1 as *const PageTable as u64);
+    if ROOT_LEVEL == 0 {
+        idmap.l0.entries.fill(0);
+        for phys in [stub, entry_phys] {
+            idmap.l0.entries[table_index(phys, 0)] = root | desc::VALID | desc::TABLE;
+        }
+        root = virt_to_phys(&idmap.l0 as *const PageTable as u64);
+    }
+
+    unsafe {
+        // Safety: the identity map covers the stub and the descriptor, and
+        // the stub touches nothing else. Exceptions stay masked while TTBR0
+        // is switched, and the TLB is flushed on each switch so no entry
+        // of one set of tables is used with the other.
+        core::arch::asm!(
+            "mrs {daif}, daif",
+            "msr daifset, #0xf",
+            "mrs {ttbr0}, ttbr0_el1",
+            "dsb ishst",
+            "msr ttbr0_el1, {root}",
+            "isb",
+            "tlbi vmalle1",
+            "dsb nsh",
+            "isb",
+            "blr {stub}",
+            "msr ttbr0_el1, {ttbr0}",
+            "isb",
+            "tlbi vmalle1is",
+            "dsb ish",
+            "isb",
+            "msr daif, {daif}",
+            daif = out(reg) _,
+            ttbr0 = out(reg) _,
+            root = in(reg) root,
+            stub = in(reg) stub,
+            in("x0") entry_phys,
+            in("x1") new,
+            out("x30") _,
+        );
+    }
+}
+
+/// Replace the block descriptor at `entry` with an equivalent table.
+#[cfg(target_os = "none")]
+fn split_block(entry: &mut u64, level: usize) -> Result<(), &'static str> {
+    let block = *entry;
+    let phys = alloc_table_page()?;
+
+    // Safety: freshly allocated page from RAM, covered by the linear map
+    let table = unsafe { table_at(phys) };
+    for (i, slot) in table.entries.iter_mut().enumerate() {
+        *slot = split_entry(block, level, i);
+    }
+
+    // Make the new table visible to the walker before linking it
+    barrier::dsb(Scope::IshSt);
+    replace_live(entry, phys | desc::VALID | desc::TABLE);
+
+    Ok(())
+}
+
+/// Link a new, empty next-level table at `entry`.
+#[cfg(target_os = "none")]
+fn alloc_table(entry: &mut u64) -> Result<(), &'static str> {
+    let phys = alloc_table_page()?;
+
+    // Safety: freshly allocated page from RAM, covered by the linear map
+    let table = unsafe { table_at(phys) };
+    table.entries.fill(0);
+
+    // The empty table must be visible before it is linked
+    barrier::dsb(Scope::IshSt);
+    unsafe {
+        // Safety: `entry` is a live descriptor owned by the kernel tables
+        core::ptr::write_volatile(entry, phys | desc::VALID | desc::TABLE);
+    }
+
+    Ok(())
+}
+
+/// Walk to the L3 descriptor for `va`, splitting blocks along the way.
+///
+/// Missing intermediate tables are allocated if `create` is set, otherwise
+/// an unmapped address is an error.
+#[cfg(target_os = "none")]
+fn walk_to_page(va: u64, create: bool) -> Result<&'static mut u64, &'static str> {
+    let mut phys = kernel_root();
+
+    for level in ROOT_LEVEL..3 {
+        // Safety: `phys` is the root or a table linked by this walk
+        let table = unsafe { table_at(phys) };
+        let entry = &mut table.entries[table_index(va, level)];
+
+        if is_block(*entry) {
+            split_block(entry, level)?;
+        } else if !is_table(*entry) {
+            if !create {
+                return Err("virtual address is not mapped");
+            }
+            alloc_table(entry)?;
+        }
+        phys = *entry & desc::ADDR_MASK;
+    }
+
+    // Safety: `phys` is an L3 table linked by this walk
+    let table = unsafe { table_at(phys) };
+    Ok(&mut table.entries[table_index(va, 3)])
+}
+
+/// Link a caller-provided table as the next level below `level` for `va`.
+///
+/// Lets early code install statically allocated tables before memblock
+/// can hand out pages. The descriptor must be empty and all tables above
+/// `level` must already exist.
+///
+/// # Arguments
+/// * `va` - Kernel virtual address the table will translate
+/// * `level` - Level of the descriptor to fill in, from `ROOT_LEVEL` to 2
+/// * `table_phys` - Physical address of a zeroed, page aligned table
+#[cfg(target_os = "none")]
+pub fn install_table(va: u64, level: usize, table_phys: u64) -> Result<(), &'static str> {
+    if !(ROOT_LEVEL..3).contains(&level) {
+        return Err("invalid table level");
+    }
+
+    let mut phys = kernel_root();
+    for walk in ROOT_LEVEL..level {
+        // Safety: `phys` is the root or a table linked by this walk
+        let table = unsafe { table_at(phys) };
+        let entry = table.entries[table_index(va, walk)];
+        if !is_table(entry) {
+            return Err("parent table is missing");
+        }
+        phys = entry & desc::ADDR_MASK;
+    }
+
+    // Safety: `phys` is a live table owned by the kernel tables
+    let table = unsafe { table_at(phys) };
+    let entry = &mut table.entries[table_index(va, level)];
+    if *entry & desc::VALID != 0 {
+        return Err("virtual address is already mapped");
+    }
+
+    // The table must be visible before it is linked
+    barrier::dsb(Scope::IshSt);
+    unsafe {
+        // Safety: `entry` is a live, empty descriptor of the kernel tables
+        core::ptr::write_volatile(entry, table_phys | desc::VALID | desc::TABLE);
+    }
+
+    Ok(())
+}
+
+/// Remove the mapping for the 4KB page containing `va`.
+///
+/// Any access to the page afterwards raises a translation fault.
+///
+/// # Arguments
+/// * `va` - Kernel virtual address within the page to unmap
+#[cfg(target_os = "none")]
+pub fn unmap_page(va: u64) -> Result<(), &'static str> {
+    let entry = walk_to_page(va, false)?;
+
+    unsafe {
+        // Safety: `entry` is a live L3 descriptor owned by the kernel tables
+        core::ptr::write_volatile(entry, 0);
+    }
+    flush_tlb_all();
+
+    Ok(())
+}
+
+/// Check that `attr_index` suits what `[phys, phys + size)` is in `mb`.
+///
+/// Normal memory attributes are only allowed on present RAM, and device
+/// attributes never on it: a device mapping aliasing RAM that the linear
+/// map also covers has mismatched attributes, which the architecture
+/// leaves unpredictable.
+pub fn check_mapping_attr(
+    mb: &crate::mm::memblock::Memblock,
+    phys: u64,
+    size: u64,
+    attr_index: u64,
+) -> Result<(), &'static str> {
+    let normal = address::mair::BOOT
+        .type_at(attr_index)
+        .ok_or("unknown memory attribute index")?
+        .is_normal();
+    if normal {
+        if !mb.is_memory(phys, size) {
+            return Err("normal memory mapping outside RAM");
+        }
+    } else {
+        let range = crate::mm::memblock::Region::new(phys, size);
+        if mb.memory_regions().iter().any(|m| m.overlaps(&range)) {
+            return Err("device mapping overlaps RAM");
+        }
+    }
+    Ok(())
+}
+
+/// Map `[va, va + size)` to `[phys, phys + size)` with 4KB pages.
+///
+/// All addresses and the size must be page aligned, and the range must lie
+/// within a single window of the kernel layout. Existing mappings in the
+/// range are replaced. The attribute must fit the physical range, see
+/// [`check_mapping_attr`].
+///
+/// # Arguments
+/// * `va` - Kernel virtual start address
+/// * `phys` - Physical start address
+/// * `size` - Size of the range in bytes
+/// * `attr_index` - MAIR_EL1 attribute index for the mapping
+#[cfg(target_os = "none")]
+pub fn map_range(va: u64, phys: u64, size: u64, attr_index: u64) -> Result<(), &'static str> {
+    let page_size = address::kernel::PAGE_SIZE;
+    if (va | phys | size) & (page_size - 1) != 0 {
+        return Err("mapping is not page aligned");
+    }
+    if layout::window_of(va, size).is_none() {
+        return Err("mapping is outside the kernel layout");
+    }
+    // Released before walking, table allocation takes the lock again
+    check_mapping_attr(&memblock::lock(), phys, size, attr_index)?;
+
+    let mut offset = 0;
+    while offset < size {
+        let entry = walk_to_page(va + offset, true)?;
+        unsafe {
+            // Safety: `entry` is a live L3 descriptor owned by the kernel tables
+            core::ptr::write_volatile(entry, page_entry(phys + offset, attr_index));
+        }
+        offset += page_size;
+    }
+    flush_tlb_all();
+
+    Ok(())
+}
+
+/// Remove all mappings in `[va, va + size)`.
+///
+/// # Arguments
+/// * `va` - Page aligned kernel virtual start address
+/// * `size` - Page aligned size of the range in bytes
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn unmap_range(va: u64, size: u64) -> Result<(), &'static str> {
+    let page_size = address::kernel::PAGE_SIZE;
+    if (va | size) & (page_size - 1) != 0 {
+        return Err("mapping is not page aligned");
+    }
+
+    let mut offset = 0;
+    while offset < size {
+        let entry = walk_to_page(va + offset, false)?;
+        unsafe {
+            // Safety: `entry` is a live L3 descriptor owned by the kernel tables
+            core::ptr::write_volatile(entry, 0);
+        }
+        offset += page_size;
+    }
+    flush_tlb_all();
+
+    Ok(())
+}
+
+/// Invalidate the TLB entries for the page containing `va` and synchronize.
+#[cfg(target_os = "none")]
+pub fn flush_tlb_page(va: u64) {
+    unsafe {
+        // Safety: TLB maintenance only affects cached translations
+        core::arch::asm!(
+            "dsb ishst",
+            "tlbi vaae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) (va >> 12) & ((1 << 44) - 1),
+        );
+    }
+}
+
+/// Invalidate all EL1 TLB entries and synchronize.
+#[cfg(target_os = "none")]
+fn flush_tlb_all() {
+    unsafe {
+        // Safety: TLB maintenance only affects cached translations
+        core::arch::asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb");
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_mapping_attr() {
+        use crate::arch::address::mair;
+        use crate::arch::address::virt::{RAM_BASE, UART_BASE};
+        use crate::mm::memblock::Memblock;
+
+        let mb = Memblock::from_regions(
+            &[(RAM_BASE, 0x10_0000), (RAM_BASE + 0x20_0000, 0x10_0000)],
+            &[],
+        );
+        let normal = mair::IDX_NORMAL;
+        let device = mair::IDX_DEVICE_NGNRE;
+
+        assert_eq!(check_mapping_attr(&mb, RAM_BASE, 0x1000, normal), Ok(()));
+        assert_eq!(
+            check_mapping_attr(&mb, RAM_BASE + 0x1000, 0x1000, mair::IDX_NORMAL_NC),
+            Ok(())
+        );
+        // RAM attributes on a hole between banks, across it, or on MMIO
+        for (phys, size) in [
+            (RAM_BASE + 0x10_0000, 0x1000),
+            (RAM_BASE + 0xf_f000, 0x2000),
+            (UART_BASE, 0x1000),
+        ] {
+            assert_eq!(
+                check_mapping_attr(&mb, phys, size, normal),
+                Err("normal memory mapping outside RAM")
+            );
+        }
+
+        assert_eq!(check_mapping_attr(&mb, UART_BASE, 0x1000, device), Ok(()));
+        assert_eq!(
+            check_mapping_attr(&mb, RAM_BASE + 0x10_0000, 0x1000, device),
+            Ok(())
+        );
+        assert_eq!(
+            check_mapping_attr(&mb, RAM_BASE + 0x1f_f000, 0x2000, device),
+            Err("device mapping overlaps RAM")
+        );
+        assert_eq!(
+            check_mapping_attr(&mb, RAM_BASE, 0x1000, 7),
+            Err("unknown memory attribute index")
+        );
+    }
+
+    #[test]
+    fn test_table_index() {
+        let va = address::kernel::VIRTUAL_BASE + 0x4020_3000;
+        assert_eq!(table_index(va, 1), 1);
+        assert_eq!(table_index(va, 2), 1);
+        assert_eq!(table_index(va, 3), 3);
+    }
+
+    #[test]
+    fn test_levels_per_va_size() {
+        assert_eq!((levels(39), root_level(39)), (3, 1));
+        assert_eq!((levels(48), root_level(48)), (4, 0));
+        assert_eq!(level_shift(0), 39);
+        assert_eq!(level_size(1), 0x4000_0000);
+
+        // T0SZ and T1SZ both follow the VA size, the rest matches boot.S
+        assert_eq!(tcr_el1(39, 5), 0x5_b519_3519);
+        assert_eq!(tcr_el1(48, 5), 0x5_b510_3510);
+        assert_eq!(BOOT_TCR_EL1, tcr_el1(address::kernel::VA_BITS, 0));
+        assert_eq!(ROOT_LEVEL, root_level(address::kernel::VA_BITS));
+    }
+
+    #[test]
+    fn test_tcr_ips_from_cpu() {
+        use crate::arch::cpu::CpuFeatures;
+
+        // 44-bit PA (cortex-a57) and 52-bit PA capped to 48 bits
+        let a57 = CpuFeatures::decode(0x1124, 0x2222);
+        assert_eq!(tcr_el1(39, a57.tcr_ips()), 0x4_b519_3519);
+        let max = CpuFeatures::decode(0x1000_0000_1020_0026, 0x0011_0011);
+        assert_eq!(tcr_el1(48, max.tcr_ips()), 0x5_b510_3510);
+    }
+
+    #[test]
+    fn test_walk_indices_va39() {
+        // Linear map of RAM: L1 entry 1, as installed by boot.S
+        let va = 0xffff_ff80_0000_0000 + 0x4020_3000;
+        assert_eq!(walk_indices(va, 39), [None, Some(1), Some(1), Some(3)]);
+
+        // Start of the ioremap window, 2GB above the base
+        let va = 0xffff_ff80_8000_0000;
+        assert_eq!(walk_indices(va, 39), [None, Some(2), Some(0), Some(0)]);
+
+        // Fixmap, near the top of the address space
+        let va = 0xffff_ffff_fe00_3000;
+        assert_eq!(walk_indices(va, 39), [None, Some(511), Some(496), Some(3)]);
+    }
+
+    #[test]
+    fn test_walk_indices_va48() {
+        // Linear map of RAM: L0 entry 0 leads to the boot L1 table
+        let va = 0xffff_0000_0000_0000 + 0x4020_3000;
+        assert_eq!(walk_indices(va, 48), [Some(0), Some(1), Some(1), Some(3)]);
+
+        // Start of the ioremap window, 1TB above the base
+        let va = 0xffff_0100_0000_0000;
+        assert_eq!(walk_indices(va, 48), [Some(2), Some(0), Some(0), Some(0)]);
+
+        // Halfway up the VA space
+        let va = 0xffff_8080_4020_3000;
+        assert_eq!(walk_indices(va, 48), [Some(257), Some(1), Some(1), Some(3)]);
+
+        let va = 0xffff_ffff_fe00_3000;
+        assert_eq!(
+            walk_indices(va, 48),
+            [Some(511), Some(511), Some(496), Some(3)]
+        );
+    }
+
+    #[test]
+    fn test_split_entry() {
+        // RAM block from boot.S: MT_NORMAL, inner shareable, AF, UXN
+        let block = 0x0040_0000_4000_0721;
+        assert_eq!(split_entry(block, 1, 0), 0x0040_0000_4000_0721);
+        assert_eq!(split_entry(block, 1, 3), 0x0040_0000_4060_0721);
+
+        let l2 = split_entry(block, 1, 3);
+        assert_eq!(split_entry(l2, 2, 0), 0x0040_0000_4060_0723);
+        assert_eq!(split_entry(l2, 2, 5), 0x0040_0000_4060_5723);
+        assert!(is_block(l2));
+        assert!(!is_table(l2));
+    }
+
+    /// Fabricated tables, keyed by descriptor address.
+    struct Tables(std::collections::BTreeMap<u64, u64>);
+
+    impl Tables {
+        fn new() -> Self {
+            Self(std::collections::BTreeMap::new())
+        }
+
+        fn set(&mut self, table: u64, index: usize, entry: u64) {
+            self.0.insert(table + index as u64 * 8, entry);
+        }
+
+        fn reader(&self) -> impl TableReader + '_ {
+            |phys| self.0.get(&phys).copied().unwrap_or(0)
+        }
+
+        fn dump(&self, root: u64, va_bits: u32) -> String {
+            let mut out = String::new();
+            dump_tables(
+                root,
+                ttbr1_base(va_bits),
+                va_bits,
+                BOOT_MAIR_EL1,
+                self.reader(),
+                &mut out,
+            )
+            .unwrap();
+            out
+        }
+    }
+
+    /// Kernel RAM attributes used by boot.S.
+    const NORMAL: u64 = desc::SH_INNER | desc::AF;
+
+    fn block(phys: u64, attrs: u64) -> u64 {
+        phys | attrs | desc::BLOCK
+    }
+
+    fn page(phys: u64, attrs: u64) -> u64 {
+        phys | attrs | desc::PAGE
+    }
+
+    fn table(phys: u64) -> u64 {
+        phys | desc::VALID | desc::TABLE
+    }
+
+    /// 39-bit tables mixing 1GB and 2MB blocks, 4KB pages and holes.
+    fn mixed_tables() -> Tables {
+        let device = (address::mair::IDX_DEVICE_NGNRE << desc::ATTR_INDX_SHIFT)
+            | desc::AF
+            | desc::PXN
+            | desc::UXN;
+        let text = NORMAL | desc::AP_RO | desc::PXN;
+
+        let mut t = Tables::new();
+        t.set(0x1000, 0, table(0x2000));
+        t.set(0x1000, 1, block(0x8000_0000, NORMAL));
+        // Two contiguous 2MB blocks, then a hole at index 2
+        t.set(0x2000, 0, block(0x4000_0000, NORMAL));
+        t.set(0x2000, 1, block(0x4020_0000, NORMAL));
+        t.set(0x2000, 3, table(0x3000));
+        // Same attributes as index 1 but not contiguous in VA
+        t.set(0x2000, 4, block(0x4080_0000, NORMAL));
+        for i in 0..4 {
+            t.set(0x3000, i, page(0x4060_0000 + i as u64 * 0x1000, text));
+        }
+        t.set(0x3000, 4, page(0x0900_0000, device));
+        // Contiguous in VA but not in PA
+        t.set(0x3000, 5, page(0x0900_2000, device));
+        t.set(0x3000, 6, page(0x4100_0000, desc::AP_EL0 | desc::PXN));
+        t
+    }
+
+    #[test]
+    fn test_dump_coalesces_runs() {
+        let t = mixed_tables();
+        let expected = "\
+0xffffff8000000000..0xffffff8000400000 -> 0x40000000 [2MB blocks] Normal RW XN=0
+0xffffff8000600000..0xffffff8000604000 -> 0x40600000 [4KB pages] Normal RO XN=1
+0xffffff8000604000..0xffffff8000605000 -> 0x9000000 [4KB pages] Device-nGnRE RW XN=1
+0xffffff8000605000..0xffffff8000606000 -> 0x9002000 [4KB pages] Device-nGnRE RW XN=1
+0xffffff8000606000..0xffffff8000607000 -> 0x41000000 [4KB pages] Normal RW XN=1 EL0 AF=0
+0xffffff8000800000..0xffffff8000a00000 -> 0x40800000 [2MB blocks] Normal RW XN=0
+0xffffff8040000000..0xffffff8080000000 -> 0x80000000 [1GB blocks] Normal RW XN=0
+";
+        assert_eq!(t.dump(0x1000, 39), expected);
+        assert_eq!(Tables::new().dump(0x1000, 39), "");
+    }
+
+    #[test]
+    fn test_translate_agrees_with_dump() {
+        let t = mixed_tables();
+        let base = ttbr1_base(39);
+
+        // Every byte of every dumped range translates at the dumped offset
+        for line in t.dump(0x1000, 39).lines() {
+            let (range, rest) = line.split_once(" -> ").unwrap();
+            let (start, end) = range.split_once("..").unwrap();
+            let start = u64::from_str_radix(&start[2..], 16).unwrap();
+            let end = u64::from_str_radix(&end[2..], 16).unwrap();
+            let phys = u64::from_str_radix(&rest[2..rest.find(' ').unwrap()], 16).unwrap();
+
+            for va in [start, start + 0x123, end - 1] {
+                let leaf = translate(0x1000, va, 39, t.reader()).unwrap();
+                assert_eq!(leaf.phys, phys + (va - start), "{:#x}", va);
+            }
+        }
+
+        let leaf = walk(0x1000, base + 0x60_3008, 39, t.reader()).unwrap();
+        assert_eq!(leaf.level, 3);
+        assert_eq!(
+            leaf.attrs(),
+            Attrs {
+                attr_index: 0,
+                read_only: true,
+                el0: false,
+                execute_never: true,
+                accessed: true,
+            }
+        );
+
+        // Holes report the level the walk stopped at
+        assert_eq!(
+            walk(0x1000, base + 0x40_0000, 39, t.reader()),
+            Err(Unmapped { level: 2 })
+        );
+        assert_eq!(
+            walk(0x1000, base + 0x60_7000, 39, t.reader()),
+            Err(Unmapped { level: 3 })
+        );
+        let unmapped = walk(0x1000, base + 0x8000_0000, 39, t.reader()).unwrap_err();
+        assert_eq!(unmapped.to_string(), "VA not mapped at level 1");
+        assert_eq!(translate(0x1000, base + 0x8000_0000, 39, t.reader()), None);
+    }
+
+    #[test]
+    fn test_walk_split_halves() {
+        let kernel = mixed_tables();
+        let base = ttbr1_base(39);
+        let mut t = Tables::new();
+        t.0.extend(kernel.0);
+        // Identity map at 0x10000: RAM at L1 index 1, as boot.S builds it
+        t.set(0x10000, 1, block(0x4000_0000, NORMAL));
+
+        assert_eq!(ttbr_for(0x4008_0000, 39), Some(Ttbr::Ttbr0));
+        assert_eq!(ttbr_for((1 << 39) - 1, 39), Some(Ttbr::Ttbr0));
+        assert_eq!(ttbr_for(1 << 39, 39), None);
+        assert_eq!(ttbr_for(base - 1, 39), None);
+        assert_eq!(ttbr_for(base, 39), Some(Ttbr::Ttbr1));
+        assert_eq!(ttbr_for(base, 48), Some(Ttbr::Ttbr1));
+        assert_eq!(ttbr_for(1 << 48, 48), None);
+        assert_eq!(Ttbr::Ttbr1.as_str(), "TTBR1");
+
+        let roots = [0x10000, 0x1000];
+        let low = walk_split(0x4008_1234, 39, roots, t.reader()).unwrap();
+        assert_eq!((low.phys, low.level), (0x4008_1234, 1));
+        let high = walk_split(base + 0x60_3008, 39, roots, t.reader()).unwrap();
+        assert_eq!((high.phys, high.level), (0x4060_3008, 3));
+
+        // The same VA bits in the other half take the other tables
+        assert_eq!(
+            walk_split(0x60_3008, 39, roots, t.reader()),
+            Err(Unmapped { level: 1 })
+        );
+        assert_eq!(
+            walk_split(0x8000_0000_0000, 39, roots, t.reader()),
+            Err(Unmapped { level: 1 })
+        );
+        assert_eq!(
+            walk_split(1 << 48, 48, roots, t.reader()),
+            Err(Unmapped { level: 0 })
+        );
+
+        let mut line = String::new();
+        write_attrs(&high.attrs(), BOOT_MAIR_EL1, &mut line).unwrap();
+        assert_eq!(line, "Normal RO XN=1");
+    }
+
+    #[test]
+    fn test_dump_va48_top_of_address_space() {
+        let mut t = Tables::new();
+        t.set(0x1000, 511, table(0x2000));
+        t.set(0x2000, 511, block(0x4000_0000, NORMAL));
+
+        let expected = "\
+0xffffffffc0000000..0x10000000000000000 -> 0x40000000 [1GB blocks] Normal RW XN=0
+";
+        assert_eq!(t.dump(0x1000, 48), expected);
+        let leaf = translate(0x1000, u64::MAX, 48, t.reader()).unwrap();
+        assert_eq!(leaf.phys, 0x7fff_ffff);
+    }
+
+    #[test]
+    fn test_memory_type_names() {
+        assert_eq!(memory_type(mair_attr(BOOT_MAIR_EL1, 0)), "Normal");
+        assert_eq!(memory_type(0x44), "Normal-NC");
+        assert_eq!(memory_type(0x00), "Device-nGnRnE");
+        assert_eq!(memory_type(0x04), "Device-nGnRE");
+        assert_eq!(memory_type(0x0c), "Device");
+        assert_eq!(memory_type(0xf0), "Normal-other");
+    }
+
+    #[test]
+    fn test_page_entry() {
+        let entry = page_entry(0x0900_0000, 4);
+        assert_eq!(entry & desc::ADDR_MASK, 0x0900_0000);
+        assert_eq!(entry & desc::TYPE_MASK, desc::PAGE);
+        assert_eq!((entry >> desc::ATTR_INDX_SHIFT) & 0b111, 4);
+        assert_ne!(entry & desc::AF, 0);
+        assert_ne!(entry & desc::PXN, 0);
+        assert_ne!(entry & desc::UXN, 0);
+    }
+
+    #[test]
+    fn test_idmap_block() {
+        use crate::arch::address::mair;
+
+        let entry = idmap_block(0x4008_1234);
+        assert_eq!(entry & desc::ADDR_MASK, 0x4000_0000);
+        assert!(is_block(entry));
+        assert_eq!(attr_index(entry), mair::IDX_NORMAL);
+        assert_ne!(entry & desc::AF, 0);
+        // The break-before-make stub runs from it
+        assert_eq!(entry & desc::PXN, 0);
+    }
+}
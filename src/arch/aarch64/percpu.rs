@@ -0,0 +1,290 @@
+//! Per-CPU data reached through TPIDR_EL1.
+//!
+//! Each CPU owns one [`PerCpuData`] block allocated from memblock when the
+//! CPU is brought up. The block's virtual address is kept in the CPU's
+//! TPIDR_EL1, so finding the local block is a single register read with no
+//! locking.
+//!
+//! Mutation only happens inside [`get_mut_with_irqs_off`], which masks IRQs
+//! for the duration of the closure so an interrupt handler on the same CPU
+//! cannot observe a half-done update. The mutable reference never outlives
+//! the closure.
+//!
+//! TPIDR_EL1 is accessed through the [`ThreadPointer`] trait; host tests
+//! substitute a plain cell for the system register.
+
+use crate::arch::irq;
+use crate::mm::memblock::{Memblock, ReservationOwner};
+use core::mem::{align_of, offset_of, size_of};
+
+/// Highest number of CPUs that can get a per-CPU block.
+pub const MAX_CPUS: usize = 8;
+
+/// Blocks are padded to this so two CPUs never share a cache line.
+pub const CACHE_LINE: u64 = 64;
+
+/// Per-CPU state.
+///
+/// `#[repr(C)]` so assembly can reach fields at the fixed offsets
+/// asserted below.
+#[repr(C)]
+#[derive(Debug)]
+pub struct PerCpuData {
+    /// Logical CPU number.
+    pub cpu_id: u64,
+    /// Timer ticks taken on this CPU.
+    pub ticks: u64,
+    /// Preemption disable depth.
+    pub preempt_count: u32,
+    /// Free for short-lived per-CPU use.
+    pub scratch: [u8; 64],
+}
+
+impl PerCpuData {
+    /// Create the initial state for `cpu_id`.
+    pub const fn new(cpu_id: u64) -> Self {
+        Self {
+            cpu_id,
+            ticks: 0,
+            preempt_count: 0,
+            scratch: [0; 64],
+        }
+    }
+}
+
+const _: () = assert!(
+    offset_of!(PerCpuData, cpu_id) == 0
+        && offset_of!(PerCpuData, ticks) == 8
+        && offset_of!(PerCpuData, preempt_count) == 16
+        && offset_of!(PerCpuData, scratch) == 20,
+    "per-CPU field offsets changed"
+);
+
+/// Bytes allocated for each CPU's block.
+pub const BLOCK_SIZE: u64 = (size_of::<PerCpuData>() as u64).next_multiple_of(CACHE_LINE);
+
+/// Alignment of each CPU's block.
+pub const BLOCK_ALIGN: u64 = CACHE_LINE;
+
+const _: () = assert!(
+    align_of::<PerCpuData>() as u64 <= BLOCK_ALIGN,
+    "per-CPU block alignment is below the type's alignment"
+);
+
+/// Access to the register holding the current CPU's block address.
+pub trait ThreadPointer {
+    /// Returns the stored block address, 0 if none was installed.
+    fn read(&self) -> u64;
+
+    /// Store `value` as the block address.
+    fn write(&self, value: u64);
+}
+
+/// TPIDR_EL1 of the running CPU.
+#[cfg(target_os = "none")]
+pub struct Tpidr;
+
+#[cfg(target_os = "none")]
+impl ThreadPointer for Tpidr {
+    fn read(&self) -> u64 {
+        let value: u64;
+        // Safety: reading TPIDR_EL1 has no side effects
+        unsafe { core::arch::asm!("mrs {}, tpidr_el1", out(reg) value, options(nomem, nostack)) };
+        value
+    }
+
+    fn write(&self, value: u64) {
+        // Safety: TPIDR_EL1 is reserved for the per-CPU block pointer
+        unsafe { core::arch::asm!("msr tpidr_el1, {}", in(reg) value, options(nostack)) };
+    }
+}
+
+/// Allocate a block from `mb` for one CPU.
+///
+/// # Returns
+/// Physical address of a [`BLOCK_SIZE`] block, tagged
+/// `ReservationOwner::PerCpu`.
+pub fn alloc_block(mb: &mut Memblock) -> Result<u64, &'static str> {
+    mb.alloc_tagged(BLOCK_SIZE, BLOCK_ALIGN, ReservationOwner::PerCpu)
+        .map_err(|e| e.as_str())
+}
+
+/// Initialize the block at `virt` for `cpu_id` and point `tp` at it.
+///
+/// # Safety
+/// `virt` must point to [`BLOCK_SIZE`] writable bytes, aligned to
+/// [`BLOCK_ALIGN`], that stay reserved for this CPU forever.
+pub unsafe fn install(tp: &impl ThreadPointer, virt: u64, cpu_id: u64) {
+    // Safety: guaranteed by the caller
+    unsafe { (virt as *mut PerCpuData).write(PerCpuData::new(cpu_id)) };
+    tp.write(virt);
+}
+
+/// Returns the block `tp` points to, if one was installed.
+pub fn current_with(tp: &impl ThreadPointer) -> Option<&'static PerCpuData> {
+    let ptr = tp.read() as *const PerCpuData;
+    // Safety: only `install` stores a non-zero value, and it requires the
+    // block to live forever
+    unsafe { ptr.as_ref() }
+}
+
+/// Run `f` on the block `tp` points to with IRQs masked.
+///
+/// # Returns
+/// The closure's result, or `None` if no block was installed.
+pub fn get_mut_with_irqs_off_with<R>(
+    tp: &impl ThreadPointer,
+    f: impl FnOnce(&mut PerCpuData) -> R,
+) -> Option<R> {
+    let flags = irq::disable_save();
+    let ptr = tp.read() as *mut PerCpuData;
+    // Safety: the block belongs to this CPU and, with IRQs masked, nothing
+    // else on it can run until the closure returns
+    let result = unsafe { ptr.as_mut() }.map(f);
+    irq::restore(flags);
+    result
+}
+
+/// Allocate and install the running CPU's block.
+///
+/// Called once per CPU as it comes up; CPU0's block is set up during
+/// `kernel_init`.
+///
+/// # Arguments
+/// * `cpu_id` - Logical number of the running CPU
+#[cfg(target_os = "none")]
+pub fn init_cpu(cpu_id: u64) -> Result<(), &'static str> {
+    use crate::arch::address::translation;
+    use crate::mm::memblock;
+
+    if cpu_id >= MAX_CPUS as u64 {
+        return Err("CPU number exceeds MAX_CPUS");
+    }
+    if Tpidr.read() != 0 {
+        return Err("per-CPU block already installed");
+    }
+
+    let phys = alloc_block(&mut memblock::lock())?;
+    let virt = translation::phys_to_virt(phys);
+    // Safety: the block was just allocated from linear-mapped RAM and is
+    // never freed
+    unsafe { install(&Tpidr, virt, cpu_id) };
+    Ok(())
+}
+
+/// Returns the running CPU's block.
+///
+/// Panics if the CPU has no block yet. The reference must not be held
+/// across [`get_mut_with_irqs_off`] on the same CPU.
+#[cfg(target_os = "none")]
+pub fn current() -> &'static PerCpuData {
+    current_with(&Tpidr).expect("per-CPU block not installed")
+}
+
+/// Run `f` on the running CPU's block with IRQs masked.
+///
+/// # Returns
+/// The closure's result, or `None` if the CPU has no block yet.
+#[cfg(target_os = "none")]
+pub fn get_mut_with_irqs_off<R>(f: impl FnOnce(&mut PerCpuData) -> R) -> Option<R> {
+    get_mut_with_irqs_off_with(&Tpidr, f)
+}
+
+/// Timer tick hook: count the tick on the running CPU.
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn tick() {
+    get_mut_with_irqs_off(|data| data.ticks += 1);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    /// Stand-in for TPIDR_EL1.
+    struct MockTpidr(Cell<u64>);
+
+    impl ThreadPointer for MockTpidr {
+        fn read(&self) -> u64 {
+            self.0.get()
+        }
+
+        fn write(&self, value: u64) {
+            self.0.set(value);
+        }
+    }
+
+    /// Leak a block-sized, block-aligned buffer.
+    fn leak_block() -> u64 {
+        #[repr(C, align(64))]
+        struct Block([u8; BLOCK_SIZE as usize]);
+        Box::leak(Box::new(Block([0xa5; BLOCK_SIZE as usize]))) as *mut Block as u64
+    }
+
+    #[test]
+    fn test_percpu_layout() {
+        assert!(size_of::<PerCpuData>() as u64 <= BLOCK_SIZE);
+        assert_eq!(BLOCK_SIZE % CACHE_LINE, 0);
+        assert_eq!(BLOCK_SIZE, 128);
+    }
+
+    #[test]
+    fn test_percpu_alloc_blocks() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x10_0000).unwrap();
+
+        let mut blocks: Vec<u64> = (0..MAX_CPUS)
+            .map(|_| alloc_block(&mut mb).unwrap())
+            .collect();
+        blocks.sort();
+
+        // Every block is cache line aligned and no two share a line
+        for pair in blocks.windows(2) {
+            assert!(pair[1] - pair[0] >= BLOCK_SIZE);
+        }
+        assert!(blocks.iter().all(|b| b % CACHE_LINE == 0));
+        assert_eq!(
+            mb.stats().reserved(ReservationOwner::PerCpu),
+            BLOCK_SIZE * MAX_CPUS as u64
+        );
+    }
+
+    #[test]
+    fn test_percpu_install_and_read_back() {
+        let tp = MockTpidr(Cell::new(0));
+        assert!(current_with(&tp).is_none());
+        assert_eq!(get_mut_with_irqs_off_with(&tp, |_| ()), None);
+
+        let virt = leak_block();
+        unsafe { install(&tp, virt, 3) };
+        assert_eq!(tp.read(), virt);
+
+        let data = current_with(&tp).unwrap();
+        assert_eq!(data.cpu_id, 3);
+        assert_eq!(data.ticks, 0);
+        assert_eq!(data.preempt_count, 0);
+        assert_eq!(data.scratch, [0; 64]);
+    }
+
+    #[test]
+    fn test_percpu_mutation_masks_irqs() {
+        let tp = MockTpidr(Cell::new(0));
+        unsafe { install(&tp, leak_block(), 0) };
+        assert!(irq::irqs_enabled());
+
+        let masked = get_mut_with_irqs_off_with(&tp, |data| {
+            data.ticks += 1;
+            data.preempt_count += 2;
+            data.scratch[0] = 0x42;
+            !irq::irqs_enabled()
+        });
+        assert_eq!(masked, Some(true));
+        assert!(irq::irqs_enabled());
+
+        let data = current_with(&tp).unwrap();
+        assert_eq!(data.ticks, 1);
+        assert_eq!(data.preempt_count, 2);
+        assert_eq!(data.scratch[0], 0x42);
+    }
+}
@@ -0,0 +1,186 @@
+//! PSCI (Power State Coordination Interface) firmware calls.
+//!
+//! PSCI calls go to firmware through either `hvc` or `smc`, depending on
+//! whether a hypervisor or secure monitor implements them. The device tree
+//! names the conduit in the `method` property of `/psci`.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// PSCI function IDs (SMC32 calling convention).
+pub mod function {
+    /// Power the whole system off.
+    pub const SYSTEM_OFF: u32 = 0x8400_0008;
+    /// Reset the whole system.
+    pub const SYSTEM_RESET: u32 = 0x8400_0009;
+}
+
+/// A PSCI call as loaded into x0-x3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call {
+    /// Function ID, passed in x0.
+    pub function: u32,
+    /// Arguments, passed in x1-x3.
+    pub args: [u64; 3],
+}
+
+impl Call {
+    /// `SYSTEM_OFF`, which takes no arguments.
+    pub const fn system_off() -> Self {
+        Self {
+            function: function::SYSTEM_OFF,
+            args: [0; 3],
+        }
+    }
+
+    /// `SYSTEM_RESET`, which takes no arguments.
+    pub const fn system_reset() -> Self {
+        Self {
+            function: function::SYSTEM_RESET,
+            args: [0; 3],
+        }
+    }
+
+    /// Returns the values of x0-x3 for the call.
+    pub fn registers(&self) -> [u64; 4] {
+        [
+            self.function as u64,
+            self.args[0],
+            self.args[1],
+            self.args[2],
+        ]
+    }
+}
+
+/// Instruction used to call PSCI firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conduit {
+    /// Hypervisor call (`hvc #0`).
+    Hvc = 1,
+    /// Secure monitor call (`smc #0`).
+    Smc = 2,
+}
+
+impl Conduit {
+    /// Parses the device tree `method` property value.
+    ///
+    /// # Returns
+    /// The conduit, or `None` for an unknown method
+    pub fn from_method(method: &str) -> Option<Self> {
+        match method {
+            "hvc" => Some(Conduit::Hvc),
+            "smc" => Some(Conduit::Smc),
+            _ => None,
+        }
+    }
+
+    /// Returns the method name as used in the device tree.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Conduit::Hvc => "hvc",
+            Conduit::Smc => "smc",
+        }
+    }
+}
+
+/// Selected conduit, zero until one is set.
+static CONDUIT: AtomicU8 = AtomicU8::new(0);
+
+/// Record the conduit to use for PSCI calls.
+pub fn set_conduit(conduit: Conduit) {
+    CONDUIT.store(conduit as u8, Ordering::Release);
+}
+
+/// Returns the selected conduit, or `None` if PSCI was not discovered.
+#[allow(dead_code)]
+pub fn conduit() -> Option<Conduit> {
+    match CONDUIT.load(Ordering::Acquire) {
+        1 => Some(Conduit::Hvc),
+        2 => Some(Conduit::Smc),
+        _ => None,
+    }
+}
+
+/// Issue `call` through `conduit`.
+///
+/// # Returns
+/// The PSCI return code from x0
+#[cfg(target_os = "none")]
+fn invoke(conduit: Conduit, call: Call) -> i64 {
+    let [mut x0, x1, x2, x3] = call.registers();
+
+    // Safety: PSCI calls only touch the argument registers; the rest of
+    // the caller-saved state is declared clobbered per SMCCC.
+    unsafe {
+        match conduit {
+            Conduit::Hvc => core::arch::asm!(
+                "hvc #0",
+                inout("x0") x0,
+                in("x1") x1,
+                in("x2") x2,
+                in("x3") x3,
+                clobber_abi("C"),
+                options(nostack)
+            ),
+            Conduit::Smc => core::arch::asm!(
+                "smc #0",
+                inout("x0") x0,
+                in("x1") x1,
+                in("x2") x2,
+                in("x3") x3,
+                clobber_abi("C"),
+                options(nostack)
+            ),
+        }
+    }
+
+    x0 as i64
+}
+
+/// Power the system off through PSCI.
+///
+/// Only returns if PSCI was not discovered or the firmware refused.
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn system_off() -> Result<(), &'static str> {
+    let conduit = conduit().ok_or("PSCI not available")?;
+    invoke(conduit, Call::system_off());
+    Err("PSCI SYSTEM_OFF returned")
+}
+
+/// Reset the system through PSCI.
+///
+/// Only returns if PSCI was not discovered or the firmware refused.
+#[cfg(target_os = "none")]
+pub fn system_reset() -> Result<(), &'static str> {
+    let conduit = conduit().ok_or("PSCI not available")?;
+    invoke(conduit, Call::system_reset());
+    Err("PSCI SYSTEM_RESET returned")
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conduit_from_method() {
+        assert_eq!(Conduit::from_method("hvc"), Some(Conduit::Hvc));
+        assert_eq!(Conduit::from_method("smc"), Some(Conduit::Smc));
+        assert_eq!(Conduit::from_method("HVC"), None);
+        assert_eq!(Conduit::from_method(""), None);
+        assert_eq!(Conduit::Smc.as_str(), "smc");
+    }
+
+    #[test]
+    fn test_call_encoding() {
+        assert_eq!(Call::system_off().registers(), [0x8400_0008, 0, 0, 0]);
+        assert_eq!(Call::system_reset().registers(), [0x8400_0009, 0, 0, 0]);
+
+        for call in [Call::system_off(), Call::system_reset()] {
+            let id = call.function;
+            // Fast call, SMC32, standard secure service owner
+            assert_eq!(id >> 31, 1);
+            assert_eq!((id >> 30) & 1, 0);
+            assert_eq!((id >> 24) & 0x3f, 4);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Typed system register accessors.
+//!
+//! [`read_sysreg!`] and [`write_sysreg!`] wrap `mrs`/`msr` so the register
+//! name is written once, as an identifier, instead of inside an assembly
+//! string. The getters below use them for the registers the kernel reads
+//! in more than one place.
+//!
+//! Host test builds expand the macros to a per-thread mock register file
+//! instead, so code built on them compiles everywhere and can be exercised
+//! with [`mock::set`].
+
+/// Read the system register `$reg` as a `u64`.
+///
+/// ```ignore
+/// let sctlr = read_sysreg!(sctlr_el1);
+/// ```
+#[cfg(target_os = "none")]
+macro_rules! read_sysreg {
+    ($reg:ident) => {{
+        let value: u64;
+        // Safety: the registers read through this macro have no read side
+        // effects
+        unsafe {
+            core::arch::asm!(
+                concat!("mrs {}, ", stringify!($reg)),
+                out(reg) value,
+                options(nomem, nostack, preserves_flags)
+            )
+        };
+        value
+    }};
+}
+
+/// Write `$value` to the system register `$reg`.
+///
+/// Must be used in an `unsafe` block: the caller is responsible for what
+/// the new value does, and for the `isb` the register may need before it
+/// takes effect.
+///
+/// ```ignore
+/// unsafe { write_sysreg!(tpidr_el1, value) };
+/// ```
+#[cfg(target_os = "none")]
+macro_rules! write_sysreg {
+    ($reg:ident, $value:expr) => {
+        core::arch::asm!(
+            concat!("msr ", stringify!($reg), ", {}"),
+            in(reg) $value as u64,
+            options(nostack, preserves_flags)
+        )
+    };
+}
+
+#[cfg(not(target_os = "none"))]
+macro_rules! read_sysreg {
+    ($reg:ident) => {
+        $crate::arch::reg::mock::read(stringify!($reg))
+    };
+}
+
+#[cfg(not(target_os = "none"))]
+macro_rules! write_sysreg {
+    ($reg:ident, $value:expr) => {
+        $crate::arch::reg::mock::write(stringify!($reg), $value as u64)
+    };
+}
+
+// Host builds only use them in this module
+#[allow(unused_imports)]
+pub(crate) use {read_sysreg, write_sysreg};
+
+/// Mock register file for host builds.
+#[cfg(not(target_os = "none"))]
+pub mod mock {
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    std::thread_local! {
+        /// Registers written so far; unwritten ones read as their reset value.
+        static REGS: RefCell<BTreeMap<&'static str, u64>> = const { RefCell::new(BTreeMap::new()) };
+    }
+
+    /// Value a register reads as before it is written.
+    fn reset_value(reg: &str) -> u64 {
+        match reg {
+            // Running at EL1
+            "currentel" => 1 << 2,
+            _ => 0,
+        }
+    }
+
+    /// Read the mock register `reg`.
+    pub fn read(reg: &'static str) -> u64 {
+        REGS.with(|regs| {
+            regs.borrow()
+                .get(reg)
+                .copied()
+                .unwrap_or_else(|| reset_value(reg))
+        })
+    }
+
+    /// Write the mock register `reg`.
+    ///
+    /// # Safety
+    /// Always safe on the host; `unsafe` to match the target macro, which
+    /// must be used in an `unsafe` block.
+    pub unsafe fn write(reg: &'static str, value: u64) {
+        REGS.with(|regs| regs.borrow_mut().insert(reg, value));
+    }
+
+    /// Set the mock register `reg` for a test.
+    #[allow(dead_code)]
+    pub fn set(reg: &'static str, value: u64) {
+        // Safety: host mock only
+        unsafe { write(reg, value) };
+    }
+}
+
+/// Read MPIDR_EL1.
+pub fn mpidr_el1() -> u64 {
+    read_sysreg!(mpidr_el1)
+}
+
+/// Returns the exception level encoded in a CurrentEL value, bits[3:2].
+pub const fn el_from_current_el(current_el: u64) -> u8 {
+    ((current_el >> 2) & 0b11) as u8
+}
+
+/// Returns the exception level the CPU runs at, from CurrentEL.
+pub fn current_el() -> u8 {
+    el_from_current_el(read_sysreg!(currentel))
+}
+
+/// Read SCTLR_EL1.
+pub fn sctlr_el1() -> u64 {
+    read_sysreg!(sctlr_el1)
+}
+
+/// Write SCTLR_EL1 and synchronize the context.
+///
+/// # Safety
+/// `value` switches the MMU, caches and alignment checking of this CPU;
+/// the caller must make sure the code and data it runs on stay reachable.
+#[allow(dead_code)]
+pub unsafe fn set_sctlr_el1(value: u64) {
+    // Safety: guaranteed by the caller
+    unsafe { write_sysreg!(sctlr_el1, value) };
+    crate::arch::barrier::isb();
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sysreg_macros() {
+        assert_eq!(read_sysreg!(tpidr_el1), 0);
+        // Safety: host mock only
+        unsafe { write_sysreg!(tpidr_el1, 0x1234u32) };
+        assert_eq!(read_sysreg!(tpidr_el1), 0x1234);
+        // Registers are kept apart
+        assert_eq!(read_sysreg!(tpidr_el0), 0);
+    }
+
+    #[test]
+    fn test_current_el_decode() {
+        for el in 0..4 {
+            assert_eq!(el_from_current_el(el << 2), el as u8);
+        }
+        // RES0 bits around the field are ignored
+        assert_eq!(el_from_current_el(0xffff_fff3 | (1 << 2)), 1);
+        assert_eq!(el_from_current_el(0b1000 | 0b11), 2);
+    }
+
+    #[test]
+    fn test_typed_getters() {
+        assert_eq!(current_el(), 1);
+        mock::set("currentel", 2 << 2);
+        assert_eq!(current_el(), 2);
+
+        mock::set("mpidr_el1", 0x8000_0003);
+        assert_eq!(mpidr_el1(), 0x8000_0003);
+
+        // Safety: host mock only
+        unsafe { set_sctlr_el1(0x30d0_198d) };
+        assert_eq!(sctlr_el1(), 0x30d0_198d);
+    }
+}
@@ -0,0 +1,227 @@
+//! ARM PL031 real-time clock.
+//!
+//! QEMU virt has a PL031 at [`RTC_BASE`] whose data register counts
+//! seconds since the Unix epoch, seeded from the host clock. It is only
+//! read, to put wall-clock time in the boot banner and panic output so
+//! kernel logs can be lined up with host-side timestamps.
+//!
+//! [`init`] checks the PrimeCell and peripheral ID registers before the
+//! clock is trusted; until then, or if they do not match a PL031,
+//! [`read_epoch`] returns `None`.
+//!
+//! [`RTC_BASE`]: crate::arch::address::virt::RTC_BASE
+
+use core::fmt;
+#[cfg(target_os = "none")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// PL031 register offsets.
+pub mod registers {
+    /// Data register, current counter value.
+    pub const DR: usize = 0x000;
+    /// First of four peripheral ID registers, one byte each.
+    pub const PERIPH_ID0: usize = 0xfe0;
+    /// First of four PrimeCell ID registers, one byte each.
+    pub const PCELL_ID0: usize = 0xff0;
+}
+
+/// Part number in the peripheral ID of a PL031.
+pub const PL031_PART: u32 = 0x031;
+
+/// Designer code in the peripheral ID of ARM parts.
+pub const ARM_DESIGNER: u32 = 0x41;
+
+/// PrimeCell ID bytes every PrimeCell peripheral reports.
+pub const PCELL_ID: [u32; 4] = [0x0d, 0xf0, 0x05, 0xb1];
+
+/// Check the ID registers read from a candidate PL031.
+///
+/// Only the low byte of each register is defined. The revision in
+/// peripheral ID 2 is not checked.
+///
+/// # Arguments
+/// * `periph_id` - Peripheral ID registers 0 to 3
+/// * `pcell_id` - PrimeCell ID registers 0 to 3
+pub fn check_id(periph_id: [u32; 4], pcell_id: [u32; 4]) -> Result<(), &'static str> {
+    if pcell_id.map(|b| b & 0xff) != PCELL_ID {
+        return Err("not a PrimeCell peripheral");
+    }
+    let [id0, id1, id2, _] = periph_id.map(|b| b & 0xff);
+    let part = id0 | (id1 & 0xf) << 8;
+    let designer = id1 >> 4 | (id2 & 0xf) << 4;
+    if part != PL031_PART || designer != ARM_DESIGNER {
+        return Err("not a PL031");
+    }
+    Ok(())
+}
+
+/// A UTC calendar date and time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u64,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Convert seconds since 1970-01-01 00:00:00 UTC to a calendar date.
+    ///
+    /// Integer only, proleptic Gregorian: leap years are every fourth,
+    /// except centuries not divisible by 400. Leap seconds do not exist
+    /// in epoch time and are not modeled.
+    pub const fn from_epoch(epoch: u64) -> Self {
+        let days = epoch / 86_400;
+        let secs = epoch % 86_400;
+
+        // Count from 0000-03-01 so a leap day is always the last day of a
+        // year; it lies 719_468 days before the epoch
+        let days = days + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        // Months from March, each 153 days per five
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Write `epoch` as `YYYY-MM-DD HH:MM:SS UTC`.
+///
+/// # Arguments
+/// * `epoch` - Seconds since the Unix epoch
+/// * `out` - Sink for the text
+pub fn format_datetime(epoch: u64, out: &mut dyn fmt::Write) -> fmt::Result {
+    write!(out, "{}", DateTime::from_epoch(epoch))
+}
+
+/// Virtual base of the validated RTC, 0 until [`init`] accepts it.
+#[cfg(target_os = "none")]
+static RTC_VIRT: AtomicU64 = AtomicU64::new(0);
+
+/// Read the 32-bit register at `offset` from the RTC at `base`.
+#[cfg(target_os = "none")]
+fn read_reg(base: u64, offset: usize) -> u32 {
+    // Safety: `base` maps the PL031 register window, which is 4KB
+    unsafe { core::ptr::read_volatile((base as usize + offset) as *const u32) }
+}
+
+/// Validate the RTC through the boot device mapping.
+#[cfg(target_os = "none")]
+fn init() -> Result<(), &'static str> {
+    use crate::arch::address::{translation::phys_to_virt, virt};
+
+    let base = phys_to_virt(virt::RTC_BASE);
+    let id = |first: usize| core::array::from_fn(|i| read_reg(base, first + 4 * i));
+    check_id(id(registers::PERIPH_ID0), id(registers::PCELL_ID0)).map_err(|_| "RTC unavailable")?;
+    RTC_VIRT.store(base, Ordering::Release);
+    Ok(())
+}
+
+initcall!(Early, "rtc", init, optional);
+
+/// Returns seconds since the Unix epoch, `None` if there is no usable
+/// RTC.
+#[cfg(target_os = "none")]
+pub fn read_epoch() -> Option<u64> {
+    match RTC_VIRT.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(read_reg(base, registers::DR) as u64),
+    }
+}
+
+/// Write the current wall-clock time, or "RTC unavailable".
+#[cfg(target_os = "none")]
+pub fn write_now(out: &mut dyn fmt::Write) -> fmt::Result {
+    match read_epoch() {
+        Some(epoch) => format_datetime(epoch, out),
+        None => out.write_str("RTC unavailable"),
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn datetime(epoch: u64) -> String {
+        let mut out = String::new();
+        format_datetime(epoch, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_format_datetime() {
+        assert_eq!(datetime(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(datetime(86_399), "1970-01-01 23:59:59 UTC");
+        assert_eq!(datetime(1_000_000_000), "2001-09-09 01:46:40 UTC");
+        assert_eq!(datetime(1_792_108_800), "2026-10-16 00:00:00 UTC");
+    }
+
+    #[test]
+    fn test_leap_days() {
+        assert_eq!(datetime(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(datetime(951_868_800), "2000-03-01 00:00:00 UTC");
+        assert_eq!(datetime(1_709_164_800), "2024-02-29 00:00:00 UTC");
+        // 2100 is not a leap year
+        assert_eq!(datetime(4_107_456_000), "2100-02-28 00:00:00 UTC");
+        assert_eq!(datetime(4_107_542_400), "2100-03-01 00:00:00 UTC");
+        // Last second of 2099
+        assert_eq!(datetime(4_102_444_799), "2099-12-31 23:59:59 UTC");
+    }
+
+    #[test]
+    fn test_2038_boundary() {
+        // Where a signed 32-bit time_t overflows
+        assert_eq!(datetime(0x7fff_ffff), "2038-01-19 03:14:07 UTC");
+        assert_eq!(datetime(0x8000_0000), "2038-01-19 03:14:08 UTC");
+        // Largest PL031 counter value
+        assert_eq!(datetime(0xffff_ffff), "2106-02-07 06:28:15 UTC");
+    }
+
+    #[test]
+    fn test_check_id() {
+        // As QEMU's PL031 reports them
+        let periph = [0x31, 0x10, 0x14, 0x00];
+        assert_eq!(check_id(periph, PCELL_ID), Ok(()));
+        // Upper bits of each register are not part of the ID
+        assert_eq!(check_id(periph.map(|b| b | 0xff00), PCELL_ID), Ok(()));
+        // A PL011 UART's ID, or nothing at all
+        assert_eq!(
+            check_id([0x11, 0x10, 0x14, 0x00], PCELL_ID),
+            Err("not a PL031")
+        );
+        assert_eq!(check_id([0; 4], [0; 4]), Err("not a PrimeCell peripheral"));
+        // Right part from another designer
+        assert_eq!(
+            check_id([0x31, 0x00, 0x14, 0x00], PCELL_ID),
+            Err("not a PL031")
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Exiting QEMU with a status code, and console output, through
+//! semihosting.
+//!
+//! PSCI `SYSTEM_OFF` always makes QEMU exit with status 0. With the
+//! `semihosting_exit` feature, [`exit`] instead issues the AArch64
+//! semihosting `SYS_EXIT` call (`hlt #0xf000`) with an
+//! `ADP_Stopped_ApplicationExit` parameter block, which QEMU turns into
+//! its own exit status. QEMU only honours the call when started with
+//!
+//! ```text
+//! qemu-system-aarch64 ... -semihosting-config enable=on,target=native
+//! ```
+//!
+//! Without that option the `hlt` traps as an undefined instruction and
+//! the exception handler reports it. Without the feature, [`exit`] falls
+//! back to PSCI and the code is lost.
+//!
+//! [`write_str`] prints through `SYS_WRITE0`, for the semihosting
+//! console backend. It needs the same QEMU option but no feature, since
+//! nothing calls it unless `console=semihosting` selects it.
+
+/// Semihosting operation number of `SYS_WRITE0`, passed in w0.
+pub const SYS_WRITE0: u64 = 0x04;
+
+/// Semihosting operation number of `SYS_EXIT`, passed in w0.
+#[cfg(any(feature = "semihosting_exit", test))]
+pub const SYS_EXIT: u64 = 0x18;
+
+/// Bytes of text passed per `SYS_WRITE0` call, before the NUL.
+pub const WRITE0_CHUNK: usize = 63;
+
+/// Reason code for a normal application exit.
+#[cfg(any(feature = "semihosting_exit", test))]
+pub const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x2_0026;
+
+/// Returns the `SYS_EXIT` parameter block for exit status `code`.
+///
+/// On AArch64 x1 points at two 64-bit words: the reason code and the
+/// status the host should exit with.
+#[cfg(any(feature = "semihosting_exit", test))]
+pub const fn exit_block(code: u32) -> [u64; 2] {
+    [ADP_STOPPED_APPLICATION_EXIT, code as u64]
+}
+
+/// Copy the start of `bytes` into `buf` as a NUL-terminated string.
+///
+/// `SYS_WRITE0` stops at the first NUL, so NULs in `bytes` are dropped.
+///
+/// # Returns
+/// The number of bytes of `bytes` consumed, at most [`WRITE0_CHUNK`]
+pub fn fill_write0(bytes: &[u8], buf: &mut [u8; WRITE0_CHUNK + 1]) -> usize {
+    let consumed = bytes.len().min(WRITE0_CHUNK);
+    let mut len = 0;
+    for &byte in bytes[..consumed].iter().filter(|&&b| b != 0) {
+        buf[len] = byte;
+        len += 1;
+    }
+    buf[len] = 0;
+    consumed
+}
+
+/// Issue semihosting call `op` with parameter `arg`.
+///
+/// # Safety
+/// `arg` must be what `op` expects; for a pointer, the memory it points
+/// to must be valid for the call.
+#[cfg(target_os = "none")]
+unsafe fn call(op: u64, arg: u64) -> u64 {
+    let ret: u64;
+    // Safety: the host only reads the parameter, per the caller
+    unsafe {
+        core::arch::asm!(
+            "hlt #0xf000",
+            inout("x0") op => ret,
+            in("x1") arg,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Print `s` on the host console with `SYS_WRITE0`.
+#[cfg(target_os = "none")]
+pub fn write_str(s: &str) {
+    let mut bytes = s.as_bytes();
+    let mut buf = [0; WRITE0_CHUNK + 1];
+    while !bytes.is_empty() {
+        let consumed = fill_write0(bytes, &mut buf);
+        // Safety: `buf` is NUL-terminated and lives across the call
+        unsafe { call(SYS_WRITE0, buf.as_ptr() as u64) };
+        bytes = &bytes[consumed..];
+    }
+}
+
+/// Stop the machine, exiting QEMU with status `code`.
+///
+/// Powers off through PSCI without the `semihosting_exit` feature, and
+/// parks the CPU if that fails too.
+///
+/// # Arguments
+/// * `code` - Exit status, truncated to 8 bits by the host
+#[cfg(target_os = "none")]
+pub fn exit(code: u32) -> ! {
+    use crate::arch::{idle, psci, serial};
+    use core::fmt::Write;
+
+    let _ = writeln!(serial::Writer, "Exiting with status {}", code);
+
+    #[cfg(feature = "semihosting_exit")]
+    {
+        let block = exit_block(code);
+        // Safety: the host reads the block and stops the machine; the
+        // block outlives the call since we do not return on success
+        unsafe { call(SYS_EXIT, block.as_ptr() as u64) };
+        serial::write_str("Semihosting exit returned\n");
+    }
+
+    if let Err(e) = psci::system_off() {
+        let _ = writeln!(serial::Writer, "Power off failed: {}", e);
+    }
+    idle::park_forever()
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_block() {
+        assert_eq!(exit_block(0), [0x2_0026, 0]);
+        assert_eq!(exit_block(0x41), [0x2_0026, 0x41]);
+        assert_eq!(exit_block(u32::MAX)[1], 0xffff_ffff);
+    }
+
+    #[test]
+    fn test_fill_write0() {
+        let mut buf = [0xff; WRITE0_CHUNK + 1];
+        assert_eq!(fill_write0(b"boot\n", &mut buf), 5);
+        assert_eq!(&buf[..6], b"boot\n\0");
+
+        // NULs would end the string early and are dropped
+        assert_eq!(fill_write0(b"a\0b", &mut buf), 3);
+        assert_eq!(&buf[..3], b"ab\0");
+
+        // Long text is split, each chunk terminated
+        let long = [b'x'; WRITE0_CHUNK + 10];
+        assert_eq!(fill_write0(&long, &mut buf), WRITE0_CHUNK);
+        assert_eq!(buf[WRITE0_CHUNK], 0);
+        assert_eq!(fill_write0(&long[WRITE0_CHUNK..], &mut buf), 10);
+        assert_eq!(buf[10], 0);
+    }
+}
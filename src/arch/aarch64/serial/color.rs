@@ -0,0 +1,176 @@
+//! Colored message level prefixes.
+//!
+//! [`write_prefix`] starts a console line with its level, wrapped in ANSI
+//! color codes when color is on: red for errors, yellow for warnings, dim
+//! for debug output and the terminal default for info. The reset sequence
+//! always follows the level name, so a line cut short by a crash does not
+//! leave the terminal colored.
+//!
+//! Color is on by default; `nocolor` on the kernel command line or
+//! [`set_color`] turns it off. Panic output is always red.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Kernel command line flag turning color off.
+pub const NOCOLOR_FLAG: &str = "nocolor";
+
+/// Resets all attributes.
+pub const RESET: &str = "\x1b[0m";
+
+/// Severity of a console message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Level {
+    /// Something failed.
+    Error,
+    /// Something looks wrong but boot goes on.
+    Warn,
+    /// Normal progress.
+    Info,
+    /// Detail for debugging.
+    Debug,
+}
+
+impl Level {
+    /// Returns the level name as printed in the prefix.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+        }
+    }
+
+    /// Returns the escape sequence selecting the level's color, empty for
+    /// the terminal default.
+    pub const fn ansi(&self) -> &'static str {
+        match self {
+            Self::Error => "\x1b[31m",
+            Self::Warn => "\x1b[33m",
+            Self::Info => "",
+            Self::Debug => "\x1b[2m",
+        }
+    }
+}
+
+/// Whether prefixes are colored.
+static COLOR: AtomicBool = AtomicBool::new(true);
+
+/// Turn colored prefixes on or off.
+#[allow(dead_code)]
+pub fn set_color(enabled: bool) {
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns true if prefixes are colored.
+#[allow(dead_code)]
+pub fn color_enabled() -> bool {
+    COLOR.load(Ordering::Relaxed)
+}
+
+/// Returns false if `cmdline` turns color off.
+#[allow(dead_code)]
+pub fn color_from_cmdline(cmdline: &str) -> bool {
+    !cmdline
+        .split_ascii_whitespace()
+        .any(|arg| arg == NOCOLOR_FLAG)
+}
+
+/// Write the prefix of a console line: `LEVEL module: `.
+///
+/// The crate name is dropped from `module`, an empty path is left out.
+///
+/// # Arguments
+/// * `out` - Destination
+/// * `level` - Message level
+/// * `color` - Wrap the level in its color and a reset
+/// * `module` - Module path, as from `module_path!()`
+pub fn write_prefix(
+    out: &mut dyn fmt::Write,
+    level: Level,
+    color: bool,
+    module: &str,
+) -> fmt::Result {
+    if color {
+        write!(out, "{}{}{}", level.ansi(), level.as_str(), RESET)?;
+    } else {
+        out.write_str(level.as_str())?;
+    }
+
+    let module = module.split_once("::").map_or("", |(_, rest)| rest);
+    if module.is_empty() {
+        out.write_str(": ")
+    } else {
+        write!(out, " {}: ", module)
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const ALL: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+    fn prefix(level: Level, color: bool, module: &str) -> String {
+        let mut out = String::new();
+        write_prefix(&mut out, level, color, module).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_prefix_plain() {
+        let plain: Vec<_> = ALL
+            .iter()
+            .map(|&level| prefix(level, false, "phoenix::mm::memblock"))
+            .collect();
+        assert_eq!(
+            plain,
+            [
+                "ERROR mm::memblock: ",
+                "WARN mm::memblock: ",
+                "INFO mm::memblock: ",
+                "DEBUG mm::memblock: ",
+            ]
+        );
+        assert!(plain.iter().all(|p| !p.contains('\x1b')));
+    }
+
+    #[test]
+    fn test_prefix_color() {
+        assert_eq!(
+            prefix(Level::Error, true, "phoenix::arch"),
+            "\x1b[31mERROR\x1b[0m arch: "
+        );
+        assert_eq!(
+            prefix(Level::Warn, true, "phoenix::arch"),
+            "\x1b[33mWARN\x1b[0m arch: "
+        );
+        assert_eq!(
+            prefix(Level::Debug, true, "phoenix::arch"),
+            "\x1b[2mDEBUG\x1b[0m arch: "
+        );
+        // Info keeps the default color but still resets
+        assert_eq!(
+            prefix(Level::Info, true, "phoenix::arch"),
+            "INFO\x1b[0m arch: "
+        );
+
+        for level in ALL {
+            let p = prefix(level, true, "phoenix");
+            assert!(
+                p.ends_with(&format!("{}{}: ", level.as_str(), RESET)),
+                "{:?}",
+                p
+            );
+        }
+    }
+
+    #[test]
+    fn test_nocolor_flag() {
+        assert!(color_from_cmdline(""));
+        assert!(color_from_cmdline("console nocolors"));
+        assert!(!color_from_cmdline("console nocolor"));
+    }
+}
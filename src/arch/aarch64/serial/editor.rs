@@ -0,0 +1,329 @@
+//! Line editor for interactive serial input.
+//!
+//! Turns received bytes into complete lines, echoing edits back to the
+//! terminal. Supports backspace, ctrl-U to kill the line and up/down arrow
+//! recall of recent lines. Arrow keys arrive as ANSI escape sequences that
+//! may be split across reads, so the decoder keeps its state between
+//! bytes.
+
+/// Longest accepted input line; further characters are dropped.
+pub const LINE_MAX: usize = 64;
+
+/// Number of previous lines kept for recall.
+pub const HISTORY_LEN: usize = 8;
+
+/// Escape sequence decoder state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    /// Not inside a sequence.
+    None,
+    /// Got ESC.
+    Start,
+    /// Got `ESC [` or `ESC O`, waiting for the final byte.
+    Sequence,
+}
+
+/// One stored line.
+#[derive(Clone, Copy)]
+struct Line {
+    buf: [u8; LINE_MAX],
+    len: usize,
+}
+
+impl Line {
+    const EMPTY: Self = Self {
+        buf: [0; LINE_MAX],
+        len: 0,
+    };
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Collects input bytes into lines, with history.
+pub struct LineEditor {
+    line: Line,
+    /// The previous call returned a line, clear it before editing.
+    done: bool,
+    /// The previous byte ended a line with `\r`.
+    after_cr: bool,
+    escape: Escape,
+    /// Recent lines, `history[(next - 1) % HISTORY_LEN]` is the newest.
+    history: [Line; HISTORY_LEN],
+    /// Index of the next history slot to write.
+    next: usize,
+    /// Number of valid history entries.
+    count: usize,
+    /// How far back the recalled line is, 1 for the newest.
+    browse: usize,
+}
+
+impl LineEditor {
+    /// Create an editor with an empty line and no history.
+    pub const fn new() -> Self {
+        Self {
+            line: Line::EMPTY,
+            done: false,
+            after_cr: false,
+            escape: Escape::None,
+            history: [Line::EMPTY; HISTORY_LEN],
+            next: 0,
+            count: 0,
+            browse: 0,
+        }
+    }
+
+    /// Returns the number of lines available for recall.
+    #[cfg(test)]
+    pub fn history_len(&self) -> usize {
+        self.count
+    }
+
+    /// Feed one received byte.
+    ///
+    /// # Arguments
+    /// * `byte` - Byte read from the serial port
+    /// * `echo` - Sink for bytes to echo back to the terminal
+    ///
+    /// # Returns
+    /// The complete line once enter is pressed, without the line ending.
+    /// Lines that are not valid UTF-8 are returned as "".
+    pub fn feed(&mut self, byte: u8, echo: &mut impl FnMut(u8)) -> Option<&str> {
+        if core::mem::take(&mut self.done) {
+            self.line.len = 0;
+        }
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+
+        match self.escape {
+            Escape::Start => {
+                self.escape = match byte {
+                    b'[' | b'O' => Escape::Sequence,
+                    _ => Escape::None,
+                };
+                return None;
+            }
+            Escape::Sequence => {
+                // Parameter and intermediate bytes come before the final byte
+                if (0x40..=0x7e).contains(&byte) {
+                    self.escape = Escape::None;
+                    match byte {
+                        b'A' => self.recall(self.browse + 1, echo),
+                        b'B' => self.recall(self.browse.saturating_sub(1), echo),
+                        _ => {}
+                    }
+                }
+                return None;
+            }
+            Escape::None => {}
+        }
+
+        match byte {
+            // Terminals send "\r", "\n" or "\r\n"; count the pair once
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => {
+                echo(b'\r');
+                echo(b'\n');
+                self.browse = 0;
+                self.done = true;
+                self.remember();
+                Some(core::str::from_utf8(self.line.as_bytes()).unwrap_or(""))
+            }
+            0x1b => {
+                self.escape = Escape::Start;
+                None
+            }
+            // Backspace and delete
+            0x08 | 0x7f => {
+                if self.line.len > 0 {
+                    self.line.len -= 1;
+                    erase(1, echo);
+                }
+                None
+            }
+            // Ctrl-U
+            0x15 => {
+                erase(self.line.len, echo);
+                self.line.len = 0;
+                None
+            }
+            0x20..=0x7e => {
+                if self.line.len < LINE_MAX {
+                    self.line.buf[self.line.len] = byte;
+                    self.line.len += 1;
+                    echo(byte);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Add the current line to the history, skipping blanks and repeats.
+    fn remember(&mut self) {
+        let line = self.line.as_bytes();
+        if line.iter().all(|b| *b == b' ') {
+            return;
+        }
+        if self.count > 0 && self.entry(1).as_bytes() == line {
+            return;
+        }
+        self.history[self.next] = self.line;
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.count = (self.count + 1).min(HISTORY_LEN);
+    }
+
+    /// Returns the history entry `back` lines ago, 1 for the newest.
+    fn entry(&self, back: usize) -> &Line {
+        &self.history[(self.next + HISTORY_LEN - back) % HISTORY_LEN]
+    }
+
+    /// Replace the line with the entry `back` lines ago, 0 for an empty line.
+    fn recall(&mut self, back: usize, echo: &mut impl FnMut(u8)) {
+        let back = back.min(self.count);
+        if back == self.browse {
+            return;
+        }
+        self.browse = back;
+
+        erase(self.line.len, echo);
+        self.line = if back == 0 {
+            Line::EMPTY
+        } else {
+            *self.entry(back)
+        };
+        self.line.as_bytes().iter().for_each(|&b| echo(b));
+    }
+}
+
+/// Echo the sequence that erases the last `count` characters.
+fn erase(count: usize, echo: &mut impl FnMut(u8)) {
+    for _ in 0..count {
+        b"\x08 \x08".iter().for_each(|&b| echo(b));
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const UP: &[u8] = b"\x1b[A";
+    const DOWN: &[u8] = b"\x1b[B";
+
+    /// Feed `input` through `editor`, returning the lines and echo.
+    fn run(editor: &mut LineEditor, input: &[u8]) -> (Vec<String>, Vec<u8>) {
+        let mut echo = Vec::new();
+        let mut lines = Vec::new();
+        for &b in input {
+            if let Some(line) = editor.feed(b, &mut |e| echo.push(e)) {
+                lines.push(line.to_string());
+            }
+        }
+        (lines, echo)
+    }
+
+    /// Lines produced by a fresh editor.
+    fn fresh_lines(input: &[u8]) -> Vec<String> {
+        run(&mut LineEditor::new(), input).0
+    }
+
+    #[test]
+    fn test_editor_lines() {
+        let mut editor = LineEditor::new();
+        let (lines, echo) = run(&mut editor, b"mem\rstats\r\n\nx\n");
+        assert_eq!(lines, ["mem", "stats", "", "x"]);
+        assert_eq!(echo, b"mem\r\nstats\r\n\r\nx\r\n");
+    }
+
+    #[test]
+    fn test_editor_backspace_and_kill() {
+        let mut editor = LineEditor::new();
+        let (lines, echo) = run(&mut editor, b"ab\x7fc\r");
+        assert_eq!(lines, ["ac"]);
+        assert_eq!(echo, b"ab\x08 \x08c\r\n");
+
+        // Ctrl-U erases everything typed so far
+        let (lines, echo) = run(&mut editor, b"rd 0x1\x15mem\r");
+        assert_eq!(lines, ["mem"]);
+        assert_eq!(&echo[..6], b"rd 0x1");
+        assert_eq!(&echo[6..24], b"\x08 \x08".repeat(6).as_slice());
+        assert_eq!(&echo[24..], b"mem\r\n");
+
+        // Editing an empty line echoes nothing
+        let (lines, echo) = run(&mut editor, b"\x08\x15\r");
+        assert_eq!(lines, [""]);
+        assert_eq!(echo, b"\r\n");
+    }
+
+    #[test]
+    fn test_editor_truncates_and_filters() {
+        let mut long = vec![b'a'; LINE_MAX + 8];
+        long.push(b'\r');
+        let (lines, echo) = run(&mut LineEditor::new(), &long);
+        assert_eq!(lines, ["a".repeat(LINE_MAX)]);
+        assert_eq!(echo.len(), LINE_MAX + 2);
+
+        // Other control characters are dropped
+        assert_eq!(fresh_lines(b"\x01m\x02em\r"), ["mem"]);
+    }
+
+    #[test]
+    fn test_editor_history_recall() {
+        let mut editor = LineEditor::new();
+        run(&mut editor, b"one\rtwo\rthree\r");
+        assert_eq!(editor.history_len(), 3);
+
+        // Up twice recalls "two", enter submits it
+        let input = [UP, UP, b"\r"].concat();
+        let (lines, echo) = run(&mut editor, &input);
+        assert_eq!(lines, ["two"]);
+        let mut expected = b"three".to_vec();
+        expected.extend(b"\x08 \x08".repeat(5));
+        expected.extend(b"two\r\n");
+        assert_eq!(echo, expected);
+
+        // Up past the oldest stays on it, down past the newest clears
+        let input = [UP, UP, UP, UP, UP, b"\r"].concat();
+        assert_eq!(run(&mut editor, &input).0, ["one"]);
+        let input = [UP, DOWN, b"x\r"].concat();
+        assert_eq!(run(&mut editor, &input).0, ["x"]);
+    }
+
+    #[test]
+    fn test_editor_history_skips_blank_and_repeat() {
+        let mut editor = LineEditor::new();
+        run(&mut editor, b"mem\rmem\r\r   \r");
+        assert_eq!(editor.history_len(), 1);
+    }
+
+    #[test]
+    fn test_editor_history_wraps() {
+        let mut editor = LineEditor::new();
+        for i in 0..HISTORY_LEN + 3 {
+            run(&mut editor, format!("cmd{}\r", i).as_bytes());
+        }
+        assert_eq!(editor.history_len(), HISTORY_LEN);
+
+        // The oldest kept line is three past the first one typed
+        let mut input = UP.repeat(HISTORY_LEN + 2);
+        input.push(b'\r');
+        assert_eq!(run(&mut editor, &input).0, ["cmd3"]);
+    }
+
+    #[test]
+    fn test_editor_split_escape_sequence() {
+        let mut editor = LineEditor::new();
+        run(&mut editor, b"stats\r");
+
+        // The arrow arrives one byte per read
+        for &b in UP {
+            assert_eq!(run(&mut editor, &[b]).0, Vec::<String>::new());
+        }
+        assert_eq!(run(&mut editor, b"\r").0, ["stats"]);
+
+        // SS3 arrows, parameters and unknown sequences are consumed whole
+        assert_eq!(fresh_lines(b"a\x1bOAb\r"), ["ab"]);
+        assert_eq!(fresh_lines(b"a\x1b[1;5Cb\r"), ["ab"]);
+        assert_eq!(fresh_lines(b"a\x1bxb\r"), ["ab"]);
+    }
+}
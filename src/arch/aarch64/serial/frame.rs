@@ -0,0 +1,461 @@
+//! Framed, binary-safe messages over the serial console.
+//!
+//! Lets a host-side harness pick structured records (test results, boot
+//! stages, failures) out of the console stream without scraping text.
+//! Frames are self-delimiting, so they can be interleaved with normal
+//! output. On the wire a frame is
+//!
+//! ```text
+//! 0x7e | kind | len (u16 LE) | payload | crc16 (u16 LE) | 0x7e
+//! ```
+//!
+//! Everything between the two flags is byte-stuffed: `0x7e` and `0x7d` are
+//! sent as `0x7d, byte ^ 0x20`. The CRC is CRC-16/CCITT-FALSE over the
+//! unstuffed kind, length and payload.
+
+/// Frame delimiter.
+pub const FLAG: u8 = 0x7e;
+/// Escape byte for stuffing.
+pub const ESCAPE: u8 = 0x7d;
+/// XOR applied to an escaped byte.
+pub const ESCAPE_XOR: u8 = 0x20;
+
+/// Largest payload a frame can carry.
+pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+/// What a frame's payload describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// Status byte (0 pass, 1 fail) followed by the test name.
+    TestResult = 1,
+    /// Name of the boot stage being entered.
+    BootStage = 2,
+    /// Description of an unrecoverable failure.
+    PanicReport = 3,
+}
+
+impl FrameKind {
+    /// Returns the kind encoded as `byte`, if it is known.
+    #[cfg(not(target_os = "none"))]
+    pub fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Self::TestResult),
+            2 => Some(Self::BootStage),
+            3 => Some(Self::PanicReport),
+            _ => None,
+        }
+    }
+}
+
+/// CRC-16/CCITT-FALSE (poly 0x1021, initial value 0xffff) of `data`,
+/// continuing from `crc`.
+pub fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Emit `byte` through `out`, escaping it if it is special.
+fn put_stuffed(byte: u8, out: &mut impl FnMut(u8)) {
+    if byte == FLAG || byte == ESCAPE {
+        out(ESCAPE);
+        out(byte ^ ESCAPE_XOR);
+    } else {
+        out(byte);
+    }
+}
+
+/// Encode a frame through `out`.
+///
+/// # Arguments
+/// * `kind` - Frame kind
+/// * `payload` - Up to [`MAX_PAYLOAD`] bytes
+/// * `out` - Byte sink
+pub fn encode(
+    kind: FrameKind,
+    payload: &[u8],
+    out: &mut impl FnMut(u8),
+) -> Result<(), &'static str> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err("frame payload too large");
+    }
+
+    let header = [kind as u8, payload.len() as u8, (payload.len() >> 8) as u8];
+    let crc = crc16(crc16(0xffff, &header), payload);
+
+    out(FLAG);
+    for &byte in header.iter().chain(payload).chain(&crc.to_le_bytes()) {
+        put_stuffed(byte, out);
+    }
+    out(FLAG);
+
+    Ok(())
+}
+
+/// Send a frame over the global serial instance.
+///
+/// # Arguments
+/// * `kind` - Frame kind
+/// * `payload` - Up to [`MAX_PAYLOAD`] bytes
+pub fn send_frame(kind: FrameKind, payload: &[u8]) -> Result<(), &'static str> {
+    encode(kind, payload, &mut |b| super::write_byte(b))
+}
+
+/// Send a `TestResult` frame.
+///
+/// # Arguments
+/// * `name` - Test name
+/// * `passed` - Whether the test passed
+pub fn send_test_result(name: &str, passed: bool) {
+    let mut buf = FrameBuf::new();
+    buf.push(if passed { 0 } else { 1 });
+    buf.extend(name.as_bytes());
+    let _ = send_frame(FrameKind::TestResult, buf.as_bytes());
+}
+
+/// Fixed-size payload buffer, for frames assembled from several parts.
+///
+/// Writes past the end are dropped, so formatted text is truncated rather
+/// than lost.
+pub struct FrameBuf {
+    data: [u8; FrameBuf::CAPACITY],
+    len: usize,
+}
+
+impl FrameBuf {
+    /// Bytes the buffer can hold.
+    pub const CAPACITY: usize = 256;
+
+    /// Create an empty buffer.
+    pub const fn new() -> Self {
+        Self {
+            data: [0; Self::CAPACITY],
+            len: 0,
+        }
+    }
+
+    /// Append one byte, dropping it if the buffer is full.
+    pub fn push(&mut self, byte: u8) {
+        if self.len < Self::CAPACITY {
+            self.data[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Append as much of `bytes` as fits.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        bytes.iter().for_each(|&b| self.push(b));
+    }
+
+    /// Returns the buffered bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl core::fmt::Write for FrameBuf {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.extend(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// A frame parsed by [`Decoder`].
+#[cfg(not(target_os = "none"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Frame kind.
+    pub kind: FrameKind,
+    /// Unstuffed payload.
+    pub payload: Vec<u8>,
+}
+
+/// Reasons a delimited frame is rejected by [`Decoder`].
+#[cfg(not(target_os = "none"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Fewer bytes than a header and CRC.
+    TooShort,
+    /// The length field disagrees with the bytes received.
+    BadLength,
+    /// The CRC does not match.
+    BadCrc,
+    /// The kind byte is not a known [`FrameKind`].
+    UnknownKind(u8),
+    /// An escape byte was followed by a flag.
+    BadEscape,
+}
+
+/// Host-side frame decoder for test harnesses.
+///
+/// Bytes outside frames (normal console text) are skipped. If a flag that
+/// was taken as the end of a frame yields garbage, it is treated as the
+/// start of the next frame instead, so a stray `~` in console text costs
+/// at most one reported error.
+#[cfg(not(target_os = "none"))]
+#[derive(Debug, Default)]
+pub struct Decoder {
+    in_frame: bool,
+    escaped: bool,
+    buf: Vec<u8>,
+}
+
+#[cfg(not(target_os = "none"))]
+impl Decoder {
+    /// Create a decoder waiting for the first flag.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed received bytes.
+    ///
+    /// Frames may be split across any number of calls.
+    ///
+    /// # Returns
+    /// Every frame completed by `data`, in order, and the errors for any
+    /// rejected ones
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Result<Frame, DecodeError>> {
+        let mut frames = Vec::new();
+        for &byte in data {
+            if let Some(result) = self.feed_byte(byte) {
+                frames.push(result);
+            }
+        }
+        frames
+    }
+
+    fn feed_byte(&mut self, byte: u8) -> Option<Result<Frame, DecodeError>> {
+        if byte == FLAG {
+            if !self.in_frame || self.buf.is_empty() {
+                // Opening flag, or back-to-back flags
+                self.start();
+                return None;
+            }
+
+            let result = self.parse();
+            if result.is_ok() {
+                self.in_frame = false;
+            } else {
+                // Maybe this flag opens the next frame: resync on it
+                self.start();
+            }
+            self.buf.clear();
+            return Some(result);
+        }
+
+        if !self.in_frame {
+            return None;
+        }
+        if self.escaped {
+            self.escaped = false;
+            self.buf.push(byte ^ ESCAPE_XOR);
+        } else if byte == ESCAPE {
+            self.escaped = true;
+        } else {
+            self.buf.push(byte);
+        }
+        None
+    }
+
+    fn start(&mut self) {
+        self.in_frame = true;
+        self.escaped = false;
+        self.buf.clear();
+    }
+
+    fn parse(&mut self) -> Result<Frame, DecodeError> {
+        if self.escaped {
+            return Err(DecodeError::BadEscape);
+        }
+
+        let buf = &self.buf;
+        if buf.len() < 5 {
+            return Err(DecodeError::TooShort);
+        }
+        let len = u16::from_le_bytes([buf[1], buf[2]]) as usize;
+        if buf.len() != 3 + len + 2 {
+            return Err(DecodeError::BadLength);
+        }
+
+        let (body, crc) = buf.split_at(3 + len);
+        if crc16(0xffff, body) != u16::from_le_bytes([crc[0], crc[1]]) {
+            return Err(DecodeError::BadCrc);
+        }
+        let kind = FrameKind::from_u8(buf[0]).ok_or(DecodeError::UnknownKind(buf[0]))?;
+
+        Ok(Frame {
+            kind,
+            payload: body[3..].to_vec(),
+        })
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn encoded(kind: FrameKind, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(kind, payload, &mut |b| out.push(b)).unwrap();
+        out
+    }
+
+    fn frame(kind: FrameKind, payload: &[u8]) -> Result<Frame, DecodeError> {
+        Ok(Frame {
+            kind,
+            payload: payload.to_vec(),
+        })
+    }
+
+    #[test]
+    fn test_crc16() {
+        // CRC-16/CCITT-FALSE check value
+        assert_eq!(crc16(0xffff, b"123456789"), 0x29b1);
+        assert_eq!(crc16(0xffff, b""), 0xffff);
+    }
+
+    #[test]
+    fn test_frame_encoding() {
+        let crc = crc16(0xffff, &[2, 2, 0, b'h', b'i']).to_le_bytes();
+        assert_eq!(
+            encoded(FrameKind::BootStage, b"hi"),
+            [FLAG, 2, 2, 0, b'h', b'i', crc[0], crc[1], FLAG]
+        );
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        let mut decoder = Decoder::new();
+        for (kind, payload) in [
+            (FrameKind::TestResult, &b"\x00memory_allocation"[..]),
+            (FrameKind::BootStage, b"memory"),
+            (FrameKind::PanicReport, b"boom"),
+            (FrameKind::PanicReport, b""),
+        ] {
+            assert_eq!(
+                decoder.feed(&encoded(kind, payload)),
+                [frame(kind, payload)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_frame_stuffing() {
+        let payload = [FLAG, ESCAPE, 0x5e, 0x5d, FLAG, FLAG, ESCAPE];
+        let wire = encoded(FrameKind::BootStage, &payload);
+
+        // Only the outer flags appear raw on the wire
+        assert_eq!(wire.iter().filter(|&&b| b == FLAG).count(), 2);
+        assert_eq!(&wire[4..8], [ESCAPE, 0x5e, ESCAPE, 0x5d]);
+        assert_eq!(
+            Decoder::new().feed(&wire),
+            [frame(FrameKind::BootStage, &payload)]
+        );
+
+        // A length whose bytes need stuffing (0x7e = 126 bytes)
+        let payload = [0xaa; 0x7e];
+        let wire = encoded(FrameKind::BootStage, &payload);
+        assert_eq!(&wire[1..5], [2, ESCAPE, 0x5e, 0]);
+        assert_eq!(
+            Decoder::new().feed(&wire),
+            [frame(FrameKind::BootStage, &payload)]
+        );
+    }
+
+    #[test]
+    fn test_frame_bad_crc_rejected() {
+        let mut wire = encoded(FrameKind::BootStage, b"payload");
+        let crc_pos = wire.len() - 2;
+        wire[crc_pos] ^= 0x01;
+
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.feed(&wire), [Err(DecodeError::BadCrc)]);
+
+        // The decoder recovers for the next frame
+        assert_eq!(
+            decoder.feed(&encoded(FrameKind::BootStage, b"ok")),
+            [frame(FrameKind::BootStage, b"ok")]
+        );
+    }
+
+    #[test]
+    fn test_frame_split_feeds() {
+        let wire = encoded(FrameKind::BootStage, b"split across calls");
+        let mut decoder = Decoder::new();
+        for chunk in wire[..wire.len() - 1].chunks(3) {
+            assert_eq!(decoder.feed(chunk), []);
+        }
+        assert_eq!(
+            decoder.feed(&wire[wire.len() - 1..]),
+            [frame(FrameKind::BootStage, b"split across calls")]
+        );
+
+        // Split right after an escape byte
+        let wire = encoded(FrameKind::BootStage, &[FLAG]);
+        let esc = wire.iter().position(|&b| b == ESCAPE).unwrap();
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.feed(&wire[..=esc]), []);
+        assert_eq!(
+            decoder.feed(&wire[esc + 1..]),
+            [frame(FrameKind::BootStage, &[FLAG])]
+        );
+    }
+
+    #[test]
+    fn test_frame_interleaved_text() {
+        let mut stream = b"Booting...\n".to_vec();
+        stream.extend(encoded(FrameKind::BootStage, b"memory"));
+        stream.extend(b"Testing memory allocation...\n");
+        stream.extend(encoded(FrameKind::TestResult, b"\x01alloc"));
+        stream.extend(encoded(FrameKind::BootStage, b"back to back"));
+
+        assert_eq!(
+            Decoder::new().feed(&stream),
+            [
+                frame(FrameKind::BootStage, b"memory"),
+                frame(FrameKind::TestResult, b"\x01alloc"),
+                frame(FrameKind::BootStage, b"back to back"),
+            ]
+        );
+
+        // A stray '~' in the text costs one error, not the next frame
+        let mut stream = b"home ~/x\n".to_vec();
+        stream.extend(encoded(FrameKind::BootStage, b"after"));
+        assert_eq!(
+            Decoder::new().feed(&stream),
+            [
+                Err(DecodeError::TooShort),
+                frame(FrameKind::BootStage, b"after")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_frame_buf_truncates() {
+        use core::fmt::Write;
+
+        let mut buf = FrameBuf::new();
+        let (what, err) = ("Invalid kernel layout", "empty");
+        write!(buf, "{}: {}", what, err).unwrap();
+        assert_eq!(buf.as_bytes(), b"Invalid kernel layout: empty");
+
+        let mut buf = FrameBuf::new();
+        buf.extend(&[b'x'; FrameBuf::CAPACITY + 10]);
+        assert_eq!(buf.as_bytes().len(), FrameBuf::CAPACITY);
+    }
+
+    #[test]
+    fn test_frame_payload_too_large() {
+        let payload = vec![0; MAX_PAYLOAD + 1];
+        assert!(encode(FrameKind::BootStage, &payload, &mut |_| {}).is_err());
+    }
+}
@@ -0,0 +1,1851 @@
+//! Serial output driver for PL011 UART.
+//!
+//! This module provides simple serial output and polled input using the
+//! PL011 UART on QEMU Virt platform.
+//!
+//! Output is polled by default. Polled writes of several bytes go out in
+//! bursts: an empty TX FIFO takes up to [`FIFO_DEPTH`] bytes without the
+//! flag register being read in between, see [`TxState::write_batched`].
+//! Once the UART TX interrupt is routed to
+//! [`handle_tx_irq`], [`enable_irq_tx`] switches writes to a ring buffer
+//! drained by the interrupt, so writers only spin while the ring is full.
+//!
+//! Code that must never wait, such as interrupt handlers, writes with
+//! [`try_write_str`] instead: it sends what fits right now and counts the
+//! rest as dropped, both in the caller's [`DropCounter`] and in
+//! [`status`].
+//!
+//! Input is polled as well until [`enable_irq_rx`] enables the UART
+//! receive interrupt. The IRQ vector then calls [`handle_irq`], which
+//! moves received bytes into a ring even while the CPU is busy printing;
+//! [`read_byte`] sleeps until a byte arrives instead of spinning on the
+//! FIFO, see [`rx`].
+//!
+//! Receive errors (overrun, break, parity, framing) are counted on every
+//! read and reported by [`error_stats`]. Callers that need to react to
+//! them, e.g. to a break, read with [`try_read_byte_checked`].
+//!
+//! # Memory ordering
+//!
+//! Registers are only accessed through [`Serial::read_reg`],
+//! [`Serial::write_reg`] and [`Serial::write_data`]. Volatile accesses
+//! keep their program order with each other, but not with ordinary
+//! memory accesses around them, so every path that writes DR after
+//! seeing space in FR puts a [`compiler_fence`] between the two: the
+//! write, and the ring or counter updates that go with it, cannot be
+//! hoisted above the status check. Ordering as seen by the UART itself
+//! comes from the device memory type of the mapping; a DSB is only used
+//! where a write must reach the UART before something else happens, such
+//! as unmasking interrupts.
+
+use crate::arch::address;
+use crate::arch::barrier::{self, Scope};
+use crate::arch::irq;
+use crate::arch::sync::IrqSafeMutex;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering, compiler_fence};
+use ring::{TX_RING_SIZE, TxRing};
+use rx::{RX_RING_SIZE, RxRing};
+use selftest::SerialSelftestError;
+
+pub mod color;
+#[cfg(any(feature = "debug_shell", test))]
+pub mod editor;
+pub mod frame;
+pub mod ring;
+pub mod rx;
+pub mod selftest;
+
+/// PL011 UART registers offsets.
+mod registers {
+    /// Data register (read/write).
+    pub const DR: u64 = 0x00;
+    /// Receive status register (read) / error clear register (write).
+    pub const RSR_ECR: u64 = 0x04;
+    /// Shift from the DR error bits down to the RSR layout.
+    pub const DR_ERROR_SHIFT: u32 = 8;
+    /// Flag register (read-only).
+    pub const FR: u64 = 0x18;
+    /// Line control register.
+    pub const LCR_H: u64 = 0x2c;
+    /// FIFO enable in LCR_H.
+    pub const LCR_H_FEN: u32 = 1 << 4;
+    /// Control register.
+    pub const CR: u64 = 0x30;
+    /// Loopback enable in CR.
+    pub const CR_LBE: u32 = 1 << 7;
+    /// Interrupt mask set/clear register.
+    pub const IMSC: u64 = 0x38;
+    /// Masked interrupt status register (read-only).
+    pub const MIS: u64 = 0x40;
+    /// Interrupt clear register (write-only).
+    pub const ICR: u64 = 0x44;
+    /// Receive interrupt bit in IMSC, MIS and ICR.
+    pub const INT_RX: u32 = 1 << 4;
+    /// Transmit interrupt bit in IMSC, MIS and ICR.
+    pub const INT_TX: u32 = 1 << 5;
+    /// Receive timeout interrupt bit in IMSC, MIS and ICR.
+    pub const INT_RT: u32 = 1 << 6;
+    /// UART busy transmitting flag.
+    pub const FR_BUSY: u32 = 1 << 3;
+    /// Receive FIFO empty flag.
+    pub const FR_RXFE: u32 = 1 << 4;
+    /// Transmit FIFO full flag.
+    pub const FR_TXFF: u32 = 1 << 5;
+    /// Transmit FIFO empty flag.
+    pub const FR_TXFE: u32 = 1 << 7;
+    /// Flag register value read back when no device answers.
+    pub const FR_ABSENT: u32 = 0xffff_ffff;
+    /// Framing error in RSR.
+    pub const RSR_FE: u32 = 1 << 0;
+    /// Parity error in RSR.
+    pub const RSR_PE: u32 = 1 << 1;
+    /// Break error in RSR.
+    pub const RSR_BE: u32 = 1 << 2;
+    /// Overrun error in RSR.
+    pub const RSR_OE: u32 = 1 << 3;
+    /// All RSR error bits.
+    pub const RSR_ERRORS: u32 = RSR_FE | RSR_PE | RSR_BE | RSR_OE;
+}
+
+/// Default number of flag register polls before a write gives up waiting.
+pub const DEFAULT_TX_SPIN_LIMIT: u32 = 1_000_000;
+
+/// Depth of the PL011 transmit FIFO in bytes.
+pub const FIFO_DEPTH: usize = 16;
+
+/// Result of waiting for transmit FIFO space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxWait {
+    /// FIFO has space.
+    Ready,
+    /// FIFO stayed full for the whole spin budget.
+    TimedOut,
+    /// Flag register reads back all-ones, no device present.
+    Absent,
+    /// Console already degraded, waiting was skipped.
+    Skipped,
+}
+
+/// Console health snapshot returned by `status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    /// Writes no longer wait for FIFO space.
+    pub degraded: bool,
+    /// The UART looked absent (all-ones flag register).
+    pub device_absent: bool,
+    /// Number of writes that timed out or skipped waiting.
+    pub dropped_waits: u64,
+    /// Bytes non-blocking writes could not send.
+    pub dropped_bytes: u64,
+    /// Flag register reads made waiting for TX FIFO space.
+    pub fr_reads: u64,
+    /// Bytes written by the blocking polled path.
+    pub tx_bytes: u64,
+    /// Received bytes dropped because the receive ring was full.
+    pub rx_dropped: u64,
+}
+
+/// A receive error reported with a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartRxError {
+    /// The line was held low for longer than a character, no data came.
+    Break,
+    /// The byte had no valid stop bit and is likely garbage.
+    Framing(u8),
+    /// The byte failed its parity check.
+    Parity(u8),
+    /// The byte is good, but the FIFO filled up and later ones were lost.
+    Overrun(u8),
+}
+
+/// Receive error counts returned by `error_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UartErrorStats {
+    /// Reads that found the receive FIFO had overflowed.
+    pub overrun: u64,
+    /// Break conditions received.
+    pub breaks: u64,
+    /// Bytes received with a parity error.
+    pub parity: u64,
+    /// Bytes received with a framing error.
+    pub framing: u64,
+}
+
+impl UartErrorStats {
+    /// Count each error in `bits`, in the RSR layout.
+    pub fn record(&mut self, bits: u32) {
+        let counters = [
+            (registers::RSR_OE, &mut self.overrun),
+            (registers::RSR_BE, &mut self.breaks),
+            (registers::RSR_PE, &mut self.parity),
+            (registers::RSR_FE, &mut self.framing),
+        ];
+        for (bit, count) in counters {
+            if bits & bit != 0 {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Returns true if no error was counted.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for UartErrorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "overrun {}, break {}, parity {}, framing {}",
+            self.overrun, self.breaks, self.parity, self.framing
+        )
+    }
+}
+
+/// Bytes lost by one user of the non-blocking write path.
+///
+/// Each call site keeps its own counter so a report can say whose output
+/// went missing; [`status`] has the total.
+pub struct DropCounter(AtomicU64);
+
+impl DropCounter {
+    /// Create a counter with nothing dropped.
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// Returns the number of bytes dropped so far.
+    #[allow(dead_code)]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Bounded transmit wait with sticky degradation.
+///
+/// Once a wait times out or the device looks absent, the console is marked
+/// degraded and later writes are fire-and-forget, so a broken UART can slow
+/// the kernel down at most once instead of hanging it.
+pub struct TxState {
+    degraded: AtomicBool,
+    absent: AtomicBool,
+    dropped_waits: AtomicU64,
+    dropped_bytes: AtomicU64,
+    fr_reads: AtomicU64,
+    tx_bytes: AtomicU64,
+    spin_limit: AtomicU32,
+}
+
+impl TxState {
+    /// Creates a healthy state allowing `spin_limit` polls per wait.
+    pub const fn new(spin_limit: u32) -> Self {
+        Self {
+            degraded: AtomicBool::new(false),
+            absent: AtomicBool::new(false),
+            dropped_waits: AtomicU64::new(0),
+            dropped_bytes: AtomicU64::new(0),
+            fr_reads: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            spin_limit: AtomicU32::new(spin_limit),
+        }
+    }
+
+    /// Wait until `read_fr` reports transmit FIFO space.
+    ///
+    /// # Arguments
+    /// * `read_fr` - Source of flag register values
+    pub fn wait(&self, read_fr: impl FnMut() -> u32) -> TxWait {
+        self.wait_flags(read_fr).0
+    }
+
+    /// Like [`wait`](Self::wait), also returning the flags that ended a
+    /// ready wait, 0 otherwise.
+    fn wait_flags(&self, mut read_fr: impl FnMut() -> u32) -> (TxWait, u32) {
+        if self.degraded.load(Ordering::Relaxed) {
+            self.dropped_waits.fetch_add(1, Ordering::Relaxed);
+            return (TxWait::Skipped, 0);
+        }
+
+        for _ in 0..self.spin_limit.load(Ordering::Relaxed) {
+            let flags = read_fr();
+            self.fr_reads.fetch_add(1, Ordering::Relaxed);
+            if flags == registers::FR_ABSENT {
+                self.absent.store(true, Ordering::Relaxed);
+                self.degrade();
+                return (TxWait::Absent, 0);
+            }
+            if flags & registers::FR_TXFF == 0 {
+                // The caller's data write must follow this read
+                compiler_fence(Ordering::SeqCst);
+                return (TxWait::Ready, flags);
+            }
+            core::hint::spin_loop();
+        }
+
+        self.degrade();
+        (TxWait::TimedOut, 0)
+    }
+
+    /// Write `bytes` in order, waiting for FIFO space per burst.
+    ///
+    /// A clear TXFF only guarantees room for one byte, so bursts longer
+    /// than that are sent only when the flags show the FIFO empty; then up
+    /// to `batch` bytes go out without another flag register read. As with
+    /// [`wait`](Self::wait), a byte is written anyway when the wait times
+    /// out, and one byte at a time once degraded.
+    ///
+    /// # Arguments
+    /// * `bytes` - Bytes to send
+    /// * `batch` - FIFO depth, at most [`FIFO_DEPTH`]; 1 without a FIFO
+    /// * `read_fr` - Source of flag register values
+    /// * `write` - Writes one byte to the data register
+    pub fn write_batched(
+        &self,
+        bytes: &[u8],
+        batch: usize,
+        mut read_fr: impl FnMut() -> u32,
+        mut write: impl FnMut(u8),
+    ) {
+        let batch = batch.clamp(1, FIFO_DEPTH);
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let burst = match self.wait_flags(&mut read_fr) {
+                (TxWait::Ready, flags) if flags & registers::FR_TXFE != 0 => batch,
+                _ => 1,
+            };
+            let (now, later) = rest.split_at(burst.min(rest.len()));
+            now.iter().for_each(|&byte| write(byte));
+            self.tx_bytes.fetch_add(now.len() as u64, Ordering::Relaxed);
+            rest = later;
+        }
+    }
+
+    /// Mark the console degraded and count the abandoned wait.
+    fn degrade(&self) {
+        self.degraded.store(true, Ordering::Relaxed);
+        self.dropped_waits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account for a non-blocking write of `len` bytes that sent `sent`.
+    ///
+    /// The shortfall is added to `drops` and to the console total.
+    ///
+    /// # Returns
+    /// `sent`
+    fn count_dropped(&self, drops: &DropCounter, len: usize, sent: usize) -> usize {
+        let lost = (len - sent) as u64;
+        if lost != 0 {
+            drops.0.fetch_add(lost, Ordering::Relaxed);
+            self.dropped_bytes.fetch_add(lost, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Returns the current console health.
+    pub fn status(&self) -> Status {
+        Status {
+            degraded: self.degraded.load(Ordering::Relaxed),
+            device_absent: self.absent.load(Ordering::Relaxed),
+            dropped_waits: self.dropped_waits.load(Ordering::Relaxed),
+            dropped_bytes: self.dropped_bytes.load(Ordering::Relaxed),
+            fr_reads: self.fr_reads.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            // Receive side, filled in by `Serial::status`
+            rx_dropped: 0,
+        }
+    }
+
+    /// Set the number of flag register polls allowed per wait.
+    pub fn set_spin_limit(&self, limit: u32) {
+        self.spin_limit.store(limit, Ordering::Relaxed);
+    }
+}
+
+/// Serial output driver.
+pub struct Serial {
+    base: AtomicU64,
+    tx: TxState,
+    /// Writes go through `ring` and the TX interrupt instead of polling.
+    buffered: AtomicBool,
+    /// Bytes a polled burst may write into an empty TX FIFO, 0 while the
+    /// FIFO state is unknown and every byte is polled for.
+    tx_batch: AtomicU32,
+    ring: IrqSafeMutex<TxRing<TX_RING_SIZE>>,
+    rx_errors: IrqSafeMutex<UartErrorStats>,
+    /// Reads come from `rx_ring`, filled by the RX interrupt.
+    rx_buffered: AtomicBool,
+    rx_ring: IrqSafeMutex<RxRing<RX_RING_SIZE>>,
+    rx_dropped: AtomicU64,
+}
+
+impl Serial {
+    /// Create a new serial driver instance.
+    ///
+    /// # Arguments
+    /// * `base` - Virtual base address of UART
+    pub const fn new(base: u64) -> Self {
+        Self {
+            base: AtomicU64::new(base),
+            tx: TxState::new(DEFAULT_TX_SPIN_LIMIT),
+            buffered: AtomicBool::new(false),
+            tx_batch: AtomicU32::new(0),
+            ring: IrqSafeMutex::new("serial_tx", TxRing::new()),
+            rx_errors: IrqSafeMutex::new(
+                "serial_rx_errors",
+                UartErrorStats {
+                    overrun: 0,
+                    breaks: 0,
+                    parity: 0,
+                    framing: 0,
+                },
+            ),
+            rx_buffered: AtomicBool::new(false),
+            rx_ring: IrqSafeMutex::new("serial_rx", RxRing::new()),
+            rx_dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the current virtual base address of the UART.
+    fn base(&self) -> u64 {
+        self.base.load(Ordering::Acquire)
+    }
+
+    /// Move the driver to the UART mapping at `base`.
+    ///
+    /// Writes through the old mapping reach the UART before any through
+    /// the new one, and the new base is visible to other CPUs before
+    /// their next access.
+    ///
+    /// # Arguments
+    /// * `base` - Virtual base address of the UART
+    pub fn set_base(&self, base: u64) {
+        barrier::dsb(Scope::Sy);
+        self.base.store(base, Ordering::Release);
+    }
+
+    /// Get the default serial instance for QEMU Virt platform.
+    #[allow(dead_code)]
+    pub fn default() -> Self {
+        Self::new(address::kernel::VIRTUAL_BASE + address::virt::UART_BASE)
+    }
+
+    /// Write a single byte to serial port.
+    ///
+    /// # Arguments
+    /// * `byte` - Byte to write
+    pub fn write_byte(&self, byte: u8) {
+        if self.buffered.load(Ordering::Acquire) {
+            self.write_byte_buffered(byte);
+        } else {
+            self.write_byte_polled(byte);
+        }
+    }
+
+    /// Write a byte straight to the data register.
+    fn write_byte_polled(&self, byte: u8) {
+        // Wait (bounded) until transmit FIFO is not full. On timeout the
+        // byte is written anyway and may be lost.
+        self.tx.wait(|| self.read_flags());
+
+        // Write byte to data register
+        self.write_data(byte);
+        self.tx.tx_bytes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Read the 32-bit register at `offset`.
+    #[inline]
+    fn read_reg(&self, offset: u64) -> u32 {
+        // Safety: `base` maps the UART's register block
+        unsafe { core::ptr::read_volatile((self.base() + offset) as *const u32) }
+    }
+
+    /// Write the 32-bit register at `offset`.
+    #[inline]
+    fn write_reg(&self, offset: u64, value: u32) {
+        // Safety: `base` maps the UART's register block
+        unsafe { core::ptr::write_volatile((self.base() + offset) as *mut u32, value) };
+    }
+
+    /// Write `byte` to the data register without checking for space.
+    ///
+    /// Callers that checked FR first fence between the check and this
+    /// write, see the module's memory ordering notes.
+    #[inline]
+    fn write_data(&self, byte: u8) {
+        // Safety: `base` maps the UART's register block
+        unsafe { core::ptr::write_volatile((self.base() + registers::DR) as *mut u8, byte) };
+    }
+
+    /// Set how many bytes a polled burst may write into an empty TX FIFO.
+    ///
+    /// # Arguments
+    /// * `batch` - [`FIFO_DEPTH`] with the FIFO enabled, 1 without it, 0
+    ///   to poll before every byte
+    pub fn set_tx_batch(&self, batch: usize) {
+        self.tx_batch
+            .store(batch.min(FIFO_DEPTH) as u32, Ordering::Relaxed);
+    }
+
+    /// Set the burst size from the FIFO enable bit in LCR_H, unless one
+    /// was set already.
+    #[cfg_attr(not(target_os = "none"), allow(dead_code))]
+    fn detect_tx_batch(&self) {
+        let lcr_h = self.read_reg(registers::LCR_H);
+        let _ = self.tx_batch.compare_exchange(
+            0,
+            tx_batch_for(lcr_h) as u32,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Queue a byte for the TX interrupt.
+    ///
+    /// Only spins if the ring is full, to push its oldest byte out.
+    fn write_byte_buffered(&self, byte: u8) {
+        let mut ring = self.ring.lock();
+        if let Err(byte) = ring.push(byte) {
+            if let Some(oldest) = ring.pop() {
+                self.write_byte_polled(oldest);
+            }
+            let _ = ring.push(byte);
+        }
+        self.fill_fifo(&mut ring);
+    }
+
+    /// Move buffered bytes into the TX FIFO until it is full.
+    ///
+    /// The TX interrupt stays enabled only while bytes remain, since the
+    /// PL011 raises it whenever the FIFO drains below its trigger level.
+    fn fill_fifo(&self, ring: &mut TxRing<TX_RING_SIZE>) {
+        ring.drain_while(|byte| {
+            let flags = self.read_flags();
+            if flags == registers::FR_ABSENT || flags & registers::FR_TXFF != 0 {
+                return false;
+            }
+            compiler_fence(Ordering::SeqCst);
+            self.write_data(byte);
+            true
+        });
+        self.set_tx_irq(!ring.is_empty());
+    }
+
+    /// Unmask or mask the TX interrupt in the UART.
+    fn set_tx_irq(&self, enabled: bool) {
+        let mask = self.read_reg(registers::IMSC);
+        let mask = if enabled {
+            mask | registers::INT_TX
+        } else {
+            mask & !registers::INT_TX
+        };
+        self.write_reg(registers::IMSC, mask);
+        // The UART must see the new mask before IRQs are unmasked again
+        barrier::dsb(Scope::Sy);
+    }
+
+    /// Refill the TX FIFO from the ring, called from the UART interrupt.
+    pub fn handle_tx_irq(&self) {
+        self.write_reg(registers::ICR, registers::INT_TX);
+        // Clear the interrupt before the FIFO refill can raise it again
+        barrier::dsb(Scope::Sy);
+        let mut ring = self.ring.lock();
+        self.fill_fifo(&mut ring);
+    }
+
+    /// Move received bytes into the receive ring, called from the UART
+    /// interrupt.
+    ///
+    /// Harmless without a pending receive interrupt, so it may also be
+    /// called after any wakeup.
+    pub fn handle_rx_irq(&self) {
+        let mis = self.read_reg(registers::MIS);
+        let mut ring = self.rx_ring.lock();
+        let served = rx::serve(
+            mis,
+            &mut ring,
+            FIFO_DEPTH,
+            |bits| self.write_reg(registers::ICR, bits),
+            || self.try_read_byte(),
+        );
+        if served.dropped != 0 {
+            self.rx_dropped
+                .fetch_add(served.dropped as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Route reads through the receive ring and RX interrupts.
+    ///
+    /// Unmasks the receive and receive timeout interrupts in the UART;
+    /// routing them to [`handle_rx_irq`](Self::handle_rx_irq) is up to
+    /// the caller.
+    pub fn enable_irq_rx(&self) {
+        self.rx_buffered.store(true, Ordering::Release);
+        let mask = self.read_reg(registers::IMSC);
+        self.write_reg(
+            registers::IMSC,
+            mask | registers::INT_RX | registers::INT_RT,
+        );
+        barrier::dsb(Scope::Sy);
+    }
+
+    /// Returns true if reads come from the receive ring.
+    pub fn rx_buffered(&self) -> bool {
+        self.rx_buffered.load(Ordering::Acquire)
+    }
+
+    /// Take the oldest received byte without waiting: from the receive
+    /// ring with RX interrupts on, else from the FIFO.
+    pub fn pop_byte(&self) -> Option<u8> {
+        if self.rx_buffered() {
+            self.rx_ring.lock().pop()
+        } else {
+            self.try_read_byte()
+        }
+    }
+
+    /// Returns the console health, both directions.
+    pub fn status(&self) -> Status {
+        Status {
+            rx_dropped: self.rx_dropped.load(Ordering::Relaxed),
+            ..self.tx.status()
+        }
+    }
+
+    /// Route writes through the ring buffer and TX interrupt.
+    pub fn enable_irq_tx(&self) {
+        self.buffered.store(true, Ordering::Release);
+    }
+
+    /// Return to polled output, writing out anything still buffered.
+    ///
+    /// Does not wait for the ring lock, so it is safe on the panic path;
+    /// bytes are left behind if another context holds it.
+    pub fn disable_irq_tx(&self) {
+        self.buffered.store(false, Ordering::Release);
+        if let Some(mut ring) = self.ring.try_lock() {
+            while let Some(byte) = ring.pop() {
+                self.write_byte_polled(byte);
+            }
+            self.set_tx_irq(false);
+        }
+    }
+
+    /// Write a string to serial port.
+    ///
+    /// # Arguments
+    /// * `s` - String slice to write
+    pub fn write_str(&self, s: &str) {
+        self.write_bytes(s.as_bytes());
+    }
+
+    /// Write a byte slice to serial port.
+    ///
+    /// Polled output is sent in bursts once the FIFO state is known, see
+    /// [`set_tx_batch`](Self::set_tx_batch).
+    ///
+    /// # Arguments
+    /// * `bytes` - Byte slice to write
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        let batch = self.tx_batch.load(Ordering::Relaxed) as usize;
+        if batch == 0 || self.buffered.load(Ordering::Acquire) {
+            for &byte in bytes {
+                self.write_byte(byte);
+            }
+            return;
+        }
+        self.tx.write_batched(
+            bytes,
+            batch,
+            || self.read_flags(),
+            |byte| self.write_data(byte),
+        );
+    }
+
+    /// Write as much of `bytes` as fits without waiting.
+    ///
+    /// Polled output writes while the TX FIFO has space. Interrupt-driven
+    /// output queues behind the ring's contents so bytes stay in order,
+    /// and sends nothing if the ring is locked by the interrupted context.
+    ///
+    /// # Arguments
+    /// * `bytes` - Byte slice to write
+    ///
+    /// # Returns
+    /// The number of leading bytes accepted
+    pub fn try_write_bytes(&self, bytes: &[u8]) -> usize {
+        if !self.buffered.load(Ordering::Acquire) {
+            return fill_nonblocking(bytes, || self.read_flags(), |byte| self.write_data(byte));
+        }
+
+        let Some(mut ring) = self.ring.try_lock() else {
+            return 0;
+        };
+        let queued = bytes
+            .iter()
+            .take_while(|&&byte| ring.push(byte).is_ok())
+            .count();
+        self.fill_fifo(&mut ring);
+        queued
+    }
+
+    /// Write an unsigned integer in decimal.
+    ///
+    /// # Arguments
+    /// * `val` - Value to write
+    pub fn write_dec_u64(&self, val: u64) {
+        format_dec_u64(val, &mut |b| self.write_byte(b));
+    }
+
+    /// Write a signed integer in decimal.
+    ///
+    /// # Arguments
+    /// * `val` - Value to write
+    pub fn write_dec_i64(&self, val: i64) {
+        format_dec_i64(val, &mut |b| self.write_byte(b));
+    }
+
+    /// Write a byte count in human readable form (e.g. "1.50 MiB").
+    ///
+    /// # Arguments
+    /// * `bytes` - Size in bytes
+    pub fn write_size(&self, bytes: u64) {
+        format_size(bytes, &mut |b| self.write_byte(b));
+    }
+
+    /// Read the flag register.
+    #[inline]
+    fn read_flags(&self) -> u32 {
+        self.read_reg(registers::FR)
+    }
+
+    /// Read a received byte without waiting.
+    ///
+    /// Bytes received with an error are counted and skipped, except after
+    /// an overrun, where the byte itself is good.
+    ///
+    /// # Returns
+    /// The byte, or `None` if the receive FIFO is empty or no UART answers
+    pub fn try_read_byte(&self) -> Option<u8> {
+        loop {
+            match self.try_read_byte_checked()? {
+                Ok(byte) | Err(UartRxError::Overrun(byte)) => return Some(byte),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    /// Read a received byte without waiting, reporting receive errors.
+    ///
+    /// Errors are counted in [`error_stats`](Self::error_stats) and the
+    /// UART's error latch is cleared.
+    ///
+    /// # Returns
+    /// `None` if the receive FIFO is empty or no UART answers
+    pub fn try_read_byte_checked(&self) -> Option<Result<u8, UartRxError>> {
+        if !rx_ready(self.read_flags()) {
+            return None;
+        }
+
+        // The upper bits of DR hold the errors of this byte, RSR also has
+        // the overrun latched when the FIFO filled up
+        let data = self.read_reg(registers::DR);
+        let rsr = self.read_reg(registers::RSR_ECR);
+        let bits = rx_error_bits(data, rsr);
+        if bits != 0 {
+            self.rx_errors.lock().record(bits);
+            // Any write to ECR clears the latched errors
+            self.write_reg(registers::RSR_ECR, 0);
+        }
+        Some(decode_rx(data as u8, bits))
+    }
+
+    /// Returns the receive errors counted so far.
+    pub fn error_stats(&self) -> UartErrorStats {
+        *self.rx_errors.lock()
+    }
+
+    /// Run the loopback self test, see [`selftest`].
+    ///
+    /// The TX ring stays locked for the whole test, so no buffered output
+    /// is looped back or interleaved with the pattern.
+    pub fn selftest(&self) -> Result<selftest::SelftestReport, SerialSelftestError> {
+        let _ring = self.ring.lock();
+        let before = self.error_stats();
+        let report = selftest::run(
+            &mut SerialPort(self),
+            self.tx.spin_limit.load(Ordering::Relaxed),
+        )?;
+        if self.error_stats() != before {
+            return Err(SerialSelftestError::ErrorsCounted);
+        }
+        Ok(report)
+    }
+}
+
+/// The self test's view of a [`Serial`]'s registers.
+struct SerialPort<'a>(&'a Serial);
+
+impl selftest::Port for SerialPort<'_> {
+    fn read_flags(&mut self) -> u32 {
+        self.0.read_flags()
+    }
+
+    fn write_data(&mut self, byte: u8) {
+        self.0.write_data(byte);
+    }
+
+    fn read_data(&mut self) -> Result<u8, UartRxError> {
+        // Only called once the flags report data
+        self.0
+            .try_read_byte_checked()
+            .unwrap_or(Err(UartRxError::Break))
+    }
+
+    fn read_control(&mut self) -> u32 {
+        self.0.read_reg(registers::CR)
+    }
+
+    fn write_control(&mut self, cr: u32) {
+        self.0.write_reg(registers::CR, cr);
+        barrier::dsb(Scope::Sy);
+    }
+}
+
+/// Returns the receive error bits of a read, in the RSR layout.
+///
+/// # Arguments
+/// * `dr` - Data register value read with the byte
+/// * `rsr` - Receive status register value read after it
+fn rx_error_bits(dr: u32, rsr: u32) -> u32 {
+    ((dr >> registers::DR_ERROR_SHIFT) | rsr) & registers::RSR_ERRORS
+}
+
+/// Turn a received byte and its error bits into a read result.
+///
+/// With several errors at once, the one saying least for the byte wins:
+/// a break carries no byte, framing and parity errors a corrupt one, and
+/// an overrun a good one.
+fn decode_rx(byte: u8, bits: u32) -> Result<u8, UartRxError> {
+    if bits & registers::RSR_BE != 0 {
+        Err(UartRxError::Break)
+    } else if bits & registers::RSR_FE != 0 {
+        Err(UartRxError::Framing(byte))
+    } else if bits & registers::RSR_PE != 0 {
+        Err(UartRxError::Parity(byte))
+    } else if bits & registers::RSR_OE != 0 {
+        Err(UartRxError::Overrun(byte))
+    } else {
+        Ok(byte)
+    }
+}
+
+/// Returns the polled burst size for line control register value `lcr_h`.
+fn tx_batch_for(lcr_h: u32) -> usize {
+    if lcr_h & registers::LCR_H_FEN != 0 {
+        FIFO_DEPTH
+    } else {
+        1
+    }
+}
+
+/// Write `bytes` through `write` while `read_fr` reports FIFO space.
+///
+/// # Returns
+/// The number of bytes written; stops at the first full or absent reading
+fn fill_nonblocking(
+    bytes: &[u8],
+    mut read_fr: impl FnMut() -> u32,
+    mut write: impl FnMut(u8),
+) -> usize {
+    let mut sent = 0;
+    for &byte in bytes {
+        let flags = read_fr();
+        if flags == registers::FR_ABSENT || flags & registers::FR_TXFF != 0 {
+            break;
+        }
+        compiler_fence(Ordering::SeqCst);
+        write(byte);
+        sent += 1;
+    }
+    sent
+}
+
+/// Returns true if flag register value `flags` reports received data.
+fn rx_ready(flags: u32) -> bool {
+    flags != registers::FR_ABSENT && flags & registers::FR_RXFE == 0
+}
+
+/// Emit `val` in decimal through `out`.
+fn format_dec_u64(val: u64, out: &mut impl FnMut(u8)) {
+    // u64::MAX has 20 decimal digits
+    let mut digits = [0u8; 20];
+    let mut len = 0;
+    let mut val = val;
+
+    loop {
+        digits[len] = b'0' + (val % 10) as u8;
+        len += 1;
+        val /= 10;
+        if val == 0 {
+            break;
+        }
+    }
+
+    for &digit in digits[..len].iter().rev() {
+        out(digit);
+    }
+}
+
+/// Emit `val` in decimal through `out`, with a leading '-' if negative.
+fn format_dec_i64(val: i64, out: &mut impl FnMut(u8)) {
+    if val < 0 {
+        out(b'-');
+    }
+    format_dec_u64(val.unsigned_abs(), out);
+}
+
+/// Emit `bytes` as a size with two decimals in the largest unit that
+/// rounds to at least 1.00, or as plain bytes below 1 KiB.
+fn format_size(bytes: u64, out: &mut impl FnMut(u8)) {
+    const UNITS: [(u64, &str); 3] = [(1 << 30, " GiB"), (1 << 20, " MiB"), (1 << 10, " KiB")];
+
+    for (unit, suffix) in UNITS {
+        // Size in hundredths of `unit`, rounded to nearest
+        let hundredths = (bytes as u128 * 100 + unit as u128 / 2) / unit as u128;
+        if hundredths >= 100 {
+            format_dec_u64((hundredths / 100) as u64, out);
+            out(b'.');
+            out(b'0' + (hundredths / 10 % 10) as u8);
+            out(b'0' + (hundredths % 10) as u8);
+            suffix.bytes().for_each(&mut *out);
+            return;
+        }
+    }
+
+    format_dec_u64(bytes, out);
+    b" B".iter().for_each(|&b| out(b));
+}
+
+/// Global serial instance for kernel use.
+///
+/// Starts out using the boot linear map and is moved to an ioremap mapping
+/// by `remap` once memory management is up.
+static SERIAL: Serial = Serial::new(address::kernel::VIRTUAL_BASE + address::virt::UART_BASE);
+
+/// Physical base of the UART behind `SERIAL`, used by `remap`.
+static UART_PHYS: AtomicU64 = AtomicU64::new(address::virt::UART_BASE);
+
+/// Returns true if a UART at `phys` is reachable through the boot device map.
+///
+/// The boot page tables map the first gigabyte (everything below RAM) as
+/// device memory in the linear map.
+fn in_boot_device_map(phys: u64) -> bool {
+    phys.checked_add(address::virt::UART_SIZE)
+        .is_some_and(|end| end <= address::virt::RAM_BASE)
+}
+
+/// Point the global serial instance at a UART found at runtime.
+///
+/// Must be called before `remap`. Output switches to the new UART through
+/// the boot linear map.
+///
+/// # Arguments
+/// * `phys` - Physical base address of the PL011
+#[allow(dead_code)]
+pub fn set_phys_base(phys: u64) -> Result<(), &'static str> {
+    if !in_boot_device_map(phys) {
+        return Err("UART outside boot device map");
+    }
+    UART_PHYS.store(phys, Ordering::Release);
+    set_base(address::translation::phys_to_virt(phys));
+    Ok(())
+}
+
+/// Point the global serial instance at the UART mapping at `base`.
+///
+/// # Arguments
+/// * `base` - Virtual base address of the UART
+pub fn set_base(base: u64) {
+    SERIAL.set_base(base);
+}
+
+/// Write a byte to serial port using global instance.
+///
+/// # Arguments
+/// * `byte` - Byte to write
+pub fn write_byte(byte: u8) {
+    SERIAL.write_byte(byte);
+}
+
+/// Read a received byte from the global instance without waiting.
+///
+/// # Returns
+/// The byte, or `None` if nothing has been received
+#[allow(dead_code)]
+pub fn try_read_byte() -> Option<u8> {
+    SERIAL.try_read_byte()
+}
+
+/// Read a received byte from the global instance, reporting errors.
+///
+/// # Returns
+/// `None` if nothing has been received
+#[allow(dead_code)]
+pub fn try_read_byte_checked() -> Option<Result<u8, UartRxError>> {
+    SERIAL.try_read_byte_checked()
+}
+
+/// Run the loopback self test on the global instance.
+#[allow(dead_code)]
+pub fn selftest() -> Result<selftest::SelftestReport, SerialSelftestError> {
+    SERIAL.selftest()
+}
+
+/// Returns the receive errors counted by the global instance.
+pub fn error_stats() -> UartErrorStats {
+    SERIAL.error_stats()
+}
+
+/// Write the global instance's receive error counts to `w`, if any.
+#[allow(dead_code)]
+pub fn write_error_stats(w: &mut dyn fmt::Write) -> fmt::Result {
+    let stats = error_stats();
+    if stats.is_empty() {
+        return Ok(());
+    }
+    writeln!(w, "UART receive errors: {}", stats)
+}
+
+/// Write a string to serial port using global instance.
+///
+/// # Arguments
+/// * `s` - String slice to write
+pub fn write_str(s: &str) {
+    SERIAL.write_str(s);
+}
+
+/// Write a byte slice to serial port using global instance.
+///
+/// # Arguments
+/// * `bytes` - Byte slice to write
+pub fn write_bytes(bytes: &[u8]) {
+    SERIAL.write_bytes(bytes);
+}
+
+/// Write as much of `s` as fits without waiting, using global instance.
+///
+/// Usable from interrupt handlers and other code that must not block.
+///
+/// # Arguments
+/// * `s` - String slice to write
+/// * `drops` - Counter charged with the bytes that were not sent
+///
+/// # Returns
+/// The number of bytes sent
+pub fn try_write_str(s: &str, drops: &DropCounter) -> usize {
+    let sent = SERIAL.try_write_bytes(s.as_bytes());
+    SERIAL.tx.count_dropped(drops, s.len(), sent)
+}
+
+/// Write an unsigned integer in decimal using global instance.
+///
+/// # Arguments
+/// * `val` - Value to write
+pub fn write_dec_u64(val: u64) {
+    SERIAL.write_dec_u64(val);
+}
+
+/// Write a signed integer in decimal using global instance.
+///
+/// # Arguments
+/// * `val` - Value to write
+#[allow(dead_code)]
+pub fn write_dec_i64(val: i64) {
+    SERIAL.write_dec_i64(val);
+}
+
+/// Write a byte count in human readable form using global instance.
+///
+/// # Arguments
+/// * `bytes` - Size in bytes
+#[allow(dead_code)]
+pub fn write_size(bytes: u64) {
+    SERIAL.write_size(bytes);
+}
+
+/// UART TX interrupt handler for the global instance.
+#[allow(dead_code)]
+pub fn handle_tx_irq() {
+    SERIAL.handle_tx_irq();
+}
+
+/// UART RX interrupt handler for the global instance.
+#[allow(dead_code)]
+pub fn handle_rx_irq() {
+    SERIAL.handle_rx_irq();
+}
+
+/// UART interrupt handler for the global instance, serving input and,
+/// when it is interrupt driven, output.
+pub fn handle_irq() {
+    SERIAL.handle_rx_irq();
+    if SERIAL.buffered.load(Ordering::Acquire) {
+        SERIAL.handle_tx_irq();
+    }
+}
+
+/// Switch the global instance to interrupt-driven input.
+///
+/// Enables the UART's SPI in the interrupt controller and the receive
+/// interrupts in the UART. Once IRQs are unmasked the IRQ vector moves
+/// received bytes into the receive ring as they arrive, also while the
+/// CPU is busy printing, so the 16 byte FIFO no longer overruns.
+///
+/// # Returns
+/// An error if no interrupt controller driver is active
+#[cfg(target_os = "none")]
+pub fn enable_irq_rx() -> Result<(), &'static str> {
+    let gic = crate::arch::gic::driver().ok_or("no interrupt controller")?;
+    gic.enable_irq(address::virt::UART_IRQ);
+    SERIAL.enable_irq_rx();
+    Ok(())
+}
+
+/// Read a byte from the global instance, waiting until one arrives.
+///
+/// With RX interrupts on the CPU sleeps in WFI between bytes, otherwise
+/// the FIFO is polled.
+#[cfg(target_os = "none")]
+pub fn read_byte() -> u8 {
+    loop {
+        // Masked from the check to the WFI, so a byte arriving in between
+        // still ends the wait; the IRQ is taken once they are restored
+        let flags = irq::disable_save();
+        let byte = SERIAL.pop_byte();
+        if byte.is_none() && SERIAL.rx_buffered() {
+            crate::arch::idle::wait_for_interrupt();
+            if !flags.irqs_enabled() {
+                // Called with IRQs masked, nothing else serves it
+                SERIAL.handle_rx_irq();
+            }
+        }
+        irq::restore(flags);
+
+        if let Some(byte) = byte {
+            return byte;
+        }
+        if !SERIAL.rx_buffered() {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Switch the global instance to interrupt-driven output.
+///
+/// The UART TX interrupt must already be routed to [`handle_tx_irq`].
+#[allow(dead_code)]
+pub fn enable_irq_tx() {
+    SERIAL.enable_irq_tx();
+}
+
+/// Switch the global instance back to polled output, flushing the ring.
+#[allow(dead_code)]
+pub fn disable_irq_tx() {
+    SERIAL.disable_irq_tx();
+}
+
+/// Returns the health of the global serial console.
+pub fn status() -> Status {
+    SERIAL.status()
+}
+
+/// Set how many flag register polls a write may spend waiting.
+#[allow(dead_code)]
+pub fn set_spin_limit(limit: u32) {
+    SERIAL.tx.set_spin_limit(limit);
+}
+
+/// Set the polled burst size of the global instance, see
+/// [`Serial::set_tx_batch`].
+///
+/// Before `init` every byte is polled for. `init` sets the size from the
+/// UART's FIFO enable bit, unless it was set here first.
+#[allow(dead_code)]
+pub fn set_tx_batch(batch: usize) {
+    SERIAL.set_tx_batch(batch);
+}
+
+/// Bytes [`Writer`] dropped while it could not wait.
+static WRITER_DROPS: DropCounter = DropCounter::new();
+
+/// Returns true if [`Writer`] must not wait for the UART.
+///
+/// IRQs stay masked through boot, when nothing can be interrupted, so
+/// masked IRQs only mean interrupt context once output is interrupt
+/// driven. The panic paths switch back to polled output first and so
+/// always get the blocking path.
+fn must_not_block() -> bool {
+    SERIAL.buffered.load(Ordering::Relaxed) && irq::in_irq_context()
+}
+
+/// `core::fmt::Write` adapter for the global serial instance.
+///
+/// Allows `write!` formatting straight to the UART without a buffer. In
+/// interrupt context it falls back to [`try_write_str`], so formatted
+/// output never waits there either.
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if must_not_block() {
+            try_write_str(s, &WRITER_DROPS);
+        } else {
+            SERIAL.write_str(s);
+        }
+        Ok(())
+    }
+}
+
+/// `core::fmt::Write` adapter that never waits, charging what it cannot
+/// send to the given counter.
+pub struct TryWriter<'a>(pub &'a DropCounter);
+
+impl fmt::Write for TryWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        try_write_str(s, self.0);
+        Ok(())
+    }
+}
+
+/// Initialize serial output.
+///
+/// The PL011 is set up by firmware; only the polled burst size is taken
+/// from whether it left the FIFOs enabled. With `serial_selftest` the
+/// loopback self test runs here too, so a miswired UART stops boot before
+/// anything relies on the console.
+#[cfg(target_os = "none")]
+fn init() -> Result<(), &'static str> {
+    SERIAL.detect_tx_batch();
+    #[cfg(feature = "serial_selftest")]
+    SERIAL
+        .selftest()
+        .map_err(|_| "serial loopback self test failed")?;
+    Ok(())
+}
+
+initcall!(Early, "serial", init);
+
+/// Move the global serial instance to a dedicated device mapping.
+///
+/// Must be called after memblock is initialized. Output before this uses
+/// the boot linear map of the UART.
+#[cfg(target_os = "none")]
+pub fn remap() -> Result<(), crate::mm::MapError> {
+    use crate::mm::{DeviceAttr, ioremap};
+
+    let base = ioremap(
+        UART_PHYS.load(Ordering::Acquire),
+        address::virt::UART_SIZE,
+        DeviceAttr::NGnRE,
+    )?;
+    set_base(base);
+
+    Ok(())
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn capture(f: impl FnOnce(&mut dyn FnMut(u8))) -> String {
+        let mut buf = Vec::new();
+        f(&mut |b| buf.push(b));
+        String::from_utf8(buf).unwrap()
+    }
+
+    fn dec_u64(val: u64) -> String {
+        capture(|out| format_dec_u64(val, &mut |b| out(b)))
+    }
+
+    fn dec_i64(val: i64) -> String {
+        capture(|out| format_dec_i64(val, &mut |b| out(b)))
+    }
+
+    fn size(bytes: u64) -> String {
+        capture(|out| format_size(bytes, &mut |b| out(b)))
+    }
+
+    #[test]
+    fn test_tx_wait_never_ready() {
+        let tx = TxState::new(100);
+        let mut reads = 0;
+        assert_eq!(
+            tx.wait(|| {
+                reads += 1;
+                registers::FR_TXFF
+            }),
+            TxWait::TimedOut
+        );
+        assert_eq!(reads, 100);
+
+        // Sticky: later writes don't poll at all
+        assert_eq!(tx.wait(|| unreachable!()), TxWait::Skipped);
+        assert_eq!(
+            tx.status(),
+            Status {
+                degraded: true,
+                device_absent: false,
+                dropped_waits: 2,
+                dropped_bytes: 0,
+                fr_reads: 100,
+                tx_bytes: 0,
+                rx_dropped: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_tx_wait_eventually_ready() {
+        let tx = TxState::new(100);
+        let mut reads = 0;
+        let outcome = tx.wait(|| {
+            reads += 1;
+            if reads < 50 { registers::FR_TXFF } else { 0 }
+        });
+        assert_eq!(outcome, TxWait::Ready);
+        assert_eq!(reads, 50);
+        assert_eq!(tx.wait(|| 0), TxWait::Ready);
+        assert!(!tx.status().degraded);
+        assert_eq!(tx.status().dropped_waits, 0);
+    }
+
+    #[test]
+    fn test_tx_wait_absent() {
+        let tx = TxState::new(100);
+        let mut reads = 0;
+        let outcome = tx.wait(|| {
+            reads += 1;
+            registers::FR_ABSENT
+        });
+        assert_eq!(outcome, TxWait::Absent);
+        assert_eq!(reads, 1);
+        assert!(tx.status().degraded);
+        assert!(tx.status().device_absent);
+    }
+
+    #[test]
+    fn test_tx_spin_limit() {
+        let tx = TxState::new(100);
+        tx.set_spin_limit(3);
+        let mut reads = 0;
+        tx.wait(|| {
+            reads += 1;
+            registers::FR_TXFF
+        });
+        assert_eq!(reads, 3);
+    }
+
+    /// TX FIFO with room for a set number of bytes.
+    struct MockFifo {
+        space: usize,
+        sent: Vec<u8>,
+    }
+
+    impl MockFifo {
+        fn new(space: usize) -> Self {
+            Self {
+                space,
+                sent: Vec::new(),
+            }
+        }
+
+        /// Non-blocking write of `s`, accounted like `try_write_str`.
+        fn try_write(&mut self, tx: &TxState, drops: &DropCounter, s: &str) -> usize {
+            let space = core::cell::Cell::new(self.space);
+            let sent = fill_nonblocking(
+                s.as_bytes(),
+                || {
+                    if space.get() == 0 {
+                        registers::FR_TXFF
+                    } else {
+                        0
+                    }
+                },
+                |byte| {
+                    space.set(space.get() - 1);
+                    self.sent.push(byte);
+                },
+            );
+            self.space = space.get();
+            tx.count_dropped(drops, s.len(), sent)
+        }
+    }
+
+    /// TX FIFO that sends `drain[i]` bytes before the `i`th flag read.
+    struct ScriptedFifo {
+        depth: usize,
+        queued: usize,
+        drain: Vec<usize>,
+        reads: usize,
+        sent: Vec<u8>,
+    }
+
+    impl ScriptedFifo {
+        fn new(depth: usize, drain: &[usize]) -> Self {
+            Self {
+                depth,
+                queued: 0,
+                drain: drain.to_vec(),
+                reads: 0,
+                sent: Vec::new(),
+            }
+        }
+
+        fn read_fr(&mut self) -> u32 {
+            // Drains everything once the script runs out
+            let drained = self.drain.get(self.reads).copied().unwrap_or(self.depth);
+            self.queued -= drained.min(self.queued);
+            self.reads += 1;
+            match self.queued {
+                0 => registers::FR_TXFE,
+                n if n == self.depth => registers::FR_TXFF,
+                _ => 0,
+            }
+        }
+
+        fn write(&mut self, byte: u8) {
+            assert!(self.queued < self.depth, "byte written while full");
+            self.queued += 1;
+            self.sent.push(byte);
+        }
+
+        /// Blocking write of `bytes` in bursts of up to `batch`.
+        fn write_batched(&mut self, tx: &TxState, bytes: &[u8], batch: usize) {
+            let fifo = core::cell::RefCell::new(self);
+            tx.write_batched(
+                bytes,
+                batch,
+                || fifo.borrow_mut().read_fr(),
+                |byte| fifo.borrow_mut().write(byte),
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_batched_bursts() {
+        let tx = TxState::new(100);
+        let data: Vec<u8> = (0..100).collect();
+        let mut fifo = ScriptedFifo::new(FIFO_DEPTH, &[]);
+        fifo.write_batched(&tx, &data, FIFO_DEPTH);
+        assert_eq!(fifo.sent, data);
+        // One read per burst of 16
+        assert_eq!(fifo.reads, 7);
+        let status = tx.status();
+        assert_eq!((status.fr_reads, status.tx_bytes), (7, 100));
+    }
+
+    #[test]
+    fn test_write_batched_full_fifo() {
+        let tx = TxState::new(100);
+        let data: Vec<u8> = (0..40).collect();
+        // Drains nothing for a while, then a byte at a time, then stalls
+        // again before emptying
+        let drain = [0, 0, 0, 1, 0, 2, 1, 0, 0, 5, 0, 0, 16];
+        let mut fifo = ScriptedFifo::new(FIFO_DEPTH, &drain);
+        fifo.write_batched(&tx, &data, FIFO_DEPTH);
+        assert_eq!(fifo.sent, data);
+        assert!(!tx.status().degraded);
+        assert_eq!(tx.status().tx_bytes, 40);
+    }
+
+    #[test]
+    fn test_write_batched_partial_space_is_one_byte() {
+        let tx = TxState::new(100);
+        // Never empties: each read frees a single slot
+        let mut fifo = ScriptedFifo::new(4, &[1; 64]);
+        fifo.queued = 3;
+        fifo.write_batched(&tx, b"abcdef", FIFO_DEPTH);
+        assert_eq!(fifo.sent, b"abcdef");
+        assert_eq!(fifo.reads, 6);
+    }
+
+    #[test]
+    fn test_write_batched_without_fifo() {
+        let tx = TxState::new(100);
+        let mut fifo = ScriptedFifo::new(1, &[]);
+        fifo.write_batched(&tx, b"hello", 1);
+        assert_eq!(fifo.sent, b"hello");
+        assert_eq!(fifo.reads, 5);
+        // A zero batch is treated as unbatched, not as stuck
+        fifo.write_batched(&tx, b"!", 0);
+        assert_eq!(fifo.sent, b"hello!");
+    }
+
+    #[test]
+    fn test_write_batched_timeout() {
+        let tx = TxState::new(3);
+        let mut writes = Vec::new();
+        tx.write_batched(
+            b"abc",
+            FIFO_DEPTH,
+            || registers::FR_TXFF,
+            |b| writes.push(b),
+        );
+        // Written anyway, one wait each until degraded
+        assert_eq!(writes, b"abc");
+        let status = tx.status();
+        assert!(status.degraded);
+        assert_eq!((status.fr_reads, status.dropped_waits), (3, 3));
+    }
+
+    /// A UART access seen by [`FullThenReady`].
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Access {
+        /// FR read, with the value returned.
+        Flags(u32),
+        /// DR write.
+        Data(u8),
+    }
+
+    /// A UART whose TX FIFO reports full for the first `full` FR reads,
+    /// logging every access in order.
+    struct FullThenReady {
+        full: usize,
+        log: Vec<Access>,
+    }
+
+    impl FullThenReady {
+        fn new(full: usize) -> core::cell::RefCell<Self> {
+            core::cell::RefCell::new(Self {
+                full,
+                log: Vec::new(),
+            })
+        }
+
+        fn read_fr(&mut self) -> u32 {
+            let flags = if self.full > 0 {
+                self.full -= 1;
+                registers::FR_TXFF
+            } else {
+                0
+            };
+            self.log.push(Access::Flags(flags));
+            flags
+        }
+
+        fn write(&mut self, byte: u8) {
+            self.log.push(Access::Data(byte));
+        }
+
+        /// Asserts every DR write directly follows an FR read without
+        /// TXFF, and returns the bytes written.
+        fn checked_writes(&self) -> Vec<u8> {
+            let mut last = None;
+            let mut sent = Vec::new();
+            for &access in &self.log {
+                match access {
+                    Access::Flags(flags) => last = Some(flags),
+                    Access::Data(byte) => {
+                        assert_eq!(last, Some(0), "{byte:#x} written before FR showed space");
+                        last = None;
+                        sent.push(byte);
+                    }
+                }
+            }
+            sent
+        }
+    }
+
+    #[test]
+    fn test_tx_status_checked_before_write() {
+        // The polled single byte path: wait, then write
+        let uart = FullThenReady::new(5);
+        let tx = TxState::new(100);
+        assert_eq!(tx.wait(|| uart.borrow_mut().read_fr()), TxWait::Ready);
+        uart.borrow_mut().write(b'a');
+        assert_eq!(uart.borrow().checked_writes(), b"a");
+        assert_eq!(uart.borrow().log.len(), 7);
+
+        // Batched writes with no FIFO, so every byte is polled for
+        let uart = FullThenReady::new(3);
+        tx.write_batched(
+            b"bcd",
+            1,
+            || uart.borrow_mut().read_fr(),
+            |byte| uart.borrow_mut().write(byte),
+        );
+        assert_eq!(uart.borrow().checked_writes(), b"bcd");
+
+        // The non-blocking path gives up on a full FIFO instead
+        let uart = FullThenReady::new(1);
+        let sent = fill_nonblocking(
+            b"ef",
+            || uart.borrow_mut().read_fr(),
+            |byte| uart.borrow_mut().write(byte),
+        );
+        assert_eq!(sent, 0);
+        assert_eq!(uart.borrow().log, [Access::Flags(registers::FR_TXFF)]);
+        let sent = fill_nonblocking(
+            b"ef",
+            || uart.borrow_mut().read_fr(),
+            |byte| uart.borrow_mut().write(byte),
+        );
+        assert_eq!(sent, 2);
+        assert_eq!(uart.borrow().checked_writes(), b"ef");
+    }
+
+    #[test]
+    fn test_tx_batch_for() {
+        assert_eq!(tx_batch_for(registers::LCR_H_FEN | 0x60), FIFO_DEPTH);
+        assert_eq!(tx_batch_for(0x60), 1);
+    }
+
+    #[test]
+    fn test_try_write_partial() {
+        let tx = TxState::new(100);
+        let irq = DropCounter::new();
+        let heartbeat = DropCounter::new();
+        let mut fifo = MockFifo::new(6);
+
+        assert_eq!(fifo.try_write(&tx, &irq, "abcd"), 4);
+        assert_eq!(fifo.try_write(&tx, &heartbeat, "efgh"), 2);
+        // Full: nothing goes out, everything is counted
+        assert_eq!(fifo.try_write(&tx, &irq, "ijk"), 0);
+        assert_eq!(fifo.sent, b"abcdef");
+        assert_eq!((irq.get(), heartbeat.get()), (3, 2));
+
+        // Room again after the FIFO drained
+        fifo.space = 16;
+        assert_eq!(fifo.try_write(&tx, &heartbeat, "lm"), 2);
+        assert_eq!(fifo.try_write(&tx, &irq, ""), 0);
+        assert_eq!((irq.get(), heartbeat.get()), (3, 2));
+
+        // Dropping bytes does not degrade the blocking path
+        let status = tx.status();
+        assert_eq!(status.dropped_bytes, 5);
+        assert_eq!(status.dropped_waits, 0);
+        assert!(!status.degraded);
+    }
+
+    #[test]
+    fn test_try_write_absent() {
+        let mut writes = 0;
+        let sent = fill_nonblocking(b"abc", || registers::FR_ABSENT, |_| writes += 1);
+        assert_eq!((sent, writes), (0, 0));
+    }
+
+    #[test]
+    fn test_format_dec_u64() {
+        assert_eq!(dec_u64(0), "0");
+        assert_eq!(dec_u64(7), "7");
+        assert_eq!(dec_u64(10), "10");
+        assert_eq!(dec_u64(1234567890), "1234567890");
+        assert_eq!(dec_u64(u64::MAX), "18446744073709551615");
+    }
+
+    #[test]
+    fn test_format_dec_i64() {
+        assert_eq!(dec_i64(0), "0");
+        assert_eq!(dec_i64(-1), "-1");
+        assert_eq!(dec_i64(42), "42");
+        assert_eq!(dec_i64(i64::MAX), "9223372036854775807");
+        assert_eq!(dec_i64(i64::MIN), "-9223372036854775808");
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(size(0), "0 B");
+        assert_eq!(size(1018), "1018 B");
+        assert_eq!(size(1023), "1.00 KiB");
+        assert_eq!(size(1024), "1.00 KiB");
+        assert_eq!(size(1536), "1.50 KiB");
+        assert_eq!(size(0x10_0000), "1.00 MiB");
+        assert_eq!(size(0x4000_0000), "1.00 GiB");
+        // Just below 1 GiB rounds up into the larger unit
+        assert_eq!(size(0x4000_0000 - 1), "1.00 GiB");
+        assert_eq!(size(u64::MAX), "17179869184.00 GiB");
+    }
+
+    #[test]
+    fn test_in_boot_device_map() {
+        assert!(in_boot_device_map(address::virt::UART_BASE));
+        assert!(in_boot_device_map(0x1c09_0000));
+        assert!(!in_boot_device_map(address::virt::RAM_BASE));
+        assert!(!in_boot_device_map(address::virt::RAM_BASE - 0x800));
+        assert!(!in_boot_device_map(u64::MAX));
+    }
+
+    #[test]
+    fn test_rx_ready() {
+        assert!(rx_ready(0));
+        assert!(rx_ready(registers::FR_TXFF));
+        assert!(!rx_ready(registers::FR_RXFE));
+        assert!(!rx_ready(registers::FR_ABSENT));
+    }
+
+    #[test]
+    fn test_rx_error_bits() {
+        use registers::*;
+        assert_eq!(rx_error_bits(0x41, 0), 0);
+        assert_eq!(rx_error_bits(0x41 | 1 << 8, 0), RSR_FE);
+        assert_eq!(rx_error_bits(0x41 | 1 << 11, 0), RSR_OE);
+        // Both sources add up, bits outside the error field are ignored
+        assert_eq!(rx_error_bits(1 << 9, RSR_OE), RSR_PE | RSR_OE);
+        assert_eq!(rx_error_bits(0xf << 8, RSR_OE), RSR_ERRORS);
+        assert_eq!(rx_error_bits(1 << 12, 0xf0), 0);
+    }
+
+    #[test]
+    fn test_decode_rx() {
+        use registers::*;
+        assert_eq!(decode_rx(b'a', 0), Ok(b'a'));
+        assert_eq!(decode_rx(b'a', RSR_OE), Err(UartRxError::Overrun(b'a')));
+        assert_eq!(decode_rx(b'a', RSR_PE), Err(UartRxError::Parity(b'a')));
+        assert_eq!(decode_rx(0, RSR_FE | RSR_BE), Err(UartRxError::Break));
+        assert_eq!(
+            decode_rx(b'a', RSR_PE | RSR_FE | RSR_OE),
+            Err(UartRxError::Framing(b'a'))
+        );
+        assert_eq!(
+            decode_rx(b'a', RSR_PE | RSR_OE),
+            Err(UartRxError::Parity(b'a'))
+        );
+    }
+
+    #[test]
+    fn test_error_stats_record() {
+        use registers::*;
+        let mut stats = UartErrorStats::default();
+        assert!(stats.is_empty());
+        stats.record(0);
+        assert!(stats.is_empty());
+
+        stats.record(RSR_OE);
+        stats.record(RSR_FE | RSR_PE | RSR_BE);
+        stats.record(rx_error_bits(0xf << 8, RSR_OE));
+        assert_eq!(
+            stats,
+            UartErrorStats {
+                overrun: 2,
+                breaks: 2,
+                parity: 2,
+                framing: 2,
+            }
+        );
+        assert_eq!(stats.to_string(), "overrun 2, break 2, parity 2, framing 2");
+    }
+
+    #[test]
+    fn test_read_byte_errors() {
+        use registers::*;
+        // FR reads 0, so there is always a byte to read
+        let mut regs = [0u32; 0x12];
+        let base = regs.as_mut_ptr();
+        let reg = |offset: u64| unsafe { base.add(offset as usize / 4) };
+        let serial = Serial::new(base as u64);
+
+        unsafe {
+            reg(DR).write_volatile(b'x' as u32 | 1 << 10);
+            reg(RSR_ECR).write_volatile(RSR_BE | RSR_OE);
+        }
+        assert_eq!(
+            serial.try_read_byte_checked(),
+            Some(Err(UartRxError::Break))
+        );
+        // The write to ECR cleared the latch
+        assert_eq!(unsafe { reg(RSR_ECR).read_volatile() }, 0);
+
+        unsafe { reg(DR).write_volatile(b'y' as u32 | 1 << 11) };
+        assert_eq!(serial.try_read_byte(), Some(b'y'));
+        unsafe { reg(DR).write_volatile(b'z' as u32) };
+        assert_eq!(serial.try_read_byte(), Some(b'z'));
+        assert_eq!(
+            serial.error_stats(),
+            UartErrorStats {
+                overrun: 2,
+                breaks: 1,
+                parity: 0,
+                framing: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_set_base() {
+        // Register blocks in host memory, FR reads 0 so TX is ready
+        let mut old = [0u32; 0x12];
+        let mut new = [0u32; 0x12];
+        let serial = Serial::new(old.as_mut_ptr() as u64);
+
+        serial.write_byte(b'a');
+        serial.set_base(new.as_mut_ptr() as u64);
+        serial.write_byte(b'b');
+        serial.write_byte(b'c');
+
+        assert_eq!(old[(registers::DR / 4) as usize] & 0xff, b'a' as u32);
+        assert_eq!(new[(registers::DR / 4) as usize] & 0xff, b'c' as u32);
+        assert_eq!(serial.base(), new.as_ptr() as u64);
+    }
+
+    #[test]
+    fn test_rx_irq_fills_ring() {
+        use registers::*;
+        // FR reads 0, so the FIFO never runs dry
+        let mut regs = [0u32; 0x12];
+        let reg = |regs: &[u32], offset: u64| regs[offset as usize / 4];
+        regs[(DR / 4) as usize] = b'q' as u32;
+        let serial = Serial::new(regs.as_mut_ptr() as u64);
+
+        // Polled until RX interrupts are on
+        assert!(!serial.rx_buffered());
+        assert_eq!(serial.pop_byte(), Some(b'q'));
+        serial.enable_irq_rx();
+        assert_eq!(reg(&regs, IMSC), INT_RX | INT_RT);
+        assert_eq!(serial.pop_byte(), None);
+
+        // Without a receive interrupt pending nothing is read
+        serial.handle_rx_irq();
+        assert_eq!(serial.pop_byte(), None);
+
+        regs[(MIS / 4) as usize] = INT_RT;
+        serial.handle_rx_irq();
+        assert_eq!(reg(&regs, ICR), INT_RT);
+        assert_eq!(serial.rx_ring.lock().len(), FIFO_DEPTH);
+        assert_eq!(serial.pop_byte(), Some(b'q'));
+
+        // One FIFO's worth per interrupt until the ring wraps
+        for _ in 0..rx::RX_RING_SIZE / FIFO_DEPTH {
+            serial.handle_rx_irq();
+        }
+        assert_eq!(serial.status().rx_dropped, FIFO_DEPTH as u64 - 1);
+        assert_eq!(serial.rx_ring.lock().len(), rx::RX_RING_SIZE);
+    }
+
+    #[test]
+    fn test_write_bytes_batch_setting() {
+        // FR reads 0: room for a byte, but the FIFO is not known empty
+        let mut regs = [0u32; 0x12];
+        let serial = Serial::new(regs.as_mut_ptr() as u64);
+        serial.write_bytes(b"ab");
+        assert_eq!(serial.tx.status().tx_bytes, 2);
+
+        regs[(registers::LCR_H / 4) as usize] = registers::LCR_H_FEN;
+        serial.detect_tx_batch();
+        assert_eq!(serial.tx_batch.load(Ordering::Relaxed), FIFO_DEPTH as u32);
+        // An explicit setting wins over detection
+        let serial = Serial::new(regs.as_mut_ptr() as u64);
+        serial.set_tx_batch(1);
+        serial.detect_tx_batch();
+        assert_eq!(serial.tx_batch.load(Ordering::Relaxed), 1);
+
+        regs[(registers::FR / 4) as usize] = registers::FR_TXFE;
+        let serial = Serial::new(regs.as_mut_ptr() as u64);
+        serial.set_tx_batch(64);
+        serial.write_bytes(b"0123456789abcdefXY");
+        let status = serial.tx.status();
+        assert_eq!((status.fr_reads, status.tx_bytes), (2, 18));
+        assert_eq!(regs[(registers::DR / 4) as usize] & 0xff, b'Y' as u32);
+    }
+}
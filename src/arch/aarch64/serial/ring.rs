@@ -0,0 +1,157 @@
+//! Fixed-size byte ring for buffered transmit.
+//!
+//! Writers push bytes and the UART TX interrupt pops them into the FIFO.
+//! The ring does no locking of its own; `Serial` keeps it behind an
+//! IRQ-safe lock.
+
+/// Bytes buffered by the global serial instance.
+pub const TX_RING_SIZE: usize = 4096;
+
+/// A FIFO ring of `N` bytes.
+pub struct TxRing<const N: usize> {
+    buf: [u8; N],
+    /// Index of the oldest byte.
+    head: usize,
+    /// Number of buffered bytes.
+    len: usize,
+}
+
+impl<const N: usize> TxRing<N> {
+    /// Create an empty ring.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of buffered bytes.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no bytes are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns true if another push would fail.
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Append `byte`.
+    ///
+    /// # Returns
+    /// `Err(byte)` if the ring is full
+    pub fn push(&mut self, byte: u8) -> Result<(), u8> {
+        if self.is_full() {
+            return Err(byte);
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove and return the oldest byte.
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    /// Pop bytes into `sink` while it accepts them.
+    ///
+    /// `sink` returns false when it has no room; the byte offered then
+    /// stays in the ring.
+    ///
+    /// # Returns
+    /// Number of bytes moved
+    pub fn drain_while(&mut self, mut sink: impl FnMut(u8) -> bool) -> usize {
+        let mut moved = 0;
+        while !self.is_empty() {
+            if !sink(self.buf[self.head]) {
+                break;
+            }
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+            moved += 1;
+        }
+        moved
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_fill_and_drain() {
+        let mut ring = TxRing::<4>::new();
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(), None);
+
+        for b in 1..=4 {
+            assert_eq!(ring.push(b), Ok(()));
+        }
+        assert!(ring.is_full());
+        assert_eq!(ring.push(5), Err(5));
+        assert_eq!(ring.len(), 4);
+
+        for b in 1..=4 {
+            assert_eq!(ring.pop(), Some(b));
+        }
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn test_ring_wrap_around() {
+        let mut ring = TxRing::<4>::new();
+        let mut out = Vec::new();
+
+        // Keep the ring partly full so head and tail wrap many times
+        for b in 0..20u8 {
+            ring.push(b).unwrap();
+            if ring.len() == 3 {
+                out.push(ring.pop().unwrap());
+            }
+        }
+        while let Some(b) = ring.pop() {
+            out.push(b);
+        }
+        assert_eq!(out, (0..20).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_ring_drain_while() {
+        let mut ring = TxRing::<8>::new();
+        for b in b"abcdef" {
+            ring.push(*b).unwrap();
+        }
+
+        // A FIFO with room for four bytes
+        let mut fifo = Vec::new();
+        let moved = ring.drain_while(|b| {
+            if fifo.len() == 4 {
+                return false;
+            }
+            fifo.push(b);
+            true
+        });
+        assert_eq!(moved, 4);
+        assert_eq!(fifo, b"abcd");
+        assert_eq!(ring.len(), 2);
+
+        // The refused byte is still first in line
+        assert_eq!(ring.pop(), Some(b'e'));
+        assert_eq!(ring.drain_while(|_| true), 1);
+        assert!(ring.is_empty());
+    }
+}
@@ -0,0 +1,304 @@
+//! Interrupt-driven receive.
+//!
+//! The UART receive interrupt moves bytes from the hardware FIFO into an
+//! [`RxRing`], and readers take them from there instead of polling the
+//! FIFO. The interrupt handler is the only producer and readers are the
+//! only consumers; `Serial` keeps the ring behind an IRQ-safe lock, so a
+//! reader popping a byte can never be interrupted halfway by the handler
+//! on the same CPU.
+//!
+//! When readers fall behind, the ring drops its oldest bytes: the newest
+//! input, such as the line being typed, is the most useful to keep.
+
+use super::registers;
+
+/// Bytes buffered by the global serial instance.
+pub const RX_RING_SIZE: usize = 256;
+
+/// A FIFO ring of `N` received bytes that overwrites its oldest byte
+/// when full.
+pub struct RxRing<const N: usize> {
+    buf: [u8; N],
+    /// Index of the oldest byte.
+    head: usize,
+    /// Number of buffered bytes.
+    len: usize,
+}
+
+impl<const N: usize> RxRing<N> {
+    /// Create an empty ring.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of buffered bytes.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no bytes are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append `byte`, dropping the oldest byte if the ring is full.
+    ///
+    /// # Returns
+    /// The dropped byte, if any
+    pub fn push(&mut self, byte: u8) -> Option<u8> {
+        let dropped = if self.len == N { self.pop() } else { None };
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        dropped
+    }
+
+    /// Remove and return the oldest byte.
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// What serving one receive interrupt did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Served {
+    /// Bytes moved from the FIFO into the ring.
+    pub received: usize,
+    /// Old bytes the ring dropped to make room.
+    pub dropped: usize,
+}
+
+/// Serve a receive interrupt with masked status `mis`.
+///
+/// The PL011 raises two receive interrupts. The RX interrupt fires once
+/// the FIFO reaches its trigger level and goes away by itself when the
+/// FIFO drains below it. The receive timeout fires when bytes sit below
+/// the trigger level with the line idle, and stays raised until cleared.
+/// Both are cleared before draining, so a byte arriving during the drain
+/// raises a fresh interrupt rather than being left behind.
+///
+/// At most `limit` bytes are moved, so a flood cannot keep the handler
+/// running; bytes left in the FIFO keep the level triggered interrupt
+/// pending.
+///
+/// # Arguments
+/// * `mis` - Masked interrupt status; nothing is done without RX or RT
+/// * `ring` - Destination of the received bytes
+/// * `limit` - Most bytes to move
+/// * `clear` - Writes interrupt bits to ICR
+/// * `read` - Reads a byte from the FIFO, `None` once it is empty
+pub fn serve<const N: usize>(
+    mis: u32,
+    ring: &mut RxRing<N>,
+    limit: usize,
+    mut clear: impl FnMut(u32),
+    mut read: impl FnMut() -> Option<u8>,
+) -> Served {
+    let causes = mis & (registers::INT_RX | registers::INT_RT);
+    if causes == 0 {
+        return Served::default();
+    }
+    clear(causes);
+
+    let mut served = Served::default();
+    while served.received < limit {
+        let Some(byte) = read() else {
+            break;
+        };
+        if ring.push(byte).is_some() {
+            served.dropped += 1;
+        }
+        served.received += 1;
+    }
+    served
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::arch::aarch64::serial::FIFO_DEPTH;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_rx_ring_drops_oldest() {
+        let mut ring = RxRing::<4>::new();
+        assert_eq!(ring.pop(), None);
+        for b in 1..=4 {
+            assert_eq!(ring.push(b), None);
+        }
+        assert_eq!(ring.push(5), Some(1));
+        assert_eq!(ring.push(6), Some(2));
+        assert_eq!(ring.len(), 4);
+        let out: Vec<u8> = core::iter::from_fn(|| ring.pop()).collect();
+        assert_eq!(out, [3, 4, 5, 6]);
+        assert!(ring.is_empty());
+    }
+
+    /// A PL011 receiver: a 16 byte FIFO with a trigger level and a
+    /// latched receive timeout.
+    struct Uart {
+        fifo: VecDeque<u8>,
+        trigger: usize,
+        /// Receive timeout raised and not yet cleared.
+        timeout: bool,
+        /// Bytes lost to a full FIFO.
+        overrun: usize,
+    }
+
+    impl Uart {
+        fn new(trigger: usize) -> Self {
+            Self {
+                fifo: VecDeque::new(),
+                trigger,
+                timeout: false,
+                overrun: 0,
+            }
+        }
+
+        /// Bytes come in off the wire.
+        fn receive(&mut self, bytes: impl IntoIterator<Item = u8>) {
+            for byte in bytes {
+                if self.fifo.len() == FIFO_DEPTH {
+                    self.overrun += 1;
+                } else {
+                    self.fifo.push_back(byte);
+                }
+            }
+        }
+
+        /// The line stays idle long enough for the timeout.
+        fn idle(&mut self) {
+            if !self.fifo.is_empty() {
+                self.timeout = true;
+            }
+        }
+
+        fn mis(&self) -> u32 {
+            let rx = if self.fifo.len() >= self.trigger {
+                registers::INT_RX
+            } else {
+                0
+            };
+            let rt = if self.timeout { registers::INT_RT } else { 0 };
+            rx | rt
+        }
+
+        /// Take the receive interrupt if it is raised.
+        fn interrupt<const N: usize>(&mut self, ring: &mut RxRing<N>) -> Served {
+            let uart = core::cell::RefCell::new(self);
+            let mis = uart.borrow().mis();
+            serve(
+                mis,
+                ring,
+                FIFO_DEPTH,
+                |bits| {
+                    if bits & registers::INT_RT != 0 {
+                        uart.borrow_mut().timeout = false;
+                    }
+                },
+                || uart.borrow_mut().fifo.pop_front(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_rx_threshold_and_timeout() {
+        let mut uart = Uart::new(8);
+        let mut ring = RxRing::<RX_RING_SIZE>::new();
+
+        // Below the trigger level nothing is raised yet
+        uart.receive(*b"ls\n");
+        assert_eq!(uart.mis(), 0);
+        assert_eq!(uart.interrupt(&mut ring), Served::default());
+        assert_eq!(uart.fifo.len(), 3);
+
+        // The timeout picks up the short line and is cleared
+        uart.idle();
+        assert_eq!(uart.mis(), registers::INT_RT);
+        let served = uart.interrupt(&mut ring);
+        assert_eq!(served.received, 3);
+        assert_eq!(uart.mis(), 0);
+
+        // Reaching the trigger level raises RX, which needs no clearing
+        uart.receive(0..10);
+        assert_eq!(uart.mis(), registers::INT_RX);
+        assert_eq!(uart.interrupt(&mut ring).received, 10);
+        assert_eq!(uart.mis(), 0);
+
+        // Some other interrupt leaves the FIFO alone
+        uart.receive(0..9);
+        assert_eq!(
+            serve(registers::INT_TX, &mut ring, FIFO_DEPTH, |_| {}, || Some(0)),
+            Served::default()
+        );
+        assert_eq!(ring.len(), 13);
+    }
+
+    #[test]
+    fn test_rx_interleaved_with_reader() {
+        let mut uart = Uart::new(8);
+        let mut ring = RxRing::<8>::new();
+        let mut read = Vec::new();
+
+        // The reader keeps up: it runs between interrupts, popping fewer
+        // bytes than arrived, and the ring never overflows
+        let mut next = 0u8;
+        for round in 0..20 {
+            uart.receive(next..next + 5);
+            next += 5;
+            uart.idle();
+            assert_eq!(uart.interrupt(&mut ring).dropped, 0);
+            let take = if round % 2 == 0 { 3 } else { 8 };
+            read.extend(core::iter::from_fn(|| ring.pop()).take(take));
+        }
+        read.extend(core::iter::from_fn(|| ring.pop()));
+        assert_eq!(read, (0..100).collect::<Vec<u8>>());
+        assert_eq!(uart.overrun, 0);
+    }
+
+    #[test]
+    fn test_rx_burst_larger_than_fifo_and_ring() {
+        let mut uart = Uart::new(8);
+        let mut ring = RxRing::<RX_RING_SIZE>::new();
+        let burst: Vec<u8> = (0..600u32).map(|i| i as u8).collect();
+
+        // Interrupts keep the FIFO from overflowing, but nobody reads the
+        // ring while the burst lasts
+        let mut dropped = 0;
+        for chunk in burst.chunks(FIFO_DEPTH) {
+            uart.receive(chunk.iter().copied());
+            dropped += uart.interrupt(&mut ring).dropped;
+        }
+        uart.idle();
+        dropped += uart.interrupt(&mut ring).dropped;
+
+        assert_eq!(uart.overrun, 0);
+        assert_eq!(dropped, burst.len() - RX_RING_SIZE);
+        let kept: Vec<u8> = core::iter::from_fn(|| ring.pop()).collect();
+        assert_eq!(kept, burst[burst.len() - RX_RING_SIZE..]);
+
+        // One interrupt for a whole burst: the FIFO overruns, and the
+        // handler moves at most its limit per call
+        uart.receive(burst.iter().copied());
+        assert_eq!(uart.overrun, burst.len() - FIFO_DEPTH);
+        let served = serve(
+            registers::INT_RX,
+            &mut ring,
+            4,
+            |_| {},
+            || uart.fifo.pop_front(),
+        );
+        assert_eq!(served.received, 4);
+        assert_eq!(uart.fifo.len(), FIFO_DEPTH - 4);
+    }
+}
@@ -0,0 +1,403 @@
+//! PL011 loopback self test.
+//!
+//! With CR.LBE set the UART feeds its transmitter straight into its
+//! receiver, so a known pattern sent one byte at a time must come back
+//! unchanged and without receive errors. A failure points at the driver or
+//! the UART model rather than at whatever sits on the other end of the
+//! line.
+//!
+//! [`run`] drives the test through a [`Port`], so the host tests can stand
+//! in a UART that loses or corrupts bytes. Every wait is bounded: TX waits
+//! go through a private [`TxState`], so a stuck FIFO fails the test without
+//! degrading the real console, and RX waits use the same spin budget.
+
+use super::{TxState, TxWait, UartRxError, registers, rx_ready};
+use core::fmt;
+
+/// Bytes sent through the loopback.
+pub const PATTERN_LEN: usize = 64;
+
+/// Pattern sent through the loopback: every bit set and cleared at least
+/// once, plus the bytes the console treats specially.
+pub const PATTERN: [u8; PATTERN_LEN] = pattern();
+
+const fn pattern() -> [u8; PATTERN_LEN] {
+    let mut bytes = [0; PATTERN_LEN];
+    let mut i = 0;
+    while i < PATTERN_LEN {
+        bytes[i] = match i % 8 {
+            0 => 0x00,
+            1 => 0xff,
+            2 => 0x55,
+            3 => 0xaa,
+            4 => b'\n',
+            5 => 0x7e,
+            _ => (i * 37) as u8,
+        } ^ (i / 8) as u8;
+        i += 1;
+    }
+    bytes
+}
+
+/// Most stale bytes drained before the test; the PL011 RX FIFO holds 32.
+pub const MAX_STALE: usize = 64;
+
+/// Reasons the self test fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialSelftestError {
+    /// The flag register reads back all-ones.
+    DeviceAbsent,
+    /// The transmitter did not finish sending before the mode switch.
+    Busy,
+    /// The receive FIFO kept reporting data after [`MAX_STALE`] reads.
+    StaleData,
+    /// No FIFO space to send pattern byte `index`.
+    TxTimeout { index: usize },
+    /// Pattern byte `index` never came back.
+    RxTimeout { index: usize },
+    /// Pattern byte `index` came back with a receive error.
+    Rx { index: usize, error: UartRxError },
+    /// Pattern byte `index` came back as a different byte.
+    Mismatch {
+        index: usize,
+        expected: u8,
+        actual: u8,
+    },
+    /// The UART's receive error counters changed during the test.
+    ErrorsCounted,
+}
+
+impl fmt::Display for SerialSelftestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeviceAbsent => f.write_str("UART absent"),
+            Self::Busy => f.write_str("transmitter stayed busy"),
+            Self::StaleData => f.write_str("receive FIFO does not drain"),
+            Self::TxTimeout { index } => write!(f, "TX timeout at byte {}", index),
+            Self::RxTimeout { index } => write!(f, "RX timeout at byte {}", index),
+            Self::Rx { index, error } => write!(f, "RX error {:?} at byte {}", error, index),
+            Self::Mismatch {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "byte {} read back as {:#04x}, sent {:#04x}",
+                index, actual, expected
+            ),
+            Self::ErrorsCounted => f.write_str("receive errors counted"),
+        }
+    }
+}
+
+/// The UART registers the self test touches.
+pub trait Port {
+    /// Read the flag register.
+    fn read_flags(&mut self) -> u32;
+
+    /// Write one byte to the data register.
+    fn write_data(&mut self, byte: u8);
+
+    /// Read one byte from the data register with its receive errors.
+    fn read_data(&mut self) -> Result<u8, UartRxError>;
+
+    /// Read the control register.
+    fn read_control(&mut self) -> u32;
+
+    /// Write the control register.
+    fn write_control(&mut self, cr: u32);
+}
+
+/// What a passing run cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SelftestReport {
+    /// Stale bytes drained before the pattern.
+    pub drained: usize,
+    /// Flag register polls spent waiting for received bytes.
+    pub rx_polls: u64,
+}
+
+/// Poll `port` until the transmitter is idle, at most `spin_limit` times.
+fn wait_idle(port: &mut impl Port, spin_limit: u32) -> Result<(), SerialSelftestError> {
+    for _ in 0..spin_limit {
+        let flags = port.read_flags();
+        if flags == registers::FR_ABSENT {
+            return Err(SerialSelftestError::DeviceAbsent);
+        }
+        if flags & registers::FR_BUSY == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(SerialSelftestError::Busy)
+}
+
+/// Read and discard whatever the receive FIFO holds.
+///
+/// # Returns
+/// The number of bytes discarded
+fn drain_stale(port: &mut impl Port) -> Result<usize, SerialSelftestError> {
+    for drained in 0..=MAX_STALE {
+        if !rx_ready(port.read_flags()) {
+            return Ok(drained);
+        }
+        if drained < MAX_STALE {
+            let _ = port.read_data();
+        }
+    }
+    Err(SerialSelftestError::StaleData)
+}
+
+/// Send `pattern` through the loopback and check every byte.
+fn echo(
+    port: &mut impl Port,
+    pattern: &[u8],
+    spin_limit: u32,
+    report: &mut SelftestReport,
+) -> Result<(), SerialSelftestError> {
+    let tx = TxState::new(spin_limit);
+    for (index, &expected) in pattern.iter().enumerate() {
+        match tx.wait(|| port.read_flags()) {
+            TxWait::Ready => port.write_data(expected),
+            TxWait::Absent => return Err(SerialSelftestError::DeviceAbsent),
+            TxWait::TimedOut | TxWait::Skipped => {
+                return Err(SerialSelftestError::TxTimeout { index });
+            }
+        }
+
+        let mut received = false;
+        for _ in 0..spin_limit {
+            report.rx_polls += 1;
+            if rx_ready(port.read_flags()) {
+                received = true;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        if !received {
+            return Err(SerialSelftestError::RxTimeout { index });
+        }
+
+        match port.read_data() {
+            Ok(actual) if actual == expected => {}
+            Ok(actual) => {
+                return Err(SerialSelftestError::Mismatch {
+                    index,
+                    expected,
+                    actual,
+                });
+            }
+            Err(error) => return Err(SerialSelftestError::Rx { index, error }),
+        }
+    }
+    Ok(())
+}
+
+/// Run the loopback test on `port`.
+///
+/// Waits for the transmitter to go idle so no console output is looped
+/// back, enables loopback, drains stale received bytes, echoes
+/// [`PATTERN`] and restores the control register, whether or not the test
+/// passed.
+///
+/// # Arguments
+/// * `port` - UART registers
+/// * `spin_limit` - Flag register polls allowed per wait
+pub fn run(port: &mut impl Port, spin_limit: u32) -> Result<SelftestReport, SerialSelftestError> {
+    wait_idle(port, spin_limit)?;
+    let cr = port.read_control();
+    port.write_control(cr | registers::CR_LBE);
+
+    let mut report = SelftestReport::default();
+    let result = drain_stale(port).and_then(|drained| {
+        report.drained = drained;
+        echo(port, &PATTERN, spin_limit, &mut report)
+    });
+
+    // Nothing is left in flight when loopback is switched off
+    let idle = wait_idle(port, spin_limit);
+    port.write_control(cr);
+    result.and(idle).map(|_| report)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// A PL011 in loopback that may lose or corrupt bytes.
+    struct MockUart {
+        cr: u32,
+        rx: VecDeque<Result<u8, UartRxError>>,
+        written: usize,
+        /// Lose every n-th byte sent.
+        drop_every: Option<usize>,
+        /// Applied to every byte looped back.
+        corrupt: fn(u8) -> Result<u8, UartRxError>,
+        /// Polls the transmitter stays busy for.
+        busy_polls: u32,
+        /// A stuck receiver: always reports data.
+        rx_stuck: bool,
+        absent: bool,
+        cr_writes: Vec<u32>,
+    }
+
+    impl MockUart {
+        fn new() -> Self {
+            Self {
+                cr: 0x301,
+                rx: VecDeque::new(),
+                written: 0,
+                drop_every: None,
+                corrupt: Ok,
+                busy_polls: 0,
+                rx_stuck: false,
+                absent: false,
+                cr_writes: Vec::new(),
+            }
+        }
+    }
+
+    impl Port for MockUart {
+        fn read_flags(&mut self) -> u32 {
+            if self.absent {
+                return registers::FR_ABSENT;
+            }
+            let mut flags = 0;
+            if self.busy_polls > 0 {
+                self.busy_polls -= 1;
+                flags |= registers::FR_BUSY;
+            }
+            if self.rx.is_empty() && !self.rx_stuck {
+                flags |= registers::FR_RXFE;
+            }
+            flags
+        }
+
+        fn write_data(&mut self, byte: u8) {
+            self.written += 1;
+            let lost = self
+                .drop_every
+                .is_some_and(|n| self.written.is_multiple_of(n));
+            if self.cr & registers::CR_LBE != 0 && !lost {
+                self.rx.push_back((self.corrupt)(byte));
+            }
+        }
+
+        fn read_data(&mut self) -> Result<u8, UartRxError> {
+            self.rx.pop_front().unwrap_or(Ok(0))
+        }
+
+        fn read_control(&mut self) -> u32 {
+            self.cr
+        }
+
+        fn write_control(&mut self, cr: u32) {
+            self.cr = cr;
+            self.cr_writes.push(cr);
+        }
+    }
+
+    #[test]
+    fn test_pattern() {
+        // Every bit of a byte is exercised both ways
+        assert_eq!(PATTERN.iter().fold(0, |acc, &b| acc | b), 0xff);
+        assert_eq!(PATTERN.iter().fold(0xff, |acc, &b| acc & b), 0);
+        assert!(PATTERN.contains(&b'\n') && PATTERN.contains(&0x7e));
+    }
+
+    #[test]
+    fn test_selftest_passes() {
+        let mut uart = MockUart::new();
+        // Stale bytes from before the test are discarded, not compared
+        uart.rx
+            .extend([Ok(b'x'), Err(UartRxError::Break), Ok(b'y')]);
+        uart.busy_polls = 3;
+
+        let report = run(&mut uart, 100).unwrap();
+        assert_eq!(report.drained, 3);
+        assert_eq!(report.rx_polls, PATTERN_LEN as u64);
+        assert_eq!(uart.written, PATTERN_LEN);
+        // Loopback on, then the original control register back
+        assert_eq!(uart.cr_writes, [0x301 | registers::CR_LBE, 0x301]);
+    }
+
+    #[test]
+    fn test_selftest_dropped_byte() {
+        let mut uart = MockUart::new();
+        uart.drop_every = Some(7);
+
+        assert_eq!(
+            run(&mut uart, 100),
+            Err(SerialSelftestError::RxTimeout { index: 6 })
+        );
+        // The timeout is bounded and loopback is still switched off
+        assert_eq!(uart.written, 7);
+        assert_eq!(uart.cr, 0x301);
+    }
+
+    #[test]
+    fn test_selftest_corrupted_echo() {
+        let mut uart = MockUart::new();
+        uart.corrupt = |b| Ok(b ^ 0x10);
+
+        assert_eq!(
+            run(&mut uart, 100),
+            Err(SerialSelftestError::Mismatch {
+                index: 0,
+                expected: PATTERN[0],
+                actual: PATTERN[0] ^ 0x10,
+            })
+        );
+        assert_eq!(uart.cr, 0x301);
+    }
+
+    #[test]
+    fn test_selftest_rx_error() {
+        let mut uart = MockUart::new();
+        uart.corrupt = |b| Err(UartRxError::Framing(b));
+
+        assert_eq!(
+            run(&mut uart, 100),
+            Err(SerialSelftestError::Rx {
+                index: 0,
+                error: UartRxError::Framing(PATTERN[0]),
+            })
+        );
+    }
+
+    #[test]
+    fn test_selftest_bounded_waits() {
+        let mut uart = MockUart::new();
+        uart.rx_stuck = true;
+        assert_eq!(run(&mut uart, 100), Err(SerialSelftestError::StaleData));
+        assert_eq!(uart.cr, 0x301);
+
+        let mut uart = MockUart::new();
+        uart.busy_polls = u32::MAX;
+        assert_eq!(run(&mut uart, 100), Err(SerialSelftestError::Busy));
+        // Never switched to loopback
+        assert!(uart.cr_writes.is_empty());
+
+        let mut uart = MockUart::new();
+        uart.absent = true;
+        assert_eq!(run(&mut uart, 100), Err(SerialSelftestError::DeviceAbsent));
+    }
+
+    #[test]
+    fn test_selftest_error_display() {
+        assert_eq!(
+            SerialSelftestError::Mismatch {
+                index: 3,
+                expected: 0xaa,
+                actual: 0xab
+            }
+            .to_string(),
+            "byte 3 read back as 0xab, sent 0xaa"
+        );
+        assert_eq!(
+            SerialSelftestError::RxTimeout { index: 6 }.to_string(),
+            "RX timeout at byte 6"
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Locks that are safe to share with interrupt handlers.
+//!
+//! A plain spin lock deadlocks if an IRQ handler on the same CPU tries to
+//! take a lock the interrupted code already holds. `IrqSafeMutex` masks
+//! IRQs for as long as the lock is held.
+//!
+//! With the `lock-debug` feature, taking a lock the current CPU already
+//! holds panics with the lock name instead of spinning forever.
+
+use crate::arch::irq::{self, IrqFlags};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Owner value meaning "not held".
+const NO_OWNER: u32 = u32::MAX;
+
+/// Returns the ID of the executing CPU.
+#[cfg(target_os = "none")]
+fn current_cpu() -> u32 {
+    crate::arch::exception::cpu_id() as u32
+}
+
+#[cfg(not(target_os = "none"))]
+std::thread_local! {
+    /// Mock CPU ID, settable by tests.
+    static CPU_ID: core::cell::Cell<u32> = const { core::cell::Cell::new(0) };
+}
+
+/// Returns the ID of the executing CPU.
+#[cfg(not(target_os = "none"))]
+fn current_cpu() -> u32 {
+    CPU_ID.with(|id| id.get())
+}
+
+/// Records which CPU holds a lock, to catch re-entrant acquisition.
+struct OwnerTracker(AtomicU32);
+
+impl OwnerTracker {
+    const fn new() -> Self {
+        Self(AtomicU32::new(NO_OWNER))
+    }
+
+    /// Returns true if `cpu` already holds the lock.
+    fn held_by(&self, cpu: u32) -> bool {
+        self.0.load(Ordering::Relaxed) == cpu
+    }
+
+    fn acquired(&self, cpu: u32) {
+        self.0.store(cpu, Ordering::Relaxed);
+    }
+
+    fn released(&self) {
+        self.0.store(NO_OWNER, Ordering::Relaxed);
+    }
+}
+
+/// A spin lock that masks IRQs on the local CPU while held.
+pub struct IrqSafeMutex<T> {
+    name: &'static str,
+    inner: spin::Mutex<T>,
+    owner: OwnerTracker,
+}
+
+impl<T> IrqSafeMutex<T> {
+    /// Create a new unlocked mutex.
+    ///
+    /// # Arguments
+    /// * `name` - Name reported by re-entrancy checks
+    /// * `value` - Protected value
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            inner: spin::Mutex::new(value),
+            owner: OwnerTracker::new(),
+        }
+    }
+
+    /// Returns the lock name.
+    #[allow(dead_code)]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Mask IRQs and acquire the lock, spinning until it is free.
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let flags = irq::disable_save();
+        let cpu = current_cpu();
+        self.check_reentry(cpu);
+
+        let guard = self.inner.lock();
+        self.owner.acquired(cpu);
+        IrqSafeMutexGuard {
+            lock: self,
+            guard: Some(guard),
+            flags,
+        }
+    }
+
+    /// Mask IRQs and acquire the lock if it is free.
+    ///
+    /// Never spins, so it is also safe to call from a handler that
+    /// interrupted the holder; the IRQ state is unchanged on failure.
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let flags = irq::disable_save();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                self.owner.acquired(current_cpu());
+                Some(IrqSafeMutexGuard {
+                    lock: self,
+                    guard: Some(guard),
+                    flags,
+                })
+            }
+            None => {
+                irq::restore(flags);
+                None
+            }
+        }
+    }
+
+    /// Panic if `cpu` already holds the lock and `lock-debug` is enabled.
+    fn check_reentry(&self, cpu: u32) {
+        if cfg!(feature = "lock-debug") && self.owner.held_by(cpu) {
+            panic!(
+                "re-entrant acquisition of lock {} on CPU {}",
+                self.name, cpu
+            );
+        }
+    }
+}
+
+/// Guard returned by [`IrqSafeMutex::lock`].
+///
+/// Dropping it releases the lock, then restores the saved IRQ state.
+pub struct IrqSafeMutexGuard<'a, T> {
+    lock: &'a IrqSafeMutex<T>,
+    /// Always `Some` until dropped.
+    guard: Option<spin::MutexGuard<'a, T>>,
+    flags: IrqFlags,
+}
+
+impl<T> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.released();
+        // Unlock before unmasking, or an IRQ could spin on our lock
+        drop(self.guard.take());
+        irq::restore(self.flags);
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irq_safe_mutex_masks_irqs() {
+        let lock = IrqSafeMutex::new("test", 1);
+        assert!(irq::irqs_enabled());
+        {
+            let mut guard = lock.lock();
+            assert!(!irq::irqs_enabled());
+            *guard += 1;
+        }
+        assert!(irq::irqs_enabled());
+        assert_eq!(*lock.lock(), 2);
+    }
+
+    #[test]
+    fn test_irq_safe_mutex_nested_locks() {
+        let a = IrqSafeMutex::new("a", ());
+        let b = IrqSafeMutex::new("b", ());
+
+        let ga = a.lock();
+        let gb = b.lock();
+        drop(gb);
+        // Still inside `a`, so IRQs stay masked
+        assert!(!irq::irqs_enabled());
+        drop(ga);
+        assert!(irq::irqs_enabled());
+    }
+
+    #[test]
+    fn test_irq_safe_mutex_try_lock() {
+        let lock = IrqSafeMutex::new("test", ());
+        let guard = lock.try_lock().unwrap();
+
+        // Failing keeps the state of the holder
+        assert!(lock.try_lock().is_none());
+        assert!(!irq::irqs_enabled());
+        drop(guard);
+        assert!(irq::irqs_enabled());
+
+        // Failing on another CPU leaves its IRQs enabled
+        let held = lock.lock();
+        let other = std::thread::scope(|s| {
+            s.spawn(|| (lock.try_lock().is_none(), irq::irqs_enabled()))
+                .join()
+                .unwrap()
+        });
+        drop(held);
+        assert_eq!(other, (true, true));
+    }
+
+    #[test]
+    fn test_owner_tracking() {
+        let lock = IrqSafeMutex::new("test", ());
+        assert!(!lock.owner.held_by(0));
+
+        let guard = lock.lock();
+        assert!(lock.owner.held_by(0));
+        assert!(!lock.owner.held_by(1));
+        drop(guard);
+        assert!(!lock.owner.held_by(0));
+
+        // Recorded per CPU
+        CPU_ID.with(|id| id.set(3));
+        let guard = lock.try_lock().unwrap();
+        assert!(lock.owner.held_by(3));
+        assert!(!lock.owner.held_by(0));
+        drop(guard);
+        CPU_ID.with(|id| id.set(0));
+    }
+
+    #[test]
+    #[cfg(feature = "lock-debug")]
+    #[should_panic(expected = "re-entrant acquisition of lock memblock on CPU 0")]
+    fn test_reentry_panics() {
+        let lock = IrqSafeMutex::new("memblock", ());
+        let _guard = lock.lock();
+        let _again = lock.lock();
+    }
+}
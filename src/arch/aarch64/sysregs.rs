@@ -0,0 +1,572 @@
+//! Typed values for the MMU control registers.
+//!
+//! boot.S loads `MAIR_EL1`, `TCR_EL1` and `SCTLR_EL1` from constants
+//! built here with [`MairValue`], [`TcrValue`] and [`SctlrFlags`], so the
+//! bit packing is reviewed and tested in Rust rather than spelled as hex
+//! in assembly. Each builder can also check a value read back from the
+//! register and report the fields that differ, which [`check_boot`]
+//! does once the kernel runs: the MMU is already on by then, since Rust
+//! code is linked at its virtual address, but a mismatch is still
+//! reported before anything relies on the memory types.
+
+use crate::arch::address::{kernel, mair};
+use crate::arch::cpu::sctlr;
+use core::fmt;
+
+/// A register field, `width` bits at `shift`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    /// Field name as in the Arm ARM.
+    pub name: &'static str,
+    /// Lowest bit of the field.
+    pub shift: u32,
+    /// Width of the field in bits.
+    pub width: u32,
+}
+
+impl Field {
+    const fn new(name: &'static str, shift: u32, width: u32) -> Self {
+        Self { name, shift, width }
+    }
+
+    /// Returns the field's bits in place.
+    pub const fn mask(&self) -> u64 {
+        (u64::MAX >> (64 - self.width)) << self.shift
+    }
+
+    /// Extract the field from `value`.
+    pub const fn get(&self, value: u64) -> u64 {
+        (value & self.mask()) >> self.shift
+    }
+}
+
+/// MAIR_EL1 fields, one attribute byte per index.
+const MAIR_FIELDS: [Field; 8] = [
+    Field::new("Attr0", 0, 8),
+    Field::new("Attr1", 8, 8),
+    Field::new("Attr2", 16, 8),
+    Field::new("Attr3", 24, 8),
+    Field::new("Attr4", 32, 8),
+    Field::new("Attr5", 40, 8),
+    Field::new("Attr6", 48, 8),
+    Field::new("Attr7", 56, 8),
+];
+
+/// TCR_EL1 fields.
+const TCR_FIELDS: [Field; 17] = [
+    Field::new("T0SZ", 0, 6),
+    Field::new("EPD0", 7, 1),
+    Field::new("IRGN0", 8, 2),
+    Field::new("ORGN0", 10, 2),
+    Field::new("SH0", 12, 2),
+    Field::new("TG0", 14, 2),
+    Field::new("T1SZ", 16, 6),
+    Field::new("A1", 22, 1),
+    Field::new("EPD1", 23, 1),
+    Field::new("IRGN1", 24, 2),
+    Field::new("ORGN1", 26, 2),
+    Field::new("SH1", 28, 2),
+    Field::new("TG1", 30, 2),
+    Field::new("IPS", 32, 3),
+    Field::new("AS", 36, 1),
+    Field::new("TBI0", 37, 1),
+    Field::new("TBI1", 38, 1),
+];
+
+/// SCTLR_EL1 fields the kernel sets or clears.
+const SCTLR_FIELDS: [Field; 6] = [
+    Field::new("M", 0, 1),
+    Field::new("A", 1, 1),
+    Field::new("C", 2, 1),
+    Field::new("I", 12, 1),
+    Field::new("WXN", 19, 1),
+    Field::new("EE", 25, 1),
+];
+
+/// One field that read back differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldMismatch {
+    /// Field name, "other" for bits outside the known fields.
+    pub name: &'static str,
+    /// Value written.
+    pub expected: u64,
+    /// Value read back.
+    pub actual: u64,
+}
+
+/// Differences between a register value and its readback.
+///
+/// The values are kept whole and decoded into fields on demand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MismatchReport {
+    /// Register name.
+    pub register: &'static str,
+    /// Known fields of the register.
+    layout: &'static [Field],
+    /// Bits that were compared.
+    mask: u64,
+    /// Value written.
+    pub expected: u64,
+    /// Value read back.
+    pub actual: u64,
+}
+
+impl MismatchReport {
+    /// Iterates over the differing fields in register bit order.
+    ///
+    /// Differing bits outside the known fields come last, as "other".
+    pub fn fields(&self) -> impl Iterator<Item = FieldMismatch> + '_ {
+        let diff = (self.expected ^ self.actual) & self.mask;
+        let known = self.layout.iter().fold(0, |acc, f| acc | f.mask());
+        let other = FieldMismatch {
+            name: "other",
+            expected: self.expected & self.mask & !known,
+            actual: self.actual & self.mask & !known,
+        };
+
+        self.layout
+            .iter()
+            .filter(move |f| diff & f.mask() != 0)
+            .map(|f| FieldMismatch {
+                name: f.name,
+                expected: f.get(self.expected),
+                actual: f.get(self.actual),
+            })
+            .chain((diff & !known != 0).then_some(other))
+    }
+}
+
+impl fmt::Display for MismatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} mismatch:", self.register)?;
+        for field in self.fields() {
+            write!(
+                f,
+                " {} expected {:#x} got {:#x}",
+                field.name, field.expected, field.actual
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare the bits of `expected` and `actual` selected by `mask`.
+fn compare(
+    register: &'static str,
+    layout: &'static [Field],
+    mask: u64,
+    expected: u64,
+    actual: u64,
+) -> Result<(), MismatchReport> {
+    if (expected ^ actual) & mask == 0 {
+        return Ok(());
+    }
+    Err(MismatchReport {
+        register,
+        layout,
+        mask,
+        expected,
+        actual,
+    })
+}
+
+/// MAIR_EL1 builder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MairValue(u64);
+
+impl MairValue {
+    /// Starts with every index Device-nGnRnE.
+    #[cfg(test)]
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    /// Starts from the slots of `config`.
+    pub const fn from_config(config: &mair::MairConfig) -> Self {
+        Self(config.value())
+    }
+
+    /// Set the attribute at `index`.
+    #[cfg(test)]
+    pub const fn attr(self, index: u64, attr: u64) -> Self {
+        let shift = index * 8;
+        Self((self.0 & !(0xff << shift)) | ((attr & 0xff) << shift))
+    }
+
+    /// Returns the register value.
+    pub const fn build(self) -> u64 {
+        self.0
+    }
+
+    /// Check a value read back from MAIR_EL1.
+    pub fn verify(&self, readback: u64) -> Result<(), MismatchReport> {
+        compare("MAIR_EL1", &MAIR_FIELDS, u64::MAX, self.0, readback)
+    }
+}
+
+/// TCR_EL1 shareability: inner shareable.
+const TCR_SH_INNER: u64 = 0b11;
+
+/// TCR_EL1 cacheability: write-back read-allocate write-allocate.
+const TCR_RGN_WBWA: u64 = 0b01;
+
+/// TCR_EL1.TG0 encoding of the 4KB granule.
+const TCR_TG0_4K: u64 = 0b00;
+
+/// TCR_EL1.TG1 encoding of the 4KB granule.
+const TCR_TG1_4K: u64 = 0b10;
+
+/// TCR_EL1 builder for 4KB granules in both halves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcrValue(u64);
+
+impl TcrValue {
+    /// Starts with `va_bits` of VA in both halves, inner shareable
+    /// write-back walks and IPS 0.
+    pub const fn new(va_bits: u32) -> Self {
+        let tsz = 64 - va_bits as u64;
+        let half = (TCR_SH_INNER << 12) | (TCR_RGN_WBWA << 10) | (TCR_RGN_WBWA << 8);
+        Self(tsz | half | (TCR_TG0_4K << 14) | (tsz << 16) | (half << 16) | (TCR_TG1_4K << 30))
+    }
+
+    /// Set the intermediate physical address size, as a PARange encoding.
+    pub const fn ips(self, parange: u64) -> Self {
+        Self((self.0 & !(0b111 << 32)) | ((parange & 0b111) << 32))
+    }
+
+    /// Returns the register value.
+    pub const fn build(self) -> u64 {
+        self.0
+    }
+
+    /// Check a value read back from TCR_EL1.
+    pub fn verify(&self, readback: u64) -> Result<(), MismatchReport> {
+        compare("TCR_EL1", &TCR_FIELDS, u64::MAX, self.0, readback)
+    }
+}
+
+/// SCTLR_EL1 builder: bits to set and clear on top of a base value.
+///
+/// Only the bits explicitly set or cleared are verified; the rest belong
+/// to the base value, which a loader may have chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SctlrFlags {
+    base: u64,
+    set: u64,
+    clear: u64,
+}
+
+impl SctlrFlags {
+    /// Starts from `base` with nothing set or cleared.
+    pub const fn new(base: u64) -> Self {
+        Self {
+            base,
+            set: 0,
+            clear: 0,
+        }
+    }
+
+    /// Set `bits`.
+    pub const fn set(self, bits: u64) -> Self {
+        Self {
+            set: self.set | bits,
+            clear: self.clear & !bits,
+            ..self
+        }
+    }
+
+    /// Clear `bits`.
+    pub const fn clear(self, bits: u64) -> Self {
+        Self {
+            set: self.set & !bits,
+            clear: self.clear | bits,
+            ..self
+        }
+    }
+
+    /// Returns the register value.
+    pub const fn build(self) -> u64 {
+        (self.base | self.set) & !self.clear
+    }
+
+    /// Check a value read back from SCTLR_EL1.
+    pub fn verify(&self, readback: u64) -> Result<(), MismatchReport> {
+        let mask = self.set | self.clear;
+        compare("SCTLR_EL1", &SCTLR_FIELDS, mask, self.build(), readback)
+    }
+}
+
+/// HCR_EL2 bit positions.
+pub mod hcr {
+    /// EL1 executes in AArch64.
+    pub const RW: u64 = 1 << 31;
+}
+
+/// SPSR_ELx fields.
+pub mod spsr {
+    /// M[3:0] for EL1 using SP_EL1 (EL1h).
+    pub const M_EL1H: u64 = 0b0101;
+    /// FIQ masked.
+    pub const F: u64 = 1 << 6;
+    /// IRQ masked.
+    pub const I: u64 = 1 << 7;
+    /// SError masked.
+    pub const A: u64 = 1 << 8;
+    /// Debug exceptions masked.
+    pub const D: u64 = 1 << 9;
+    /// All of DAIF.
+    pub const DAIF: u64 = D | A | I | F;
+}
+
+/// Returns the SPSR_EL2 value that makes `eret` enter EL1h.
+///
+/// # Arguments
+/// * `masked` - Enter with all of DAIF masked
+pub const fn spsr_el2_to_el1h(masked: bool) -> u64 {
+    if masked {
+        spsr::M_EL1H | spsr::DAIF
+    } else {
+        spsr::M_EL1H
+    }
+}
+
+/// HCR_EL2 written by boot.S before dropping to EL1: AArch64 EL1, nothing
+/// trapped to EL2.
+pub const BOOT_HCR_EL2: u64 = hcr::RW;
+
+/// SPSR_EL2 written by boot.S before dropping to EL1: EL1h, interrupts
+/// masked until the kernel enables them.
+pub const BOOT_SPSR_EL2: u64 = spsr_el2_to_el1h(true);
+
+/// MAIR_EL1 as programmed by boot.S, laid out by `address::mair::BOOT`.
+pub const BOOT_MAIR: MairValue = MairValue::from_config(&mair::BOOT);
+
+/// SCTLR_EL1 reset value written by boot.S: little endian, MMU and
+/// caches off, RES1 bits set.
+pub const SCTLR_EL1_RESET: u64 = 0x30c5_0830;
+
+/// SCTLR_EL1 bits boot.S sets to turn the MMU and caches on.
+pub const SCTLR_MMU_ON: u64 = sctlr::M | sctlr::C | sctlr::I;
+
+/// SCTLR_EL1 once the kernel runs: MMU and caches on, little endian,
+/// no alignment checking, WXN off.
+pub const BOOT_SCTLR: SctlrFlags = SctlrFlags::new(SCTLR_EL1_RESET)
+    .set(SCTLR_MMU_ON)
+    .clear(sctlr::A | sctlr::WXN | sctlr::EE);
+
+/// Returns the TCR_EL1 value boot.S programs for a CPU with `parange`.
+pub const fn boot_tcr(parange: u64) -> TcrValue {
+    TcrValue::new(kernel::VA_BITS).ips(parange)
+}
+
+/// Read MAIR_EL1.
+#[cfg(target_os = "none")]
+pub fn read_mair_el1() -> u64 {
+    crate::arch::reg::read_sysreg!(mair_el1)
+}
+
+/// Read TCR_EL1.
+#[cfg(target_os = "none")]
+pub fn read_tcr_el1() -> u64 {
+    crate::arch::reg::read_sysreg!(tcr_el1)
+}
+
+/// Read SCTLR_EL1.
+#[cfg(target_os = "none")]
+pub fn read_sctlr_el1() -> u64 {
+    crate::arch::reg::sctlr_el1()
+}
+
+/// Compare the live MMU control registers with what boot.S programs.
+///
+/// # Arguments
+/// * `tcr_ips` - IPS boot.S derived from the CPU's PARange
+/// * `out` - Where mismatches are reported
+///
+/// # Returns
+/// True if all three registers match
+#[cfg(target_os = "none")]
+pub fn check_boot(tcr_ips: u64, out: &mut dyn fmt::Write) -> bool {
+    let results = [
+        BOOT_MAIR.verify(read_mair_el1()),
+        boot_tcr(tcr_ips).verify(read_tcr_el1()),
+        BOOT_SCTLR.verify(read_sctlr_el1()),
+    ];
+    let mut ok = true;
+    for report in results.iter().filter_map(|r| r.err()) {
+        let _ = writeln!(out, "{}", report);
+        ok = false;
+    }
+    ok
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_el2_drop_values() {
+        // What boot.S loaded as literals before the constants existed
+        assert_eq!(BOOT_HCR_EL2, 0x8000_0000);
+        assert_eq!(BOOT_SPSR_EL2, 0x3c5);
+        assert_eq!(spsr_el2_to_el1h(false), 0x5);
+    }
+
+    /// MAIR_EL1 as programmed by Linux on QEMU virt: Normal, Normal
+    /// tagged, Normal-NC, Device-nGnRnE, Device-nGnRE at indices 0 to 4.
+    const LINUX_MAIR_EL1: u64 = 0x0000_0004_0044_f0ff;
+
+    #[test]
+    fn test_mair_build() {
+        assert_eq!(BOOT_MAIR.build(), LINUX_MAIR_EL1);
+        assert_eq!(MairValue::new().build(), 0);
+        assert_eq!(MairValue::new().attr(7, 0xff).build(), 0xff << 56);
+        // Setting an index again replaces it
+        assert_eq!(MairValue::new().attr(1, 0xff).attr(1, 0x44).build(), 0x4400);
+        assert_eq!(MairValue::new().attr(0, 0x1ff).build(), 0xff);
+    }
+
+    #[test]
+    fn test_mair_verify() {
+        assert_eq!(BOOT_MAIR.verify(LINUX_MAIR_EL1), Ok(()));
+
+        // Index 3 as Device-nGnRE instead of nGnRnE
+        let report = BOOT_MAIR.verify(LINUX_MAIR_EL1 | 0x04 << 24).unwrap_err();
+        assert_eq!(report.register, "MAIR_EL1");
+        assert_eq!(
+            report.fields().collect::<Vec<_>>(),
+            [FieldMismatch {
+                name: "Attr3",
+                expected: 0x00,
+                actual: 0x04
+            }]
+        );
+
+        let report = BOOT_MAIR.verify(0).unwrap_err();
+        let names: Vec<_> = report.fields().map(|f| f.name).collect();
+        assert_eq!(names, ["Attr0", "Attr1", "Attr2", "Attr4"]);
+    }
+
+    #[test]
+    fn test_tcr_build() {
+        // 39-bit VA: T0SZ = T1SZ = 25
+        assert_eq!(TcrValue::new(39).build(), 0xb519_3519);
+        // 48-bit VA with a 40-bit PA CPU, as on QEMU virt with -cpu max
+        assert_eq!(TcrValue::new(48).ips(2).build(), 0x2_b510_3510);
+        assert_eq!(TcrValue::new(48).ips(9).build(), 0x1_b510_3510);
+        assert_eq!(TcrValue::new(48).ips(5).ips(0).build(), 0xb510_3510);
+        assert_eq!(boot_tcr(0), TcrValue::new(kernel::VA_BITS));
+
+        let value = TcrValue::new(48).build();
+        let get = |name| {
+            TCR_FIELDS
+                .iter()
+                .find(|f| f.name == name)
+                .unwrap()
+                .get(value)
+        };
+        assert_eq!(get("T0SZ"), 16);
+        assert_eq!(get("T1SZ"), 16);
+        assert_eq!(get("TG0"), 0b00);
+        assert_eq!(get("TG1"), 0b10);
+        for (sh, irgn, orgn) in [("SH0", "IRGN0", "ORGN0"), ("SH1", "IRGN1", "ORGN1")] {
+            assert_eq!(get(sh), 0b11);
+            assert_eq!(get(irgn), 0b01);
+            assert_eq!(get(orgn), 0b01);
+        }
+    }
+
+    #[test]
+    fn test_tcr_verify() {
+        let tcr = TcrValue::new(48).ips(5);
+        assert_eq!(tcr.verify(tcr.build()), Ok(()));
+
+        // 64KB granule for TTBR0, IPS of 40 bits and TBI0 set
+        let readback = tcr.build() | 0b01 << 14 | 1 << 37;
+        let readback = (readback & !(0b111 << 32)) | 2 << 32;
+        let report = tcr.verify(readback).unwrap_err();
+        assert_eq!(
+            report.fields().collect::<Vec<_>>(),
+            [
+                FieldMismatch {
+                    name: "TG0",
+                    expected: 0,
+                    actual: 1
+                },
+                FieldMismatch {
+                    name: "IPS",
+                    expected: 5,
+                    actual: 2
+                },
+                FieldMismatch {
+                    name: "TBI0",
+                    expected: 0,
+                    actual: 1
+                },
+            ]
+        );
+        assert_eq!(
+            report.to_string(),
+            "TCR_EL1 mismatch: TG0 expected 0x0 got 0x1 IPS expected 0x5 got 0x2 TBI0 expected 0x0 got 0x1"
+        );
+
+        // Bits outside the named fields
+        let report = tcr.verify(tcr.build() | 1 << 40).unwrap_err();
+        assert_eq!(
+            report.fields().collect::<Vec<_>>(),
+            [FieldMismatch {
+                name: "other",
+                expected: 0,
+                actual: 1 << 40
+            }]
+        );
+    }
+
+    #[test]
+    fn test_sctlr_build() {
+        assert_eq!(SCTLR_MMU_ON, 0x1005);
+        assert_eq!(BOOT_SCTLR.build(), 0x30c5_1835);
+        // Clearing and setting the same bit: the last call wins
+        assert_eq!(SctlrFlags::new(0).set(1).clear(1).build(), 0);
+        assert_eq!(SctlrFlags::new(0).clear(1).set(1).build(), 1);
+        assert_eq!(SctlrFlags::new(0xf0).clear(0x10).build(), 0xe0);
+    }
+
+    #[test]
+    fn test_sctlr_verify() {
+        assert_eq!(BOOT_SCTLR.verify(0x30c5_1835), Ok(()));
+        // Bits the kernel does not care about may differ
+        assert_eq!(BOOT_SCTLR.verify(0x1005 | sctlr::M), Ok(()));
+
+        let report = BOOT_SCTLR
+            .verify((0x30c5_1835 & !sctlr::C) | sctlr::WXN)
+            .unwrap_err();
+        assert_eq!(
+            report.fields().collect::<Vec<_>>(),
+            [
+                FieldMismatch {
+                    name: "C",
+                    expected: 1,
+                    actual: 0
+                },
+                FieldMismatch {
+                    name: "WXN",
+                    expected: 0,
+                    actual: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_field_mask() {
+        assert_eq!(Field::new("x", 0, 64).mask(), u64::MAX);
+        assert_eq!(Field::new("x", 32, 3).mask(), 0x7 << 32);
+        assert_eq!(Field::new("x", 63, 1).get(1 << 63), 1);
+        let covered = TCR_FIELDS.iter().fold(0, |acc, f| {
+            assert_eq!(acc & f.mask(), 0, "{} overlaps", f.name);
+            acc | f.mask()
+        });
+        assert_eq!(covered, 0x77_ffff_ffbf);
+    }
+}
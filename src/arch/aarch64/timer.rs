@@ -0,0 +1,95 @@
+//! ARM generic timer access.
+//!
+//! Provides the raw system counter and conversions between counter ticks
+//! and wall time. No timer interrupts are programmed here.
+
+/// Read the physical system counter (CNTPCT_EL0).
+#[cfg(target_os = "none")]
+pub fn ticks() -> u64 {
+    let ticks: u64;
+    unsafe {
+        // Safety: reading the system counter has no side effects. The ISB
+        // keeps the read from being speculated ahead of earlier code.
+        core::arch::asm!("isb", "mrs {}, cntpct_el0", out(reg) ticks);
+    }
+    ticks
+}
+
+/// Read the system counter frequency in Hz (CNTFRQ_EL0).
+#[cfg(target_os = "none")]
+pub fn frequency() -> u64 {
+    let freq: u64;
+    unsafe {
+        // Safety: reading CNTFRQ_EL0 has no side effects
+        core::arch::asm!("mrs {}, cntfrq_el0", out(reg) freq);
+    }
+    freq
+}
+
+/// Check the counter frequency the rest of the kernel converts with.
+///
+/// Firmware must program CNTFRQ_EL0; without it no timestamps or timeouts
+/// can be computed.
+#[cfg(target_os = "none")]
+fn init() -> Result<(), &'static str> {
+    if frequency() == 0 {
+        return Err("CNTFRQ_EL0 is not set");
+    }
+    Ok(())
+}
+
+initcall!(Early, "timer", init, optional);
+
+/// Timer interrupt handler body.
+///
+/// Counts the tick on the running CPU and lets the boot watchdog check
+/// for an overrunning stage. With `heap_debug`, also checks the heap
+/// redzones now and then.
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn handle_tick() {
+    crate::arch::percpu::tick();
+    crate::arch::boot::watchdog::tick();
+    #[cfg(feature = "heap_debug")]
+    crate::mm::heap::tick();
+}
+
+/// Convert milliseconds to counter ticks at `freq` Hz.
+pub const fn ms_to_ticks(ms: u64, freq: u64) -> u64 {
+    (ms as u128 * freq as u128 / 1000) as u64
+}
+
+/// Convert counter ticks at `freq` Hz to milliseconds.
+#[allow(dead_code)]
+pub const fn ticks_to_ms(ticks: u64, freq: u64) -> u64 {
+    if freq == 0 {
+        return 0;
+    }
+    (ticks as u128 * 1000 / freq as u128) as u64
+}
+
+/// Convert counter ticks at `freq` Hz to microseconds.
+#[allow(dead_code)]
+pub const fn ticks_to_us(ticks: u64, freq: u64) -> u64 {
+    if freq == 0 {
+        return 0;
+    }
+    (ticks as u128 * 1_000_000 / freq as u128) as u64
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tick_conversion() {
+        // QEMU virt runs the counter at 62.5MHz
+        let freq = 62_500_000;
+        assert_eq!(ms_to_ticks(2000, freq), 125_000_000);
+        assert_eq!(ticks_to_ms(125_000_000, freq), 2000);
+        assert_eq!(ticks_to_ms(62_499, freq), 0);
+        assert_eq!(ticks_to_ms(1, 0), 0);
+        assert_eq!(ticks_to_us(125, freq), 2);
+        assert_eq!(ticks_to_us(1, 0), 0);
+    }
+}
@@ -1,2 +1,30 @@
-#[cfg(target_arch = "aarch64")]
+//! Architecture facade.
+//!
+//! Upper layers import architecture services through `crate::arch::*`
+//! instead of naming a specific architecture, so a port only needs to
+//! provide the same set of modules and re-export them here.
+
+#[cfg(any(target_arch = "aarch64", test))]
 pub mod aarch64;
+
+#[cfg(any(target_arch = "aarch64", test))]
+#[allow(unused_imports)]
+pub use aarch64::{
+    address, barrier, boot, cache, cpu, earlycon, exception, gic, idle, irq, pagetable, percpu,
+    psci, reg, rtc, semihosting, serial, sync, sysregs, timer,
+};
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    #[test]
+    fn test_facade_resolves_to_aarch64() {
+        // Only compiles if the facade re-exports the aarch64 items
+        let _: crate::arch::aarch64::serial::Writer = crate::arch::serial::Writer;
+        let _: crate::arch::aarch64::boot::BootInfoError = crate::arch::boot::BootInfoError::Empty;
+
+        assert_eq!(
+            crate::arch::address::kernel::VIRTUAL_BASE,
+            crate::arch::aarch64::address::kernel::VIRTUAL_BASE
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! LZ4 block format decompressor.
+//!
+//! A block is a run of sequences. Each starts with a token byte whose high
+//! nibble is the literal length and low nibble the match length minus 4; a
+//! nibble of 15 continues in following bytes, each added until one is below
+//! 255. The literals follow, then a 2-byte little-endian offset back into
+//! the output the match copies from. The last sequence has literals only.
+//!
+//! Every length and offset is checked against the input and output before
+//! use, so a malformed block fails with an error instead of reading or
+//! writing out of bounds.
+
+use super::DecompressError;
+
+/// Shortest match a sequence encodes.
+const MIN_MATCH: usize = 4;
+
+/// Reads a length nibble and its continuation bytes from `src` at `*ip`.
+fn read_length(nibble: u8, src: &[u8], ip: &mut usize) -> Result<usize, DecompressError> {
+    let mut len = nibble as usize;
+    if nibble != 15 {
+        return Ok(len);
+    }
+    loop {
+        let byte = *src.get(*ip).ok_or(DecompressError::TruncatedInput)?;
+        *ip += 1;
+        len = len
+            .checked_add(byte as usize)
+            .ok_or(DecompressError::OutputOverflow)?;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+/// Decompress the LZ4 block `src` into `dst`.
+///
+/// # Arguments
+/// * `src` - One complete LZ4 block
+/// * `dst` - Output buffer, at least as large as the decompressed data
+///
+/// # Returns
+/// The number of bytes written to `dst`
+pub fn lz4_decompress(src: &[u8], dst: &mut [u8]) -> Result<usize, DecompressError> {
+    let mut ip: usize = 0;
+    let mut op: usize = 0;
+
+    loop {
+        let token = *src.get(ip).ok_or(DecompressError::TruncatedInput)?;
+        ip += 1;
+
+        let literals = read_length(token >> 4, src, &mut ip)?;
+        let lit_end = ip
+            .checked_add(literals)
+            .filter(|&end| end <= src.len())
+            .ok_or(DecompressError::TruncatedInput)?;
+        let out_end = op
+            .checked_add(literals)
+            .filter(|&end| end <= dst.len())
+            .ok_or(DecompressError::OutputOverflow)?;
+        dst[op..out_end].copy_from_slice(&src[ip..lit_end]);
+        ip = lit_end;
+        op = out_end;
+
+        // The last sequence ends after its literals
+        if ip == src.len() {
+            return Ok(op);
+        }
+
+        let offset = src
+            .get(ip..ip + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+            .ok_or(DecompressError::TruncatedInput)?;
+        ip += 2;
+        if offset == 0 || offset > op {
+            return Err(DecompressError::BadOffset);
+        }
+
+        let len = read_length(token & 0xf, src, &mut ip)?
+            .checked_add(MIN_MATCH)
+            .ok_or(DecompressError::OutputOverflow)?;
+        let match_end = op
+            .checked_add(len)
+            .filter(|&end| end <= dst.len())
+            .ok_or(DecompressError::OutputOverflow)?;
+        // Byte by byte: a match may overlap the bytes it produces
+        for i in op..match_end {
+            dst[i] = dst[i - offset];
+        }
+        op = match_end;
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    /// Decompress `src` into a buffer of `capacity` bytes.
+    fn decompress(src: &[u8], capacity: usize) -> Result<Vec<u8>, DecompressError> {
+        let mut dst = vec![0; capacity];
+        let len = lz4_decompress(src, &mut dst)?;
+        dst.truncate(len);
+        Ok(dst)
+    }
+
+    /// Fixtures compressed by the reference `lz4` tool, see testdata/gen.sh.
+    fn fixtures() -> [(&'static str, Vec<u8>, &'static [u8]); 4] {
+        [
+            (
+                "text",
+                include_bytes!("testdata/text.bin").to_vec(),
+                include_bytes!("testdata/text.lz4"),
+            ),
+            (
+                "random",
+                include_bytes!("testdata/random.bin").to_vec(),
+                include_bytes!("testdata/random.lz4"),
+            ),
+            (
+                "pattern",
+                include_bytes!("testdata/pattern.bin").to_vec(),
+                include_bytes!("testdata/pattern.lz4"),
+            ),
+            ("zeros", vec![0; 8192], include_bytes!("testdata/zeros.lz4")),
+        ]
+    }
+
+    #[test]
+    fn test_fixtures() {
+        for (name, original, compressed) in fixtures() {
+            assert_eq!(
+                decompress(compressed, original.len()).as_deref(),
+                Ok(&original[..]),
+                "{}",
+                name
+            );
+            // One byte short of room is an error, not a short result
+            assert_eq!(
+                decompress(compressed, original.len() - 1),
+                Err(DecompressError::OutputOverflow),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_hand_built_blocks() {
+        // Literals only
+        assert_eq!(decompress(b"\x30abc", 8).as_deref(), Ok(&b"abc"[..]));
+        // Overlapping match: "ab", 6 bytes copied from 2 back, then "x"
+        assert_eq!(
+            decompress(b"\x22ab\x02\x00\x10x", 16).as_deref(),
+            Ok(&b"ababababx"[..])
+        );
+        // Literal length continued in extra bytes: 15 + 255 + 0
+        let mut long = vec![0xf0, 255, 0];
+        long.extend(std::iter::repeat_n(b'z', 270));
+        assert_eq!(decompress(&long, 270).map(|d| d.len()), Ok(270));
+        // An empty block is a lone zero token
+        assert_eq!(decompress(b"\x00", 0).as_deref(), Ok(&b""[..]));
+    }
+
+    #[test]
+    fn test_malformed() {
+        use DecompressError::*;
+        assert_eq!(decompress(b"", 8), Err(TruncatedInput));
+        // Literals run past the input
+        assert_eq!(decompress(b"\x40abc", 8), Err(TruncatedInput));
+        // Length continuation missing
+        assert_eq!(decompress(b"\xf0", 64), Err(TruncatedInput));
+        // Offset cut short
+        assert_eq!(decompress(b"\x20ab\x02", 16), Err(TruncatedInput));
+        // Offset zero, and reaching back before the output
+        assert_eq!(decompress(b"\x20ab\x00\x00", 16), Err(BadOffset));
+        assert_eq!(decompress(b"\x20ab\x03\x00", 16), Err(BadOffset));
+        // Match runs past the output
+        assert_eq!(decompress(b"\x22ab\x02\x00\x10x", 8), Err(OutputOverflow));
+    }
+
+    #[test]
+    fn test_fuzz_mutations() {
+        // xorshift64, fixed seed so failures reproduce
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for (_, original, compressed) in fixtures() {
+            let mut dst = vec![0; original.len()];
+            for _ in 0..2000 {
+                let mut input = compressed.to_vec();
+                match next() % 3 {
+                    0 => {
+                        for _ in 0..1 + next() % 4 {
+                            let i = (next() % input.len() as u64) as usize;
+                            input[i] ^= 1 << (next() % 8);
+                        }
+                    }
+                    1 => {
+                        let i = (next() % input.len() as u64) as usize;
+                        input[i] = next() as u8;
+                    }
+                    _ => {
+                        // A cut block never decodes to the whole output
+                        let len = (next() % input.len() as u64) as usize;
+                        input.truncate(len);
+                        let result = lz4_decompress(&input, &mut dst);
+                        assert!(result.is_err() || result.unwrap() < original.len());
+                        continue;
+                    }
+                }
+                // Corrupt input may still decode; it must not panic
+                if let Ok(len) = lz4_decompress(&input, &mut dst) {
+                    assert!(len <= dst.len());
+                }
+            }
+        }
+    }
+}
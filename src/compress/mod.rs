@@ -0,0 +1,222 @@
+//! Compressed payloads embedded in the kernel image.
+//!
+//! A payload is a [`Header`] followed by one LZ4 block (see [`lz4`]):
+//!
+//! ```text
+//! "PHXZ" | compressed len | decompressed len | crc32 | LZ4 block
+//! ```
+//!
+//! All header fields are little-endian `u32`s; the CRC is CRC-32 (IEEE)
+//! over the decompressed bytes. [`unpack`] checks every field against
+//! what decoding actually produced, so a damaged payload is an error
+//! rather than silently wrong data.
+
+pub mod lz4;
+
+pub use lz4::lz4_decompress;
+
+/// Reasons decompressing a payload fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    /// The input ends in the middle of a sequence or header.
+    TruncatedInput,
+    /// The output does not fit the destination.
+    OutputOverflow,
+    /// A match reaches back before the start of the output.
+    BadOffset,
+    /// The header does not start with [`MAGIC`].
+    BadMagic,
+    /// Decoding produced a different size than the header says.
+    LengthMismatch,
+    /// The decompressed bytes do not match the header CRC.
+    BadCrc,
+}
+
+impl DecompressError {
+    /// Returns a short human-readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::TruncatedInput => "compressed data truncated",
+            Self::OutputOverflow => "decompressed data too large",
+            Self::BadOffset => "match offset outside output",
+            Self::BadMagic => "bad payload magic",
+            Self::LengthMismatch => "decompressed length mismatch",
+            Self::BadCrc => "payload CRC mismatch",
+        }
+    }
+}
+
+/// CRC-32 (IEEE, reflected poly 0xedb88320) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// First four bytes of a payload.
+pub const MAGIC: [u8; 4] = *b"PHXZ";
+
+/// Payload header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Size of the LZ4 block after the header.
+    pub compressed_len: u32,
+    /// Size of the data once decompressed.
+    pub decompressed_len: u32,
+    /// CRC-32 of the decompressed data.
+    pub crc32: u32,
+}
+
+impl Header {
+    /// Size of the encoded header in bytes.
+    pub const SIZE: usize = 16;
+
+    /// Parse the header at the start of `payload`.
+    ///
+    /// # Returns
+    /// The header and the LZ4 block it describes
+    pub fn parse(payload: &[u8]) -> Result<(Self, &[u8]), DecompressError> {
+        let raw = payload
+            .get(..Self::SIZE)
+            .ok_or(DecompressError::TruncatedInput)?;
+        if raw[..4] != MAGIC {
+            return Err(DecompressError::BadMagic);
+        }
+        let field = |i: usize| u32::from_le_bytes([raw[i], raw[i + 1], raw[i + 2], raw[i + 3]]);
+        let header = Self {
+            compressed_len: field(4),
+            decompressed_len: field(8),
+            crc32: field(12),
+        };
+
+        // The section holding the payload may be padded past the block
+        let block = payload[Self::SIZE..]
+            .get(..header.compressed_len as usize)
+            .ok_or(DecompressError::TruncatedInput)?;
+        Ok((header, block))
+    }
+
+    /// Returns the encoded header.
+    #[allow(dead_code)]
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut raw = [0; Self::SIZE];
+        raw[..4].copy_from_slice(&MAGIC);
+        raw[4..8].copy_from_slice(&self.compressed_len.to_le_bytes());
+        raw[8..12].copy_from_slice(&self.decompressed_len.to_le_bytes());
+        raw[12..].copy_from_slice(&self.crc32.to_le_bytes());
+        raw
+    }
+}
+
+/// Decompress `payload` into `dst` and check it against its header.
+///
+/// # Arguments
+/// * `payload` - Header and LZ4 block
+/// * `dst` - At least `decompressed_len` bytes
+///
+/// # Returns
+/// The number of bytes written to `dst`
+pub fn unpack(payload: &[u8], dst: &mut [u8]) -> Result<usize, DecompressError> {
+    let (header, block) = Header::parse(payload)?;
+    let out = dst
+        .get_mut(..header.decompressed_len as usize)
+        .ok_or(DecompressError::OutputOverflow)?;
+
+    let len = lz4_decompress(block, out)?;
+    if len != out.len() {
+        return Err(DecompressError::LengthMismatch);
+    }
+    if crc32(out) != header.crc32 {
+        return Err(DecompressError::BadCrc);
+    }
+    Ok(len)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = include_bytes!("testdata/text.bin");
+    const TEXT_LZ4: &[u8] = include_bytes!("testdata/text.lz4");
+
+    fn payload(header: Header, block: &[u8]) -> Vec<u8> {
+        let mut payload = header.to_bytes().to_vec();
+        payload.extend_from_slice(block);
+        payload
+    }
+
+    fn text_header() -> Header {
+        Header {
+            compressed_len: TEXT_LZ4.len() as u32,
+            decompressed_len: TEXT.len() as u32,
+            crc32: crc32(TEXT),
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        // CRC-32/ISO-HDLC check value
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = text_header();
+        let mut bytes = payload(header, TEXT_LZ4);
+        // Padding after the block is ignored
+        bytes.extend_from_slice(&[0; 7]);
+        assert_eq!(Header::parse(&bytes), Ok((header, TEXT_LZ4)));
+
+        assert_eq!(
+            Header::parse(&bytes[..Header::SIZE - 1]),
+            Err(DecompressError::TruncatedInput)
+        );
+        assert_eq!(
+            Header::parse(&bytes[..Header::SIZE + 10]),
+            Err(DecompressError::TruncatedInput)
+        );
+        bytes[0] = b'X';
+        assert_eq!(Header::parse(&bytes), Err(DecompressError::BadMagic));
+    }
+
+    #[test]
+    fn test_unpack() {
+        let mut dst = vec![0; TEXT.len() + 16];
+        let good = payload(text_header(), TEXT_LZ4);
+        assert_eq!(unpack(&good, &mut dst), Ok(TEXT.len()));
+        assert_eq!(&dst[..TEXT.len()], TEXT);
+
+        assert_eq!(
+            unpack(&good, &mut dst[..TEXT.len() - 1]),
+            Err(DecompressError::OutputOverflow)
+        );
+
+        let short = Header {
+            decompressed_len: TEXT.len() as u32 + 1,
+            ..text_header()
+        };
+        assert_eq!(
+            unpack(&payload(short, TEXT_LZ4), &mut dst),
+            Err(DecompressError::LengthMismatch)
+        );
+
+        let wrong_crc = Header {
+            crc32: crc32(TEXT) ^ 1,
+            ..text_header()
+        };
+        assert_eq!(
+            unpack(&payload(wrong_crc, TEXT_LZ4), &mut dst),
+            Err(DecompressError::BadCrc)
+        );
+    }
+}
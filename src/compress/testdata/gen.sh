@@ -0,0 +1,17 @@
+#!/bin/sh
+# Regenerate the LZ4 block fixtures used by the host tests.
+#
+# lz4 -l writes the legacy frame format: a 4-byte magic, then each block
+# as a 4-byte little-endian size and the raw LZ4 block. The inputs are
+# small enough for one block, so dropping the first 8 bytes leaves the
+# block the decompressor takes.
+set -e
+cd "$(dirname "$0")"
+
+head -c 8192 /dev/zero > zeros.bin
+for name in text random pattern zeros; do
+    lz4 -q -l -9 -f "$name.bin" "$name.tmp"
+    tail -c +9 "$name.tmp" > "$name.lz4"
+    rm "$name.tmp"
+done
+rm zeros.bin
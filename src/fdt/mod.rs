@@ -0,0 +1,677 @@
+//! Flattened device tree (FDT) parser.
+//!
+//! A read-only view over the DTB blob handed over by the bootloader.
+//! Nothing is copied or allocated: nodes, properties and strings borrow
+//! from the blob for its lifetime `'a`.
+//!
+//! A malformed structure block ends iteration early instead of panicking,
+//! so lookups on a damaged blob simply find nothing.
+//!
+//! The big-endian cell, string and token decoding is in [`reader`].
+
+pub mod reader;
+
+use reader::{Cursor, Reader, Token};
+
+/// Magic number at the start of every DTB.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// Size of the v17 header in bytes.
+pub const HEADER_SIZE: usize = 40;
+
+/// Oldest blob version whose header carries `size_dt_struct`.
+const MIN_VERSION: u32 = 17;
+
+/// Newest format the blob may require us to understand.
+const MAX_COMP_VERSION: u32 = 17;
+
+/// Maximum node nesting depth tracked during a walk.
+pub const MAX_DEPTH: usize = 16;
+
+/// `#address-cells` assumed when a node does not specify it.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+
+/// `#size-cells` assumed when a node does not specify it.
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+/// Reasons a blob is rejected by [`Fdt::new`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    /// The blob does not start with the FDT magic.
+    BadMagic,
+    /// The blob uses a format version we cannot read.
+    BadVersion,
+    /// The blob is shorter than its header claims.
+    Truncated,
+    /// The header points outside the blob.
+    BadLayout,
+}
+
+impl FdtError {
+    /// Returns a short human-readable description of the error.
+    #[allow(dead_code)]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FdtError::BadMagic => "bad FDT magic",
+            FdtError::BadVersion => "unsupported FDT version",
+            FdtError::Truncated => "FDT truncated",
+            FdtError::BadLayout => "FDT header offsets out of range",
+        }
+    }
+}
+
+/// A parsed device tree blob.
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    /// The whole blob, trimmed to `totalsize`.
+    data: &'a [u8],
+    /// The structure block.
+    structs: &'a [u8],
+    /// The strings block.
+    strings: &'a [u8],
+}
+
+/// Reads the `totalsize` field from a blob header.
+///
+/// Only the magic and the size are checked, so this works before the
+/// rest of the blob is known to be readable.
+///
+/// # Arguments
+/// * `header` - The first [`HEADER_SIZE`] bytes of the blob
+pub fn header_total_size(header: &[u8]) -> Result<usize, FdtError> {
+    let header = Reader::new(header);
+    let field = |index: usize| {
+        header
+            .read_be_u32(index * 4)
+            .map_err(|_| FdtError::Truncated)
+    };
+
+    if field(0)? != FDT_MAGIC {
+        return Err(FdtError::BadMagic);
+    }
+    let total_size = field(1)? as usize;
+    if total_size < HEADER_SIZE {
+        return Err(FdtError::Truncated);
+    }
+    Ok(total_size)
+}
+
+impl<'a> Fdt<'a> {
+    /// Validates the header of `data` and wraps it.
+    ///
+    /// # Arguments
+    /// * `data` - The blob; may be longer than the header's `totalsize`
+    pub fn new(data: &'a [u8]) -> Result<Self, FdtError> {
+        let header = Reader::new(data);
+        let field = |index: usize| {
+            header
+                .read_be_u32(index * 4)
+                .map_err(|_| FdtError::Truncated)
+        };
+
+        if field(0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+
+        let total_size = field(1)? as usize;
+        let off_struct = field(2)? as usize;
+        let off_strings = field(3)? as usize;
+        let version = field(5)?;
+        let last_comp_version = field(6)?;
+        let size_strings = field(8)? as usize;
+        let size_struct = field(9)? as usize;
+
+        if version < MIN_VERSION || last_comp_version > MAX_COMP_VERSION {
+            return Err(FdtError::BadVersion);
+        }
+        if total_size > data.len() {
+            return Err(FdtError::Truncated);
+        }
+
+        let data = &data[..total_size];
+        let block = |offset: usize, size: usize| {
+            let end = offset.checked_add(size).ok_or(FdtError::BadLayout)?;
+            data.get(offset..end).ok_or(FdtError::BadLayout)
+        };
+
+        Ok(Self {
+            data,
+            structs: block(off_struct, size_struct)?,
+            strings: block(off_strings, size_strings)?,
+        })
+    }
+
+    /// Wraps a blob in memory, using its header to find its length.
+    ///
+    /// # Arguments
+    /// * `ptr` - Virtual address of the blob
+    ///
+    /// # Safety
+    /// `ptr` must point to readable memory holding at least a header, and
+    /// the `totalsize` bytes it describes must stay mapped and unmodified
+    /// for the rest of the kernel's lifetime.
+    #[allow(dead_code)]
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Fdt<'static>, FdtError> {
+        // Safety: the caller guarantees a readable header
+        let header = unsafe { core::slice::from_raw_parts(ptr, HEADER_SIZE) };
+        let total_size = header_total_size(header)?;
+
+        // Safety: the caller guarantees `totalsize` bytes stay readable
+        Fdt::new(unsafe { core::slice::from_raw_parts(ptr, total_size) })
+    }
+
+    /// Returns the size of the blob in bytes, as recorded in its header.
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns the root node.
+    #[allow(dead_code)]
+    pub fn root(&self) -> Option<Node<'a>> {
+        self.nodes().next()
+    }
+
+    /// Iterates over every node in document order.
+    pub fn nodes(&self) -> NodeIter<'a> {
+        NodeIter {
+            fdt: *self,
+            cursor: Cursor::new(self.structs, 0),
+            depth: 0,
+            cells: [(DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS); MAX_DEPTH],
+        }
+    }
+
+    /// Looks up a node by absolute path, such as `/soc/serial@1c090000`.
+    ///
+    /// A path component without a unit address also matches a node name
+    /// that has one, so `/memory` finds `memory@40000000`.
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        let mut want = components.next();
+        // Depth of the deepest node on the path matched so far
+        let mut matched = 0;
+
+        for node in self.nodes() {
+            let depth = node.depth();
+            if depth == 0 {
+                if want.is_none() {
+                    return Some(node);
+                }
+                continue;
+            }
+            if depth <= matched {
+                // Walked out of the matched subtree
+                return None;
+            }
+            if depth == matched + 1 && want.is_some_and(|c| node.name_matches(c)) {
+                matched = depth;
+                want = components.next();
+                if want.is_none() {
+                    return Some(node);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Iterates over the nodes whose `compatible` list contains `compatible`.
+    pub fn find_compatible<'b>(&self, compatible: &'b str) -> CompatibleIter<'a, 'b> {
+        CompatibleIter {
+            nodes: self.nodes(),
+            compatible,
+        }
+    }
+
+    /// Returns the string at `offset` in the strings block.
+    fn string(&self, offset: u32) -> Option<&'a str> {
+        Reader::new(self.strings).read_str(offset as usize).ok()
+    }
+}
+
+/// A node in the device tree.
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    /// Offset of the node's first property token.
+    props: usize,
+    /// Nesting depth, zero for the root.
+    depth: usize,
+    /// The parent's `#address-cells`, used to decode `reg`.
+    address_cells: u32,
+    /// The parent's `#size-cells`, used to decode `reg`.
+    size_cells: u32,
+}
+
+impl<'a> Node<'a> {
+    /// Returns the node name including any unit address, empty for the root.
+    #[allow(dead_code)]
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Returns the nesting depth, zero for the root.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Iterates over the node's own properties.
+    pub fn properties(&self) -> PropertyIter<'a> {
+        PropertyIter {
+            fdt: self.fdt,
+            cursor: Cursor::new(self.fdt.structs, self.props),
+        }
+    }
+
+    /// Looks up a property by name.
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.properties().find(|p| p.name() == name)
+    }
+
+    /// Returns true if `compatible` appears in the node's `compatible` list.
+    pub fn compatible_contains(&self, compatible: &str) -> bool {
+        self.property("compatible")
+            .is_some_and(|p| p.as_str_list().any(|c| c == compatible))
+    }
+
+    /// Returns false if the node's `status` marks it as unusable.
+    ///
+    /// A node without `status` is enabled.
+    pub fn is_enabled(&self) -> bool {
+        match self.property("status").and_then(|p| p.as_str()) {
+            None => true,
+            Some(status) => status == "okay" || status == "ok",
+        }
+    }
+
+    /// Iterates over the `(address, size)` pairs in the node's `reg`.
+    ///
+    /// Entries are decoded with the parent's `#address-cells` and
+    /// `#size-cells`. Yields nothing if either exceeds two cells.
+    pub fn reg_entries(&self) -> RegIter<'a> {
+        RegIter {
+            value: self.property("reg").map_or(&[][..], |p| p.value()),
+            address_cells: self.address_cells as usize,
+            size_cells: self.size_cells as usize,
+        }
+    }
+
+    /// Returns true if path component `component` names this node.
+    fn name_matches(&self, component: &str) -> bool {
+        if self.name == component {
+            return true;
+        }
+        !component.contains('@') && self.name.split('@').next() == Some(component)
+    }
+
+    /// Returns the `#address-cells` and `#size-cells` this node gives its
+    /// children.
+    fn child_cells(&self) -> (u32, u32) {
+        let mut cells = (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS);
+        for prop in self.properties() {
+            match prop.name() {
+                "#address-cells" => cells.0 = prop.as_u32().unwrap_or(cells.0),
+                "#size-cells" => cells.1 = prop.as_u32().unwrap_or(cells.1),
+                _ => {}
+            }
+        }
+        cells
+    }
+}
+
+/// Iterator over all nodes, returned by [`Fdt::nodes`].
+pub struct NodeIter<'a> {
+    fdt: Fdt<'a>,
+    cursor: Cursor<'a>,
+    depth: usize,
+    /// `#address-cells` / `#size-cells` each open node gives its children.
+    cells: [(u32, u32); MAX_DEPTH],
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        loop {
+            match self.cursor.next_token()? {
+                Token::BeginNode(name) => {
+                    let depth = self.depth;
+                    if depth >= MAX_DEPTH {
+                        return None;
+                    }
+                    let (address_cells, size_cells) = match depth {
+                        0 => (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS),
+                        _ => self.cells[depth - 1],
+                    };
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        props: self.cursor.offset(),
+                        depth,
+                        address_cells,
+                        size_cells,
+                    };
+                    self.cells[depth] = node.child_cells();
+                    self.depth += 1;
+                    return Some(node);
+                }
+                Token::EndNode => self.depth = self.depth.checked_sub(1)?,
+                Token::Prop { .. } => {}
+            }
+        }
+    }
+}
+
+/// Iterator over compatible nodes, returned by [`Fdt::find_compatible`].
+pub struct CompatibleIter<'a, 'b> {
+    nodes: NodeIter<'a>,
+    compatible: &'b str,
+}
+
+impl<'a> Iterator for CompatibleIter<'a, '_> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        let compatible = self.compatible;
+        self.nodes.find(|n| n.compatible_contains(compatible))
+    }
+}
+
+/// A property of a node.
+#[derive(Clone, Copy)]
+pub struct Property<'a> {
+    name: &'a str,
+    value: &'a [u8],
+}
+
+impl<'a> Property<'a> {
+    /// Returns the property name.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Returns the raw property value.
+    pub fn value(&self) -> &'a [u8] {
+        self.value
+    }
+
+    /// Decodes a single-cell value.
+    pub fn as_u32(&self) -> Option<u32> {
+        let bytes: [u8; 4] = self.value.try_into().ok()?;
+        Some(u32::from_be_bytes(bytes))
+    }
+
+    /// Decodes a two-cell value.
+    #[allow(dead_code)]
+    pub fn as_u64(&self) -> Option<u64> {
+        let bytes: [u8; 8] = self.value.try_into().ok()?;
+        Some(u64::from_be_bytes(bytes))
+    }
+
+    /// Decodes a single NUL-terminated string.
+    pub fn as_str(&self) -> Option<&'a str> {
+        let (&last, body) = self.value.split_last()?;
+        if last != 0 || body.contains(&0) {
+            return None;
+        }
+        core::str::from_utf8(body).ok()
+    }
+
+    /// Iterates over a NUL-separated string list, such as `compatible`.
+    ///
+    /// Entries that are not valid UTF-8 are skipped.
+    pub fn as_str_list(&self) -> impl Iterator<Item = &'a str> + use<'a> {
+        let body = self.value.strip_suffix(&[0]).unwrap_or(&[]);
+        body.split(|&b| b == 0)
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+}
+
+/// Iterator over a node's properties, returned by [`Node::properties`].
+pub struct PropertyIter<'a> {
+    fdt: Fdt<'a>,
+    cursor: Cursor<'a>,
+}
+
+impl<'a> Iterator for PropertyIter<'a> {
+    type Item = Property<'a>;
+
+    fn next(&mut self) -> Option<Property<'a>> {
+        // Properties always precede child nodes
+        match self.cursor.next_token()? {
+            Token::Prop { name_offset, value } => Some(Property {
+                name: self.fdt.string(name_offset)?,
+                value,
+            }),
+            Token::BeginNode(_) | Token::EndNode => None,
+        }
+    }
+}
+
+/// One `reg` entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegEntry {
+    /// Bus address of the region.
+    pub address: u64,
+    /// Size of the region, zero if the bus has no size cells.
+    pub size: u64,
+}
+
+/// Iterator over `reg` entries, returned by [`Node::reg_entries`].
+pub struct RegIter<'a> {
+    value: &'a [u8],
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl RegIter<'_> {
+    /// Folds `cells` big-endian cells from the front of the value.
+    fn take(&mut self, cells: usize) -> u64 {
+        let (head, rest) = self.value.split_at(cells * 4);
+        self.value = rest;
+        head.chunks_exact(4).fold(0, |acc, c| {
+            (acc << 32) | u32::from_be_bytes([c[0], c[1], c[2], c[3]]) as u64
+        })
+    }
+}
+
+impl Iterator for RegIter<'_> {
+    type Item = RegEntry;
+
+    fn next(&mut self) -> Option<RegEntry> {
+        if self.address_cells == 0 || self.address_cells > 2 || self.size_cells > 2 {
+            return None;
+        }
+        if self.value.len() < (self.address_cells + self.size_cells) * 4 {
+            return None;
+        }
+        Some(RegEntry {
+            address: self.take(self.address_cells),
+            size: self.take(self.size_cells),
+        })
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    /// Built from `test.dts` next to this file.
+    static TEST_DTB: &[u8] = include_bytes!("test.dtb");
+
+    fn fdt() -> Fdt<'static> {
+        Fdt::new(TEST_DTB).unwrap()
+    }
+
+    #[test]
+    fn test_fdt_header() {
+        assert_eq!(fdt().total_size(), TEST_DTB.len());
+        assert_eq!(fdt().root().unwrap().name(), "");
+
+        let mut bad = TEST_DTB.to_vec();
+        bad[0] = 0;
+        assert_eq!(Fdt::new(&bad).err(), Some(FdtError::BadMagic));
+
+        assert_eq!(Fdt::new(&TEST_DTB[..100]).err(), Some(FdtError::Truncated));
+        assert_eq!(Fdt::new(&[]).err(), Some(FdtError::Truncated));
+
+        let mut old = TEST_DTB.to_vec();
+        old[20..24].copy_from_slice(&16u32.to_be_bytes());
+        assert_eq!(Fdt::new(&old).err(), Some(FdtError::BadVersion));
+
+        let mut layout = TEST_DTB.to_vec();
+        layout[36..40].copy_from_slice(&0x10000u32.to_be_bytes());
+        assert_eq!(Fdt::new(&layout).err(), Some(FdtError::BadLayout));
+    }
+
+    #[test]
+    fn test_fdt_header_total_size() {
+        assert_eq!(header_total_size(TEST_DTB), Ok(TEST_DTB.len()));
+        assert_eq!(
+            header_total_size(&TEST_DTB[..HEADER_SIZE]),
+            Ok(TEST_DTB.len())
+        );
+        assert_eq!(header_total_size(&TEST_DTB[..6]), Err(FdtError::Truncated));
+
+        let mut bad = TEST_DTB[..HEADER_SIZE].to_vec();
+        bad[0] = 0;
+        assert_eq!(header_total_size(&bad), Err(FdtError::BadMagic));
+
+        // A size smaller than the header itself is rejected
+        let mut tiny = TEST_DTB[..HEADER_SIZE].to_vec();
+        tiny[4..8].copy_from_slice(&8u32.to_be_bytes());
+        assert_eq!(header_total_size(&tiny), Err(FdtError::Truncated));
+    }
+
+    #[test]
+    fn test_fdt_from_ptr() {
+        let fdt = unsafe { Fdt::from_ptr(TEST_DTB.as_ptr()) }.unwrap();
+        assert_eq!(fdt.total_size(), TEST_DTB.len());
+        assert!(fdt.find_node("/psci").is_some());
+    }
+
+    #[test]
+    fn test_fdt_find_node() {
+        let fdt = fdt();
+        assert_eq!(fdt.find_node("/").unwrap().depth(), 0);
+        assert_eq!(fdt.find_node("/memory").unwrap().name(), "memory@40000000");
+        assert_eq!(fdt.find_node("/memory@40000000").unwrap().depth(), 1);
+
+        let sensor = fdt.find_node("/soc/bus@1c100000/sensor@48").unwrap();
+        assert_eq!(sensor.depth(), 3);
+        assert_eq!(
+            fdt.find_node("/soc/bus/sensor").unwrap().name(),
+            "sensor@48"
+        );
+
+        // Not a top-level node, and a wrong unit address
+        assert!(fdt.find_node("/sensor@48").is_none());
+        assert!(fdt.find_node("/memory@0").is_none());
+        assert!(fdt.find_node("/soc/missing").is_none());
+        // Matching a prefix of a name is not enough
+        assert!(fdt.find_node("/pl").is_none());
+    }
+
+    #[test]
+    fn test_fdt_properties() {
+        let fdt = fdt();
+        let root = fdt.root().unwrap();
+        assert_eq!(
+            root.property("model").unwrap().as_str(),
+            Some("phoenix-test")
+        );
+
+        let uart = fdt.find_node("/pl011@9000000").unwrap();
+        let clock = uart.property("clock-frequency").unwrap();
+        assert_eq!(clock.as_u32(), Some(24_000_000));
+        assert_eq!(clock.as_u64(), None);
+        assert_eq!(clock.value().len(), 4);
+
+        let chosen = fdt.find_node("/chosen").unwrap();
+        let initrd = chosen.property("linux,initrd-start").unwrap();
+        assert_eq!(initrd.as_u64(), Some(0x4800_0000));
+        assert_eq!(initrd.as_str(), None);
+
+        // A string list is not a single string
+        let compatible = uart.property("compatible").unwrap();
+        assert_eq!(compatible.as_str(), None);
+        assert_eq!(compatible.as_str_list().count(), 2);
+
+        // Missing property, and properties of children are not ours
+        assert!(uart.property("interrupts").is_none());
+        assert!(fdt.find_node("/soc").unwrap().property("reg").is_none());
+        assert_eq!(fdt.find_node("/psci").unwrap().properties().count(), 2);
+    }
+
+    #[test]
+    fn test_fdt_compatible() {
+        let fdt = fdt();
+        let psci = fdt.find_node("/psci").unwrap();
+        assert!(psci.compatible_contains("arm,psci-1.0"));
+        assert!(psci.compatible_contains("arm,psci"));
+        assert!(!psci.compatible_contains("arm,psci-0.1"));
+        assert!(!psci.compatible_contains("arm"));
+
+        let names: Vec<_> = fdt.find_compatible("arm,pl011").map(|n| n.name()).collect();
+        assert_eq!(names, ["pl011@9000000", "serial@1c090000"]);
+        assert_eq!(fdt.find_compatible("vendor,sensor").count(), 1);
+        assert_eq!(fdt.find_compatible("arm,gic-v3").count(), 0);
+    }
+
+    #[test]
+    fn test_fdt_status() {
+        let fdt = fdt();
+        assert!(fdt.find_node("/pl011").unwrap().is_enabled());
+        assert!(!fdt.find_node("/soc/serial").unwrap().is_enabled());
+    }
+
+    #[test]
+    fn test_fdt_reg_entries() {
+        let fdt = fdt();
+
+        // Two cells each at the root
+        let gic: Vec<_> = fdt.find_node("/intc").unwrap().reg_entries().collect();
+        assert_eq!(
+            gic,
+            [
+                RegEntry {
+                    address: 0x0800_0000,
+                    size: 0x10000
+                },
+                RegEntry {
+                    address: 0x0801_0000,
+                    size: 0x10000
+                },
+            ]
+        );
+
+        // One cell each under /soc
+        let serial = fdt.find_node("/soc/serial").unwrap();
+        assert_eq!(
+            serial.reg_entries().next(),
+            Some(RegEntry {
+                address: 0x1c09_0000,
+                size: 0x1000
+            })
+        );
+
+        // No size cells under the bus
+        let sensor = fdt.find_node("/soc/bus/sensor").unwrap();
+        assert_eq!(
+            sensor.reg_entries().collect::<Vec<_>>(),
+            [RegEntry {
+                address: 0x48,
+                size: 0
+            }]
+        );
+
+        // A sibling after a nested subtree sees the root's cells again
+        let chosen = fdt.find_node("/chosen").unwrap();
+        assert_eq!(chosen.address_cells, 2);
+        assert_eq!(chosen.reg_entries().count(), 0);
+    }
+}
@@ -0,0 +1,253 @@
+//! Bounds-checked reads from a device tree blob.
+//!
+//! Everything in a blob is big-endian: header fields, tokens and property
+//! cells. [`Reader`] decodes cells and NUL-terminated strings at any byte
+//! offset, aligned or not, and returns an error instead of panicking when
+//! a read would run past the end. [`Cursor`] walks the structure block
+//! token by token on top of it.
+
+/// Structure block tokens.
+pub mod token {
+    pub const BEGIN_NODE: u32 = 0x1;
+    pub const END_NODE: u32 = 0x2;
+    pub const PROP: u32 = 0x3;
+    pub const NOP: u32 = 0x4;
+    pub const END: u32 = 0x9;
+}
+
+/// Reasons a read from the blob fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadError {
+    /// The read extends past the end of the bytes.
+    OutOfBounds,
+    /// No NUL ends the string before the end of the bytes.
+    Unterminated,
+    /// The string is not valid UTF-8.
+    BadUtf8,
+}
+
+impl ReadError {
+    /// Returns a short human-readable description of the error.
+    #[allow(dead_code)]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReadError::OutOfBounds => "FDT read out of bounds",
+            ReadError::Unterminated => "FDT string not terminated",
+            ReadError::BadUtf8 => "FDT string is not UTF-8",
+        }
+    }
+}
+
+/// Big-endian reader over a byte slice of a blob.
+#[derive(Clone, Copy)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Wraps `bytes`; offsets are relative to its start.
+    pub const fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    /// Returns the number of bytes readable.
+    #[allow(dead_code)]
+    pub const fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns true if there is nothing to read.
+    #[allow(dead_code)]
+    pub const fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns the `len` bytes at `offset`.
+    pub fn read_bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], ReadError> {
+        let end = offset.checked_add(len).ok_or(ReadError::OutOfBounds)?;
+        self.bytes.get(offset..end).ok_or(ReadError::OutOfBounds)
+    }
+
+    /// Reads a big-endian `u32` at `offset`.
+    pub fn read_be_u32(&self, offset: usize) -> Result<u32, ReadError> {
+        let raw = self.read_bytes(offset, 4)?;
+        Ok(u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]))
+    }
+
+    /// Reads a big-endian `u64` at `offset`.
+    #[allow(dead_code)]
+    pub fn read_be_u64(&self, offset: usize) -> Result<u64, ReadError> {
+        let raw = self.read_bytes(offset, 8)?;
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(raw);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// Reads a value of `cells` 32-bit cells at `offset`.
+    ///
+    /// Only one or two cells fit a `u64`; other counts are out of bounds.
+    #[allow(dead_code)]
+    pub fn read_cells(&self, offset: usize, cells: usize) -> Result<u64, ReadError> {
+        match cells {
+            1 => self.read_be_u32(offset).map(u64::from),
+            2 => self.read_be_u64(offset),
+            _ => Err(ReadError::OutOfBounds),
+        }
+    }
+
+    /// Reads a NUL-terminated string at `offset`, without the NUL.
+    pub fn read_str(&self, offset: usize) -> Result<&'a str, ReadError> {
+        let rest = self.bytes.get(offset..).ok_or(ReadError::OutOfBounds)?;
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(ReadError::Unterminated)?;
+        core::str::from_utf8(&rest[..len]).map_err(|_| ReadError::BadUtf8)
+    }
+}
+
+/// Rounds `offset` up to the next token boundary.
+pub const fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// One structure block token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    /// Start of a node, with its name and unit address.
+    BeginNode(&'a str),
+    /// End of the innermost open node.
+    EndNode,
+    /// A property; the name is at `name_offset` in the strings block.
+    Prop { name_offset: u32, value: &'a [u8] },
+}
+
+/// Reads tokens from the structure block, skipping `FDT_NOP`.
+#[derive(Clone, Copy)]
+pub struct Cursor<'a> {
+    structs: Reader<'a>,
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    /// Starts reading the structure block `structs` at `offset`.
+    pub fn new(structs: &'a [u8], offset: usize) -> Self {
+        Self {
+            structs: Reader::new(structs),
+            offset,
+        }
+    }
+
+    /// Returns the offset of the next token.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the next token, or `None` at `FDT_END` or on malformed input.
+    pub fn next_token(&mut self) -> Option<Token<'a>> {
+        loop {
+            let tag = self.structs.read_be_u32(self.offset).ok()?;
+            let body = self.offset + 4;
+
+            match tag {
+                token::BEGIN_NODE => {
+                    let name = self.structs.read_str(body).ok()?;
+                    self.offset = align4(body + name.len() + 1);
+                    return Some(Token::BeginNode(name));
+                }
+                token::END_NODE => {
+                    self.offset = body;
+                    return Some(Token::EndNode);
+                }
+                token::PROP => {
+                    let len = self.structs.read_be_u32(body).ok()? as usize;
+                    let name_offset = self.structs.read_be_u32(body + 4).ok()?;
+                    let value = self.structs.read_bytes(body + 8, len).ok()?;
+                    self.offset = align4(body + 8 + len);
+                    return Some(Token::Prop { name_offset, value });
+                }
+                token::NOP => self.offset = body,
+                token::END => return None,
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const BYTES: &[u8] = &[
+        0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, b'c', b'p', b'u', 0, b'x', 0xff, 0,
+    ];
+
+    #[test]
+    fn test_read_be() {
+        let r = Reader::new(BYTES);
+        assert_eq!(r.read_be_u32(0), Ok(0x1234_5678));
+        assert_eq!(r.read_be_u64(0), Ok(0x1234_5678_9abc_def0));
+        // Offsets need not be aligned
+        assert_eq!(r.read_be_u32(1), Ok(0x3456_789a));
+        assert_eq!(r.read_be_u64(3), Ok(0x789a_bcde_f063_7075));
+        assert_eq!(r.read_cells(4, 1), Ok(0x9abc_def0));
+        assert_eq!(r.read_cells(0, 2), Ok(0x1234_5678_9abc_def0));
+        assert_eq!(r.read_cells(0, 3), Err(ReadError::OutOfBounds));
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        let r = Reader::new(&BYTES[..6]);
+        assert_eq!(r.read_be_u32(2), Ok(0x5678_9abc));
+        assert_eq!(r.read_be_u32(3), Err(ReadError::OutOfBounds));
+        assert_eq!(r.read_be_u64(0), Err(ReadError::OutOfBounds));
+        assert_eq!(r.read_be_u32(usize::MAX), Err(ReadError::OutOfBounds));
+        assert_eq!(r.read_bytes(6, 0), Ok(&[][..]));
+        assert_eq!(r.read_bytes(7, 0), Err(ReadError::OutOfBounds));
+        assert_eq!(Reader::new(&[]).read_be_u32(0), Err(ReadError::OutOfBounds));
+    }
+
+    #[test]
+    fn test_read_str() {
+        let r = Reader::new(BYTES);
+        assert_eq!(r.read_str(8), Ok("cpu"));
+        assert_eq!(r.read_str(10), Ok("u"));
+        assert_eq!(r.read_str(11), Ok(""));
+        assert_eq!(r.read_str(12), Err(ReadError::BadUtf8));
+        assert_eq!(r.read_str(BYTES.len()), Err(ReadError::Unterminated));
+        assert_eq!(r.read_str(BYTES.len() + 1), Err(ReadError::OutOfBounds));
+        assert_eq!(
+            Reader::new(&BYTES[..10]).read_str(8),
+            Err(ReadError::Unterminated)
+        );
+    }
+
+    #[test]
+    fn test_cursor() {
+        #[rustfmt::skip]
+        let structs: &[u8] = &[
+            0, 0, 0, 1, b'a', b'@', b'1', 0,
+            0, 0, 0, 4,
+            0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, 7, 0xab, 0xcd, 0, 0,
+            0, 0, 0, 2,
+            0, 0, 0, 9,
+        ];
+        let mut cursor = Cursor::new(structs, 0);
+        assert_eq!(cursor.next_token(), Some(Token::BeginNode("a@1")));
+        assert_eq!(cursor.offset(), 8);
+        // The NOP is skipped and the value padded to a token boundary
+        assert_eq!(
+            cursor.next_token(),
+            Some(Token::Prop {
+                name_offset: 7,
+                value: &[0xab, 0xcd]
+            })
+        );
+        assert_eq!(cursor.next_token(), Some(Token::EndNode));
+        assert_eq!(cursor.next_token(), None);
+
+        // A property value running off the end stops the walk
+        let mut cursor = Cursor::new(&structs[..25], 12);
+        assert_eq!(cursor.next_token(), None);
+    }
+}
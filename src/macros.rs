@@ -0,0 +1,207 @@
+//! Kernel assertion macros.
+//!
+//! `kassert!` checks a condition and, on failure, prints the condition,
+//! message and source location over serial and halts. Unlike `assert!` it
+//! does not go through the panic machinery, so it is usable on paths where
+//! the panic handler would be too heavy or not yet trustworthy. Host test
+//! builds turn the failure into a panic carrying the same message.
+//!
+//! `initcall!` registers an init function with `boot::initcall`.
+
+use core::fmt;
+
+/// Check `cond` and halt with a report over serial if it is false.
+///
+/// ```ignore
+/// kassert!(base < end);
+/// kassert!(base < end, "empty range {:#x}..{:#x}", base, end);
+/// ```
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::macros::kassert_failed(
+                stringify!($cond),
+                file!(),
+                line!(),
+                None,
+            );
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::macros::kassert_failed(
+                stringify!($cond),
+                file!(),
+                line!(),
+                Some(format_args!($($arg)+)),
+            );
+        }
+    };
+}
+
+/// Register `func` to run at init level `level` under `name`.
+///
+/// Append `optional` if boot may continue when it fails. Entries only
+/// exist in target builds, where the linker collects them in the
+/// `.initcalls` section.
+///
+/// ```ignore
+/// initcall!(Early, "serial", init);
+/// initcall!(Late, "selftest", selftest, optional);
+/// ```
+macro_rules! initcall {
+    ($level:ident, $name:expr, $func:path $(,)?) => {
+        initcall!(@entry $level, $name, $func, false);
+    };
+    ($level:ident, $name:expr, $func:path, optional $(,)?) => {
+        initcall!(@entry $level, $name, $func, true);
+    };
+    (@entry $level:ident, $name:expr, $func:path, $optional:expr) => {
+        #[cfg(target_os = "none")]
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = ".initcalls")]
+            static ENTRY: $crate::arch::boot::initcall::InitcallEntry =
+                $crate::arch::boot::initcall::InitcallEntry {
+                    level: $crate::arch::boot::initcall::InitLevel::$level,
+                    name: $name,
+                    func: $func,
+                    optional: $optional,
+                };
+        };
+    };
+}
+
+/// A failed `kassert!`, formatted as
+/// `<file>:<line>: kassert failed: <cond>[: <message>]`.
+pub struct AssertFailure<'a> {
+    /// Source text of the condition.
+    pub cond: &'static str,
+    /// Source file of the assertion.
+    pub file: &'static str,
+    /// Source line of the assertion.
+    pub line: u32,
+    /// Optional formatted message.
+    pub msg: Option<fmt::Arguments<'a>>,
+}
+
+impl fmt::Display for AssertFailure<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: kassert failed: {}",
+            self.file, self.line, self.cond
+        )?;
+        if let Some(msg) = self.msg {
+            write!(f, ": {}", msg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Report a failed `kassert!` and halt.
+///
+/// Called by the macro only.
+#[cfg(target_os = "none")]
+#[cold]
+pub fn kassert_failed(
+    cond: &'static str,
+    file: &'static str,
+    line: u32,
+    msg: Option<fmt::Arguments<'_>>,
+) -> ! {
+    use crate::arch::serial::{self, frame};
+    use core::fmt::Write;
+
+    let failure = AssertFailure {
+        cond,
+        file,
+        line,
+        msg,
+    };
+
+    // Nothing may be left waiting for a TX interrupt that will not come
+    serial::disable_irq_tx();
+    let _ = writeln!(serial::Writer, "\n{}", failure);
+    let mut report = frame::FrameBuf::new();
+    let _ = write!(report, "{}", failure);
+    let _ = frame::send_frame(frame::FrameKind::PanicReport, report.as_bytes());
+
+    crate::arch::idle::park_forever()
+}
+
+/// Host builds panic with the report so tests can observe it.
+#[cfg(not(target_os = "none"))]
+#[cold]
+pub fn kassert_failed(
+    cond: &'static str,
+    file: &'static str,
+    line: u32,
+    msg: Option<fmt::Arguments<'_>>,
+) -> ! {
+    let failure = AssertFailure {
+        cond,
+        file,
+        line,
+        msg,
+    };
+    panic!("{}", failure)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    /// Run `f` and return the message it panicked with.
+    fn panic_message(f: impl FnOnce() + std::panic::UnwindSafe) -> String {
+        let payload = std::panic::catch_unwind(f).unwrap_err();
+        payload.downcast::<String>().map(|s| *s).unwrap()
+    }
+
+    #[test]
+    fn test_kassert_passes() {
+        let x = 3;
+        kassert!(x == 3);
+        kassert!(x > 2, "x is {}", x);
+    }
+
+    #[test]
+    fn test_kassert_message() {
+        let base = 0x1234u64;
+        let line = line!() + 2;
+        let msg = panic_message(|| {
+            kassert!(base < 0x1000, "base {:#x} out of range", base);
+        });
+        assert_eq!(
+            msg,
+            format!(
+                "src/macros.rs:{}: kassert failed: base < 0x1000: base 0x1234 out of range",
+                line
+            )
+        );
+    }
+
+    #[test]
+    fn test_kassert_without_message() {
+        let line = line!() + 1;
+        let msg = panic_message(|| kassert!(1 + 1 == 3));
+        assert_eq!(
+            msg,
+            format!("src/macros.rs:{}: kassert failed: 1 + 1 == 3", line)
+        );
+    }
+
+    #[test]
+    fn test_assert_failure_display() {
+        let failure = AssertFailure {
+            cond: "ok",
+            file: "src/mm/heap.rs",
+            line: 42,
+            msg: Some(format_args!("{} left", 7)),
+        };
+        assert_eq!(
+            failure.to_string(),
+            "src/mm/heap.rs:42: kassert failed: ok: 7 left"
+        );
+    }
+}
@@ -1,10 +1,23 @@
 #![cfg_attr(target_os = "none", no_std)]
 #![cfg_attr(target_os = "none", no_main)]
 
-#[cfg(target_os = "none")]
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+#[macro_use]
+mod macros;
+
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
 mod arch;
 
+#[cfg(any(all(target_os = "none", feature = "payload"), test))]
+#[cfg_attr(test, allow(dead_code))]
+mod compress;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+mod fdt;
 mod mm;
+mod stats;
 
 #[cfg(target_os = "none")]
 unsafe extern "C" {
@@ -16,18 +29,18 @@ unsafe extern "C" {
 
 #[cfg(target_os = "none")]
 #[unsafe(no_mangle)]
-pub extern "C" fn kernel_main() {
-    use crate::arch::aarch64::boot;
+pub extern "C" fn kernel_main(dtb_phys: u64) {
+    use crate::arch::boot;
 
     // Get kernel virtual addresses from linker script
     let kernel_virt_start = unsafe { &__kernel_virtual_start as *const u8 as u64 };
     let kernel_virt_end = unsafe { &__kernel_virtual_end as *const u8 as u64 };
 
     // Perform early initialization
-    boot::early_init();
+    boot::early_init(dtb_phys);
 
     // Perform main kernel initialization
-    boot::kernel_init(kernel_virt_start, kernel_virt_end);
+    boot::kernel_init(kernel_virt_start, kernel_virt_end, dtb_phys);
 }
 
 #[cfg(not(target_os = "none"))]
@@ -38,6 +51,7 @@ fn main() {
 #[cfg(all(test, not(target_os = "none")))]
 mod tests {
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_target_guard() {
         assert!(true);
     }
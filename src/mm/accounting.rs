@@ -0,0 +1,423 @@
+//! Boot-time memory usage by subsystem.
+//!
+//! Every byte taken from RAM during boot is charged to a [`Category`], so
+//! the end-of-boot report can say where memory went:
+//!
+//! ```text
+//! Memory: mem_map 4.0 MB (0.4%) | kernel image 2.1 MB (0.2%) | ... | free 1001 MB (97.8%)
+//! ```
+//!
+//! Reservations made through the global memblock instance are charged when
+//! its lock guard is dropped, from the change in bytes reserved per
+//! [`ReservationOwner`]; that covers the kernel image, page tables, the
+//! heap and kernel stacks. Memory handed to the page allocator counts as
+//! free, so the allocator charges its own bitmap separately.
+//!
+//! [`write_report`] also checks that the categories and free memory add up
+//! to total RAM, give or take a page, and warns if they do not.
+
+use crate::mm::memblock::ReservationOwner;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Largest difference between accounted and total memory that is not
+/// reported, one page.
+pub const SLACK: u64 = 0x1000;
+
+/// What boot-time memory is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// Kernel text, data and BSS.
+    KernelImage,
+    /// Translation tables.
+    PageTables,
+    /// Page frame metadata and allocator bitmaps.
+    MemMap,
+    /// Kernel heap arena.
+    Heap,
+    /// Kernel stacks and their guard pages.
+    Stacks,
+    /// Contiguous DMA pool.
+    Cma,
+    /// Everything else: DTB, initrd, per-CPU data, untagged allocations.
+    Other,
+}
+
+impl Category {
+    /// Number of categories.
+    pub const COUNT: usize = 7;
+
+    /// All categories, in display order for equal sizes.
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::KernelImage,
+        Self::PageTables,
+        Self::MemMap,
+        Self::Heap,
+        Self::Stacks,
+        Self::Cma,
+        Self::Other,
+    ];
+
+    /// Returns the name printed in the report.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::KernelImage => "kernel image",
+            Self::PageTables => "page tables",
+            Self::MemMap => "mem_map",
+            Self::Heap => "heap",
+            Self::Stacks => "stacks",
+            Self::Cma => "CMA",
+            Self::Other => "other",
+        }
+    }
+
+    /// Returns the category memblock reservations by `owner` are charged
+    /// to, `None` for memory owned by the page allocator, which is free
+    /// until allocated from it.
+    pub fn of_owner(owner: ReservationOwner) -> Option<Self> {
+        match owner {
+            ReservationOwner::KernelImage => Some(Self::KernelImage),
+            ReservationOwner::PageTable => Some(Self::PageTables),
+            ReservationOwner::MemMap => Some(Self::MemMap),
+            ReservationOwner::Heap => Some(Self::Heap),
+            ReservationOwner::Stack => Some(Self::Stacks),
+            ReservationOwner::Cma => Some(Self::Cma),
+            // Handed on to the page allocator, or not RAM at all
+            ReservationOwner::PageAlloc | ReservationOwner::Mmio => None,
+            ReservationOwner::Dtb
+            | ReservationOwner::Initrd
+            | ReservationOwner::PerCpu
+            | ReservationOwner::EarlyAlloc
+            | ReservationOwner::Payload
+            | ReservationOwner::Other => Some(Self::Other),
+        }
+    }
+}
+
+/// Bytes charged to each category, indexed by `Category as usize`.
+static CHARGED: [AtomicU64; Category::COUNT] = [const { AtomicU64::new(0) }; Category::COUNT];
+
+/// Charge `bytes` to `category`.
+pub fn charge(category: Category, bytes: u64) {
+    CHARGED[category as usize].fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Return `bytes` previously charged to `category`.
+///
+/// Saturates at zero rather than wrapping if more is returned than was
+/// charged.
+pub fn uncharge(category: Category, bytes: u64) {
+    let _ = CHARGED[category as usize].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+        Some(v.saturating_sub(bytes))
+    });
+}
+
+/// Charge the change from `before` to `after`, both bytes reserved per
+/// owner indexed like [`ReservationOwner::ALL`].
+pub fn charge_owner_delta(
+    before: &[u64; ReservationOwner::COUNT],
+    after: &[u64; ReservationOwner::COUNT],
+) {
+    for (i, owner) in ReservationOwner::ALL.into_iter().enumerate() {
+        let Some(category) = Category::of_owner(owner) else {
+            continue;
+        };
+        if after[i] > before[i] {
+            charge(category, after[i] - before[i]);
+        } else if before[i] > after[i] {
+            uncharge(category, before[i] - after[i]);
+        }
+    }
+}
+
+/// Bytes charged per category at one point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Usage {
+    /// Indexed by `Category as usize`.
+    pub bytes: [u64; Category::COUNT],
+}
+
+impl Usage {
+    /// Returns the bytes charged to `category`.
+    pub fn get(&self, category: Category) -> u64 {
+        self.bytes[category as usize]
+    }
+
+    /// Returns the bytes charged to all categories together.
+    pub fn total(&self) -> u64 {
+        self.bytes.iter().sum()
+    }
+
+    /// Returns the categories and their sizes, largest first.
+    ///
+    /// Equal sizes keep [`Category::ALL`] order.
+    pub fn sorted(&self) -> [(Category, u64); Category::COUNT] {
+        let mut entries = Category::ALL.map(|c| (c, self.get(c)));
+        entries.sort_unstable_by_key(|&(c, bytes)| (core::cmp::Reverse(bytes), c as usize));
+        entries
+    }
+}
+
+/// Returns the bytes charged so far.
+pub fn usage() -> Usage {
+    Usage {
+        bytes: core::array::from_fn(|i| CHARGED[i].load(Ordering::Relaxed)),
+    }
+}
+
+/// A byte count shown in B, KB, MB or GB.
+///
+/// Below 10 units one decimal is shown, e.g. "2.1 MB", above it whole
+/// units, e.g. "384 KB". A value that would round to 1024 of a unit is
+/// shown in the next one instead, so 1023.96 KB reads "1.0 MB".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size(pub u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+        let bytes = self.0 as u128;
+        if bytes < 1024 {
+            return write!(f, "{} B", bytes);
+        }
+        for (i, unit) in UNITS.iter().enumerate().skip(1) {
+            let scale = 1u128 << (10 * i);
+            let tenths = (bytes * 10 + scale / 2) / scale;
+            if tenths < 100 {
+                return write!(f, "{}.{} {}", tenths / 10, tenths % 10, unit);
+            }
+            let whole = (bytes + scale / 2) / scale;
+            if whole < 1024 || i == UNITS.len() - 1 {
+                return write!(f, "{} {}", whole, unit);
+            }
+        }
+        unreachable!()
+    }
+}
+
+/// Returns `part` as a percentage of `total` in tenths of a percent,
+/// rounded to nearest; 0 if `total` is 0.
+pub fn percent_tenths(part: u64, total: u64) -> u64 {
+    if total == 0 {
+        return 0;
+    }
+    ((part as u128 * 1000 + total as u128 / 2) / total as u128) as u64
+}
+
+/// Accounted and free memory do not add up to total RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Charged plus free bytes.
+    pub accounted: u64,
+    /// Total RAM.
+    pub total: u64,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (sign, diff) = if self.accounted > self.total {
+            ('+', self.accounted - self.total)
+        } else {
+            ('-', self.total - self.accounted)
+        };
+        write!(
+            f,
+            "accounted {:#x} vs total {:#x} ({}{:#x})",
+            self.accounted, self.total, sign, diff
+        )
+    }
+}
+
+/// Check that `charged` and `free` bytes add up to `total` within
+/// [`SLACK`].
+pub fn check_consistency(charged: u64, free: u64, total: u64) -> Result<(), Mismatch> {
+    let accounted = charged.saturating_add(free);
+    if accounted.abs_diff(total) <= SLACK {
+        Ok(())
+    } else {
+        Err(Mismatch { accounted, total })
+    }
+}
+
+/// Write the usage table and a warning line if it does not add up.
+///
+/// # Arguments
+/// * `out` - Sink for the report
+/// * `usage` - Bytes charged per category
+/// * `free` - Bytes still free
+/// * `total` - Total RAM
+pub fn write_report(out: &mut dyn fmt::Write, usage: &Usage, free: u64, total: u64) -> fmt::Result {
+    let percent = |bytes| {
+        let tenths = percent_tenths(bytes, total);
+        (tenths / 10, tenths % 10)
+    };
+
+    write!(out, "Memory:")?;
+    for (category, bytes) in usage.sorted() {
+        // Categories nothing was charged to are left out
+        if bytes == 0 {
+            continue;
+        }
+        let (whole, frac) = percent(bytes);
+        write!(
+            out,
+            " {} {} ({}.{}%) |",
+            category.as_str(),
+            Size(bytes),
+            whole,
+            frac
+        )?;
+    }
+    let (whole, frac) = percent(free);
+    writeln!(out, " free {} ({}.{}%)", Size(free), whole, frac)?;
+
+    if let Err(mismatch) = check_consistency(usage.total(), free, total) {
+        writeln!(out, "warning: memory accounting off: {}", mismatch)?;
+    }
+    Ok(())
+}
+
+/// Write the report for the running kernel.
+///
+/// Free memory is what the page allocator has left once it is up, what
+/// memblock has left before that.
+#[cfg(target_os = "none")]
+pub fn report(out: &mut dyn fmt::Write) -> fmt::Result {
+    use crate::arch::address::kernel::PAGE_SIZE;
+    use crate::mm::{memblock, page_alloc};
+
+    let stats = memblock::lock().stats();
+    let free = match page_alloc::zone_stats() {
+        Some(zones) => zones.iter().map(|z| z.free_pages * PAGE_SIZE).sum(),
+        None => stats.free(),
+    };
+    write_report(out, &usage(), free, stats.total_memory)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const KB: u64 = 1 << 10;
+    const MB: u64 = 1 << 20;
+
+    fn size(bytes: u64) -> String {
+        Size(bytes).to_string()
+    }
+
+    fn report(usage: &Usage, free: u64, total: u64) -> String {
+        let mut out = String::new();
+        write_report(&mut out, usage, free, total).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_size_humanized() {
+        assert_eq!(size(0), "0 B");
+        assert_eq!(size(1023), "1023 B");
+        assert_eq!(size(KB), "1.0 KB");
+        assert_eq!(size(12 * KB), "12 KB");
+        assert_eq!(size(384 * KB), "384 KB");
+        assert_eq!(size(2150 * KB), "2.1 MB");
+        assert_eq!(size(1001 * MB), "1001 MB");
+        assert_eq!(size(4 << 30), "4.0 GB");
+        assert_eq!(size(4096 << 30), "4096 GB");
+    }
+
+    #[test]
+    fn test_size_unit_boundary() {
+        // A whole 1023 KB stays in KB...
+        assert_eq!(size(1023 * KB), "1023 KB");
+        // ...but anything rounding to 1024 KB moves up a unit
+        assert_eq!(size(1023 * KB + 600), "1.0 MB");
+        assert_eq!(size(MB), "1.0 MB");
+        assert_eq!(size(MB - 1), "1.0 MB");
+        // 9.96 rounds past one decimal into whole units
+        assert_eq!(size(9 * MB + 980 * KB), "10 MB");
+    }
+
+    #[test]
+    fn test_percent_tenths() {
+        assert_eq!(percent_tenths(0, 100), 0);
+        assert_eq!(percent_tenths(1, 3), 333);
+        assert_eq!(percent_tenths(2, 3), 667);
+        assert_eq!(percent_tenths(1, 2000), 1);
+        assert_eq!(percent_tenths(1, 2001), 0);
+        assert_eq!(percent_tenths(5, 5), 1000);
+        assert_eq!(percent_tenths(5, 0), 0);
+        assert_eq!(percent_tenths(u64::MAX, u64::MAX), 1000);
+    }
+
+    #[test]
+    fn test_sorted_descending() {
+        let mut usage = Usage::default();
+        usage.bytes[Category::Heap as usize] = MB;
+        usage.bytes[Category::Cma as usize] = 16 * MB;
+        usage.bytes[Category::Stacks as usize] = MB;
+        let sorted = usage.sorted();
+        assert_eq!(sorted[0], (Category::Cma, 16 * MB));
+        // Ties keep display order
+        assert_eq!(sorted[1], (Category::Heap, MB));
+        assert_eq!(sorted[2], (Category::Stacks, MB));
+        assert!(sorted[3..].iter().all(|&(_, bytes)| bytes == 0));
+        assert_eq!(usage.total(), 18 * MB);
+    }
+
+    #[test]
+    fn test_owner_categories() {
+        assert_eq!(Category::of_owner(ReservationOwner::PageAlloc), None);
+        assert_eq!(Category::of_owner(ReservationOwner::Mmio), None);
+        assert_eq!(
+            Category::of_owner(ReservationOwner::PageTable),
+            Some(Category::PageTables)
+        );
+        assert_eq!(
+            Category::of_owner(ReservationOwner::Dtb),
+            Some(Category::Other)
+        );
+    }
+
+    #[test]
+    fn test_consistency() {
+        assert_eq!(check_consistency(MB, 3 * MB, 4 * MB), Ok(()));
+        // Sub-page rounding is tolerated either way
+        assert_eq!(check_consistency(MB - 100, 3 * MB, 4 * MB), Ok(()));
+        assert_eq!(check_consistency(MB + SLACK, 3 * MB, 4 * MB), Ok(()));
+        assert_eq!(
+            check_consistency(MB + SLACK + 1, 3 * MB, 4 * MB),
+            Err(Mismatch {
+                accounted: 4 * MB + SLACK + 1,
+                total: 4 * MB
+            })
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let mut usage = Usage::default();
+        usage.bytes[Category::KernelImage as usize] = 2150 * KB;
+        usage.bytes[Category::PageTables as usize] = 384 * KB;
+        usage.bytes[Category::Other as usize] = 12 * KB;
+        let total = 64 * MB;
+        let free = total - usage.total();
+        assert_eq!(
+            report(&usage, free, total),
+            "Memory: kernel image 2.1 MB (3.3%) | page tables 384 KB (0.6%) | \
+             other 12 KB (0.0%) | free 62 MB (96.1%)\n"
+        );
+    }
+
+    #[test]
+    fn test_report_warns_on_mismatch() {
+        let mut usage = Usage::default();
+        usage.bytes[Category::Heap as usize] = MB;
+        // Two pages unaccounted for
+        let out = report(&usage, 3 * MB - 0x2000, 4 * MB);
+        let (table, warning) = out.split_once('\n').unwrap();
+        assert!(table.starts_with("Memory: heap 1.0 MB (25.0%) | free"));
+        assert_eq!(
+            warning,
+            "warning: memory accounting off: accounted 0x3fe000 vs total 0x400000 (-0x2000)\n"
+        );
+    }
+}
@@ -0,0 +1,481 @@
+//! Contiguous memory pool for DMA buffers.
+//!
+//! Devices such as virtio queues and framebuffers need large physically
+//! contiguous buffers, which get hard to find once memory fragments. A
+//! pool is carved from memblock during `init_memory`, before anything
+//! else can fragment it, and handed out at page granularity through a
+//! bitmap allocator.
+//!
+//! The pool size defaults to [`DEFAULT_POOL_SIZE`] and can be changed with
+//! `cma=<size>` on the command line, e.g. `cma=32M`.
+
+use crate::arch::address;
+use core::fmt;
+use spin::Mutex;
+
+/// Page size managed by the pool.
+const PAGE_SIZE: u64 = address::kernel::PAGE_SIZE;
+
+/// Pool size used when the command line does not set one.
+pub const DEFAULT_POOL_SIZE: u64 = 16 << 20;
+
+/// Largest pool the bitmap can track.
+pub const MAX_POOL_SIZE: u64 = 256 << 20;
+
+/// Pages the bitmap can track.
+pub const MAX_POOL_PAGES: usize = (MAX_POOL_SIZE / PAGE_SIZE) as usize;
+
+/// Alignment of the pool base, so large aligned buffers are possible.
+pub const POOL_ALIGN: u64 = 0x20_0000;
+
+/// The pool must end within this many bytes of the start of RAM.
+///
+/// Meant as "below 1GB" for devices with narrow DMA masks. QEMU virt puts
+/// RAM at 1GB, so the limit is taken relative to the RAM base; an absolute
+/// 1GB bound could never be met there.
+pub const DMA_WINDOW: u64 = 0x4000_0000;
+
+/// Command line option that sets the pool size.
+pub const CMDLINE_OPTION: &str = "cma=";
+
+/// Errors returned by [`alloc`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// No pool was set up.
+    NoPool,
+    /// Zero pages or an alignment that is not a power of two.
+    InvalidRequest,
+    /// No free run of the requested size and alignment.
+    NoSpace,
+}
+
+/// Errors returned by [`release`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReleaseError {
+    /// No pool was set up.
+    NoPool,
+    /// The range is not page aligned or not inside the pool.
+    OutOfPool,
+    /// Part of the range is already free, e.g. a double release.
+    NotAllocated,
+}
+
+/// One bit per page, set while the page is allocated.
+pub struct Bitmap {
+    words: [u64; MAX_POOL_PAGES / 64],
+    pages: usize,
+}
+
+impl Bitmap {
+    /// Create a bitmap of `pages` free pages.
+    ///
+    /// `pages` is capped at [`MAX_POOL_PAGES`].
+    pub const fn new(pages: usize) -> Self {
+        Self {
+            words: [0; MAX_POOL_PAGES / 64],
+            pages: if pages < MAX_POOL_PAGES {
+                pages
+            } else {
+                MAX_POOL_PAGES
+            },
+        }
+    }
+
+    /// Returns the number of pages tracked.
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Returns true if page `index` is allocated.
+    pub fn is_set(&self, index: usize) -> bool {
+        self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, index: usize, value: bool) {
+        let bit = 1 << (index % 64);
+        if value {
+            self.words[index / 64] |= bit;
+        } else {
+            self.words[index / 64] &= !bit;
+        }
+    }
+
+    /// Mark `count` pages from `start` allocated or free.
+    pub fn set_range(&mut self, start: usize, count: usize, value: bool) {
+        for index in start..start + count {
+            self.set(index, value);
+        }
+    }
+
+    /// Returns true if every page in the range is allocated.
+    pub fn all_set(&self, start: usize, count: usize) -> bool {
+        (start..start + count).all(|index| self.is_set(index))
+    }
+
+    /// First-fit search for `count` free pages.
+    ///
+    /// # Arguments
+    /// * `count` - Pages needed
+    /// * `align` - Required alignment in pages, a power of two
+    /// * `offset` - Page number of index 0, so alignment applies to
+    ///   physical page numbers rather than bitmap indices
+    ///
+    /// # Returns
+    /// Index of the first page of the run
+    pub fn find_run(&self, count: usize, align: usize, offset: usize) -> Option<usize> {
+        let aligned = |index: usize| (index + offset).next_multiple_of(align) - offset;
+
+        let mut start = aligned(0);
+        while start.checked_add(count)? <= self.pages {
+            // Jump past the last allocated page of the candidate, if any
+            match (start..start + count).rev().find(|&i| self.is_set(i)) {
+                Some(used) => start = aligned(used + 1),
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    /// Returns the number of allocated pages.
+    pub fn used(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Returns the length of the longest run of free pages.
+    pub fn largest_free_run(&self) -> usize {
+        let (mut largest, mut run) = (0, 0);
+        for index in 0..self.pages {
+            if self.is_set(index) {
+                run = 0;
+            } else {
+                run += 1;
+                largest = largest.max(run);
+            }
+        }
+        largest
+    }
+}
+
+/// Usage summary of the pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CmaInfo {
+    /// Physical base of the pool.
+    pub base: u64,
+    /// Pool size in bytes.
+    pub total: u64,
+    /// Bytes currently allocated.
+    pub used: u64,
+    /// Largest contiguous free range in bytes.
+    pub largest_free: u64,
+}
+
+impl fmt::Display for CmaInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CMA [{:#x}-{:#x}] {} KiB, {} KiB used, largest free {} KiB",
+            self.base,
+            self.base + self.total,
+            self.total / 1024,
+            self.used / 1024,
+            self.largest_free / 1024
+        )
+    }
+}
+
+/// A contiguous pool of pages starting at `base`.
+pub struct Pool {
+    base: u64,
+    bitmap: Bitmap,
+}
+
+impl Pool {
+    /// Create a pool over `pages` pages at the page-aligned `base`.
+    pub const fn new(base: u64, pages: usize) -> Self {
+        Self {
+            base,
+            bitmap: Bitmap::new(pages),
+        }
+    }
+
+    /// Allocate `pages` contiguous pages.
+    ///
+    /// # Arguments
+    /// * `pages` - Number of pages
+    /// * `align_pages` - Alignment of the physical base in pages, a power
+    ///   of two
+    ///
+    /// # Returns
+    /// Physical address of the first page
+    pub fn alloc(&mut self, pages: usize, align_pages: usize) -> Result<u64, AllocError> {
+        if pages == 0 || !align_pages.is_power_of_two() {
+            return Err(AllocError::InvalidRequest);
+        }
+        let first_pfn = (self.base / PAGE_SIZE) as usize;
+        let start = self
+            .bitmap
+            .find_run(pages, align_pages, first_pfn)
+            .ok_or(AllocError::NoSpace)?;
+        self.bitmap.set_range(start, pages, true);
+        Ok(self.base + start as u64 * PAGE_SIZE)
+    }
+
+    /// Return `pages` pages starting at `addr` to the pool.
+    ///
+    /// The whole range must be allocated; releasing a page twice is
+    /// rejected without changing anything.
+    pub fn release(&mut self, addr: u64, pages: usize) -> Result<(), ReleaseError> {
+        if addr < self.base || !(addr - self.base).is_multiple_of(PAGE_SIZE) {
+            return Err(ReleaseError::OutOfPool);
+        }
+        let start = ((addr - self.base) / PAGE_SIZE) as usize;
+        if pages == 0
+            || start
+                .checked_add(pages)
+                .is_none_or(|end| end > self.bitmap.pages())
+        {
+            return Err(ReleaseError::OutOfPool);
+        }
+        if !self.bitmap.all_set(start, pages) {
+            return Err(ReleaseError::NotAllocated);
+        }
+        self.bitmap.set_range(start, pages, false);
+        Ok(())
+    }
+
+    /// Returns the pool's usage summary.
+    pub fn info(&self) -> CmaInfo {
+        CmaInfo {
+            base: self.base,
+            total: self.bitmap.pages() as u64 * PAGE_SIZE,
+            used: self.bitmap.used() as u64 * PAGE_SIZE,
+            largest_free: self.bitmap.largest_free_run() as u64 * PAGE_SIZE,
+        }
+    }
+}
+
+/// Parse a size with an optional `K`, `M` or `G` suffix.
+///
+/// Accepts decimal or `0x` hexadecimal digits.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    value.checked_mul(1 << shift)
+}
+
+/// Returns the pool size requested by `cmdline`.
+///
+/// The last `cma=` option wins. Sizes are rounded up to whole pages and
+/// capped at [`MAX_POOL_SIZE`]; `cma=0` disables the pool.
+pub fn pool_size(cmdline: &str) -> u64 {
+    let size = cmdline
+        .split_ascii_whitespace()
+        .filter_map(|arg| arg.strip_prefix(CMDLINE_OPTION))
+        .filter_map(parse_size)
+        .next_back()
+        .unwrap_or(DEFAULT_POOL_SIZE);
+    size.min(MAX_POOL_SIZE).next_multiple_of(PAGE_SIZE)
+}
+
+/// The boot pool, once carved.
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+
+/// Carve the pool from memblock.
+///
+/// # Arguments
+/// * `cmdline` - Kernel command line, for `cma=`
+///
+/// # Returns
+/// The pool's usage summary, or `None` if `cma=0` disabled it.
+pub fn init(cmdline: &str) -> Result<Option<CmaInfo>, &'static str> {
+    let mut pool = POOL.lock();
+    if pool.is_some() {
+        return Err("CMA pool already initialized");
+    }
+
+    let size = pool_size(cmdline);
+    if size == 0 {
+        return Ok(None);
+    }
+    let (ram_base, _) = address::regions::ram();
+    use crate::mm::memblock;
+
+    let slot = memblock::FixedSlot {
+        limit: Some(ram_base.saturating_add(DMA_WINDOW)),
+        ..crate::mm::layout::phys::CMA
+    };
+    let base = memblock::request_fixed(&slot, size).map_err(|e| e.as_str())?;
+
+    let carved = pool.insert(Pool::new(base, (size / PAGE_SIZE) as usize));
+    Ok(Some(carved.info()))
+}
+
+/// Allocate `pages` contiguous pages from the pool.
+///
+/// # Returns
+/// Physical address of the first page
+#[allow(dead_code)]
+pub fn alloc(pages: usize, align_pages: usize) -> Result<u64, AllocError> {
+    POOL.lock()
+        .as_mut()
+        .ok_or(AllocError::NoPool)?
+        .alloc(pages, align_pages)
+}
+
+/// Return pages obtained from [`alloc`] to the pool.
+#[allow(dead_code)]
+pub fn release(addr: u64, pages: usize) -> Result<(), ReleaseError> {
+    POOL.lock()
+        .as_mut()
+        .ok_or(ReleaseError::NoPool)?
+        .release(addr, pages)
+}
+
+/// Returns the pool's usage summary, if there is a pool.
+pub fn info() -> Option<CmaInfo> {
+    POOL.lock().as_ref().map(Pool::info)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x4020_0000;
+
+    #[test]
+    fn test_bitmap_find_run() {
+        let mut bitmap = Bitmap::new(64);
+        assert_eq!(bitmap.find_run(64, 1, 0), Some(0));
+        assert_eq!(bitmap.find_run(65, 1, 0), None);
+
+        bitmap.set_range(2, 3, true);
+        assert_eq!(bitmap.find_run(2, 1, 0), Some(0));
+        assert_eq!(bitmap.find_run(3, 1, 0), Some(5));
+        // Alignment skips index 5
+        assert_eq!(bitmap.find_run(3, 4, 0), Some(8));
+        // ...and is applied to the page number, not the index
+        assert_eq!(bitmap.find_run(3, 4, 1), Some(7));
+        assert_eq!(bitmap.find_run(1, 64, 0), Some(0));
+        assert_eq!(bitmap.find_run(1, 64, 1), Some(63));
+        assert_eq!(bitmap.find_run(2, 64, 1), None);
+    }
+
+    #[test]
+    fn test_cma_alloc_whole_pool() {
+        let mut pool = Pool::new(BASE, 256);
+        assert_eq!(pool.alloc(256, 1), Ok(BASE));
+        assert_eq!(pool.alloc(1, 1), Err(AllocError::NoSpace));
+        assert_eq!(pool.info().used, 256 * PAGE_SIZE);
+        assert_eq!(pool.info().largest_free, 0);
+
+        pool.release(BASE, 256).unwrap();
+        assert_eq!(pool.info().used, 0);
+        assert_eq!(pool.alloc(257, 1), Err(AllocError::NoSpace));
+    }
+
+    #[test]
+    fn test_cma_alignment_larger_than_request() {
+        let mut pool = Pool::new(BASE, 1024);
+        let first = pool.alloc(1, 1).unwrap();
+        assert_eq!(first, BASE);
+
+        // 1MB alignment for a single page skips to the next 1MB boundary
+        let aligned = pool.alloc(1, 256).unwrap();
+        assert_eq!(aligned, BASE + 0x10_0000);
+        assert_eq!(aligned % 0x10_0000, 0);
+
+        // The gap left behind is still usable
+        assert_eq!(pool.alloc(2, 1), Ok(BASE + PAGE_SIZE));
+        assert_eq!(pool.alloc(1, 3), Err(AllocError::InvalidRequest));
+        assert_eq!(pool.alloc(0, 1), Err(AllocError::InvalidRequest));
+    }
+
+    #[test]
+    fn test_cma_release_coalesces() {
+        let mut pool = Pool::new(BASE, 16);
+        let a = pool.alloc(4, 1).unwrap();
+        let b = pool.alloc(4, 1).unwrap();
+        let c = pool.alloc(4, 1).unwrap();
+        assert_eq!(pool.info().largest_free, 4 * PAGE_SIZE);
+
+        // Freeing neighbours merges them into one run
+        pool.release(a, 4).unwrap();
+        assert_eq!(pool.alloc(5, 1), Err(AllocError::NoSpace));
+        pool.release(b, 4).unwrap();
+        assert_eq!(pool.info().largest_free, 8 * PAGE_SIZE);
+        assert_eq!(pool.alloc(8, 1), Ok(a));
+        pool.release(a, 8).unwrap();
+        pool.release(c, 4).unwrap();
+        assert_eq!(pool.info().largest_free, 16 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_cma_fragmentation_accounting() {
+        let mut pool = Pool::new(BASE, 16);
+        let pages: Vec<u64> = (0..16).map(|_| pool.alloc(1, 1).unwrap()).collect();
+        for page in pages.iter().step_by(2) {
+            pool.release(*page, 1).unwrap();
+        }
+
+        // Half the pool is free but no two free pages touch
+        let info = pool.info();
+        assert_eq!(info.total, 16 * PAGE_SIZE);
+        assert_eq!(info.used, 8 * PAGE_SIZE);
+        assert_eq!(info.largest_free, PAGE_SIZE);
+        assert_eq!(pool.alloc(2, 1), Err(AllocError::NoSpace));
+    }
+
+    #[test]
+    fn test_cma_double_release_rejected() {
+        let mut pool = Pool::new(BASE, 16);
+        let a = pool.alloc(4, 1).unwrap();
+        pool.release(a, 4).unwrap();
+        assert_eq!(pool.release(a, 4), Err(ReleaseError::NotAllocated));
+
+        // A range that is only partly allocated is left untouched
+        let b = pool.alloc(2, 1).unwrap();
+        assert_eq!(pool.release(b, 3), Err(ReleaseError::NotAllocated));
+        assert_eq!(pool.info().used, 2 * PAGE_SIZE);
+
+        assert_eq!(
+            pool.release(BASE - PAGE_SIZE, 1),
+            Err(ReleaseError::OutOfPool)
+        );
+        assert_eq!(pool.release(BASE + 1, 1), Err(ReleaseError::OutOfPool));
+        assert_eq!(pool.release(BASE, 17), Err(ReleaseError::OutOfPool));
+        assert_eq!(pool.release(BASE, 0), Err(ReleaseError::OutOfPool));
+    }
+
+    #[test]
+    fn test_cma_pool_size_from_cmdline() {
+        assert_eq!(pool_size(""), DEFAULT_POOL_SIZE);
+        assert_eq!(pool_size("quiet cma=32M"), 32 << 20);
+        assert_eq!(pool_size("cma=0x100000"), 0x10_0000);
+        assert_eq!(pool_size("cma=4K cma=8k"), 0x2000);
+        assert_eq!(pool_size("cma=1G"), MAX_POOL_SIZE);
+        assert_eq!(pool_size("cma=5000"), 0x2000);
+        assert_eq!(pool_size("cma=0"), 0);
+        // Unparsable values fall back to the default
+        assert_eq!(pool_size("cma=lots"), DEFAULT_POOL_SIZE);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("99999999999G"), None);
+    }
+
+    #[test]
+    fn test_cma_info_display() {
+        let mut pool = Pool::new(BASE, 256);
+        pool.alloc(16, 1).unwrap();
+        assert_eq!(
+            pool.info().to_string(),
+            "CMA [0x40200000-0x40300000] 1024 KiB, 64 KiB used, largest free 960 KiB"
+        );
+    }
+}
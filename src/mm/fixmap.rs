@@ -0,0 +1,290 @@
+//! Fixed virtual mappings for early boot.
+//!
+//! The fixmap window (see `mm::layout::FIXMAP`) is carved into one-page
+//! slots at compile-time known addresses. Each slot can be pointed at any
+//! physical page, e.g. the DTB or a console before `ioremap` is usable.
+//! The tables covering the window (L2 and L3, plus L1 with 4-level tables)
+//! are static, so installing a mapping never allocates.
+
+use crate::arch::address;
+
+/// A fixmap slot. Each slot covers one page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Slot {
+    /// First page of the flattened device tree.
+    Dtb,
+    /// Early console registers.
+    Console,
+    /// Scratch page for short-lived accesses.
+    Temp,
+}
+
+impl Slot {
+    /// Number of slots.
+    pub const COUNT: usize = 3;
+
+    /// Returns the index of the slot within the window.
+    pub const fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Returns the virtual address the slot is mapped at.
+    pub const fn virt(self) -> u64 {
+        address::kernel::FIXMAP_START + self.index() as u64 * address::kernel::PAGE_SIZE
+    }
+}
+
+const _: () = assert!(
+    Slot::COUNT as u64 * address::kernel::PAGE_SIZE <= address::kernel::FIXMAP_SIZE,
+    "fixmap slots must fit in the fixmap window"
+);
+
+/// Errors returned by the fixmap API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixmapError {
+    /// Physical address is not page aligned.
+    Unaligned,
+    /// The slot already holds a mapping.
+    SlotInUse,
+    /// The slot holds no mapping.
+    SlotEmpty,
+    /// `init` has not linked the fixmap tables yet.
+    NotInitialized,
+    /// Page table update failed.
+    PageTable(&'static str),
+}
+
+impl FixmapError {
+    /// Returns a human readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unaligned => "physical address is not page aligned",
+            Self::SlotInUse => "fixmap slot is already mapped",
+            Self::SlotEmpty => "fixmap slot is not mapped",
+            Self::NotInitialized => "fixmap is not initialized",
+            Self::PageTable(e) => e,
+        }
+    }
+}
+
+/// Bookkeeping of which slot maps which physical page.
+#[derive(Debug)]
+pub struct Slots {
+    phys: [Option<u64>; Slot::COUNT],
+}
+
+impl Slots {
+    /// Creates a table with every slot empty.
+    pub const fn new() -> Self {
+        Self {
+            phys: [None; Slot::COUNT],
+        }
+    }
+
+    /// Records `phys` as mapped at `slot`.
+    ///
+    /// # Returns
+    /// Virtual address of the slot
+    pub fn set(&mut self, slot: Slot, phys: u64) -> Result<u64, FixmapError> {
+        if !phys.is_multiple_of(address::kernel::PAGE_SIZE) {
+            return Err(FixmapError::Unaligned);
+        }
+        let entry = &mut self.phys[slot.index()];
+        if entry.is_some() {
+            return Err(FixmapError::SlotInUse);
+        }
+        *entry = Some(phys);
+        Ok(slot.virt())
+    }
+
+    /// Forgets the mapping at `slot`.
+    ///
+    /// # Returns
+    /// The physical address the slot was mapped to
+    pub fn clear(&mut self, slot: Slot) -> Result<u64, FixmapError> {
+        self.phys[slot.index()].take().ok_or(FixmapError::SlotEmpty)
+    }
+
+    /// Returns the physical page mapped at `slot`, if any.
+    #[allow(dead_code)]
+    pub fn get(&self, slot: Slot) -> Option<u64> {
+        self.phys[slot.index()]
+    }
+}
+
+#[cfg(target_os = "none")]
+mod tables {
+    use super::{FixmapError, Slot, Slots};
+    use crate::arch::address;
+    use crate::arch::pagetable::{self, ENTRIES, PageTable};
+    use spin::Mutex;
+
+    /// Statically allocated tables and slot state.
+    pub struct Fixmap {
+        /// Only linked when the root is an L0 table.
+        l1: PageTable,
+        l2: PageTable,
+        l3: PageTable,
+        slots: Slots,
+        ready: bool,
+    }
+
+    /// Global fixmap.
+    pub static FIXMAP: Mutex<Fixmap> = Mutex::new(Fixmap {
+        l1: PageTable {
+            entries: [0; ENTRIES],
+        },
+        l2: PageTable {
+            entries: [0; ENTRIES],
+        },
+        l3: PageTable {
+            entries: [0; ENTRIES],
+        },
+        slots: Slots::new(),
+        ready: false,
+    });
+
+    /// Link the fixmap tables into the kernel page tables.
+    pub fn init() -> Result<(), FixmapError> {
+        let mut fixmap = FIXMAP.lock();
+        if fixmap.ready {
+            return Ok(());
+        }
+
+        let va = address::kernel::FIXMAP_START;
+        let phys = |table: &PageTable| {
+            address::translation::virt_to_phys(table as *const PageTable as u64)
+        };
+        // Table to link below each level, indexed by level
+        let tables = [phys(&fixmap.l1), phys(&fixmap.l2), phys(&fixmap.l3)];
+
+        // Link the L3 table, adding our own tables above it until one finds
+        // an existing parent
+        let mut level = 2;
+        while let Err(e) = pagetable::install_table(va, level, tables[level]) {
+            if level == pagetable::ROOT_LEVEL {
+                return Err(FixmapError::PageTable(e));
+            }
+            level -= 1;
+        }
+        for (level, &table) in tables.iter().enumerate().skip(level + 1) {
+            pagetable::install_table(va, level, table).map_err(FixmapError::PageTable)?;
+        }
+
+        fixmap.ready = true;
+        Ok(())
+    }
+
+    /// Map `phys` at `slot`.
+    pub fn set(slot: Slot, phys: u64, attr_index: u64) -> Result<u64, FixmapError> {
+        let mut fixmap = FIXMAP.lock();
+        if !fixmap.ready {
+            return Err(FixmapError::NotInitialized);
+        }
+
+        let va = fixmap.slots.set(slot, phys)?;
+        let entry = &mut fixmap.l3.entries[pagetable::table_index(va, 3)];
+        // Safety: the descriptor belongs to the fixmap L3 table, which the
+        // walker only sees through `init`
+        unsafe { core::ptr::write_volatile(entry, pagetable::page_entry(phys, attr_index)) };
+        pagetable::flush_tlb_page(va);
+
+        Ok(va)
+    }
+
+    /// Remove the mapping at `slot`.
+    pub fn clear(slot: Slot) -> Result<(), FixmapError> {
+        let mut fixmap = FIXMAP.lock();
+        fixmap.slots.clear(slot)?;
+
+        let va = slot.virt();
+        let entry = &mut fixmap.l3.entries[pagetable::table_index(va, 3)];
+        // Safety: as in `set`
+        unsafe { core::ptr::write_volatile(entry, 0) };
+        pagetable::flush_tlb_page(va);
+
+        Ok(())
+    }
+}
+
+/// Link the fixmap's static page tables into the kernel tables.
+///
+/// Must run once after the MMU is on and before any `set`. Needs no
+/// allocator.
+#[cfg(target_os = "none")]
+pub fn init() -> Result<(), FixmapError> {
+    tables::init()
+}
+
+/// Map one physical page at a fixmap slot.
+///
+/// # Arguments
+/// * `slot` - Slot to fill, must be empty
+/// * `phys` - Page aligned physical address
+/// * `attr_index` - MAIR_EL1 attribute index for the mapping
+///
+/// # Returns
+/// Virtual address of the slot
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn set(slot: Slot, phys: u64, attr_index: u64) -> Result<u64, FixmapError> {
+    tables::set(slot, phys, attr_index)
+}
+
+/// Unmap a fixmap slot.
+///
+/// # Arguments
+/// * `slot` - Slot previously filled by `set`
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn clear(slot: Slot) -> Result<(), FixmapError> {
+    tables::clear(slot)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::mm::layout;
+
+    #[test]
+    fn test_fixmap_slot_addresses() {
+        assert_eq!(Slot::Dtb.virt(), layout::FIXMAP.start);
+        assert_eq!(Slot::Console.virt(), layout::FIXMAP.start + 0x1000);
+        assert_eq!(Slot::Temp.virt(), layout::FIXMAP.start + 0x2000);
+
+        for slot in [Slot::Dtb, Slot::Console, Slot::Temp] {
+            assert!(layout::FIXMAP.contains_range(slot.virt(), address::kernel::PAGE_SIZE));
+        }
+    }
+
+    #[test]
+    fn test_fixmap_double_set() {
+        let mut slots = Slots::new();
+        assert_eq!(slots.set(Slot::Dtb, 0x4000_0000), Ok(Slot::Dtb.virt()));
+        assert_eq!(
+            slots.set(Slot::Dtb, 0x4000_1000),
+            Err(FixmapError::SlotInUse)
+        );
+        // The first mapping is untouched and other slots are independent
+        assert_eq!(slots.get(Slot::Dtb), Some(0x4000_0000));
+        assert_eq!(
+            slots.set(Slot::Console, 0x0900_0000),
+            Ok(Slot::Console.virt())
+        );
+
+        assert_eq!(slots.clear(Slot::Dtb), Ok(0x4000_0000));
+        assert_eq!(slots.clear(Slot::Dtb), Err(FixmapError::SlotEmpty));
+        assert_eq!(slots.set(Slot::Dtb, 0x4000_1000), Ok(Slot::Dtb.virt()));
+    }
+
+    #[test]
+    fn test_fixmap_unaligned() {
+        let mut slots = Slots::new();
+        assert_eq!(
+            slots.set(Slot::Temp, 0x4000_0010),
+            Err(FixmapError::Unaligned)
+        );
+        assert_eq!(slots.get(Slot::Temp), None);
+    }
+}
@@ -0,0 +1,514 @@
+//! Kernel heap.
+//!
+//! The heap arena is carved out of the largest contiguous free range left
+//! in memblock once the boot reservations are in place, so it never has to
+//! straddle a reservation. The arena is reserved under
+//! `ReservationOwner::Heap` and handed to [`FreeList`], a first-fit
+//! allocator, which serves as the global allocator.
+//!
+//! With the `heap_debug` feature the allocator is wrapped in
+//! [`RedzoneAlloc`](super::redzone::RedzoneAlloc): every allocation gets
+//! guard zones, [`check_all`] verifies them on demand (the debug shell's
+//! `heapcheck`), and the timer tick runs the same check every
+//! [`CHECK_INTERVAL_SECS`].
+
+use crate::arch::address;
+use crate::arch::sync::IrqSafeMutex;
+use crate::mm::memblock::{self, Region, ReservationOwner};
+use core::alloc::{GlobalAlloc, Layout};
+use spin::Mutex;
+
+/// Upper bound on the arena size, so memblock keeps memory for later
+/// boot allocations.
+pub const MAX_HEAP_SIZE: u64 = 0x100_0000;
+
+/// Smallest arena worth setting up.
+pub const MIN_HEAP_SIZE: u64 = 0x1_0000;
+
+/// Physical range of the heap arena, once placed.
+static HEAP: Mutex<Option<Region>> = Mutex::new(None);
+
+/// Granule of heap blocks, also their smallest size and alignment.
+pub const MIN_BLOCK: usize = size_of::<FreeBlock>();
+
+/// Header written at the start of each free block.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct FreeBlock {
+    /// Size of the block in bytes.
+    size: usize,
+    /// Address of the next free block, 0 at the end of the list.
+    next: usize,
+}
+
+/// First-fit allocator over memory handed to it with [`add_region`].
+///
+/// Free blocks form a list sorted by address, threaded through the blocks
+/// themselves. Sizes and addresses are multiples of [`MIN_BLOCK`], so
+/// every piece left over by a split is large enough to hold a header.
+/// Freed blocks are merged with free neighbours.
+///
+/// [`add_region`]: FreeList::add_region
+#[derive(Debug)]
+pub struct FreeList {
+    /// Address of the lowest free block, 0 if there is none.
+    head: usize,
+    /// Bytes in free blocks.
+    free: usize,
+}
+
+impl FreeList {
+    /// Create an allocator with no memory.
+    pub const fn empty() -> Self {
+        Self { head: 0, free: 0 }
+    }
+
+    /// Returns the bytes in free blocks.
+    #[cfg(test)]
+    pub fn free_bytes(&self) -> usize {
+        self.free
+    }
+
+    /// Returns the block size and alignment used for `layout`.
+    fn block_layout(layout: Layout) -> (usize, usize) {
+        let size = layout.size().max(1).next_multiple_of(MIN_BLOCK);
+        (size, layout.align().max(MIN_BLOCK))
+    }
+
+    /// Give `[base, base + size)` to the allocator.
+    ///
+    /// The range is trimmed to [`MIN_BLOCK`] boundaries.
+    ///
+    /// # Safety
+    /// The range must be writable, used by nothing else and stay valid for
+    /// as long as the allocator is used.
+    pub unsafe fn add_region(&mut self, base: usize, size: usize) {
+        let start = base.next_multiple_of(MIN_BLOCK);
+        let end = (base + size) & !(MIN_BLOCK - 1);
+        if end > start {
+            // Safety: per the caller
+            unsafe { self.insert(start, end - start) };
+        }
+    }
+
+    /// Allocate a block for `layout`.
+    ///
+    /// # Returns
+    /// The block, or null if no free block is large enough
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::block_layout(layout);
+        let mut prev = 0;
+        let mut cur = self.head;
+        while cur != 0 {
+            // Safety: list entries are free blocks holding a header
+            let block = unsafe { read(cur) };
+            // A gap before the block must be able to stay on the list
+            let mut start = cur.next_multiple_of(align);
+            if start != cur && start - cur < MIN_BLOCK {
+                start = (cur + MIN_BLOCK).next_multiple_of(align);
+            }
+            let fits = start
+                .checked_add(size)
+                .is_some_and(|end| end <= cur + block.size);
+            if fits {
+                // Put what is left on either side back in `cur`'s place
+                let end = start + size;
+                let mut next = block.next;
+                if end < cur + block.size {
+                    let tail = FreeBlock {
+                        size: cur + block.size - end,
+                        next,
+                    };
+                    // Safety: the tail is part of the free block
+                    unsafe { write(end, tail) };
+                    next = end;
+                }
+                if start > cur {
+                    let size = start - cur;
+                    // Safety: the gap is the start of the free block
+                    unsafe { write(cur, FreeBlock { size, next }) };
+                    next = cur;
+                }
+                // Safety: `prev` is 0 or a list entry
+                unsafe { self.set_next(prev, next) };
+                self.free -= size;
+                return start as *mut u8;
+            }
+            prev = cur;
+            cur = block.next;
+        }
+        core::ptr::null_mut()
+    }
+
+    /// Free a block returned by [`alloc`](Self::alloc).
+    ///
+    /// # Safety
+    /// `ptr` must come from `alloc` with the same `layout` and not have
+    /// been freed since.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::block_layout(layout);
+        // Safety: per the caller the block is ours again
+        unsafe { self.insert(ptr as usize, size) };
+    }
+
+    /// Put the free block `[addr, addr + size)` on the list, merging it
+    /// with its neighbours.
+    ///
+    /// # Safety
+    /// The block must be unused, writable and not on the list.
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        self.free += size;
+        let mut prev = 0;
+        let mut next = self.head;
+        while next != 0 && next < addr {
+            prev = next;
+            // Safety: list entries are free blocks holding a header
+            next = unsafe { read(next) }.next;
+        }
+
+        let mut block = FreeBlock { size, next };
+        if next != 0 && addr + size == next {
+            // Safety: `next` is a list entry
+            let following = unsafe { read(next) };
+            block.size += following.size;
+            block.next = following.next;
+        }
+        if prev != 0 {
+            // Safety: `prev` is a list entry
+            let mut before = unsafe { read(prev) };
+            if prev + before.size == addr {
+                before.size += block.size;
+                before.next = block.next;
+                // Safety: as above
+                unsafe { write(prev, before) };
+                return;
+            }
+        }
+        // Safety: per the caller the block is ours to write
+        unsafe { write(addr, block) };
+        // Safety: `prev` is 0 or a list entry
+        unsafe { self.set_next(prev, addr) };
+    }
+
+    /// Point the entry at `prev`, or the list head if 0, at `next`.
+    ///
+    /// # Safety
+    /// `prev` must be 0 or a list entry.
+    unsafe fn set_next(&mut self, prev: usize, next: usize) {
+        if prev == 0 {
+            self.head = next;
+        } else {
+            // Safety: per the caller
+            let mut block = unsafe { read(prev) };
+            block.next = next;
+            // Safety: per the caller
+            unsafe { write(prev, block) };
+        }
+    }
+}
+
+/// Read the free block header at `addr`.
+///
+/// # Safety
+/// `addr` must hold a free block header.
+unsafe fn read(addr: usize) -> FreeBlock {
+    // Safety: per the caller; blocks are MIN_BLOCK aligned
+    unsafe { (addr as *const FreeBlock).read() }
+}
+
+/// Write a free block header at `addr`.
+///
+/// # Safety
+/// `addr` must be a free, writable, MIN_BLOCK aligned address.
+unsafe fn write(addr: usize, block: FreeBlock) {
+    // Safety: per the caller
+    unsafe { (addr as *mut FreeBlock).write(block) }
+}
+
+/// The locked kernel allocator.
+pub struct KernelHeap(IrqSafeMutex<FreeList>);
+
+impl KernelHeap {
+    /// Create the allocator with an empty arena.
+    pub const fn new() -> Self {
+        Self(IrqSafeMutex::new("heap", FreeList::empty()))
+    }
+}
+
+// Safety: blocks come from the free list, which hands out each byte of
+// the arena once until it is freed
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Safety: per the GlobalAlloc contract
+        unsafe { self.0.lock().dealloc(ptr, layout) }
+    }
+}
+
+#[cfg(all(target_os = "none", not(feature = "heap_debug")))]
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap::new();
+
+#[cfg(all(target_os = "none", feature = "heap_debug"))]
+#[global_allocator]
+static ALLOCATOR: super::redzone::RedzoneAlloc<KernelHeap> =
+    super::redzone::RedzoneAlloc::new(KernelHeap::new());
+
+/// Returns the allocator managing the arena, below any redzones.
+#[cfg(target_os = "none")]
+fn arena() -> &'static KernelHeap {
+    #[cfg(feature = "heap_debug")]
+    return ALLOCATOR.inner();
+    #[cfg(not(feature = "heap_debug"))]
+    &ALLOCATOR
+}
+
+/// Compute the arena to carve from a free range.
+///
+/// The range is shrunk to page boundaries and capped at `max_size`.
+///
+/// # Arguments
+/// * `free` - Free range to carve from
+/// * `max_size` - Largest arena to return
+///
+/// # Returns
+/// The arena, or `None` if less than [`MIN_HEAP_SIZE`] remains.
+pub fn heap_region(free: Region, max_size: u64) -> Option<Region> {
+    let page = address::kernel::PAGE_SIZE;
+    let base = free.base.checked_next_multiple_of(page)?;
+    let end = free.end() & !(page - 1);
+    let size = end.checked_sub(base)?.min(max_size & !(page - 1));
+    if size < MIN_HEAP_SIZE {
+        return None;
+    }
+    Some(Region::new(base, size))
+}
+
+/// Reserve the heap arena from the largest free memblock range.
+///
+/// # Returns
+/// The physical range of the arena.
+pub fn init_from_memblock() -> Result<Region, &'static str> {
+    let mut heap = HEAP.lock();
+    if heap.is_some() {
+        return Err("heap already initialized");
+    }
+
+    let mut mb = memblock::lock();
+    let free = mb.largest_free_block().ok_or("no free memory for heap")?;
+    let region = heap_region(free, MAX_HEAP_SIZE).ok_or("largest free block too small for heap")?;
+    mb.reserve_tagged(region.base, region.size, ReservationOwner::Heap)
+        .map_err(|e| e.as_str())?;
+
+    #[cfg(target_os = "none")]
+    {
+        let base = address::translation::phys_to_virt(region.base);
+        // Safety: the arena is reserved for the heap and linearly mapped
+        unsafe {
+            arena()
+                .0
+                .lock()
+                .add_region(base as usize, region.size as usize)
+        };
+    }
+    *heap = Some(region);
+    Ok(region)
+}
+
+/// Seconds between the redzone checks run from the timer tick.
+#[cfg(feature = "heap_debug")]
+pub const CHECK_INTERVAL_SECS: u64 = 5;
+
+/// Verify the redzones of every live heap allocation.
+///
+/// # Returns
+/// The number of allocations checked, or the address of the first
+/// damaged block and its damage
+#[cfg(all(target_os = "none", feature = "heap_debug"))]
+#[cfg_attr(not(feature = "debug_shell"), allow(dead_code))]
+pub fn check_all() -> Result<usize, (u64, super::redzone::Corruption)> {
+    ALLOCATOR.check_all()
+}
+
+/// Returns the number of live allocations [`check_all`] cannot see.
+#[cfg(all(target_os = "none", feature = "heap_debug"))]
+#[cfg_attr(not(feature = "debug_shell"), allow(dead_code))]
+pub fn untracked() -> u64 {
+    ALLOCATOR.untracked()
+}
+
+/// Run [`check_all`] if [`CHECK_INTERVAL_SECS`] passed since the last
+/// run, panicking with the corruption report on damage.
+///
+/// Called from the timer tick. Skips the check if the interrupted code
+/// was in the middle of an allocation.
+#[cfg(all(target_os = "none", feature = "heap_debug"))]
+pub fn tick() {
+    use crate::arch::timer;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    /// Counter value at which the next check is due.
+    static NEXT_CHECK: AtomicU64 = AtomicU64::new(0);
+
+    let freq = timer::frequency();
+    let now = timer::ticks();
+    if freq == 0 || now < NEXT_CHECK.load(Ordering::Relaxed) {
+        return;
+    }
+    match ALLOCATOR.try_check_all() {
+        // Try again on the next tick
+        None => return,
+        Some(Err((base, corruption))) => {
+            // Safety: tracked blocks are live
+            panic!("{}", unsafe {
+                super::redzone::Report::live(base, corruption)
+            });
+        }
+        Some(Ok(_)) => {}
+    }
+    NEXT_CHECK.store(now + CHECK_INTERVAL_SECS * freq, Ordering::Relaxed);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heap_region_aligns_and_caps() {
+        // Unaligned edges are trimmed to pages
+        assert_eq!(
+            heap_region(Region::new(0x1_0800, 0x2_0000), MAX_HEAP_SIZE),
+            Some(Region::new(0x1_1000, 0x1_f000))
+        );
+
+        // Large blocks are capped, keeping the low end
+        assert_eq!(
+            heap_region(Region::new(0x4000_0000, 0x4000_0000), MAX_HEAP_SIZE),
+            Some(Region::new(0x4000_0000, MAX_HEAP_SIZE))
+        );
+    }
+
+    #[test]
+    fn test_heap_region_too_small() {
+        assert_eq!(
+            heap_region(Region::new(0x1000, MIN_HEAP_SIZE - 0x1000), MAX_HEAP_SIZE),
+            None
+        );
+        assert_eq!(
+            heap_region(Region::new(0x1800, MIN_HEAP_SIZE), MAX_HEAP_SIZE),
+            None
+        );
+        assert_eq!(heap_region(Region::new(0x1800, 0x800), MAX_HEAP_SIZE), None);
+    }
+
+    /// A free list over `size` bytes of a fresh buffer, page aligned so
+    /// the placements are the same on every run.
+    fn free_list(size: usize) -> (FreeList, usize, Vec<u128>) {
+        let mut buf = vec![0u128; (size + 0x1000) / 16];
+        let base = (buf.as_mut_ptr() as usize).next_multiple_of(0x1000);
+        let mut heap = FreeList::empty();
+        unsafe { heap.add_region(base, size) };
+        (heap, base, buf)
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn test_free_list_first_fit() {
+        let (mut heap, base, _buf) = free_list(0x1000);
+        assert_eq!(heap.free_bytes(), 0x1000);
+
+        // Sizes round up to whole blocks
+        let a = heap.alloc(layout(1, 1));
+        let b = heap.alloc(layout(40, 8));
+        assert_eq!((a as usize, b as usize), (base, base + 16));
+        assert_eq!(heap.free_bytes(), 0x1000 - 64);
+
+        // The hole left by `a` is reused first
+        unsafe { heap.dealloc(a, layout(1, 1)) };
+        assert_eq!(heap.alloc(layout(16, 16)) as usize, base);
+
+        // Nothing fits
+        assert!(heap.alloc(layout(0x1000, 16)).is_null());
+    }
+
+    #[test]
+    fn test_free_list_alignment() {
+        let (mut heap, base, _buf) = free_list(0x2000);
+        let small = heap.alloc(layout(16, 16));
+        assert_eq!(small as usize, base);
+
+        // The gap before an aligned block stays allocatable
+        let aligned = heap.alloc(layout(64, 0x400));
+        assert_eq!(aligned as usize % 0x400, 0);
+        let gap = heap.alloc(layout(16, 16));
+        assert_eq!(gap as usize, base + 16);
+        assert_eq!(heap.free_bytes(), 0x2000 - 96);
+    }
+
+    #[test]
+    fn test_free_list_merges_neighbours() {
+        let (mut heap, _base, _buf) = free_list(0x1000);
+        let blocks: Vec<*mut u8> = (0..8).map(|_| heap.alloc(layout(100, 8))).collect();
+        // Free out of order: odd ones first, then the even ones fill the gaps
+        for &p in blocks.iter().skip(1).step_by(2) {
+            unsafe { heap.dealloc(p, layout(100, 8)) };
+        }
+        for &p in blocks.iter().step_by(2) {
+            unsafe { heap.dealloc(p, layout(100, 8)) };
+        }
+        assert_eq!(heap.free_bytes(), 0x1000);
+        // Everything merged back into one block
+        assert!(!heap.alloc(layout(0x1000, 16)).is_null());
+    }
+
+    #[test]
+    fn test_free_list_randomized() {
+        let (mut heap, base, _buf) = free_list(0x1_0000);
+        let mut live: Vec<(usize, Layout)> = Vec::new();
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        for _ in 0..5000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            if live.len() < 64 && !state.is_multiple_of(3) {
+                let l = layout((state >> 8) as usize % 500, 1 << ((state >> 20) % 7));
+                let p = heap.alloc(l) as usize;
+                if p == 0 {
+                    continue;
+                }
+                assert!(p.is_multiple_of(l.align()));
+                assert!(p >= base && p + l.size() <= base + 0x1_0000);
+                // No overlap with any live block
+                for &(q, m) in &live {
+                    assert!(p + l.size() <= q || q + m.size() <= p);
+                }
+                live.push((p, l));
+            } else if !live.is_empty() {
+                let (p, l) = live.swap_remove((state >> 8) as usize % live.len());
+                unsafe { heap.dealloc(p as *mut u8, l) };
+            }
+        }
+        for (p, l) in live.drain(..) {
+            unsafe { heap.dealloc(p as *mut u8, l) };
+        }
+        assert_eq!(heap.free_bytes(), 0x1_0000);
+    }
+
+    #[test]
+    fn test_heap_sized_from_largest_free_block() {
+        let mut mb = memblock::Memblock::new();
+        mb.add(0x4000_0000, 0x100_0000).unwrap();
+        mb.reserve(0x4000_0000, 0x20_0000).unwrap();
+        mb.reserve(0x4080_0000, 0x1000).unwrap();
+
+        let free = mb.largest_free_block().unwrap();
+        assert_eq!(free, Region::new(0x4080_1000, 0x7f_f000));
+        assert_eq!(heap_region(free, MAX_HEAP_SIZE), Some(free));
+    }
+}
@@ -0,0 +1,272 @@
+//! Device memory mapping (ioremap).
+//!
+//! MMIO regions are mapped into a dedicated virtual window with device
+//! memory attributes instead of relying on the linear map covering them.
+//! Virtual space in the window is handed out first-fit and can be returned
+//! with `iounmap`.
+
+use crate::arch::address;
+#[cfg(target_os = "none")]
+use crate::mm::VirtAddr;
+use crate::mm::memblock::Region;
+use spin::Mutex;
+
+/// Maximum number of live mappings in a window.
+const MAX_MAPPINGS: usize = 64;
+
+/// Memory attributes for device mappings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceAttr {
+    /// Device-nGnRnE, strongly ordered.
+    #[allow(dead_code)]
+    NGnRnE,
+    /// Device-nGnRE, allows early write acknowledgement.
+    NGnRE,
+}
+
+impl DeviceAttr {
+    /// Returns the MAIR_EL1 attribute index for this memory type.
+    pub fn mair_index(&self) -> u64 {
+        match self {
+            Self::NGnRnE => address::mair::IDX_DEVICE_NGNRNE,
+            Self::NGnRE => address::mair::IDX_DEVICE_NGNRE,
+        }
+    }
+}
+
+/// Errors returned by the ioremap API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// Requested size is zero or overflows.
+    InvalidSize,
+    /// No free virtual space large enough in the window.
+    OutOfVirtualSpace,
+    /// Maximum number of live mappings reached.
+    TooManyMappings,
+    /// Address was not returned by `ioremap`.
+    NotMapped,
+    /// Page table update failed.
+    PageTable(&'static str),
+}
+
+impl MapError {
+    /// Returns a human readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::InvalidSize => "invalid mapping size",
+            Self::OutOfVirtualSpace => "ioremap window exhausted",
+            Self::TooManyMappings => "maximum number of mappings reached",
+            Self::NotMapped => "address is not an ioremap mapping",
+            Self::PageTable(e) => e,
+        }
+    }
+}
+
+/// A page-granular virtual address window allocator.
+///
+/// ioremap and vmalloc each own a window.
+#[derive(Debug)]
+pub struct VaWindow {
+    /// Start of the window (inclusive).
+    start: u64,
+    /// End of the window (exclusive).
+    end: u64,
+    /// Allocated ranges, sorted by base address.
+    ranges: [Region; MAX_MAPPINGS],
+    /// Number of valid entries in `ranges`.
+    count: usize,
+}
+
+impl VaWindow {
+    /// Creates an empty window covering `[start, start + size)`.
+    pub const fn new(start: u64, size: u64) -> Self {
+        Self {
+            start,
+            end: start + size,
+            ranges: [Region::new(0, 0); MAX_MAPPINGS],
+            count: 0,
+        }
+    }
+
+    /// Checks if `addr` falls inside the window.
+    #[cfg(test)]
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Allocates `size` bytes of virtual space, rounded up to whole pages.
+    ///
+    /// Returns the page aligned base of the allocated range.
+    pub fn alloc(&mut self, size: u64) -> Result<u64, MapError> {
+        let page_size = address::kernel::PAGE_SIZE;
+        if size == 0 {
+            return Err(MapError::InvalidSize);
+        }
+        let size = size
+            .checked_next_multiple_of(page_size)
+            .ok_or(MapError::InvalidSize)?;
+
+        if self.count >= MAX_MAPPINGS {
+            return Err(MapError::TooManyMappings);
+        }
+
+        // First fit: look at the gap before each allocated range, then the tail
+        let mut candidate = self.start;
+        let mut insert_pos = self.count;
+        for i in 0..self.count {
+            if self.ranges[i].base - candidate >= size {
+                insert_pos = i;
+                break;
+            }
+            candidate = self.ranges[i].end();
+        }
+
+        if insert_pos == self.count && self.end - candidate < size {
+            return Err(MapError::OutOfVirtualSpace);
+        }
+
+        for i in (insert_pos..self.count).rev() {
+            self.ranges[i + 1] = self.ranges[i];
+        }
+        self.ranges[insert_pos] = Region::new(candidate, size);
+        self.count += 1;
+
+        Ok(candidate)
+    }
+
+    /// Frees the range starting at `base`.
+    ///
+    /// Returns the size of the freed range.
+    pub fn free(&mut self, base: u64) -> Result<u64, MapError> {
+        let pos = (0..self.count)
+            .find(|&i| self.ranges[i].base == base)
+            .ok_or(MapError::NotMapped)?;
+        let size = self.ranges[pos].size;
+
+        for i in pos..self.count - 1 {
+            self.ranges[i] = self.ranges[i + 1];
+        }
+        self.count -= 1;
+
+        Ok(size)
+    }
+}
+
+/// Global ioremap window.
+#[allow(dead_code)]
+static IOREMAP_WINDOW: Mutex<VaWindow> = Mutex::new(VaWindow::new(
+    address::kernel::IOREMAP_START,
+    address::kernel::IOREMAP_SIZE,
+));
+
+/// Map a device region into the ioremap window.
+///
+/// Requires memblock to be initialized, since new page tables are allocated
+/// from it.
+///
+/// # Arguments
+/// * `phys_base` - Physical address of the device region
+/// * `size` - Size of the region in bytes
+/// * `attr` - Device memory type for the mapping
+///
+/// # Returns
+/// Virtual address corresponding to `phys_base`
+#[cfg(target_os = "none")]
+pub fn ioremap(phys_base: u64, size: u64, attr: DeviceAttr) -> Result<VirtAddr, MapError> {
+    use crate::arch::pagetable;
+
+    let page_mask = address::kernel::PAGE_SIZE - 1;
+    let offset = phys_base & page_mask;
+    let map_size = size.checked_add(offset).ok_or(MapError::InvalidSize)?;
+
+    let mut window = IOREMAP_WINDOW.lock();
+    let va = window.alloc(map_size)?;
+    let aligned_size = map_size.next_multiple_of(page_mask + 1);
+
+    if let Err(e) = pagetable::map_range(va, phys_base - offset, aligned_size, attr.mair_index()) {
+        window.free(va)?;
+        return Err(MapError::PageTable(e));
+    }
+
+    Ok(va + offset)
+}
+
+/// Unmap a region previously returned by `ioremap`.
+///
+/// # Arguments
+/// * `va` - Virtual address returned by `ioremap`
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn iounmap(va: VirtAddr) -> Result<(), MapError> {
+    use crate::arch::pagetable;
+
+    let base = va & !(address::kernel::PAGE_SIZE - 1);
+    let mut window = IOREMAP_WINDOW.lock();
+    let size = window.free(base)?;
+
+    pagetable::unmap_range(base, size).map_err(MapError::PageTable)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const START: u64 = 0x1_0000_0000;
+
+    #[test]
+    fn test_window_alloc() {
+        let mut window = VaWindow::new(START, 0x10000);
+        assert_eq!(window.alloc(0x1000), Ok(START));
+        // Sizes are rounded up to whole pages
+        assert_eq!(window.alloc(0x10), Ok(START + 0x1000));
+        assert_eq!(window.alloc(0x1001), Ok(START + 0x2000));
+        assert_eq!(window.alloc(0x1000), Ok(START + 0x4000));
+        assert_eq!(window.alloc(0), Err(MapError::InvalidSize));
+    }
+
+    #[test]
+    fn test_window_free_and_reuse() {
+        let mut window = VaWindow::new(START, 0x10000);
+        let a = window.alloc(0x1000).unwrap();
+        let b = window.alloc(0x2000).unwrap();
+        let c = window.alloc(0x1000).unwrap();
+
+        assert_eq!(window.free(b), Ok(0x2000));
+        assert_eq!(window.free(b), Err(MapError::NotMapped));
+
+        // The freed hole is reused first fit
+        assert_eq!(window.alloc(0x1000), Ok(b));
+        assert_eq!(window.alloc(0x1000), Ok(b + 0x1000));
+        // Too big for any hole, goes after the last range
+        assert_eq!(window.alloc(0x2000), Ok(c + 0x1000));
+        assert_eq!(window.free(a), Ok(0x1000));
+    }
+
+    #[test]
+    fn test_window_exhaustion() {
+        let mut window = VaWindow::new(START, 0x4000);
+        assert_eq!(window.alloc(0x3000), Ok(START));
+        assert_eq!(window.alloc(0x2000), Err(MapError::OutOfVirtualSpace));
+        assert_eq!(window.alloc(0x1000), Ok(START + 0x3000));
+        assert_eq!(window.alloc(0x1000), Err(MapError::OutOfVirtualSpace));
+        assert_eq!(window.alloc(u64::MAX), Err(MapError::InvalidSize));
+    }
+
+    #[test]
+    fn test_window_contains() {
+        let window = VaWindow::new(START, 0x4000);
+        assert!(window.contains(START));
+        assert!(window.contains(START + 0x3fff));
+        assert!(!window.contains(START + 0x4000));
+        assert!(!window.contains(START - 1));
+    }
+
+    #[test]
+    fn test_window_mapping_limit() {
+        let mut window = VaWindow::new(START, 0x1000 * (MAX_MAPPINGS as u64 + 1));
+        for _ in 0..MAX_MAPPINGS {
+            window.alloc(0x1000).unwrap();
+        }
+        assert_eq!(window.alloc(0x1000), Err(MapError::TooManyMappings));
+    }
+}
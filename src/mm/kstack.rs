@@ -0,0 +1,295 @@
+//! Guard-page protected kernel stacks.
+//!
+//! Each stack is allocated with one extra page below it that is left
+//! unmapped, so running off the bottom of the stack raises a data abort
+//! instead of silently corrupting the neighbouring allocation. Live stacks
+//! are tracked in a registry so the exception handler can tell a stack
+//! overflow apart from any other fault.
+//!
+//! Stacks are blocks of the buddy allocator, so they can only be
+//! allocated once boot has promoted to it.
+
+use crate::arch::address;
+#[cfg(target_os = "none")]
+use crate::mm::page_alloc;
+use spin::Mutex;
+
+/// Maximum number of stacks tracked by the registry.
+const MAX_STACKS: usize = 16;
+
+/// Errors returned when allocating a kernel stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum AllocError {
+    /// Not enough physical memory for the stack and its guard page.
+    OutOfMemory(&'static str),
+    /// Unmapping the guard page failed.
+    Map(&'static str),
+    /// Maximum number of tracked stacks reached.
+    RegistryFull,
+}
+
+impl AllocError {
+    /// Returns a human readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::OutOfMemory(e) | Self::Map(e) => e,
+            Self::RegistryFull => "maximum number of kernel stacks reached",
+        }
+    }
+}
+
+/// Virtual address layout of a guarded stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackLayout {
+    /// Start of the guard page.
+    pub guard: u64,
+    /// Lowest usable stack address.
+    pub bottom: u64,
+    /// Initial stack pointer, 16-byte aligned.
+    pub top: u64,
+}
+
+impl StackLayout {
+    /// Compute the layout of a stack whose guard page starts at `base`.
+    ///
+    /// # Arguments
+    /// * `base` - Page aligned start of the allocation
+    /// * `stack_size` - Usable stack size in bytes
+    pub const fn new(base: u64, stack_size: u64) -> Self {
+        let bottom = base + address::kernel::PAGE_SIZE;
+        Self {
+            guard: base,
+            bottom,
+            top: (bottom + stack_size) & !0xf,
+        }
+    }
+
+    /// Returns the allocation size for a stack plus its guard page.
+    pub const fn alloc_size(stack_size: u64) -> u64 {
+        stack_size.next_multiple_of(address::kernel::PAGE_SIZE) + address::kernel::PAGE_SIZE
+    }
+
+    /// Returns the order of the smallest block holding a stack plus its
+    /// guard page.
+    #[allow(dead_code)]
+    pub const fn alloc_order(stack_size: u64) -> usize {
+        let pages = Self::alloc_size(stack_size) / address::kernel::PAGE_SIZE;
+        pages.next_power_of_two().trailing_zeros() as usize
+    }
+
+    /// Checks if `addr` falls inside the guard page.
+    pub fn in_guard(&self, addr: u64) -> bool {
+        addr >= self.guard && addr < self.bottom
+    }
+}
+
+/// Registry of live stacks, indexed by stack id.
+struct StackRegistry {
+    stacks: [Option<StackLayout>; MAX_STACKS],
+}
+
+impl StackRegistry {
+    const fn new() -> Self {
+        Self {
+            stacks: [None; MAX_STACKS],
+        }
+    }
+
+    /// Track `layout`, returning its stack id.
+    fn register(&mut self, layout: StackLayout) -> Result<usize, AllocError> {
+        let id = self
+            .stacks
+            .iter()
+            .position(Option::is_none)
+            .ok_or(AllocError::RegistryFull)?;
+        self.stacks[id] = Some(layout);
+        Ok(id)
+    }
+
+    /// Stop tracking stack `id`.
+    #[allow(dead_code)]
+    fn unregister(&mut self, id: usize) {
+        self.stacks[id] = None;
+    }
+
+    /// Returns the id of the stack whose guard page contains `addr`.
+    fn classify(&self, addr: u64) -> Option<usize> {
+        self.stacks
+            .iter()
+            .position(|s| s.is_some_and(|s| s.in_guard(addr)))
+    }
+}
+
+/// Global stack registry.
+static REGISTRY: Mutex<StackRegistry> = Mutex::new(StackRegistry::new());
+
+/// Track a stack that was not allocated through `KernelStack`.
+///
+/// Used for the boot stack, whose guard page is set up by the boot code.
+pub fn register(layout: StackLayout) -> Result<usize, AllocError> {
+    REGISTRY.lock().register(layout)
+}
+
+/// Returns the id of the stack whose guard page contains `addr`, if any.
+///
+/// Safe to call from the exception handler: gives up instead of spinning
+/// if the registry is locked.
+pub fn classify_fault(addr: u64) -> Option<usize> {
+    REGISTRY.try_lock()?.classify(addr)
+}
+
+/// A kernel stack with an unmapped guard page below it.
+#[allow(dead_code)]
+pub struct KernelStack {
+    /// Physical address of the allocation (guard page first).
+    phys: u64,
+    /// Virtual layout within the linear map.
+    layout: StackLayout,
+    /// Registry id.
+    id: usize,
+}
+
+#[allow(dead_code)]
+impl KernelStack {
+    /// Allocate a `STACK_SIZE` stack plus guard page.
+    ///
+    /// The stack pages stay mapped read/write through the kernel linear
+    /// map; only the guard page is unmapped. The stack holds the only
+    /// reference to its block.
+    #[cfg(target_os = "none")]
+    pub fn allocate() -> Result<Self, AllocError> {
+        use crate::arch::pagetable;
+
+        let stack_size = address::kernel::STACK_SIZE;
+        let order = StackLayout::alloc_order(stack_size);
+        let phys = page_alloc::alloc_pages(order, page_alloc::GFP_KERNEL)
+            .map_err(|e| AllocError::OutOfMemory(e.as_str()))?;
+        let layout = StackLayout::new(address::translation::phys_to_virt(phys), stack_size);
+
+        if let Err(e) = pagetable::unmap_page(layout.guard) {
+            let _ = page_alloc::put_page(phys);
+            return Err(AllocError::Map(e));
+        }
+
+        let id = match register(layout) {
+            Ok(id) => id,
+            Err(e) => {
+                Self::release(phys, layout);
+                return Err(e);
+            }
+        };
+
+        Ok(Self { phys, layout, id })
+    }
+
+    /// Remap the guard page and drop the stack's reference to its block.
+    #[cfg(target_os = "none")]
+    fn release(phys: u64, layout: StackLayout) {
+        use crate::arch::pagetable;
+
+        let page_size = address::kernel::PAGE_SIZE;
+        let _ = pagetable::map_range(layout.guard, phys, page_size, address::mair::IDX_NORMAL);
+        let _ = page_alloc::put_page(phys);
+    }
+
+    /// Returns the initial stack pointer (16-byte aligned).
+    pub fn top(&self) -> u64 {
+        self.layout.top
+    }
+
+    /// Returns the lowest usable stack address.
+    pub fn bottom(&self) -> u64 {
+        self.layout.bottom
+    }
+
+    /// Returns the registry id used in overflow reports.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+#[cfg(target_os = "none")]
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        REGISTRY.lock().unregister(self.id);
+        Self::release(self.phys, self.layout);
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = address::kernel::VIRTUAL_BASE + 0x4010_0000;
+
+    #[test]
+    fn test_stack_layout() {
+        let layout = StackLayout::new(BASE, 0x10000);
+        assert_eq!(layout.guard, BASE);
+        assert_eq!(layout.bottom, BASE + 0x1000);
+        assert_eq!(layout.top, BASE + 0x11000);
+        assert_eq!(layout.top % 16, 0);
+
+        // Odd sizes still give an aligned top inside the stack
+        let layout = StackLayout::new(BASE, 0x1001);
+        assert_eq!(layout.top, BASE + 0x2000);
+    }
+
+    #[test]
+    fn test_stack_alloc_size() {
+        assert_eq!(StackLayout::alloc_size(0x10000), 0x11000);
+        assert_eq!(StackLayout::alloc_size(0x1001), 0x3000);
+
+        // Rounded up to a whole block
+        assert_eq!(StackLayout::alloc_order(0x10000), 5);
+        assert_eq!(StackLayout::alloc_order(0x1000), 1);
+        assert_eq!(StackLayout::alloc_order(0x1001), 2);
+    }
+
+    #[test]
+    fn test_stack_guard_placement() {
+        let layout = StackLayout::new(BASE, 0x4000);
+        assert!(!layout.in_guard(BASE - 1));
+        assert!(layout.in_guard(BASE));
+        assert!(layout.in_guard(BASE + 0xfff));
+        assert!(!layout.in_guard(layout.bottom));
+        assert!(!layout.in_guard(layout.top - 8));
+    }
+
+    #[test]
+    fn test_registry_classify() {
+        let mut registry = StackRegistry::new();
+        let a = registry.register(StackLayout::new(BASE, 0x4000)).unwrap();
+        let b = registry
+            .register(StackLayout::new(BASE + 0x10000, 0x4000))
+            .unwrap();
+        assert_ne!(a, b);
+
+        // Overflow just below the bottom of stack b
+        assert_eq!(registry.classify(BASE + 0x10ff8), Some(b));
+        assert_eq!(registry.classify(BASE + 0x10), Some(a));
+        // Inside a stack or between stacks is not an overflow
+        assert_eq!(registry.classify(BASE + 0x2000), None);
+        assert_eq!(registry.classify(BASE + 0x8000), None);
+
+        registry.unregister(a);
+        assert_eq!(registry.classify(BASE + 0x10), None);
+        // Freed slots are reused
+        assert_eq!(registry.register(StackLayout::new(BASE, 0x4000)), Ok(a));
+    }
+
+    #[test]
+    fn test_registry_full() {
+        let mut registry = StackRegistry::new();
+        for i in 0..MAX_STACKS as u64 {
+            registry
+                .register(StackLayout::new(BASE + i * 0x10000, 0x4000))
+                .unwrap();
+        }
+        assert_eq!(
+            registry.register(StackLayout::new(BASE, 0x4000)),
+            Err(AllocError::RegistryFull)
+        );
+    }
+}
@@ -0,0 +1,413 @@
+//! Kernel virtual memory map.
+//!
+//! Names the windows of the kernel's TTBR1 address space and checks that
+//! they stay apart and inside the `VA_BITS` VA space starting at
+//! `VIRTUAL_BASE`. The page table mapper refuses mappings that fall outside
+//! every window, so a stray address is caught before it corrupts the tables.
+//!
+//! `phys_to_virt` offsets every physical address by `VIRTUAL_BASE`, so RAM
+//! only lands in the linear map window if the window reaches `RAM_END`;
+//! [`validate_linear_map`] checks that at compile time.
+//!
+//! ```text
+//! VIRTUAL_BASE + LOAD_OFFSET   kernel image (up to MAX_KERNEL_SIZE)
+//! VIRTUAL_BASE + RAM_BASE      linear map of RAM
+//! IOREMAP_START                MMIO (ioremap) window
+//! VMALLOC_START                vmalloc window
+//! FIXMAP_START                 fixmap, near the top of the VA space
+//! ```
+
+use crate::arch::address::{kernel, layout_checks, translation, virt};
+use core::fmt;
+
+/// A named, half-open range of kernel virtual addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRange {
+    /// Name used in diagnostics.
+    pub name: &'static str,
+    /// First virtual address of the range.
+    pub start: u64,
+    /// Size of the range in bytes.
+    pub size: u64,
+}
+
+impl VirtRange {
+    /// Create a range covering `[start, start + size)`.
+    pub const fn new(name: &'static str, start: u64, size: u64) -> Self {
+        Self { name, start, size }
+    }
+
+    /// Returns the end address (exclusive), saturating on overflow.
+    pub const fn end(&self) -> u64 {
+        self.start.saturating_add(self.size)
+    }
+
+    /// Returns true if `[va, va + size)` lies entirely within this range.
+    pub const fn contains_range(&self, va: u64, size: u64) -> bool {
+        va >= self.start && size <= self.size && va - self.start <= self.size - size
+    }
+
+    /// Returns true if this range and `other` share at least one address.
+    pub const fn overlaps(&self, other: &VirtRange) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+
+    /// Returns true if the range lies inside the kernel VA space.
+    const fn in_va_space(&self) -> bool {
+        self.start >= kernel::VIRTUAL_BASE
+            && self.size <= kernel::VA_SPACE_SIZE
+            && self.start - kernel::VIRTUAL_BASE <= kernel::VA_SPACE_SIZE - self.size
+    }
+}
+
+/// Kernel image, as linked at `VIRTUAL_START`.
+pub const KERNEL_IMAGE: VirtRange = VirtRange::new(
+    "kernel image",
+    kernel::VIRTUAL_START,
+    layout_checks::MAX_KERNEL_SIZE,
+);
+
+/// Linear map of RAM, `phys_to_virt` for RAM addresses lands here.
+pub const LINEAR_MAP: VirtRange = VirtRange::new(
+    "linear map",
+    translation::phys_to_virt(virt::RAM_BASE),
+    virt::RAM_SIZE,
+);
+
+/// Window the linear map may populate, physical 0 at its start.
+pub const LINEAR_MAP_WINDOW: VirtRange = VirtRange::new(
+    "linear map window",
+    kernel::VIRTUAL_BASE,
+    kernel::LINEAR_MAP_SIZE,
+);
+
+/// Window reserved for virtually contiguous kernel allocations.
+pub const VMALLOC: VirtRange =
+    VirtRange::new("vmalloc", kernel::VMALLOC_START, kernel::VMALLOC_SIZE);
+
+/// Window handed out by `ioremap` for device mappings.
+pub const MMIO: VirtRange = VirtRange::new("mmio", kernel::IOREMAP_START, kernel::IOREMAP_SIZE);
+
+/// Fixed-address mappings for early and temporary use.
+pub const FIXMAP: VirtRange = VirtRange::new("fixmap", kernel::FIXMAP_START, kernel::FIXMAP_SIZE);
+
+/// Every window of the kernel virtual address space.
+pub const KERNEL_LAYOUT: [VirtRange; 5] = [KERNEL_IMAGE, LINEAR_MAP, MMIO, FIXMAP, VMALLOC];
+
+const _: () = assert!(
+    kernel::VIRTUAL_BASE == 0u64.wrapping_sub(kernel::VA_SPACE_SIZE),
+    "VIRTUAL_BASE must be the start of the VA_BITS TTBR1 range"
+);
+const _: () = assert!(
+    validate(&KERNEL_LAYOUT).is_ok(),
+    "kernel virtual layout is invalid"
+);
+const _: () = assert!(
+    validate_linear_map(&LINEAR_MAP_WINDOW, virt::RAM_BASE, virt::RAM_SIZE).is_ok(),
+    "the linear map window must cover RAM up to RAM_END"
+);
+const _: () = assert!(
+    LINEAR_MAP.start == LINEAR_MAP_WINDOW.start + virt::RAM_BASE
+        && LINEAR_MAP.end() == translation::phys_to_virt(virt::RAM_END),
+    "LINEAR_MAP must be where phys_to_virt puts RAM"
+);
+
+/// Problems found in a virtual layout definition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeError {
+    /// The named range has zero size.
+    Empty(&'static str),
+    /// The named range is not page aligned.
+    Unaligned(&'static str),
+    /// The named range does not fit in the kernel VA space.
+    OutsideVaSpace(&'static str),
+    /// The two named ranges overlap.
+    Overlap(&'static str, &'static str),
+    /// RAM does not fit in the named linear map window.
+    Uncovered(&'static str),
+}
+
+impl RangeError {
+    /// Returns a short human-readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RangeError::Empty(_) => "virtual range is empty",
+            RangeError::Unaligned(_) => "virtual range is not page aligned",
+            RangeError::OutsideVaSpace(_) => "virtual range is outside the kernel VA space",
+            RangeError::Overlap(..) => "virtual ranges overlap",
+            RangeError::Uncovered(_) => "RAM is not covered by the linear map window",
+        }
+    }
+}
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RangeError::Empty(name)
+            | RangeError::Unaligned(name)
+            | RangeError::OutsideVaSpace(name)
+            | RangeError::Uncovered(name) => write!(f, "{}: {}", self.as_str(), name),
+            RangeError::Overlap(a, b) => write!(f, "{}: {} and {}", self.as_str(), a, b),
+        }
+    }
+}
+
+/// Check a layout definition.
+///
+/// Every range must be non-empty, page aligned and inside the `VA_BITS` VA
+/// space starting at `VIRTUAL_BASE`, and no two ranges may overlap.
+///
+/// # Arguments
+/// * `ranges` - Ranges making up the layout, in any order
+///
+/// # Returns
+/// `Ok(())` if the layout is sane, or the first problem found
+pub const fn validate(ranges: &[VirtRange]) -> Result<(), RangeError> {
+    let mut i = 0;
+    while i < ranges.len() {
+        let range = &ranges[i];
+        if range.size == 0 {
+            return Err(RangeError::Empty(range.name));
+        }
+        if !range.start.is_multiple_of(kernel::PAGE_SIZE)
+            || !range.size.is_multiple_of(kernel::PAGE_SIZE)
+        {
+            return Err(RangeError::Unaligned(range.name));
+        }
+        if !range.in_va_space() {
+            return Err(RangeError::OutsideVaSpace(range.name));
+        }
+
+        let mut j = 0;
+        while j < i {
+            if range.overlaps(&ranges[j]) {
+                return Err(RangeError::Overlap(ranges[j].name, range.name));
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+/// Check that a linear map window covers RAM.
+///
+/// Physical address 0 maps to the start of the window, so RAM is covered
+/// only if `RAM_END` is at most the window size. Evaluated at compile time
+/// for the real constants; a layout that fails here would have
+/// `phys_to_virt` return addresses the boot tables never map.
+///
+/// # Arguments
+/// * `window` - The linear map window
+/// * `ram_base` - Physical base of RAM
+/// * `ram_size` - Size of RAM
+///
+/// # Returns
+/// `Ok(())` if all of RAM maps into the window, or the problem found
+pub const fn validate_linear_map(
+    window: &VirtRange,
+    ram_base: u64,
+    ram_size: u64,
+) -> Result<(), RangeError> {
+    if window.size == 0 || ram_size == 0 {
+        return Err(RangeError::Empty(window.name));
+    }
+    if !window.in_va_space() {
+        return Err(RangeError::OutsideVaSpace(window.name));
+    }
+    match ram_base.checked_add(ram_size) {
+        Some(ram_end) if ram_end <= window.size => Ok(()),
+        _ => Err(RangeError::Uncovered(window.name)),
+    }
+}
+
+/// Preferred physical addresses of boot-time allocations.
+///
+/// Allocated through `memblock::request_fixed`, these land at the same
+/// address every boot, which keeps dumps comparable between runs. They sit
+/// above the largest kernel image and below 128 MiB of RAM, the least QEMU
+/// virt boots with. A slot that is taken or not RAM is placed elsewhere
+/// with a message on the console.
+pub mod phys {
+    use crate::arch::address::{kernel, layout_checks::MAX_KERNEL_SIZE, virt};
+    use crate::mm::cma;
+    use crate::mm::memblock::{FixedSlot, ReservationOwner};
+
+    /// The CMA pool, at its default size.
+    pub const CMA: FixedSlot = FixedSlot {
+        name: "cma",
+        base: virt::RAM_BASE + 0x600_0000,
+        align: cma::POOL_ALIGN,
+        limit: None,
+        owner: ReservationOwner::Cma,
+    };
+
+    /// The page allocator's free bitmaps.
+    pub const PAGE_ALLOC: FixedSlot = FixedSlot {
+        name: "page_alloc",
+        base: virt::RAM_BASE + 0x700_0000,
+        align: kernel::PAGE_SIZE,
+        limit: None,
+        owner: ReservationOwner::PageAlloc,
+    };
+
+    const _: () = assert!(
+        CMA.base >= virt::RAM_BASE + kernel::LOAD_OFFSET + MAX_KERNEL_SIZE,
+        "fixed slots must lie above the largest kernel image"
+    );
+    const _: () = assert!(
+        CMA.base + cma::DEFAULT_POOL_SIZE <= PAGE_ALLOC.base,
+        "the default CMA pool must not reach the page allocator slot"
+    );
+    const _: () = assert!(
+        CMA.base + cma::DEFAULT_POOL_SIZE <= virt::RAM_BASE + cma::DMA_WINDOW,
+        "the CMA slot must lie in the DMA window"
+    );
+}
+
+/// Find the kernel window containing all of `[va, va + size)`.
+///
+/// # Returns
+/// The window, or `None` if the range is outside the layout or straddles
+/// two windows
+pub fn window_of(va: u64, size: u64) -> Option<&'static VirtRange> {
+    KERNEL_LAYOUT
+        .iter()
+        .find(|range| range.contains_range(va, size))
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = kernel::VIRTUAL_BASE;
+
+    #[test]
+    fn test_kernel_layout_valid() {
+        assert_eq!(validate(&KERNEL_LAYOUT), Ok(()));
+        assert_eq!(validate(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_layout_overlap_detected() {
+        let a = VirtRange::new("a", BASE, 0x2000);
+        let b = VirtRange::new("b", BASE + 0x1000, 0x2000);
+        assert_eq!(validate(&[a, b]), Err(RangeError::Overlap("a", "b")));
+
+        // Order does not matter, and adjacent ranges are fine
+        assert_eq!(validate(&[b, a]), Err(RangeError::Overlap("b", "a")));
+        let c = VirtRange::new("c", BASE + 0x2000, 0x1000);
+        assert_eq!(validate(&[a, c]), Ok(()));
+
+        // A window placed over an existing one
+        let mut layout = KERNEL_LAYOUT;
+        layout[3].start = MMIO.start + 0x1000;
+        assert_eq!(
+            validate(&layout),
+            Err(RangeError::Overlap("mmio", "fixmap"))
+        );
+    }
+
+    #[test]
+    fn test_layout_out_of_range_detected() {
+        // Below VIRTUAL_BASE, in the TTBR0 half
+        let low = VirtRange::new("low", 0x4000_0000, 0x1000);
+        assert_eq!(validate(&[low]), Err(RangeError::OutsideVaSpace("low")));
+
+        let below = VirtRange::new("below", BASE - 0x1000, 0x2000);
+        assert_eq!(validate(&[below]), Err(RangeError::OutsideVaSpace("below")));
+
+        // Ending exactly at the top of the address space is allowed
+        let top = VirtRange::new("top", u64::MAX - 0xfff, 0x1000);
+        assert_eq!(validate(&[top]), Ok(()));
+        let past = VirtRange::new("past", u64::MAX - 0xfff, 0x2000);
+        assert_eq!(validate(&[past]), Err(RangeError::OutsideVaSpace("past")));
+
+        let huge = VirtRange::new("huge", BASE, kernel::VA_SPACE_SIZE + 0x1000);
+        assert_eq!(validate(&[huge]), Err(RangeError::OutsideVaSpace("huge")));
+    }
+
+    #[test]
+    fn test_layout_bad_ranges() {
+        let empty = VirtRange::new("empty", BASE, 0);
+        assert_eq!(validate(&[empty]), Err(RangeError::Empty("empty")));
+
+        let unaligned = VirtRange::new("unaligned", BASE + 0x10, 0x1000);
+        assert_eq!(
+            validate(&[unaligned]),
+            Err(RangeError::Unaligned("unaligned"))
+        );
+    }
+
+    #[test]
+    fn test_window_of() {
+        assert_eq!(window_of(MMIO.start, 0x1000), Some(&MMIO));
+        assert_eq!(window_of(MMIO.end() - 0x1000, 0x1000), Some(&MMIO));
+        assert_eq!(
+            window_of(kernel::VIRTUAL_BASE + virt::RAM_BASE + 0x1000, 0x1000),
+            Some(&LINEAR_MAP)
+        );
+        assert_eq!(window_of(FIXMAP.start, FIXMAP.size), Some(&FIXMAP));
+        assert_eq!(window_of(VMALLOC.start, 0x1000), Some(&VMALLOC));
+
+        // Straddling the end of a window, or outside all of them
+        assert_eq!(window_of(MMIO.end() - 0x1000, 0x2000), None);
+        assert_eq!(window_of(VMALLOC.end() - 0x1000, 0x2000), None);
+        assert_eq!(window_of(0x4000_0000, 0x1000), None);
+    }
+
+    #[test]
+    fn test_linear_map_covers_ram() {
+        assert_eq!(
+            validate_linear_map(&LINEAR_MAP_WINDOW, virt::RAM_BASE, virt::RAM_SIZE),
+            Ok(())
+        );
+        let last = translation::phys_to_virt(virt::RAM_END - 1);
+        assert!(LINEAR_MAP.contains_range(last, 1));
+        assert!(LINEAR_MAP_WINDOW.contains_range(last, 1));
+        assert_eq!(window_of(last, 1), Some(&LINEAR_MAP));
+    }
+
+    #[test]
+    fn test_linear_map_inconsistent_constants() {
+        // These would fail the build as the real constants
+        let window = VirtRange::new("2GB window", BASE, 0x8000_0000);
+        // RAM ending exactly at the window end still fits
+        assert_eq!(
+            validate_linear_map(&window, 0x4000_0000, 0x4000_0000),
+            Ok(())
+        );
+        // 2GB of RAM at 1GB runs 1GB past it
+        assert_eq!(
+            validate_linear_map(&window, 0x4000_0000, 0x8000_0000),
+            Err(RangeError::Uncovered("2GB window"))
+        );
+        assert_eq!(
+            validate_linear_map(&window, u64::MAX - 0xfff, 0x2000),
+            Err(RangeError::Uncovered("2GB window"))
+        );
+        assert_eq!(
+            validate_linear_map(&window, 0x4000_0000, 0),
+            Err(RangeError::Empty("2GB window"))
+        );
+
+        // A window in the user half cannot be the linear map
+        let low = VirtRange::new("low", 0, 0x8000_0000);
+        assert_eq!(
+            validate_linear_map(&low, 0x4000_0000, 0x1000),
+            Err(RangeError::OutsideVaSpace("low"))
+        );
+        assert_eq!(
+            RangeError::Uncovered("linear map window").to_string(),
+            "RAM is not covered by the linear map window: linear map window"
+        );
+    }
+
+    #[test]
+    fn test_range_error_display() {
+        let e = RangeError::Overlap("mmio", "fixmap");
+        assert_eq!(e.to_string(), "virtual ranges overlap: mmio and fixmap");
+        let e = RangeError::Empty("fixmap");
+        assert_eq!(e.to_string(), "virtual range is empty: fixmap");
+    }
+}
@@ -4,12 +4,231 @@
 //! before the full buddy system is initialized. It manages physical memory
 //! regions with basic reserve and allocation operations.
 
+#[cfg(any(target_os = "none", test))]
+use crate::arch::irq::{self, IrqGuard};
+#[cfg(target_os = "none")]
+use crate::arch::sync::{IrqSafeMutex, IrqSafeMutexGuard};
+use crate::stats::{self, Counter};
+use core::cmp::Ordering;
 use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering as AtomicOrdering, fence};
+#[cfg(not(target_os = "none"))]
 use spin::Mutex;
 
 /// Maximum number of memory regions that can be tracked.
 const MAX_REGIONS: usize = 128;
 
+/// Maximum number of multi-page allocations whose size is remembered.
+const MAX_ALLOCATIONS: usize = 64;
+
+/// Page granule used by page-sized allocations.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// One past the highest physical address, 2^64.
+///
+/// A region may end exactly here, which does not fit in a `u64`; region
+/// ends are compared as `u128` internally for that reason.
+const ADDRESS_SPACE_END: u128 = 1 << 64;
+
+/// Region must not be mapped by the kernel (e.g. guard pages).
+#[allow(dead_code)]
+pub const FLAG_NOMAP: u64 = 1 << 0;
+
+/// Region is a device register window, not RAM.
+pub const FLAG_MMIO: u64 = 1 << 1;
+
+/// Number of successful allocations.
+pub static ALLOC_COUNT: Counter = Counter::new();
+
+/// Number of successful reservations, including those made by allocations.
+pub static RESERVE_COUNT: Counter = Counter::new();
+
+/// What a reserved region is being used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationOwner {
+    /// Kernel text, data and BSS.
+    KernelImage,
+    /// Flattened device tree blob.
+    Dtb,
+    /// Initial ramdisk.
+    Initrd,
+    /// Page frame descriptor array.
+    MemMap,
+    /// Kernel stacks and their guard pages.
+    Stack,
+    /// Translation tables.
+    PageTable,
+    /// Kernel heap arena.
+    Heap,
+    /// Contiguous DMA pool.
+    Cma,
+    /// Per-CPU data blocks.
+    PerCpu,
+    /// Untagged `alloc` calls.
+    EarlyAlloc,
+    /// Memory handed over to the page allocator.
+    PageAlloc,
+    /// Unpacked embedded payload.
+    Payload,
+    /// Device register windows outside RAM.
+    Mmio,
+    /// Untagged `reserve` calls.
+    Other,
+}
+
+impl ReservationOwner {
+    /// Number of owner kinds.
+    pub const COUNT: usize = 14;
+
+    /// All owner kinds, in display order.
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::KernelImage,
+        Self::Dtb,
+        Self::Initrd,
+        Self::MemMap,
+        Self::Stack,
+        Self::PageTable,
+        Self::Heap,
+        Self::Cma,
+        Self::PerCpu,
+        Self::EarlyAlloc,
+        Self::PageAlloc,
+        Self::Payload,
+        Self::Mmio,
+        Self::Other,
+    ];
+
+    /// Returns a short name for dumps.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::KernelImage => "kernel",
+            Self::Dtb => "dtb",
+            Self::Initrd => "initrd",
+            Self::MemMap => "mem_map",
+            Self::Stack => "stack",
+            Self::PageTable => "pagetable",
+            Self::Heap => "heap",
+            Self::Cma => "cma",
+            Self::PerCpu => "percpu",
+            Self::EarlyAlloc => "early_alloc",
+            Self::PageAlloc => "page_alloc",
+            Self::Payload => "payload",
+            Self::Mmio => "mmio",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Preferred physical placement of a boot-time allocation.
+///
+/// Allocations made through [`Memblock::request_fixed`] land at `base`
+/// every boot unless something else got there first, so their addresses
+/// do not shift when unrelated allocations come and go. The well-known
+/// slots are listed in `mm::layout::phys`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub struct FixedSlot {
+    /// Name used in the relocation message.
+    pub name: &'static str,
+    /// Preferred physical base.
+    pub base: u64,
+    /// Alignment of the base, also used when relocating.
+    pub align: u64,
+    /// Upper bound (exclusive) for the end of a relocated region.
+    pub limit: Option<u64>,
+    /// Owner to tag the reservation with.
+    pub owner: ReservationOwner,
+}
+
+/// Why a fixed slot could not be used at its preferred base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum SlotConflict {
+    /// The preferred base is not aligned to the slot's alignment.
+    Misaligned,
+    /// The range is not entirely inside one memory region.
+    OutsideMemory,
+    /// Part of the range is already reserved.
+    Reserved,
+}
+
+impl SlotConflict {
+    /// Returns a short human-readable description of the conflict.
+    #[allow(dead_code)]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Misaligned => "misaligned",
+            Self::OutsideMemory => "not in memory",
+            Self::Reserved => "already reserved",
+        }
+    }
+}
+
+/// Errors returned by memblock operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemblockError {
+    /// The alignment is neither 0 nor a power of two.
+    BadAlign,
+    /// The range overlaps a region it may not, or the ranges of one
+    /// request overlap each other.
+    Overlap,
+    /// No free range satisfies the request.
+    InsufficientMemory,
+    /// The range to free cuts into or does not match an allocation.
+    BadFree,
+    /// A region list or the allocation table is full.
+    OutOfRegions,
+    /// The range extends past the top of the address space.
+    OutOfRange,
+    /// Zero bytes or an empty window were requested.
+    Empty,
+    /// The request is malformed, e.g. a color outside its stride.
+    BadRequest,
+}
+
+impl MemblockError {
+    /// Returns a short human-readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::BadAlign => "alignment is not a power of two",
+            Self::Overlap => "region overlaps an existing region",
+            Self::InsufficientMemory => "insufficient memory",
+            Self::BadFree => "bad free: range does not match allocation",
+            Self::OutOfRegions => "maximum number of regions reached",
+            Self::OutOfRange => "region extends past the top of the address space",
+            Self::Empty => "empty allocation request",
+            Self::BadRequest => "invalid allocation request",
+        }
+    }
+}
+
+/// What a memory region holds, as the device tree describes it.
+///
+/// Only [`Normal`](Self::Normal) memory is handed out by memblock and the
+/// page allocator; the other kinds stay listed so dumps show the whole
+/// map. Reserved regions keep using the `FLAG_*` bits instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(dead_code)]
+pub enum RegionKind {
+    /// Ordinary RAM.
+    Normal,
+    /// RAM firmware keeps for itself, from `/reserved-memory`.
+    Firmware,
+    /// RAM set aside for devices, such as a `shared-dma-pool`.
+    Device,
+}
+
+impl RegionKind {
+    /// Returns a short name for dumps.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Firmware => "firmware",
+            Self::Device => "device",
+        }
+    }
+}
+
 /// A memory region descriptor.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Region {
@@ -17,8 +236,12 @@ pub struct Region {
     pub base: u64,
     /// Size of the region in bytes.
     pub size: u64,
-    /// Region flags (reserved for future use).
+    /// Reserved region flags (`FLAG_*`).
     pub flags: u64,
+    /// Owner of a reserved region (`Other` for memory regions).
+    pub owner: ReservationOwner,
+    /// Kind of a memory region (`Normal` for reserved regions).
+    pub kind: RegionKind,
 }
 
 impl Region {
@@ -28,29 +251,132 @@ impl Region {
             base,
             size,
             flags: 0,
+            owner: ReservationOwner::Other,
+            kind: RegionKind::Normal,
         }
     }
 
+    /// Creates a new region with the given flags.
+    pub const fn with_flags(base: u64, size: u64, flags: u64) -> Self {
+        Self {
+            base,
+            size,
+            flags,
+            owner: ReservationOwner::Other,
+            kind: RegionKind::Normal,
+        }
+    }
+
+    /// Returns this region tagged with `owner`.
+    pub const fn with_owner(self, owner: ReservationOwner) -> Self {
+        Self { owner, ..self }
+    }
+
+    /// Returns this region classified as `kind`.
+    pub const fn with_kind(self, kind: RegionKind) -> Self {
+        Self { kind, ..self }
+    }
+
+    /// Checks if this region can be merged with an adjacent one.
+    ///
+    /// Regions of different kinds never merge. With `flag_aware`, flags
+    /// and owner must match as well. Two regions that together would
+    /// cover all 2^64 bytes stay apart, since the size would not fit.
+    fn mergeable(&self, other: &Region, flag_aware: bool) -> bool {
+        self.adjacent(other)
+            && self.kind == other.kind
+            && (!flag_aware || (self.flags == other.flags && self.owner == other.owner))
+            && self.size.checked_add(other.size).is_some()
+    }
+
     /// Returns the ending address (exclusive).
-    pub fn end(&self) -> u64 {
-        self.base + self.size
+    ///
+    /// A region reaching the top of the address space ends at 2^64, which
+    /// does not fit; `u64::MAX` is returned for it instead. Use
+    /// [`end_wide`](Self::end_wide) or [`last`](Self::last) where the
+    /// exact end matters.
+    pub const fn end(&self) -> u64 {
+        self.base.saturating_add(self.size)
+    }
+
+    /// Returns the exact ending address (exclusive), at most 2^64 for a
+    /// region that fits the address space.
+    pub const fn end_wide(&self) -> u128 {
+        self.base as u128 + self.size as u128
+    }
+
+    /// Returns the address of the last byte of a non-empty region.
+    #[allow(dead_code)]
+    pub const fn last(&self) -> u64 {
+        (self.end_wide() - 1) as u64
+    }
+
+    /// Returns true if the region ends at or below 2^64.
+    const fn fits(&self) -> bool {
+        self.end_wide() <= ADDRESS_SPACE_END
     }
 
     /// Checks if the region contains the given address.
     #[allow(dead_code)]
     pub fn contains(&self, addr: u64) -> bool {
-        addr >= self.base && addr < self.end()
+        addr >= self.base && (addr as u128) < self.end_wide()
     }
 
     /// Checks if this region overlaps with another.
     pub fn overlaps(&self, other: &Region) -> bool {
-        self.base < other.end() && other.base < self.end()
+        (self.base as u128) < other.end_wide() && (other.base as u128) < self.end_wide()
     }
 
     /// Checks if this region is adjacent to another (touching but not overlapping).
     #[allow(dead_code)]
     pub fn adjacent(&self, other: &Region) -> bool {
-        self.end() == other.base || other.end() == self.base
+        self.end_wide() == other.base as u128 || other.end_wide() == self.base as u128
+    }
+
+    /// Iterates over the base address of every page lying wholly inside
+    /// the region, in ascending order.
+    ///
+    /// An unaligned base is rounded up and an unaligned end rounded down,
+    /// so partial pages at either edge are skipped and a region smaller
+    /// than a page yields nothing. Divide by `page_size` for page frame
+    /// numbers.
+    ///
+    /// # Arguments
+    /// * `page_size` - Page size in bytes, a power of two
+    pub fn page_frames(&self, page_size: u64) -> impl Iterator<Item = u64> + use<> {
+        debug_assert!(page_size.is_power_of_two());
+        let page = page_size as u128;
+        let first = (self.base as u128).next_multiple_of(page);
+        let end = self.end_wide() / page * page;
+        (first..end)
+            .step_by(page_size as usize)
+            .map(|addr| addr as u64)
+    }
+}
+
+/// Returns the size of `[base, end)`, a range within the address space.
+const fn span(base: u64, end: u128) -> u64 {
+    (end - base as u128) as u64
+}
+
+impl Ord for Region {
+    /// Orders by base, then size.
+    ///
+    /// Flags, owner and kind only break ties, so the order agrees with
+    /// `==`.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.base
+            .cmp(&other.base)
+            .then(self.size.cmp(&other.size))
+            .then(self.flags.cmp(&other.flags))
+            .then((self.owner as usize).cmp(&(other.owner as usize)))
+            .then(self.kind.cmp(&other.kind))
+    }
+}
+
+impl PartialOrd for Region {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
@@ -60,469 +386,4285 @@ impl fmt::Display for Region {
             f,
             "[{:#018x} - {:#018x}) ({:#x} bytes)",
             self.base,
-            self.end(),
+            self.end_wide(),
             self.size
         )
     }
 }
 
-/// The boot-time memory allocator.
-#[derive(Debug)]
-pub struct Memblock {
-    /// Available memory regions.
-    memory_regions: [Region; MAX_REGIONS],
-    /// Number of valid entries in `memory_regions`.
-    memory_count: usize,
+/// Compact one-line formatting for a list of regions.
+///
+/// Prints `[base-end)` ranges separated by spaces, or `(no regions)`.
+pub struct RegionList<'a>(pub &'a [Region]);
 
-    /// Reserved memory regions.
-    reserved_regions: [Region; MAX_REGIONS],
-    /// Number of valid entries in `reserved_regions`.
-    reserved_count: usize,
+impl fmt::Display for RegionList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "(no regions)");
+        }
+        for (i, region) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "[{:#x}-{:#x})", region.base, region.end_wide())?;
+        }
+        Ok(())
+    }
 }
 
-impl Memblock {
-    /// Creates a new empty memblock allocator.
-    #[allow(dead_code)]
+impl fmt::Debug for RegionList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// A list of at most `N` regions in a fixed array.
+///
+/// Holds the insert, remove and merge logic shared by the memblock region
+/// lists. The list is only kept sorted by callers sticking to
+/// [`push_sorted`](Self::push_sorted) and [`cut`](Self::cut).
+#[derive(Clone, Copy)]
+pub struct RegionVec<const N: usize> {
+    regions: [Region; N],
+    /// Number of valid entries at the front of `regions`.
+    len: usize,
+}
+
+impl<const N: usize> RegionVec<N> {
+    /// Creates an empty list.
     pub const fn new() -> Self {
         Self {
-            memory_regions: [Region::new(0, 0); MAX_REGIONS],
-            memory_count: 0,
-            reserved_regions: [Region::new(0, 0); MAX_REGIONS],
-            reserved_count: 0,
+            regions: [Region::new(0, 0); N],
+            len: 0,
         }
     }
 
-    /// Adds a new memory region to the available pool.
-    ///
-    /// The region may be merged with existing adjacent regions.
+    /// Creates a list holding `regions`, which must fit.
+    #[cfg(test)]
+    fn from_slice(regions: &[Region]) -> Self {
+        let mut list = Self::new();
+        list.regions[..regions.len()].copy_from_slice(regions);
+        list.len = regions.len();
+        list
+    }
+
+    /// Returns the number of regions.
     #[allow(dead_code)]
-    pub fn add(&mut self, base: u64, size: u64) -> Result<(), &'static str> {
-        if size == 0 {
-            return Ok(());
-        }
+    pub fn len(&self) -> usize {
+        self.len
+    }
 
-        let new_region = Region::new(base, size);
+    /// Returns true if the list holds no regions.
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 
-        // Check for overlap with existing memory regions
-        for i in 0..self.memory_count {
-            if self.memory_regions[i].overlaps(&new_region) {
-                return Err("region overlaps with existing memory region");
-            }
-        }
+    /// Returns true if another insert would fail.
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
 
-        // Find insertion position (sorted by base address)
-        let mut insert_pos = self.memory_count;
-        for i in 0..self.memory_count {
-            if self.memory_regions[i].base > new_region.base {
-                insert_pos = i;
-                break;
-            }
-        }
+    /// Returns the valid regions.
+    pub fn as_slice(&self) -> &[Region] {
+        &self.regions[..self.len]
+    }
 
-        // Shift regions to make space
-        if self.memory_count >= MAX_REGIONS {
-            return Err("maximum number of memory regions reached");
-        }
+    /// Iterates over the valid regions.
+    pub fn iter(&self) -> core::slice::Iter<'_, Region> {
+        self.as_slice().iter()
+    }
 
-        for i in (insert_pos..self.memory_count).rev() {
-            self.memory_regions[i + 1] = self.memory_regions[i];
-        }
-        self.memory_regions[insert_pos] = new_region;
-        self.memory_count += 1;
+    /// Returns the sum of the region sizes.
+    pub fn total_size(&self) -> u64 {
+        self.iter().map(|r| r.size).sum()
+    }
 
-        // Merge adjacent regions
-        self.merge_memory_regions();
+    /// Appends `region`.
+    ///
+    /// # Returns
+    /// `Err(region)` if the list is full
+    pub fn push(&mut self, region: Region) -> Result<(), Region> {
+        self.insert(self.len, region)
+    }
 
+    /// Inserts `region` at `index`, shifting later entries up.
+    ///
+    /// # Returns
+    /// `Err(region)` if the list is full
+    pub fn insert(&mut self, index: usize, region: Region) -> Result<(), Region> {
+        assert!(index <= self.len, "insert index out of bounds");
+        if self.is_full() {
+            return Err(region);
+        }
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = region;
+        self.len += 1;
         Ok(())
     }
 
-    /// Reserves a region of memory (marks it as unavailable for allocation).
-    #[allow(dead_code)]
-    pub fn reserve(&mut self, base: u64, size: u64) -> Result<(), &'static str> {
-        if size == 0 {
-            return Ok(());
-        }
+    /// Inserts `region` where it keeps the list sorted, after any entries
+    /// that compare equal.
+    ///
+    /// # Returns
+    /// The index it went to, or `Err(region)` if the list is full
+    pub fn push_sorted(&mut self, region: Region) -> Result<usize, Region> {
+        let index = self.as_slice().partition_point(|r| r <= &region);
+        self.insert(index, region).map(|()| index)
+    }
 
-        let new_reserved = Region::new(base, size);
+    /// Removes and returns the entry at `index`, shifting later ones down.
+    #[allow(dead_code)]
+    pub fn remove_at(&mut self, index: usize) -> Region {
+        let region = self.as_slice()[index];
+        self.remove_range(index..index + 1);
+        region
+    }
 
-        // Check for overlap with existing reserved regions
-        for i in 0..self.reserved_count {
-            if self.reserved_regions[i].overlaps(&new_reserved) {
-                return Err("region overlaps with existing reserved region");
-            }
-        }
+    /// Removes the entries in `range`, shifting later ones down.
+    pub fn remove_range(&mut self, range: core::ops::Range<usize>) {
+        let removed = self.as_slice()[range.clone()].len();
+        self.regions.copy_within(range.end..self.len, range.start);
+        self.len -= removed;
+    }
 
-        // Find insertion position
-        let mut insert_pos = self.reserved_count;
-        for i in 0..self.reserved_count {
-            if self.reserved_regions[i].base > new_reserved.base {
-                insert_pos = i;
-                break;
+    /// Keeps only the regions for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(&Region) -> bool) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            if keep(&self.regions[i]) {
+                self.regions[kept] = self.regions[i];
+                kept += 1;
             }
         }
+        self.len = kept;
+    }
 
-        if self.reserved_count >= MAX_REGIONS {
-            return Err("maximum number of reserved regions reached");
+    /// Merges each run of adjacent regions into one.
+    ///
+    /// With `flag_aware`, only regions with the same flags and owner merge.
+    /// The list must be sorted.
+    ///
+    /// # Returns
+    /// The number of entries eliminated
+    pub fn merge_adjacent(&mut self, flag_aware: bool) -> usize {
+        if self.len <= 1 {
+            return 0;
         }
 
-        for i in (insert_pos..self.reserved_count).rev() {
-            self.reserved_regions[i + 1] = self.reserved_regions[i];
+        let before = self.len;
+        let mut merged = 1;
+        for i in 1..self.len {
+            let current = self.regions[i];
+            let last = &mut self.regions[merged - 1];
+            if last.mergeable(&current, flag_aware) {
+                last.size += current.size;
+            } else {
+                self.regions[merged] = current;
+                merged += 1;
+            }
         }
-        self.reserved_regions[insert_pos] = new_reserved;
-        self.reserved_count += 1;
-
-        // Merge adjacent reserved regions
-        self.merge_reserved_regions();
-
-        Ok(())
+        self.len = merged;
+        before - merged
     }
 
-    /// Removes a region from the available memory pool.
+    /// Cuts `range` out of every region, keeping what sticks out on either
+    /// side with its flags and owner.
     ///
-    /// This is used when memory becomes unavailable (e.g., device memory).
-    #[allow(dead_code)]
-    pub fn remove(&mut self, base: u64, size: u64) -> Result<(), &'static str> {
-        if size == 0 {
-            return Ok(());
-        }
-
-        let remove_region = Region::new(base, size);
-        let mut new_memory = [Region::new(0, 0); MAX_REGIONS];
-        let mut new_count = 0;
-
-        for i in 0..self.memory_count {
-            let region = self.memory_regions[i];
+    /// Nothing changes if the pieces do not fit.
+    ///
+    /// # Returns
+    /// The number of bytes removed, or `Err(region)` with the first piece
+    /// that did not fit
+    pub fn cut(&mut self, range: &Region) -> Result<u64, Region> {
+        let before = self.total_size();
+        let mut pieces = Self::new();
 
-            if !region.overlaps(&remove_region) {
-                // No overlap, keep region as is
-                new_memory[new_count] = region;
-                new_count += 1;
+        for region in self.iter() {
+            if !region.overlaps(range) {
+                pieces.push(*region)?;
                 continue;
             }
-
-            // Region overlaps with removal area
-            if remove_region.contains(region.base) && remove_region.contains(region.end() - 1) {
-                // Entire region is removed
-                continue;
-            } else if remove_region.contains(region.base) {
-                // Overlap at the beginning
-                let new_base = remove_region.end();
-                let new_size = region.end() - new_base;
-                if new_size > 0 {
-                    new_memory[new_count] = Region::new(new_base, new_size);
-                    new_count += 1;
-                }
-            } else if remove_region.contains(region.end() - 1) {
-                // Overlap at the end
-                let new_size = remove_region.base - region.base;
-                if new_size > 0 {
-                    new_memory[new_count] = Region::new(region.base, new_size);
-                    new_count += 1;
-                }
-            } else {
-                // Removal area is in the middle
-                let left_size = remove_region.base - region.base;
-                let right_base = remove_region.end();
-                let right_size = region.end() - right_base;
-
-                if left_size > 0 {
-                    new_memory[new_count] = Region::new(region.base, left_size);
-                    new_count += 1;
-                }
-                if right_size > 0 {
-                    new_memory[new_count] = Region::new(right_base, right_size);
-                    new_count += 1;
-                }
+            if region.base < range.base {
+                pieces.push(Region {
+                    size: range.base - region.base,
+                    ..*region
+                })?;
+            }
+            // The range then ends below 2^64, so its end() is exact
+            if region.end_wide() > range.end_wide() {
+                pieces.push(Region {
+                    base: range.end(),
+                    size: span(range.end(), region.end_wide()),
+                    ..*region
+                })?;
             }
         }
 
-        self.memory_regions = new_memory;
-        self.memory_count = new_count;
-
-        Ok(())
+        *self = pieces;
+        Ok(before - self.total_size())
     }
+}
 
-    /// Allocates a contiguous region of physical memory.
-    ///
-    /// Returns the base address of the allocated region, or an error if no
-    /// suitable region could be found.
-    #[allow(dead_code)]
-    pub fn alloc(&mut self, size: u64, align: u64) -> Result<u64, &'static str> {
-        if size == 0 {
-            return Err("cannot allocate zero-sized region");
-        }
+/// What a call to [`Memblock::remove`] took away.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RemoveReport {
+    /// Bytes removed from memory regions.
+    pub memory_removed: u64,
+    /// Bytes clipped from reservations overlapping the range.
+    pub reserved_clipped: u64,
+}
 
-        let align = align.max(1);
+/// Memory totals with reservations broken down per owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemblockStats {
+    /// Total size of available memory.
+    pub total_memory: u64,
+    /// Total size of all reservations.
+    pub total_reserved: u64,
+    /// Bytes of memory not covered by reservations.
+    pub free_memory: u64,
+    /// Reserved bytes, indexed by `ReservationOwner as usize`.
+    pub reserved_by_owner: [u64; ReservationOwner::COUNT],
+    /// Padding left beside allocations so far, see [`Allocation`].
+    pub pad_bytes: u64,
+}
 
-        // Find first fit in memory regions
-        for i in 0..self.memory_count {
-            let region = self.memory_regions[i];
-            let mut aligned_base = (region.base + align - 1) & !(align - 1);
-
-            while aligned_base + size <= region.end() {
-                // Check if this candidate overlaps with any reserved region
-                let candidate = Region::new(aligned_base, size);
-                let mut overlaps = false;
-                for j in 0..self.reserved_count {
-                    if self.reserved_regions[j].overlaps(&candidate) {
-                        overlaps = true;
-                        break;
-                    }
-                }
+impl MemblockStats {
+    /// Returns the bytes of memory not covered by reservations.
+    #[allow(dead_code)]
+    pub fn free(&self) -> u64 {
+        self.free_memory
+    }
 
-                if !overlaps {
-                    // Reserve this region
-                    self.reserve(aligned_base, size)?;
-                    return Ok(aligned_base);
-                }
+    /// Returns the bytes reserved on behalf of `owner`.
+    pub fn reserved(&self, owner: ReservationOwner) -> u64 {
+        self.reserved_by_owner[owner as usize]
+    }
+}
 
-                // Try next aligned address
-                aligned_base = (aligned_base + align) & !(align - 1);
-                if aligned_base == 0 {
-                    // Overflow, break
-                    break;
-                }
-            }
-        }
+/// Memblock totals readable without the lock, see [`summary_unlocked`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemblockSummary {
+    /// Total size of available memory.
+    pub total_memory: u64,
+    /// Total size of all reservations.
+    pub total_reserved: u64,
+    /// Number of memory regions.
+    pub memory_count: u64,
+    /// Number of reserved regions.
+    pub reserved_count: u64,
+    /// Base of the most recent allocation.
+    pub last_alloc_base: u64,
+    /// Size of the most recent allocation, 0 if there was none.
+    pub last_alloc_size: u64,
+}
 
-        Err("insufficient memory")
-    }
+impl MemblockSummary {
+    /// Number of words in the lock-free copy.
+    const WORDS: usize = 6;
 
-    /// Returns the total size of all available memory regions.
-    #[allow(dead_code)]
-    pub fn total_memory(&self) -> u64 {
-        let mut total = 0;
-        for i in 0..self.memory_count {
-            total += self.memory_regions[i].size;
-        }
-        total
+    fn to_words(self) -> [u64; Self::WORDS] {
+        [
+            self.total_memory,
+            self.total_reserved,
+            self.memory_count,
+            self.reserved_count,
+            self.last_alloc_base,
+            self.last_alloc_size,
+        ]
     }
 
-    /// Returns the total size of all reserved regions.
-    #[allow(dead_code)]
-    pub fn total_reserved(&self) -> u64 {
-        let mut total = 0;
-        for i in 0..self.reserved_count {
-            total += self.reserved_regions[i].size;
+    fn from_words(w: [u64; Self::WORDS]) -> Self {
+        Self {
+            total_memory: w[0],
+            total_reserved: w[1],
+            memory_count: w[2],
+            reserved_count: w[3],
+            last_alloc_base: w[4],
+            last_alloc_size: w[5],
         }
-        total
     }
+}
 
-    /// Dumps the current state for debugging.
-    #[allow(dead_code)]
-    pub fn dump(&self) {
-        // This function is a no-op by default.
-        // To enable debug output, implementors should provide their own
-        // output mechanism and uncomment the println! macros below.
-        /*
-        crate::println!("Memblock state:");
-        crate::println!("  Memory regions ({}):", self.memory_count);
-        for i in 0..self.memory_count {
-            crate::println!("    {}", self.memory_regions[i]);
-        }
-        crate::println!("  Reserved regions ({}):", self.reserved_count);
-        for i in 0..self.reserved_count {
-            crate::println!("    {}", self.reserved_regions[i]);
+impl fmt::Display for MemblockSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "memblock: {:#x} bytes in {} regions, {:#x} reserved in {} regions",
+            self.total_memory, self.memory_count, self.total_reserved, self.reserved_count
+        )?;
+        if self.last_alloc_size != 0 {
+            write!(
+                f,
+                ", last alloc {}",
+                Region::new(self.last_alloc_base, self.last_alloc_size)
+            )?;
         }
-        crate::println!("  Total memory: {:#x}", self.total_memory());
-        crate::println!("  Total reserved: {:#x}", self.total_reserved());
-        */
+        Ok(())
     }
+}
 
-    /// Merges adjacent memory regions.
-    #[allow(dead_code)]
-    fn merge_memory_regions(&mut self) {
-        if self.memory_count <= 1 {
-            return;
-        }
-
-        let mut merged = [Region::new(0, 0); MAX_REGIONS];
-        let mut merged_count = 1;
-        merged[0] = self.memory_regions[0];
+/// Attempts a reader makes before settling for a possibly torn copy.
+const SUMMARY_READ_RETRIES: usize = 64;
 
-        for i in 1..self.memory_count {
-            let current = self.memory_regions[i];
-            let last = &mut merged[merged_count - 1];
+/// A [`MemblockSummary`] behind a sequence lock.
+///
+/// There is only one writer, the holder of the memblock lock, so
+/// publishing never waits. Readers retry while an update is in flight.
+struct SummaryCell {
+    /// Odd while an update is in flight.
+    seq: AtomicU64,
+    words: [AtomicU64; MemblockSummary::WORDS],
+}
 
-            if last.adjacent(&current) {
-                // Merge: extend the last region
-                last.size += current.size;
-            } else {
-                merged[merged_count] = current;
-                merged_count += 1;
-            }
+impl SummaryCell {
+    const fn new() -> Self {
+        Self {
+            seq: AtomicU64::new(0),
+            words: [const { AtomicU64::new(0) }; MemblockSummary::WORDS],
         }
+    }
 
-        self.memory_regions = merged;
-        self.memory_count = merged_count;
+    /// Replace the stored summary. Callers must be serialized.
+    fn publish(&self, summary: &MemblockSummary) {
+        let seq = self.seq.load(AtomicOrdering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), AtomicOrdering::Relaxed);
+        fence(AtomicOrdering::Release);
+        for (word, value) in self.words.iter().zip(summary.to_words()) {
+            word.store(value, AtomicOrdering::Relaxed);
+        }
+        self.seq.store(seq.wrapping_add(2), AtomicOrdering::Release);
     }
 
-    /// Merges adjacent reserved regions.
-    #[allow(dead_code)]
-    fn merge_reserved_regions(&mut self) {
-        if self.reserved_count <= 1 {
-            return;
+    /// Returns the stored summary.
+    ///
+    /// A writer that stopped half way, e.g. by panicking, never finishes
+    /// its update; after a bounded number of retries the words are
+    /// returned as found, each valid on its own.
+    fn read(&self) -> MemblockSummary {
+        let load = || {
+            self.words
+                .each_ref()
+                .map(|w| w.load(AtomicOrdering::Relaxed))
+        };
+        for _ in 0..SUMMARY_READ_RETRIES {
+            let before = self.seq.load(AtomicOrdering::Acquire);
+            if before & 1 == 0 {
+                let words = load();
+                fence(AtomicOrdering::Acquire);
+                if self.seq.load(AtomicOrdering::Relaxed) == before {
+                    return MemblockSummary::from_words(words);
+                }
+            }
+            core::hint::spin_loop();
         }
+        MemblockSummary::from_words(load())
+    }
+}
 
-        let mut merged = [Region::new(0, 0); MAX_REGIONS];
-        let mut merged_count = 1;
-        merged[0] = self.reserved_regions[0];
+/// Invariant violations within a single region list.
+enum ListError {
+    Empty,
+    Unsorted,
+    Overlap,
+}
 
-        for i in 1..self.reserved_count {
-            let current = self.reserved_regions[i];
-            let last = &mut merged[merged_count - 1];
+/// An allocation and the fragments its placement left in its free gap.
+///
+/// The pads stay free, but show how much fragmentation an alignment
+/// causes: [`MemblockStats::pad_bytes`] sums them over all allocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    /// Base of the reserved range.
+    pub base: u64,
+    /// Size of the reserved range.
+    pub size: u64,
+    /// Free bytes skipped between the start of the gap and `base` to
+    /// satisfy the alignment.
+    pub pad_before: u64,
+    /// Free bytes between the end of the allocation and the end of the
+    /// gap if there are too few for a page, 0 otherwise.
+    pub pad_after: u64,
+}
 
-            if last.adjacent(&current) {
-                last.size += current.size;
+impl Allocation {
+    /// Returns the allocation of `[base, base + size)` from `gap`.
+    fn in_gap(gap: Region, base: u64, size: u64) -> Self {
+        let tail = gap.end_wide() - (base as u128 + size as u128);
+        Self {
+            base,
+            size,
+            pad_before: base - gap.base,
+            pad_after: if tail < PAGE_SIZE as u128 {
+                tail as u64
             } else {
-                merged[merged_count] = current;
-                merged_count += 1;
-            }
+                0
+            },
         }
+    }
 
-        self.reserved_regions = merged;
-        self.reserved_count = merged_count;
+    /// Returns both pads together.
+    pub fn padding(&self) -> u64 {
+        self.pad_before + self.pad_after
     }
 }
 
-/// Global instance of the memblock allocator.
+/// How allocations choose among the free gaps that fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 #[allow(dead_code)]
-static MEMBLOCK: Mutex<Memblock> = Mutex::new(Memblock::new());
+pub enum AllocPolicy {
+    /// The lowest gap that fits.
+    #[default]
+    FirstFit,
+    /// The smallest gap that fits, the lowest of equal ones. Leaves large
+    /// gaps whole for later large requests.
+    BestFit,
+}
 
-/// Returns a lock guard for the global memblock instance.
-///
-/// This function provides safe concurrent access to the memblock allocator.
-#[allow(dead_code)]
-pub fn lock() -> spin::MutexGuard<'static, Memblock> {
-    MEMBLOCK.lock()
+/// The boot-time memory allocator.
+pub struct Memblock {
+    /// Available memory regions.
+    memory: RegionVec<MAX_REGIONS>,
+    /// Reserved memory regions.
+    reserved: RegionVec<MAX_REGIONS>,
+
+    /// Live allocations of more than one page, unsorted.
+    ///
+    /// Reservations merge, so this is what tells [`free`](Self::free)
+    /// how large an allocation was.
+    allocations: RegionVec<MAX_ALLOCATIONS>,
+
+    /// Most recent allocation, empty before the first one.
+    last_alloc: Region,
+
+    /// Gap selection for allocations without a fixed address.
+    policy: AllocPolicy,
+
+    /// Sum of [`Allocation::padding`] over all allocations.
+    pad_bytes: u64,
 }
 
-/// Initializes the memblock allocator with the given memory region.
-///
-/// This should be called early during kernel boot.
-#[allow(dead_code)]
-pub fn init(base: u64, size: u64) -> Result<(), &'static str> {
-    let mut mb = lock();
-    mb.add(base, size)
-}
+impl Memblock {
+    /// Creates a new empty memblock allocator.
+    #[allow(dead_code)]
+    pub const fn new() -> Self {
+        Self {
+            memory: RegionVec::new(),
+            reserved: RegionVec::new(),
+            allocations: RegionVec::new(),
+            last_alloc: Region::new(0, 0),
+            policy: AllocPolicy::FirstFit,
+            pad_bytes: 0,
+        }
+    }
+
+    /// Returns the allocation policy in use.
+    #[allow(dead_code)]
+    pub fn policy(&self) -> AllocPolicy {
+        self.policy
+    }
+
+    /// Selects how later allocations choose among free gaps.
+    ///
+    /// Allocations already made stay where they are.
+    #[allow(dead_code)]
+    pub fn set_policy(&mut self, policy: AllocPolicy) {
+        self.policy = policy;
+    }
+
+    /// Adds a new memory region to the available pool.
+    ///
+    /// The region may be merged with existing adjacent regions.
+    #[allow(dead_code)]
+    pub fn add(&mut self, base: u64, size: u64) -> Result<(), MemblockError> {
+        self.add_kind(base, size, RegionKind::Normal)
+    }
+
+    /// Adds a new memory region of the given kind.
+    ///
+    /// The region may be merged with existing adjacent regions of the
+    /// same kind.
+    #[allow(dead_code)]
+    pub fn add_kind(
+        &mut self,
+        base: u64,
+        size: u64,
+        kind: RegionKind,
+    ) -> Result<(), MemblockError> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let new_region = Region::new(base, size).with_kind(kind);
+        if !new_region.fits() {
+            return Err(MemblockError::OutOfRange);
+        }
+
+        // Check for overlap with existing memory regions
+        if self.memory.iter().any(|r| r.overlaps(&new_region)) {
+            return Err(MemblockError::Overlap);
+        }
+
+        // Insert sorted by base address
+        self.memory
+            .push_sorted(new_region)
+            .map_err(|_| MemblockError::OutOfRegions)?;
+
+        // Merge adjacent regions
+        self.memory.merge_adjacent(false);
+        self.check_invariants();
+
+        Ok(())
+    }
+
+    /// Reserves a region of memory (marks it as unavailable for allocation).
+    #[allow(dead_code)]
+    pub fn reserve(&mut self, base: u64, size: u64) -> Result<(), MemblockError> {
+        self.reserve_tagged(base, size, ReservationOwner::Other)
+    }
+
+    /// Reserves a region of memory on behalf of `owner`.
+    #[allow(dead_code)]
+    pub fn reserve_tagged(
+        &mut self,
+        base: u64,
+        size: u64,
+        owner: ReservationOwner,
+    ) -> Result<(), MemblockError> {
+        self.reserve_with_flags(base, size, 0, owner)
+    }
+
+    /// Reserves a region of memory tagged with `FLAG_*` flags and an owner.
+    ///
+    /// Adjacent reserved regions are only merged if their flags and owners
+    /// match.
+    #[allow(dead_code)]
+    pub fn reserve_with_flags(
+        &mut self,
+        base: u64,
+        size: u64,
+        flags: u64,
+        owner: ReservationOwner,
+    ) -> Result<(), MemblockError> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let new_reserved = Region::with_flags(base, size, flags).with_owner(owner);
+        if !new_reserved.fits() {
+            return Err(MemblockError::OutOfRange);
+        }
+
+        // Check for overlap with existing reserved regions
+        if self.reserved.iter().any(|r| r.overlaps(&new_reserved)) {
+            return Err(MemblockError::Overlap);
+        }
+
+        self.reserved
+            .push_sorted(new_reserved)
+            .map_err(|_| MemblockError::OutOfRegions)?;
+
+        // Merge adjacent reserved regions
+        self.reserved.merge_adjacent(true);
+        self.check_invariants();
+        RESERVE_COUNT.inc();
+
+        Ok(())
+    }
+
+    /// Reserves every `(base, size, flags)` range in `ranges`, or none.
+    ///
+    /// All ranges are checked against the existing reservations and each
+    /// other before anything is reserved. If one is rejected, or the list
+    /// fills up midway, the allocator is left as it was.
+    #[allow(dead_code)]
+    pub fn reserve_all(&mut self, ranges: &[(u64, u64, u64)]) -> Result<(), MemblockError> {
+        for (i, &(base, size, flags)) in ranges.iter().enumerate() {
+            if size == 0 {
+                continue;
+            }
+            let range = Region::with_flags(base, size, flags);
+            if !range.fits() {
+                return Err(MemblockError::OutOfRange);
+            }
+            if self.reserved.iter().any(|r| r.overlaps(&range)) {
+                return Err(MemblockError::Overlap);
+            }
+            if ranges[..i]
+                .iter()
+                .any(|&(b, s, _)| Region::new(b, s).overlaps(&range))
+            {
+                return Err(MemblockError::Overlap);
+            }
+        }
+
+        self.transaction(|mb| {
+            for &(base, size, flags) in ranges {
+                mb.reserve_with_flags(base, size, flags, ReservationOwner::Other)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Reserves a region, absorbing reservations it overlaps.
+    ///
+    /// Unlike `reserve`, overlapping an existing reservation is not an
+    /// error: the existing entries are replaced by one covering the union.
+    #[allow(dead_code)]
+    pub fn reserve_merge(&mut self, base: u64, size: u64) -> Result<(), MemblockError> {
+        self.reserve_merge_with_flags(base, size, 0, ReservationOwner::Other)
+    }
+
+    /// Reserves a region tagged with `flags` and `owner`, absorbing
+    /// reservations it overlaps.
+    ///
+    /// Every overlapped reservation must have the same flags and owner,
+    /// otherwise nothing changes and an error is returned.
+    #[allow(dead_code)]
+    pub fn reserve_merge_with_flags(
+        &mut self,
+        base: u64,
+        size: u64,
+        flags: u64,
+        owner: ReservationOwner,
+    ) -> Result<(), MemblockError> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let range = Region::with_flags(base, size, flags).with_owner(owner);
+        if !range.fits() {
+            return Err(MemblockError::OutOfRange);
+        }
+        let mut union = range;
+        let mut first = 0;
+        let mut count = 0;
+        for (i, reserved) in self.reserved_regions().iter().enumerate() {
+            if !reserved.overlaps(&range) {
+                continue;
+            }
+            if reserved.flags != flags || reserved.owner != owner {
+                return Err(MemblockError::Overlap);
+            }
+            if count == 0 {
+                first = i;
+            }
+            count += 1;
+
+            let end = union.end_wide().max(reserved.end_wide());
+            union.base = union.base.min(reserved.base);
+            union.size =
+                u64::try_from(end - union.base as u128).map_err(|_| MemblockError::OutOfRange)?;
+        }
+
+        // Overlapped entries are consecutive since the list is sorted
+        self.reserved.remove_range(first..first + count);
+
+        self.reserve_with_flags(union.base, union.size, flags, owner)
+    }
+
+    /// Removes a region from the available memory pool.
+    ///
+    /// This is used when memory becomes unavailable (e.g., device memory).
+    /// Reservations overlapping the range are clipped to match, so they
+    /// never describe memory memblock no longer knows about.
+    ///
+    /// Fails and changes nothing if splitting a region around the range
+    /// needs more slots than either list has left.
+    #[allow(dead_code)]
+    pub fn remove(&mut self, base: u64, size: u64) -> Result<RemoveReport, MemblockError> {
+        if size == 0 {
+            return Ok(RemoveReport::default());
+        }
+
+        if !Region::new(base, size).fits() {
+            return Err(MemblockError::OutOfRange);
+        }
+        let range = Region::new(base, size);
+        // Split memory on a copy first, so running out of slots in either
+        // list leaves both untouched
+        let mut memory = self.memory;
+        let memory_removed = memory
+            .cut(&range)
+            .map_err(|_| MemblockError::OutOfRegions)?;
+        let reserved_clipped = self.clip_reserved(range)?;
+        self.memory = memory;
+        self.check_invariants();
+
+        Ok(RemoveReport {
+            memory_removed,
+            reserved_clipped,
+        })
+    }
+
+    /// Drops the parts of reservations that lie outside all memory regions.
+    ///
+    /// A cleanup pass for when the memory map is replaced after
+    /// reservations were made, e.g. by device tree discovery. Regions
+    /// flagged [`FLAG_MMIO`] are outside memory by design and are kept.
+    ///
+    /// # Returns
+    /// The number of reserved bytes dropped
+    #[allow(dead_code)]
+    pub fn remove_reserved_outside_memory(&mut self) -> Result<u64, MemblockError> {
+        let before = self.total_reserved();
+        let mut new_reserved = RegionVec::new();
+
+        for region in self.reserved_regions() {
+            if region.flags & FLAG_MMIO != 0 {
+                new_reserved
+                    .push(*region)
+                    .map_err(|_| MemblockError::OutOfRegions)?;
+                continue;
+            }
+            for memory in self.memory_regions() {
+                let base = region.base.max(memory.base);
+                let end = region.end_wide().min(memory.end_wide());
+                if base as u128 >= end {
+                    continue;
+                }
+                let piece = Region {
+                    base,
+                    size: span(base, end),
+                    ..*region
+                };
+                new_reserved
+                    .push(piece)
+                    .map_err(|_| MemblockError::OutOfRegions)?;
+            }
+        }
+
+        self.reserved = new_reserved;
+        self.check_invariants();
+
+        Ok(before - self.total_reserved())
+    }
+
+    /// Releases a reserved range so it can be allocated again.
+    ///
+    /// Reservations partially covered by the range are trimmed and keep
+    /// their flags and owner. A range touching a multi-page allocation
+    /// must be exactly that allocation; freeing part of one, or one with
+    /// the wrong size, is a bad free and changes nothing.
+    #[allow(dead_code)]
+    pub fn free(&mut self, base: u64, size: u64) -> Result<(), MemblockError> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let range = Region::new(base, size);
+        if !range.fits() {
+            return Err(MemblockError::OutOfRange);
+        }
+        let bad = self
+            .allocations()
+            .iter()
+            .any(|a| a.overlaps(&range) && (a.base, a.size) != (base, size));
+        if bad {
+            return Err(MemblockError::BadFree);
+        }
+
+        self.clip_reserved(range)?;
+        self.check_invariants();
+
+        Ok(())
+    }
+
+    /// Cuts `range` out of all reservations, keeping their flags and owner.
+    ///
+    /// Allocations the range touches are forgotten.
+    ///
+    /// # Returns
+    /// The number of reserved bytes removed
+    fn clip_reserved(&mut self, range: Region) -> Result<u64, MemblockError> {
+        let clipped = self
+            .reserved
+            .cut(&range)
+            .map_err(|_| MemblockError::OutOfRegions)?;
+        self.allocations.retain(|a| !a.overlaps(&range));
+        Ok(clipped)
+    }
+
+    /// Reserve `[base, base + size)` for `owner` as an allocation.
+    ///
+    /// Allocations of more than a page are remembered so they can only be
+    /// freed whole.
+    fn commit_alloc(
+        &mut self,
+        base: u64,
+        size: u64,
+        owner: ReservationOwner,
+    ) -> Result<u64, MemblockError> {
+        let tracked = size > PAGE_SIZE;
+        if tracked && self.allocations.is_full() {
+            return Err(MemblockError::OutOfRegions);
+        }
+        self.reserve_tagged(base, size, owner)?;
+        if tracked {
+            let _ = self
+                .allocations
+                .push(Region::new(base, size).with_owner(owner));
+        }
+        self.last_alloc = Region::new(base, size);
+        ALLOC_COUNT.inc();
+        Ok(base)
+    }
+
+    /// Returns the live multi-page allocations, in no particular order.
+    #[allow(dead_code)]
+    pub fn allocations(&self) -> &[Region] {
+        self.allocations.as_slice()
+    }
+
+    /// Allocates a contiguous region of physical memory.
+    ///
+    /// Returns the base address of the allocated region, or an error if no
+    /// suitable region could be found.
+    ///
+    /// Like every allocation method, `align` must be a power of two or 0,
+    /// which means no alignment; anything else is rejected rather than
+    /// rounded, since a caller asking for it has a bug.
+    #[allow(dead_code)]
+    pub fn alloc(&mut self, size: u64, align: u64) -> Result<u64, MemblockError> {
+        self.alloc_tagged(size, align, ReservationOwner::EarlyAlloc)
+    }
+
+    /// Allocates a contiguous region of physical memory for `owner`.
+    #[allow(dead_code)]
+    pub fn alloc_tagged(
+        &mut self,
+        size: u64,
+        align: u64,
+        owner: ReservationOwner,
+    ) -> Result<u64, MemblockError> {
+        self.allocate(size, align, owner).map(|a| a.base)
+    }
+
+    /// Like [`alloc_tagged`](Self::alloc_tagged), also reporting the
+    /// padding the alignment left in the gap.
+    #[allow(dead_code)]
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        align: u64,
+        owner: ReservationOwner,
+    ) -> Result<Allocation, MemblockError> {
+        self.alloc_matching(size, align, owner, 0, ADDRESS_SPACE_END, |_| true)
+    }
+
+    /// Allocates a region for `owner` that lies entirely in `[start, end)`.
+    ///
+    /// # Arguments
+    /// * `size` - Size of the region in bytes
+    /// * `align` - Required alignment of the base
+    /// * `start` - Lowest acceptable base
+    /// * `end` - Upper bound (exclusive) for the end of the region
+    /// * `owner` - Owner to tag the reservation with
+    #[allow(dead_code)]
+    pub fn alloc_range(
+        &mut self,
+        size: u64,
+        align: u64,
+        start: u64,
+        end: u64,
+        owner: ReservationOwner,
+    ) -> Result<u64, MemblockError> {
+        self.allocate_range(size, align, start, end, owner)
+            .map(|a| a.base)
+    }
+
+    /// Like [`alloc_range`](Self::alloc_range), also reporting the padding
+    /// the alignment left in the gap.
+    ///
+    /// The gap is clipped to `[start, end)`, so bytes below `start` are
+    /// never padding of this allocation.
+    #[allow(dead_code)]
+    pub fn allocate_range(
+        &mut self,
+        size: u64,
+        align: u64,
+        start: u64,
+        end: u64,
+        owner: ReservationOwner,
+    ) -> Result<Allocation, MemblockError> {
+        if start >= end {
+            return Err(MemblockError::Empty);
+        }
+        self.alloc_matching(size, align, owner, start, end as u128, |_| true)
+    }
+
+    /// Allocates a region whose base satisfies `base % stride == color`.
+    ///
+    /// Picking different colors for pages that are used together keeps
+    /// them from aliasing in the same cache sets.
+    ///
+    /// # Arguments
+    /// * `size` - Size of the region in bytes
+    /// * `align` - Required alignment of the base
+    /// * `stride` - Color period, e.g. the cache way size
+    /// * `color` - Required offset of the base within `stride`
+    #[allow(dead_code)]
+    pub fn alloc_colored(
+        &mut self,
+        size: u64,
+        align: u64,
+        stride: u64,
+        color: u64,
+    ) -> Result<u64, MemblockError> {
+        if stride == 0 || color >= stride {
+            return Err(MemblockError::BadRequest);
+        }
+        let align = check_align(align)?;
+
+        // Aligned bases only reach colors that are multiples of
+        // gcd(align, stride); don't scan all of memory for the rest
+        let (mut a, mut b) = (align, stride);
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        if !color.is_multiple_of(a) {
+            return Err(MemblockError::InsufficientMemory);
+        }
+
+        self.alloc_matching(
+            size,
+            align,
+            ReservationOwner::EarlyAlloc,
+            0,
+            ADDRESS_SPACE_END,
+            |base| base % stride == color,
+        )
+        .map(|a| a.base)
+    }
+
+    /// Allocates exactly `[base, base + size)`, for structures that must
+    /// live at a fixed physical address.
+    ///
+    /// Unlike `reserve`, the range must lie within one memory region and
+    /// be entirely free.
+    ///
+    /// # Returns
+    /// `base`, [`MemblockError::InsufficientMemory`] if the range is not
+    /// all RAM, or [`MemblockError::Overlap`] if part of it is already
+    /// reserved
+    #[allow(dead_code)]
+    pub fn alloc_at(&mut self, base: u64, size: u64) -> Result<u64, MemblockError> {
+        if size == 0 {
+            return Err(MemblockError::Empty);
+        }
+        if !self.is_normal_memory(base, size) {
+            return Err(MemblockError::InsufficientMemory);
+        }
+
+        self.commit_alloc(base, size, ReservationOwner::EarlyAlloc)
+    }
+
+    /// Returns why `size` bytes at `slot`'s preferred base cannot be
+    /// allocated, or `Ok` if they can.
+    #[allow(dead_code)]
+    pub fn check_fixed(&self, slot: &FixedSlot, size: u64) -> Result<(), SlotConflict> {
+        if slot.align > 1 && !slot.base.is_multiple_of(slot.align) {
+            return Err(SlotConflict::Misaligned);
+        }
+        if !self.is_normal_memory(slot.base, size) {
+            return Err(SlotConflict::OutsideMemory);
+        }
+        if self.reserved_in_range(slot.base, size).next().is_some() {
+            return Err(SlotConflict::Reserved);
+        }
+        Ok(())
+    }
+
+    /// Allocates `size` bytes at `slot`'s preferred base, or anywhere
+    /// suitable if that is not free.
+    ///
+    /// A relocation is reported to `log` as
+    /// `fixed slot <name> relocated from <base> to <actual> (<reason>)`.
+    ///
+    /// # Arguments
+    /// * `slot` - Preferred placement and owner
+    /// * `size` - Size of the region in bytes
+    /// * `log` - Where to report a relocation
+    ///
+    /// # Returns
+    /// The base of the allocation
+    #[allow(dead_code)]
+    pub fn request_fixed(
+        &mut self,
+        slot: &FixedSlot,
+        size: u64,
+        log: &mut dyn fmt::Write,
+    ) -> Result<u64, MemblockError> {
+        if size == 0 {
+            return Err(MemblockError::Empty);
+        }
+        let conflict = match self.check_fixed(slot, size) {
+            Ok(()) => return self.commit_alloc(slot.base, size, slot.owner),
+            Err(conflict) => conflict,
+        };
+
+        let end = slot.limit.map_or(ADDRESS_SPACE_END, u128::from);
+        let base = self
+            .alloc_matching(size, slot.align, slot.owner, 0, end, |_| true)?
+            .base;
+        let _ = writeln!(
+            log,
+            "fixed slot {} relocated from {:#x} to {:#x} ({})",
+            slot.name,
+            slot.base,
+            base,
+            conflict.as_str()
+        );
+        Ok(base)
+    }
+
+    /// Allocates a free aligned region inside `[start, end)` whose base
+    /// passes `accept`, from the gap the [`AllocPolicy`] picks.
+    fn alloc_matching(
+        &mut self,
+        size: u64,
+        align: u64,
+        owner: ReservationOwner,
+        start: u64,
+        end: u128,
+        accept: impl Fn(u64) -> bool,
+    ) -> Result<Allocation, MemblockError> {
+        if size == 0 {
+            return Err(MemblockError::Empty);
+        }
+        let align = check_align(align)?;
+
+        let (gap, base) = self
+            .find_free(size, align, start, end, accept)
+            .ok_or(MemblockError::InsufficientMemory)?;
+        self.commit_alloc(base, size, owner)?;
+        let allocation = Allocation::in_gap(gap, base, size);
+        self.pad_bytes += allocation.padding();
+        Ok(allocation)
+    }
+
+    /// Returns the aligned base of a free `size` byte range inside
+    /// `[start, end)` whose base passes `accept`, and the gap it is in,
+    /// clipped to the range. The [`AllocPolicy`] picks the gap.
+    ///
+    /// Only the free gaps are visited, so the cost is O(memory regions ×
+    /// reserved regions) however small `align` is.
+    fn find_free(
+        &self,
+        size: u64,
+        align: u64,
+        start: u64,
+        end: u128,
+        accept: impl Fn(u64) -> bool,
+    ) -> Option<(Region, u64)> {
+        let mut gaps = self.free_between(start as u128, end);
+        let fit = |gap: Region| {
+            Self::fit_in_gap(gap.base as u128, gap.end_wide(), size, align, &accept)
+                .map(|base| (gap, base))
+        };
+        match self.policy {
+            AllocPolicy::FirstFit => gaps.find_map(fit),
+            AllocPolicy::BestFit => {
+                // Gap size as u128, a gap may end at 2^64; `min_by_key`
+                // keeps the first of equal gaps
+                gaps.filter_map(fit)
+                    .min_by_key(|(gap, _)| gap.end_wide() - gap.base as u128)
+            }
+        }
+    }
+
+    /// Returns the lowest aligned base in the free range `[start, end)` that
+    /// fits `size` bytes and passes `accept`.
+    ///
+    /// Bounds are `u128` so a gap may end at 2^64.
+    fn fit_in_gap(
+        start: u128,
+        end: u128,
+        size: u64,
+        align: u64,
+        accept: &impl Fn(u64) -> bool,
+    ) -> Option<u64> {
+        let mut base = start.next_multiple_of(align as u128);
+        while base + size as u128 <= end {
+            // Below `end`, which is at most 2^64
+            let addr = base as u64;
+            if accept(addr) {
+                return Some(addr);
+            }
+            base += align as u128;
+        }
+        None
+    }
+
+    /// Allocates up to `count` separate pages in one forward scan.
+    ///
+    /// Page addresses are written to the front of `out` in ascending order.
+    /// Returns the number of pages actually allocated, which is less than
+    /// `count` if memory runs out part way.
+    #[allow(dead_code)]
+    pub fn alloc_pages_into(
+        &mut self,
+        count: usize,
+        out: &mut [u64],
+    ) -> Result<usize, MemblockError> {
+        if count > out.len() {
+            return Err(MemblockError::BadRequest);
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+
+        // Collect candidates first, the free-range walk borrows the arrays
+        let mut found = 0;
+        self.for_each_free(|free| {
+            for page in free.page_frames(PAGE_SIZE).take(count - found) {
+                out[found] = page;
+                found += 1;
+            }
+        });
+
+        // Contiguous pages merge into the previous reservation as we go
+        for (i, &page) in out[..found].iter().enumerate() {
+            if let Err(e) = self.reserve_tagged(page, PAGE_SIZE, ReservationOwner::EarlyAlloc) {
+                if i == 0 {
+                    return Err(e);
+                }
+                self.last_alloc = Region::new(out[i - 1], PAGE_SIZE);
+                ALLOC_COUNT.add(i as u64);
+                return Ok(i);
+            }
+        }
+
+        if found == 0 {
+            return Err(MemblockError::InsufficientMemory);
+        }
+        self.last_alloc = Region::new(out[found - 1], PAGE_SIZE);
+        ALLOC_COUNT.add(found as u64);
+        Ok(found)
+    }
+
+    /// Returns the total size of all available memory regions.
+    #[allow(dead_code)]
+    pub fn total_memory(&self) -> u64 {
+        self.memory.total_size()
+    }
+
+    /// Returns true if `addr` lies in a memory region, rather than in a
+    /// hole between banks or in device space.
+    ///
+    /// See [`is_memory`](Self::is_memory) for ranges.
+    #[allow(dead_code)]
+    pub fn memory_present(&self, addr: u64) -> bool {
+        self.memory_regions().iter().any(|m| m.contains(addr))
+    }
+
+    /// Returns true if `[base, base + size)` lies within one memory region.
+    #[allow(dead_code)]
+    pub fn is_memory(&self, base: u64, size: u64) -> bool {
+        let end = Region::new(base, size).end_wide();
+        self.memory_regions()
+            .iter()
+            .any(|m| m.base <= base && end <= m.end_wide())
+    }
+
+    /// Returns true if `[base, base + size)` lies within one
+    /// [`RegionKind::Normal`] memory region, so it may be allocated.
+    #[allow(dead_code)]
+    pub fn is_normal_memory(&self, base: u64, size: u64) -> bool {
+        let end = Region::new(base, size).end_wide();
+        self.normal_memory_iter()
+            .any(|m| m.base <= base && end <= m.end_wide())
+    }
+
+    /// Returns true if `[base, base + size)` overlaps a reservation of `owner`.
+    #[allow(dead_code)]
+    pub fn overlaps_owner(&self, base: u64, size: u64, owner: ReservationOwner) -> bool {
+        self.reserved_in_range(base, size).any(|r| r.owner == owner)
+    }
+
+    /// Returns true if `[base, base + size)` overlaps a reservation with any
+    /// of `flags` set.
+    #[allow(dead_code)]
+    pub fn overlaps_flags(&self, base: u64, size: u64, flags: u64) -> bool {
+        self.reserved_in_range(base, size)
+            .any(|r| r.flags & flags != 0)
+    }
+
+    /// Returns the total size reserved on behalf of `owner`.
+    #[allow(dead_code)]
+    pub fn reserved_by(&self, owner: ReservationOwner) -> u64 {
+        self.reserved_regions()
+            .iter()
+            .filter(|r| r.owner == owner)
+            .map(|r| r.size)
+            .sum()
+    }
+
+    /// Returns a summary of memory and per-owner reservation totals.
+    pub fn stats(&self) -> MemblockStats {
+        let mut stats = MemblockStats {
+            total_memory: self.total_memory(),
+            total_reserved: 0,
+            free_memory: 0,
+            reserved_by_owner: [0; ReservationOwner::COUNT],
+            pad_bytes: self.pad_bytes,
+        };
+        self.for_each_free(|free| stats.free_memory += free.size);
+        for region in self.reserved_regions() {
+            stats.total_reserved += region.size;
+            stats.reserved_by_owner[region.owner as usize] += region.size;
+        }
+        stats
+    }
+
+    /// Returns the totals kept for lock-free readers, see
+    /// [`summary_unlocked`].
+    pub fn summary(&self) -> MemblockSummary {
+        MemblockSummary {
+            total_memory: self.total_memory(),
+            total_reserved: self.total_reserved(),
+            memory_count: self.memory.len() as u64,
+            reserved_count: self.reserved.len() as u64,
+            last_alloc_base: self.last_alloc.base,
+            last_alloc_size: self.last_alloc.size,
+        }
+    }
+
+    /// Returns the valid available memory regions, of every kind.
+    pub fn memory_regions(&self) -> &[Region] {
+        self.memory.as_slice()
+    }
+
+    /// Iterates over the memory regions that may be allocated from, those
+    /// of [`RegionKind::Normal`].
+    pub fn normal_memory_iter(&self) -> impl Iterator<Item = &Region> + '_ {
+        self.memory.iter().filter(|m| m.kind == RegionKind::Normal)
+    }
+
+    /// Reclassifies the memory inside `[base, base + size)` as `kind`.
+    ///
+    /// Memory regions are split at the range edges and merged again with
+    /// neighbours of the same kind. Parts of the range that are not memory
+    /// are ignored. Nothing changes if the pieces do not fit.
+    ///
+    /// # Returns
+    /// The number of bytes reclassified
+    #[allow(dead_code)]
+    pub fn set_kind(
+        &mut self,
+        base: u64,
+        size: u64,
+        kind: RegionKind,
+    ) -> Result<u64, MemblockError> {
+        let range = Region::new(base, size);
+        if !range.fits() {
+            return Err(MemblockError::OutOfRange);
+        }
+
+        let mut memory = self.memory;
+        let moved = memory
+            .cut(&range)
+            .map_err(|_| MemblockError::OutOfRegions)?;
+        for m in self.memory.iter().filter(|m| m.overlaps(&range)) {
+            let lo = m.base.max(base);
+            let hi = m.end_wide().min(range.end_wide());
+            memory
+                .push_sorted(Region::new(lo, span(lo, hi)).with_kind(kind))
+                .map_err(|_| MemblockError::OutOfRegions)?;
+        }
+        memory.merge_adjacent(false);
+
+        self.memory = memory;
+        self.check_invariants();
+        Ok(moved)
+    }
+
+    /// Returns the valid reserved regions.
+    pub fn reserved_regions(&self) -> &[Region] {
+        self.reserved.as_slice()
+    }
+
+    /// Returns the total size of all reserved regions.
+    #[allow(dead_code)]
+    pub fn total_reserved(&self) -> u64 {
+        self.reserved.total_size()
+    }
+
+    /// Re-runs a full merge pass over both region arrays.
+    ///
+    /// Adjacent regions with matching flags and owners are merged. Returns the number
+    /// of entries eliminated.
+    #[allow(dead_code)]
+    pub fn coalesce(&mut self) -> usize {
+        let merged = self.memory.merge_adjacent(false) + self.reserved.merge_adjacent(true);
+        self.check_invariants();
+        merged
+    }
+
+    /// Checks the structural invariants of both region arrays.
+    ///
+    /// Memory and reserved regions must each be sorted by base address,
+    /// non-empty and non-overlapping. A reserved range that overlaps
+    /// another is exactly the state in which the allocator could hand it
+    /// out twice.
+    ///
+    /// # Returns
+    /// `Ok(())` if the arrays are consistent, or a description of the
+    /// first violation found.
+    #[allow(dead_code)]
+    pub fn validate(&self) -> Result<(), &'static str> {
+        Self::validate_list(self.memory_regions()).map_err(|e| match e {
+            ListError::Empty => "empty memory region",
+            ListError::Unsorted => "memory regions not sorted",
+            ListError::Overlap => "memory regions overlap",
+        })?;
+        Self::validate_list(self.reserved_regions()).map_err(|e| match e {
+            ListError::Empty => "empty reserved region",
+            ListError::Unsorted => "reserved regions not sorted",
+            ListError::Overlap => "reserved regions overlap",
+        })
+    }
+
+    /// Like [`validate`](Self::validate), but additionally requires every
+    /// reservation other than [`FLAG_MMIO`] windows to lie entirely within
+    /// a single memory region.
+    ///
+    /// This is not part of the default checks: boot code legitimately
+    /// reserves ranges (such as stack guard pages) that memblock does not
+    /// track as memory.
+    #[allow(dead_code)]
+    pub fn validate_strict(&self) -> Result<(), &'static str> {
+        self.validate()?;
+        for reserved in self.reserved_regions() {
+            if reserved.flags & FLAG_MMIO != 0 {
+                continue;
+            }
+            let contained = self
+                .memory_regions()
+                .iter()
+                .any(|m| m.base <= reserved.base && reserved.end_wide() <= m.end_wide());
+            if !contained {
+                return Err("reservation outside memory");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks one sorted region list.
+    fn validate_list(regions: &[Region]) -> Result<(), ListError> {
+        for (i, region) in regions.iter().enumerate() {
+            if region.size == 0 {
+                return Err(ListError::Empty);
+            }
+            if i == 0 {
+                continue;
+            }
+            let prev = &regions[i - 1];
+            if region.base < prev.base {
+                return Err(ListError::Unsorted);
+            }
+            if prev.overlaps(region) {
+                return Err(ListError::Overlap);
+            }
+        }
+        Ok(())
+    }
+
+    /// Panics if the region arrays are inconsistent.
+    ///
+    /// Only active with `debug_assertions`; release builds skip the scan.
+    fn check_invariants(&self) {
+        if cfg!(debug_assertions)
+            && let Err(e) = self.validate()
+        {
+            panic!("memblock invariant violated: {}", e);
+        }
+    }
+
+    /// Overwrites both region arrays without any checking.
+    ///
+    /// Lets tests build states that the public API cannot produce.
+    #[cfg(test)]
+    fn set_regions_unchecked(&mut self, memory: &[Region], reserved: &[Region]) {
+        self.memory = RegionVec::from_slice(memory);
+        self.reserved = RegionVec::from_slice(reserved);
+    }
+
+    /// Builds a memblock from `(base, size)` memory and reserved ranges.
+    ///
+    /// Goes through [`add`](Self::add) and [`reserve`](Self::reserve), so
+    /// the ranges may come in any order and the result is sorted and
+    /// merged like any other state. Panics if a range is rejected.
+    #[cfg(test)]
+    pub fn from_regions(memory: &[(u64, u64)], reserved: &[(u64, u64)]) -> Self {
+        let mut mb = Self::new();
+        for &(base, size) in memory {
+            mb.add(base, size).unwrap();
+        }
+        for &(base, size) in reserved {
+            mb.reserve(base, size).unwrap();
+        }
+        mb
+    }
+
+    /// Panics unless the memory and reserved lists are exactly the given
+    /// `(base, size)` ranges, in order.
+    #[cfg(test)]
+    #[track_caller]
+    pub fn assert_regions(&self, memory: &[(u64, u64)], reserved: &[(u64, u64)]) {
+        let spans = |regions: &[Region]| -> Vec<(u64, u64)> {
+            regions.iter().map(|r| (r.base, r.size)).collect()
+        };
+        assert_eq!(spans(self.memory_regions()), memory, "memory regions");
+        assert_eq!(spans(self.reserved_regions()), reserved, "reserved regions");
+    }
+
+    /// Calls `f` for every free (available and unreserved) range.
+    #[allow(dead_code)]
+    pub fn for_each_free(&self, f: impl FnMut(Region)) {
+        self.free_between(0, ADDRESS_SPACE_END).for_each(f);
+    }
+
+    /// Iterates over the reservations overlapping `[base, base + size)`,
+    /// clipped to it, in ascending order.
+    ///
+    /// Clipped pieces keep the flags and owner of their reservation.
+    #[allow(dead_code)]
+    pub fn reserved_in_range(&self, base: u64, size: u64) -> impl Iterator<Item = Region> + '_ {
+        let start = base as u128;
+        let end = start + size as u128;
+        self.reserved_regions().iter().filter_map(move |reserved| {
+            let lo = start.max(reserved.base as u128);
+            let hi = end.min(reserved.end_wide());
+            (lo < hi).then(|| Region {
+                base: lo as u64,
+                size: span(lo as u64, hi),
+                ..*reserved
+            })
+        })
+    }
+
+    /// Iterates over the free (available and unreserved) gaps inside
+    /// `[base, base + size)`, clipped to it, in ascending order.
+    #[allow(dead_code)]
+    pub fn free_in_range(&self, base: u64, size: u64) -> impl Iterator<Item = Region> + '_ {
+        let start = base as u128;
+        self.free_between(start, start + size as u128)
+    }
+
+    /// Iterates over the free gaps inside `[start, end)`.
+    ///
+    /// Relies on both region lists being sorted.
+    fn free_between(&self, start: u128, end: u128) -> impl Iterator<Item = Region> + '_ {
+        self.normal_memory_iter().flat_map(move |memory| {
+            let hi = end.min(memory.end_wide());
+            let mut cursor = start.max(memory.base as u128);
+            let mut reserved = self.reserved_regions().iter();
+            core::iter::from_fn(move || {
+                while cursor < hi {
+                    let (gap_end, resume) = match reserved.next() {
+                        Some(r) if r.end_wide() <= cursor => continue,
+                        Some(r) if (r.base as u128) < hi => (r.base as u128, r.end_wide()),
+                        _ => (hi, hi),
+                    };
+                    let gap_start = cursor;
+                    cursor = resume;
+                    if gap_start < gap_end {
+                        return Some(Region::new(
+                            gap_start as u64,
+                            span(gap_start as u64, gap_end),
+                        ));
+                    }
+                }
+                None
+            })
+        })
+    }
+
+    /// Returns how fragmented free memory is, as a percentage.
+    ///
+    /// This is the share of free memory lying outside the largest free
+    /// range: 0 means all free memory is one contiguous range, values near
+    /// 100 mean it is scattered over many small gaps. Returns 0 if there is
+    /// no free memory.
+    #[allow(dead_code)]
+    pub fn fragmentation_ratio(&self) -> u64 {
+        let mut total = 0;
+        let mut largest = 0;
+        self.for_each_free(|free| {
+            total += free.size;
+            largest = largest.max(free.size);
+        });
+
+        if total == 0 {
+            return 0;
+        }
+        (total - largest) * 100 / total
+    }
+
+    /// Returns the largest free (available and unreserved) range.
+    ///
+    /// Ties go to the lowest address. Returns `None` if no memory is free.
+    #[allow(dead_code)]
+    pub fn largest_free_block(&self) -> Option<Region> {
+        let mut largest: Option<Region> = None;
+        self.for_each_free(|free| {
+            if largest.is_none_or(|l| free.size > l.size) {
+                largest = Some(free);
+            }
+        });
+        largest
+    }
+
+    /// Capture the region lists for a later [`Memblock::restore`].
+    ///
+    /// The lists are fixed arrays, so the snapshot needs no allocation.
+    #[allow(dead_code)]
+    pub fn snapshot(&self) -> MemblockSnapshot {
+        MemblockSnapshot {
+            memory: self.memory,
+            reserved: self.reserved,
+            allocations: self.allocations,
+            last_alloc: self.last_alloc,
+            pad_bytes: self.pad_bytes,
+        }
+    }
+
+    /// Roll back to the state captured by [`Memblock::snapshot`].
+    ///
+    /// Every region added, reserved, allocated or freed since the snapshot
+    /// is forgotten. [`Memblock::stats`] is derived from the lists, so it
+    /// matches the snapshot again as well.
+    #[allow(dead_code)]
+    pub fn restore(&mut self, snap: &MemblockSnapshot) {
+        self.memory = snap.memory;
+        self.reserved = snap.reserved;
+        self.allocations = snap.allocations;
+        self.last_alloc = snap.last_alloc;
+        self.pad_bytes = snap.pad_bytes;
+    }
+
+    /// Run `f` as a transaction: keep its changes if it succeeds, roll
+    /// them back if it fails.
+    ///
+    /// Transactions nest; an inner failure only undoes the inner changes.
+    ///
+    /// # Arguments
+    /// * `f` - Boot step to run against this allocator
+    #[allow(dead_code)]
+    pub fn transaction<T, E>(
+        &mut self,
+        f: impl FnOnce(&mut Memblock) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let snap = self.snapshot();
+        let result = f(self);
+        if result.is_err() {
+            self.restore(&snap);
+        }
+        result
+    }
+
+    /// Dumps the current state for debugging.
+    #[allow(dead_code)]
+    pub fn dump(&self) {
+        // This function is a no-op by default.
+        // To enable debug output, implementors should provide their own
+        // output mechanism and uncomment the println! macros below.
+        /*
+        crate::println!("Memblock state:");
+        crate::println!("  Memory regions ({}):", self.memory.len());
+        for region in self.memory.iter() {
+            crate::println!("    {}", region);
+        }
+        crate::println!("  Reserved regions ({}):", self.reserved.len());
+        for region in self.reserved.iter() {
+            crate::println!("    {}", region);
+        }
+        crate::println!("  Total memory: {:#x}", self.total_memory());
+        crate::println!("  Total reserved: {:#x}", self.total_reserved());
+        */
+    }
+}
+
+/// Returns the alignment to use for an allocation requesting `align`.
+///
+/// 0 means no alignment and becomes 1; other values must be powers of two.
+fn check_align(align: u64) -> Result<u64, MemblockError> {
+    match align {
+        0 => Ok(1),
+        a if a.is_power_of_two() => Ok(a),
+        _ => Err(MemblockError::BadAlign),
+    }
+}
+
+/// Saved region lists of a [`Memblock`], see [`Memblock::snapshot`].
+#[derive(Clone)]
+pub struct MemblockSnapshot {
+    memory: RegionVec<MAX_REGIONS>,
+    reserved: RegionVec<MAX_REGIONS>,
+    allocations: RegionVec<MAX_ALLOCATIONS>,
+    last_alloc: Region,
+    pad_bytes: u64,
+}
+
+impl MemblockSnapshot {
+    /// Returns the saved memory regions.
+    #[allow(dead_code)]
+    pub fn memory_regions(&self) -> &[Region] {
+        self.memory.as_slice()
+    }
+
+    /// Returns the saved reserved regions.
+    #[allow(dead_code)]
+    pub fn reserved_regions(&self) -> &[Region] {
+        self.reserved.as_slice()
+    }
+}
+
+/// Returns true if `a` and `b` look like the same logical reservation
+/// in two snapshots: same owner and overlapping ranges.
+fn same_reservation(a: &Region, b: &Region) -> bool {
+    a.owner == b.owner && a.overlaps(b)
+}
+
+/// Reservations of two snapshots grouped by [`same_reservation`].
+///
+/// Index `i` below `before.len()` is `before[i]`, the rest are `after`.
+/// Regions linked directly or through others share a group.
+struct ReservationGroups<'a> {
+    before: &'a [Region],
+    after: &'a [Region],
+    parent: [usize; 2 * MAX_REGIONS],
+}
+
+impl<'a> ReservationGroups<'a> {
+    fn new(before: &'a [Region], after: &'a [Region]) -> Self {
+        let mut groups = Self {
+            before,
+            after,
+            parent: core::array::from_fn(|i| i),
+        };
+        for (i, old) in before.iter().enumerate() {
+            for (j, new) in after.iter().enumerate() {
+                if same_reservation(old, new) {
+                    groups.union(i, before.len() + j);
+                }
+            }
+        }
+        groups
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        // The lower index stays the root, so roots come in address order
+        self.parent[a.max(b)] = a.min(b);
+    }
+
+    fn region(&self, i: usize) -> &'a Region {
+        match i.checked_sub(self.before.len()) {
+            None => &self.before[i],
+            Some(j) => &self.after[j],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.before.len() + self.after.len()
+    }
+
+    /// Returns the lowest base address in the group rooted at `root`.
+    fn group_base(&mut self, root: usize) -> u64 {
+        let mut base = u64::MAX;
+        for i in 0..self.len() {
+            if self.find(i) == root {
+                base = base.min(self.region(i).base);
+            }
+        }
+        base
+    }
+}
+
+/// Write `[base-end)` for every region in `regions`, space separated.
+fn write_ranges<'a>(
+    out: &mut dyn fmt::Write,
+    regions: impl Iterator<Item = &'a Region>,
+) -> fmt::Result {
+    for (i, region) in regions.enumerate() {
+        if i > 0 {
+            write!(out, " ")?;
+        }
+        write!(out, "[{:#x}-{:#x})", region.base, region.end_wide())?;
+    }
+    Ok(())
+}
+
+/// Write ` owner` for tagged regions, nothing for untagged ones.
+fn write_owner(out: &mut dyn fmt::Write, owner: ReservationOwner) -> fmt::Result {
+    match owner {
+        ReservationOwner::Other => Ok(()),
+        owner => write!(out, " {}", owner.as_str()),
+    }
+}
+
+/// Write a byte count with its sign.
+fn write_signed(out: &mut dyn fmt::Write, before: u64, after: u64) -> fmt::Result {
+    if after >= before {
+        write!(out, "+{:#x}", after - before)
+    } else {
+        write!(out, "-{:#x}", before - after)
+    }
+}
+
+/// Print how the reservations changed between two snapshots.
+///
+/// Reservations with the same owner and overlapping ranges are taken to
+/// be one logical reservation, so a region that grew, shrank or was split
+/// in two shows up as one `~` line; anything else is added (`+`) or
+/// removed (`-`). Unchanged reservations are left out. The last line has
+/// the net change in reserved bytes.
+///
+/// # Arguments
+/// * `before` - Earlier snapshot
+/// * `after` - Later snapshot
+/// * `out` - Sink for the report
+#[allow(dead_code)]
+pub fn diff(
+    before: &MemblockSnapshot,
+    after: &MemblockSnapshot,
+    out: &mut dyn fmt::Write,
+) -> fmt::Result {
+    let (old, new) = (before.reserved_regions(), after.reserved_regions());
+    let mut groups = ReservationGroups::new(old, new);
+
+    // Group roots by lowest address; a root is its group's lowest index
+    let mut roots = [(0u64, 0usize); 2 * MAX_REGIONS];
+    let mut count = 0;
+    for i in 0..groups.len() {
+        if groups.find(i) == i {
+            roots[count] = (groups.group_base(i), i);
+            count += 1;
+        }
+    }
+    let roots = &mut roots[..count];
+    roots.sort_unstable();
+
+    let mut changes = 0;
+    for &(_, root) in roots.iter() {
+        // Each side of a group fits its snapshot's list
+        let mut old_list = RegionVec::<MAX_REGIONS>::new();
+        let mut new_list = RegionVec::<MAX_REGIONS>::new();
+        for i in 0..groups.len() {
+            if groups.find(i) == root {
+                let list = if i < old.len() {
+                    &mut old_list
+                } else {
+                    &mut new_list
+                };
+                let _ = list.push(*groups.region(i));
+            }
+        }
+        let owner = groups.region(root).owner;
+
+        let unchanged = matches!((old_list.as_slice(), new_list.as_slice()), ([a], [b])
+            if a.base == b.base && a.size == b.size && a.flags == b.flags);
+        if unchanged {
+            continue;
+        }
+        changes += 1;
+
+        let (old_size, new_size) = (old_list.total_size(), new_list.total_size());
+        if old_list.is_empty() {
+            write!(out, "  + ")?;
+            write_ranges(out, new_list.iter())?;
+            write!(out, " {:#x}", new_size)?;
+        } else if new_list.is_empty() {
+            write!(out, "  - ")?;
+            write_ranges(out, old_list.iter())?;
+            write!(out, " {:#x}", old_size)?;
+        } else {
+            write!(out, "  ~ ")?;
+            write_ranges(out, old_list.iter())?;
+            write!(out, " -> ")?;
+            write_ranges(out, new_list.iter())?;
+            write!(out, " ")?;
+            write_signed(out, old_size, new_size)?;
+        }
+        write_owner(out, owner)?;
+        writeln!(out)?;
+    }
+    if changes == 0 {
+        writeln!(out, "  (no changes)")?;
+    }
+
+    let old_total: u64 = old.iter().map(|r| r.size).sum();
+    let new_total: u64 = new.iter().map(|r| r.size).sum();
+    write!(out, "  net ")?;
+    write_signed(out, old_total, new_total)?;
+    writeln!(out, " reserved ({:#x} -> {:#x})", old_total, new_total)
+}
+
+/// Write one indexed region list section of the `Memblock` summary.
+///
+/// With `{:#}` each region is followed by its share of `total`.
+fn fmt_region_section(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    regions: &[Region],
+    total: u64,
+    show_owner: bool,
+) -> fmt::Result {
+    writeln!(f, "  {} ({}):", name, regions.len())?;
+    if regions.is_empty() {
+        return writeln!(f, "    (no regions)");
+    }
+
+    for (i, region) in regions.iter().enumerate() {
+        write!(f, "    [{:3}] {}", i, region)?;
+        if show_owner {
+            write!(f, " {}", region.owner.as_str())?;
+        } else if region.kind != RegionKind::Normal {
+            write!(f, " {}", region.kind.as_str())?;
+        }
+        if f.alternate() && total != 0 {
+            // Percentage in hundredths, rounded to nearest
+            let hundredths = (region.size as u128 * 10000 + total as u128 / 2) / total as u128;
+            write!(f, " {:3}.{:02}%", hundredths / 100, hundredths % 100)?;
+        }
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Memblock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.stats();
+        let total = stats.total_memory;
+
+        writeln!(f, "Memblock:")?;
+        fmt_region_section(f, "memory", self.memory_regions(), total, false)?;
+        fmt_region_section(f, "reserved", self.reserved_regions(), total, true)?;
+        writeln!(f, "  total memory:   {:#x}", total)?;
+        write!(f, "  total reserved: {:#x}", stats.total_reserved)?;
+        for owner in ReservationOwner::ALL {
+            let size = stats.reserved(owner);
+            if size != 0 {
+                write!(f, "\n    {:<12} {:#x}", owner.as_str(), size)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Memblock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Memblock")
+            .field("memory", &RegionList(self.memory_regions()))
+            .field("reserved", &RegionList(self.reserved_regions()))
+            .finish()
+    }
+}
+
+/// Global instance of the memblock allocator.
+#[allow(dead_code)]
+#[cfg(target_os = "none")]
+static MEMBLOCK: IrqSafeMutex<Memblock> = IrqSafeMutex::new("memblock", Memblock::new());
+
+/// Global instance of the memblock allocator.
+///
+/// Host builds have no interrupts to mask, so a plain lock will do.
+#[allow(dead_code)]
+#[cfg(not(target_os = "none"))]
+static MEMBLOCK: Mutex<Memblock> = Mutex::new(Memblock::new());
+
+/// Lock-free copy of the global instance's totals.
+static SUMMARY: SummaryCell = SummaryCell::new();
+
+#[cfg(target_os = "none")]
+type RawGuard = IrqSafeMutexGuard<'static, Memblock>;
+
+#[cfg(not(target_os = "none"))]
+type RawGuard = spin::MutexGuard<'static, Memblock>;
+
+/// Lock guard for the global memblock instance.
+///
+/// If the instance was borrowed mutably, dropping the guard publishes
+/// its [`MemblockSummary`] and charges the change in reserved bytes per
+/// owner to [`accounting`](super::accounting) before releasing the lock.
+pub struct MemblockGuard {
+    guard: RawGuard,
+    /// Bytes reserved per owner when first borrowed mutably, `None` until
+    /// then.
+    before: Option<[u64; ReservationOwner::COUNT]>,
+}
+
+impl MemblockGuard {
+    fn new(guard: RawGuard) -> Self {
+        Self {
+            guard,
+            before: None,
+        }
+    }
+}
+
+impl core::ops::Deref for MemblockGuard {
+    type Target = Memblock;
+
+    fn deref(&self) -> &Memblock {
+        &self.guard
+    }
+}
+
+impl core::ops::DerefMut for MemblockGuard {
+    fn deref_mut(&mut self) -> &mut Memblock {
+        if self.before.is_none() {
+            self.before = Some(self.guard.stats().reserved_by_owner);
+        }
+        &mut self.guard
+    }
+}
+
+impl Drop for MemblockGuard {
+    fn drop(&mut self) {
+        // Still holding the lock, so this is the only writer
+        if let Some(before) = &self.before {
+            SUMMARY.publish(&self.guard.summary());
+            super::accounting::charge_owner_delta(before, &self.guard.stats().reserved_by_owner);
+        }
+    }
+}
+
+/// Returns a lock guard for the global memblock instance.
+///
+/// This function provides safe concurrent access to the memblock allocator.
+#[allow(dead_code)]
+pub fn lock() -> MemblockGuard {
+    MemblockGuard::new(MEMBLOCK.lock())
+}
+
+/// Memblock lock guard that also keeps IRQs masked, see [`lock_irqsafe`].
+#[cfg(any(target_os = "none", test))]
+pub struct IrqSafeMemblockGuard {
+    /// Dropped first, so the lock is free before IRQs are unmasked.
+    guard: MemblockGuard,
+    _irq: Option<IrqGuard>,
+}
+
+#[cfg(any(target_os = "none", test))]
+impl core::ops::Deref for IrqSafeMemblockGuard {
+    type Target = Memblock;
+
+    fn deref(&self) -> &Memblock {
+        &self.guard
+    }
+}
+
+#[cfg(any(target_os = "none", test))]
+impl core::ops::DerefMut for IrqSafeMemblockGuard {
+    fn deref_mut(&mut self) -> &mut Memblock {
+        &mut self.guard
+    }
+}
+
+/// Locks the global memblock instance for a caller that may run with
+/// IRQs enabled.
+///
+/// Once [`irq::runtime_phase`] is set, IRQs stay masked until the guard
+/// is dropped, so an IRQ handler allocating on this CPU cannot deadlock
+/// on the lock. During boot nothing can interrupt the holder and IRQs
+/// are left alone.
+#[cfg(any(target_os = "none", test))]
+#[allow(dead_code)]
+pub fn lock_irqsafe() -> IrqSafeMemblockGuard {
+    lock_in_phase(irq::runtime_phase())
+}
+
+/// [`lock_irqsafe`] with the phase passed in.
+#[cfg(any(target_os = "none", test))]
+fn lock_in_phase(runtime: bool) -> IrqSafeMemblockGuard {
+    // Masked before spinning, so no IRQ arrives while we hold the lock
+    let irq = runtime.then(IrqGuard::new);
+    IrqSafeMemblockGuard {
+        guard: lock(),
+        _irq: irq,
+    }
+}
+
+/// Tries to lock the global memblock instance without spinning.
+///
+/// Intended for panic and failure paths, where the lock may already be
+/// held by the code that failed.
+#[allow(dead_code)]
+pub fn try_lock() -> Option<MemblockGuard> {
+    MEMBLOCK.try_lock().map(MemblockGuard::new)
+}
+
+/// Returns the totals of the global instance as of the last change made
+/// through [`lock`], without touching the lock.
+///
+/// For panic and fault paths, where the lock may be held by the code that
+/// failed. Changes made under a lock that is still held are not visible.
+#[allow(dead_code)]
+pub fn summary_unlocked() -> MemblockSummary {
+    SUMMARY.read()
+}
+
+/// Dump the global instance to `w` without spinning on the lock.
+///
+/// Falls back to [`summary_unlocked`] if the lock is held.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn try_dump_to(w: &mut dyn fmt::Write) -> fmt::Result {
+    write_dump(w, try_lock().as_deref())
+}
+
+/// Write `mb` in full, or the lock-free summary if it is `None`.
+fn write_dump(w: &mut dyn fmt::Write, mb: Option<&Memblock>) -> fmt::Result {
+    match mb {
+        Some(mb) => writeln!(w, "{}", mb),
+        None => writeln!(w, "{} (lock held, summary only)", summary_unlocked()),
+    }
+}
+
+/// Run `f` against the global instance as a transaction.
+///
+/// On failure the region lists and the `memblock.*` counters are rolled
+/// back, so a half-finished boot step leaves no trace in later dumps. The
+/// lock is held throughout; nested transactions go through
+/// [`Memblock::transaction`] on the reference `f` receives.
+///
+/// # Arguments
+/// * `f` - Boot step to run, e.g. carving out several pools at once
+#[allow(dead_code)]
+pub fn transaction<T, E>(f: impl FnOnce(&mut Memblock) -> Result<T, E>) -> Result<T, E> {
+    let mut mb = lock();
+    let counts = (ALLOC_COUNT.get(), RESERVE_COUNT.get());
+    let result = mb.transaction(f);
+    if result.is_err() {
+        ALLOC_COUNT.set(counts.0);
+        RESERVE_COUNT.set(counts.1);
+    }
+    result
+}
+
+/// Initializes the memblock allocator with the given memory region.
+///
+/// This should be called early during kernel boot.
+#[allow(dead_code)]
+pub fn init(base: u64, size: u64) -> Result<(), &'static str> {
+    stats::register("memblock.alloc_count", &ALLOC_COUNT)?;
+    stats::register("memblock.reserve_count", &RESERVE_COUNT)?;
+    #[cfg(feature = "mm_debug_poison")]
+    stats::register("mm.poison_violations", &super::poison::VIOLATIONS)?;
+
+    let mut mb = lock();
+    mb.add(base, size).map_err(|e| e.as_str())
+}
+
+/// Reserves a region of memory.
+#[allow(dead_code)]
+pub fn reserve(base: u64, size: u64) -> Result<(), MemblockError> {
+    let mut mb = lock();
+    mb.reserve(base, size)
+}
+
+/// Reserves every `(base, size, flags)` range in `ranges`, or none.
+#[allow(dead_code)]
+pub fn reserve_all(ranges: &[(u64, u64, u64)]) -> Result<(), MemblockError> {
+    let mut mb = lock();
+    mb.reserve_all(ranges)
+}
+
+/// Reserves a region of memory, absorbing reservations it overlaps.
+#[allow(dead_code)]
+pub fn reserve_merge(base: u64, size: u64) -> Result<(), MemblockError> {
+    let mut mb = lock();
+    mb.reserve_merge(base, size)
+}
+
+/// Reserves a region of memory on behalf of `owner`.
+#[allow(dead_code)]
+pub fn reserve_tagged(base: u64, size: u64, owner: ReservationOwner) -> Result<(), MemblockError> {
+    let mut mb = lock();
+    mb.reserve_tagged(base, size, owner)
+}
+
+/// Reserves a region of memory tagged with `FLAG_*` flags and an owner.
+#[allow(dead_code)]
+pub fn reserve_with_flags(
+    base: u64,
+    size: u64,
+    flags: u64,
+    owner: ReservationOwner,
+) -> Result<(), MemblockError> {
+    let mut mb = lock();
+    mb.reserve_with_flags(base, size, flags, owner)
+}
+
+/// Returns true if `addr` lies in a memory region of the global instance.
+#[allow(dead_code)]
+pub fn memory_present(addr: u64) -> bool {
+    lock().memory_present(addr)
+}
+
+/// Allocates a contiguous region of physical memory.
+#[allow(dead_code)]
+pub fn alloc(size: u64, align: u64) -> Result<u64, MemblockError> {
+    let mut mb = lock();
+    mb.alloc(size, align)
+}
+
+/// Releases a reserved range so it can be allocated again.
+///
+/// With `mm_debug_poison`, freed RAM is filled with the poison pattern
+/// before anyone else can allocate it.
+#[allow(dead_code)]
+pub fn free(base: u64, size: u64) -> Result<(), MemblockError> {
+    let mut mb = lock();
+
+    // Only RAM reachable through the linear map can be written
+    #[cfg(all(target_os = "none", feature = "mm_debug_poison"))]
+    let poison = mb.is_memory(base, size) && !mb.overlaps_flags(base, size, FLAG_NOMAP);
+
+    mb.free(base, size)?;
+
+    #[cfg(all(target_os = "none", feature = "mm_debug_poison"))]
+    if poison {
+        super::poison::poison_range(base, size);
+    }
+
+    Ok(())
+}
+
+/// Allocates a contiguous region of physical memory and zeroes it.
+///
+/// With `mm_debug_poison`, the region is first checked for writes made
+/// while it was free.
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn alloc_zeroed(size: u64, align: u64) -> Result<u64, MemblockError> {
+    let base = alloc(size, align)?;
+
+    #[cfg(feature = "mm_debug_poison")]
+    super::poison::verify_range(base, size);
+
+    let virt = crate::arch::address::translation::phys_to_virt(base);
+    // Safety: the range was just allocated from RAM covered by the linear map
+    unsafe { core::ptr::write_bytes(virt as *mut u8, 0, size as usize) };
+
+    Ok(base)
+}
+
+/// Allocates up to `count` separate pages into `out`.
+///
+/// Returns the number of pages actually allocated.
+#[allow(dead_code)]
+pub fn alloc_n(count: usize, out: &mut [u64]) -> Result<usize, MemblockError> {
+    let mut mb = lock();
+    mb.alloc_pages_into(count, out)
+}
+
+/// Allocates a contiguous region of physical memory for `owner`.
+#[allow(dead_code)]
+pub fn alloc_tagged(size: u64, align: u64, owner: ReservationOwner) -> Result<u64, MemblockError> {
+    let mut mb = lock();
+    mb.alloc_tagged(size, align, owner)
+}
+
+/// Allocates `size` bytes at `slot`'s preferred base if it is free, or
+/// elsewhere with a relocation message on the console.
+#[cfg(any(target_os = "none", test))]
+#[allow(dead_code)]
+pub fn request_fixed(slot: &FixedSlot, size: u64) -> Result<u64, MemblockError> {
+    let mut mb = lock();
+    mb.request_fixed(slot, size, &mut crate::arch::serial::Writer)
+}
+
+/// Allocates exactly `[base, base + size)` if it is free RAM.
+#[allow(dead_code)]
+pub fn alloc_at(base: u64, size: u64) -> Result<u64, MemblockError> {
+    let mut mb = lock();
+    mb.alloc_at(base, size)
+}
+
+/// Allocates a region whose base satisfies `base % stride == color`.
+#[allow(dead_code)]
+pub fn alloc_colored(size: u64, align: u64, stride: u64, color: u64) -> Result<u64, MemblockError> {
+    let mut mb = lock();
+    mb.alloc_colored(size, align, stride, color)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_page_frames() {
+        let frames: Vec<_> = Region::new(0x4000_0000, 0x3000)
+            .page_frames(0x1000)
+            .collect();
+        assert_eq!(frames, [0x4000_0000, 0x4000_1000, 0x4000_2000]);
+
+        // Partial pages at either edge are skipped
+        let frames: Vec<_> = Region::new(0x800, 0x2000).page_frames(0x1000).collect();
+        assert_eq!(frames, [0x1000]);
+
+        // Sub-page regions: nothing unless the page is whole
+        assert_eq!(Region::new(0x1000, 0x800).page_frames(0x1000).count(), 0);
+        assert_eq!(Region::new(0x1800, 0x800).page_frames(0x1000).count(), 0);
+        assert_eq!(Region::new(0x1000, 0).page_frames(0x1000).count(), 0);
+        assert_eq!(Region::new(0x1000, 0x1000).page_frames(0x1000).count(), 1);
+
+        // The last page of the address space is included
+        let top = Region::new(u64::MAX - 0x1fff, 0x2000);
+        assert_eq!(top.page_frames(0x1000).last(), Some(u64::MAX - 0xfff));
+        assert_eq!(top.page_frames(0x1000).count(), 2);
+    }
+
+    #[test]
+    fn test_region_contains() {
+        let region = Region::new(0x1000, 0x1000);
+        assert!(region.contains(0x1000));
+        assert!(region.contains(0x1fff));
+        assert!(!region.contains(0x2000));
+        assert!(!region.contains(0xfff));
+    }
+
+    #[test]
+    fn test_region_overlaps() {
+        let r1 = Region::new(0x1000, 0x1000);
+        let r2 = Region::new(0x1800, 0x1000);
+        let r3 = Region::new(0x2000, 0x1000);
+        assert!(r1.overlaps(&r2));
+        assert!(r2.overlaps(&r1));
+        assert!(!r1.overlaps(&r3));
+        assert!(!r3.overlaps(&r1));
+    }
+
+    #[test]
+    fn test_region_adjacent() {
+        let r1 = Region::new(0x1000, 0x1000);
+        let r2 = Region::new(0x3000, 0x1000);
+        let r3 = Region::new(0x1800, 0x1000);
+        assert!(!r1.adjacent(&r2)); // not touching
+        assert!(!r1.adjacent(&r3)); // overlapping
+        let r4 = Region::new(0x2000, 0x1000);
+        assert!(r1.adjacent(&r4)); // r1 ends at 0x2000, r4 starts at 0x2000
+    }
+
+    #[test]
+    fn test_region_ordering() {
+        let a = Region::new(0x1000, 0x1000);
+        let b = Region::new(0x1000, 0x2000);
+        let c = Region::new(0x2000, 0x10);
+        assert!(a < b && b < c);
+        assert_eq!(a.cmp(&a), Ordering::Equal);
+
+        // Base wins over size
+        assert!(Region::new(0x1000, 0x10_0000) < Region::new(0x2000, 0x1));
+
+        // Flags and owner break ties consistently with ==
+        let flagged = Region::with_flags(0x1000, 0x1000, FLAG_NOMAP);
+        assert_ne!(a, flagged);
+        assert_ne!(a.cmp(&flagged), Ordering::Equal);
+        let owned = a.with_owner(ReservationOwner::Dtb);
+        assert_ne!(a.cmp(&owned), Ordering::Equal);
+
+        let mut regions = [c, b, flagged, a];
+        regions.sort();
+        assert_eq!(regions, [a, flagged, b, c]);
+    }
+
+    #[test]
+    fn test_region_vec_insert_remove() {
+        let mut list = RegionVec::<3>::new();
+        assert!(list.is_empty());
+
+        let a = Region::new(0x1000, 0x1000);
+        let b = Region::new(0x3000, 0x1000);
+        let c = Region::new(0x5000, 0x1000);
+        assert_eq!(list.push_sorted(c), Ok(0));
+        assert_eq!(list.push_sorted(a), Ok(0));
+        assert_eq!(list.insert(1, b), Ok(()));
+        assert_eq!(list.as_slice(), [a, b, c]);
+        assert!(list.is_full());
+        assert_eq!(list.push(a), Err(a));
+        assert_eq!(list.total_size(), 0x3000);
+
+        assert_eq!(list.remove_at(1), b);
+        assert_eq!(list.iter().copied().collect::<Vec<_>>(), [a, c]);
+        list.remove_range(0..2);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_region_vec_merge_adjacent() {
+        let nomap = Region::with_flags(0x2000, 0x1000, FLAG_NOMAP);
+        let regions = [
+            Region::new(0x1000, 0x1000),
+            nomap,
+            Region::new(0x3000, 0x1000),
+            Region::new(0x5000, 0x1000),
+        ];
+
+        // Flags only keep regions apart when asked to
+        let mut list = RegionVec::<4>::from_slice(&regions);
+        assert_eq!(list.merge_adjacent(false), 2);
+        assert_eq!(
+            list.as_slice(),
+            [Region::new(0x1000, 0x3000), Region::new(0x5000, 0x1000)]
+        );
+
+        let mut list = RegionVec::<4>::from_slice(&regions);
+        assert_eq!(list.merge_adjacent(true), 0);
+        assert_eq!(list.as_slice(), regions);
+
+        // Two halves of the address space stay apart
+        let mut list =
+            RegionVec::<2>::from_slice(&[Region::new(0, 1 << 63), Region::new(1 << 63, 1 << 63)]);
+        assert_eq!(list.merge_adjacent(false), 0);
+    }
+
+    #[test]
+    fn test_region_vec_cut_and_retain() {
+        let nomap = Region::with_flags(0x1000, 0x4000, FLAG_NOMAP);
+        let mut list = RegionVec::<2>::from_slice(&[nomap, Region::new(0x8000, 0x1000)]);
+
+        // A hole in the middle needs a third entry: nothing changes
+        assert!(list.cut(&Region::new(0x2000, 0x1000)).is_err());
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.as_slice()[0], nomap);
+
+        // Trimming both ends keeps the flags of the piece left over
+        assert_eq!(list.cut(&Region::new(0x4000, 0x5000)), Ok(0x2000));
+        assert_eq!(
+            list.as_slice(),
+            [Region::with_flags(0x1000, 0x3000, FLAG_NOMAP)]
+        );
+
+        list.retain(|r| r.flags & FLAG_NOMAP == 0);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_region_vec_push_sorted_matches_linear_scan() {
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..200 {
+            let mut regions: Vec<Region> = (0..rng.below(MAX_REGIONS as u64))
+                .map(|_| Region::new(rng.below(0x100) * 0x1000, 0x1000))
+                .collect();
+            regions.sort();
+            regions.dedup_by_key(|r| r.base);
+            let mut list = RegionVec::<MAX_REGIONS>::from_slice(&regions);
+
+            let new = Region::new(rng.below(0x101) * 0x1000 + rng.below(2) * 0x800, 0x800);
+            // The search used before: first entry with a higher base
+            let linear = regions
+                .iter()
+                .position(|r| r.base > new.base)
+                .unwrap_or(regions.len());
+            let pos = list.push_sorted(new).unwrap();
+            if regions.iter().all(|r| r.base != new.base) {
+                assert_eq!(pos, linear);
+            }
+            assert!(list.as_slice().windows(2).all(|w| w[0] <= w[1]));
+        }
+    }
+
+    #[test]
+    fn test_memblock_lock_irqsafe() {
+        // Boot: nothing can interrupt the holder, IRQs are left alone
+        drop(lock_in_phase(false));
+        assert!(irq::irqs_enabled());
+
+        {
+            let mb = lock_in_phase(true);
+            assert!(!irq::irqs_enabled());
+            let _ = mb.total_memory();
+        }
+        assert!(irq::irqs_enabled());
+
+        // A caller that masked IRQs itself keeps them masked
+        let outer = IrqGuard::new();
+        drop(lock_in_phase(true));
+        assert!(!irq::irqs_enabled());
+        drop(outer);
+        assert!(irq::irqs_enabled());
+    }
+
+    #[test]
+    fn test_memblock_from_regions() {
+        // Out of order and adjacent ranges come out sorted and merged
+        let mb = Memblock::from_regions(
+            &[(0x8000, 0x1000), (0x1000, 0x1000), (0x2000, 0x2000)],
+            &[(0x3000, 0x800), (0x1000, 0x100), (0x3800, 0x800)],
+        );
+        mb.assert_regions(
+            &[(0x1000, 0x3000), (0x8000, 0x1000)],
+            &[(0x1000, 0x100), (0x3000, 0x1000)],
+        );
+        assert_eq!(mb.validate(), Ok(()));
+
+        let mut imperative = Memblock::new();
+        imperative.add(0x1000, 0x3000).unwrap();
+        imperative.add(0x8000, 0x1000).unwrap();
+        imperative.reserve(0x1000, 0x100).unwrap();
+        imperative.reserve(0x3000, 0x1000).unwrap();
+        assert_eq!(mb.memory_regions(), imperative.memory_regions());
+        assert_eq!(mb.reserved_regions(), imperative.reserved_regions());
+
+        Memblock::from_regions(&[], &[]).assert_regions(&[], &[]);
+    }
+
+    #[test]
+    #[should_panic(expected = "memory regions")]
+    fn test_memblock_assert_regions_mismatch() {
+        Memblock::from_regions(&[(0x1000, 0x1000)], &[]).assert_regions(&[(0x1000, 0x800)], &[]);
+    }
+
+    #[test]
+    fn test_memblock_add() {
+        let mut mb = Memblock::new();
+        assert!(mb.add(0x1000, 0x1000).is_ok());
+        assert_eq!(mb.memory_regions().len(), 1);
+        assert_eq!(mb.total_memory(), 0x1000);
+
+        // Adding overlapping region should fail
+        assert_eq!(mb.add(0x1800, 0x1000), Err(MemblockError::Overlap));
+
+        // Adding adjacent region should merge
+        assert!(mb.add(0x2000, 0x1000).is_ok());
+        assert_eq!(mb.memory_regions().len(), 1); // merged
+        assert_eq!(mb.total_memory(), 0x2000);
+    }
+
+    #[test]
+    fn test_memblock_reserve() {
+        let mb = Memblock::from_regions(&[(0x1000, 0x1000)], &[(0x1200, 0x200)]);
+        assert_eq!(mb.reserved_regions().len(), 1);
+        assert_eq!(mb.total_reserved(), 0x200);
+    }
+
+    #[test]
+    fn test_memblock_reserve_flags() {
+        let mut mb = Memblock::new();
+        mb.add(0x1000, 0x4000).unwrap();
+        mb.reserve(0x1000, 0x1000).unwrap();
+        mb.reserve_with_flags(0x2000, 0x1000, FLAG_NOMAP, ReservationOwner::Other)
+            .unwrap();
+        // Adjacent but different flags, so not merged
+        assert_eq!(mb.reserved_regions().len(), 2);
+        assert_eq!(mb.reserved_regions()[1].flags, FLAG_NOMAP);
+
+        mb.reserve_with_flags(0x3000, 0x1000, FLAG_NOMAP, ReservationOwner::Other)
+            .unwrap();
+        assert_eq!(mb.reserved_regions().len(), 2);
+        assert_eq!(mb.reserved_regions()[1].size, 0x2000);
+    }
+
+    #[test]
+    fn test_memblock_alloc() {
+        let mut mb = Memblock::new();
+        mb.add(0x1000, 0x1000).unwrap();
+        mb.reserve(0x1200, 0x200).unwrap();
+
+        // Allocate within available space
+        let addr = mb.alloc(0x100, 0x10).unwrap();
+        assert!(addr >= 0x1000 && addr + 0x100 <= 0x2000);
+        // Should be reserved now
+        assert_eq!(mb.reserved_regions().len(), 2);
+
+        // Add a non-adjacent memory region
+        mb.add(0x3000, 0x1000).unwrap();
+        // Allocate 0x1000 bytes, should go into the new region
+        let addr2 = mb.alloc(0x1000, 0x1).unwrap();
+        assert!(addr2 >= 0x3000 && addr2 + 0x1000 <= 0x4000);
+    }
+
+    #[test]
+    fn test_memblock_remove() {
+        let mut mb = Memblock::from_regions(&[(0x1000, 0x1000)], &[]);
+        mb.remove(0x1800, 0x400).unwrap();
+        // Split into two regions
+        mb.assert_regions(&[(0x1000, 0x800), (0x1c00, 0x400)], &[]);
+        assert_eq!(mb.total_memory(), 0xc00);
+    }
+
+    #[test]
+    fn test_memblock_memory_present() {
+        use crate::arch::address::virt::RAM_BASE;
+
+        // Two banks with a hole between them, reservations do not matter
+        let mb = Memblock::from_regions(
+            &[(RAM_BASE, 0x10_0000), (RAM_BASE + 0x20_0000, 0x10_0000)],
+            &[(RAM_BASE, 0x1000)],
+        );
+        assert!(mb.memory_present(RAM_BASE));
+        assert!(mb.memory_present(RAM_BASE + 0x8_0000));
+        assert!(mb.memory_present(RAM_BASE + 0x2f_ffff));
+        // In the hole, just past either bank, and below RAM
+        assert!(!mb.memory_present(RAM_BASE + 0x10_0000));
+        assert!(!mb.memory_present(RAM_BASE + 0x1f_ffff));
+        assert!(!mb.memory_present(RAM_BASE + 0x30_0000));
+        assert!(!mb.memory_present(RAM_BASE - 1));
+        assert!(!mb.memory_present(0));
+
+        assert!(mb.is_memory(RAM_BASE + 0x20_0000, 0x10_0000));
+        assert!(!mb.is_memory(RAM_BASE + 0xf_f000, 0x2000));
+    }
+
+    #[test]
+    fn test_memblock_range_queries() {
+        let mut mb = Memblock::new();
+        mb.add(0x1000, 0x4000).unwrap();
+        mb.add(0x8000, 0x1000).unwrap();
+        mb.reserve_tagged(0x2000, 0x1000, ReservationOwner::KernelImage)
+            .unwrap();
+
+        assert!(mb.is_memory(0x1000, 0x4000));
+        assert!(mb.is_memory(0x8000, 0x800));
+        // Spans the hole between the regions
+        assert!(!mb.is_memory(0x4000, 0x5000));
+        assert!(!mb.is_memory(0x800, 0x1000));
+        assert!(!mb.is_memory(u64::MAX - 1, 4));
+
+        assert!(mb.overlaps_owner(0x2fff, 0x10, ReservationOwner::KernelImage));
+        assert!(!mb.overlaps_owner(0x2fff, 0x10, ReservationOwner::Dtb));
+        assert!(!mb.overlaps_owner(0x3000, 0x1000, ReservationOwner::KernelImage));
+
+        mb.reserve_with_flags(0x4000, 0x1000, FLAG_NOMAP, ReservationOwner::Stack)
+            .unwrap();
+        assert!(mb.overlaps_flags(0x3000, 0x2000, FLAG_NOMAP));
+        assert!(!mb.overlaps_flags(0x2000, 0x2000, FLAG_NOMAP));
+    }
+
+    #[test]
+    fn test_memblock_alloc_colored() {
+        let mut mb = Memblock::new();
+        mb.add(0x10_0000, 0x10_0000).unwrap();
+
+        let stride = 0x8000;
+        for color in (0..stride).step_by(0x1000) {
+            let addr = mb.alloc_colored(0x1000, 0x1000, stride, color).unwrap();
+            assert!(addr.is_multiple_of(0x1000));
+            assert_eq!(addr % stride, color);
+        }
+
+        // Stride smaller than the alignment: every aligned base is color 0
+        let addr = mb.alloc_colored(0x2000, 0x4000, 0x1000, 0).unwrap();
+        assert!(addr.is_multiple_of(0x4000));
+
+        // Taken pages are skipped, not handed out twice
+        let first = mb.alloc_colored(0x1000, 0x1000, stride, 0x3000).unwrap();
+        let second = mb.alloc_colored(0x1000, 0x1000, stride, 0x3000).unwrap();
+        assert_eq!(second, first + stride);
+        assert_eq!(mb.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_memblock_alloc_range() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x100_0000).unwrap();
+        mb.reserve(0x4040_0000, 0x10_0000).unwrap();
+
+        // Lowest fit inside the window, skipping the reservation
+        let base = mb
+            .alloc_range(
+                0x20_0000,
+                0x1000,
+                0x403f_0000,
+                0x4080_0000,
+                ReservationOwner::Cma,
+            )
+            .unwrap();
+        assert_eq!(base, 0x4050_0000);
+        assert_eq!(mb.stats().reserved(ReservationOwner::Cma), 0x20_0000);
+
+        // The whole region must end below the limit
+        assert_eq!(
+            mb.alloc_range(
+                0x20_0000,
+                0x1000,
+                0x4070_0000,
+                0x4080_0000,
+                ReservationOwner::Cma
+            ),
+            Err(MemblockError::InsufficientMemory)
+        );
+        assert_eq!(
+            mb.alloc_range(
+                0x1000,
+                0x1000,
+                0x4080_0000,
+                0x4080_0000,
+                ReservationOwner::Cma
+            ),
+            Err(MemblockError::Empty)
+        );
+
+        // A window below memory finds nothing
+        assert_eq!(
+            mb.alloc_range(0x1000, 0x1000, 0, 0x4000_0000, ReservationOwner::Cma),
+            Err(MemblockError::InsufficientMemory)
+        );
+    }
+
+    #[test]
+    fn test_memblock_alloc_colored_impossible() {
+        let mut mb = Memblock::new();
+        mb.add(0x10_0000, 0x10_0000).unwrap();
+
+        // Page-aligned bases never land on a half-page color
+        assert_eq!(
+            mb.alloc_colored(0x1000, 0x1000, 0x8000, 0x800),
+            Err(MemblockError::InsufficientMemory)
+        );
+        // Only one base of this color fits, and it is reserved
+        mb.reserve(0x1f_8000, 0x1000).unwrap();
+        assert_eq!(
+            mb.alloc_colored(0x1000, 0x1000, 0x10_0000, 0xf_8000),
+            Err(MemblockError::InsufficientMemory)
+        );
+        assert_eq!(
+            mb.alloc_colored(0x1000, 0x1000, 0, 0),
+            Err(MemblockError::BadRequest)
+        );
+        assert_eq!(
+            mb.alloc_colored(0x1000, 0x1000, 0x1000, 0x1000),
+            Err(MemblockError::BadRequest)
+        );
+        assert_eq!(mb.total_reserved(), 0x1000);
+    }
+
+    #[test]
+    fn test_memblock_reserve_merge_same_flags() {
+        let mut mb = Memblock::new();
+        mb.add(0x0, 0x20000).unwrap();
+        mb.reserve(0x2000, 0x2000).unwrap();
+        mb.reserve(0x6000, 0x1000).unwrap();
+        mb.reserve_tagged(0x10000, 0x1000, ReservationOwner::Dtb)
+            .unwrap();
+
+        // Strict reserve still refuses the overlap
+        assert_eq!(mb.reserve(0x3000, 0x1000), Err(MemblockError::Overlap));
+
+        // Fully inside an existing reservation: nothing changes
+        mb.reserve_merge(0x2800, 0x800).unwrap();
+        assert_eq!(mb.reserved_regions()[0], Region::new(0x2000, 0x2000));
+
+        // Spanning two reservations and the gap between them
+        mb.reserve_merge(0x3000, 0x3800).unwrap();
+        assert_eq!(
+            mb.reserved_regions(),
+            [
+                Region::new(0x2000, 0x5000),
+                Region::new(0x10000, 0x1000).with_owner(ReservationOwner::Dtb),
+            ]
+        );
+
+        // Touching without overlap merges like a normal reserve
+        mb.reserve_merge(0x7000, 0x1000).unwrap();
+        assert_eq!(mb.reserved_regions()[0], Region::new(0x2000, 0x6000));
+        assert_eq!(mb.total_reserved(), 0x7000);
+        assert_eq!(mb.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_memblock_reserve_merge_different_flags() {
+        let mut mb = Memblock::new();
+        mb.add(0x0, 0x20000).unwrap();
+        mb.reserve_with_flags(0x2000, 0x2000, FLAG_NOMAP, ReservationOwner::Stack)
+            .unwrap();
+        mb.reserve(0x8000, 0x1000).unwrap();
+        let before = mb.reserved_regions().to_vec();
+
+        assert_eq!(
+            mb.reserve_merge(0x3000, 0x2000),
+            Err(MemblockError::Overlap)
+        );
+        // Partly matching: the matching overlap is not absorbed either
+        assert_eq!(
+            mb.reserve_merge(0x3000, 0x6000),
+            Err(MemblockError::Overlap)
+        );
+        assert_eq!(mb.reserved_regions(), before);
+
+        mb.reserve_merge_with_flags(0x3000, 0x2000, FLAG_NOMAP, ReservationOwner::Stack)
+            .unwrap();
+        assert_eq!(
+            mb.reserved_regions()[0],
+            Region::with_flags(0x2000, 0x3000, FLAG_NOMAP).with_owner(ReservationOwner::Stack)
+        );
+    }
+
+    /// The original candidate-stepping search, kept as a reference.
+    fn stepping_find(mb: &Memblock, size: u64, align: u64) -> Option<u64> {
+        let align = align.max(1);
+        for region in mb.memory_regions() {
+            let mut base = (region.base + align - 1) & !(align - 1);
+            while base + size <= region.end() {
+                let candidate = Region::new(base, size);
+                if !mb.reserved_regions().iter().any(|r| r.overlaps(&candidate)) {
+                    return Some(base);
+                }
+                base = (base + align) & !(align - 1);
+            }
+        }
+        None
+    }
+
+    /// Minimal xorshift generator, so layouts are reproducible.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    #[test]
+    fn test_memblock_alloc_matches_stepping_search() {
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+
+        for _ in 0..500 {
+            let mut mb = Memblock::new();
+            for _ in 0..1 + rng.below(3) {
+                let _ = mb.add(rng.below(0x100) * 0x10, (1 + rng.below(0x40)) * 0x10);
+            }
+            for _ in 0..rng.below(8) {
+                // Overlapping reservations are rejected, which is fine
+                let _ = mb.reserve(rng.below(0x140) * 0x10, (1 + rng.below(0x10)) * 0x10);
+            }
+
+            for _ in 0..8 {
+                let size = (1 + rng.below(0x20)) * 0x8;
+                let align = 1 << rng.below(9);
+                let expected = stepping_find(&mb, size, align);
+                assert_eq!(
+                    mb.find_free(size, align, 0, ADDRESS_SPACE_END, |_| true)
+                        .map(|(_, base)| base),
+                    expected,
+                    "size {:#x} align {:#x} in {:?}",
+                    size,
+                    align,
+                    mb
+                );
+                if let Some(base) = expected {
+                    assert_eq!(mb.alloc(size, align), Ok(base));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_memblock_alloc_small_align_large_reservation() {
+        // 1GB region reserved up to its last page: stepping by 16 bytes
+        // would test ~67 million candidates
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x4000_0000).unwrap();
+        mb.reserve(0x4000_0000, 0x3fff_f000).unwrap();
+
+        let start = std::time::Instant::now();
+        assert_eq!(mb.alloc(0x2000, 16), Err(MemblockError::InsufficientMemory));
+        assert_eq!(mb.alloc(0x1000, 16), Ok(0x7fff_f000));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    /// Sum of the free ranges, computed independently of the totals.
+    fn free_bytes(mb: &Memblock) -> u64 {
+        let mut free = 0;
+        mb.for_each_free(|r| free += r.size);
+        free
+    }
+
+    #[test]
+    fn test_memblock_reserved_in_range() {
+        let mut mb = Memblock::new();
+        mb.add(0x1000, 0x10000).unwrap();
+        mb.reserve_tagged(0x4000, 0x2000, ReservationOwner::Dtb)
+            .unwrap();
+        mb.reserve_with_flags(0x8000, 0x1000, FLAG_NOMAP, ReservationOwner::Other)
+            .unwrap();
+
+        // Starts inside one reservation and ends inside the next
+        let clipped: Vec<_> = mb.reserved_in_range(0x5000, 0x3800).collect();
+        assert_eq!(clipped.len(), 2);
+        assert_eq!((clipped[0].base, clipped[0].size), (0x5000, 0x1000));
+        assert_eq!(clipped[0].owner, ReservationOwner::Dtb);
+        assert_eq!((clipped[1].base, clipped[1].size), (0x8000, 0x800));
+        assert_eq!(clipped[1].flags, FLAG_NOMAP);
+
+        // Entirely inside one reservation, and exactly one reservation
+        let inside: Vec<_> = mb.reserved_in_range(0x4800, 0x800).collect();
+        assert_eq!((inside[0].base, inside[0].size), (0x4800, 0x800));
+        assert_eq!(inside.len(), 1);
+        let exact: Vec<_> = mb.reserved_in_range(0x8000, 0x1000).collect();
+        assert_eq!(exact, [mb.reserved_regions()[1]]);
+
+        assert_eq!(mb.reserved_in_range(0x6000, 0x2000).count(), 0);
+        assert_eq!(mb.reserved_in_range(0x10_0000, 0x1000).count(), 0);
+        assert_eq!(mb.reserved_in_range(0x4000, 0).count(), 0);
+    }
+
+    #[test]
+    fn test_memblock_free_in_range() {
+        let mb = Memblock::from_regions(
+            &[(0x1000, 0x10000), (0x20000, 0x4000)],
+            &[(0x4000, 0x2000), (0x8000, 0x1000)],
+        );
+
+        let free: Vec<_> = mb.free_in_range(0x5000, 0x1_c000).collect();
+        assert_eq!(
+            free,
+            [
+                Region::new(0x6000, 0x2000),
+                Region::new(0x9000, 0x8000),
+                Region::new(0x20000, 0x1000)
+            ]
+        );
+
+        // A window inside or equal to a reservation has nothing free
+        assert_eq!(mb.free_in_range(0x4800, 0x800).count(), 0);
+        assert_eq!(mb.free_in_range(0x8000, 0x1000).count(), 0);
+        // Nor does one outside all memory
+        assert_eq!(mb.free_in_range(0x11000, 0xf000).count(), 0);
+        assert_eq!(mb.free_in_range(0x100_0000, 0x1000).count(), 0);
+
+        // Whole address space matches for_each_free and the stats
+        let whole: u64 = mb.free_in_range(0, u64::MAX).map(|r| r.size).sum();
+        assert_eq!(whole, free_bytes(&mb));
+        assert_eq!(mb.stats().free(), whole);
+        assert_eq!(whole, 0x14000 - 0x3000);
+    }
+
+    #[test]
+    fn test_memblock_remove_split_when_full() {
+        let mut mb = Memblock::new();
+        for i in 0..MAX_REGIONS as u64 {
+            mb.add(i * 0x2000, 0x1000).unwrap();
+        }
+        mb.reserve(0x2000, 0x1000).unwrap();
+        let memory = mb.memory_regions().to_vec();
+        let reserved = mb.reserved_regions().to_vec();
+
+        // A middle removal needs one more memory slot than there is
+        assert_eq!(mb.remove(0x2400, 0x400), Err(MemblockError::OutOfRegions));
+        assert_eq!(mb.memory_regions(), memory);
+        assert_eq!(mb.reserved_regions(), reserved);
+
+        // Trimming an end needs no extra slot
+        let report = mb.remove(0x2c00, 0x400).unwrap();
+        assert_eq!(report.memory_removed, 0x400);
+        assert_eq!(report.reserved_clipped, 0x400);
+        assert_eq!(mb.memory_regions().len(), MAX_REGIONS);
+    }
+
+    #[test]
+    fn test_memblock_remove_clips_reservation() {
+        let mut mb = Memblock::new();
+        mb.add(0x1000, 0x10000).unwrap();
+        mb.reserve_tagged(0x4000, 0x2000, ReservationOwner::Dtb)
+            .unwrap();
+        mb.reserve(0x8000, 0x1000).unwrap();
+        assert_eq!(mb.stats().free(), free_bytes(&mb));
+
+        // Covers the upper half of the first reservation
+        let report = mb.remove(0x5000, 0x2000).unwrap();
+        assert_eq!(
+            report,
+            RemoveReport {
+                memory_removed: 0x2000,
+                reserved_clipped: 0x1000,
+            }
+        );
+
+        assert_eq!(
+            mb.memory_regions(),
+            [Region::new(0x1000, 0x4000), Region::new(0x7000, 0xa000)]
+        );
+        assert_eq!(
+            mb.reserved_regions(),
+            [
+                Region::new(0x4000, 0x1000).with_owner(ReservationOwner::Dtb),
+                Region::new(0x8000, 0x1000),
+            ]
+        );
+        assert_eq!(mb.total_memory(), 0xe000);
+        assert_eq!(mb.total_reserved(), 0x2000);
+        assert_eq!(mb.stats().free(), free_bytes(&mb));
+        assert_eq!(mb.validate_strict(), Ok(()));
+
+        // A range in the middle of a reservation splits it
+        let report = mb.remove(0x8400, 0x400).unwrap();
+        assert_eq!(report.reserved_clipped, 0x400);
+        assert_eq!(mb.reserved_regions().len(), 3);
+        assert_eq!(mb.stats().free(), free_bytes(&mb));
+    }
+
+    #[test]
+    fn test_memblock_remove_everything() {
+        let mut mb = Memblock::new();
+        mb.add(0x1000, 0x4000).unwrap();
+        mb.add(0x10000, 0x4000).unwrap();
+        mb.reserve(0x2000, 0x1000).unwrap();
+        mb.reserve_with_flags(0x11000, 0x1000, FLAG_NOMAP, ReservationOwner::Stack)
+            .unwrap();
+
+        let report = mb.remove(0, 0x20000).unwrap();
+        assert_eq!(report.memory_removed, 0x8000);
+        assert_eq!(report.reserved_clipped, 0x2000);
+        assert_eq!(mb.memory_regions().len(), 0);
+        assert_eq!(mb.reserved_regions().len(), 0);
+        assert_eq!(mb.stats().free(), 0);
+
+        // Nothing left to remove
+        assert_eq!(mb.remove(0, 0x20000).unwrap(), RemoveReport::default());
+    }
+
+    #[test]
+    fn test_memblock_remove_reserved_outside_memory() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000, 0x4000).unwrap();
+        mb.add(0x10000, 0x1000).unwrap();
+        // Straddles the start of memory, spans a hole, and lies outside
+        mb.reserve(0x3000, 0x2000).unwrap();
+        mb.reserve_tagged(0x7000, 0xa000, ReservationOwner::Initrd)
+            .unwrap();
+        mb.reserve(0x20000, 0x1000).unwrap();
+        // Device windows stay
+        mb.reserve_with_flags(0x30000, 0x1000, FLAG_MMIO, ReservationOwner::Mmio)
+            .unwrap();
+
+        assert_eq!(
+            mb.remove_reserved_outside_memory(),
+            Ok(0x1000 + 0x8000 + 0x1000)
+        );
+        assert_eq!(
+            mb.reserved_regions(),
+            [
+                Region::new(0x4000, 0x1000),
+                Region::new(0x7000, 0x1000).with_owner(ReservationOwner::Initrd),
+                Region::new(0x10000, 0x1000).with_owner(ReservationOwner::Initrd),
+                Region::with_flags(0x30000, 0x1000, FLAG_MMIO).with_owner(ReservationOwner::Mmio),
+            ]
+        );
+        assert_eq!(mb.validate_strict(), Ok(()));
+        assert_eq!(mb.stats().free(), free_bytes(&mb));
+
+        // Already clean
+        assert_eq!(mb.remove_reserved_outside_memory(), Ok(0));
+    }
+
+    #[test]
+    fn test_memblock_merge() {
+        let mut mb = Memblock::new();
+        mb.add(0x1000, 0x1000).unwrap();
+        mb.add(0x3000, 0x1000).unwrap();
+        // Not adjacent, so two regions
+        assert_eq!(mb.memory_regions().len(), 2);
+
+        let mut mb2 = Memblock::new();
+        mb2.add(0x1000, 0x1000).unwrap();
+        mb2.add(0x3000, 0x1000).unwrap();
+        // Now add adjacent region that bridges the gap
+        mb2.add(0x2000, 0x1000).unwrap();
+        // Should merge into one region (all three are adjacent)
+        assert_eq!(mb2.memory_regions().len(), 1);
+        assert_eq!(mb2.total_memory(), 0x3000);
+    }
+
+    #[test]
+    fn test_memblock_region_kind_survives_sort_and_merge() {
+        let mut mb = Memblock::new();
+        // Added out of order; only same-kind neighbours merge
+        mb.add_kind(0x5000, 0x1000, RegionKind::Firmware).unwrap();
+        mb.add(0x1000, 0x2000).unwrap();
+        mb.add_kind(0x3000, 0x2000, RegionKind::Device).unwrap();
+        mb.add(0x6000, 0x1000).unwrap();
+        mb.add_kind(0x4_0000, 0x1000, RegionKind::Firmware).unwrap();
+        mb.add(0x7000, 0x1000).unwrap();
+        let kinds: Vec<_> = mb
+            .memory_regions()
+            .iter()
+            .map(|r| (r.base, r.size, r.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (0x1000, 0x2000, RegionKind::Normal),
+                (0x3000, 0x2000, RegionKind::Device),
+                (0x5000, 0x1000, RegionKind::Firmware),
+                (0x6000, 0x2000, RegionKind::Normal),
+                (0x4_0000, 0x1000, RegionKind::Firmware),
+            ]
+        );
+        assert_eq!(mb.validate(), Ok(()));
+
+        // Carving a firmware range out of normal memory splits it, and
+        // handing it back merges with the neighbours again
+        assert_eq!(mb.set_kind(0x1800, 0x800, RegionKind::Firmware), Ok(0x800));
+        assert_eq!(mb.memory_regions().len(), 7);
+        assert_eq!(
+            mb.memory_regions()[1],
+            Region::new(0x1800, 0x800).with_kind(RegionKind::Firmware)
+        );
+        assert_eq!(mb.set_kind(0x1800, 0x800, RegionKind::Normal), Ok(0x800));
+        assert_eq!(mb.memory_regions()[0], Region::new(0x1000, 0x2000));
+
+        // Reclassifying across a hole only touches memory
+        assert_eq!(
+            mb.set_kind(0x6000, 0x1_0000, RegionKind::Firmware),
+            Ok(0x2000)
+        );
+        assert_eq!(
+            mb.memory_regions()[2],
+            Region::new(0x5000, 0x3000).with_kind(RegionKind::Firmware)
+        );
+
+        // Removing memory keeps the kind of what is left on either side
+        mb.remove(0x5800, 0x1000).unwrap();
+        assert_eq!(mb.memory_regions()[2].kind, RegionKind::Firmware);
+        assert_eq!(mb.memory_regions()[3].kind, RegionKind::Firmware);
+        assert_eq!(mb.validate(), Ok(()));
+
+        // Sorting orders by address whatever the kind
+        let mut regions = [
+            Region::new(0x3000, 0x1000).with_kind(RegionKind::Device),
+            Region::new(0x1000, 0x1000),
+            Region::new(0x2000, 0x1000).with_kind(RegionKind::Firmware),
+        ];
+        regions.sort();
+        assert_eq!(
+            regions.map(|r| r.kind),
+            [RegionKind::Normal, RegionKind::Firmware, RegionKind::Device]
+        );
+    }
+
+    #[test]
+    fn test_memblock_normal_memory_iter() {
+        let mut mb = Memblock::new();
+        mb.add(0x1000, 0x4000).unwrap();
+        mb.add_kind(0x5000, 0x1000, RegionKind::Device).unwrap();
+        mb.add(0x8000, 0x1000).unwrap();
+        mb.set_kind(0x2000, 0x1000, RegionKind::Firmware).unwrap();
+
+        let normal: Vec<_> = mb.normal_memory_iter().map(|r| (r.base, r.size)).collect();
+        assert_eq!(
+            normal,
+            [(0x1000, 0x1000), (0x3000, 0x2000), (0x8000, 0x1000)]
+        );
+
+        // Free ranges, and so every allocator, only see normal memory
+        let mut free = Vec::new();
+        mb.for_each_free(|r| free.push((r.base, r.size)));
+        assert_eq!(free, normal);
+        assert_eq!(mb.stats().free_memory, 0x4000);
+        assert_eq!(mb.total_memory(), 0x6000);
+
+        assert!(mb.is_memory(0x2000, 0x1000));
+        assert!(!mb.is_normal_memory(0x2000, 0x1000));
+        assert_eq!(
+            mb.alloc_at(0x5000, 0x1000),
+            Err(MemblockError::InsufficientMemory)
+        );
+        assert_eq!(mb.alloc(0x2000, 0x1000), Ok(0x3000));
+        assert_eq!(
+            mb.alloc(0x2000, 0x1000),
+            Err(MemblockError::InsufficientMemory)
+        );
+
+        // Dumps name the kind of memory regions that are not normal
+        let dump = mb.to_string();
+        assert!(dump.contains("(0x1000 bytes) firmware\n"));
+        assert!(dump.contains("(0x1000 bytes) device\n"));
+    }
+
+    fn summary_memblock() -> Memblock {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x1000_0000).unwrap();
+        mb.add(0x6000_0000, 0x3000_0000).unwrap();
+        mb.reserve_tagged(0x4000_0000, 0x10_0000, ReservationOwner::KernelImage)
+            .unwrap();
+        mb.reserve(0x4800_0000, 0x1000).unwrap();
+        mb.reserve_tagged(0x6000_0000, 0x20_0000, ReservationOwner::Dtb)
+            .unwrap();
+        mb
+    }
+
+    #[test]
+    fn test_memblock_display() {
+        let expected = "\
+Memblock:
+  memory (2):
+    [  0] [0x0000000040000000 - 0x0000000050000000) (0x10000000 bytes)
+    [  1] [0x0000000060000000 - 0x0000000090000000) (0x30000000 bytes)
+  reserved (3):
+    [  0] [0x0000000040000000 - 0x0000000040100000) (0x100000 bytes) kernel
+    [  1] [0x0000000048000000 - 0x0000000048001000) (0x1000 bytes) other
+    [  2] [0x0000000060000000 - 0x0000000060200000) (0x200000 bytes) dtb
+  total memory:   0x40000000
+  total reserved: 0x301000
+    kernel       0x100000
+    dtb          0x200000
+    other        0x1000";
+        assert_eq!(format!("{}", summary_memblock()), expected);
+    }
+
+    #[test]
+    fn test_memblock_display_alternate() {
+        let expected = "\
+Memblock:
+  memory (2):
+    [  0] [0x0000000040000000 - 0x0000000050000000) (0x10000000 bytes)  25.00%
+    [  1] [0x0000000060000000 - 0x0000000090000000) (0x30000000 bytes)  75.00%
+  reserved (3):
+    [  0] [0x0000000040000000 - 0x0000000040100000) (0x100000 bytes) kernel   0.10%
+    [  1] [0x0000000048000000 - 0x0000000048001000) (0x1000 bytes) other   0.00%
+    [  2] [0x0000000060000000 - 0x0000000060200000) (0x200000 bytes) dtb   0.20%
+  total memory:   0x40000000
+  total reserved: 0x301000
+    kernel       0x100000
+    dtb          0x200000
+    other        0x1000";
+        assert_eq!(format!("{:#}", summary_memblock()), expected);
+    }
+
+    #[test]
+    fn test_memblock_display_empty() {
+        let expected = "\
+Memblock:
+  memory (0):
+    (no regions)
+  reserved (0):
+    (no regions)
+  total memory:   0x0
+  total reserved: 0x0";
+        assert_eq!(format!("{}", Memblock::new()), expected);
+        assert_eq!(format!("{:#}", Memblock::new()), expected);
+    }
+
+    #[test]
+    fn test_memblock_debug() {
+        assert_eq!(
+            format!("{:?}", summary_memblock()),
+            "Memblock { memory: [0x40000000-0x50000000) [0x60000000-0x90000000), \
+             reserved: [0x40000000-0x40100000) [0x48000000-0x48001000) [0x60000000-0x60200000) }"
+        );
+        assert_eq!(
+            format!("{:?}", Memblock::new()),
+            "Memblock { memory: (no regions), reserved: (no regions) }"
+        );
+    }
+
+    #[test]
+    fn test_memblock_coalesce() {
+        let mut mb = Memblock::new();
+        mb.add(0x1000, 0x10000).unwrap();
+
+        // Build a fragmented reserved array by hand: four adjacent pages,
+        // the third tagged NOMAP so it must not merge with its neighbours
+        let regions = [
+            Region::new(0x1000, 0x1000),
+            Region::new(0x2000, 0x1000),
+            Region::with_flags(0x3000, 0x1000, FLAG_NOMAP),
+            Region::new(0x4000, 0x1000),
+            Region::new(0x5000, 0x1000),
+            Region::new(0x8000, 0x1000),
+        ];
+        mb.set_regions_unchecked(&[Region::new(0x1000, 0x10000)], &regions);
+
+        assert_eq!(mb.coalesce(), 2);
+        assert_eq!(mb.reserved_regions().len(), 4);
+        assert_eq!(mb.reserved_regions()[0], Region::new(0x1000, 0x2000));
+        assert_eq!(mb.reserved_regions()[1].flags, FLAG_NOMAP);
+        assert_eq!(mb.reserved_regions()[2], Region::new(0x4000, 0x2000));
+        assert_eq!(mb.total_reserved(), 0x6000);
+
+        // Already coalesced
+        assert_eq!(mb.coalesce(), 0);
+    }
+
+    #[test]
+    fn test_memblock_validate_ok() {
+        let mut mb = Memblock::new();
+        assert_eq!(mb.validate(), Ok(()));
+
+        mb.add(0x1000, 0x10000).unwrap();
+        mb.add(0x20000, 0x10000).unwrap();
+        mb.reserve(0x2000, 0x1000).unwrap();
+        mb.alloc(0x1000, 0x1000).unwrap();
+        mb.free(0x2000, 0x800).unwrap();
+        mb.remove(0x8000, 0x1000).unwrap();
+        assert_eq!(mb.validate_strict(), Ok(()));
+    }
+
+    #[test]
+    fn test_memblock_validate_memory() {
+        let mut mb = Memblock::new();
+
+        mb.set_regions_unchecked(
+            &[Region::new(0x1000, 0x2000), Region::new(0x2000, 0x1000)],
+            &[],
+        );
+        assert_eq!(mb.validate(), Err("memory regions overlap"));
+
+        mb.set_regions_unchecked(
+            &[Region::new(0x8000, 0x1000), Region::new(0x1000, 0x1000)],
+            &[],
+        );
+        assert_eq!(mb.validate(), Err("memory regions not sorted"));
+
+        mb.set_regions_unchecked(&[Region::new(0x1000, 0)], &[]);
+        assert_eq!(mb.validate(), Err("empty memory region"));
+    }
+
+    #[test]
+    fn test_memblock_validate_reserved() {
+        let mut mb = Memblock::new();
+        let memory = [Region::new(0x0, 0x10000)];
+
+        mb.set_regions_unchecked(
+            &memory,
+            &[Region::new(0x1000, 0x2000), Region::new(0x2000, 0x1000)],
+        );
+        assert_eq!(mb.validate(), Err("reserved regions overlap"));
+
+        mb.set_regions_unchecked(
+            &memory,
+            &[Region::new(0x4000, 0x1000), Region::new(0x1000, 0x1000)],
+        );
+        assert_eq!(mb.validate(), Err("reserved regions not sorted"));
+
+        mb.set_regions_unchecked(&memory, &[Region::new(0x1000, 0)]);
+        assert_eq!(mb.validate(), Err("empty reserved region"));
+    }
+
+    #[test]
+    fn test_memblock_validate_strict() {
+        let mut mb = Memblock::new();
+        mb.add(0x1000, 0x1000).unwrap();
+
+        // Guard pages outside memory are fine for the default checks
+        mb.reserve(0x8000, 0x1000).unwrap();
+        assert_eq!(mb.validate(), Ok(()));
+        assert_eq!(mb.validate_strict(), Err("reservation outside memory"));
+
+        // Straddling the end of memory is also caught
+        mb.free(0x8000, 0x1000).unwrap();
+        mb.reserve(0x1800, 0x1000).unwrap();
+        assert_eq!(mb.validate_strict(), Err("reservation outside memory"));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "memblock invariant violated")]
+    fn test_memblock_invariant_check_panics() {
+        let mut mb = Memblock::new();
+        mb.set_regions_unchecked(
+            &[Region::new(0x0, 0x10000)],
+            &[Region::new(0x1000, 0x2000), Region::new(0x2000, 0x1000)],
+        );
+        // Any mutation re-checks the arrays
+        let _ = mb.free(0x9000, 0x1000);
+    }
+
+    #[test]
+    fn test_memblock_fragmentation_ratio() {
+        let mut mb = Memblock::new();
+        assert_eq!(mb.fragmentation_ratio(), 0);
 
-/// Reserves a region of memory.
-#[allow(dead_code)]
-pub fn reserve(base: u64, size: u64) -> Result<(), &'static str> {
-    let mut mb = lock();
-    mb.reserve(base, size)
-}
+        mb.add(0x0, 0x10000).unwrap();
+        assert_eq!(mb.fragmentation_ratio(), 0);
 
-/// Allocates a contiguous region of physical memory.
-#[allow(dead_code)]
-pub fn alloc(size: u64, align: u64) -> Result<u64, &'static str> {
-    let mut mb = lock();
-    mb.alloc(size, align)
-}
+        // Free: [0x0-0x1000) and [0x2000-0x10000), largest 0xe000 of 0xf000
+        mb.reserve(0x1000, 0x1000).unwrap();
+        assert_eq!(mb.fragmentation_ratio(), 6);
 
-#[cfg(all(test, not(target_os = "none")))]
-mod tests {
-    use super::*;
+        // Free: four 0x1000 gaps and [0x8000-0x10000)
+        mb.reserve(0x3000, 0x1000).unwrap();
+        mb.reserve(0x5000, 0x1000).unwrap();
+        mb.reserve(0x7000, 0x1000).unwrap();
+        assert_eq!(mb.fragmentation_ratio(), 33);
+
+        // Nothing free
+        mb.reserve(0x0, 0x1000).unwrap();
+        mb.reserve(0x2000, 0x1000).unwrap();
+        mb.reserve(0x4000, 0x1000).unwrap();
+        mb.reserve(0x6000, 0x1000).unwrap();
+        mb.reserve(0x8000, 0x8000).unwrap();
+        assert_eq!(mb.fragmentation_ratio(), 0);
+    }
 
     #[test]
-    fn test_region_contains() {
-        let region = Region::new(0x1000, 0x1000);
-        assert!(region.contains(0x1000));
-        assert!(region.contains(0x1fff));
-        assert!(!region.contains(0x2000));
-        assert!(!region.contains(0xfff));
+    fn test_memblock_largest_free_block() {
+        let mut mb = Memblock::new();
+        assert_eq!(mb.largest_free_block(), None);
+
+        // Largest at the front
+        mb.add(0x0, 0x10000).unwrap();
+        mb.reserve(0x8000, 0x1000).unwrap();
+        mb.reserve(0xc000, 0x2000).unwrap();
+        assert_eq!(mb.largest_free_block(), Some(Region::new(0x0, 0x8000)));
+
+        // Largest in the middle
+        mb.reserve(0x0, 0x7000).unwrap();
+        assert_eq!(mb.largest_free_block(), Some(Region::new(0x9000, 0x3000)));
+
+        // Largest at the back, in a second memory region
+        mb.add(0x20000, 0x4000).unwrap();
+        assert_eq!(mb.largest_free_block(), Some(Region::new(0x20000, 0x4000)));
+
+        // Equal sizes go to the lowest address
+        mb.reserve(0x22000, 0x2000).unwrap();
+        mb.reserve(0x9000, 0x1000).unwrap();
+        assert_eq!(mb.largest_free_block(), Some(Region::new(0xa000, 0x2000)));
     }
 
     #[test]
-    fn test_region_overlaps() {
-        let r1 = Region::new(0x1000, 0x1000);
-        let r2 = Region::new(0x1800, 0x1000);
-        let r3 = Region::new(0x2000, 0x1000);
-        assert!(r1.overlaps(&r2));
-        assert!(r2.overlaps(&r1));
-        assert!(!r1.overlaps(&r3));
-        assert!(!r3.overlaps(&r1));
+    fn test_memblock_largest_free_block_all_reserved() {
+        let regions = [(0x0, 0x4000), (0x8000, 0x4000)];
+        let mb = Memblock::from_regions(&regions, &regions);
+        assert_eq!(mb.largest_free_block(), None);
     }
 
     #[test]
-    fn test_region_adjacent() {
-        let r1 = Region::new(0x1000, 0x1000);
-        let r2 = Region::new(0x3000, 0x1000);
-        let r3 = Region::new(0x1800, 0x1000);
-        assert!(!r1.adjacent(&r2)); // not touching
-        assert!(!r1.adjacent(&r3)); // overlapping
-        let r4 = Region::new(0x2000, 0x1000);
-        assert!(r1.adjacent(&r4)); // r1 ends at 0x2000, r4 starts at 0x2000
+    fn test_memblock_owner_accounting() {
+        let mut mb = Memblock::new();
+        mb.add(0x0, 0x10000).unwrap();
+
+        // Interleave owners so adjacent reservations differ
+        mb.reserve_tagged(0x0, 0x1000, ReservationOwner::KernelImage)
+            .unwrap();
+        mb.reserve_tagged(0x1000, 0x1000, ReservationOwner::PageTable)
+            .unwrap();
+        mb.reserve_tagged(0x2000, 0x1000, ReservationOwner::KernelImage)
+            .unwrap();
+        mb.reserve_tagged(0x3000, 0x2000, ReservationOwner::PageTable)
+            .unwrap();
+        // Same owner as its left neighbour, so it merges
+        mb.reserve_tagged(0x5000, 0x1000, ReservationOwner::PageTable)
+            .unwrap();
+        let addr = mb.alloc(0x1000, 0x1000).unwrap();
+        let tagged = mb
+            .alloc_tagged(0x2000, 0x1000, ReservationOwner::MemMap)
+            .unwrap();
+
+        assert_eq!(mb.reserved_regions().len(), 6);
+        assert_eq!(
+            mb.reserved_regions()[3],
+            Region::new(0x3000, 0x3000).with_owner(ReservationOwner::PageTable)
+        );
+        assert_eq!(addr, 0x6000);
+        assert_eq!(tagged, 0x7000);
+
+        let stats = mb.stats();
+        assert_eq!(stats.total_memory, 0x10000);
+        assert_eq!(stats.total_reserved, 0x9000);
+        assert_eq!(stats.reserved(ReservationOwner::KernelImage), 0x2000);
+        assert_eq!(stats.reserved(ReservationOwner::PageTable), 0x4000);
+        assert_eq!(stats.reserved(ReservationOwner::EarlyAlloc), 0x1000);
+        assert_eq!(stats.reserved(ReservationOwner::MemMap), 0x2000);
+        assert_eq!(stats.reserved(ReservationOwner::Other), 0);
+        assert_eq!(mb.reserved_by(ReservationOwner::PageTable), 0x4000);
     }
 
     #[test]
-    fn test_memblock_add() {
+    fn test_memblock_alloc_pages_into() {
         let mut mb = Memblock::new();
-        assert!(mb.add(0x1000, 0x1000).is_ok());
-        assert_eq!(mb.memory_count, 1);
-        assert_eq!(mb.total_memory(), 0x1000);
+        mb.add(0x1000, 0x8000).unwrap();
+        mb.reserve(0x3000, 0x1000).unwrap();
 
-        // Adding overlapping region should fail
-        assert!(mb.add(0x1800, 0x1000).is_err());
+        let mut pages = [0u64; 4];
+        assert_eq!(mb.alloc_pages_into(4, &mut pages), Ok(4));
+        // Skips the reserved page, all distinct and ascending
+        assert_eq!(pages, [0x1000, 0x2000, 0x4000, 0x5000]);
+        assert_eq!(mb.total_reserved(), 0x5000);
 
-        // Adding adjacent region should merge
-        assert!(mb.add(0x2000, 0x1000).is_ok());
-        assert_eq!(mb.memory_count, 1); // merged
-        assert_eq!(mb.total_memory(), 0x2000);
+        // Already allocated pages are not handed out again
+        let mut more = [0u64; 2];
+        assert_eq!(mb.alloc_pages_into(2, &mut more), Ok(2));
+        assert_eq!(more, [0x6000, 0x7000]);
     }
 
     #[test]
-    fn test_memblock_reserve() {
+    fn test_memblock_alloc_pages_into_partial() {
         let mut mb = Memblock::new();
-        mb.add(0x1000, 0x1000).unwrap();
-        mb.reserve(0x1200, 0x200).unwrap();
-        assert_eq!(mb.reserved_count, 1);
-        assert_eq!(mb.total_reserved(), 0x200);
+        mb.add(0x1000, 0x3000).unwrap();
+
+        let mut pages = [0u64; 8];
+        assert_eq!(mb.alloc_pages_into(5, &mut pages), Ok(3));
+        assert_eq!(&pages[..3], &[0x1000, 0x2000, 0x3000]);
+        assert_eq!(pages[3], 0);
+
+        // Nothing left at all
+        assert_eq!(
+            mb.alloc_pages_into(1, &mut pages),
+            Err(MemblockError::InsufficientMemory)
+        );
+        assert_eq!(mb.alloc_pages_into(0, &mut pages), Ok(0));
+        assert_eq!(
+            mb.alloc_pages_into(9, &mut pages),
+            Err(MemblockError::BadRequest)
+        );
     }
 
     #[test]
-    fn test_memblock_alloc() {
+    fn test_memblock_free() {
         let mut mb = Memblock::new();
-        mb.add(0x1000, 0x1000).unwrap();
-        mb.reserve(0x1200, 0x200).unwrap();
+        mb.add(0x0, 0x10000).unwrap();
+        mb.reserve_with_flags(0x1000, 0x4000, FLAG_NOMAP, ReservationOwner::Stack)
+            .unwrap();
+        mb.reserve(0x8000, 0x1000).unwrap();
 
-        // Allocate within available space
-        let addr = mb.alloc(0x100, 0x10).unwrap();
-        assert!(addr >= 0x1000 && addr + 0x100 <= 0x2000);
-        // Should be reserved now
-        assert_eq!(mb.reserved_count, 2);
+        // Punch a hole in the middle of the first reservation
+        mb.free(0x2000, 0x1000).unwrap();
+        assert_eq!(mb.reserved_regions().len(), 3);
+        assert_eq!(
+            mb.reserved_regions()[0],
+            Region::with_flags(0x1000, 0x1000, FLAG_NOMAP).with_owner(ReservationOwner::Stack)
+        );
+        assert_eq!(
+            mb.reserved_regions()[1],
+            Region::with_flags(0x3000, 0x2000, FLAG_NOMAP).with_owner(ReservationOwner::Stack)
+        );
 
-        // Add a non-adjacent memory region
-        mb.add(0x3000, 0x1000).unwrap();
-        // Allocate 0x1000 bytes, should go into the new region
-        let addr2 = mb.alloc(0x1000, 0x1).unwrap();
-        assert!(addr2 >= 0x3000 && addr2 + 0x1000 <= 0x4000);
+        // Freed memory can be allocated again
+        assert_eq!(mb.alloc(0x1000, 0x1000), Ok(0x0));
+        assert_eq!(mb.alloc(0x1000, 0x1000), Ok(0x2000));
+
+        // Free spanning several reservations and unreserved space
+        mb.free(0x0, 0x10000).unwrap();
+        assert_eq!(mb.reserved_regions().len(), 0);
+        assert_eq!(mb.free(0x0, 0x1000), Ok(()));
     }
 
     #[test]
-    fn test_memblock_remove() {
+    fn test_memblock_reserve_all() {
         let mut mb = Memblock::new();
-        mb.add(0x1000, 0x1000).unwrap();
-        mb.remove(0x1800, 0x400).unwrap();
-        assert_eq!(mb.memory_count, 2); // split into two regions
-        assert_eq!(mb.total_memory(), 0xc00); // 0x1000 - 0x400
+        mb.add(0x4000_0000, 0x1000_0000).unwrap();
+        mb.reserve(0x4800_0000, 0x1000).unwrap();
+
+        mb.reserve_all(&[
+            (0x4008_0000, 0x20_0000, 0),
+            (0x4400_0000, 0, 0),
+            (0x4900_0000, 0x1000, FLAG_NOMAP),
+        ])
+        .unwrap();
+        assert_eq!(mb.reserved_regions().len(), 3);
+        assert_eq!(mb.reserved_regions()[2].flags, FLAG_NOMAP);
     }
 
     #[test]
-    fn test_memblock_merge() {
+    fn test_memblock_reserve_all_conflict() {
         let mut mb = Memblock::new();
-        mb.add(0x1000, 0x1000).unwrap();
-        mb.add(0x3000, 0x1000).unwrap();
-        // Not adjacent, so two regions
-        assert_eq!(mb.memory_count, 2);
+        mb.add(0x4000_0000, 0x1000_0000).unwrap();
+        mb.reserve(0x4800_0000, 0x1000).unwrap();
+        let reserved = mb.reserved_regions().to_vec();
 
-        let mut mb2 = Memblock::new();
-        mb2.add(0x1000, 0x1000).unwrap();
-        mb2.add(0x3000, 0x1000).unwrap();
-        // Now add adjacent region that bridges the gap
-        mb2.add(0x2000, 0x1000).unwrap();
-        // Should merge into one region (all three are adjacent)
-        assert_eq!(mb2.memory_count, 1);
-        assert_eq!(mb2.total_memory(), 0x3000);
+        // The third range overlaps an existing reservation
+        let ranges = [
+            (0x4008_0000, 0x20_0000, 0),
+            (0x4400_0000, 0x1000, 0),
+            (0x47ff_f000, 0x2000, 0),
+        ];
+        assert_eq!(mb.reserve_all(&ranges), Err(MemblockError::Overlap));
+        assert_eq!(mb.reserved_regions(), reserved.as_slice());
+
+        // Ranges overlapping each other are rejected as well
+        let ranges = [(0x4008_0000, 0x2000, 0), (0x4008_1000, 0x2000, 0)];
+        assert_eq!(mb.reserve_all(&ranges), Err(MemblockError::Overlap));
+        assert_eq!(mb.reserved_regions(), reserved.as_slice());
+        assert!(mb.validate().is_ok());
+    }
+
+    #[test]
+    fn test_memblock_snapshot_restore() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x1000_0000).unwrap();
+        mb.reserve_tagged(0x4008_0000, 0x20_0000, ReservationOwner::KernelImage)
+            .unwrap();
+
+        let snap = mb.snapshot();
+        let memory = mb.memory_regions().to_vec();
+        let reserved = mb.reserved_regions().to_vec();
+        let stats = mb.stats();
+        assert_eq!(snap.memory_regions(), memory.as_slice());
+        assert_eq!(snap.reserved_regions(), reserved.as_slice());
+
+        // Speculative changes to both lists
+        mb.add(0x8000_0000, 0x1000_0000).unwrap();
+        mb.alloc_tagged(0x1000, 0x1000, ReservationOwner::PageTable)
+            .unwrap();
+        mb.reserve(0x4400_0000, 0x10_0000).unwrap();
+        mb.remove(0x4f00_0000, 0x100_0000).unwrap();
+        assert_ne!(mb.reserved_regions(), reserved.as_slice());
+
+        mb.restore(&snap);
+        assert_eq!(mb.memory_regions(), memory.as_slice());
+        assert_eq!(mb.reserved_regions(), reserved.as_slice());
+        assert_eq!(mb.stats(), stats);
+        assert!(mb.validate().is_ok());
+
+        // A snapshot can be restored more than once
+        let base = mb.alloc(0x1000, 0x1000).unwrap();
+        mb.restore(&snap);
+        assert_eq!(mb.alloc(0x1000, 0x1000), Ok(base));
+    }
+
+    /// Snapshot of 256MB of memory with `reserved` reserved.
+    fn reserved_snapshot(reserved: &[(u64, u64, u64, ReservationOwner)]) -> MemblockSnapshot {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x1000_0000).unwrap();
+        for &(base, size, flags, owner) in reserved {
+            mb.reserve_with_flags(base, size, flags, owner).unwrap();
+        }
+        mb.snapshot()
+    }
+
+    fn diff_string(before: &MemblockSnapshot, after: &MemblockSnapshot) -> String {
+        let mut out = String::new();
+        diff(before, after, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_memblock_same_reservation() {
+        let heap = Region::new(0x4000_0000, 0x1000).with_owner(ReservationOwner::Heap);
+        let grown = Region::new(0x4000_0000, 0x2000).with_owner(ReservationOwner::Heap);
+        let next = Region::new(0x4000_1000, 0x1000).with_owner(ReservationOwner::Heap);
+        assert!(same_reservation(&heap, &grown));
+        // Touching is not overlapping
+        assert!(!same_reservation(&heap, &next));
+        assert!(!same_reservation(
+            &heap,
+            &grown.with_owner(ReservationOwner::Cma)
+        ));
+    }
+
+    #[test]
+    fn test_memblock_diff_grown_added_removed() {
+        use ReservationOwner::*;
+        let before = reserved_snapshot(&[
+            (0x4000_0000, 0x1000, 0, Dtb),
+            (0x4008_0000, 0x8_0000, 0, KernelImage),
+            (0x4010_0000, 0x10_0000, 0, Heap),
+        ]);
+        let after = reserved_snapshot(&[
+            (0x4008_0000, 0x8_0000, 0, KernelImage),
+            (0x4010_0000, 0x20_0000, 0, Heap),
+            (0x4040_0000, 0x1000, 0, PageTable),
+            (0x4050_0000, 0x2000, 0, Other),
+        ]);
+        assert_eq!(
+            diff_string(&before, &after),
+            "  - [0x40000000-0x40001000) 0x1000 dtb\n\
+             \x20 ~ [0x40100000-0x40200000) -> [0x40100000-0x40300000) +0x100000 heap\n\
+             \x20 + [0x40400000-0x40401000) 0x1000 pagetable\n\
+             \x20 + [0x40500000-0x40502000) 0x2000\n\
+             \x20 net +0x102000 reserved (0x181000 -> 0x283000)\n"
+        );
+        // Backwards, every change is undone
+        assert_eq!(
+            diff_string(&after, &before),
+            "  + [0x40000000-0x40001000) 0x1000 dtb\n\
+             \x20 ~ [0x40100000-0x40300000) -> [0x40100000-0x40200000) -0x100000 heap\n\
+             \x20 - [0x40400000-0x40401000) 0x1000 pagetable\n\
+             \x20 - [0x40500000-0x40502000) 0x2000\n\
+             \x20 net -0x102000 reserved (0x283000 -> 0x181000)\n"
+        );
+        assert_eq!(
+            diff_string(&before, &before),
+            "  (no changes)\n  net +0x0 reserved (0x181000 -> 0x181000)\n"
+        );
+    }
+
+    #[test]
+    fn test_memblock_diff_split_reservation() {
+        use ReservationOwner::Cma;
+        // Replaced by two adjacent halves that differ in flags, so they
+        // stay separate regions
+        let before = reserved_snapshot(&[(0x4100_0000, 0x20_0000, 0, Cma)]);
+        let after = reserved_snapshot(&[
+            (0x4100_0000, 0x10_0000, 0, Cma),
+            (0x4110_0000, 0x8_0000, FLAG_NOMAP, Cma),
+        ]);
+        assert_eq!(after.reserved_regions().len(), 2);
+        assert_eq!(
+            diff_string(&before, &after),
+            "  ~ [0x41000000-0x41200000) -> [0x41000000-0x41100000) [0x41100000-0x41180000) \
+             -0x80000 cma\n\
+             \x20 net -0x80000 reserved (0x200000 -> 0x180000)\n"
+        );
+        // Same range, new flags
+        let nomap = reserved_snapshot(&[(0x4100_0000, 0x20_0000, FLAG_NOMAP, Cma)]);
+        assert!(diff_string(&before, &nomap).starts_with("  ~ [0x41000000-0x41200000) -> "));
+    }
+
+    #[test]
+    fn test_memblock_diff_owner_change() {
+        use ReservationOwner::*;
+        // The DTB's old home was reused by an early allocation: that is
+        // not the DTB changing size
+        let before = reserved_snapshot(&[(0x4800_0000, 0x2000, 0, Dtb)]);
+        let after = reserved_snapshot(&[
+            (0x4800_0000, 0x1000, 0, EarlyAlloc),
+            (0x4900_0000, 0x2000, 0, Dtb),
+        ]);
+        assert_eq!(
+            diff_string(&before, &after),
+            "  - [0x48000000-0x48002000) 0x2000 dtb\n\
+             \x20 + [0x48000000-0x48001000) 0x1000 early_alloc\n\
+             \x20 + [0x49000000-0x49002000) 0x2000 dtb\n\
+             \x20 net +0x1000 reserved (0x2000 -> 0x3000)\n"
+        );
+    }
+
+    #[test]
+    fn test_memblock_transaction_rollback() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x1000_0000).unwrap();
+        mb.reserve_tagged(0x4008_0000, 0x20_0000, ReservationOwner::KernelImage)
+            .unwrap();
+        let memory = mb.memory_regions().to_vec();
+        let reserved = mb.reserved_regions().to_vec();
+        let stats = mb.stats();
+
+        // Three allocations, then a step that does not fit
+        let result = mb.transaction(|mb| {
+            mb.alloc_tagged(0x100_0000, 0x20_0000, ReservationOwner::Cma)?;
+            mb.alloc_tagged(0x10_0000, 0x1000, ReservationOwner::MemMap)?;
+            mb.alloc_tagged(0x1000, 0x1000, ReservationOwner::PageTable)?;
+            assert_eq!(mb.reserved_regions().len(), 4);
+            mb.alloc(0x2000_0000, 0x1000)?;
+            Ok(())
+        });
+        assert_eq!(result, Err(MemblockError::InsufficientMemory));
+        assert_eq!(mb.memory_regions(), memory.as_slice());
+        assert_eq!(mb.reserved_regions(), reserved.as_slice());
+        assert_eq!(mb.stats(), stats);
+
+        // Success keeps everything
+        let base = mb
+            .transaction(|mb| mb.alloc_tagged(0x1000, 0x1000, ReservationOwner::PageTable))
+            .unwrap();
+        assert!(mb.overlaps_owner(base, 0x1000, ReservationOwner::PageTable));
+    }
+
+    #[test]
+    fn test_memblock_transaction_nested() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x100_0000).unwrap();
+
+        let outer = mb.transaction(|mb| {
+            let kept = mb.alloc(0x1000, 0x1000).map_err(|e| e.as_str())?;
+            // The inner failure only undoes the inner allocation
+            let inner: Result<u64, &str> = mb.transaction(|mb| {
+                mb.alloc(0x1000, 0x1000).map_err(|e| e.as_str())?;
+                Err("inner step failed")
+            });
+            assert!(inner.is_err());
+            assert_eq!(mb.reserved_regions().len(), 1);
+            assert_eq!(mb.reserved_regions()[0].base, kept);
+            Ok::<_, &str>(kept)
+        });
+        assert!(outer.is_ok());
+        assert_eq!(mb.total_reserved(), 0x1000);
+
+        // An outer failure undoes a successful inner transaction too
+        let outer: Result<(), &str> = mb.transaction(|mb| {
+            mb.transaction(|mb| mb.alloc(0x1000, 0x1000))
+                .map_err(|e| e.as_str())?;
+            assert_eq!(mb.total_reserved(), 0x2000);
+            Err("outer step failed")
+        });
+        assert!(outer.is_err());
+        assert_eq!(mb.total_reserved(), 0x1000);
+    }
+
+    #[test]
+    fn test_memblock_global_transaction() {
+        let before = lock().snapshot();
+        let result: Result<(), &str> = transaction(|mb| {
+            mb.add(0x1_0000_0000, 0x10_0000).map_err(|e| e.as_str())?;
+            mb.alloc(0x1000, 0x1000).map_err(|e| e.as_str())?;
+            Err("rolled back")
+        });
+        assert!(result.is_err());
+        let mb = lock();
+        assert_eq!(mb.memory_regions(), before.memory_regions());
+        assert_eq!(mb.reserved_regions(), before.reserved_regions());
+    }
+
+    #[test]
+    fn test_memblock_summary_cell() {
+        let cell = SummaryCell::new();
+        assert_eq!(cell.read(), MemblockSummary::default());
+
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x10_0000).unwrap();
+        mb.reserve(0x4000_0000, 0x1000).unwrap();
+        let base = mb.alloc(0x2000, 0x1000).unwrap();
+        cell.publish(&mb.summary());
+        assert_eq!(
+            cell.read(),
+            MemblockSummary {
+                total_memory: 0x10_0000,
+                total_reserved: 0x3000,
+                memory_count: 1,
+                reserved_count: 2,
+                last_alloc_base: base,
+                last_alloc_size: 0x2000,
+            }
+        );
+
+        // A writer that never finished: the reader gives up, no hang
+        cell.seq.fetch_add(1, AtomicOrdering::Relaxed);
+        assert_eq!(cell.read().total_reserved, 0x3000);
+    }
+
+    #[test]
+    fn test_memblock_summary_follows_global_lock() {
+        let expected = {
+            let mut mb = lock();
+            mb.add(0x3_0000_0000, 0x10_0000).unwrap();
+            mb.alloc_at(0x3_0000_0000, 0x1000).unwrap();
+            mb.summary()
+        };
+        assert_eq!(summary_unlocked(), expected);
+        assert_eq!(expected.last_alloc_base, 0x3_0000_0000);
+
+        // Held: try_dump_to falls back to the summary instead of spinning
+        let held = lock();
+        let mut out = String::new();
+        try_dump_to(&mut out).unwrap();
+        drop(held);
+        assert!(out.ends_with("(lock held, summary only)\n"), "{}", out);
+        assert!(out.contains("last alloc [0x0000000300000000 - "), "{}", out);
+
+        let mb = Memblock::new();
+        let mut out = String::new();
+        write_dump(&mut out, Some(&mb)).unwrap();
+        assert_eq!(out, format!("{}\n", mb));
+    }
+
+    #[test]
+    fn test_memblock_panic_dump_renders_regions() {
+        // What the panic handler prints when the lock is free
+        let mut mb = Memblock::from_regions(&[(0x4000_0000, 0x10_0000)], &[]);
+        mb.reserve_tagged(0x4008_0000, 0x2_0000, ReservationOwner::KernelImage)
+            .unwrap();
+        let mut out = String::new();
+        write_dump(&mut out, Some(&mb)).unwrap();
+        assert!(out.starts_with("Memblock:\n"), "{}", out);
+        assert!(out.contains("0x0000000040000000"), "{}", out);
+        assert!(out.contains("0x0000000040080000"), "{}", out);
+        assert!(out.contains("kernel"), "{}", out);
+        assert!(!out.contains("lock held"), "{}", out);
+
+        // Held: one summary line and no region list, without spinning
+        let mut out = String::new();
+        write_dump(&mut out, None).unwrap();
+        assert_eq!(out.lines().count(), 1, "{}", out);
+        assert!(!out.contains("Memblock:"), "{}", out);
+    }
+
+    #[test]
+    fn test_memblock_alignment_padding() {
+        const MB: u64 = 1 << 20;
+
+        // The gap starts 1MB past a 2MB boundary
+        let mut mb = Memblock::from_regions(&[(0x4000_0000, 16 * MB)], &[(0x4000_0000, MB)]);
+        let a = mb.allocate(2 * MB, 2 * MB, ReservationOwner::Heap).unwrap();
+        assert_eq!(
+            a,
+            Allocation {
+                base: 0x4020_0000,
+                size: 2 * MB,
+                pad_before: MB,
+                pad_after: 0,
+            }
+        );
+        assert_eq!(mb.stats().pad_bytes, MB);
+
+        // Allocations that start at their gap's start add nothing
+        let b = mb.allocate(2 * MB, 2 * MB, ReservationOwner::Heap).unwrap();
+        assert_eq!((b.base, b.pad_before), (0x4040_0000, 0));
+        let c = mb
+            .allocate_range(MB, 2 * MB, 0x4010_0000, 0x4100_0000, ReservationOwner::Cma)
+            .unwrap();
+        assert_eq!((c.base, c.pad_before), (0x4060_0000, 0));
+        assert_eq!(mb.stats().pad_bytes, MB);
+
+        // A page taken from the head and the 2MB allocation freed: the
+        // next one skips the rest of the head again, and the total grows
+        assert_eq!(mb.alloc(0x1000, 0x1000), Ok(0x4010_0000));
+        mb.free(a.base, a.size).unwrap();
+        let d = mb.allocate(2 * MB, 2 * MB, ReservationOwner::Heap).unwrap();
+        assert_eq!((d.base, d.pad_before), (0x4020_0000, MB - 0x1000));
+        assert_eq!(mb.stats().pad_bytes, 2 * MB - 0x1000);
+
+        // Pads roll back with the allocator state
+        let snap = mb.snapshot();
+        mb.allocate(2 * MB, 2 * MB, ReservationOwner::Heap).unwrap();
+        mb.restore(&snap);
+        assert_eq!(mb.stats().pad_bytes, 2 * MB - 0x1000);
+        assert!(mb.validate().is_ok());
+    }
+
+    #[test]
+    fn test_memblock_padding_edges() {
+        // Page aligned allocations from page aligned gaps waste nothing
+        let mut mb = Memblock::from_regions(&[(0x4000_0000, 0x10_0000)], &[]);
+        for _ in 0..4 {
+            let a = mb
+                .allocate(0x2000, 0x1000, ReservationOwner::Stack)
+                .unwrap();
+            assert_eq!(a.padding(), 0);
+        }
+        assert_eq!(mb.stats().pad_bytes, 0);
+
+        // A sub-page tail left in the gap is padding too
+        let mut mb = Memblock::from_regions(&[(0x4000_0000, 0x2800)], &[]);
+        let a = mb
+            .allocate(0x2000, 0x1000, ReservationOwner::Stack)
+            .unwrap();
+        assert_eq!((a.pad_before, a.pad_after), (0, 0x800));
+
+        // Only the part of the gap inside the range counts
+        let mut mb = Memblock::from_regions(&[(0x4000_0000, 0x10_0000)], &[]);
+        let a = mb
+            .allocate_range(
+                0x1000,
+                0x4000,
+                0x4000_1000,
+                0x4001_0000,
+                ReservationOwner::Cma,
+            )
+            .unwrap();
+        assert_eq!((a.base, a.pad_before), (0x4000_4000, 0x3000));
+    }
+
+    /// Memory with free gaps of 0x3000, 0x1000, 0x2000 and 0x8000 bytes,
+    /// lowest first.
+    fn gapped() -> Memblock {
+        let mb = Memblock::from_regions(
+            &[(0x4000_0000, 0x2_0000)],
+            &[
+                (0x4000_3000, 0x1000),
+                (0x4000_5000, 0x1000),
+                (0x4000_8000, 0x1000),
+                (0x4001_1000, 0xf000),
+            ],
+        );
+        let mut free = Vec::new();
+        mb.for_each_free(|r| free.push((r.base, r.size)));
+        assert_eq!(
+            free,
+            [
+                (0x4000_0000, 0x3000),
+                (0x4000_4000, 0x1000),
+                (0x4000_6000, 0x2000),
+                (0x4000_9000, 0x8000),
+            ]
+        );
+        mb
+    }
+
+    #[test]
+    fn test_memblock_first_fit_vs_best_fit() {
+        let mut mb = gapped();
+        assert_eq!(mb.policy(), AllocPolicy::FirstFit);
+        // First fit takes the earliest gap that is large enough
+        assert_eq!(mb.alloc(0x2000, 0x1000), Ok(0x4000_0000));
+        assert_eq!(mb.alloc(0x1000, 0x1000), Ok(0x4000_2000));
+
+        let mut mb = gapped();
+        mb.set_policy(AllocPolicy::BestFit);
+        // Best fit takes the tightest gap: 0x2000 for 0x2000...
+        assert_eq!(mb.alloc(0x2000, 0x1000), Ok(0x4000_6000));
+        // ...the single page gap for a page...
+        assert_eq!(mb.alloc(0x1000, 0x1000), Ok(0x4000_4000));
+        // ...and 0x3000 over 0x8000 for something in between
+        assert_eq!(mb.alloc(0x2800, 0x1000), Ok(0x4000_0000));
+        // Only the large gap is left
+        assert_eq!(mb.alloc(0x1000, 0x1000), Ok(0x4000_9000));
+        assert!(mb.validate().is_ok());
+    }
+
+    #[test]
+    fn test_memblock_best_fit_constraints() {
+        let mut mb = gapped();
+        mb.set_policy(AllocPolicy::BestFit);
+        // Alignment counts: the exact 0x2000 gap is not 0x4000 aligned, so
+        // the next tightest one is taken
+        assert_eq!(mb.alloc(0x2000, 0x4000), Ok(0x4000_0000));
+        // The range clips gaps, so the clipped size is what is compared:
+        // [0x4000_9000, 0x4000_a000) is as tight as the one page gap, and
+        // the lower of the two wins
+        assert_eq!(
+            mb.alloc_range(
+                0x1000,
+                0x1000,
+                0x4000_4000,
+                0x4000_a000,
+                ReservationOwner::Cma
+            ),
+            Ok(0x4000_4000)
+        );
+        assert_eq!(
+            mb.alloc_range(
+                0x1000,
+                0x1000,
+                0x4000_7000,
+                0x4000_a000,
+                ReservationOwner::Cma
+            ),
+            Ok(0x4000_7000)
+        );
+        // Nothing fits: same error as first fit
+        assert_eq!(
+            mb.alloc(0x9000, 0x1000),
+            Err(MemblockError::InsufficientMemory)
+        );
+    }
+
+    #[test]
+    fn test_memblock_alloc_align_policy() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0001, 0x10_0000).unwrap();
+
+        // Not a power of two: rejected, nothing reserved
+        for align in [3, 0x1800, u64::MAX] {
+            assert_eq!(mb.alloc(0x10, align), Err(MemblockError::BadAlign));
+            assert_eq!(
+                mb.alloc_range(0x10, align, 0, u64::MAX, ReservationOwner::Cma),
+                Err(MemblockError::BadAlign)
+            );
+            assert_eq!(
+                mb.alloc_colored(0x10, align, 0x1000, 0),
+                Err(MemblockError::BadAlign)
+            );
+        }
+        assert_eq!(mb.total_reserved(), 0);
+
+        // Zero means unaligned, like 1
+        assert_eq!(mb.alloc(0x10, 0), Ok(0x4000_0001));
+        assert_eq!(mb.alloc(0x10, 1), Ok(0x4000_0011));
+
+        // Page alignment skips to the next page boundary
+        assert_eq!(mb.alloc(0x10, 0x1000), Ok(0x4000_1000));
+        assert!(mb.validate().is_ok());
+    }
+
+    #[test]
+    fn test_memblock_alloc_at_free() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x10_0000).unwrap();
+        mb.reserve(0x4000_0000, 0x1000).unwrap();
+
+        // Right after an existing reservation is still free
+        assert_eq!(mb.alloc_at(0x4000_1000, 0x1000), Ok(0x4000_1000));
+        assert_eq!(mb.alloc_at(0x400f_f000, 0x1000), Ok(0x400f_f000));
+        assert_eq!(mb.reserved_by(ReservationOwner::EarlyAlloc), 0x2000);
+
+        // Other allocations go around it
+        assert_eq!(mb.alloc(0x1000, 0x1000), Ok(0x4000_2000));
+        assert!(mb.validate().is_ok());
+    }
+
+    #[test]
+    fn test_memblock_alloc_at_reserved() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x10_0000).unwrap();
+        mb.reserve(0x4000_2000, 0x1000).unwrap();
+
+        for (base, size) in [
+            (0x4000_2000, 0x1000),
+            (0x4000_1800, 0x1000),
+            (0x4000_0000, 0x10_0000),
+        ] {
+            assert_eq!(mb.alloc_at(base, size), Err(MemblockError::Overlap));
+        }
+        assert_eq!(mb.alloc_at(0x4000_3000, 0), Err(MemblockError::Empty));
+        assert_eq!(mb.total_reserved(), 0x1000);
+    }
+
+    #[test]
+    fn test_memblock_alloc_at_outside_memory() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x1000).unwrap();
+        mb.add(0x4000_2000, 0x1000).unwrap();
+
+        // Below, straddling the end, across a hole and wrapping
+        for (base, size) in [
+            (0x1000, 0x1000),
+            (0x4000_0800, 0x1000),
+            (0x4000_0000, 0x3000),
+            (u64::MAX - 0xfff, 0x2000),
+        ] {
+            assert_eq!(
+                mb.alloc_at(base, size),
+                Err(MemblockError::InsufficientMemory)
+            );
+        }
+        assert_eq!(mb.total_reserved(), 0);
+        assert_eq!(mb.alloc_at(0x4000_2000, 0x1000), Ok(0x4000_2000));
+    }
+
+    const SLOT: FixedSlot = FixedSlot {
+        name: "test",
+        base: 0x4001_0000,
+        align: 0x1000,
+        limit: None,
+        owner: ReservationOwner::Cma,
+    };
+
+    #[test]
+    fn test_memblock_request_fixed_free() {
+        let mut mb = Memblock::from_regions(&[(0x4000_0000, 0x10_0000)], &[(0x4000_0000, 0x1000)]);
+        let mut log = String::new();
+
+        assert_eq!(mb.check_fixed(&SLOT, 0x4000), Ok(()));
+        assert_eq!(mb.request_fixed(&SLOT, 0x4000, &mut log), Ok(0x4001_0000));
+        assert_eq!(mb.reserved_by(ReservationOwner::Cma), 0x4000);
+        assert_eq!(log, "");
+        assert_eq!(
+            mb.request_fixed(&SLOT, 0, &mut log),
+            Err(MemblockError::Empty)
+        );
+    }
+
+    #[test]
+    fn test_memblock_request_fixed_relocated() {
+        // Only the first page of the slot is taken
+        let mut mb = Memblock::from_regions(&[(0x4000_0000, 0x10_0000)], &[(0x4001_0000, 0x1000)]);
+        let mut log = String::new();
+
+        assert_eq!(mb.check_fixed(&SLOT, 0x4000), Err(SlotConflict::Reserved));
+        assert_eq!(mb.request_fixed(&SLOT, 0x4000, &mut log), Ok(0x4000_0000));
+        assert_eq!(
+            log,
+            "fixed slot test relocated from 0x40010000 to 0x40000000 (already reserved)\n"
+        );
+        mb.assert_regions(
+            &[(0x4000_0000, 0x10_0000)],
+            &[(0x4000_0000, 0x4000), (0x4001_0000, 0x1000)],
+        );
+        assert_eq!(mb.reserved_by(ReservationOwner::Cma), 0x4000);
+    }
+
+    #[test]
+    fn test_memblock_request_fixed_outside_memory() {
+        let mut mb = Memblock::from_regions(&[(0x8000_0000, 0x10_0000)], &[]);
+        let mut log = String::new();
+
+        assert_eq!(
+            mb.check_fixed(&SLOT, 0x1000),
+            Err(SlotConflict::OutsideMemory)
+        );
+        assert_eq!(mb.request_fixed(&SLOT, 0x1000, &mut log), Ok(0x8000_0000));
+        assert!(log.ends_with("to 0x80000000 (not in memory)\n"));
+
+        // A slot running off the end of memory is outside too
+        let tail = FixedSlot {
+            base: 0x800f_f000,
+            ..SLOT
+        };
+        assert_eq!(
+            mb.check_fixed(&tail, 0x2000),
+            Err(SlotConflict::OutsideMemory)
+        );
+        let misaligned = FixedSlot {
+            base: 0x8000_2800,
+            ..SLOT
+        };
+        assert_eq!(
+            mb.check_fixed(&misaligned, 0x1000),
+            Err(SlotConflict::Misaligned)
+        );
+    }
+
+    #[test]
+    fn test_memblock_request_fixed_limit() {
+        let mut mb = Memblock::from_regions(&[(0x4000_0000, 0x10_0000)], &[(0x4001_0000, 0x1000)]);
+        let mut log = String::new();
+        let low = FixedSlot {
+            limit: Some(0x4000_2000),
+            ..SLOT
+        };
+
+        // Relocation stays below the limit, or fails without a message
+        assert_eq!(mb.request_fixed(&low, 0x2000, &mut log), Ok(0x4000_0000));
+        log.clear();
+        assert_eq!(
+            mb.request_fixed(&low, 0x2000, &mut log),
+            Err(MemblockError::InsufficientMemory)
+        );
+        assert_eq!(log, "");
+    }
+
+    /// Base of the last 4GB of the address space.
+    const TOP: u64 = 0xffff_ffff_0000_0000;
+
+    #[test]
+    fn test_memblock_region_at_top() {
+        let top = Region::new(TOP, 0x1_0000_0000);
+        assert_eq!(top.end(), u64::MAX);
+        assert_eq!(top.end_wide(), 1 << 64);
+        assert_eq!(top.last(), u64::MAX);
+        assert!(top.contains(u64::MAX));
+        assert!(top.overlaps(&Region::new(u64::MAX, 1)));
+        assert!(top.adjacent(&Region::new(TOP - 0x1000, 0x1000)));
+        assert_eq!(
+            format!("{}", top),
+            "[0xffffffff00000000 - 0x10000000000000000) (0x100000000 bytes)"
+        );
+
+        // Ending exactly at 2^64 is fine, one byte more is not
+        let mut mb = Memblock::new();
+        assert_eq!(mb.add(TOP, 0x1_0000_0001), Err(MemblockError::OutOfRange));
+        assert_eq!(mb.reserve(u64::MAX, 2), Err(MemblockError::OutOfRange));
+        mb.add(TOP, 0x1_0000_0000).unwrap();
+        mb.add(TOP - 0x1_0000_0000, 0x1_0000_0000).unwrap();
+        assert_eq!(
+            mb.memory_regions(),
+            [Region::new(TOP - 0x1_0000_0000, 0x2_0000_0000)]
+        );
+        assert!(mb.is_memory(u64::MAX - 0xfff, 0x1000));
+        assert!(!mb.is_memory(u64::MAX - 0xfff, 0x1001));
+        assert!(mb.validate_strict().is_ok());
+    }
+
+    #[test]
+    fn test_memblock_alloc_at_top() {
+        let mut mb = Memblock::new();
+        mb.add(TOP, 0x1_0000_0000).unwrap();
+        mb.reserve(TOP, 0xffff_e000).unwrap();
+
+        // Only the last two pages are free
+        assert_eq!(
+            mb.largest_free_block(),
+            Some(Region::new(u64::MAX - 0x1fff, 0x2000))
+        );
+        assert_eq!(mb.alloc(0x1000, 0x1000), Ok(u64::MAX - 0x1fff));
+        assert_eq!(mb.alloc_at(u64::MAX - 0xfff, 0x1000), Ok(u64::MAX - 0xfff));
+        assert_eq!(mb.alloc(0x1000, 0), Err(MemblockError::InsufficientMemory));
+
+        // Everything is taken and merged into one reservation ending at 2^64
+        assert_eq!(mb.reserved_regions().len(), 2);
+        mb.free(TOP, 0xffff_e000).unwrap();
+        assert_eq!(
+            mb.reserved_regions(),
+            [Region::new(u64::MAX - 0x1fff, 0x2000).with_owner(ReservationOwner::EarlyAlloc)]
+        );
+        assert!(mb.validate_strict().is_ok());
+
+        // Pages up to the very top are handed out
+        mb.free(u64::MAX - 0x1fff, 0x2000).unwrap();
+        let mut pages = [0; 4];
+        mb.reserve(TOP, 0xffff_d000).unwrap();
+        assert_eq!(mb.alloc_pages_into(4, &mut pages), Ok(3));
+        assert_eq!(pages[2], u64::MAX - 0xfff);
+    }
+
+    #[test]
+    fn test_memblock_remove_at_top() {
+        let mut mb = Memblock::new();
+        mb.add(TOP, 0x1_0000_0000).unwrap();
+        mb.reserve(u64::MAX - 0x2fff, 0x3000).unwrap();
+
+        // Removing the tail clips the reservation ending at 2^64
+        mb.remove(u64::MAX - 0xfff, 0x1000).unwrap();
+        assert_eq!(mb.memory_regions(), [Region::new(TOP, 0xffff_f000)]);
+        assert_eq!(
+            mb.reserved_regions(),
+            [Region::new(u64::MAX - 0x2fff, 0x2000)]
+        );
+
+        // A hole in the middle leaves a piece ending at 2^64
+        mb.add(u64::MAX - 0xfff, 0x1000).unwrap();
+        mb.remove(TOP + 0x1000, 0x1000).unwrap();
+        assert_eq!(
+            mb.memory_regions(),
+            [
+                Region::new(TOP, 0x1000),
+                Region::new(TOP + 0x2000, 0xffff_e000)
+            ]
+        );
+        assert!(mb.validate_strict().is_ok());
+    }
+
+    #[test]
+    fn test_memblock_free_checks_allocation_size() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x10_0000).unwrap();
+        let a = mb.alloc(0x4000, 0x1000).unwrap();
+        let b = mb.alloc(0x3000, 0x1000).unwrap();
+        // Merged into one reservation, the registry keeps them apart
+        assert_eq!(mb.reserved_regions().len(), 1);
+        assert_eq!(mb.allocations().len(), 2);
+
+        // Wrong size, part of an allocation, or spanning both
+        for (base, size) in [(a, 0x1000), (a, 0x5000), (a + 0x1000, 0x3000), (a, 0x7000)] {
+            assert_eq!(mb.free(base, size), Err(MemblockError::BadFree));
+        }
+        assert_eq!(mb.total_reserved(), 0x7000);
+
+        // The right size frees it and forgets it
+        mb.free(a, 0x4000).unwrap();
+        assert_eq!(mb.total_reserved(), 0x3000);
+        assert_eq!(
+            mb.allocations(),
+            [Region::new(b, 0x3000).with_owner(ReservationOwner::EarlyAlloc)]
+        );
+        assert_eq!(mb.free(b, 0x4000), Err(MemblockError::BadFree));
+        mb.free(b, 0x3000).unwrap();
+        assert!(mb.allocations().is_empty());
+        assert_eq!(mb.total_reserved(), 0);
+
+        // Single pages are not tracked and still free by range
+        let page = mb.alloc(0x1000, 0x1000).unwrap();
+        assert!(mb.allocations().is_empty());
+        mb.free(page, 0x1000).unwrap();
+    }
+
+    #[test]
+    fn test_memblock_allocation_registry_rollback() {
+        let mut mb = Memblock::new();
+        mb.add(0x4000_0000, 0x100_0000).unwrap();
+        let kept = mb.alloc_at(0x4000_0000, 0x2000).unwrap();
+
+        let result: Result<(), &str> = mb.transaction(|mb| {
+            mb.alloc(0x2000, 0).map_err(|e| e.as_str())?;
+            mb.free(kept, 0x2000).map_err(|e| e.as_str())?;
+            Err("abort")
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            mb.allocations(),
+            [Region::new(kept, 0x2000).with_owner(ReservationOwner::EarlyAlloc)]
+        );
+
+        // Full registry: multi-page allocations fail, pages still work
+        for _ in 1..MAX_ALLOCATIONS {
+            mb.alloc(0x2000, 0).unwrap();
+        }
+        assert_eq!(mb.alloc(0x2000, 0), Err(MemblockError::OutOfRegions));
+        assert!(mb.alloc(0x1000, 0).is_ok());
+        assert_eq!(mb.allocations().len(), MAX_ALLOCATIONS);
     }
 }
@@ -0,0 +1,435 @@
+//! Per-frame metadata and page reference counts.
+//!
+//! Every physical page frame has a [`PageFrame`] holding its state and the
+//! number of users sharing it. An allocated block starts with one
+//! reference, kept in the frame of its first page; [`MemMap::get_page`]
+//! adds one for each new user and [`MemMap::put_page`] drops one, handing
+//! the block back to its allocator when the last reference goes. Counts
+//! never wrap: a put on a frame without references and a get on a
+//! saturated one are refused.
+//!
+//! [`MemMap`] works on any frame slice and returns blocks through the
+//! release function it was built with. The kernel's frame array covers
+//! the pages of the buddy allocator and is built by `page_alloc::init`.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+/// Page size covered by one frame.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Size of one frame's metadata, for sizing the frame array.
+pub const FRAME_SIZE: u64 = size_of::<PageFrame>() as u64;
+
+/// State of a page frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameState {
+    /// Owned by the page allocator, no users.
+    Free,
+    /// Not usable RAM or not the page allocator's, never allocated.
+    Nomap,
+    /// Handed out, with at least one reference.
+    Allocated,
+}
+
+impl FrameState {
+    /// Decode a stored state byte.
+    const fn from_u8(val: u8) -> Self {
+        match val {
+            0 => Self::Free,
+            1 => Self::Nomap,
+            _ => Self::Allocated,
+        }
+    }
+
+    /// Returns the state name.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Free => "free",
+            Self::Nomap => "nomap",
+            Self::Allocated => "allocated",
+        }
+    }
+}
+
+/// Errors returned by the reference count operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// PFN not covered by the frame array.
+    OutOfRange,
+    /// Frame is not allocated.
+    NotAllocated(FrameState),
+    /// Frame has no references left to drop.
+    Underflow,
+    /// Reference count is at its maximum.
+    Saturated,
+    /// Explicit free of a frame other users still hold, with its count.
+    Shared(u32),
+}
+
+impl FrameError {
+    /// Returns a human readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::OutOfRange => "pfn outside mem_map",
+            Self::NotAllocated(_) => "page is not allocated",
+            Self::Underflow => "page reference count underflow",
+            Self::Saturated => "page reference count saturated",
+            Self::Shared(_) => "page still shared",
+        }
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAllocated(state) => write!(f, "{} ({})", self.as_str(), state.as_str()),
+            Self::Shared(count) => write!(f, "{} ({} references)", self.as_str(), count),
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+/// Metadata of one physical page frame.
+#[derive(Debug)]
+pub struct PageFrame {
+    /// A `FrameState`.
+    state: AtomicU8,
+    /// Order of the block starting at this frame, while allocated.
+    order: AtomicU8,
+    /// Number of references, 0 unless allocated.
+    count: AtomicU32,
+}
+
+impl PageFrame {
+    /// Create a frame in `state` with no references.
+    pub const fn new(state: FrameState) -> Self {
+        Self {
+            state: AtomicU8::new(state as u8),
+            order: AtomicU8::new(0),
+            count: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns the frame state.
+    pub fn state(&self) -> FrameState {
+        FrameState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    /// Returns the number of references.
+    #[cfg(test)]
+    pub fn count(&self) -> u32 {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Returns the order of the block starting at this frame.
+    pub fn order(&self) -> usize {
+        self.order.load(Ordering::Acquire) as usize
+    }
+}
+
+/// Frame metadata for a contiguous range of page frames.
+pub struct MemMap<'a> {
+    /// PFN of `frames[0]`.
+    base_pfn: u64,
+    /// One entry per page frame.
+    frames: &'a [PageFrame],
+    /// Returns a block to its allocator, given its physical address and
+    /// order.
+    release: fn(u64, usize),
+}
+
+impl<'a> MemMap<'a> {
+    /// Create a map of `frames`, starting at `base_pfn`.
+    ///
+    /// # Arguments
+    /// * `base_pfn` - PFN of the first frame
+    /// * `frames` - Frame metadata
+    /// * `release` - Called with the physical address and order of each
+    ///   block whose last reference is dropped
+    pub const fn new(base_pfn: u64, frames: &'a [PageFrame], release: fn(u64, usize)) -> Self {
+        Self {
+            base_pfn,
+            frames,
+            release,
+        }
+    }
+
+    /// Returns the metadata of `pfn`.
+    pub fn frame(&self, pfn: u64) -> Result<&PageFrame, FrameError> {
+        pfn.checked_sub(self.base_pfn)
+            .and_then(|index| self.frames.get(usize::try_from(index).ok()?))
+            .ok_or(FrameError::OutOfRange)
+    }
+
+    /// Mark free frame `pfn` allocated with one reference.
+    ///
+    /// Called by the page allocator when it hands out the block of
+    /// 2^`order` pages starting at `pfn`.
+    pub fn mark_allocated(&self, pfn: u64, order: usize) -> Result<(), FrameError> {
+        let frame = self.frame(pfn)?;
+        frame
+            .state
+            .compare_exchange(
+                FrameState::Free as u8,
+                FrameState::Allocated as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .map_err(|state| FrameError::NotAllocated(FrameState::from_u8(state)))?;
+        frame.order.store(order as u8, Ordering::Release);
+        frame.count.store(1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Take another reference to allocated frame `pfn`.
+    ///
+    /// # Returns
+    /// The new reference count
+    // Nothing shares pages between users yet
+    #[allow(dead_code)]
+    pub fn get_page(&self, pfn: u64) -> Result<u32, FrameError> {
+        let frame = self.frame(pfn)?;
+        let state = frame.state();
+        if state != FrameState::Allocated {
+            return Err(FrameError::NotAllocated(state));
+        }
+
+        // A count of zero means the last put is freeing the frame
+        frame
+            .count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| match count {
+                0 | u32::MAX => None,
+                _ => Some(count + 1),
+            })
+            .map(|count| count + 1)
+            .map_err(|count| match count {
+                0 => FrameError::NotAllocated(FrameState::Free),
+                _ => FrameError::Saturated,
+            })
+    }
+
+    /// Drop a reference to frame `pfn`, freeing it with the last one.
+    ///
+    /// # Returns
+    /// The remaining reference count; 0 means the page was released
+    pub fn put_page(&self, pfn: u64) -> Result<u32, FrameError> {
+        let frame = self.frame(pfn)?;
+        let Ok(count) = frame
+            .count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                count.checked_sub(1)
+            })
+        else {
+            report(pfn, FrameError::Underflow);
+            return Err(FrameError::Underflow);
+        };
+
+        if count == 1 {
+            self.release_frame(pfn, frame);
+        }
+        Ok(count - 1)
+    }
+
+    /// Free allocated frame `pfn` on behalf of its only user.
+    ///
+    /// The explicit counterpart of the last [`put_page`](Self::put_page):
+    /// a frame someone else still holds is refused, since freeing it would
+    /// leave them with a dangling page.
+    pub fn free_page(&self, pfn: u64) -> Result<(), FrameError> {
+        let frame = self.frame(pfn)?;
+        match frame
+            .count
+            .compare_exchange(1, 0, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => {
+                self.release_frame(pfn, frame);
+                Ok(())
+            }
+            Err(0) => Err(FrameError::NotAllocated(frame.state())),
+            Err(count) => {
+                report(pfn, FrameError::Shared(count));
+                Err(FrameError::Shared(count))
+            }
+        }
+    }
+
+    /// Returns the reference count of `pfn`.
+    #[cfg(test)]
+    pub fn page_count(&self, pfn: u64) -> Result<u32, FrameError> {
+        Ok(self.frame(pfn)?.count())
+    }
+
+    /// Mark `frame` free and return its block to the allocator.
+    fn release_frame(&self, pfn: u64, frame: &PageFrame) {
+        let order = frame.order();
+        frame.state.store(FrameState::Free as u8, Ordering::Release);
+        (self.release)(pfn * PAGE_SIZE, order);
+    }
+}
+
+/// Log a refused reference count operation.
+#[cfg(target_os = "none")]
+fn report(pfn: u64, err: FrameError) {
+    use crate::arch::serial;
+    use core::fmt::Write;
+
+    let _ = writeln!(serial::Writer, "mem_map: pfn {:#x}: {}", pfn, err);
+}
+
+/// Host builds only return the error.
+#[cfg(not(target_os = "none"))]
+fn report(_pfn: u64, _err: FrameError) {}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    std::thread_local! {
+        /// Blocks handed to the release function.
+        static RELEASED: RefCell<Vec<(u64, usize)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn release(addr: u64, order: usize) {
+        RELEASED.with(|r| r.borrow_mut().push((addr, order)));
+    }
+
+    fn released() -> Vec<(u64, usize)> {
+        RELEASED.with(|r| r.take())
+    }
+
+    /// Four frames from PFN 0x40000: three free RAM pages, one hole.
+    fn frames() -> [PageFrame; 4] {
+        [
+            PageFrame::new(FrameState::Free),
+            PageFrame::new(FrameState::Free),
+            PageFrame::new(FrameState::Free),
+            PageFrame::new(FrameState::Nomap),
+        ]
+    }
+
+    const BASE: u64 = 0x40000;
+
+    #[test]
+    fn test_share_then_put_frees_once() {
+        let frames = frames();
+        let map = MemMap::new(BASE, &frames, release);
+
+        map.mark_allocated(BASE + 1, 0).unwrap();
+        assert_eq!(map.page_count(BASE + 1), Ok(1));
+        assert_eq!(map.get_page(BASE + 1), Ok(2));
+
+        assert_eq!(map.put_page(BASE + 1), Ok(1));
+        assert!(released().is_empty());
+        assert_eq!(map.put_page(BASE + 1), Ok(0));
+        assert_eq!(released(), [(0x4000_1000, 0)]);
+        assert_eq!(map.frame(BASE + 1).unwrap().state(), FrameState::Free);
+
+        // Free again, so it can be handed out again
+        map.mark_allocated(BASE + 1, 0).unwrap();
+        assert_eq!(map.page_count(BASE + 1), Ok(1));
+    }
+
+    #[test]
+    fn test_double_put_detected() {
+        let frames = frames();
+        let map = MemMap::new(BASE, &frames, release);
+
+        map.mark_allocated(BASE, 0).unwrap();
+        assert_eq!(map.put_page(BASE), Ok(0));
+        assert_eq!(map.put_page(BASE), Err(FrameError::Underflow));
+        assert_eq!(map.page_count(BASE), Ok(0));
+        assert_eq!(released(), [(0x4000_0000, 0)]);
+    }
+
+    #[test]
+    fn test_block_released_whole() {
+        let frames = frames();
+        let map = MemMap::new(BASE, &frames, release);
+
+        // Only the first frame of a block carries its count
+        map.mark_allocated(BASE, 1).unwrap();
+        assert_eq!(map.frame(BASE).unwrap().order(), 1);
+        assert_eq!(map.page_count(BASE + 1), Ok(0));
+        assert_eq!(map.free_page(BASE), Ok(()));
+        assert_eq!(released(), [(0x4000_0000, 1)]);
+    }
+
+    #[test]
+    fn test_free_shared_page_refused() {
+        let frames = frames();
+        let map = MemMap::new(BASE, &frames, release);
+
+        map.mark_allocated(BASE + 2, 0).unwrap();
+        map.get_page(BASE + 2).unwrap();
+        map.get_page(BASE + 2).unwrap();
+        let err = map.free_page(BASE + 2).unwrap_err();
+        assert_eq!(err, FrameError::Shared(3));
+        assert_eq!(err.to_string(), "page still shared (3 references)");
+        assert!(released().is_empty());
+
+        map.put_page(BASE + 2).unwrap();
+        map.put_page(BASE + 2).unwrap();
+        assert_eq!(map.free_page(BASE + 2), Ok(()));
+        assert_eq!(released(), [(0x4000_2000, 0)]);
+        assert_eq!(
+            map.free_page(BASE + 2),
+            Err(FrameError::NotAllocated(FrameState::Free))
+        );
+    }
+
+    #[test]
+    fn test_get_requires_allocated_frame() {
+        let frames = frames();
+        let map = MemMap::new(BASE, &frames, release);
+
+        assert_eq!(
+            map.get_page(BASE),
+            Err(FrameError::NotAllocated(FrameState::Free))
+        );
+        let err = map.get_page(BASE + 3).unwrap_err();
+        assert_eq!(err, FrameError::NotAllocated(FrameState::Nomap));
+        assert_eq!(err.to_string(), "page is not allocated (nomap)");
+        assert_eq!(
+            map.mark_allocated(BASE + 3, 0),
+            Err(FrameError::NotAllocated(FrameState::Nomap))
+        );
+        assert_eq!(map.get_page(BASE + 4), Err(FrameError::OutOfRange));
+        assert_eq!(map.page_count(BASE - 1), Err(FrameError::OutOfRange));
+    }
+
+    #[test]
+    fn test_count_saturates() {
+        let frames = frames();
+        let map = MemMap::new(BASE, &frames, release);
+
+        map.mark_allocated(BASE, 0).unwrap();
+        frames[0].count.store(u32::MAX - 1, Ordering::Relaxed);
+        assert_eq!(map.get_page(BASE), Ok(u32::MAX));
+        assert_eq!(map.get_page(BASE), Err(FrameError::Saturated));
+        assert_eq!(map.page_count(BASE), Ok(u32::MAX));
+    }
+
+    #[test]
+    fn test_concurrent_get_put() {
+        let frames = frames();
+        let map = MemMap::new(BASE, &frames, release);
+        map.mark_allocated(BASE, 0).unwrap();
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        map.get_page(BASE).unwrap();
+                        map.put_page(BASE).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(map.page_count(BASE), Ok(1));
+        assert_eq!(map.put_page(BASE), Ok(0));
+        assert_eq!(released(), [(0x4000_0000, 0)]);
+    }
+}
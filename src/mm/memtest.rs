@@ -0,0 +1,201 @@
+//! Boot-time memory test (`memtest=<passes>`).
+//!
+//! Free RAM is tested in chunks of [`CHUNK_SIZE`], cut at chunk-aligned
+//! addresses. Every pass fills a chunk with a walking bit pattern and
+//! reads it back; a chunk that does not read back what was written is
+//! reported once and not tested further, so the caller can remove it from
+//! memblock before anything is allocated there.
+//!
+//! Memory is reached through [`PhysMem`], so the chunk walk and failure
+//! handling run on the host against a buffer.
+
+use crate::mm::memblock::Region;
+
+/// Granule in which bad memory is isolated.
+pub const CHUNK_SIZE: u64 = 1 << 20;
+
+/// Size of one tested word.
+const WORD: u64 = 8;
+
+/// Word access to physical memory.
+pub trait PhysMem {
+    /// Write `value` to the word at `phys`.
+    fn write(&mut self, phys: u64, value: u64);
+
+    /// Read the word at `phys`.
+    fn read(&mut self, phys: u64) -> u64;
+}
+
+/// Returns the value written to word `index` of a chunk in `pass`.
+///
+/// Even passes walk a single one bit across the words, odd passes a
+/// single zero bit. The starting bit moves with the pass, so every bit
+/// of every word sees both values over 128 passes.
+pub const fn pattern(pass: u32, index: u64) -> u64 {
+    let seed: u64 = if pass.is_multiple_of(2) { 1 } else { !1 };
+    seed.rotate_left(((index + (pass / 2) as u64) % 64) as u32)
+}
+
+/// Split `range` at chunk-aligned addresses.
+///
+/// The ends are trimmed to whole words.
+pub fn chunks(range: Region) -> impl Iterator<Item = Region> {
+    let start = range.base.next_multiple_of(WORD);
+    let end = range.end() & !(WORD - 1);
+    let mut cursor = start;
+    core::iter::from_fn(move || {
+        if cursor >= end {
+            return None;
+        }
+        let next = (cursor & !(CHUNK_SIZE - 1)).saturating_add(CHUNK_SIZE);
+        let chunk = Region::new(cursor, next.min(end) - cursor);
+        cursor = next;
+        Some(chunk)
+    })
+}
+
+/// Run one pass over `chunk`.
+///
+/// # Returns
+/// True if every word read back what was written
+pub fn test_chunk(mem: &mut impl PhysMem, chunk: Region, pass: u32) -> bool {
+    let words = chunk.size / WORD;
+    for i in 0..words {
+        mem.write(chunk.base + i * WORD, pattern(pass, i));
+    }
+    (0..words).all(|i| mem.read(chunk.base + i * WORD) == pattern(pass, i))
+}
+
+/// Test every chunk of the `free` ranges for `passes` passes.
+///
+/// # Arguments
+/// * `mem` - Access to the memory under test
+/// * `free` - Ranges that nothing uses yet
+/// * `passes` - Number of passes per chunk
+/// * `bad` - Called once for every failing chunk
+///
+/// # Returns
+/// The number of failing chunks
+pub fn run(
+    mem: &mut impl PhysMem,
+    free: &[Region],
+    passes: u32,
+    mut bad: impl FnMut(Region),
+) -> usize {
+    let mut failed = 0;
+    for &range in free {
+        for chunk in chunks(range) {
+            if (0..passes).any(|pass| !test_chunk(mem, chunk, pass)) {
+                bad(chunk);
+                failed += 1;
+            }
+        }
+    }
+    failed
+}
+
+/// Physical memory through the linear map.
+#[cfg(target_os = "none")]
+pub struct LinearMap;
+
+#[cfg(target_os = "none")]
+impl PhysMem for LinearMap {
+    fn write(&mut self, phys: u64, value: u64) {
+        let va = crate::arch::address::translation::phys_to_virt(phys);
+        // Safety: the caller only tests free RAM, which the linear map covers
+        unsafe { core::ptr::write_volatile(va as *mut u64, value) };
+    }
+
+    fn read(&mut self, phys: u64) -> u64 {
+        let va = crate::arch::address::translation::phys_to_virt(phys);
+        // Safety: as for `write`
+        unsafe { core::ptr::read_volatile(va as *const u64) }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    /// Memory backed by a vector, with one optionally stuck-at-zero bit.
+    struct Buffer {
+        base: u64,
+        words: Vec<u64>,
+        stuck: Option<(u64, u64)>,
+    }
+
+    impl Buffer {
+        fn new(base: u64, size: u64) -> Self {
+            Self {
+                base,
+                words: vec![0; (size / WORD) as usize],
+                stuck: None,
+            }
+        }
+    }
+
+    impl PhysMem for Buffer {
+        fn write(&mut self, phys: u64, mut value: u64) {
+            if let Some((addr, mask)) = self.stuck
+                && addr == phys
+            {
+                value &= !mask;
+            }
+            self.words[((phys - self.base) / WORD) as usize] = value;
+        }
+
+        fn read(&mut self, phys: u64) -> u64 {
+            self.words[((phys - self.base) / WORD) as usize]
+        }
+    }
+
+    #[test]
+    fn test_chunks() {
+        let split: Vec<_> = chunks(Region::new(0x4008_0004, 0x20_0000)).collect();
+        assert_eq!(
+            split,
+            [
+                Region::new(0x4008_0008, 0x8_0000 - 8),
+                Region::new(0x4010_0000, CHUNK_SIZE),
+                Region::new(0x4020_0000, 0x8_0000),
+            ]
+        );
+        assert_eq!(chunks(Region::new(0x1000, 4)).count(), 0);
+        assert_eq!(chunks(Region::new(0x10_0000, CHUNK_SIZE)).count(), 1);
+    }
+
+    #[test]
+    fn test_pattern_walks() {
+        assert_eq!(pattern(0, 0), 1);
+        assert_eq!(pattern(0, 1), 2);
+        assert_eq!(pattern(0, 64), 1);
+        assert_eq!(pattern(1, 0), !1);
+        assert_eq!(pattern(1, 3), !8);
+        assert_eq!(pattern(2, 0), 2);
+    }
+
+    #[test]
+    fn test_run_isolates_fault() {
+        const BASE: u64 = 0x4000_0000;
+        let mut mem = Buffer::new(BASE, 3 * CHUNK_SIZE);
+        let free = [
+            Region::new(BASE, CHUNK_SIZE),
+            Region::new(BASE + CHUNK_SIZE + 0x1000, 2 * CHUNK_SIZE - 0x1000),
+        ];
+
+        let mut bad = Vec::new();
+        assert_eq!(run(&mut mem, &free, 2, |c| bad.push(c)), 0);
+        assert!(bad.is_empty());
+
+        // Bit 5 of one word in the second chunk never sets
+        mem.stuck = Some((BASE + CHUNK_SIZE + 0x2000, 1 << 5));
+        assert_eq!(run(&mut mem, &free, 2, |c| bad.push(c)), 1);
+        assert_eq!(
+            bad,
+            [Region::new(BASE + CHUNK_SIZE + 0x1000, CHUNK_SIZE - 0x1000)]
+        );
+
+        // Zero passes test nothing
+        assert_eq!(run(&mut mem, &free, 0, |_| panic!()), 0);
+    }
+}
@@ -1,3 +1,54 @@
 //! Memory management module for Phoenix kernel.
 
+// Charged through memblock everywhere, only reported on the target
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub mod accounting;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod cma;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod fixmap;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod heap;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod ioremap;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod kstack;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod layout;
 pub mod memblock;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod memmap;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod memtest;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod page_alloc;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod pmm;
+pub mod poison;
+#[cfg(any(all(target_os = "none", feature = "heap_debug"), test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod redzone;
+#[cfg(any(target_os = "none", test))]
+#[cfg_attr(test, allow(dead_code))]
+pub mod vmalloc;
+
+#[cfg(target_os = "none")]
+#[allow(unused_imports)]
+pub use ioremap::{DeviceAttr, MapError, ioremap, iounmap};
+#[cfg(target_os = "none")]
+#[allow(unused_imports)]
+pub use vmalloc::{vfree, vmalloc};
+
+/// A kernel virtual address.
+#[allow(dead_code)]
+pub type VirtAddr = u64;
@@ -0,0 +1,952 @@
+//! Zoned buddy page allocator.
+//!
+//! Physical pages are handed out in blocks of 2^order pages, up to
+//! [`MAX_ORDER`]. Freed blocks merge with their free buddy into the next
+//! order, so memory does not stay split after use.
+//!
+//! RAM is split into two zones at a boundary physical address:
+//!
+//! - [`ZoneId::Dma`]: RAM below the boundary, for devices that can only
+//!   address low memory
+//! - [`ZoneId::Normal`]: RAM at or above it
+//!
+//! [`GFP_DMA`] allocations only come from the DMA zone. Other allocations
+//! prefer the Normal zone and fall back to DMA once Normal is exhausted,
+//! so low memory is kept for the devices that need it. Frees go back to
+//! the zone the PFN lies in.
+//!
+//! Each zone tracks its free blocks in one bitmap per order: bit `i` of
+//! order `o` is set while the block of 2^o pages at index `i` is free and
+//! not merged into a larger one. Memory is handed over from memblock by
+//! `pmm::promote_to_buddy`, which runs as a late initcall.
+
+use crate::mm::memblock::{Memblock, RegionVec, ReservationOwner};
+use crate::mm::memmap::FrameError;
+#[cfg(target_os = "none")]
+use crate::mm::memmap::{self, FrameState, MemMap, PageFrame};
+use core::fmt;
+
+/// Page size in bytes.
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// Largest block order, 2^10 pages (4 MiB).
+pub const MAX_ORDER: usize = 10;
+
+/// Number of block orders.
+const ORDERS: usize = MAX_ORDER + 1;
+
+/// Free ranges [`PageAlloc::take_free`] collects from memblock per pass.
+const TAKE_BATCH: usize = 64;
+
+/// Pages in a largest block; zone bitmaps start on this alignment.
+const MAX_BLOCK_PAGES: u64 = 1 << MAX_ORDER;
+
+/// Default end of the DMA zone, the first 1 GiB of physical memory.
+pub const DEFAULT_DMA_LIMIT: u64 = 1 << 30;
+
+/// Allocation constraints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocFlags(u32);
+
+impl AllocFlags {
+    /// Returns true if all flags in `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Any zone, Normal first.
+pub const GFP_KERNEL: AllocFlags = AllocFlags(0);
+
+/// Only memory below the DMA boundary.
+pub const GFP_DMA: AllocFlags = AllocFlags(1 << 0);
+
+/// Memory zones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneId {
+    /// RAM below the DMA boundary.
+    Dma = 0,
+    /// RAM at or above the DMA boundary.
+    Normal = 1,
+}
+
+impl ZoneId {
+    /// Number of zones.
+    pub const COUNT: usize = 2;
+
+    /// Returns the zone name.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Dma => "DMA",
+            Self::Normal => "Normal",
+        }
+    }
+}
+
+/// Errors returned by the page allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// Order above [`MAX_ORDER`].
+    InvalidOrder,
+    /// No free block large enough in the allowed zones.
+    OutOfMemory,
+    /// Freed block is not inside a zone.
+    OutOfRange,
+    /// Freed address is not aligned to its order.
+    Misaligned,
+    /// Freed block is already free.
+    DoubleFree,
+    /// Freed with another order than it was allocated with.
+    WrongOrder,
+    /// The block's frame refused the operation.
+    Frame(FrameError),
+}
+
+impl AllocError {
+    /// Returns a human readable description of the error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::InvalidOrder => "allocation order too large",
+            Self::OutOfMemory => "out of pages",
+            Self::OutOfRange => "page outside all zones",
+            Self::Misaligned => "page not aligned to its order",
+            Self::DoubleFree => "page already free",
+            Self::WrongOrder => "block freed with the wrong order",
+            Self::Frame(e) => e.as_str(),
+        }
+    }
+}
+
+/// Page counts of one zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneStats {
+    /// Which zone.
+    pub zone: ZoneId,
+    /// Physical address span `[start, end)` of the zone.
+    pub start: u64,
+    pub end: u64,
+    /// Pages handed to the zone.
+    pub total_pages: u64,
+    /// Pages currently free.
+    pub free_pages: u64,
+    /// Free blocks per order, order 0 first.
+    pub free_blocks: [u64; ORDERS],
+}
+
+impl fmt::Display for ZoneStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<6} [{:#x}-{:#x}) {} pages, {} free",
+            self.zone.as_str(),
+            self.start,
+            self.end,
+            self.total_pages,
+            self.free_pages
+        )
+    }
+}
+
+/// Returns the number of blocks of order `order` covering the PFNs from
+/// `base_pfn` to `end_pfn`, which must be a largest block apart.
+const fn blocks(base_pfn: u64, end_pfn: u64, order: usize) -> u64 {
+    (end_pfn - base_pfn) >> order
+}
+
+/// Buddy free lists of one zone.
+pub struct Zone<'a> {
+    id: ZoneId,
+    /// PFN span `[start_pfn, end_pfn)` of the zone.
+    start_pfn: u64,
+    end_pfn: u64,
+    /// PFN of bit 0 in every bitmap, `start_pfn` aligned down to a
+    /// largest block so buddies are found by flipping an index bit.
+    base_pfn: u64,
+    /// Free block bitmaps of all orders, order 0 first.
+    bitmap: &'a mut [u64],
+    /// Word offset of each order's bitmap in `bitmap`.
+    offsets: [usize; ORDERS],
+    /// Free blocks per order.
+    free_blocks: [u64; ORDERS],
+    total_pages: u64,
+    free_pages: u64,
+}
+
+impl<'a> Zone<'a> {
+    /// Returns the PFN span the bitmaps of a zone over
+    /// `[start_pfn, end_pfn)` cover.
+    fn bitmap_span(start_pfn: u64, end_pfn: u64) -> (u64, u64) {
+        let base = start_pfn / MAX_BLOCK_PAGES * MAX_BLOCK_PAGES;
+        (base, end_pfn.next_multiple_of(MAX_BLOCK_PAGES).max(base))
+    }
+
+    /// Returns the number of bitmap words a zone over
+    /// `[start_pfn, end_pfn)` needs.
+    pub fn bitmap_words(start_pfn: u64, end_pfn: u64) -> usize {
+        let (base, end) = Self::bitmap_span(start_pfn, end_pfn);
+        (0..ORDERS)
+            .map(|order| blocks(base, end, order).div_ceil(64) as usize)
+            .sum()
+    }
+
+    /// Create an empty zone over `[start_pfn, end_pfn)`.
+    ///
+    /// # Arguments
+    /// * `id` - Which zone this is
+    /// * `start_pfn`, `end_pfn` - PFN span; empty if `end_pfn <= start_pfn`
+    /// * `bitmap` - At least [`bitmap_words`](Self::bitmap_words) words,
+    ///   cleared here
+    pub fn new(id: ZoneId, start_pfn: u64, end_pfn: u64, bitmap: &'a mut [u64]) -> Self {
+        let end_pfn = end_pfn.max(start_pfn);
+        let (base_pfn, span_end) = Self::bitmap_span(start_pfn, end_pfn);
+        assert!(
+            bitmap.len() >= Self::bitmap_words(start_pfn, end_pfn),
+            "zone bitmap too small"
+        );
+        bitmap.fill(0);
+
+        let mut offsets = [0; ORDERS];
+        let mut offset = 0;
+        for (order, slot) in offsets.iter_mut().enumerate() {
+            *slot = offset;
+            offset += blocks(base_pfn, span_end, order).div_ceil(64) as usize;
+        }
+
+        Self {
+            id,
+            start_pfn,
+            end_pfn,
+            base_pfn,
+            bitmap,
+            offsets,
+            free_blocks: [0; ORDERS],
+            total_pages: 0,
+            free_pages: 0,
+        }
+    }
+
+    /// Returns true if the block of 2^`order` pages at `pfn` lies in the
+    /// zone.
+    fn covers(&self, pfn: u64, order: usize) -> bool {
+        pfn >= self.start_pfn && pfn.saturating_add(1 << order) <= self.end_pfn
+    }
+
+    /// Returns the word index and bit mask of block `index` of `order`.
+    fn bit(&self, order: usize, index: u64) -> (usize, u64) {
+        (
+            self.offsets[order] + (index / 64) as usize,
+            1 << (index % 64),
+        )
+    }
+
+    fn test(&self, order: usize, index: u64) -> bool {
+        let (word, mask) = self.bit(order, index);
+        self.bitmap[word] & mask != 0
+    }
+
+    fn set(&mut self, order: usize, index: u64) {
+        let (word, mask) = self.bit(order, index);
+        self.bitmap[word] |= mask;
+        self.free_blocks[order] += 1;
+    }
+
+    fn clear(&mut self, order: usize, index: u64) {
+        let (word, mask) = self.bit(order, index);
+        self.bitmap[word] &= !mask;
+        self.free_blocks[order] -= 1;
+    }
+
+    /// Returns the index of the lowest free block of `order`.
+    fn first_free(&self, order: usize) -> Option<u64> {
+        let end = self.offsets.get(order + 1).copied();
+        let words = &self.bitmap[self.offsets[order]..end.unwrap_or(self.bitmap.len())];
+        words
+            .iter()
+            .position(|&w| w != 0)
+            .map(|i| i as u64 * 64 + words[i].trailing_zeros() as u64)
+    }
+
+    /// Hand the pages in `[pfn, pfn + pages)` to the zone as free.
+    ///
+    /// The range is clipped to the zone and carved into the largest
+    /// aligned blocks that fit.
+    pub fn add_free(&mut self, pfn: u64, pages: u64) {
+        let end = pfn.saturating_add(pages).min(self.end_pfn);
+        let mut pfn = pfn.max(self.start_pfn);
+        while pfn < end {
+            let align = (pfn - self.base_pfn).trailing_zeros() as usize;
+            let fit = (end - pfn).ilog2() as usize;
+            let order = align.min(fit).min(MAX_ORDER);
+            self.insert(pfn, order);
+            self.total_pages += 1 << order;
+            self.free_pages += 1 << order;
+            pfn += 1 << order;
+        }
+    }
+
+    /// Mark the block at `pfn` free, merging it with free buddies.
+    fn insert(&mut self, pfn: u64, order: usize) {
+        let mut order = order;
+        let mut index = (pfn - self.base_pfn) >> order;
+        while order < MAX_ORDER && self.test(order, index ^ 1) {
+            self.clear(order, index ^ 1);
+            index >>= 1;
+            order += 1;
+        }
+        self.set(order, index);
+    }
+
+    /// Allocate a block of 2^`order` pages.
+    ///
+    /// # Returns
+    /// The first PFN of the block, or `None` if no block is large enough
+    pub fn alloc(&mut self, order: usize) -> Option<u64> {
+        let found = (order..ORDERS).find(|&o| self.free_blocks[o] != 0)?;
+        let index = self.first_free(found)?;
+        self.clear(found, index);
+
+        // Keep the lower half of each split, free the upper one
+        let pfn = self.base_pfn + (index << found);
+        for split in (order..found).rev() {
+            self.set(split, ((pfn - self.base_pfn) >> split) + 1);
+        }
+        self.free_pages -= 1 << order;
+        Some(pfn)
+    }
+
+    /// Free the block of 2^`order` pages at `pfn`.
+    pub fn free(&mut self, pfn: u64, order: usize) -> Result<(), AllocError> {
+        if !self.covers(pfn, order) {
+            return Err(AllocError::OutOfRange);
+        }
+        if !(pfn - self.base_pfn).is_multiple_of(1 << order) {
+            return Err(AllocError::Misaligned);
+        }
+        // Free already if it or a block containing it is, or if part of
+        // it is: a free block of a lower order inside it
+        let offset = pfn - self.base_pfn;
+        let whole = (order..ORDERS).any(|o| self.test(o, offset >> o));
+        let part = (0..order).any(|o| {
+            let first = offset >> o;
+            (first..first + (1 << (order - o))).any(|index| self.test(o, index))
+        });
+        if whole || part {
+            return Err(AllocError::DoubleFree);
+        }
+
+        self.insert(pfn, order);
+        self.free_pages += 1 << order;
+        Ok(())
+    }
+
+    /// Returns the page counts of the zone.
+    pub fn stats(&self) -> ZoneStats {
+        ZoneStats {
+            zone: self.id,
+            start: self.start_pfn * PAGE_SIZE,
+            end: self.end_pfn * PAGE_SIZE,
+            total_pages: self.total_pages,
+            free_pages: self.free_pages,
+            free_blocks: self.free_blocks,
+        }
+    }
+}
+
+/// Buddy allocator over the DMA and Normal zones.
+pub struct PageAlloc<'a> {
+    zones: [Zone<'a>; ZoneId::COUNT],
+}
+
+impl<'a> PageAlloc<'a> {
+    /// Returns the PFN spans of the DMA and Normal zones for RAM in
+    /// `[start, end)` split at `dma_limit`.
+    fn zone_spans(start: u64, end: u64, dma_limit: u64) -> [(u64, u64); ZoneId::COUNT] {
+        let (start, end, limit) = (start / PAGE_SIZE, end / PAGE_SIZE, dma_limit / PAGE_SIZE);
+        [
+            (start, end.min(limit).max(start)),
+            (start.max(limit).min(end), end),
+        ]
+    }
+
+    /// Returns the number of bitmap words [`new`](Self::new) needs.
+    pub fn bitmap_words(start: u64, end: u64, dma_limit: u64) -> usize {
+        Self::zone_spans(start, end, dma_limit)
+            .iter()
+            .map(|&(s, e)| Zone::bitmap_words(s, e))
+            .sum()
+    }
+
+    /// Create an allocator for RAM in `[start, end)` with no free pages.
+    ///
+    /// # Arguments
+    /// * `start`, `end` - Physical span of RAM, page aligned
+    /// * `dma_limit` - End of the DMA zone, page aligned
+    /// * `bitmap` - At least [`bitmap_words`](Self::bitmap_words) words
+    pub fn new(start: u64, end: u64, dma_limit: u64, bitmap: &'a mut [u64]) -> Self {
+        let [dma, normal] = Self::zone_spans(start, end, dma_limit);
+        let (dma_bitmap, normal_bitmap) = bitmap.split_at_mut(Zone::bitmap_words(dma.0, dma.1));
+        Self {
+            zones: [
+                Zone::new(ZoneId::Dma, dma.0, dma.1, dma_bitmap),
+                Zone::new(ZoneId::Normal, normal.0, normal.1, normal_bitmap),
+            ],
+        }
+    }
+
+    /// Returns the zone `pfn` belongs to, if any.
+    pub fn zone_of(&self, pfn: u64) -> Option<ZoneId> {
+        self.zones
+            .iter()
+            .find(|zone| zone.covers(pfn, 0))
+            .map(|zone| zone.id)
+    }
+
+    /// Hand the free physical range `[base, base + size)` to the zones.
+    ///
+    /// Partial pages at either end are left out, and a range straddling
+    /// the DMA boundary is split between the zones.
+    pub fn add_free(&mut self, base: u64, size: u64) {
+        let first = base.div_ceil(PAGE_SIZE);
+        let end = base.saturating_add(size) / PAGE_SIZE;
+        if first >= end {
+            return;
+        }
+        for zone in &mut self.zones {
+            zone.add_free(first, end - first);
+        }
+    }
+
+    /// Take over every range `mb` still has free.
+    ///
+    /// The ranges are reserved in `mb` for the page allocator, so `mb`
+    /// has nothing left to allocate afterwards.
+    ///
+    /// # Returns
+    /// The number of bytes handed over
+    pub fn take_free(&mut self, mb: &mut Memblock) -> Result<u64, &'static str> {
+        let mut taken = 0;
+        loop {
+            // Collected first, reserving them changes the free list. A
+            // batch that fills up is finished on the next pass.
+            let mut free = RegionVec::<TAKE_BATCH>::new();
+            let mut more = false;
+            mb.for_each_free(|r| more |= free.push(r).is_err());
+            for r in free.iter() {
+                mb.reserve_tagged(r.base, r.size, ReservationOwner::PageAlloc)
+                    .map_err(|e| e.as_str())?;
+                self.add_free(r.base, r.size);
+            }
+            taken += free.total_size();
+            if !more {
+                return Ok(taken);
+            }
+        }
+    }
+
+    /// Allocate 2^`order` contiguous pages.
+    ///
+    /// # Returns
+    /// The physical address of the first page
+    pub fn alloc_pages(&mut self, order: usize, flags: AllocFlags) -> Result<u64, AllocError> {
+        if order > MAX_ORDER {
+            return Err(AllocError::InvalidOrder);
+        }
+        let order_of_zones: &[ZoneId] = if flags.contains(GFP_DMA) {
+            &[ZoneId::Dma]
+        } else {
+            &[ZoneId::Normal, ZoneId::Dma]
+        };
+        order_of_zones
+            .iter()
+            .find_map(|&id| self.zones[id as usize].alloc(order))
+            .map(|pfn| pfn * PAGE_SIZE)
+            .ok_or(AllocError::OutOfMemory)
+    }
+
+    /// Free 2^`order` pages at physical address `addr` back to their zone.
+    pub fn free_pages(&mut self, addr: u64, order: usize) -> Result<(), AllocError> {
+        if order > MAX_ORDER {
+            return Err(AllocError::InvalidOrder);
+        }
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(AllocError::Misaligned);
+        }
+        let pfn = addr / PAGE_SIZE;
+        let zone = self.zone_of(pfn).ok_or(AllocError::OutOfRange)?;
+        self.zones[zone as usize].free(pfn, order)
+    }
+
+    /// Returns the page counts of each zone, DMA first.
+    pub fn zone_stats(&self) -> [ZoneStats; ZoneId::COUNT] {
+        [self.zones[0].stats(), self.zones[1].stats()]
+    }
+}
+
+/// The kernel's page allocator, once `init` has run.
+#[cfg(target_os = "none")]
+static PAGE_ALLOC: spin::Mutex<Option<PageAlloc<'static>>> = spin::Mutex::new(None);
+
+/// Reference counts of the blocks the page allocator hands out, set up
+/// by `init` before the allocator itself.
+#[cfg(target_os = "none")]
+static MEM_MAP: spin::Once<MemMap<'static>> = spin::Once::new();
+
+/// Free a block whose last reference was dropped to its zone.
+///
+/// With `mm_debug_poison`, the block is filled with the poison pattern
+/// before anyone else can allocate it.
+#[cfg(target_os = "none")]
+fn release_block(addr: u64, order: usize) {
+    use crate::arch::serial;
+    use core::fmt::Write;
+
+    #[cfg(feature = "mm_debug_poison")]
+    crate::mm::poison::poison_range(addr, PAGE_SIZE << order);
+
+    let freed = PAGE_ALLOC
+        .lock()
+        .as_mut()
+        .map(|pa| pa.free_pages(addr, order));
+    if let Some(Err(e)) = freed {
+        let _ = writeln!(serial::Writer, "page_alloc: {:#x}: {}", addr, e.as_str());
+    }
+}
+
+/// Take over all memory memblock still has free, split at `dma_limit`.
+///
+/// The free ranges are reserved in memblock for the page allocator, so
+/// memblock must not be needed for allocations afterwards. Called through
+/// `pmm::promote_to_buddy`, which switches page allocations over.
+#[cfg(target_os = "none")]
+pub fn init(dma_limit: u64) -> Result<(), &'static str> {
+    use crate::arch::address::translation::phys_to_virt;
+    use crate::mm::accounting;
+    use crate::mm::memblock;
+
+    let mut mb = memblock::lock();
+    let (start, end) = match (mb.memory_regions().first(), mb.memory_regions().last()) {
+        (Some(first), Some(last)) => (first.base, last.end()),
+        _ => return Err("no memory"),
+    };
+
+    let words = PageAlloc::bitmap_words(start, end, dma_limit);
+    let bytes = (words as u64 * 8).next_multiple_of(PAGE_SIZE);
+    let slot = crate::mm::layout::phys::PAGE_ALLOC;
+    let bitmap_phys = mb
+        .request_fixed(&slot, bytes, &mut crate::arch::serial::Writer)
+        .map_err(|e| e.as_str())?;
+    // Tagged like the pages it tracks, which are not charged until allocated
+    accounting::charge(accounting::Category::MemMap, bytes);
+    // Safety: the bitmap was just allocated for the page allocator alone
+    // and stays reserved for the kernel's lifetime
+    let bitmap =
+        unsafe { core::slice::from_raw_parts_mut(phys_to_virt(bitmap_phys) as *mut u64, words) };
+
+    // One frame per page of RAM, free for the pages handed over below and
+    // outside the allocator for the rest
+    let base_pfn = start / PAGE_SIZE;
+    let frame_count = (end / PAGE_SIZE - base_pfn) as usize;
+    let map_bytes = (frame_count as u64 * memmap::FRAME_SIZE).next_multiple_of(PAGE_SIZE);
+    let map_phys = mb
+        .alloc_tagged(map_bytes, PAGE_SIZE, ReservationOwner::MemMap)
+        .map_err(|e| e.as_str())?;
+    // Safety: allocated for the frame array alone and reserved for the
+    // kernel's lifetime; every frame is written before it is read
+    let frames = unsafe {
+        core::slice::from_raw_parts_mut(phys_to_virt(map_phys) as *mut PageFrame, frame_count)
+    };
+    frames.fill_with(|| PageFrame::new(FrameState::Nomap));
+    mb.for_each_free(|r| {
+        for pfn in r.base.div_ceil(PAGE_SIZE)..r.end() / PAGE_SIZE {
+            frames[(pfn - base_pfn) as usize] = PageFrame::new(FrameState::Free);
+        }
+    });
+
+    let mut pa = PageAlloc::new(start, end, dma_limit, bitmap);
+    pa.take_free(&mut mb)?;
+
+    MEM_MAP.call_once(|| MemMap::new(base_pfn, frames, release_block));
+    *PAGE_ALLOC.lock() = Some(pa);
+    Ok(())
+}
+
+/// Allocate 2^`order` pages from the kernel's page allocator.
+///
+/// The block starts out with one reference, held by the caller. With
+/// `mm_debug_poison`, it is first checked for writes made while it was
+/// free.
+#[cfg(target_os = "none")]
+pub fn alloc_pages(order: usize, flags: AllocFlags) -> Result<u64, AllocError> {
+    let addr = PAGE_ALLOC
+        .lock()
+        .as_mut()
+        .ok_or(AllocError::OutOfMemory)?
+        .alloc_pages(order, flags)?;
+    // Set up before the allocator, so always there once a block is out
+    if let Some(map) = MEM_MAP.get() {
+        map.mark_allocated(addr / PAGE_SIZE, order)
+            .map_err(AllocError::Frame)?;
+    }
+
+    #[cfg(feature = "mm_debug_poison")]
+    crate::mm::poison::verify_range(addr, PAGE_SIZE << order);
+
+    Ok(addr)
+}
+
+/// Free 2^`order` pages at `addr` to the kernel's page allocator.
+///
+/// Only the block's sole user may free it: a block someone else still
+/// holds a reference to is refused. With `mm_debug_poison`, the freed
+/// block is poisoned.
+#[cfg(target_os = "none")]
+pub fn free_pages(addr: u64, order: usize) -> Result<(), AllocError> {
+    let map = MEM_MAP.get().ok_or(AllocError::OutOfRange)?;
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(AllocError::Misaligned);
+    }
+    let pfn = addr / PAGE_SIZE;
+    let frame = map.frame(pfn).map_err(AllocError::Frame)?;
+    if frame.state() == FrameState::Allocated && frame.order() != order {
+        return Err(AllocError::WrongOrder);
+    }
+    // Hands the block to `release_block` when the count drops to zero
+    map.free_page(pfn).map_err(AllocError::Frame)
+}
+
+/// Drop a reference to the block at `addr`, freeing it with the last one.
+///
+/// # Returns
+/// The references left; 0 means the block was freed
+#[cfg(target_os = "none")]
+pub fn put_page(addr: u64) -> Result<u32, AllocError> {
+    let map = MEM_MAP.get().ok_or(AllocError::OutOfRange)?;
+    map.put_page(addr / PAGE_SIZE).map_err(AllocError::Frame)
+}
+
+/// Returns the page counts of each zone, `None` before `init`.
+#[cfg(target_os = "none")]
+pub fn zone_stats() -> Option<[ZoneStats; ZoneId::COUNT]> {
+    PAGE_ALLOC.lock().as_ref().map(PageAlloc::zone_stats)
+}
+
+/// Write one line per zone to `w`, nothing before `init`.
+///
+/// Part of the boot report, which comes after the late initcalls have
+/// handed memblock's free memory over.
+#[cfg(target_os = "none")]
+pub fn write_zone_stats(w: &mut dyn fmt::Write) -> fmt::Result {
+    for stats in zone_stats().iter().flatten() {
+        writeln!(w, "Zone {}", stats)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1 << 20;
+
+    /// Allocator over RAM in `[start, end)` with `free` ranges handed over.
+    fn page_alloc<'a>(
+        start: u64,
+        end: u64,
+        limit: u64,
+        free: &[(u64, u64)],
+        bitmap: &'a mut Vec<u64>,
+    ) -> PageAlloc<'a> {
+        bitmap.resize(PageAlloc::bitmap_words(start, end, limit), 0);
+        let mut pa = PageAlloc::new(start, end, limit, bitmap);
+        for &(base, size) in free {
+            pa.add_free(base, size);
+        }
+        pa
+    }
+
+    fn free_pages(pa: &PageAlloc) -> [u64; ZoneId::COUNT] {
+        pa.zone_stats().map(|s| s.free_pages)
+    }
+
+    #[test]
+    fn test_zone_split_at_boundary() {
+        // 8 MiB of RAM straddling a boundary at 0x4040_0000
+        let mut bitmap = Vec::new();
+        let limit = 0x4040_0000;
+        let pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            limit,
+            &[(0x4000_0000, 8 * MB)],
+            &mut bitmap,
+        );
+        let [dma, normal] = pa.zone_stats();
+        assert_eq!((dma.start, dma.end), (0x4000_0000, limit));
+        assert_eq!((normal.start, normal.end), (limit, 0x4080_0000));
+        assert_eq!(dma.total_pages, 0x400);
+        assert_eq!(normal.total_pages, 0x400);
+        assert_eq!(free_pages(&pa), [0x400, 0x400]);
+
+        assert_eq!(
+            pa.zone_of((limit - PAGE_SIZE) / PAGE_SIZE),
+            Some(ZoneId::Dma)
+        );
+        assert_eq!(pa.zone_of(limit / PAGE_SIZE), Some(ZoneId::Normal));
+        assert_eq!(pa.zone_of(0x4080_0000 / PAGE_SIZE), None);
+    }
+
+    #[test]
+    fn test_zone_region_ending_at_boundary() {
+        // A region that ends exactly at the boundary is all DMA
+        let mut bitmap = Vec::new();
+        let limit = 0x4040_0000;
+        let pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            limit,
+            &[(0x4030_0000, MB), (limit, MB)],
+            &mut bitmap,
+        );
+        assert_eq!(free_pages(&pa), [0x100, 0x100]);
+    }
+
+    #[test]
+    fn test_alloc_dma_stays_low() {
+        let mut bitmap = Vec::new();
+        let limit = 0x4040_0000;
+        let mut pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            limit,
+            &[(0x4000_0000, 8 * MB)],
+            &mut bitmap,
+        );
+        for order in [0, 3, 0, MAX_ORDER - 1, 1] {
+            let addr = pa.alloc_pages(order, GFP_DMA).unwrap();
+            assert!(addr + (PAGE_SIZE << order) <= limit);
+        }
+
+        // Normal allocations prefer the high zone
+        let addr = pa.alloc_pages(0, GFP_KERNEL).unwrap();
+        assert_eq!(addr, limit);
+        assert_eq!(pa.zone_stats()[1].free_pages, 0x3ff);
+    }
+
+    #[test]
+    fn test_free_blocks_per_order() {
+        let mut bitmap = Vec::new();
+        let limit = 0x4040_0000;
+        let mut pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            limit,
+            &[(0x4000_0000, 8 * MB)],
+            &mut bitmap,
+        );
+        let mut whole = [0; ORDERS];
+        whole[MAX_ORDER] = 1;
+        assert_eq!(pa.zone_stats()[1].free_blocks, whole);
+
+        // Splitting the largest block leaves one buddy on every lower order
+        pa.alloc_pages(0, GFP_KERNEL).unwrap();
+        let mut split = [1; ORDERS];
+        split[MAX_ORDER] = 0;
+        assert_eq!(pa.zone_stats()[1].free_blocks, split);
+        assert_eq!(pa.zone_stats()[0].free_blocks, whole);
+    }
+
+    #[test]
+    fn test_alloc_dma_exhausted() {
+        let mut bitmap = Vec::new();
+        let limit = 0x4040_0000;
+        let mut pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            limit,
+            &[(0x4000_0000, 0x2000), (limit, 4 * MB)],
+            &mut bitmap,
+        );
+        pa.alloc_pages(1, GFP_DMA).unwrap();
+        // Normal memory is not used for DMA
+        assert_eq!(pa.alloc_pages(0, GFP_DMA), Err(AllocError::OutOfMemory));
+    }
+
+    #[test]
+    fn test_alloc_normal_falls_back_to_dma() {
+        let mut bitmap = Vec::new();
+        let limit = 0x4040_0000;
+        let mut pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            limit,
+            &[(0x4000_0000, 0x4000), (limit, 0x2000)],
+            &mut bitmap,
+        );
+        assert_eq!(pa.alloc_pages(1, GFP_KERNEL), Ok(limit));
+        assert_eq!(pa.alloc_pages(0, GFP_KERNEL), Ok(0x4000_0000));
+        assert_eq!(pa.alloc_pages(0, GFP_KERNEL), Ok(0x4000_1000));
+        assert_eq!(pa.alloc_pages(1, GFP_KERNEL), Ok(0x4000_2000));
+        assert_eq!(pa.alloc_pages(0, GFP_KERNEL), Err(AllocError::OutOfMemory));
+        assert_eq!(free_pages(&pa), [0, 0]);
+    }
+
+    #[test]
+    fn test_free_returns_to_zone() {
+        let mut bitmap = Vec::new();
+        let limit = 0x4040_0000;
+        let mut pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            limit,
+            &[(0x4000_0000, 8 * MB)],
+            &mut bitmap,
+        );
+        let low = pa.alloc_pages(2, GFP_DMA).unwrap();
+        let high = pa.alloc_pages(2, GFP_KERNEL).unwrap();
+        assert_eq!(free_pages(&pa), [0x3fc, 0x3fc]);
+
+        pa.free_pages(high, 2).unwrap();
+        assert_eq!(free_pages(&pa), [0x3fc, 0x400]);
+        pa.free_pages(low, 2).unwrap();
+        assert_eq!(free_pages(&pa), [0x400, 0x400]);
+
+        // Split blocks merged back: a largest block fits again
+        assert_eq!(pa.alloc_pages(MAX_ORDER, GFP_DMA), Ok(0x4000_0000));
+    }
+
+    #[test]
+    fn test_free_errors() {
+        let mut bitmap = Vec::new();
+        let mut pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            DEFAULT_DMA_LIMIT,
+            &[(0x4000_0000, 8 * MB)],
+            &mut bitmap,
+        );
+        let addr = pa.alloc_pages(1, GFP_KERNEL).unwrap();
+        assert_eq!(
+            pa.free_pages(addr + PAGE_SIZE, 1),
+            Err(AllocError::Misaligned)
+        );
+        assert_eq!(pa.free_pages(addr + 1, 0), Err(AllocError::Misaligned));
+        assert_eq!(pa.free_pages(0x8000_0000, 0), Err(AllocError::OutOfRange));
+        assert_eq!(
+            pa.free_pages(addr, MAX_ORDER + 1),
+            Err(AllocError::InvalidOrder)
+        );
+        assert_eq!(
+            pa.alloc_pages(MAX_ORDER + 1, GFP_KERNEL),
+            Err(AllocError::InvalidOrder)
+        );
+
+        pa.free_pages(addr, 1).unwrap();
+        assert_eq!(pa.free_pages(addr, 1), Err(AllocError::DoubleFree));
+        // Merged into a larger free block, still a double free
+        assert_eq!(pa.free_pages(addr, 0), Err(AllocError::DoubleFree));
+    }
+
+    #[test]
+    fn test_free_partly_free_block() {
+        let mut bitmap = Vec::new();
+        let mut pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            DEFAULT_DMA_LIMIT,
+            &[(0x4000_0000, 8 * MB)],
+            &mut bitmap,
+        );
+        let addr = pa.alloc_pages(2, GFP_KERNEL).unwrap();
+        let before = free_pages(&pa);
+
+        // The upper page of the lower half goes back, then the whole block
+        // is freed: refused without touching the bitmaps
+        pa.free_pages(addr + PAGE_SIZE, 0).unwrap();
+        assert_eq!(pa.free_pages(addr, 2), Err(AllocError::DoubleFree));
+        assert_eq!(pa.free_pages(addr, 1), Err(AllocError::DoubleFree));
+        assert_eq!(free_pages(&pa)[1], before[1] + 1);
+
+        // The rest of it can still be freed piece by piece
+        pa.free_pages(addr, 0).unwrap();
+        pa.free_pages(addr + 2 * PAGE_SIZE, 1).unwrap();
+        assert_eq!(free_pages(&pa)[1], before[1] + 4);
+    }
+
+    #[test]
+    fn test_take_free_past_one_batch() {
+        // More separate free ranges than one pass collects: RAM banks of
+        // a page with holes between them
+        let mut mb = Memblock::new();
+        for i in 0..TAKE_BATCH as u64 + 6 {
+            mb.add(0x4000_0000 + i * 2 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        }
+        let free = mb.stats().free();
+        assert_eq!(free, (TAKE_BATCH as u64 + 6) * PAGE_SIZE);
+
+        let mut bitmap = Vec::new();
+        let mut pa = page_alloc(
+            0x4000_0000,
+            0x4100_0000,
+            DEFAULT_DMA_LIMIT,
+            &[],
+            &mut bitmap,
+        );
+        assert_eq!(pa.take_free(&mut mb), Ok(free));
+        assert_eq!(mb.stats().free(), 0);
+        assert_eq!(free_pages(&pa)[1] * PAGE_SIZE, free);
+    }
+
+    #[test]
+    fn test_holes_never_merge() {
+        // RAM with a reserved hole: the free pages either side of it
+        // must not merge across
+        let mut bitmap = Vec::new();
+        let mut pa = page_alloc(
+            0x4000_0000,
+            0x4040_0000,
+            DEFAULT_DMA_LIMIT,
+            &[(0x4000_0000, 0x1000), (0x4000_2000, 0x3fe000)],
+            &mut bitmap,
+        );
+        assert_eq!(free_pages(&pa), [0, 0x3ff]);
+        let addr = pa.alloc_pages(0, GFP_KERNEL).unwrap();
+        assert_eq!(addr, 0x4000_0000);
+        pa.free_pages(addr, 0).unwrap();
+        assert_eq!(pa.alloc_pages(1, GFP_KERNEL), Ok(0x4000_2000));
+        assert_eq!(
+            pa.alloc_pages(MAX_ORDER, GFP_KERNEL),
+            Err(AllocError::OutOfMemory)
+        );
+    }
+
+    #[test]
+    fn test_add_free_unaligned() {
+        let mut bitmap = Vec::new();
+        let pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            DEFAULT_DMA_LIMIT,
+            // Partial pages at either end are dropped
+            &[(0x4000_0800, 0x3000)],
+            &mut bitmap,
+        );
+        // RAM above the default limit is all Normal
+        assert_eq!(free_pages(&pa), [0, 2]);
+    }
+
+    #[test]
+    fn test_zone_stats_display() {
+        let mut bitmap = Vec::new();
+        let pa = page_alloc(
+            0x4000_0000,
+            0x4080_0000,
+            0x4040_0000,
+            &[(0x4000_0000, 4 * MB)],
+            &mut bitmap,
+        );
+        assert_eq!(
+            pa.zone_stats()[0].to_string(),
+            "DMA    [0x40000000-0x40400000) 1024 pages, 1024 free"
+        );
+    }
+}
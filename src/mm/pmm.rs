@@ -0,0 +1,353 @@
+//! Physical memory manager: one page allocation API over memblock and the
+//! buddy allocator.
+//!
+//! Pages come from memblock until [`promote_to_buddy`] hands everything
+//! memblock has free to the buddy allocator, and from the buddy allocator
+//! after that. Callers use [`alloc_page`] and [`free_page`] in both
+//! phases and never need to know which allocator is active.
+//!
+//! Pages allocated from memblock before the switch stay memblock's: they
+//! are freed back to it even afterwards, as the buddy allocator never
+//! tracked them.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Which allocator hands out pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Early boot, pages come from memblock.
+    Memblock = 0,
+    /// Pages come from the buddy allocator.
+    Buddy = 1,
+}
+
+impl Phase {
+    /// Returns the phase name.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Memblock => "memblock",
+            Self::Buddy => "buddy",
+        }
+    }
+}
+
+/// A page allocator behind the façade.
+pub trait PageSource {
+    /// Allocate one page.
+    ///
+    /// # Returns
+    /// The physical address of the page
+    fn alloc_page(&self) -> Result<u64, &'static str>;
+
+    /// Free a page returned by `alloc_page`.
+    fn free_page(&self, phys: u64) -> Result<(), &'static str>;
+}
+
+/// The early allocator, which also knows what it handed over.
+pub trait EarlySource: PageSource {
+    /// Returns true if the page at `phys` was handed to the buddy
+    /// allocator by the promotion.
+    fn handed_over(&self, phys: u64) -> bool;
+}
+
+/// Routes page allocations to the allocator of the current [`Phase`].
+pub struct Pmm<E, B> {
+    phase: AtomicU8,
+    early: E,
+    buddy: B,
+}
+
+impl<E: EarlySource, B: PageSource> Pmm<E, B> {
+    /// Creates a façade in the memblock phase.
+    pub const fn new(early: E, buddy: B) -> Self {
+        Self {
+            phase: AtomicU8::new(Phase::Memblock as u8),
+            early,
+            buddy,
+        }
+    }
+
+    /// Returns the active phase.
+    pub fn phase(&self) -> Phase {
+        match self.phase.load(Ordering::Acquire) {
+            0 => Phase::Memblock,
+            _ => Phase::Buddy,
+        }
+    }
+
+    /// Allocate one page from the active allocator.
+    pub fn alloc_page(&self) -> Result<u64, &'static str> {
+        match self.phase() {
+            Phase::Memblock => self.early.alloc_page(),
+            Phase::Buddy => self.buddy.alloc_page(),
+        }
+    }
+
+    /// Free a page to the allocator it came from.
+    pub fn free_page(&self, phys: u64) -> Result<(), &'static str> {
+        match self.phase() {
+            Phase::Buddy if self.early.handed_over(phys) => self.buddy.free_page(phys),
+            _ => self.early.free_page(phys),
+        }
+    }
+
+    /// Switch to the buddy allocator once `hand_over` has given it
+    /// memblock's free memory.
+    ///
+    /// Boot runs this on one CPU before anything allocates concurrently.
+    ///
+    /// # Arguments
+    /// * `hand_over` - Sets up the buddy allocator from memblock
+    pub fn promote(
+        &self,
+        hand_over: impl FnOnce() -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        if self.phase() == Phase::Buddy {
+            return Err("already promoted to the buddy allocator");
+        }
+        hand_over()?;
+        self.phase.store(Phase::Buddy as u8, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Pages from the global memblock.
+#[cfg(target_os = "none")]
+struct GlobalMemblock;
+
+#[cfg(target_os = "none")]
+impl PageSource for GlobalMemblock {
+    fn alloc_page(&self) -> Result<u64, &'static str> {
+        use super::memblock::{self, ReservationOwner};
+        use super::page_alloc::PAGE_SIZE;
+
+        memblock::alloc_tagged(PAGE_SIZE, PAGE_SIZE, ReservationOwner::EarlyAlloc)
+            .map_err(|e| e.as_str())
+    }
+
+    fn free_page(&self, phys: u64) -> Result<(), &'static str> {
+        super::memblock::free(phys, super::page_alloc::PAGE_SIZE).map_err(|e| e.as_str())
+    }
+}
+
+#[cfg(target_os = "none")]
+impl EarlySource for GlobalMemblock {
+    fn handed_over(&self, phys: u64) -> bool {
+        use super::memblock::{self, ReservationOwner};
+
+        memblock::lock().overlaps_owner(
+            phys,
+            super::page_alloc::PAGE_SIZE,
+            ReservationOwner::PageAlloc,
+        )
+    }
+}
+
+/// Pages from the global buddy allocator.
+#[cfg(target_os = "none")]
+struct GlobalBuddy;
+
+#[cfg(target_os = "none")]
+impl PageSource for GlobalBuddy {
+    fn alloc_page(&self) -> Result<u64, &'static str> {
+        use super::page_alloc;
+
+        page_alloc::alloc_pages(0, page_alloc::GFP_KERNEL).map_err(|e| e.as_str())
+    }
+
+    fn free_page(&self, phys: u64) -> Result<(), &'static str> {
+        super::page_alloc::free_pages(phys, 0).map_err(|e| e.as_str())
+    }
+}
+
+/// The kernel's physical memory manager.
+#[cfg(target_os = "none")]
+static PMM: Pmm<GlobalMemblock, GlobalBuddy> = Pmm::new(GlobalMemblock, GlobalBuddy);
+
+/// Returns the allocator currently handing out pages.
+#[cfg(target_os = "none")]
+pub fn phase() -> Phase {
+    PMM.phase()
+}
+
+/// Allocate one page from whichever allocator is active.
+///
+/// # Returns
+/// The physical address of the page
+#[cfg(target_os = "none")]
+pub fn alloc_page() -> Result<u64, &'static str> {
+    PMM.alloc_page()
+}
+
+/// Free a page returned by `alloc_page`.
+///
+/// # Arguments
+/// * `phys` - Physical address of the page
+#[cfg(target_os = "none")]
+pub fn free_page(phys: u64) -> Result<(), &'static str> {
+    PMM.free_page(phys)
+}
+
+/// Hand all free memblock memory to the buddy allocator and allocate
+/// pages from it from now on.
+///
+/// Runs as the last stage of boot: memblock cannot allocate afterwards,
+/// so everything carving fixed regions out of it must be done by then.
+#[cfg(target_os = "none")]
+pub fn promote_to_buddy() -> Result<(), &'static str> {
+    use super::page_alloc;
+    use crate::arch::serial;
+    use core::fmt::Write;
+
+    PMM.promote(|| page_alloc::init(page_alloc::DEFAULT_DMA_LIMIT))?;
+    let _ = writeln!(serial::Writer, "pmm: pages from {}", phase().as_str());
+    Ok(())
+}
+
+initcall!(Late, "buddy", promote_to_buddy, optional);
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::mm::memblock::{Memblock, ReservationOwner};
+    use crate::mm::page_alloc::{GFP_KERNEL, PAGE_SIZE, PageAlloc};
+    use spin::Mutex;
+
+    const RAM_BASE: u64 = 0x4000_0000;
+    const RAM_SIZE: u64 = 0x40_0000;
+
+    /// A private memblock with 4 MiB of RAM.
+    struct TestMemblock(Mutex<Memblock>);
+
+    impl PageSource for TestMemblock {
+        fn alloc_page(&self) -> Result<u64, &'static str> {
+            self.0
+                .lock()
+                .alloc_tagged(PAGE_SIZE, PAGE_SIZE, ReservationOwner::EarlyAlloc)
+                .map_err(|e| e.as_str())
+        }
+
+        fn free_page(&self, phys: u64) -> Result<(), &'static str> {
+            self.0.lock().free(phys, PAGE_SIZE).map_err(|e| e.as_str())
+        }
+    }
+
+    impl EarlySource for TestMemblock {
+        fn handed_over(&self, phys: u64) -> bool {
+            self.0
+                .lock()
+                .overlaps_owner(phys, PAGE_SIZE, ReservationOwner::PageAlloc)
+        }
+    }
+
+    /// A private buddy allocator, empty until promotion.
+    struct TestBuddy<'a>(Mutex<Option<PageAlloc<'a>>>);
+
+    impl PageSource for TestBuddy<'_> {
+        fn alloc_page(&self) -> Result<u64, &'static str> {
+            let mut pa = self.0.lock();
+            let pa = pa.as_mut().ok_or("no buddy allocator")?;
+            pa.alloc_pages(0, GFP_KERNEL).map_err(|e| e.as_str())
+        }
+
+        fn free_page(&self, phys: u64) -> Result<(), &'static str> {
+            let mut pa = self.0.lock();
+            let pa = pa.as_mut().ok_or("no buddy allocator")?;
+            pa.free_pages(phys, 0).map_err(|e| e.as_str())
+        }
+    }
+
+    fn pmm<'a>() -> Pmm<TestMemblock, TestBuddy<'a>> {
+        let mut mb = Memblock::new();
+        mb.add(RAM_BASE, RAM_SIZE).unwrap();
+        Pmm::new(TestMemblock(Mutex::new(mb)), TestBuddy(Mutex::new(None)))
+    }
+
+    /// Set up the buddy allocator from what the memblock has free.
+    fn promote<'a>(pmm: &Pmm<TestMemblock, TestBuddy<'a>>, bitmap: &'a mut Vec<u64>) {
+        let end = RAM_BASE + RAM_SIZE;
+        bitmap.resize(PageAlloc::bitmap_words(RAM_BASE, end, end), 0);
+        pmm.promote(|| {
+            let mut pa = PageAlloc::new(RAM_BASE, end, end, bitmap);
+            pa.take_free(&mut pmm.early.0.lock())?;
+            *pmm.buddy.0.lock() = Some(pa);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    fn buddy_free_pages(pmm: &Pmm<TestMemblock, TestBuddy>) -> u64 {
+        let pa = pmm.buddy.0.lock();
+        pa.as_ref()
+            .unwrap()
+            .zone_stats()
+            .iter()
+            .map(|z| z.free_pages)
+            .sum()
+    }
+
+    #[test]
+    fn test_phase_switch() {
+        let pmm = pmm();
+        assert_eq!(pmm.phase(), Phase::Memblock);
+        assert_eq!(pmm.phase().as_str(), "memblock");
+
+        // A failed hand-over leaves memblock in charge
+        assert_eq!(pmm.promote(|| Err("no bitmap")), Err("no bitmap"));
+        assert_eq!(pmm.phase(), Phase::Memblock);
+
+        let mut bitmap = Vec::new();
+        promote(&pmm, &mut bitmap);
+        assert_eq!(pmm.phase(), Phase::Buddy);
+        assert_eq!(pmm.phase().as_str(), "buddy");
+        assert_eq!(
+            pmm.promote(|| Ok(())),
+            Err("already promoted to the buddy allocator")
+        );
+        assert_eq!(pmm.phase(), Phase::Buddy);
+    }
+
+    #[test]
+    fn test_alloc_in_both_phases() {
+        let pmm = pmm();
+        let early = pmm.alloc_page().unwrap();
+        let freed = pmm.alloc_page().unwrap();
+        assert_eq!(early % PAGE_SIZE, 0);
+        assert_ne!(early, freed);
+        pmm.free_page(freed).unwrap();
+        assert!(
+            !pmm.early
+                .0
+                .lock()
+                .overlaps_owner(freed, PAGE_SIZE, ReservationOwner::EarlyAlloc)
+        );
+
+        let mut bitmap = Vec::new();
+        promote(&pmm, &mut bitmap);
+        // Memblock has nothing left to give
+        assert!(pmm.early.0.lock().alloc(PAGE_SIZE, PAGE_SIZE).is_err());
+        assert_eq!(buddy_free_pages(&pmm), RAM_SIZE / PAGE_SIZE - 1);
+
+        let late = pmm.alloc_page().unwrap();
+        assert_ne!(late, early);
+        assert!(
+            pmm.early
+                .0
+                .lock()
+                .overlaps_owner(late, PAGE_SIZE, ReservationOwner::PageAlloc)
+        );
+        pmm.free_page(late).unwrap();
+        // A double free reaches the buddy allocator, not memblock
+        assert_eq!(pmm.free_page(late), Err("page already free"));
+
+        // The early page goes back to memblock, not into the buddy pool
+        pmm.free_page(early).unwrap();
+        assert_eq!(buddy_free_pages(&pmm), RAM_SIZE / PAGE_SIZE - 1);
+        assert!(
+            !pmm.early
+                .0
+                .lock()
+                .overlaps_owner(early, PAGE_SIZE, ReservationOwner::EarlyAlloc)
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Free-memory poisoning for catching use-after-free bugs.
+//!
+//! With the `mm_debug_poison` feature, memory released to the allocator is
+//! filled with [`POISON`] and checked again when it is handed out. Anything
+//! other than poison or zero in a fresh allocation means someone wrote to
+//! the memory while it was free.
+//!
+//! The fill and scan work on byte slices so they can be tested on the host;
+//! only [`poison_range`] and [`verify_range`] touch physical memory.
+
+use crate::stats::Counter;
+
+/// Pattern written over freed memory, as little-endian `u32`s.
+pub const POISON: u32 = 0xdead_beef;
+
+/// Number of allocations that found freed memory overwritten.
+pub static VIOLATIONS: Counter = Counter::new();
+
+/// Returns the number of poison violations detected so far.
+#[allow(dead_code)]
+pub fn poison_violations() -> u64 {
+    VIOLATIONS.get()
+}
+
+/// Returns the poison byte expected at physical address `addr`.
+///
+/// The pattern is anchored to 4-byte aligned addresses, so a range can be
+/// poisoned and checked in pieces that do not start on a word boundary.
+pub const fn poison_byte(addr: u64) -> u8 {
+    POISON.to_le_bytes()[(addr % 4) as usize]
+}
+
+/// Fill `buf` with the poison pattern.
+///
+/// # Arguments
+/// * `buf` - Memory to poison
+/// * `base` - Physical address of `buf[0]`
+#[allow(dead_code)]
+pub fn fill(buf: &mut [u8], base: u64) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = poison_byte(base + i as u64);
+    }
+}
+
+/// Find the first byte of `buf` that is neither poison nor zero.
+///
+/// # Arguments
+/// * `buf` - Memory about to be handed out
+/// * `base` - Physical address of `buf[0]`
+/// * `header` - Leading bytes written by the allocator itself, not checked
+///
+/// # Returns
+/// The offset of the first overwritten byte, or `None` if the memory is clean
+#[allow(dead_code)]
+pub fn find_overwrite(buf: &[u8], base: u64, header: usize) -> Option<usize> {
+    buf.iter()
+        .enumerate()
+        .skip(header)
+        .find(|&(i, &b)| b != 0 && b != poison_byte(base + i as u64))
+        .map(|(i, _)| i)
+}
+
+/// Poison `[phys, phys + size)` through the linear map.
+///
+/// The range must be RAM covered by the linear map.
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn poison_range(phys: u64, size: u64) {
+    let virt = crate::arch::address::translation::phys_to_virt(phys) as *mut u8;
+    for i in 0..size {
+        // Safety: the caller guarantees the range is mapped RAM that
+        // nobody owns
+        unsafe { core::ptr::write_volatile(virt.add(i as usize), poison_byte(phys + i)) };
+    }
+}
+
+/// Check `[phys, phys + size)` for writes made while it was free.
+///
+/// Logs the first overwritten address and counts a violation.
+///
+/// # Returns
+/// True if the range only holds poison or zero
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn verify_range(phys: u64, size: u64) -> bool {
+    use crate::arch::{address, serial};
+    use core::fmt::Write;
+
+    let virt = address::translation::phys_to_virt(phys);
+    // Safety: the caller just allocated the range and it is mapped RAM
+    let buf = unsafe { core::slice::from_raw_parts(virt as *const u8, size as usize) };
+
+    let Some(offset) = find_overwrite(buf, phys, 0) else {
+        return true;
+    };
+    VIOLATIONS.inc();
+    let _ = writeln!(
+        serial::Writer,
+        "POISON OVERWRITTEN at {:#x} (offset {:#x} of {:#x}+{:#x})",
+        phys + offset as u64,
+        offset,
+        phys,
+        size
+    );
+    false
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poison_fill_pattern() {
+        let mut buf = [0u8; 8];
+        fill(&mut buf, 0x1000);
+        assert_eq!(buf, [0xef, 0xbe, 0xad, 0xde, 0xef, 0xbe, 0xad, 0xde]);
+
+        // Anchored to the address, not the start of the buffer
+        let mut buf = [0u8; 4];
+        fill(&mut buf, 0x1002);
+        assert_eq!(buf, [0xad, 0xde, 0xef, 0xbe]);
+    }
+
+    #[test]
+    fn test_poison_clean_memory() {
+        let mut buf = [0u8; 64];
+        assert_eq!(find_overwrite(&buf, 0x2000, 0), None);
+        fill(&mut buf, 0x2000);
+        assert_eq!(find_overwrite(&buf, 0x2000, 0), None);
+
+        // Zeroed words inside a poisoned range are fine too
+        buf[8..16].fill(0);
+        assert_eq!(find_overwrite(&buf, 0x2000, 0), None);
+    }
+
+    #[test]
+    fn test_poison_first_mismatch() {
+        let mut buf = [0u8; 64];
+        fill(&mut buf, 0x3000);
+        buf[21] = 0x41;
+        buf[40] = 0x42;
+        assert_eq!(find_overwrite(&buf, 0x3000, 0), Some(21));
+
+        // The same bytes checked against a misaligned base don't match
+        let mut buf = [0u8; 8];
+        fill(&mut buf, 0x3001);
+        assert_eq!(find_overwrite(&buf, 0x3000, 0), Some(0));
+    }
+
+    #[test]
+    fn test_poison_skips_header() {
+        let mut buf = [0u8; 32];
+        fill(&mut buf, 0x4000);
+        buf[..8].copy_from_slice(&0x1234_5678_u64.to_le_bytes());
+        assert_eq!(find_overwrite(&buf, 0x4000, 0), Some(0));
+        assert_eq!(find_overwrite(&buf, 0x4000, 8), None);
+
+        buf[8] = 0x99;
+        assert_eq!(find_overwrite(&buf, 0x4000, 8), Some(8));
+        assert_eq!(find_overwrite(&buf, 0x4000, 64), None);
+    }
+}
@@ -0,0 +1,678 @@
+//! Heap redzones for catching out-of-bounds writes.
+//!
+//! [`RedzoneAlloc`] wraps a heap allocator (the kernel heap, with the
+//! `heap_debug` feature) and pads every allocation with guard zones of at
+//! least [`REDZONE`] bytes on both sides, filled with a canary derived
+//! from the block's address. A small header in front of the
+//! first zone records the requested size apart from the padded one, so a
+//! block can be checked without its `Layout`:
+//!
+//! ```text
+//! | header | front redzone | payload (requested) | rear redzone |
+//! 0        HEADER_SIZE     front                 front+requested padded
+//! ```
+//!
+//! `dealloc` verifies both zones and panics with a [`Report`] pinpointing
+//! the first bad byte on mismatch, then poisons the block. [`check_all`]
+//! walks every live allocation on demand.
+//!
+//! Arming, checking and report formatting work on byte slices so they can
+//! be tested on the host against corrupted buffers.
+//!
+//! [`check_all`]: RedzoneAlloc::check_all
+
+use super::poison;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use spin::Mutex;
+
+/// Smallest guard zone on each side of an allocation.
+pub const REDZONE: usize = 16;
+
+/// Bytes of header at the start of each padded block.
+pub const HEADER_SIZE: usize = 16;
+
+/// Most live allocations [`RedzoneAlloc::check_all`] can track.
+pub const MAX_TRACKED: usize = 1024;
+
+/// Returns the canary byte at `offset` of the block at `base`.
+///
+/// Mixed from the address so a zone copied from another block, or a stale
+/// pointer into one, does not pass for a good one.
+pub const fn canary_byte(base: u64, offset: usize) -> u8 {
+    let key = (base ^ (base >> 17)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    (key >> ((offset % 8) * 8)) as u8 ^ offset as u8
+}
+
+/// Placement of the payload in a padded block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guard {
+    /// Offset of the payload, the end of the front zone.
+    pub front: usize,
+    /// Total size of the block.
+    pub padded: usize,
+    /// Alignment of the block, at least the header's.
+    pub align: usize,
+}
+
+impl Guard {
+    /// Compute the padded block for a `size` byte allocation aligned to
+    /// `align`, a power of two.
+    ///
+    /// # Returns
+    /// The placement, or `None` if the block would not fit the header's
+    /// 32-bit fields
+    pub fn new(size: usize, align: usize) -> Option<Self> {
+        let align = align.max(HEADER_SIZE);
+        let front = (HEADER_SIZE + REDZONE).checked_next_multiple_of(align)?;
+        let padded = front
+            .checked_add(size)?
+            .checked_add(REDZONE)?
+            .checked_next_multiple_of(align)?;
+        u32::try_from(padded).ok()?;
+        Some(Self {
+            front,
+            padded,
+            align,
+        })
+    }
+
+    /// Returns the layout to request from the wrapped allocator.
+    pub fn layout(&self) -> Layout {
+        // Both were checked by `new`
+        Layout::from_size_align(self.padded, self.align).unwrap()
+    }
+}
+
+/// Header stored at the start of a padded block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Bytes the caller asked for.
+    pub requested: u64,
+    /// Offset of the payload.
+    pub front: u32,
+    /// Total size of the block.
+    pub padded: u32,
+}
+
+impl Header {
+    /// Decode the header at the start of `block`.
+    pub fn read(block: &[u8]) -> Self {
+        let word = |at: usize, len: usize| {
+            let mut bytes = [0; 8];
+            bytes[..len].copy_from_slice(&block[at..at + len]);
+            u64::from_le_bytes(bytes)
+        };
+        Self {
+            requested: word(0, 8),
+            front: word(8, 4) as u32,
+            padded: word(12, 4) as u32,
+        }
+    }
+
+    /// Encode the header into the start of `block`.
+    pub fn write(&self, block: &mut [u8]) {
+        block[0..8].copy_from_slice(&self.requested.to_le_bytes());
+        block[8..12].copy_from_slice(&self.front.to_le_bytes());
+        block[12..16].copy_from_slice(&self.padded.to_le_bytes());
+    }
+
+    /// Returns the range of the payload, if the header describes a block
+    /// of `len` bytes with room for both zones.
+    fn payload(&self, len: usize) -> Option<(usize, usize)> {
+        let front = self.front as usize;
+        let end = front.checked_add(usize::try_from(self.requested).ok()?)?;
+        let sane = self.padded as usize == len
+            && front >= HEADER_SIZE + REDZONE
+            && end.checked_add(REDZONE)? <= len;
+        sane.then_some((front, end))
+    }
+}
+
+/// Write the header and fill both zones of a freshly allocated block.
+///
+/// # Arguments
+/// * `block` - The whole padded block
+/// * `base` - Address of `block[0]`
+/// * `requested` - Bytes the caller asked for
+/// * `front` - Offset of the payload
+pub fn arm(block: &mut [u8], base: u64, requested: usize, front: usize) {
+    Header {
+        requested: requested as u64,
+        front: front as u32,
+        padded: block.len() as u32,
+    }
+    .write(block);
+    let rear = front + requested;
+    for offset in (HEADER_SIZE..front).chain(rear..block.len()) {
+        block[offset] = canary_byte(base, offset);
+    }
+}
+
+/// Which part of a block was found damaged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// The header does not describe the block.
+    Header,
+    /// The guard zone before the payload.
+    Front,
+    /// The guard zone after the payload.
+    Rear,
+}
+
+impl Zone {
+    /// Returns a short name for reports.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Header => "header",
+            Self::Front => "front redzone",
+            Self::Rear => "rear redzone",
+        }
+    }
+}
+
+/// Damage found by [`check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corruption {
+    /// Damaged part of the block.
+    pub zone: Zone,
+    /// Block offset of the start of the damaged part.
+    pub start: usize,
+    /// Block offset of the end (exclusive) of the damaged part.
+    pub end: usize,
+    /// Block offset of the first bad byte.
+    pub first: usize,
+    /// Number of bad bytes in the zone.
+    pub bad: usize,
+}
+
+/// Verify the header and both zones of a padded block.
+///
+/// # Arguments
+/// * `block` - The whole padded block
+/// * `base` - Address of `block[0]`
+///
+/// # Returns
+/// The first damaged part, front zone before rear
+pub fn check(block: &[u8], base: u64) -> Result<(), Corruption> {
+    let Some((front, rear)) = Header::read(block).payload(block.len()) else {
+        return Err(Corruption {
+            zone: Zone::Header,
+            start: 0,
+            end: HEADER_SIZE,
+            first: 0,
+            bad: HEADER_SIZE,
+        });
+    };
+
+    for (zone, start, end) in [
+        (Zone::Front, HEADER_SIZE, front),
+        (Zone::Rear, rear, block.len()),
+    ] {
+        let mut bad = (start..end).filter(|&offset| block[offset] != canary_byte(base, offset));
+        if let Some(first) = bad.next() {
+            return Err(Corruption {
+                zone,
+                start,
+                end,
+                first,
+                bad: 1 + bad.count(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Fill a freed block with the free-memory poison pattern.
+///
+/// # Arguments
+/// * `block` - The whole padded block
+/// * `base` - Address of `block[0]`
+pub fn poison_freed(block: &mut [u8], base: u64) {
+    poison::fill(block, base);
+}
+
+/// Corruption report for a damaged block, printed by the panic.
+pub struct Report<'a> {
+    /// Address of the block.
+    pub base: u64,
+    /// Layout the caller allocated with, if known.
+    pub layout: Option<Layout>,
+    /// The whole padded block.
+    pub block: &'a [u8],
+    /// What [`check`] found.
+    pub corruption: Corruption,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let c = &self.corruption;
+        let header = Header::read(self.block);
+        write!(
+            f,
+            "heap redzone corrupted: allocation {:#x}",
+            self.base + header.front as u64
+        )?;
+        match self.layout {
+            Some(layout) => write!(f, " (size {}, align {})", layout.size(), layout.align())?,
+            None => write!(f, " (size {})", header.requested)?,
+        }
+        writeln!(
+            f,
+            "\n{} at block {:#x} +{:#x}, {} of {} bytes bad",
+            c.zone.as_str(),
+            self.base,
+            c.first,
+            c.bad,
+            c.end - c.start
+        )?;
+
+        // Dump the zone a line at a time, marking the bad bytes below
+        for line in (c.start..c.end).step_by(16) {
+            let end = (line + 16).min(c.end);
+            write!(f, "  {:#x}:", self.base + line as u64)?;
+            for offset in line..end {
+                write!(f, " {:02x}", self.block[offset])?;
+            }
+            let bad = |offset: usize| {
+                c.zone == Zone::Header || self.block[offset] != canary_byte(self.base, offset)
+            };
+            if !(line..end).any(bad) {
+                writeln!(f)?;
+                continue;
+            }
+            write!(
+                f,
+                "\n  {:w$}",
+                "",
+                w = format_width(self.base + line as u64)
+            )?;
+            for offset in line..end {
+                f.write_str(if bad(offset) { " ^^" } else { "   " })?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Report<'_> {
+    /// Build the report for damage found in the live block at `base`.
+    ///
+    /// # Safety
+    /// The block must still be allocated, as for blocks reported by
+    /// [`RedzoneAlloc::check_all`].
+    pub unsafe fn live(base: u64, corruption: Corruption) -> Report<'static> {
+        // The report reads the header and the damaged zone, nothing past it
+        let len = corruption.end.max(HEADER_SIZE);
+        // Safety: per the caller the block, at least `len` bytes, is live
+        let block = unsafe { core::slice::from_raw_parts(base as *const u8, len) };
+        Report {
+            base,
+            layout: None,
+            block,
+            corruption,
+        }
+    }
+}
+
+/// Returns the width of `addr` printed as `{:#x}` followed by a colon.
+fn format_width(addr: u64) -> usize {
+    let digits = (64 - addr.leading_zeros() as usize).div_ceil(4).max(1);
+    2 + digits + 1
+}
+
+/// Address and size of the padded blocks currently handed out.
+struct Tracker {
+    blocks: [(u64, usize); MAX_TRACKED],
+    len: usize,
+    /// Allocations made while the tracker was full, not checked by
+    /// `check_all`.
+    untracked: u64,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        Self {
+            blocks: [(0, 0); MAX_TRACKED],
+            len: 0,
+            untracked: 0,
+        }
+    }
+
+    fn insert(&mut self, base: u64, padded: usize) {
+        if self.len == MAX_TRACKED {
+            self.untracked += 1;
+            return;
+        }
+        self.blocks[self.len] = (base, padded);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, base: u64) {
+        if let Some(i) = self.blocks[..self.len].iter().position(|&(b, _)| b == base) {
+            self.len -= 1;
+            self.blocks[i] = self.blocks[self.len];
+        }
+    }
+
+    fn check(&self) -> Result<usize, (u64, Corruption)> {
+        for &(base, padded) in &self.blocks[..self.len] {
+            // Safety: tracked blocks are live and `padded` bytes long
+            let block = unsafe { core::slice::from_raw_parts(base as *const u8, padded) };
+            check(block, base).map_err(|c| (base, c))?;
+        }
+        Ok(self.len)
+    }
+}
+
+/// Heap allocator wrapper adding redzones to every allocation of `A`.
+pub struct RedzoneAlloc<A> {
+    inner: A,
+    live: Mutex<Tracker>,
+}
+
+impl<A: GlobalAlloc> RedzoneAlloc<A> {
+    /// Wrap `inner`.
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            live: Mutex::new(Tracker::new()),
+        }
+    }
+
+    /// Verify the redzones of every tracked live allocation.
+    ///
+    /// # Returns
+    /// The number of allocations checked, or the address of the first
+    /// damaged block and its damage
+    pub fn check_all(&self) -> Result<usize, (u64, Corruption)> {
+        self.live.lock().check()
+    }
+
+    /// Like [`check_all`](Self::check_all), but gives up with `None`
+    /// instead of spinning if an allocation is being tracked, so it can
+    /// run from an interrupt handler.
+    pub fn try_check_all(&self) -> Option<Result<usize, (u64, Corruption)>> {
+        self.live.try_lock().map(|live| live.check())
+    }
+
+    /// Returns the wrapped allocator.
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Returns the number of allocations `check_all` could not track.
+    pub fn untracked(&self) -> u64 {
+        self.live.lock().untracked
+    }
+}
+
+// Safety: blocks come from `inner` with the padded layout and are returned
+// to it with the same layout; the payload handed out lies inside the block
+// at an offset that keeps the caller's alignment.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for RedzoneAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(guard) = Guard::new(layout.size(), layout.align()) else {
+            return core::ptr::null_mut();
+        };
+        // Safety: the padded layout is never zero sized
+        let ptr = unsafe { self.inner.alloc(guard.layout()) };
+        if ptr.is_null() {
+            return ptr;
+        }
+        // Safety: `inner` returned `guard.padded` writable bytes
+        let block = unsafe { core::slice::from_raw_parts_mut(ptr, guard.padded) };
+        arm(block, ptr as u64, layout.size(), guard.front);
+        self.live.lock().insert(ptr as u64, guard.padded);
+        // Safety: `front` is inside the block
+        unsafe { ptr.add(guard.front) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // The same layout gave a valid guard in `alloc`
+        let guard = Guard::new(layout.size(), layout.align()).unwrap();
+        // Safety: `ptr` was returned by `alloc` for this layout
+        let base = unsafe { ptr.sub(guard.front) };
+        // Safety: the whole padded block belongs to this allocation
+        let block = unsafe { core::slice::from_raw_parts_mut(base, guard.padded) };
+        if let Err(corruption) = check(block, base as u64) {
+            panic!(
+                "{}",
+                Report {
+                    base: base as u64,
+                    layout: Some(layout),
+                    block,
+                    corruption,
+                }
+            );
+        }
+        self.live.lock().remove(base as u64);
+        poison_freed(block, base as u64);
+        // Safety: allocated from `inner` with this layout
+        unsafe { self.inner.dealloc(base, guard.layout()) };
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    /// A padded block armed for a `size` byte allocation at `base`.
+    fn armed(base: u64, size: usize, align: usize) -> (Guard, Vec<u8>) {
+        let guard = Guard::new(size, align).unwrap();
+        let mut block = vec![0; guard.padded];
+        arm(&mut block, base, size, guard.front);
+        (guard, block)
+    }
+
+    #[test]
+    fn test_guard_layout() {
+        assert_eq!(
+            Guard::new(1, 1),
+            Some(Guard {
+                front: 32,
+                padded: 64,
+                align: 16
+            })
+        );
+        // The payload keeps large alignments and both zones stay whole
+        let guard = Guard::new(100, 64).unwrap();
+        assert_eq!((guard.front, guard.padded), (64, 192));
+        for size in 0..100 {
+            let guard = Guard::new(size, 8).unwrap();
+            assert!(guard.front >= HEADER_SIZE + REDZONE);
+            assert!(guard.padded - guard.front - size >= REDZONE);
+        }
+        assert_eq!(Guard::new(u32::MAX as usize, 16), None);
+        assert_eq!(Guard::new(usize::MAX - 8, 16), None);
+    }
+
+    #[test]
+    fn test_canary_depends_on_address() {
+        let a: Vec<u8> = (0..32).map(|i| canary_byte(0x4000_1000, i)).collect();
+        let b: Vec<u8> = (0..32).map(|i| canary_byte(0x4000_1040, i)).collect();
+        assert_ne!(a, b);
+        // Not a constant fill either
+        assert!(a.windows(2).any(|w| w[0] != w[1]));
+
+        // A zone copied from another block is caught
+        let (guard, mut block) = armed(0x4000_1000, 24, 8);
+        let (_, other) = armed(0x4000_1040, 24, 8);
+        block[HEADER_SIZE..guard.front].copy_from_slice(&other[HEADER_SIZE..guard.front]);
+        assert_eq!(check(&block, 0x4000_1000).unwrap_err().zone, Zone::Front);
+    }
+
+    #[test]
+    fn test_check_pinpoints_corruption() {
+        let base = 0x4010_0000;
+        let (guard, mut block) = armed(base, 20, 8);
+        assert_eq!(check(&block, base), Ok(()));
+        // Writing the payload is fine
+        block[guard.front..guard.front + 20].fill(0x41);
+        assert_eq!(check(&block, base), Ok(()));
+
+        // One byte past the end
+        let rear = guard.front + 20;
+        block[rear] ^= 0xff;
+        assert_eq!(
+            check(&block, base),
+            Err(Corruption {
+                zone: Zone::Rear,
+                start: rear,
+                end: guard.padded,
+                first: rear,
+                bad: 1,
+            })
+        );
+        block[rear] ^= 0xff;
+
+        // Underflow into the front zone, several bytes
+        block[guard.front - 3..guard.front].fill(0);
+        let c = check(&block, base).unwrap_err();
+        assert_eq!((c.zone, c.first, c.bad), (Zone::Front, guard.front - 3, 3));
+    }
+
+    #[test]
+    fn test_check_header() {
+        let (guard, mut block) = armed(0x1000, 16, 8);
+        assert_eq!(
+            Header::read(&block),
+            Header {
+                requested: 16,
+                front: guard.front as u32,
+                padded: guard.padded as u32
+            }
+        );
+        // A requested size running into the rear zone is not believed
+        Header {
+            requested: 40,
+            ..Header::read(&block)
+        }
+        .write(&mut block);
+        assert_eq!(check(&block, 0x1000).unwrap_err().zone, Zone::Header);
+    }
+
+    #[test]
+    fn test_report_format() {
+        let base = 0x4020_0000;
+        let (guard, mut block) = armed(base, 24, 8);
+        let first = guard.front + 24 + 2;
+        block[first] ^= 0xff;
+        block[first + 1] ^= 0xff;
+        let report = Report {
+            base,
+            layout: Some(Layout::from_size_align(24, 8).unwrap()),
+            block: &block,
+            corruption: check(&block, base).unwrap_err(),
+        }
+        .to_string();
+
+        let mut lines = report.lines();
+        assert_eq!(
+            lines.next(),
+            Some("heap redzone corrupted: allocation 0x40200020 (size 24, align 8)")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("rear redzone at block 0x40200000 +0x3a, 2 of 24 bytes bad")
+        );
+        // The zone starts at +0x38; the markers sit under its 3rd and 4th bytes
+        let dump = lines.next().unwrap();
+        assert!(dump.starts_with("  0x40200038: "), "{}", dump);
+        let marks = lines.next().unwrap();
+        let col = |n: usize| "  0x40200038:".len() + 3 * n + 1;
+        assert_eq!(&marks[col(2)..col(2) + 2], "^^");
+        assert_eq!(&marks[col(3)..col(3) + 2], "^^");
+        assert_eq!(marks.matches("^^").count(), 2);
+        assert!(lines.next().unwrap().starts_with("  0x40200048: "));
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn test_freed_block_poisoned() {
+        let (_, mut block) = armed(0x2000, 8, 8);
+        poison_freed(&mut block, 0x2000);
+        assert_eq!(poison::find_overwrite(&block, 0x2000, 0), None);
+        assert!(check(&block, 0x2000).is_err());
+    }
+
+    #[test]
+    fn test_randomized_no_false_positives() {
+        let heap = RedzoneAlloc::new(System);
+        let mut live: Vec<(*mut u8, Layout)> = Vec::new();
+        // xorshift64, fixed seed
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..5000 {
+            let r = next();
+            if live.len() < 200 && (r % 3 != 0 || live.is_empty()) {
+                let size = (r >> 8) as usize % 300;
+                let align = 1 << ((r >> 20) % 8);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = unsafe { heap.alloc(layout) };
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0);
+                // Use every byte handed out
+                unsafe { core::ptr::write_bytes(ptr, r as u8, size) };
+                live.push((ptr, layout));
+            } else {
+                let (ptr, layout) = live.swap_remove((r >> 8) as usize % live.len());
+                unsafe { heap.dealloc(ptr, layout) };
+            }
+            if r % 64 == 0 {
+                assert_eq!(heap.check_all(), Ok(live.len()));
+            }
+        }
+        for (ptr, layout) in live.drain(..) {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        assert_eq!(heap.check_all(), Ok(0));
+        assert_eq!(heap.untracked(), 0);
+    }
+
+    #[test]
+    fn test_check_all_finds_overflow() {
+        let heap = RedzoneAlloc::new(System);
+        let layout = Layout::from_size_align(10, 4).unwrap();
+        let a = unsafe { heap.alloc(layout) };
+        let b = unsafe { heap.alloc(layout) };
+        assert_eq!(heap.check_all(), Ok(2));
+
+        // Off by one on `b`
+        unsafe { b.add(10).write(0) };
+        let (base, corruption) = heap.check_all().unwrap_err();
+        assert_eq!(base + corruption.first as u64, b as u64 + 10);
+        assert_eq!(corruption.zone, Zone::Rear);
+
+        let report = unsafe { Report::live(base, corruption) }.to_string();
+        assert!(report.contains("rear redzone"), "{}", report);
+        assert_eq!(heap.try_check_all(), Some(Err((base, corruption))));
+
+        unsafe { b.add(10).write(canary_byte(base, corruption.first)) };
+        assert_eq!(heap.try_check_all(), Some(Ok(2)));
+        unsafe {
+            heap.dealloc(a, layout);
+            heap.dealloc(b, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "rear redzone")]
+    fn test_dealloc_panics_on_overflow() {
+        let heap = RedzoneAlloc::new(System);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr = unsafe { heap.alloc(layout) };
+        unsafe {
+            ptr.add(32).write(0x5a ^ *ptr.add(32));
+            heap.dealloc(ptr, layout);
+        }
+    }
+}
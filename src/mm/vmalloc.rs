@@ -0,0 +1,610 @@
+//! Virtually contiguous allocations (vmalloc).
+//!
+//! Large buffers do not need physically contiguous memory. `vmalloc`
+//! takes page-granular virtual space from the vmalloc window, backs every
+//! page with its own physical frame from the page allocator (memblock
+//! until it is up) and maps them Normal cacheable RW.
+//! Each area is followed by one unmapped guard page, so running off the
+//! end faults instead of reaching the next area.
+//!
+//! The frame source and the page table updates go through the
+//! [`FrameProvider`] and [`Mapper`] traits, so the bookkeeping can be
+//! tested on the host.
+
+use crate::arch::address;
+#[cfg(target_os = "none")]
+use crate::mm::VirtAddr;
+use crate::mm::ioremap::{MapError, VaWindow};
+use spin::Mutex;
+
+/// Maximum number of live vmalloc areas.
+const MAX_AREAS: usize = 32;
+
+/// Pages unmapped at a time when an area is released.
+const RELEASE_BATCH: usize = 16;
+
+/// Unmapped pages after each area.
+pub const GUARD_PAGES: u64 = 1;
+
+/// Errors returned by the vmalloc API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// Requested size is zero or overflows.
+    InvalidSize,
+    /// No free virtual space large enough in the window.
+    OutOfVirtualSpace,
+    /// Maximum number of live areas reached.
+    TooManyAreas,
+    /// No physical frame left to back a page.
+    OutOfMemory(&'static str),
+    /// Address was not returned by `vmalloc`.
+    NotAllocated,
+    /// Page table update failed.
+    Map(&'static str),
+    /// A frame could not be given back.
+    Free(&'static str),
+}
+
+impl From<MapError> for AllocError {
+    fn from(e: MapError) -> Self {
+        match e {
+            MapError::InvalidSize => Self::InvalidSize,
+            MapError::OutOfVirtualSpace => Self::OutOfVirtualSpace,
+            MapError::TooManyMappings => Self::TooManyAreas,
+            MapError::NotMapped => Self::NotAllocated,
+            MapError::PageTable(e) => Self::Map(e),
+        }
+    }
+}
+
+/// Source of the physical frames backing vmalloc pages.
+pub trait FrameProvider {
+    /// Allocate one page frame.
+    fn alloc_frame(&mut self) -> Result<u64, &'static str>;
+
+    /// Return a frame from `alloc_frame`.
+    fn free_frame(&mut self, phys: u64) -> Result<(), &'static str>;
+}
+
+/// Page table updates for vmalloc areas.
+pub trait Mapper {
+    /// Map the page at `va` to the frame at `phys`, Normal cacheable RW.
+    fn map_page(&mut self, va: u64, phys: u64) -> Result<(), &'static str>;
+
+    /// Returns the frame the page at `va` maps to.
+    fn frame_of(&self, va: u64) -> Option<u64>;
+
+    /// Unmap `[va, va + size)` and flush the TLB for it.
+    fn unmap(&mut self, va: u64, size: u64) -> Result<(), &'static str>;
+}
+
+/// A live vmalloc area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Area {
+    /// Start of the mapped pages.
+    pub va: u64,
+    /// Number of mapped pages, not counting the guard.
+    pub pages: u64,
+}
+
+impl Area {
+    /// Returns the mapped size in bytes.
+    pub const fn size(&self) -> u64 {
+        self.pages * address::kernel::PAGE_SIZE
+    }
+
+    /// Returns the start of the guard page after the area.
+    #[cfg(test)]
+    pub const fn guard(&self) -> u64 {
+        self.va + self.size()
+    }
+}
+
+/// Virtual space and registry of live areas.
+pub struct Vmalloc {
+    window: VaWindow,
+    areas: [Option<Area>; MAX_AREAS],
+}
+
+impl Vmalloc {
+    /// Creates an allocator with no areas over `[start, start + size)`.
+    pub const fn new(start: u64, size: u64) -> Self {
+        Self {
+            window: VaWindow::new(start, size),
+            areas: [None; MAX_AREAS],
+        }
+    }
+
+    /// Allocate `size` bytes, rounded up to whole pages, and map them.
+    ///
+    /// On failure everything taken so far is given back.
+    ///
+    /// # Returns
+    /// The page aligned start of the area
+    pub fn alloc(
+        &mut self,
+        size: u64,
+        frames: &mut impl FrameProvider,
+        mapper: &mut impl Mapper,
+    ) -> Result<u64, AllocError> {
+        let page_size = address::kernel::PAGE_SIZE;
+        if size == 0 {
+            return Err(AllocError::InvalidSize);
+        }
+        let size = size
+            .checked_next_multiple_of(page_size)
+            .ok_or(AllocError::InvalidSize)?;
+        let reserved = size
+            .checked_add(GUARD_PAGES * page_size)
+            .ok_or(AllocError::InvalidSize)?;
+
+        let slot = self
+            .areas
+            .iter()
+            .position(Option::is_none)
+            .ok_or(AllocError::TooManyAreas)?;
+        let va = self.window.alloc(reserved)?;
+
+        let mut mapped = 0;
+        let mut result = Ok(());
+        while mapped < size {
+            let phys = match frames.alloc_frame() {
+                Ok(phys) => phys,
+                Err(e) => {
+                    result = Err(AllocError::OutOfMemory(e));
+                    break;
+                }
+            };
+            if let Err(e) = mapper.map_page(va + mapped, phys) {
+                result = Err(match frames.free_frame(phys) {
+                    Ok(()) => AllocError::Map(e),
+                    Err(e) => AllocError::Free(e),
+                });
+                break;
+            }
+            mapped += page_size;
+        }
+
+        if let Err(e) = result {
+            Self::release(va, mapped, frames, mapper)?;
+            self.window.free(va)?;
+            return Err(e);
+        }
+
+        self.areas[slot] = Some(Area {
+            va,
+            pages: size / page_size,
+        });
+        Ok(va)
+    }
+
+    /// Unmap the area starting at `va` and return its frames.
+    ///
+    /// If the page tables cannot be updated the area stays live and
+    /// nothing it still maps is freed.
+    ///
+    /// # Returns
+    /// The number of pages freed
+    pub fn free(
+        &mut self,
+        va: u64,
+        frames: &mut impl FrameProvider,
+        mapper: &mut impl Mapper,
+    ) -> Result<u64, AllocError> {
+        let slot = self
+            .areas
+            .iter()
+            .position(|area| area.is_some_and(|area| area.va == va))
+            .ok_or(AllocError::NotAllocated)?;
+        let area = self.areas[slot].take().ok_or(AllocError::NotAllocated)?;
+
+        let released = Self::release(area.va, area.size(), frames, mapper);
+        if let Err(e @ AllocError::Map(_)) = released {
+            // Parts are still mapped, keep the area so the free can be retried
+            self.areas[slot] = Some(area);
+            return Err(e);
+        }
+        self.window.free(area.va)?;
+        released.map(|()| area.pages)
+    }
+
+    /// Returns the live area containing `addr`, guard page included.
+    #[cfg(test)]
+    pub fn find(&self, addr: u64) -> Option<Area> {
+        let page_size = address::kernel::PAGE_SIZE;
+        self.areas
+            .iter()
+            .flatten()
+            .find(|area| addr >= area.va && addr < area.guard() + GUARD_PAGES * page_size)
+            .copied()
+    }
+
+    /// Returns the number of live areas.
+    #[cfg(test)]
+    pub fn count(&self) -> usize {
+        self.areas.iter().flatten().count()
+    }
+
+    /// Unmap `[va, va + size)` and hand back the frames behind it.
+    ///
+    /// A frame is only freed once no translation to it is left, so it can
+    /// never be reached through the old address after someone else got it.
+    /// The range goes in batches of [`RELEASE_BATCH`] pages: look up the
+    /// frames, unmap and flush, then free them. If unmapping fails the
+    /// frames of that batch stay allocated.
+    fn release(
+        va: u64,
+        size: u64,
+        frames: &mut impl FrameProvider,
+        mapper: &mut impl Mapper,
+    ) -> Result<(), AllocError> {
+        let page_size = address::kernel::PAGE_SIZE;
+        let batch_size = RELEASE_BATCH as u64 * page_size;
+        let mut result = Ok(());
+        let mut start = 0;
+        while start < size {
+            let len = batch_size.min(size - start);
+            let mut batch = [0; RELEASE_BATCH];
+            let mut count = 0;
+            let mut offset = 0;
+            while offset < len {
+                if let Some(phys) = mapper.frame_of(va + start + offset) {
+                    batch[count] = phys;
+                    count += 1;
+                }
+                offset += page_size;
+            }
+
+            mapper.unmap(va + start, len).map_err(AllocError::Map)?;
+            for &phys in &batch[..count] {
+                // Keep going so one bad frame does not leak the rest
+                if let Err(e) = frames.free_frame(phys) {
+                    result = result.and(Err(AllocError::Free(e)));
+                }
+            }
+            start += len;
+        }
+        result
+    }
+}
+
+/// Frames from the physical memory manager.
+#[cfg(target_os = "none")]
+struct KernelFrames;
+
+#[cfg(target_os = "none")]
+impl FrameProvider for KernelFrames {
+    fn alloc_frame(&mut self) -> Result<u64, &'static str> {
+        crate::mm::pmm::alloc_page()
+    }
+
+    fn free_frame(&mut self, phys: u64) -> Result<(), &'static str> {
+        crate::mm::pmm::free_page(phys)
+    }
+}
+
+/// The live kernel page tables.
+#[cfg(target_os = "none")]
+struct KernelTables;
+
+#[cfg(target_os = "none")]
+impl Mapper for KernelTables {
+    fn map_page(&mut self, va: u64, phys: u64) -> Result<(), &'static str> {
+        crate::arch::pagetable::map_range(
+            va,
+            phys,
+            address::kernel::PAGE_SIZE,
+            address::mair::IDX_NORMAL,
+        )
+    }
+
+    fn frame_of(&self, va: u64) -> Option<u64> {
+        crate::arch::pagetable::translate_active(va).map(|(phys, _)| phys)
+    }
+
+    fn unmap(&mut self, va: u64, size: u64) -> Result<(), &'static str> {
+        // unmap_range flushes the TLB once the entries are cleared
+        crate::arch::pagetable::unmap_range(va, size)
+    }
+}
+
+/// Global vmalloc allocator.
+#[allow(dead_code)]
+static VMALLOC: Mutex<Vmalloc> = Mutex::new(Vmalloc::new(
+    address::kernel::VMALLOC_START,
+    address::kernel::VMALLOC_SIZE,
+));
+
+/// Allocate `size` bytes of virtually contiguous memory.
+///
+/// Requires memblock to be initialized. Frames come from `pmm`.
+///
+/// # Arguments
+/// * `size` - Size in bytes, rounded up to whole pages
+///
+/// # Returns
+/// Page aligned virtual address of the buffer
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn vmalloc(size: u64) -> Result<VirtAddr, AllocError> {
+    VMALLOC
+        .lock()
+        .alloc(size, &mut KernelFrames, &mut KernelTables)
+}
+
+/// Free a buffer returned by `vmalloc`.
+///
+/// # Arguments
+/// * `va` - Virtual address returned by `vmalloc`
+#[cfg(target_os = "none")]
+#[allow(dead_code)]
+pub fn vfree(va: VirtAddr) -> Result<(), AllocError> {
+    VMALLOC
+        .lock()
+        .free(va, &mut KernelFrames, &mut KernelTables)
+        .map(|_| ())
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    const START: u64 = 0x1_0000_0000;
+    const PAGE: u64 = address::kernel::PAGE_SIZE;
+
+    /// Hands out frames from a counter, up to `limit`.
+    struct MockFrames {
+        next: u64,
+        limit: usize,
+        live: Vec<u64>,
+    }
+
+    impl MockFrames {
+        fn new(limit: usize) -> Self {
+            Self {
+                next: 0x4000_0000,
+                limit,
+                live: Vec::new(),
+            }
+        }
+    }
+
+    impl FrameProvider for MockFrames {
+        fn alloc_frame(&mut self) -> Result<u64, &'static str> {
+            if self.live.len() == self.limit {
+                return Err("out of frames");
+            }
+            let phys = self.next;
+            self.next += PAGE;
+            self.live.push(phys);
+            Ok(phys)
+        }
+
+        fn free_frame(&mut self, phys: u64) -> Result<(), &'static str> {
+            let pos = self
+                .live
+                .iter()
+                .position(|&p| p == phys)
+                .ok_or("frame not allocated")?;
+            self.live.remove(pos);
+            Ok(())
+        }
+    }
+
+    /// Page table as a map from page address to frame.
+    #[derive(Default)]
+    struct MockMapper {
+        pages: BTreeMap<u64, u64>,
+        fail_at: Option<u64>,
+        fail_unmap: bool,
+        flushed: Vec<(u64, u64)>,
+    }
+
+    impl Mapper for MockMapper {
+        fn map_page(&mut self, va: u64, phys: u64) -> Result<(), &'static str> {
+            if self.fail_at == Some(va) {
+                return Err("map failed");
+            }
+            self.pages.insert(va, phys);
+            Ok(())
+        }
+
+        fn frame_of(&self, va: u64) -> Option<u64> {
+            self.pages.get(&va).copied()
+        }
+
+        fn unmap(&mut self, va: u64, size: u64) -> Result<(), &'static str> {
+            if self.fail_unmap {
+                return Err("unmap failed");
+            }
+            self.pages.retain(|&page, _| page < va || page >= va + size);
+            self.flushed.push((va, size));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_vmalloc_size_rounding() {
+        let mut vm = Vmalloc::new(START, 0x100000);
+        let mut frames = MockFrames::new(usize::MAX);
+        let mut mapper = MockMapper::default();
+
+        let a = vm.alloc(1, &mut frames, &mut mapper).unwrap();
+        assert_eq!(vm.find(a).unwrap().pages, 1);
+        let b = vm.alloc(PAGE + 1, &mut frames, &mut mapper).unwrap();
+        assert_eq!(vm.find(b).unwrap().pages, 2);
+        assert_eq!(mapper.pages.len(), 3);
+        assert_eq!(frames.live.len(), 3);
+
+        assert_eq!(
+            vm.alloc(0, &mut frames, &mut mapper),
+            Err(AllocError::InvalidSize)
+        );
+        assert_eq!(
+            vm.alloc(u64::MAX, &mut frames, &mut mapper),
+            Err(AllocError::InvalidSize)
+        );
+    }
+
+    #[test]
+    fn test_vmalloc_guard_spacing() {
+        let mut vm = Vmalloc::new(START, 0x100000);
+        let mut frames = MockFrames::new(usize::MAX);
+        let mut mapper = MockMapper::default();
+
+        let a = vm.alloc(2 * PAGE, &mut frames, &mut mapper).unwrap();
+        let b = vm.alloc(PAGE, &mut frames, &mut mapper).unwrap();
+        assert_eq!(a, START);
+        // One unmapped page separates the areas
+        assert_eq!(b, a + 3 * PAGE);
+        let guard = vm.find(a).unwrap().guard();
+        assert_eq!(guard, a + 2 * PAGE);
+        assert_eq!(mapper.frame_of(guard), None);
+        assert_eq!(vm.find(guard), vm.find(a));
+        assert_eq!(vm.find(b + 2 * PAGE), None);
+    }
+
+    #[test]
+    fn test_vfree_bookkeeping() {
+        let mut vm = Vmalloc::new(START, 0x100000);
+        let mut frames = MockFrames::new(usize::MAX);
+        let mut mapper = MockMapper::default();
+
+        let a = vm.alloc(3 * PAGE, &mut frames, &mut mapper).unwrap();
+        let b = vm.alloc(PAGE, &mut frames, &mut mapper).unwrap();
+        assert_eq!(vm.count(), 2);
+
+        assert_eq!(vm.free(a, &mut frames, &mut mapper), Ok(3));
+        assert_eq!(vm.count(), 1);
+        assert_eq!(frames.live.len(), 1);
+        assert_eq!(mapper.flushed, [(a, 3 * PAGE)]);
+        assert!(mapper.pages.keys().all(|&va| va == b));
+
+        // The hole, guard included, is reused
+        assert_eq!(vm.alloc(3 * PAGE, &mut frames, &mut mapper), Ok(a));
+    }
+
+    #[test]
+    fn test_vfree_unmaps_before_freeing() {
+        let mut vm = Vmalloc::new(START, 0x100000);
+        let mut frames = MockFrames::new(usize::MAX);
+        let mut mapper = MockMapper::default();
+
+        // Frames still mapped are never given back
+        let a = vm.alloc(2 * PAGE, &mut frames, &mut mapper).unwrap();
+        mapper.fail_unmap = true;
+        assert_eq!(
+            vm.free(a, &mut frames, &mut mapper),
+            Err(AllocError::Map("unmap failed"))
+        );
+        assert_eq!(frames.live.len(), 2);
+        assert_eq!(mapper.pages.len(), 2);
+        mapper.fail_unmap = false;
+        assert_eq!(vm.free(a, &mut frames, &mut mapper), Ok(2));
+        assert!(frames.live.is_empty());
+        mapper.flushed.clear();
+
+        // Large areas go in batches, each flushed before its frames are freed
+        let pages = RELEASE_BATCH as u64 + 3;
+        let b = vm.alloc(pages * PAGE, &mut frames, &mut mapper).unwrap();
+        assert_eq!(vm.free(b, &mut frames, &mut mapper), Ok(pages));
+        assert_eq!(
+            mapper.flushed,
+            [
+                (b, RELEASE_BATCH as u64 * PAGE),
+                (b + RELEASE_BATCH as u64 * PAGE, 3 * PAGE)
+            ]
+        );
+        assert!(frames.live.is_empty());
+
+        // A frame the provider does not know is reported, the rest freed
+        let c = vm.alloc(2 * PAGE, &mut frames, &mut mapper).unwrap();
+        frames.live.remove(0);
+        assert_eq!(
+            vm.free(c, &mut frames, &mut mapper),
+            Err(AllocError::Free("frame not allocated"))
+        );
+        assert!(frames.live.is_empty());
+        assert!(mapper.pages.is_empty());
+        assert_eq!(vm.count(), 0);
+    }
+
+    #[test]
+    fn test_vfree_unknown() {
+        let mut vm = Vmalloc::new(START, 0x100000);
+        let mut frames = MockFrames::new(usize::MAX);
+        let mut mapper = MockMapper::default();
+
+        let a = vm.alloc(2 * PAGE, &mut frames, &mut mapper).unwrap();
+        assert_eq!(
+            vm.free(a + PAGE, &mut frames, &mut mapper),
+            Err(AllocError::NotAllocated)
+        );
+        assert_eq!(vm.free(a, &mut frames, &mut mapper), Ok(2));
+        assert_eq!(
+            vm.free(a, &mut frames, &mut mapper),
+            Err(AllocError::NotAllocated)
+        );
+        assert!(mapper.flushed.len() == 1);
+    }
+
+    #[test]
+    fn test_vmalloc_failure_rolls_back() {
+        let mut vm = Vmalloc::new(START, 0x100000);
+        let mut frames = MockFrames::new(2);
+        let mut mapper = MockMapper::default();
+
+        assert_eq!(
+            vm.alloc(3 * PAGE, &mut frames, &mut mapper),
+            Err(AllocError::OutOfMemory("out of frames"))
+        );
+        assert!(frames.live.is_empty());
+        assert!(mapper.pages.is_empty());
+        assert_eq!(vm.count(), 0);
+
+        let mut frames = MockFrames::new(usize::MAX);
+        mapper.fail_at = Some(START + PAGE);
+        assert_eq!(
+            vm.alloc(2 * PAGE, &mut frames, &mut mapper),
+            Err(AllocError::Map("map failed"))
+        );
+        assert!(frames.live.is_empty());
+        // The virtual space was given back
+        mapper.fail_at = None;
+        assert_eq!(vm.alloc(PAGE, &mut frames, &mut mapper), Ok(START));
+    }
+
+    #[test]
+    fn test_vmalloc_skips_small_holes() {
+        let mut vm = Vmalloc::new(START, 0x100000);
+        let mut frames = MockFrames::new(usize::MAX);
+        let mut mapper = MockMapper::default();
+
+        let a = vm.alloc(PAGE, &mut frames, &mut mapper).unwrap();
+        let b = vm.alloc(PAGE, &mut frames, &mut mapper).unwrap();
+        let c = vm.alloc(PAGE, &mut frames, &mut mapper).unwrap();
+        vm.free(b, &mut frames, &mut mapper).unwrap();
+
+        // Two pages plus a guard do not fit the two-page hole left by b
+        let d = vm.alloc(2 * PAGE, &mut frames, &mut mapper).unwrap();
+        assert_eq!(d, c + 2 * PAGE);
+        assert_eq!(vm.alloc(PAGE, &mut frames, &mut mapper), Ok(b));
+        assert_eq!(vm.find(a).unwrap().pages, 1);
+    }
+
+    #[test]
+    fn test_vmalloc_window_exhaustion() {
+        let mut vm = Vmalloc::new(START, 4 * PAGE);
+        let mut frames = MockFrames::new(usize::MAX);
+        let mut mapper = MockMapper::default();
+
+        // The guard page needs room too
+        assert_eq!(
+            vm.alloc(4 * PAGE, &mut frames, &mut mapper),
+            Err(AllocError::OutOfVirtualSpace)
+        );
+        assert_eq!(vm.alloc(3 * PAGE, &mut frames, &mut mapper), Ok(START));
+        assert!(frames.live.len() == 3);
+    }
+}
@@ -0,0 +1,153 @@
+//! Lock-free event counters for kernel statistics.
+//!
+//! Counters are plain atomics so hot paths can bump them without taking a
+//! lock. Subsystems register their counters by name so they can all be
+//! dumped together.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Maximum number of counters that can be registered.
+const MAX_COUNTERS: usize = 32;
+
+/// A monotonically increasing event counter.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Creates a counter starting at zero.
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// Increments the counter by one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Adds `n` to the counter.
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Overwrites the value.
+    ///
+    /// Only for rolling back a failed step; counters otherwise only grow.
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// Returns the current value.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Registry of named counters.
+struct Registry {
+    entries: [Option<(&'static str, &'static Counter)>; MAX_COUNTERS],
+    count: usize,
+}
+
+/// Global counter registry.
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    entries: [None; MAX_COUNTERS],
+    count: 0,
+});
+
+/// Registers a named counter for `dump`.
+///
+/// Registering the same counter twice is a no-op.
+#[allow(dead_code)]
+pub fn register(name: &'static str, counter: &'static Counter) -> Result<(), &'static str> {
+    let mut registry = REGISTRY.lock();
+
+    let count = registry.count;
+    let already = registry.entries[..count]
+        .iter()
+        .flatten()
+        .any(|&(_, c)| core::ptr::eq(c, counter));
+    if already {
+        return Ok(());
+    }
+
+    if count >= MAX_COUNTERS {
+        return Err("maximum number of counters reached");
+    }
+    registry.entries[count] = Some((name, counter));
+    registry.count += 1;
+
+    Ok(())
+}
+
+/// Writes all registered counters as `name: value` lines.
+#[allow(dead_code)]
+pub fn write_all(w: &mut dyn fmt::Write) -> fmt::Result {
+    let registry = REGISTRY.lock();
+    for &(name, counter) in registry.entries[..registry.count].iter().flatten() {
+        writeln!(w, "  {}: {}", name, counter.get())?;
+    }
+    Ok(())
+}
+
+/// Dumps all registered counters over serial.
+#[cfg(target_os = "none")]
+pub fn dump() {
+    use crate::arch::serial;
+    use core::fmt::Write;
+
+    let _ = writeln!(serial::Writer, "Kernel statistics:");
+    let _ = write_all(&mut serial::Writer);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_counter_ops() {
+        let counter = Counter::new();
+        assert_eq!(counter.get(), 0);
+        counter.inc();
+        counter.add(41);
+        assert_eq!(counter.get(), 42);
+    }
+
+    #[test]
+    fn test_counter_concurrent() {
+        static COUNTER: Counter = Counter::new();
+        const THREADS: u64 = 8;
+        const ITERS: u64 = 10_000;
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..ITERS {
+                        COUNTER.inc();
+                    }
+                    COUNTER.add(ITERS);
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(COUNTER.get(), THREADS * ITERS * 2);
+    }
+
+    #[test]
+    fn test_register_and_write() {
+        static EVENTS: Counter = Counter::new();
+
+        register("test.events", &EVENTS).unwrap();
+        register("test.events", &EVENTS).unwrap();
+        EVENTS.add(3);
+
+        let mut out = String::new();
+        write_all(&mut out).unwrap();
+        assert_eq!(out.matches("test.events").count(), 1);
+        assert!(out.contains("  test.events: 3\n"));
+    }
+}